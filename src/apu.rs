@@ -0,0 +1,975 @@
+use crate::mixer::CHANNEL_COUNT;
+use crate::savestate::{SaveState, StateError, StateReader};
+
+// The NTSC CPU clock, which the APU runs from
+pub const CPU_CLOCK_RATE: f64 = 1_789_773.0;
+// The rate Nes::audio is sampled at
+pub const SAMPLE_RATE: u32 = 44_100;
+
+// Length counter loads, indexed by the top 5 bits of the channel's last register
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28,
+    32, 30,
+];
+
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// Noise and DMC timer periods in CPU cycles, NTSC
+const NOISE_PERIODS: [u16; 16] = [4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068];
+const DMC_RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+
+// Frame counter steps in CPU cycles since it was last reset. The 4 step sequence
+// raises its IRQ on its last 3 cycles; the 5 step one never does.
+const QUARTER_FRAME_1: u32 = 7457;
+const HALF_FRAME_1: u32 = 14913;
+const QUARTER_FRAME_3: u32 = 22371;
+const FOUR_STEP_LAST: u32 = 29829;
+const FOUR_STEP_PERIOD: u32 = 29830;
+const FIVE_STEP_LAST: u32 = 37281;
+const FIVE_STEP_PERIOD: u32 = 37282;
+
+// Pulse periods below this are silenced, along with sweeps that would overflow 11 bits
+const MIN_PULSE_PERIOD: u16 = 8;
+const MAX_PULSE_PERIOD: u16 = 0x7FF;
+
+// Volume envelope shared by the pulses and the noise. The channel's control
+// register holds its period or constant volume, and the loop flag.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    pub start: bool,
+    pub divider: u8,
+    pub decay: u8,
+}
+
+impl Envelope {
+    // Quarter frame
+    fn clock(&mut self, control: u8) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = control & 0x0F;
+        } else if self.divider == 0 {
+            self.divider = control & 0x0F;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if control & 0x20 != 0 {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self, control: u8) -> u8 {
+        if control & 0x10 != 0 {
+            control & 0x0F
+        } else {
+            self.decay
+        }
+    }
+}
+
+// $4000-$4003 and $4004-$4007: DDLC VVVV, EPPP NSSS, the period's low 8 bits,
+// then the length and the period's top 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pulse {
+    // pulse 1 negates its sweep with ones' complement, one less than pulse 2
+    pub ones_complement: bool,
+    pub control: u8,
+    pub sweep: u8,
+    pub period: u16,
+    pub timer: u16,
+    // position in the duty cycle
+    pub step: u8,
+    pub length: u8,
+    pub envelope: Envelope,
+    pub sweep_divider: u8,
+    pub sweep_reload: bool,
+}
+
+impl Pulse {
+    fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            control: 0,
+            sweep: 0,
+            period: 0,
+            timer: 0,
+            step: 0,
+            length: 0,
+            envelope: Envelope::default(),
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    fn write(&mut self, register: u16, value: u8, enabled: bool) {
+        match register {
+            0 => self.control = value,
+            1 => {
+                self.sweep = value;
+                self.sweep_reload = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (value as u16 & 0x07) << 8;
+                if enabled {
+                    self.length = LENGTH_TABLE[value as usize >> 3];
+                }
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // The period the sweep unit is heading for
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> (self.sweep & 0x07);
+        if self.sweep & 0x08 == 0 {
+            self.period + change
+        } else if self.ones_complement {
+            self.period.saturating_sub(change + 1)
+        } else {
+            self.period.saturating_sub(change)
+        }
+    }
+
+    // Too high or low a period mutes the channel, even with the sweep off
+    fn muted(&self) -> bool {
+        self.period < MIN_PULSE_PERIOD || self.sweep_target() > MAX_PULSE_PERIOD
+    }
+
+    // Every other CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Half frame
+    fn clock_sweep(&mut self) {
+        let enabled = self.sweep & 0x80 != 0 && self.sweep & 0x07 != 0;
+        if self.sweep_divider == 0 && enabled && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = (self.sweep >> 4) & 0x07;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.control & 0x20 != 0
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length == 0 || self.muted() || DUTY_CYCLES[self.control as usize >> 6][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume(self.control)
+        }
+    }
+}
+
+// $4008-$400B: CRRR RRRR (length halt and linear counter control, reload value),
+// unused, the period's low 8 bits, then the length and the period's top 3
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Triangle {
+    pub control: u8,
+    pub period: u16,
+    pub timer: u16,
+    pub step: u8,
+    pub length: u8,
+    pub linear_counter: u8,
+    pub linear_reload: bool,
+}
+
+impl Triangle {
+    fn write(&mut self, register: u16, value: u8, enabled: bool) {
+        match register {
+            0 => self.control = value,
+            1 => {}
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | (value as u16 & 0x07) << 8;
+                if enabled {
+                    self.length = LENGTH_TABLE[value as usize >> 3];
+                }
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Every CPU cycle. Silencing stops the sequence where it is rather than
+    // dropping the output to 0, which would click.
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length > 0 && self.linear_counter > 0 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Quarter frame
+    fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.control & 0x7F;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if self.control & 0x80 == 0 {
+            self.linear_reload = false;
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    pub fn output(&self) -> u8 {
+        TRIANGLE_SEQUENCE[self.step as usize]
+    }
+}
+
+// $400C-$400F: --LC VVVV, unused, M--- PPPP (short mode, period), then the length
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Noise {
+    pub control: u8,
+    pub mode: bool,
+    pub period: u16,
+    pub timer: u16,
+    // 15 bit LFSR, the channel is silent while bit 0 is set
+    pub shift: u16,
+    pub length: u8,
+    pub envelope: Envelope,
+}
+
+impl Default for Noise {
+    fn default() -> Self {
+        Noise {
+            control: 0,
+            mode: false,
+            period: NOISE_PERIODS[0],
+            timer: 0,
+            shift: 1,
+            length: 0,
+            envelope: Envelope::default(),
+        }
+    }
+}
+
+impl Noise {
+    fn write(&mut self, register: u16, value: u8, enabled: bool) {
+        match register {
+            0 => self.control = value,
+            1 => {}
+            2 => {
+                self.mode = value & 0x80 != 0;
+                self.period = NOISE_PERIODS[value as usize & 0x0F];
+            }
+            _ => {
+                if enabled {
+                    self.length = LENGTH_TABLE[value as usize >> 3];
+                }
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // Every CPU cycle, the periods are in CPU cycles
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            // short mode taps bit 6 instead of bit 1, for a metallic 93 step loop
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 0x01;
+            self.shift = (self.shift >> 1) | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn halted(&self) -> bool {
+        self.control & 0x20 != 0
+    }
+
+    pub fn output(&self) -> u8 {
+        if self.length == 0 || self.shift & 0x01 != 0 {
+            0
+        } else {
+            self.envelope.volume(self.control)
+        }
+    }
+}
+
+// $4010-$4013: IL-- RRRR (IRQ enable, loop, rate), -DDD DDDD (direct load), the
+// sample address as $C000 + A * 64 and its length as L * 16 + 1 bytes. It plays
+// 1 bit deltas from memory, which the bus fetches for it, see fetch_address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dmc {
+    pub control: u8,
+    pub sample_address: u8,
+    pub sample_length: u8,
+    pub level: u8,
+    pub timer: u16,
+    pub address: u16,
+    pub bytes_remaining: u16,
+    pub buffer: Option<u8>,
+    pub shift: u8,
+    pub bits_remaining: u8,
+    // the output unit holds its level while there was no sample byte to play
+    pub silence: bool,
+    pub irq: bool,
+}
+
+impl Default for Dmc {
+    fn default() -> Self {
+        Dmc {
+            control: 0,
+            sample_address: 0,
+            sample_length: 0,
+            level: 0,
+            timer: 0,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+}
+
+impl Dmc {
+    fn write(&mut self, register: u16, value: u8) {
+        match register {
+            0 => {
+                self.control = value;
+                if value & 0x80 == 0 {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_address = value,
+            _ => self.sample_length = value,
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = 0xC000 | (self.sample_address as u16) << 6;
+        self.bytes_remaining = (self.sample_length as u16) << 4 | 1;
+    }
+
+    fn rate(&self) -> u16 {
+        DMC_RATES[self.control as usize & 0x0F]
+    }
+
+    // Every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate() - 1;
+        if !self.silence {
+            if self.shift & 0x01 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.shift = value;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // The address of the next sample byte once the buffer has room for it. The bus
+    // reads it and hands it to load_sample.
+    pub fn fetch_address(&self) -> Option<u16> {
+        (self.buffer.is_none() && self.bytes_remaining > 0).then_some(self.address)
+    }
+
+    pub fn load_sample(&mut self, value: u8) {
+        self.buffer = Some(value);
+        // the address wraps around to $8000, not $0000
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.control & 0x40 != 0 {
+                self.restart();
+            } else if self.control & 0x80 != 0 {
+                self.irq = true;
+            }
+        }
+    }
+
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+// The 2A03's audio processing unit: two pulse waves, a triangle, noise and the
+// delta modulation channel, stepped by the frame counter at $4017. The bus
+// clocks it every CPU cycle and mixes what it outputs, see NesBus::tick.
+// The DMC's fetches don't stall the CPU.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Apu {
+    pub pulses: [Pulse; 2],
+    pub triangle: Triangle,
+    pub noise: Noise,
+    pub dmc: Dmc,
+    // $4015's channel enables, in the low 5 bits
+    pub enabled: u8,
+    // $4017: 5 step mode and IRQ inhibit
+    pub frame_control: u8,
+    // CPU cycles since the frame counter was last reset
+    pub frame_cycle: u32,
+    pub frame_irq: bool,
+    // pulse and noise timers step on every other CPU cycle
+    pub odd_cycle: bool,
+}
+
+// Plain copy of one channel, for debuggers and tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    pub enabled: bool,
+    // timer period, in CPU cycles for the noise
+    pub period: u16,
+    pub length: u8,
+    // the envelope or constant volume, the linear counter for the triangle
+    pub volume: u8,
+    pub output: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmcState {
+    pub enabled: bool,
+    pub rate: u16,
+    pub address: u16,
+    pub bytes_remaining: u16,
+    pub output: u8,
+    pub irq: bool,
+}
+
+// Plain copy of the APU's channels and frame counter, for debuggers and tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuState {
+    pub pulses: [ChannelState; 2],
+    pub triangle: ChannelState,
+    pub noise: ChannelState,
+    pub dmc: DmcState,
+    pub five_step: bool,
+    pub irq_inhibit: bool,
+    pub frame_cycle: u32,
+    pub frame_irq: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Apu {
+    pub fn new() -> Self {
+        Apu {
+            pulses: [Pulse::new(true), Pulse::new(false)],
+            triangle: Triangle::default(),
+            noise: Noise::default(),
+            dmc: Dmc::default(),
+            enabled: 0,
+            frame_control: 0,
+            frame_cycle: 0,
+            frame_irq: false,
+            odd_cycle: false,
+        }
+    }
+
+    // The reset button silences every channel and starts the frame counter over,
+    // keeping the mode written to $4017
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.frame_cycle = 0;
+        self.frame_irq = false;
+        self.dmc.irq = false;
+    }
+
+    // $4000-$4013, $4015 and $4017
+    pub fn write_register(&mut self, address: u16, value: u8) {
+        let register = address & 0x03;
+        match address {
+            0x4000..=0x4003 => self.pulses[0].write(register, value, self.enabled & 0x01 != 0),
+            0x4004..=0x4007 => self.pulses[1].write(register, value, self.enabled & 0x02 != 0),
+            0x4008..=0x400B => self.triangle.write(register, value, self.enabled & 0x04 != 0),
+            0x400C..=0x400F => self.noise.write(register, value, self.enabled & 0x08 != 0),
+            0x4010..=0x4013 => self.dmc.write(register, value),
+            0x4015 => {
+                self.enabled = value & 0x1F;
+                // disabling a channel clears its length counter right away
+                let [pulse1, pulse2] = &mut self.pulses;
+                let lengths = [&mut pulse1.length, &mut pulse2.length, &mut self.triangle.length, &mut self.noise.length];
+                for (bit, length) in lengths.into_iter().enumerate() {
+                    if value & (1 << bit) == 0 {
+                        *length = 0;
+                    }
+                }
+                if value & 0x10 == 0 {
+                    self.dmc.bytes_remaining = 0;
+                } else if self.dmc.bytes_remaining == 0 {
+                    self.dmc.restart();
+                }
+                self.dmc.irq = false;
+            }
+            // The real counter resets 3 or 4 cycles after the write; here it's at once.
+            // 5 step mode clocks the envelopes, lengths and sweeps straight away.
+            0x4017 => {
+                self.frame_control = value & 0xC0;
+                self.frame_cycle = 0;
+                if value & 0x40 != 0 {
+                    self.frame_irq = false;
+                }
+                if value & 0x80 != 0 {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // $4015: which channels are still playing and the pending IRQs. Reading it
+    // acknowledges the frame IRQ.
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn peek_status(&self) -> u8 {
+        let lengths = [self.pulses[0].length, self.pulses[1].length, self.triangle.length, self.noise.length];
+        let playing = lengths.iter().enumerate().fold(0, |status, (bit, &length)| status | ((length > 0) as u8) << bit);
+        playing | ((self.dmc.bytes_remaining > 0) as u8) << 4 | (self.frame_irq as u8) << 6 | (self.dmc.irq as u8) << 7
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self) {
+        self.clock_frame_counter();
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.odd_cycle {
+            for pulse in &mut self.pulses {
+                pulse.clock_timer();
+            }
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let five_step = self.frame_control & 0x80 != 0;
+        match self.frame_cycle {
+            QUARTER_FRAME_1 | QUARTER_FRAME_3 => self.clock_quarter_frame(),
+            HALF_FRAME_1 => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FOUR_STEP_LAST if !five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            FIVE_STEP_LAST if five_step => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            _ => {}
+        }
+        if !five_step && self.frame_control & 0x40 == 0 && (FOUR_STEP_LAST - 1..=FOUR_STEP_PERIOD).contains(&self.frame_cycle) {
+            self.frame_irq = true;
+        }
+        let period = if five_step { FIVE_STEP_PERIOD } else { FOUR_STEP_PERIOD };
+        if self.frame_cycle >= period {
+            self.frame_cycle = 0;
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulses[0].envelope.clock(self.pulses[0].control);
+        self.pulses[1].envelope.clock(self.pulses[1].control);
+        self.noise.envelope.clock(self.noise.control);
+        self.triangle.clock_linear_counter();
+    }
+
+    fn clock_half_frame(&mut self) {
+        for pulse in &mut self.pulses {
+            if !pulse.halted() && pulse.length > 0 {
+                pulse.length -= 1;
+            }
+            pulse.clock_sweep();
+        }
+        if !self.triangle.halted() && self.triangle.length > 0 {
+            self.triangle.length -= 1;
+        }
+        if !self.noise.halted() && self.noise.length > 0 {
+            self.noise.length -= 1;
+        }
+    }
+
+    pub fn snapshot(&self) -> ApuState {
+        let enabled = |bit: u8| self.enabled & 1 << bit != 0;
+        let pulse = |index: usize| {
+            let pulse = &self.pulses[index];
+            ChannelState {
+                enabled: enabled(index as u8),
+                period: pulse.period,
+                length: pulse.length,
+                volume: pulse.envelope.volume(pulse.control),
+                output: pulse.output(),
+            }
+        };
+        ApuState {
+            pulses: [pulse(0), pulse(1)],
+            triangle: ChannelState {
+                enabled: enabled(2),
+                period: self.triangle.period,
+                length: self.triangle.length,
+                volume: self.triangle.linear_counter,
+                output: self.triangle.output(),
+            },
+            noise: ChannelState {
+                enabled: enabled(3),
+                period: self.noise.period,
+                length: self.noise.length,
+                volume: self.noise.envelope.volume(self.noise.control),
+                output: self.noise.output(),
+            },
+            dmc: DmcState {
+                enabled: enabled(4),
+                rate: self.dmc.rate(),
+                address: self.dmc.address,
+                bytes_remaining: self.dmc.bytes_remaining,
+                output: self.dmc.output(),
+                irq: self.dmc.irq,
+            },
+            five_step: self.frame_control & 0x80 != 0,
+            irq_inhibit: self.frame_control & 0x40 != 0,
+            frame_cycle: self.frame_cycle,
+            frame_irq: self.frame_irq,
+        }
+    }
+
+    // What each channel feeds the mixer, in Channel order
+    pub fn outputs(&self) -> [u8; CHANNEL_COUNT] {
+        [self.pulses[0].output(), self.pulses[1].output(), self.triangle.output(), self.noise.output(), self.dmc.output()]
+    }
+}
+
+impl SaveState for Apu {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        for pulse in &self.pulses {
+            out.extend_from_slice(&[pulse.control, pulse.sweep, pulse.step, pulse.length, pulse.sweep_divider, pulse.sweep_reload as u8]);
+            out.extend_from_slice(&pulse.period.to_le_bytes());
+            out.extend_from_slice(&pulse.timer.to_le_bytes());
+            save_envelope(&pulse.envelope, out);
+        }
+        let triangle = &self.triangle;
+        out.extend_from_slice(&[triangle.control, triangle.step, triangle.length, triangle.linear_counter, triangle.linear_reload as u8]);
+        out.extend_from_slice(&triangle.period.to_le_bytes());
+        out.extend_from_slice(&triangle.timer.to_le_bytes());
+        let noise = &self.noise;
+        out.extend_from_slice(&[noise.control, noise.mode as u8, noise.length]);
+        for value in [noise.period, noise.timer, noise.shift] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        save_envelope(&noise.envelope, out);
+        let dmc = &self.dmc;
+        out.extend_from_slice(&[dmc.control, dmc.sample_address, dmc.sample_length, dmc.level, dmc.shift, dmc.bits_remaining]);
+        out.extend_from_slice(&[dmc.buffer.is_some() as u8, dmc.buffer.unwrap_or(0), dmc.silence as u8, dmc.irq as u8]);
+        for value in [dmc.timer, dmc.address, dmc.bytes_remaining] {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&[self.enabled, self.frame_control, self.frame_irq as u8, self.odd_cycle as u8]);
+        out.extend_from_slice(&self.frame_cycle.to_le_bytes());
+    }
+
+    // Counters are brought back in range so a corrupt state can't index past the tables
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        for pulse in &mut self.pulses {
+            let bytes = input.read_bytes(6)?;
+            (pulse.control, pulse.sweep, pulse.step, pulse.length) = (bytes[0], bytes[1], bytes[2] % 8, bytes[3]);
+            (pulse.sweep_divider, pulse.sweep_reload) = (bytes[4], bytes[5] != 0);
+            pulse.period = input.read_u16()? & MAX_PULSE_PERIOD;
+            pulse.timer = input.read_u16()?;
+            pulse.envelope = load_envelope(input)?;
+        }
+        let bytes = input.read_bytes(5)?;
+        let triangle = &mut self.triangle;
+        (triangle.control, triangle.step, triangle.length) = (bytes[0], bytes[1] % 32, bytes[2]);
+        (triangle.linear_counter, triangle.linear_reload) = (bytes[3], bytes[4] != 0);
+        triangle.period = input.read_u16()? & 0x7FF;
+        triangle.timer = input.read_u16()?;
+        let bytes = input.read_bytes(3)?;
+        let noise = &mut self.noise;
+        (noise.control, noise.mode, noise.length) = (bytes[0], bytes[1] != 0, bytes[2]);
+        noise.period = input.read_u16()?.max(1);
+        noise.timer = input.read_u16()?;
+        noise.shift = input.read_u16()? & 0x7FFF;
+        noise.envelope = load_envelope(input)?;
+        let bytes = input.read_bytes(10)?;
+        let dmc = &mut self.dmc;
+        (dmc.control, dmc.sample_address, dmc.sample_length) = (bytes[0], bytes[1], bytes[2]);
+        (dmc.level, dmc.shift, dmc.bits_remaining) = (bytes[3] & 0x7F, bytes[4], bytes[5].clamp(1, 8));
+        dmc.buffer = (bytes[6] != 0).then_some(bytes[7]);
+        (dmc.silence, dmc.irq) = (bytes[8] != 0, bytes[9] != 0);
+        dmc.timer = input.read_u16()?;
+        dmc.address = input.read_u16()? | 0x8000;
+        dmc.bytes_remaining = input.read_u16()?;
+        let bytes = input.read_bytes(4)?;
+        (self.enabled, self.frame_control) = (bytes[0] & 0x1F, bytes[1] & 0xC0);
+        (self.frame_irq, self.odd_cycle) = (bytes[2] != 0, bytes[3] != 0);
+        let mut frame_cycle = [0; 4];
+        frame_cycle.copy_from_slice(input.read_bytes(4)?);
+        self.frame_cycle = u32::from_le_bytes(frame_cycle) % FIVE_STEP_PERIOD;
+        Ok(())
+    }
+}
+
+fn save_envelope(envelope: &Envelope, out: &mut Vec<u8>) {
+    out.extend_from_slice(&[envelope.start as u8, envelope.divider, envelope.decay]);
+}
+
+fn load_envelope(input: &mut StateReader) -> Result<Envelope, StateError> {
+    let bytes = input.read_bytes(3)?;
+    Ok(Envelope { start: bytes[0] != 0, divider: bytes[1] & 0x0F, decay: bytes[2] & 0x0F })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.clock();
+        }
+    }
+
+    #[test]
+    fn test_length_counters() {
+        let mut apu = Apu::new();
+        // a disabled channel ignores length loads
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.peek_status(), 0x00);
+        apu.write_register(0x4015, 0x0F);
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x400F, 0x18);
+        assert_eq!((apu.pulses[0].length, apu.noise.length), (254, 2));
+        assert_eq!(apu.read_status(), 0x09);
+        // two half frames run the noise out
+        clock(&mut apu, FOUR_STEP_LAST);
+        assert_eq!((apu.pulses[0].length, apu.noise.length), (252, 0));
+        apu.write_register(0x4015, 0x00);
+        assert_eq!(apu.peek_status() & 0x0F, 0x00);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new();
+        clock(&mut apu, FOUR_STEP_LAST - 2);
+        assert!(!apu.frame_irq);
+        clock(&mut apu, 1);
+        assert_eq!(apu.peek_status(), 0x40);
+        assert_eq!(apu.read_status(), 0x40);
+        // raised again on the sequence's last cycles, then it starts over
+        clock(&mut apu, 2);
+        assert!(apu.read_status() & 0x40 != 0 && apu.frame_cycle == 0);
+        clock(&mut apu, FOUR_STEP_PERIOD);
+        assert!(apu.frame_irq);
+
+        // inhibited, and never in 5 step mode
+        apu.write_register(0x4017, 0x40);
+        assert!(!apu.frame_irq);
+        clock(&mut apu, FOUR_STEP_PERIOD);
+        apu.write_register(0x4017, 0x80);
+        clock(&mut apu, FIVE_STEP_PERIOD);
+        assert!(!apu.frame_irq);
+    }
+
+    #[test]
+    fn test_pulse() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x01);
+        // 50% duty, constant volume 9, period 8
+        apu.write_register(0x4000, 0xB9);
+        apu.write_register(0x4002, 0x08);
+        apu.write_register(0x4003, 0x08);
+        let mut wave = Vec::new();
+        for _ in 0..8 {
+            wave.push(apu.outputs()[0]);
+            clock(&mut apu, 18);
+        }
+        assert_eq!(wave, [0, 9, 9, 9, 9, 0, 0, 0]);
+
+        // too low a period is silenced
+        apu.write_register(0x4002, 0x07);
+        assert!((0..32).all(|_| {
+            apu.clock();
+            apu.outputs()[0] == 0
+        }));
+    }
+
+    #[test]
+    fn test_sweep() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x03);
+        for base in [0x4000, 0x4004] {
+            // negate by period >> 1, every half frame
+            apu.write_register(base, 0xBF);
+            apu.write_register(base + 1, 0x89);
+            apu.write_register(base + 2, 0x00);
+            apu.write_register(base + 3, 0x01);
+        }
+        clock(&mut apu, HALF_FRAME_1);
+        // pulse 1 subtracts one more
+        assert_eq!((apu.pulses[0].period, apu.pulses[1].period), (0x07F, 0x080));
+        // a target past $7FF mutes the channel without touching the period
+        apu.write_register(0x4005, 0x81);
+        apu.write_register(0x4007, 0x07);
+        assert!(apu.pulses[1].muted());
+        clock(&mut apu, FOUR_STEP_LAST - HALF_FRAME_1);
+        assert_eq!(apu.pulses[1].period, 0x780);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x08);
+        // decay by one every quarter frame
+        apu.write_register(0x400C, 0x00);
+        apu.write_register(0x400F, 0x08);
+        clock(&mut apu, QUARTER_FRAME_1);
+        assert_eq!(apu.noise.envelope.decay, 15);
+        clock(&mut apu, HALF_FRAME_1 - QUARTER_FRAME_1);
+        assert_eq!(apu.noise.envelope.decay, 14);
+        // the noise plays at the envelope's level when its shifter's bit 0 is clear
+        let levels: Vec<u8> = (0..2000).map(|_| {
+            apu.clock();
+            apu.outputs()[3]
+        }).collect();
+        assert!(levels.contains(&0) && levels.contains(&14));
+    }
+
+    #[test]
+    fn test_triangle() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x04);
+        apu.write_register(0x4008, 0x81);
+        apu.write_register(0x400A, 0x00);
+        apu.write_register(0x400B, 0x08);
+        // nothing plays until the linear counter is loaded on a quarter frame
+        clock(&mut apu, 100);
+        assert_eq!(apu.triangle.step, 0);
+        clock(&mut apu, QUARTER_FRAME_1 - 100);
+        clock(&mut apu, 4);
+        assert_eq!((apu.triangle.step, apu.triangle.output()), (5, 10));
+    }
+
+    #[test]
+    fn test_dmc() {
+        let mut apu = Apu::new();
+        // IRQ at the end, fastest rate, sample at $C040, 17 bytes
+        apu.write_register(0x4010, 0x8F);
+        apu.write_register(0x4011, 0x40);
+        apu.write_register(0x4012, 0x01);
+        apu.write_register(0x4013, 0x01);
+        assert_eq!(apu.dmc.fetch_address(), None);
+        apu.write_register(0x4015, 0x10);
+        assert_eq!((apu.dmc.fetch_address(), apu.peek_status()), (Some(0xC040), 0x10));
+        for _ in 0..17 {
+            let address = apu.dmc.fetch_address().unwrap();
+            apu.dmc.load_sample(0xFF);
+            // the buffer has to empty before the next fetch
+            assert_eq!(apu.dmc.fetch_address(), None);
+            clock(&mut apu, 54 * 8);
+            assert!(address >= 0xC040);
+        }
+        assert_eq!(apu.peek_status(), 0x80);
+        assert!(apu.dmc.level > 0x40);
+        // acknowledged by writing $4015
+        apu.write_register(0x4015, 0x00);
+        assert!(!apu.dmc.irq);
+
+        // the address wraps to $8000
+        apu.dmc.address = 0xFFFF;
+        apu.dmc.bytes_remaining = 2;
+        apu.dmc.load_sample(0);
+        assert_eq!(apu.dmc.address, 0x8000);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x15);
+        apu.write_register(0x4000, 0x37);
+        apu.write_register(0x4002, 0x23);
+        apu.write_register(0x4003, 0x09);
+        apu.write_register(0x4008, 0x10);
+        apu.write_register(0x400B, 0x08);
+        apu.write_register(0x400E, 0x03);
+        apu.write_register(0x4017, 0x40);
+        clock(&mut apu, 10);
+        let state = apu.snapshot();
+        let pulse = state.pulses[0];
+        assert_eq!((pulse.enabled, pulse.period, pulse.length, pulse.volume), (true, 0x123, 254, 7));
+        assert!(!state.pulses[1].enabled);
+        assert_eq!((state.triangle.length, state.triangle.volume, state.triangle.output), (254, 0, 15));
+        assert_eq!((state.noise.enabled, state.noise.period, state.noise.length), (false, 32, 0));
+        assert_eq!((state.dmc.enabled, state.dmc.address, state.dmc.bytes_remaining), (true, 0xC000, 1));
+        assert_eq!((state.five_step, state.irq_inhibit, state.frame_cycle), (false, true, 10));
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut apu = Apu::new();
+        apu.write_register(0x4015, 0x1F);
+        for (address, value) in [(0x4000, 0x3F), (0x4003, 0x08), (0x4008, 0x81), (0x400B, 0x08), (0x400F, 0x08), (0x4013, 0x01)] {
+            apu.write_register(address, value);
+        }
+        apu.dmc.load_sample(0x55);
+        clock(&mut apu, 12345);
+        let mut out = Vec::new();
+        apu.save_state(&mut out);
+        let mut loaded = Apu::new();
+        loaded.load_state(&mut StateReader::new(&out)).unwrap();
+        assert_eq!(loaded, apu);
+        assert_eq!(loaded.load_state(&mut StateReader::new(&out[..out.len() - 1])), Err(StateError::Truncated));
+    }
+}
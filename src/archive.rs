@@ -0,0 +1,232 @@
+use crate::hash;
+use crate::inflate::inflate_limited;
+use crate::rom::RomError;
+
+const GZIP_MAGIC: [u8; 3] = [0x1F, 0x8B, 0x08];
+const GZIP_HEADER_SIZE: usize = 10;
+// CRC-32 and uncompressed size
+const GZIP_TRAILER_SIZE: usize = 8;
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+const GZIP_HEADER_CRC: u8 = 0x02;
+
+const ZIP_LOCAL_HEADER: [u8; 4] = [0x50, 0x4B, 0x03, 0x04];
+const ZIP_CENTRAL_HEADER: [u8; 4] = [0x50, 0x4B, 0x01, 0x02];
+const ZIP_END_OF_DIRECTORY: [u8; 4] = [0x50, 0x4B, 0x05, 0x06];
+const ZIP_END_OF_DIRECTORY_SIZE: usize = 22;
+const ZIP_CENTRAL_HEADER_SIZE: usize = 46;
+const ZIP_LOCAL_HEADER_SIZE: usize = 30;
+const ZIP_STORED: u16 = 0;
+const ZIP_DEFLATED: u16 = 8;
+
+const ROM_EXTENSION: &str = ".nes";
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16, RomError> {
+    let bytes = data.get(offset..offset + 2).ok_or(RomError::InvalidArchive)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Result<usize, RomError> {
+    let bytes = data.get(offset..offset + 4).ok_or(RomError::InvalidArchive)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+// Returns the ROM inside a .gz or .zip file, or the data itself when it is not compressed
+pub fn unpack(data: Vec<u8>) -> Result<Vec<u8>, RomError> {
+    if data.starts_with(&GZIP_MAGIC) {
+        gunzip(&data)
+    } else if data.starts_with(&ZIP_LOCAL_HEADER) {
+        unzip(&data)
+    } else {
+        Ok(data)
+    }
+}
+
+fn gunzip(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let flags = *data.get(3).ok_or(RomError::InvalidArchive)?;
+    let mut offset = GZIP_HEADER_SIZE;
+    if flags & GZIP_EXTRA != 0 {
+        offset += 2 + u16_at(data, offset)? as usize;
+    }
+    // zero-terminated file name and comment
+    for flag in [GZIP_NAME, GZIP_COMMENT] {
+        if flags & flag != 0 {
+            let rest = data.get(offset..).ok_or(RomError::InvalidArchive)?;
+            offset += rest.iter().position(|&byte| byte == 0).ok_or(RomError::InvalidArchive)? + 1;
+        }
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        offset += 2;
+    }
+    let end = data.len().checked_sub(GZIP_TRAILER_SIZE).ok_or(RomError::InvalidArchive)?;
+    let compressed = data.get(offset..end).ok_or(RomError::InvalidArchive)?;
+    // the size is only kept modulo 4GB, far past any ROM
+    let size = u32_at(data, end + 4)?;
+    let contents = inflate_limited(compressed, size)?;
+    check(&contents, size, u32_at(data, end)? as u32)?;
+    Ok(contents)
+}
+
+// What came out has to be what the archive says went in
+fn check(contents: &[u8], size: usize, crc: u32) -> Result<(), RomError> {
+    if contents.len() != size || hash::crc32(contents) != crc {
+        return Err(RomError::ArchiveMismatch);
+    }
+    Ok(())
+}
+
+// Extracts the first .nes file, found through the central directory since
+// local headers may leave the sizes to a trailing data descriptor
+fn unzip(data: &[u8]) -> Result<Vec<u8>, RomError> {
+    let end = data
+        .len()
+        .checked_sub(ZIP_END_OF_DIRECTORY_SIZE)
+        .and_then(|last| (0..=last).rev().find(|&offset| data[offset..].starts_with(&ZIP_END_OF_DIRECTORY)))
+        .ok_or(RomError::InvalidArchive)?;
+    let entries = u16_at(data, end + 10)?;
+    let mut offset = u32_at(data, end + 16)?;
+
+    for _ in 0..entries {
+        let header = data.get(offset..offset + ZIP_CENTRAL_HEADER_SIZE).ok_or(RomError::InvalidArchive)?;
+        if !header.starts_with(&ZIP_CENTRAL_HEADER) {
+            return Err(RomError::InvalidArchive);
+        }
+        let method = u16_at(header, 10)?;
+        let crc = u32_at(header, 16)? as u32;
+        let compressed_size = u32_at(header, 20)?;
+        let size = u32_at(header, 24)?;
+        let name_length = u16_at(header, 28)? as usize;
+        let extra_length = u16_at(header, 30)? as usize;
+        let comment_length = u16_at(header, 32)? as usize;
+        let local_offset = u32_at(header, 42)?;
+        let name_start = offset + ZIP_CENTRAL_HEADER_SIZE;
+        let name = data.get(name_start..name_start + name_length).ok_or(RomError::InvalidArchive)?;
+        offset = name_start + name_length + extra_length + comment_length;
+
+        if !String::from_utf8_lossy(name).to_ascii_lowercase().ends_with(ROM_EXTENSION) {
+            continue;
+        }
+        let start = local_offset
+            + ZIP_LOCAL_HEADER_SIZE
+            + u16_at(data, local_offset + 26)? as usize
+            + u16_at(data, local_offset + 28)? as usize;
+        let contents = data.get(start..start + compressed_size).ok_or(RomError::InvalidArchive)?;
+        let contents = match method {
+            ZIP_STORED => contents.to_vec(),
+            ZIP_DEFLATED => inflate_limited(contents, size)?,
+            method => return Err(RomError::UnsupportedCompression(method)),
+        };
+        check(&contents, size, crc)?;
+        return Ok(contents);
+    }
+    Err(RomError::NoRomInArchive)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::{inflate, InflateError};
+
+    // Raw deflate of "madNES madNES madNES"
+    const DEFLATED: [u8; 11] = [0xCB, 0x4D, 0x4C, 0xF1, 0x73, 0x0D, 0x56, 0xC8, 0x45, 0xA6, 0x00];
+    const CONTENTS: &[u8] = b"madNES madNES madNES";
+
+    // A zip with one entry per (name, method, data), sizes and CRCs only in the
+    // central directory
+    fn zip(files: &[(&str, u16, &[u8])]) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut directory = Vec::new();
+        for (name, method, contents) in files {
+            let original = match *method {
+                ZIP_DEFLATED => inflate(contents).unwrap(),
+                _ => contents.to_vec(),
+            };
+            let offset = data.len() as u32;
+            data.extend_from_slice(&ZIP_LOCAL_HEADER);
+            data.extend_from_slice(&[20, 0, 0x08, 0]);
+            data.extend_from_slice(&method.to_le_bytes());
+            data.extend_from_slice(&[0; 16]);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(contents);
+
+            directory.extend_from_slice(&ZIP_CENTRAL_HEADER);
+            directory.extend_from_slice(&[20, 0, 20, 0, 0x08, 0]);
+            directory.extend_from_slice(&method.to_le_bytes());
+            directory.extend_from_slice(&[0; 4]);
+            directory.extend_from_slice(&hash::crc32(&original).to_le_bytes());
+            directory.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(original.len() as u32).to_le_bytes());
+            directory.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            directory.extend_from_slice(&[0; 12]);
+            directory.extend_from_slice(&offset.to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+        }
+        let directory_offset = data.len() as u32;
+        data.extend_from_slice(&directory);
+        data.extend_from_slice(&ZIP_END_OF_DIRECTORY);
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        data.extend_from_slice(&directory_offset.to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_uncompressed() {
+        assert_eq!(unpack(b"NES\x1A".to_vec()).unwrap(), b"NES\x1A");
+    }
+
+    fn gzip(crc: u32, size: u32) -> Vec<u8> {
+        let mut data = GZIP_MAGIC.to_vec();
+        data.extend_from_slice(&[GZIP_NAME, 0, 0, 0, 0, 0, 3]);
+        data.extend_from_slice(b"game.nes\0");
+        data.extend_from_slice(&DEFLATED);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_gunzip() {
+        let crc = hash::crc32(CONTENTS);
+        assert_eq!(unpack(gzip(crc, 20)).unwrap(), CONTENTS);
+        assert!(matches!(unpack(gzip(crc ^ 1, 20)), Err(RomError::ArchiveMismatch)));
+        assert!(matches!(unpack(gzip(crc, 21)), Err(RomError::ArchiveMismatch)));
+        // a stream that would inflate past its declared size is cut off
+        assert!(matches!(unpack(gzip(crc, 4)), Err(RomError::Decompress(InflateError::TooLong))));
+    }
+
+    #[test]
+    fn test_unzip() {
+        let data = zip(&[("readme.txt", ZIP_STORED, b"hello"), ("Game.NES", ZIP_DEFLATED, &DEFLATED)]);
+        assert_eq!(unpack(data).unwrap(), CONTENTS);
+        let data = zip(&[("game.nes", ZIP_STORED, CONTENTS)]);
+        assert_eq!(unpack(data).unwrap(), CONTENTS);
+    }
+
+    #[test]
+    fn test_unzip_errors() {
+        let data = zip(&[("readme.txt", ZIP_STORED, b"hello")]);
+        assert!(matches!(unpack(data), Err(RomError::NoRomInArchive)));
+        let data = zip(&[("game.nes", 14, CONTENTS)]);
+        assert!(matches!(unpack(data), Err(RomError::UnsupportedCompression(14))));
+        let mut data = zip(&[("game.nes", ZIP_STORED, CONTENTS)]);
+        data.truncate(40);
+        assert!(matches!(unpack(data), Err(RomError::InvalidArchive)));
+
+        // a flipped byte in the stored data fails the CRC
+        let mut data = zip(&[("game.nes", ZIP_STORED, CONTENTS)]);
+        data[ZIP_LOCAL_HEADER_SIZE + 8] ^= 0x20;
+        assert!(matches!(unpack(data), Err(RomError::ArchiveMismatch)));
+        // and a smaller uncompressed size stops the inflate
+        let mut data = zip(&[("game.nes", ZIP_DEFLATED, &DEFLATED)]);
+        let header = data.len() - ZIP_END_OF_DIRECTORY_SIZE - "game.nes".len() - ZIP_CENTRAL_HEADER_SIZE;
+        data[header + 24] = 10;
+        assert!(matches!(unpack(data), Err(RomError::Decompress(InflateError::TooLong))));
+    }
+}
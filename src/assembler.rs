@@ -0,0 +1,444 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::cpu::{AddressingMode, INSTRUCTIONS};
+use crate::instruction::Instruction;
+use crate::symbols::SymbolTable;
+
+// A small 6502 assembler for test programs and the debugger's assemble command.
+// It takes the ca65 subset the disassembler writes, so its output assembles back:
+//   reset:                  labels end in a colon
+//   frame = $10             constants
+//       LDA #<table         $hex, %binary or decimal, < and > for the low and high byte
+//       STA a:frame+1       a: forces absolute addressing for zero page addresses
+//       BNE reset           ; comments
+//   .byte 1, $02  .word reset  .res 16, $FF  .org $C000
+// Undocumented opcodes are available by their usual names, e.g. LAX.
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Whole,
+    Low,
+    High,
+}
+
+// Numbers and names added and subtracted, like `table+2`
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expr {
+    part: Part,
+    terms: Vec<(bool, String)>,
+}
+
+impl Expr {
+    fn parse(text: &str) -> Result<Expr, String> {
+        let text = text.trim();
+        let (part, text) = match text.as_bytes().first() {
+            Some(b'<') => (Part::Low, &text[1..]),
+            Some(b'>') => (Part::High, &text[1..]),
+            _ => (Part::Whole, text),
+        };
+        let mut terms = Vec::new();
+        let mut negative = false;
+        let mut term = String::new();
+        for c in text.chars().chain(Some('+')) {
+            match c {
+                '+' | '-' => {
+                    let name = term.trim();
+                    if name.is_empty() {
+                        return Err(format!("invalid expression {}", text));
+                    }
+                    terms.push((negative, name.to_string()));
+                    negative = c == '-';
+                    term.clear();
+                }
+                c => term.push(c),
+            }
+        }
+        Ok(Expr { part, terms })
+    }
+
+    // None while a name is still undefined
+    fn evaluate(&self, symbols: &HashMap<String, i64>) -> Option<i64> {
+        let mut value = 0i64;
+        for (negative, term) in &self.terms {
+            let term = parse_value(term).or_else(|| symbols.get(term).copied())?;
+            value += if *negative { -term } else { term };
+        }
+        Some(match self.part {
+            Part::Whole => value,
+            Part::Low => value & 0xFF,
+            Part::High => (value >> 8) & 0xFF,
+        })
+    }
+}
+
+fn parse_value(text: &str) -> Option<i64> {
+    if let Some(hex) = text.strip_prefix('$') {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix('%') {
+        i64::from_str_radix(binary, 2).ok()
+    } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+        text.parse().ok()
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(Expr),
+    // zero page or absolute, maybe indexed, unless `a:` forced absolute
+    Direct { expr: Expr, index: Option<char>, absolute: bool },
+    Indirect(Expr),
+    IndirectX(Expr),
+    IndirectY(Expr),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Operand, String> {
+        let text = text.trim();
+        let upper = text.to_ascii_uppercase();
+        if text.is_empty() {
+            return Ok(Operand::None);
+        }
+        if upper == "A" {
+            return Ok(Operand::Accumulator);
+        }
+        if let Some(value) = text.strip_prefix('#') {
+            return Ok(Operand::Immediate(Expr::parse(value)?));
+        }
+        if text.starts_with('(') {
+            let inner = |suffix: &str| Expr::parse(&text[1..text.len() - suffix.len()]);
+            return if upper.ends_with(",X)") {
+                Ok(Operand::IndirectX(inner(",X)")?))
+            } else if upper.ends_with("),Y") {
+                Ok(Operand::IndirectY(inner("),Y")?))
+            } else if upper.ends_with(')') {
+                Ok(Operand::Indirect(inner(")")?))
+            } else {
+                Err(format!("invalid operand {}", text))
+            };
+        }
+        let (text, index) = match upper.rsplit_once(',') {
+            Some((_, "X")) => (&text[..text.len() - 2], Some('X')),
+            Some((_, "Y")) => (&text[..text.len() - 2], Some('Y')),
+            Some(_) => return Err(format!("invalid operand {}", text)),
+            None => (text, None),
+        };
+        let (text, absolute) = match text.strip_prefix("a:") {
+            Some(rest) => (rest, true),
+            None => (text, false),
+        };
+        Ok(Operand::Direct { expr: Expr::parse(text)?, index, absolute })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Statement {
+    Label(String),
+    Constant(String, Expr),
+    Org(Expr),
+    Bytes(Vec<Expr>),
+    Words(Vec<Expr>),
+    Reserve(Expr, Expr),
+    Instruction { mnemonic: String, operand: Operand },
+}
+
+fn is_name(text: &str) -> bool {
+    text.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn parse_list(text: &str) -> Result<Vec<Expr>, String> {
+    text.split(',').map(Expr::parse).collect()
+}
+
+fn parse_line(line: &str) -> Result<Vec<Statement>, String> {
+    let mut text = line.split(';').next().unwrap_or_default().trim();
+    let mut statements = Vec::new();
+    if let Some((label, rest)) = text.split_once(':') {
+        // not the `a:` of an operand
+        if is_name(label.trim()) {
+            statements.push(Statement::Label(label.trim().to_string()));
+            text = rest.trim();
+        }
+    }
+    if let Some((name, value)) = text.split_once('=') {
+        if !is_name(name.trim()) {
+            return Err(format!("invalid name {}", name.trim()));
+        }
+        statements.push(Statement::Constant(name.trim().to_string(), Expr::parse(value)?));
+        return Ok(statements);
+    }
+    if text.is_empty() {
+        return Ok(statements);
+    }
+    let (word, rest) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+    let statement = match word.to_ascii_lowercase().as_str() {
+        ".org" => Statement::Org(Expr::parse(rest)?),
+        ".byte" | ".db" => Statement::Bytes(parse_list(rest)?),
+        ".word" | ".dw" => Statement::Words(parse_list(rest)?),
+        ".res" => match rest.split_once(',') {
+            Some((count, fill)) => Statement::Reserve(Expr::parse(count)?, Expr::parse(fill)?),
+            None => Statement::Reserve(Expr::parse(rest)?, Expr::parse("0")?),
+        },
+        ".segment" => return Ok(statements),
+        directive if directive.starts_with('.') => return Err(format!("unknown directive {}", word)),
+        _ => Statement::Instruction { mnemonic: word.to_ascii_uppercase(), operand: Operand::parse(rest)? },
+    };
+    statements.push(statement);
+    Ok(statements)
+}
+
+fn find(mnemonic: &str, mode: AddressingMode) -> Option<&'static Instruction> {
+    INSTRUCTIONS.find(mnemonic, mode)
+}
+
+// Picks the opcode. Zero page is only chosen for values already known, so a
+// forward reference keeps the size it was given on the first pass.
+fn select(mnemonic: &str, operand: &Operand, symbols: &HashMap<String, i64>) -> Result<&'static Instruction, String> {
+    let instruction = match operand {
+        Operand::None => find(mnemonic, AddressingMode::Implied).or_else(|| find(mnemonic, AddressingMode::Accumulator)),
+        Operand::Accumulator => find(mnemonic, AddressingMode::Accumulator),
+        Operand::Immediate(_) => find(mnemonic, AddressingMode::Immediate),
+        Operand::Indirect(_) => find(mnemonic, AddressingMode::Indirect),
+        Operand::IndirectX(_) => find(mnemonic, AddressingMode::IndirectX),
+        Operand::IndirectY(_) => find(mnemonic, AddressingMode::IndirectY),
+        Operand::Direct { expr, index, absolute } => {
+            let (zero_page, full) = match index {
+                None => (AddressingMode::ZeroPage, AddressingMode::Absolute),
+                Some('X') => (AddressingMode::ZeroPageX, AddressingMode::AbsoluteX),
+                _ => (AddressingMode::ZeroPageY, AddressingMode::AbsoluteY),
+            };
+            let fits = !absolute && expr.evaluate(symbols).is_some_and(|value| (0..=0xFF).contains(&value));
+            let relative = if index.is_none() { find(mnemonic, AddressingMode::Relative) } else { None };
+            relative
+                .or_else(|| if fits { find(mnemonic, zero_page) } else { None })
+                .or_else(|| find(mnemonic, full))
+                .or_else(|| if *absolute { None } else { find(mnemonic, zero_page) })
+        }
+    };
+    instruction.ok_or_else(|| format!("{} can't take that operand", mnemonic))
+}
+
+fn statement_size(statement: &Statement, instruction: Option<&Instruction>, symbols: &HashMap<String, i64>) -> Result<i64, String> {
+    Ok(match statement {
+        Statement::Bytes(values) => values.len() as i64,
+        Statement::Words(values) => values.len() as i64 * 2,
+        Statement::Reserve(count, _) => count.evaluate(symbols).ok_or("the .res count must be known")?,
+        Statement::Instruction { .. } => instruction.map_or(0, |instruction| instruction.bytes as i64),
+        _ => 0,
+    })
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    if (-0x80..=0xFF).contains(&value) {
+        Ok(value as u8)
+    } else {
+        Err(format!("{} doesn't fit in a byte", value))
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    if (-0x8000..=0xFFFF).contains(&value) {
+        Ok(value as u16)
+    } else {
+        Err(format!("{} doesn't fit in a word", value))
+    }
+}
+
+// Assembles source to be loaded at origin
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    assemble_with_symbols(source, origin, &SymbolTable::new())
+}
+
+// Assembles with the names from a symbol file available, for the debugger
+pub fn assemble_with_symbols(source: &str, origin: u16, symbols: &SymbolTable) -> Result<Vec<u8>, AsmError> {
+    let mut lines = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let error = |message| AsmError { line: index + 1, message };
+        lines.extend(parse_line(line).map_err(error)?.into_iter().map(|statement| (index + 1, statement)));
+    }
+    let mut names: HashMap<String, i64> =
+        symbols.names.iter().map(|(&address, name)| (name.clone(), address as i64)).collect();
+
+    // first pass: where everything goes, and with that the value of every label
+    let mut instructions = Vec::with_capacity(lines.len());
+    let mut pc = origin as i64;
+    for (line, statement) in &lines {
+        let error = |message| AsmError { line: *line, message };
+        let instruction = match statement {
+            Statement::Label(name) => {
+                if names.insert(name.clone(), pc).is_some() && symbols.address_of(name).is_none() {
+                    return Err(error(format!("{} is defined twice", name)));
+                }
+                None
+            }
+            Statement::Constant(name, value) => {
+                if let Some(value) = value.evaluate(&names) {
+                    names.insert(name.clone(), value);
+                }
+                None
+            }
+            Statement::Org(address) => {
+                pc = address.evaluate(&names).ok_or_else(|| error("the .org address must be known".to_string()))?;
+                None
+            }
+            Statement::Instruction { mnemonic, operand } => Some(select(mnemonic, operand, &names).map_err(error)?),
+            _ => None,
+        };
+        pc += statement_size(statement, instruction, &names).map_err(error)?;
+        instructions.push(instruction);
+    }
+    // constants defined in terms of later labels
+    for (_, statement) in &lines {
+        if let Statement::Constant(name, value) = statement {
+            if let Some(value) = value.evaluate(&names) {
+                names.insert(name.clone(), value);
+            }
+        }
+    }
+
+    // second pass: the bytes
+    let mut out: Vec<u8> = Vec::new();
+    let mut pc = origin as i64;
+    for ((line, statement), instruction) in lines.iter().zip(instructions) {
+        let error = |message| AsmError { line: *line, message };
+        let value = |expr: &Expr| expr.evaluate(&names).ok_or_else(|| error(format!("undefined name in {}", expr.terms[0].1)));
+        let start = out.len();
+        match statement {
+            Statement::Org(address) => {
+                let address = value(address)?;
+                let offset = address - origin as i64;
+                if offset < out.len() as i64 {
+                    return Err(error(format!(".org ${:04X} goes back over code", address)));
+                }
+                out.resize(offset as usize, 0);
+                pc = address;
+                continue;
+            }
+            Statement::Bytes(values) => {
+                for expr in values {
+                    out.push(byte(value(expr)?).map_err(error)?);
+                }
+            }
+            Statement::Words(values) => {
+                for expr in values {
+                    out.extend(word(value(expr)?).map_err(error)?.to_le_bytes());
+                }
+            }
+            Statement::Reserve(count, fill) => {
+                let fill = byte(value(fill)?).map_err(error)?;
+                out.extend(std::iter::repeat_n(fill, value(count)? as usize));
+            }
+            Statement::Instruction { operand, .. } => {
+                let instruction = instruction.unwrap();
+                out.push(instruction.opcode);
+                let expr = match operand {
+                    Operand::Immediate(expr)
+                    | Operand::Direct { expr, .. }
+                    | Operand::Indirect(expr)
+                    | Operand::IndirectX(expr)
+                    | Operand::IndirectY(expr) => Some(value(expr)?),
+                    Operand::None | Operand::Accumulator => None,
+                };
+                match (instruction.bytes, &instruction.addressing_mode, expr) {
+                    (2, AddressingMode::Relative, Some(target)) => {
+                        let offset = target - (pc + 2);
+                        if !(-0x80..0x80).contains(&offset) {
+                            return Err(error(format!("branch to ${:04X} is out of range", target)));
+                        }
+                        out.push(offset as u8);
+                    }
+                    (2, AddressingMode::Immediate, Some(value)) => out.push(byte(value).map_err(error)?),
+                    (2, _, Some(value)) if (0..=0xFF).contains(&value) => out.push(value as u8),
+                    (2, _, Some(value)) => return Err(error(format!("${:04X} is not a zero page address", value))),
+                    (3, _, Some(value)) => out.extend(word(value).map_err(error)?.to_le_bytes()),
+                    _ => {}
+                }
+            }
+            Statement::Label(_) | Statement::Constant(..) => {}
+        }
+        pc += (out.len() - start) as i64;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disassembler::Disassembly;
+
+    #[test]
+    fn test_assemble() {
+        assert_eq!(assemble("LDA #$C0\nTAX\nINX\nBRK", 0x8000).unwrap(), [0xA9, 0xC0, 0xAA, 0xE8, 0x00]);
+        let source = "
+            count = 3
+            start:  LDX #count      ; loop three times
+            loop:   DEX
+                    STA $10,X
+                    STA a:$10
+                    LDA (zp),Y
+                    BNE loop
+                    JMP (ptr+1)
+                    ASL
+                    LAX $20
+            ptr:    .word start, >ptr
+            zp = ptr-$8000+3
+        ";
+        assert_eq!(
+            assemble(source, 0x8000).unwrap(),
+            [
+                0xA2, 0x03, // LDX #3
+                0xCA, // DEX
+                0x95, 0x10, // STA $10,X
+                0x8D, 0x10, 0x00, // STA $0010
+                0xB1, 0x15, // LDA ($15),Y, zp is only known by the second pass
+                0xD0, 0xF6, // BNE $8002
+                0x6C, 0x13, 0x80, // JMP ($8013)
+                0x0A, // ASL A
+                0xA7, 0x20, // LAX $20
+                0x00, 0x80, 0x80, 0x00,
+            ][..]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |source| assemble(source, 0x8000).unwrap_err().to_string();
+        assert_eq!(error("NOP\nFOO #1"), "line 2: FOO can't take that operand");
+        assert_eq!(error("LDA #$100"), "line 1: 256 doesn't fit in a byte");
+        assert_eq!(error("BNE far"), "line 1: undefined name in far");
+        assert_eq!(error("BEQ $9000"), "line 1: branch to $9000 is out of range");
+        assert_eq!(error("a: NOP\na: NOP"), "line 2: a is defined twice");
+    }
+
+    #[test]
+    fn test_symbols_and_ca65_round_trip() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x2002, "PPUSTATUS");
+        assert_eq!(assemble_with_symbols("BIT PPUSTATUS", 0x8000, &symbols).unwrap(), [0x2C, 0x02, 0x20]);
+
+        // what the disassembler writes assembles back to the same ROM
+        let mut prg = vec![0xFF; 0x4000];
+        let program = assemble("reset: SEI\nloop: LDA $2002\nBPL loop\nJSR sub\nJMP loop\n.byte 1, 2\nsub: INC $10\nRTS", 0xC000).unwrap();
+        prg[..program.len()].copy_from_slice(&program);
+        prg[0x3FFA..].copy_from_slice(&[0x00, 0xC0, 0x00, 0xC0, 0x00, 0xC0]);
+        let source = Disassembly::new(&prg, 0xC000).to_ca65();
+        assert_eq!(assemble(&source, 0xC000).unwrap(), prg);
+    }
+}
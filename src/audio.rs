@@ -0,0 +1,376 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::recording::WavWriter;
+
+// The output ring is kept about half full; the further it strays the harder the
+// resampling ratio is nudged, up to this fraction. Small enough not to be heard.
+const MAX_DRIFT_CORRECTION: f64 = 0.005;
+const IDLE_SLEEP: Duration = Duration::from_millis(1);
+const CHUNK_SIZE: usize = 512;
+
+// Single producer, single consumer sample queue shared between threads.
+// Samples are stored as f32 bits in atomics so neither side ever takes a lock.
+struct Ring {
+    slots: Box<[AtomicU32]>,
+    // total samples ever written and read, the difference is the fill level
+    written: AtomicUsize,
+    read: AtomicUsize,
+}
+
+impl Ring {
+    fn len(&self) -> usize {
+        self.written.load(Ordering::Acquire).wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+pub struct SampleProducer {
+    ring: Arc<Ring>,
+}
+
+pub struct SampleConsumer {
+    ring: Arc<Ring>,
+}
+
+pub fn sample_ring(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    let ring = Arc::new(Ring {
+        slots: (0..capacity.max(1)).map(|_| AtomicU32::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+    });
+    (SampleProducer { ring: ring.clone() }, SampleConsumer { ring })
+}
+
+impl SampleProducer {
+    // Queues as many samples as fit and returns how many that was
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let ring = &self.ring;
+        let written = ring.written.load(Ordering::Relaxed);
+        let count = samples.len().min(ring.slots.len() - ring.len());
+        for (offset, sample) in samples[..count].iter().enumerate() {
+            ring.slots[written.wrapping_add(offset) % ring.slots.len()].store(sample.to_bits(), Ordering::Relaxed);
+        }
+        ring.written.store(written.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+}
+
+impl SampleConsumer {
+    // Fills `out` with the oldest samples and returns how many there were
+    pub fn pop(&mut self, out: &mut [f32]) -> usize {
+        let ring = &self.ring;
+        let read = ring.read.load(Ordering::Relaxed);
+        let count = out.len().min(ring.len());
+        for (offset, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(ring.slots[read.wrapping_add(offset) % ring.slots.len()].load(Ordering::Relaxed));
+        }
+        ring.read.store(read.wrapping_add(count), Ordering::Release);
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+}
+
+// Linear interpolation from the emulated sample rate to the device's
+pub struct Resampler {
+    // input samples per output sample, before drift correction
+    pub ratio: f64,
+    position: f64,
+    previous: f32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        Resampler { ratio: input_rate / output_rate, position: 0.0, previous: 0.0 }
+    }
+
+    // The ratio adjusted for how full the output is: emulation and the audio
+    // device never run at exactly the rates they claim, so a filling buffer is
+    // drained by producing slightly fewer samples and an emptying one topped up
+    pub fn corrected_ratio(&self, fill: usize, capacity: usize) -> f64 {
+        let error = 1.0 - 2.0 * fill as f64 / capacity.max(1) as f64;
+        self.ratio * (1.0 - error * MAX_DRIFT_CORRECTION)
+    }
+
+    pub fn process(&mut self, input: &[f32], ratio: f64, output: &mut Vec<f32>) {
+        for &sample in input {
+            // emit every output sample that falls between the previous input sample and this one
+            while self.position < 1.0 {
+                output.push(self.previous + (sample - self.previous) * self.position as f32);
+                self.position += ratio;
+            }
+            self.position -= 1.0;
+            self.previous = sample;
+        }
+    }
+}
+
+// Resamples audio off the emulation thread. Emulation queues raw samples with
+// queue(); the device callback reads the resampled ones from the consumer.
+pub struct AudioThread {
+    input: SampleProducer,
+    // the device's side, to see how much it has left to play
+    output: Arc<Ring>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl AudioThread {
+    pub fn spawn(input_rate: f64, output_rate: f64, capacity: usize) -> (AudioThread, SampleConsumer) {
+        let (input, mut raw) = sample_ring(capacity);
+        let (mut output, device) = sample_ring(capacity);
+        let output_ring = device.ring.clone();
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let handle = thread::spawn(move || {
+            let mut resampler = Resampler::new(input_rate, output_rate);
+            let mut chunk = [0.0; CHUNK_SIZE];
+            let mut resampled = Vec::new();
+            while thread_running.load(Ordering::Acquire) {
+                let count = raw.pop(&mut chunk);
+                if count == 0 {
+                    thread::sleep(IDLE_SLEEP);
+                    continue;
+                }
+                let ratio = resampler.corrected_ratio(output.len(), output.capacity());
+                resampled.clear();
+                resampler.process(&chunk[..count], ratio, &mut resampled);
+                // when the device has stalled, drop what doesn't fit rather than block emulation
+                output.push(&resampled);
+            }
+        });
+        (AudioThread { input, output: output_ring, running, handle: Some(handle) }, device)
+    }
+
+    // Hands a frame's worth of samples to the audio thread, returns how many fit
+    pub fn queue(&mut self, samples: &[f32]) -> usize {
+        self.input.push(samples)
+    }
+
+    // How full the device's buffer is, 0.0-1.0, for SyncMode::Audio
+    pub fn fill(&self) -> f32 {
+        self.output.len() as f32 / self.output.slots.len() as f32
+    }
+}
+
+impl Drop for AudioThread {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Release);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+// Everything between the emulator's mixed output and the speakers: the resampling
+// thread when there is an audio device, and the optional WAV dump (--dump-audio or
+// its hotkey). The dump gets the samples at the emulated rate, before resampling,
+// so it is the same however fast the host runs.
+pub struct AudioManager {
+    pub sample_rate: u32,
+    output: Option<AudioThread>,
+    dump: Option<(PathBuf, WavWriter<BufWriter<File>>)>,
+    // where the hotkey dumps to, and the hotkey
+    dump_path: PathBuf,
+    dump_key: String,
+}
+
+// Where the dump hotkey writes without a --dump-audio path
+const DEFAULT_DUMP_PATH: &str = "madnes.wav";
+
+impl AudioManager {
+    pub fn new(sample_rate: u32, output: Option<AudioThread>) -> Self {
+        AudioManager {
+            sample_rate,
+            output,
+            dump: None,
+            dump_path: PathBuf::from(DEFAULT_DUMP_PATH),
+            dump_key: Config::default().dump_audio_key,
+        }
+    }
+
+    // Takes the dump settings from the config, starting the dump right away when it
+    // has a --dump-audio path
+    pub fn with_config(sample_rate: u32, output: Option<AudioThread>, config: &Config) -> io::Result<Self> {
+        let mut audio = AudioManager::new(sample_rate, output);
+        audio.dump_key = config.dump_audio_key.clone();
+        if let Some(path) = &config.dump_audio {
+            audio.dump_path = path.clone();
+            audio.start_dump(path)?;
+        }
+        Ok(audio)
+    }
+
+    // Replaces a dump already running
+    pub fn start_dump(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.stop_dump()?;
+        let path = path.as_ref();
+        let writer = WavWriter::new(BufWriter::new(File::create(path)?), self.sample_rate)?;
+        self.dump = Some((path.to_path_buf(), writer));
+        Ok(())
+    }
+
+    // Finishes the WAV header, returns the file and how many samples it has
+    pub fn stop_dump(&mut self) -> io::Result<Option<(PathBuf, u32)>> {
+        match self.dump.take() {
+            Some((path, writer)) => {
+                let samples = writer.samples();
+                writer.finish()?;
+                Ok(Some((path, samples)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    // The runtime toggle, returns whether a dump is now running
+    pub fn toggle_dump(&mut self, path: impl AsRef<Path>) -> io::Result<bool> {
+        if self.is_dumping() {
+            self.stop_dump()?;
+            Ok(false)
+        } else {
+            self.start_dump(path)?;
+            Ok(true)
+        }
+    }
+
+    pub fn is_dumping(&self) -> bool {
+        self.dump.is_some()
+    }
+
+    // Toggles the dump when `key` is the dump hotkey, returning whether one is now
+    // running. Other keys are left for the rest of the frontend.
+    pub fn key_pressed(&mut self, key: &str) -> io::Result<Option<bool>> {
+        if key != self.dump_key {
+            return Ok(None);
+        }
+        let path = self.dump_path.clone();
+        self.toggle_dump(path).map(Some)
+    }
+
+    // Called once per frame with the frame's samples
+    pub fn queue(&mut self, samples: &[f32]) -> io::Result<()> {
+        if let Some(output) = &mut self.output {
+            output.queue(samples);
+        }
+        if let Some((_, writer)) = &mut self.dump {
+            writer.write_samples(samples)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for AudioManager {
+    fn drop(&mut self) {
+        let _ = self.stop_dump();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use std::time::Instant;
+
+    #[test]
+    fn test_sample_ring() {
+        let (mut producer, mut consumer) = sample_ring(4);
+        assert_eq!(producer.push(&[1.0, 2.0, 3.0]), 3);
+        let mut out = [0.0; 2];
+        assert_eq!(consumer.pop(&mut out), 2);
+        assert_eq!(out, [1.0, 2.0]);
+        // wraps around, and stops when full
+        assert_eq!(producer.push(&[4.0, 5.0, 6.0, 7.0]), 3);
+        let mut out = [0.0; 8];
+        assert_eq!(consumer.pop(&mut out), 4);
+        assert_eq!(out[..4], [3.0, 4.0, 5.0, 6.0]);
+        assert!(consumer.is_empty());
+    }
+
+    #[test]
+    fn test_resampler() {
+        let mut resampler = Resampler::new(2.0, 1.0);
+        let mut output = Vec::new();
+        resampler.process(&[0.0, 1.0, 2.0, 3.0], resampler.ratio, &mut output);
+        assert_eq!(output, [0.0, 1.0]);
+
+        let resampler = Resampler::new(44100.0, 44100.0);
+        assert!(resampler.corrected_ratio(0, 100) < 1.0);
+        assert_eq!(resampler.corrected_ratio(50, 100), 1.0);
+        assert!(resampler.corrected_ratio(100, 100) > 1.0);
+    }
+
+    #[test]
+    fn test_audio_thread() {
+        let (mut audio, mut device) = AudioThread::spawn(2.0, 1.0, 1024);
+        assert_eq!(audio.queue(&[0.5; 100]), 100);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while device.len() < 45 && Instant::now() < deadline {
+            thread::sleep(IDLE_SLEEP);
+        }
+        let mut out = [0.0; 64];
+        let count = device.pop(&mut out);
+        assert!((45..=55).contains(&count));
+        assert_eq!(out[count - 1], 0.5);
+    }
+
+    #[test]
+    fn test_dump_audio() {
+        let dir = TestDir::new("audio-dump");
+        let path = dir.join("dump.wav");
+        let mut audio = AudioManager::new(44100, None);
+        audio.queue(&[0.5; 10]).unwrap();
+        assert!(audio.toggle_dump(&path).unwrap());
+        assert!(audio.is_dumping());
+        audio.queue(&[0.25; 100]).unwrap();
+        assert_eq!(audio.stop_dump().unwrap(), Some((path.clone(), 100)));
+        assert_eq!(audio.stop_dump().unwrap(), None);
+        assert!(audio.toggle_dump(&path).unwrap());
+        assert!(!audio.toggle_dump(&path).unwrap());
+        // the header is complete once the dump stops
+        audio.start_dump(&path).unwrap();
+        audio.queue(&[0.25; 100]).unwrap();
+        drop(audio);
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 44 + 200);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 200);
+
+        // --dump-audio starts it, the hotkey stops and restarts it
+        let config = Config { dump_audio: Some(dir.join("music.wav")), ..Config::default() };
+        let mut audio = AudioManager::with_config(44100, None, &config).unwrap();
+        assert!(audio.is_dumping());
+        audio.queue(&[0.5; 10]).unwrap();
+        assert_eq!(audio.key_pressed("F8").unwrap(), None);
+        assert_eq!(audio.key_pressed("F7").unwrap(), Some(false));
+        assert_eq!(std::fs::read(dir.join("music.wav")).unwrap().len(), 44 + 20);
+        assert_eq!(audio.key_pressed("F7").unwrap(), Some(true));
+        assert_eq!(audio.stop_dump().unwrap(), Some((dir.join("music.wav"), 0)));
+    }
+}
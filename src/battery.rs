@@ -0,0 +1,188 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::nes::Nes;
+
+// A game's battery save. It's a bare dump of the cartridge's PRG RAM named after the
+// ROM, <name>.sav, which is what other emulators read and write too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatterySave {
+    pub path: PathBuf,
+    // how often update() writes the RAM out, zero for only on flush()
+    pub interval: Duration,
+    // earlier versions kept as <name>.sav.1 (newest) up to .sav.<backups>
+    pub backups: usize,
+    // the file's contents as of the last load or write, so unchanged RAM isn't rewritten
+    written: Vec<u8>,
+    since_flush: Duration,
+}
+
+impl BatterySave {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration, backups: usize) -> Self {
+        BatterySave { path: path.into(), interval, backups, written: Vec::new(), since_flush: Duration::ZERO }
+    }
+
+    // <saves directory>/<ROM name>.sav, or next to the ROM without a saves directory
+    pub fn for_rom(rom_path: &Path, config: &Config) -> Self {
+        let directory = match &config.saves_directory {
+            Some(directory) => directory.clone(),
+            None => rom_path.parent().map(Path::to_path_buf).unwrap_or_default(),
+        };
+        let mut name = rom_path.file_stem().map(OsString::from).unwrap_or_default();
+        name.push(".sav");
+        BatterySave::new(directory.join(name), config.autosave_interval, config.save_backups)
+    }
+
+    pub fn backup_path(&self, generation: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", generation));
+        path.into()
+    }
+
+    // Restores the .sav into the cartridge. Ok(false) when the game has no battery
+    // or hasn't been saved yet.
+    pub fn load(&mut self, nes: &mut Nes) -> io::Result<bool> {
+        if nes.battery_ram().is_none() {
+            return Ok(false);
+        }
+        let loaded = match fs::read(&self.path) {
+            Ok(data) => nes.load_battery_ram(&data),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+            Err(error) => return Err(error),
+        };
+        self.written = nes.battery_ram().unwrap_or_default().to_vec();
+        Ok(loaded)
+    }
+
+    // Writes the RAM out if it changed since it was last loaded or written,
+    // e.g. on exit or before switching games
+    pub fn flush(&mut self, nes: &Nes) -> io::Result<bool> {
+        self.since_flush = Duration::ZERO;
+        let Some(ram) = nes.battery_ram() else {
+            return Ok(false);
+        };
+        if ram == self.written.as_slice() {
+            return Ok(false);
+        }
+        self.rotate_backups()?;
+        write_atomic(&self.path, ram)?;
+        self.written = ram.to_vec();
+        Ok(true)
+    }
+
+    // Called every frame with the time since the last call; flushes every `interval`
+    pub fn update(&mut self, nes: &Nes, elapsed: Duration) -> io::Result<bool> {
+        if self.interval.is_zero() {
+            return Ok(false);
+        }
+        self.since_flush += elapsed;
+        if self.since_flush < self.interval {
+            return Ok(false);
+        }
+        self.flush(nes)
+    }
+
+    // Shifts .sav.1 to .sav.2 and so on, dropping the oldest, then copies the
+    // current file to .sav.1. The .sav itself stays put until it's replaced.
+    fn rotate_backups(&self) -> io::Result<()> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for generation in (1..self.backups).rev() {
+            let older = self.backup_path(generation);
+            if older.exists() {
+                fs::rename(older, self.backup_path(generation + 1))?;
+            }
+        }
+        fs::copy(&self.path, self.backup_path(1))?;
+        Ok(())
+    }
+}
+
+// Writes a temporary file next to `path` and renames it into place, so a crash
+// part way through leaves the old file rather than half of the new one
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+    use crate::testdir::TestDir;
+
+    fn console(battery: bool) -> Nes {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, if battery { 0x02 } else { 0 }, 0)).unwrap()).unwrap();
+        nes
+    }
+
+    #[test]
+    fn test_sav_path() {
+        let mut config = Config::default();
+        let save = BatterySave::for_rom(Path::new("roms/Game (USA).zip"), &config);
+        assert_eq!(save.path, Path::new("roms/Game (USA).sav"));
+        assert_eq!(save.backup_path(2), Path::new("roms/Game (USA).sav.2"));
+
+        config.saves_directory = Some("saves".into());
+        assert_eq!(BatterySave::for_rom(Path::new("roms/game.nes"), &config).path, Path::new("saves/game.sav"));
+    }
+
+    #[test]
+    fn test_flush_and_load() {
+        let directory = TestDir::new("battery-flush");
+        let mut save = BatterySave::new(directory.join("game.sav"), Duration::ZERO, 0);
+        let mut nes = console(true);
+        assert!(!save.load(&mut nes).unwrap());
+        // nothing changed yet
+        assert!(!save.flush(&nes).unwrap());
+
+        nes.poke(0x6000, 0x42);
+        assert!(save.flush(&nes).unwrap());
+        assert_eq!(fs::read(&save.path).unwrap()[0], 0x42);
+        assert!(!directory.join("game.sav.tmp").exists());
+
+        let mut restored = console(true);
+        let mut save = BatterySave::new(directory.join("game.sav"), Duration::ZERO, 0);
+        assert!(save.load(&mut restored).unwrap());
+        assert_eq!(restored.peek(0x6000), 0x42);
+
+        // no battery, no file
+        let mut plain = console(false);
+        plain.poke(0x6000, 0x42);
+        let mut save = BatterySave::new(directory.join("plain.sav"), Duration::ZERO, 0);
+        assert!(!save.load(&mut plain).unwrap());
+        assert!(!save.flush(&plain).unwrap());
+    }
+
+    #[test]
+    fn test_autosave_and_backups() {
+        let directory = TestDir::new("battery-backups");
+        let mut save = BatterySave::new(directory.join("game.sav"), Duration::from_secs(10), 2);
+        let mut nes = console(true);
+        save.load(&mut nes).unwrap();
+        for value in 1..=4 {
+            nes.poke(0x6000, value);
+            assert!(!save.update(&nes, Duration::from_secs(6)).unwrap());
+            assert!(save.update(&nes, Duration::from_secs(6)).unwrap());
+        }
+        let first_byte = |path: PathBuf| fs::read(path).unwrap()[0];
+        assert_eq!(first_byte(save.path.clone()), 4);
+        assert_eq!(first_byte(save.backup_path(1)), 3);
+        assert_eq!(first_byte(save.backup_path(2)), 2);
+        assert!(!save.backup_path(3).exists());
+    }
+}
@@ -0,0 +1,109 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::nes::Nes;
+use crate::timing::NTSC_FRAME_DURATION;
+use crate::trace::{TraceChannel, Tracer};
+
+// How fast the console ran headless, with nothing slowing it to real time
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BenchReport {
+    pub frames: u64,
+    pub instructions: u64,
+    pub elapsed: Duration,
+    pub slowest_frame: Duration,
+    // formatting trace lines, included in elapsed
+    pub trace: Duration,
+}
+
+impl BenchReport {
+    pub fn emulated_seconds(&self) -> f64 {
+        self.frames as f64 * NTSC_FRAME_DURATION.as_secs_f64()
+    }
+
+    // Emulated seconds per wall clock second, 1.0 is full speed
+    pub fn speed(&self) -> f64 {
+        self.emulated_seconds() / self.elapsed.as_secs_f64()
+    }
+
+    pub fn average_frame(&self) -> Duration {
+        self.elapsed.div_f64(self.frames.max(1) as f64)
+    }
+}
+
+impl fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let seconds = self.elapsed.as_secs_f64();
+        let share = |part: Duration| part.as_secs_f64() / seconds * 100.0;
+        writeln!(f, "{} frames ({:.1}s emulated) in {:.2}s", self.frames, self.emulated_seconds(), seconds)?;
+        writeln!(f, "speed          {:.2}x", self.speed())?;
+        writeln!(f, "average frame  {:.3}ms", self.average_frame().as_secs_f64() * 1000.0)?;
+        writeln!(f, "slowest frame  {:.3}ms", self.slowest_frame.as_secs_f64() * 1000.0)?;
+        writeln!(f, "instructions   {} ({:.2}M/s)", self.instructions, self.instructions as f64 / seconds / 1e6)?;
+        // the bus runs the PPU and APU a cycle at a time between CPU accesses, so
+        // they're timed together with the CPU
+        writeln!(f, "emulation      {:.1}%", share(self.elapsed - self.trace))?;
+        write!(f, "trace          {:.1}%", share(self.trace))
+    }
+}
+
+// Runs `frames` frames as fast as possible, timing the trace logging separately
+pub fn run(nes: &mut Nes, frames: u64, tracer: &mut Tracer) -> BenchReport {
+    let tracing = tracer.is_enabled(TraceChannel::Cpu);
+    let tracing_writes = tracer.traces_writes();
+    let mut report = BenchReport::default();
+    let start = Instant::now();
+    for _ in 0..frames {
+        let frame_start = Instant::now();
+        let frame = nes.cpu().bus.ppu.frame;
+        while nes.cpu().bus.ppu.frame == frame && nes.fault().is_none() {
+            if tracing {
+                let trace_start = Instant::now();
+                tracer.log_cpu(nes.cpu());
+                report.trace += trace_start.elapsed();
+            }
+            if tracing_writes {
+                nes.cpu_mut().bus.write_log = Some(Vec::new());
+            }
+            let pc = nes.cpu().pc;
+            nes.step_instruction();
+            if tracing_writes {
+                let writes = nes.cpu_mut().bus.write_log.take().unwrap_or_default();
+                tracer.log_writes(pc, &writes);
+            }
+            report.instructions += 1;
+        }
+        if nes.fault().is_some() {
+            break;
+        }
+        report.slowest_frame = report.slowest_frame.max(frame_start.elapsed());
+        report.frames += 1;
+    }
+    report.elapsed = start.elapsed();
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::nes_with_program;
+    use crate::trace::TraceSink;
+
+    #[test]
+    fn test_bench_run() {
+        // NOP; JMP $8000
+        let mut nes = nes_with_program(&[0xEA, 0x4C, 0x00, 0x80]);
+
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(1));
+        let report = run(&mut nes, 2, &mut tracer);
+        assert_eq!(report.frames, 2);
+        // a frame is about 29780 CPU cycles, two instructions every 5 cycles
+        assert!((23800..=23840).contains(&report.instructions));
+        assert!(report.trace <= report.elapsed);
+        assert!(report.to_string().starts_with("2 frames (0.0s emulated)"));
+        // a little over 60 frames a second
+        let report = BenchReport { frames: 601, ..BenchReport::default() };
+        assert!((report.emulated_seconds() - 10.0).abs() < 0.001);
+    }
+}
@@ -0,0 +1,192 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::nes::{Nes, NesError};
+use crate::rom::{Rom, RomError};
+
+// blargg's test ROMs report through cartridge RAM:
+//   0x6000         status, 0x80 while running, 0x81 when the ROM wants a reset, otherwise the result code
+//   0x6001-0x6003  DE B0 61 once the status is valid
+//   0x6004-        NUL-terminated text of what the ROM printed
+const STATUS_ADDRESS: u16 = 0x6000;
+const SIGNATURE_ADDRESS: u16 = 0x6001;
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const OUTPUT_ADDRESS: u16 = 0x6004;
+const OUTPUT_END: u16 = 0x7FFF;
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_NEEDS_RESET: u8 = 0x81;
+
+// The ROMs ask for the reset button to be held at least 100ms
+const RESET_DELAY_FRAMES: u64 = 6;
+// Long enough for the full cpu_instrs image
+const DEFAULT_TIMEOUT_FRAMES: u64 = 60 * 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestStatus {
+    // the signature has not been written yet
+    Starting,
+    Running,
+    NeedsReset,
+    Passed,
+    Failed(u8),
+}
+
+#[derive(Debug)]
+pub enum RomTestError {
+    Rom(RomError),
+    Nes(NesError),
+    Timeout { frames: u64, output: String },
+    Failed { code: u8, output: String },
+}
+
+impl fmt::Display for RomTestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomTestError::Rom(error) => write!(f, "{}", error),
+            RomTestError::Nes(error) => write!(f, "{}", error),
+            RomTestError::Timeout { frames, output } => {
+                write!(f, "No result after {} frames\n{}", frames, output)
+            }
+            RomTestError::Failed { code, output } => write!(f, "Failed with code {}\n{}", code, output),
+        }
+    }
+}
+
+impl From<RomError> for RomTestError {
+    fn from(error: RomError) -> Self {
+        RomTestError::Rom(error)
+    }
+}
+
+impl From<NesError> for RomTestError {
+    fn from(error: NesError) -> Self {
+        RomTestError::Nes(error)
+    }
+}
+
+// Runs a test ROM headlessly until it reports a result
+pub struct RomTestRunner {
+    pub nes: Nes,
+    pub timeout_frames: u64,
+}
+
+impl RomTestRunner {
+    pub fn new(rom: Rom) -> Result<Self, RomTestError> {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(rom)?;
+        Ok(RomTestRunner {
+            nes,
+            timeout_frames: DEFAULT_TIMEOUT_FRAMES,
+        })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, RomTestError> {
+        Self::new(Rom::load(path)?)
+    }
+
+    pub fn status(&self) -> TestStatus {
+        let signature = [0, 1, 2].map(|offset| self.nes.peek(SIGNATURE_ADDRESS + offset));
+        if signature != SIGNATURE {
+            return TestStatus::Starting;
+        }
+        match self.nes.peek(STATUS_ADDRESS) {
+            STATUS_RUNNING => TestStatus::Running,
+            STATUS_NEEDS_RESET => TestStatus::NeedsReset,
+            0 => TestStatus::Passed,
+            code => TestStatus::Failed(code),
+        }
+    }
+
+    // The text the ROM has printed so far
+    pub fn output(&self) -> String {
+        (OUTPUT_ADDRESS..=OUTPUT_END)
+            .map(|address| self.nes.peek(address))
+            .take_while(|&byte| byte != 0)
+            .map(char::from)
+            .collect()
+    }
+
+    // Runs until the ROM passes, fails or times out
+    pub fn run(&mut self) -> Result<String, RomTestError> {
+        let mut reset_at = None;
+        for frame in 0..self.timeout_frames {
+            self.nes.step_frame();
+            match self.status() {
+                TestStatus::Passed => return Ok(self.output()),
+                TestStatus::Failed(code) => {
+                    return Err(RomTestError::Failed {
+                        code,
+                        output: self.output(),
+                    })
+                }
+                TestStatus::NeedsReset => {
+                    let at = *reset_at.get_or_insert(frame + RESET_DELAY_FRAMES);
+                    if frame >= at {
+                        reset_at = None;
+                        // the ROM clears the request itself, but not before we see it again
+                        self.nes.poke(STATUS_ADDRESS, STATUS_RUNNING);
+                        self.nes.reset()?;
+                    }
+                }
+                TestStatus::Starting | TestStatus::Running => {}
+            }
+        }
+        Err(RomTestError::Timeout {
+            frames: self.timeout_frames,
+            output: self.output(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines_with_program;
+
+    // A ROM that writes the given status and "ok", then spins
+    fn result_rom(status: u8, signed: bool) -> Rom {
+        let mut program = vec![];
+        let mut store = |value: u8, address: u16| {
+            let [low, high] = address.to_le_bytes();
+            program.extend_from_slice(&[0xA9, value, 0x8D, low, high]);
+        };
+        if signed {
+            for (offset, byte) in SIGNATURE.iter().enumerate() {
+                store(*byte, SIGNATURE_ADDRESS + offset as u16);
+            }
+        }
+        for (offset, byte) in b"ok\0".iter().enumerate() {
+            store(*byte, OUTPUT_ADDRESS + offset as u16);
+        }
+        store(status, STATUS_ADDRESS);
+        let end = 0x8000 + program.len() as u16;
+        program.push(0x4C);
+        program.extend_from_slice(&end.to_le_bytes());
+
+        Rom::new(&ines_with_program(&program)).unwrap()
+    }
+
+    #[test]
+    fn test_passing_rom() {
+        let mut runner = RomTestRunner::new(result_rom(0, true)).unwrap();
+        assert_eq!(runner.status(), TestStatus::Starting);
+        assert_eq!(runner.run().unwrap(), "ok");
+        assert_eq!(runner.status(), TestStatus::Passed);
+    }
+
+    #[test]
+    fn test_failing_rom() {
+        let mut runner = RomTestRunner::new(result_rom(3, true)).unwrap();
+        match runner.run() {
+            Err(RomTestError::Failed { code, output }) => assert_eq!((code, output.as_str()), (3, "ok")),
+            result => panic!("expected a failure, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn test_timeout_without_signature() {
+        let mut runner = RomTestRunner::new(result_rom(0, false)).unwrap();
+        runner.timeout_frames = 3;
+        assert!(matches!(runner.run(), Err(RomTestError::Timeout { frames: 3, .. })));
+    }
+}
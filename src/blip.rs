@@ -0,0 +1,151 @@
+// Band-limited step synthesis, in the manner of blip_buf. The APU's channels change
+// level at exact CPU cycles; picking one level per output sample instead aliases
+// high pitched pulse waves into audible noise. Here every level change is drawn as
+// a windowed-sinc step at its fractional sample position, so the output holds no
+// frequencies above the output's Nyquist rate.
+
+// Samples each step is spread over, and the fractional positions it is drawn at
+const KERNEL_TAPS: usize = 16;
+const PHASES: usize = 32;
+// Fraction of the Nyquist frequency kept, the rest is the filter's transition band
+const CUTOFF: f64 = 0.9;
+
+#[derive(Clone)]
+pub struct BlipBuffer {
+    clocks_per_sample: f64,
+    // where the current frame starts, in output samples from buffer[0]
+    time: f64,
+    // sample to sample differences, summed by the integrator as they are read
+    buffer: Vec<f32>,
+    integrator: f32,
+    amplitude: f32,
+    kernel: Box<[[f32; KERNEL_TAPS]; PHASES]>,
+}
+
+impl BlipBuffer {
+    // `clock_rate` is what times are given in, the CPU clock for the APU
+    pub fn new(clock_rate: f64, sample_rate: f64) -> Self {
+        BlipBuffer {
+            clocks_per_sample: clock_rate / sample_rate,
+            time: 0.0,
+            buffer: Vec::new(),
+            integrator: 0.0,
+            amplitude: 0.0,
+            kernel: Box::new(step_kernel()),
+        }
+    }
+
+    // Sets the level from `clock` cycles into the current frame on
+    pub fn set_amplitude(&mut self, clock: u64, amplitude: f32) {
+        let delta = amplitude - self.amplitude;
+        if delta != 0.0 {
+            self.amplitude = amplitude;
+            self.add_delta(clock, delta);
+        }
+    }
+
+    pub fn add_delta(&mut self, clock: u64, delta: f32) {
+        let position = self.time + clock as f64 / self.clocks_per_sample;
+        let sample = position as usize;
+        let phase = ((position - sample as f64) * PHASES as f64) as usize;
+        if self.buffer.len() < sample + KERNEL_TAPS {
+            self.buffer.resize(sample + KERNEL_TAPS, 0.0);
+        }
+        for (value, weight) in self.buffer[sample..].iter_mut().zip(&self.kernel[phase]) {
+            *value += delta * weight;
+        }
+    }
+
+    // Ends the frame after `clocks` cycles, making its samples available
+    pub fn end_frame(&mut self, clocks: u64) {
+        self.time += clocks as f64 / self.clocks_per_sample;
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.time as usize
+    }
+
+    // Appends the finished samples to `output`. The last KERNEL_TAPS / 2 samples
+    // of a step wait for the next frame, a fixed delay of under half a millisecond.
+    pub fn read_samples(&mut self, output: &mut Vec<f32>) {
+        let count = self.samples_available();
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0.0);
+        }
+        for delta in self.buffer.drain(..count) {
+            self.integrator += delta;
+            output.push(self.integrator);
+        }
+        self.time -= count as f64;
+    }
+}
+
+// A band-limited impulse for each phase. Summed up by the integrator it becomes a
+// step, so each phase is normalized to add up to exactly 1.
+fn step_kernel() -> [[f32; KERNEL_TAPS]; PHASES] {
+    let mut kernel = [[0.0; KERNEL_TAPS]; PHASES];
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let offset = phase as f64 / PHASES as f64;
+        let mut weights = [0.0f64; KERNEL_TAPS];
+        for (tap, weight) in weights.iter_mut().enumerate() {
+            // distance from the step, the kernel is centered on the middle tap
+            let x = tap as f64 - (KERNEL_TAPS / 2) as f64 - offset;
+            let sinc = if x == 0.0 { 1.0 } else { (std::f64::consts::PI * CUTOFF * x).sin() / (std::f64::consts::PI * CUTOFF * x) };
+            // Blackman window over the kernel's width
+            let w = (x + KERNEL_TAPS as f64 / 2.0) / KERNEL_TAPS as f64;
+            let window = 0.42 - 0.5 * (2.0 * std::f64::consts::PI * w).cos() + 0.08 * (4.0 * std::f64::consts::PI * w).cos();
+            *weight = sinc * window.max(0.0);
+        }
+        let sum: f64 = weights.iter().sum();
+        for (tap, weight) in taps.iter_mut().zip(weights) {
+            *tap = (weight / sum) as f32;
+        }
+    }
+    kernel
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CPU_CLOCK: f64 = 1_789_773.0;
+
+    #[test]
+    fn test_step_settles() {
+        let mut blip = BlipBuffer::new(CPU_CLOCK, 44100.0);
+        blip.set_amplitude(1000, 1.0);
+        blip.end_frame(29781);
+        let mut output = Vec::new();
+        blip.read_samples(&mut output);
+        assert_eq!(output.len(), 733);
+        // silent before the step, level after it, with a little ringing in between
+        assert_eq!(output[0], 0.0);
+        assert!((output[100] - 1.0).abs() < 1e-5);
+        assert!(output.iter().all(|&sample| (-0.15..=1.15).contains(&sample)));
+
+        // the fraction of a sample left over carries into the next frame
+        blip.end_frame(29781);
+        blip.read_samples(&mut output);
+        assert_eq!(output.len(), 1467);
+        assert!((output[1466] - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_high_pitch_has_no_alias() {
+        // a 31.1kHz square wave, far above what 44.1kHz can hold. Point sampling
+        // turns it into a loud audible tone; band-limited it is nearly silent.
+        let half_period = 29;
+        let level = |clock: u64| if (clock / half_period).is_multiple_of(2) { 1.0 } else { -1.0 };
+        let mut blip = BlipBuffer::new(CPU_CLOCK, 44100.0);
+        for clock in (0..CPU_CLOCK as u64 / 10).step_by(half_period as usize) {
+            blip.set_amplitude(clock, level(clock));
+        }
+        let point_sampled: Vec<f32> = (0..4410).map(|sample| level((sample as f64 * CPU_CLOCK / 44100.0) as u64)).collect();
+        blip.end_frame(CPU_CLOCK as u64 / 10);
+        let mut output = Vec::new();
+        blip.read_samples(&mut output);
+        let rms = |samples: &[f32]| (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt();
+        assert!(rms(&output[100..4000]) < 0.1);
+        assert!(rms(&point_sampled[100..4000]) > 0.9);
+    }
+}
@@ -0,0 +1,202 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::config::Config;
+
+const MAX_RECENT: usize = 10;
+const ROM_EXTENSION: &str = "nes";
+
+// Most recently loaded ROMs first, kept in recent.txt next to the config file
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct RecentRoms {
+    pub paths: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn default_path() -> Option<PathBuf> {
+        Some(Config::directory()?.join("recent.txt"))
+    }
+
+    // One path per line, a missing file is an empty list
+    pub fn load(path: impl AsRef<Path>) -> io::Result<RecentRoms> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(RecentRoms::default()),
+            Err(error) => return Err(error),
+        };
+        let paths = text.lines().filter(|line| !line.trim().is_empty()).map(PathBuf::from).take(MAX_RECENT).collect();
+        Ok(RecentRoms { paths })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(directory) = path.parent() {
+            fs::create_dir_all(directory)?;
+        }
+        let mut text = String::new();
+        for rom in &self.paths {
+            text.push_str(&rom.to_string_lossy());
+            text.push('\n');
+        }
+        fs::write(path, text)
+    }
+
+    // Moves the ROM to the top of the list
+    pub fn add(&mut self, path: impl Into<PathBuf>) {
+        let path = path.into();
+        self.paths.retain(|recent| *recent != path);
+        self.paths.insert(0, path);
+        self.paths.truncate(MAX_RECENT);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Recent,
+    Parent,
+    Directory,
+    Rom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BrowserEntry {
+    pub kind: EntryKind,
+    pub path: PathBuf,
+}
+
+impl BrowserEntry {
+    // The line shown in the menu
+    pub fn label(&self) -> String {
+        let name = self.path.file_name().map(|name| name.to_string_lossy().into_owned());
+        match self.kind {
+            EntryKind::Parent => "../".to_string(),
+            EntryKind::Directory => format!("{}/", name.unwrap_or_default()),
+            EntryKind::Recent => format!("* {}", name.unwrap_or_else(|| self.path.to_string_lossy().into_owned())),
+            EntryKind::Rom => name.unwrap_or_default(),
+        }
+    }
+}
+
+// The menu shown when madNES starts without a ROM: recent ROMs, then the
+// current directory with subdirectories before .nes files
+pub struct RomBrowser {
+    pub directory: PathBuf,
+    pub entries: Vec<BrowserEntry>,
+    pub selected: usize,
+    recent: Vec<PathBuf>,
+}
+
+impl RomBrowser {
+    pub fn open(directory: impl Into<PathBuf>, recent: &RecentRoms) -> io::Result<RomBrowser> {
+        let mut browser = RomBrowser {
+            directory: directory.into(),
+            entries: Vec::new(),
+            selected: 0,
+            recent: recent.paths.clone(),
+        };
+        browser.refresh()?;
+        Ok(browser)
+    }
+
+    // Rereads the directory, keeping the selection in range
+    pub fn refresh(&mut self) -> io::Result<()> {
+        let mut directories = Vec::new();
+        let mut roms = Vec::new();
+        for entry in fs::read_dir(&self.directory)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                directories.push(path);
+            } else if path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case(ROM_EXTENSION)) {
+                roms.push(path);
+            }
+        }
+        directories.sort();
+        roms.sort();
+
+        self.entries = self.recent.iter().map(|path| BrowserEntry { kind: EntryKind::Recent, path: path.clone() }).collect();
+        if let Some(parent) = self.directory.parent() {
+            self.entries.push(BrowserEntry { kind: EntryKind::Parent, path: parent.to_path_buf() });
+        }
+        self.entries.extend(directories.into_iter().map(|path| BrowserEntry { kind: EntryKind::Directory, path }));
+        self.entries.extend(roms.into_iter().map(|path| BrowserEntry { kind: EntryKind::Rom, path }));
+        self.selected = self.selected.min(self.entries.len().saturating_sub(1));
+        Ok(())
+    }
+
+    // Up and down arrows, wrapping around
+    pub fn move_selection(&mut self, delta: isize) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let count = self.entries.len() as isize;
+        self.selected = (self.selected as isize + delta).rem_euclid(count) as usize;
+    }
+
+    pub fn selected_entry(&self) -> Option<&BrowserEntry> {
+        self.entries.get(self.selected)
+    }
+
+    // Enter: descends into directories, or returns the ROM to load
+    pub fn activate(&mut self) -> io::Result<Option<PathBuf>> {
+        let Some(entry) = self.selected_entry().cloned() else {
+            return Ok(None);
+        };
+        match entry.kind {
+            EntryKind::Parent | EntryKind::Directory => {
+                self.directory = entry.path;
+                self.selected = 0;
+                self.refresh()?;
+                Ok(None)
+            }
+            EntryKind::Recent | EntryKind::Rom => Ok(Some(entry.path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_recent_roms() {
+        let mut recent = RecentRoms::default();
+        for index in 0..12 {
+            recent.add(format!("game{}.nes", index));
+        }
+        recent.add("game5.nes");
+        assert_eq!(recent.paths.len(), MAX_RECENT);
+        assert_eq!(recent.paths[0], PathBuf::from("game5.nes"));
+        assert_eq!(recent.paths.iter().filter(|path| path.as_path() == Path::new("game5.nes")).count(), 1);
+
+        let dir = TestDir::new("browser-recent");
+        let path = dir.join("madnes").join("recent.txt");
+        recent.save(&path).unwrap();
+        assert_eq!(RecentRoms::load(&path).unwrap(), recent);
+        assert_eq!(RecentRoms::load(path.with_file_name("missing.txt")).unwrap(), RecentRoms::default());
+    }
+
+    #[test]
+    fn test_browse() {
+        let dir = TestDir::new("browser-browse");
+        fs::create_dir(dir.join("homebrew")).unwrap();
+        fs::write(dir.join("homebrew").join("demo.NES"), []).unwrap();
+        fs::write(dir.join("b.nes"), []).unwrap();
+        fs::write(dir.join("a.nes"), []).unwrap();
+        fs::write(dir.join("notes.txt"), []).unwrap();
+        let recent = RecentRoms { paths: vec!["/roms/smb.nes".into()] };
+
+        let mut browser = RomBrowser::open(dir.path(), &recent).unwrap();
+        let labels: Vec<String> = browser.entries.iter().map(BrowserEntry::label).collect();
+        assert_eq!(labels, ["* smb.nes", "../", "homebrew/", "a.nes", "b.nes"]);
+        assert_eq!(browser.activate().unwrap(), Some(PathBuf::from("/roms/smb.nes")));
+
+        browser.move_selection(-1);
+        assert_eq!(browser.activate().unwrap(), Some(dir.join("b.nes")));
+        browser.move_selection(-2);
+        assert_eq!(browser.activate().unwrap(), None);
+        assert_eq!(browser.directory, dir.join("homebrew"));
+        assert_eq!(browser.entries.last().unwrap().label(), "demo.NES");
+    }
+}
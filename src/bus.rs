@@ -0,0 +1,402 @@
+use crate::apu::{Apu, CPU_CLOCK_RATE, SAMPLE_RATE};
+use crate::blip::BlipBuffer;
+use crate::cpu::{Bus, RAM_SIZE};
+use crate::input::{Device, InputDevice};
+use crate::cartridge::Cartridge;
+use crate::mixer::{ChannelScope, FilterChain, Mixer, Mixing, CHANNEL_COUNT};
+use crate::ppu::{IoLatch, Ppu};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+// What the internal RAM holds at power on. Real consoles come up with a mix that
+// differs between units, and a few games read RAM before clearing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RamPattern {
+    #[default]
+    Zeros,
+    Ones,
+    // four bytes of $00 then four of $FF, like FCEUX
+    Alternating,
+}
+
+impl RamPattern {
+    pub fn parse(name: &str) -> Option<RamPattern> {
+        match name.to_ascii_lowercase().as_str() {
+            "zeros" | "00" => Some(RamPattern::Zeros),
+            "ones" | "ff" => Some(RamPattern::Ones),
+            "alternating" | "fceux" => Some(RamPattern::Alternating),
+            _ => None,
+        }
+    }
+
+    pub fn byte(self, address: usize) -> u8 {
+        match self {
+            RamPattern::Zeros => 0x00,
+            RamPattern::Ones => 0xFF,
+            RamPattern::Alternating if address & 4 == 0 => 0x00,
+            RamPattern::Alternating => 0xFF,
+        }
+    }
+}
+
+// The console's CPU address space:
+//   0x0000-0x1FFF  2KB internal RAM, mirrored
+//   0x2000-0x3FFF  PPU registers
+//   0x4000-0x4013  APU channels
+//   0x4014         OAM DMA
+//   0x4015         APU status
+//   0x4016-0x4017  controllers, and the APU frame counter on writes to 0x4017
+//   0x4020-0xFFFF  cartridge, through its mapper
+// Reads of write-only and unmapped registers return open bus instead of 0, as
+// some games expect.
+pub struct NesBus {
+    pub ram: [u8; RAM_SIZE],
+    pub cartridge: Option<Cartridge>,
+    // controller ports 1 and 2, standard controllers unless something else is connected
+    pub ports: [Box<dyn InputDevice>; 2],
+    // the last byte on the CPU data bus, which nothing drives on unmapped reads
+    pub open_bus: u8,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    // the APU's and the cartridge's sound, mixed into `blip` every cycle it changes
+    pub mixer: Mixer,
+    pub blip: BlipBuffer,
+    // applied to the samples read out of `blip` with accurate mixing
+    pub filters: FilterChain,
+    // CPU cycles since the last end_audio_frame
+    pub audio_clock: u64,
+    // the mixer inputs last drawn into `blip`
+    last_outputs: ([u8; CHANNEL_COUNT], f32),
+    // the channel outputs about once per output sample, while the debugger shows them
+    pub scope: Option<ChannelScope>,
+    // CPU cycles ticked so far, the clock the latch decays by
+    pub cycles: u64,
+    // the page written to $4014, until the CPU copies it
+    pub oam_dma: Option<u8>,
+    // every write while Some, for memory write hooks and write tracing
+    pub write_log: Option<Vec<(u16, u8)>>,
+}
+
+impl Default for NesBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NesBus {
+    pub fn new() -> Self {
+        NesBus {
+            ram: [0; RAM_SIZE],
+            cartridge: None,
+            ports: [Device::Joypad.create(), Device::Joypad.create()],
+            open_bus: 0,
+            ppu: Ppu::new(),
+            apu: Apu::new(),
+            mixer: Mixer::new(),
+            blip: BlipBuffer::new(CPU_CLOCK_RATE, SAMPLE_RATE as f64),
+            filters: FilterChain::new(SAMPLE_RATE as f32),
+            audio_clock: 0,
+            last_outputs: ([0; CHANNEL_COUNT], 0.0),
+            scope: None,
+            cycles: 0,
+            oam_dma: None,
+            write_log: None,
+        }
+    }
+
+    pub fn insert_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = Some(cartridge);
+    }
+
+    pub fn fill_ram(&mut self, pattern: RamPattern) {
+        for (address, byte) in self.ram.iter_mut().enumerate() {
+            *byte = pattern.byte(address);
+        }
+    }
+
+    // Appends the samples mixed since the last call, at SAMPLE_RATE
+    pub fn end_audio_frame(&mut self, output: &mut Vec<f32>) {
+        self.blip.end_frame(std::mem::take(&mut self.audio_clock));
+        let start = output.len();
+        self.blip.read_samples(output);
+        if self.mixer.mixing == Mixing::Accurate {
+            for sample in &mut output[start..] {
+                *sample = self.filters.process(*sample);
+            }
+        }
+    }
+
+    // Feeds the blip buffer the mixer's level for the cycle just clocked
+    fn mix(&mut self, expansion: f32) {
+        let outputs = (self.apu.outputs(), expansion);
+        if outputs != self.last_outputs {
+            self.last_outputs = outputs;
+            self.blip.set_amplitude(self.audio_clock, self.mixer.mix_with_expansion(outputs.0, outputs.1));
+        }
+    }
+}
+
+impl Bus for NesBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = match address {
+            0x2000..=0x3FFF => self.ppu.read_register(address, self.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper), self.cycles),
+            // bit 5 isn't driven
+            0x4015 => self.apu.read_status() | self.open_bus & 0x20,
+            0x4016 => self.ports[0].read(&self.ppu) | self.open_bus & JOYPAD_OPEN_BUS_BITS,
+            0x4017 => self.ports[1].read(&self.ppu) | self.open_bus & JOYPAD_OPEN_BUS_BITS,
+            0x4020..=0xFFFF => self.cartridge.as_mut().map_or(self.open_bus, |cartridge| cartridge.read_prg(address)),
+            _ => self.peek(address),
+        };
+        self.open_bus = value;
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.open_bus = value;
+        if let Some(log) = &mut self.write_log {
+            log.push((address, value));
+        }
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize % RAM_SIZE] = value,
+            0x2000..=0x3FFF => self.ppu.write_register(address, value, self.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper), self.cycles),
+            0x4000..=0x4013 | 0x4015 => self.apu.write_register(address, value),
+            0x4014 => self.oam_dma = Some(value),
+            // the strobe is wired to both ports
+            0x4016 => self.ports.iter_mut().for_each(|port| port.strobe(value)),
+            0x4017 => self.apu.write_register(address, value),
+            0x4020..=0xFFFF => {
+                if let Some(cartridge) = &mut self.cartridge {
+                    cartridge.mapper.write_prg(address, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        match address {
+            0x0000..=0x1FFF => self.ram[address as usize % RAM_SIZE],
+            0x4015 => self.apu.peek_status() | self.open_bus & 0x20,
+            0x4016 => self.ports[0].peek(&self.ppu),
+            0x4017 => self.ports[1].peek(&self.ppu),
+            0x2000..=0x3FFF => self.ppu.peek_register(address, self.cycles),
+            0x4020..=0xFFFF => self.cartridge.as_ref().map_or(0, |cartridge| cartridge.peek_prg(address)),
+            // e.g. the high byte of the address for `LDA $4018`
+            _ => self.open_bus,
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.cycles += cycles as u64;
+        let expansion = self.cartridge.as_ref().map_or(0.0, |cartridge| cartridge.mapper.audio_output());
+        for _ in 0..cycles {
+            for _ in 0..3 {
+                self.ppu.tick(self.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper));
+            }
+            self.apu.clock();
+            if let Some(address) = self.apu.dmc.fetch_address() {
+                let value = self.cartridge.as_mut().map_or(self.open_bus, |cartridge| cartridge.read_prg(address));
+                self.apu.dmc.load_sample(value);
+            }
+            self.mix(expansion);
+            if let Some(scope) = &mut self.scope {
+                if self.audio_clock.is_multiple_of(SCOPE_INTERVAL) {
+                    scope.record(self.apu.outputs());
+                }
+            }
+            self.audio_clock += 1;
+        }
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.mapper.tick(cycles);
+        }
+    }
+
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma.take()
+    }
+
+    fn ppu_mut(&mut self) -> Option<&mut Ppu> {
+        Some(&mut self.ppu)
+    }
+
+    fn read_ppu(&mut self, address: u16) -> Option<u8> {
+        Some(self.ppu.peek_vram(address, self.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper)))
+    }
+
+    fn write_ppu(&mut self, address: u16, value: u8) -> bool {
+        self.ppu.write_vram(address, value, self.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper));
+        true
+    }
+
+    fn patch_prg(&mut self, address: u16, value: u8) -> bool {
+        self.cartridge.as_mut().is_some_and(|cartridge| cartridge.patch_prg(address, value))
+    }
+
+    fn clear_prg_patches(&mut self) {
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.clear_patches();
+        }
+    }
+
+    fn changes_prg(&self, address: u16) -> bool {
+        address >= 0x4020 && self.cartridge.as_ref().is_some_and(|cartridge| cartridge.mapper.changes_prg(address))
+    }
+}
+
+// CPU cycles between the scope's records, close to one 44.1kHz sample
+const SCOPE_INTERVAL: u64 = 40;
+
+// The controller ports only drive the low five data lines
+const JOYPAD_OPEN_BUS_BITS: u8 = 0xE0;
+
+impl SaveState for NesBus {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ram);
+        if let Some(cartridge) = &self.cartridge {
+            cartridge.save_state(out);
+        }
+        for port in &self.ports {
+            port.save_state(out);
+        }
+        self.ppu.save_state(out);
+        self.apu.save_state(out);
+        out.push(self.ppu.latch.read(self.cycles));
+        out.push(self.open_bus);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let ram = input.read_bytes(RAM_SIZE)?;
+        self.ram.copy_from_slice(ram);
+        if let Some(cartridge) = &mut self.cartridge {
+            cartridge.load_state(input)?;
+        }
+        for port in &mut self.ports {
+            port.load_state(input)?;
+        }
+        self.ppu.load_state(input)?;
+        self.apu.load_state(input)?;
+        // the decay starts over from the load
+        let latch = input.read_u8()?;
+        self.ppu.latch = IoLatch::default();
+        self.ppu.latch.write(latch, self.cycles);
+        self.open_bus = input.read_u8()?;
+        Ok(())
+    }
+
+    fn rom_crc32(&self) -> u32 {
+        self.cartridge.as_ref().map_or(0, Cartridge::crc32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::joypad::JoypadButton;
+    use crate::mapper::Nrom;
+    use crate::ppu::IO_LATCH_DECAY_CYCLES;
+    use crate::rom::tests::{ines, ines_with_program};
+    use crate::rom::Rom;
+
+    fn cartridge(data: &[u8]) -> NesBus {
+        let mut bus = NesBus::new();
+        bus.insert_cartridge(Cartridge::from_mapper(Box::new(Nrom::new(&Rom::new(data).unwrap()))));
+        bus
+    }
+
+    #[test]
+    fn test_ram_mirroring() {
+        let mut bus = cartridge(&ines(1, 1, 0, 0));
+        bus.write(0x0801, 0x42);
+        assert_eq!(bus.read(0x0001), 0x42);
+        assert_eq!(bus.peek(0x1801), 0x42);
+        bus.write(0x6000, 0x11);
+        assert_eq!(bus.read(0x6000), 0x11);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // LDA #$02; STA $4014
+        let mut cpu = Cpu::with_bus(cartridge(&ines_with_program(&[0xA9, 0x02, 0x8D, 0x14, 0x40])));
+        cpu.reset();
+        for (index, byte) in cpu.bus.ram[0x200..0x300].iter_mut().enumerate() {
+            *byte = index as u8;
+        }
+        cpu.bus.ppu.oam_address = 0x10;
+        cpu.step();
+        assert_eq!(cpu.step(), 4 + 513);
+        // the copy starts at OAMADDR and wraps around
+        assert_eq!((cpu.bus.ppu.oam[0x10], cpu.bus.ppu.oam[0x0F], cpu.bus.ppu.oam_address), (0x00, 0xFF, 0x10));
+        assert_eq!(cpu.bus.cycles, 2 + 4 + 513);
+    }
+
+    #[test]
+    fn test_apu_registers() {
+        let mut data = ines(1, 1, 0, 0);
+        data[16 + 0x0040] = 0xFF;
+        let mut bus = cartridge(&data);
+        // a one byte sample at $C040, which the DMC fetches on the next cycle
+        bus.write(0x4012, 0x01);
+        bus.write(0x4015, 0x10);
+        bus.write(0x0000, 0x20);
+        assert_eq!(bus.read(0x4015), 0x30);
+        bus.tick(1);
+        assert_eq!((bus.apu.dmc.buffer, bus.peek(0x4015)), (Some(0xFF), 0x20));
+        // writes to $4017 go to the frame counter, reads to controller 2
+        bus.write(0x4017, 0x40);
+        assert_eq!(bus.apu.frame_control, 0x40);
+        assert_eq!(bus.read(0x4017) & 0x1F, 0x00);
+    }
+
+    #[test]
+    fn test_prg_rom_mirroring() {
+        let mut data = ines(1, 1, 0, 0);
+        data[16] = 0x4C;
+        let mut bus = cartridge(&data);
+        assert_eq!(bus.read(0x8000), 0x4C);
+        assert_eq!(bus.read(0xC000), 0x4C);
+        // ROM is not writable
+        bus.write(0x8000, 0x00);
+        assert_eq!(bus.read(0x8000), 0x4C);
+    }
+
+    #[test]
+    fn test_joypad_shift_register() {
+        let mut bus = NesBus::new();
+        bus.ports[0].set_buttons(JoypadButton::A | JoypadButton::Start);
+        bus.ports[1].set_buttons(JoypadButton::B);
+        bus.write(0x4016, 1);
+        // reads return A while the strobe is held
+        assert_eq!(bus.read(0x4016), 1);
+        assert_eq!(bus.read(0x4016), 1);
+        bus.write(0x4016, 0);
+
+        let bits: Vec<u8> = (0..9).map(|_| bus.read(0x4016)).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 0, 1]);
+        assert_eq!(bus.peek(0x4017), 0);
+        bus.read(0x4017);
+        assert_eq!(bus.read(0x4017), 1);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut bus = NesBus::new();
+        // unmapped reads see the last byte on the bus
+        bus.write(0x0000, 0x40);
+        assert_eq!(bus.read(0x4018), 0x40);
+        bus.read(0x0001);
+        assert_eq!(bus.read(0x4000), 0x00);
+
+        bus.write(0x2000, 0xFF);
+        assert_eq!(bus.read(0x2005), 0xFF);
+        // $2002 drives its own top bits, clearing them in the latch
+        assert_eq!(bus.read(0x2002), 0x1F);
+        assert_eq!(bus.peek(0x3FF8), 0x1F);
+
+        bus.tick(200);
+        bus.write(0x2001, 0x03);
+        bus.cycles = IO_LATCH_DECAY_CYCLES + 100;
+        assert_eq!(bus.read(0x2006), 0x03);
+        bus.cycles += 200;
+        assert_eq!(bus.read(0x2006), 0x00);
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::BTreeMap;
+
+use crate::mapper::{self, Mapper};
+use crate::rom::{Rom, RomError, TRAINER_ADDRESS};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+// What's in the cartridge slot: the image it was made from and the board running it.
+// The board holds PRG ROM, CHR ROM or RAM and PRG RAM, and the bus that owns the
+// cartridge lends it to the PPU for every VRAM access, so the CPU and PPU sides
+// always see the same banks.
+pub struct Cartridge {
+    // None for a Famicom Disk System disk, which has no iNES image
+    rom: Option<Rom>,
+    pub mapper: Box<dyn Mapper>,
+    // PRG ROM bytes changed from the debugger, by CPU address, read in place of the
+    // board's whichever bank is switched in. Resets and power cycles keep them.
+    patches: BTreeMap<u16, u8>,
+    // Rom::crc32, kept for savestates to check against
+    crc32: u32,
+}
+
+impl Cartridge {
+    pub fn new(rom: Rom) -> Result<Cartridge, RomError> {
+        let mapper = mapper::create(&rom)?;
+        let crc32 = rom.crc32();
+        Ok(Cartridge { rom: Some(rom), mapper, patches: BTreeMap::new(), crc32 })
+    }
+
+    // A board without an iNES image behind it, like the disk system's RAM adapter
+    pub fn from_mapper(mapper: Box<dyn Mapper>) -> Cartridge {
+        Cartridge { rom: None, mapper, patches: BTreeMap::new(), crc32: 0 }
+    }
+
+    pub fn rom(&self) -> Option<&Rom> {
+        self.rom.as_ref()
+    }
+
+    // The CRC-32 of the iNES image's PRG and CHR, 0 without one
+    pub fn crc32(&self) -> u32 {
+        self.crc32
+    }
+
+    pub fn into_rom(self) -> Option<Rom> {
+        self.rom
+    }
+
+    // The CPU's view of $4020-$FFFF, with the patches over it
+    pub fn read_prg(&mut self, address: u16) -> u8 {
+        match self.patches.get(&address) {
+            Some(&value) => value,
+            None => self.mapper.read_prg(address),
+        }
+    }
+
+    pub fn peek_prg(&self, address: u16) -> u8 {
+        match self.patches.get(&address) {
+            Some(&value) => value,
+            None => self.mapper.peek_prg(address),
+        }
+    }
+
+    // False below $8000, where there's RAM or registers to write to instead
+    pub fn patch_prg(&mut self, address: u16, value: u8) -> bool {
+        if address < 0x8000 {
+            return false;
+        }
+        self.patches.insert(address, value);
+        true
+    }
+
+    pub fn patches(&self) -> &BTreeMap<u16, u8> {
+        &self.patches
+    }
+
+    pub fn clear_patches(&mut self) {
+        self.patches.clear();
+    }
+
+    // The 512 byte trainer and where it goes in PRG RAM, for the console to copy
+    // in at power on
+    pub fn trainer(&self) -> Option<(u16, &[u8])> {
+        Some((TRAINER_ADDRESS, self.rom.as_ref()?.trainer.as_deref()?))
+    }
+
+    // What goes in the game's .sav file: its PRG RAM, when the cartridge has a battery
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        if !self.rom.as_ref()?.has_battery {
+            return None;
+        }
+        self.mapper.save_ram()
+    }
+
+    pub fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        if !self.rom.as_ref()?.has_battery {
+            return None;
+        }
+        self.mapper.save_ram_mut()
+    }
+
+    // Restores a .sav file. Files from emulators that save a different amount of
+    // RAM are cut short or padded with zeros. False without a battery.
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> bool {
+        let Some(ram) = self.battery_ram_mut() else {
+            return false;
+        };
+        let count = data.len().min(ram.len());
+        ram[..count].copy_from_slice(&data[..count]);
+        ram[count..].fill(0);
+        true
+    }
+
+    // The board as it comes up from power off, keeping what's on the battery.
+    // A disk stays in the drive as it is.
+    pub fn power_cycle(&mut self) {
+        let Some(rom) = &self.rom else {
+            return;
+        };
+        // created from the same ROM before, so this can't fail
+        let Ok(mut mapper) = mapper::create(rom) else {
+            return;
+        };
+        if let (true, Some(old), Some(new)) = (rom.has_battery, self.mapper.save_ram(), mapper.save_ram_mut()) {
+            new.copy_from_slice(old);
+        }
+        self.mapper = mapper;
+    }
+}
+
+// The image is the same on both sides, so only the board's registers and RAM are saved
+impl SaveState for Cartridge {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        self.mapper.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.mapper.load_state(input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mapper::PRG_RAM_SIZE;
+    use crate::rom::tests::ines;
+
+    #[test]
+    fn test_battery_ram() {
+        let mut cartridge = Cartridge::new(Rom::new(&ines(1, 1, 0x02, 0)).unwrap()).unwrap();
+        cartridge.mapper.write_prg(0x6001, 0x11);
+        assert!(cartridge.load_battery_ram(&[0x42]));
+        // short files are padded out
+        assert_eq!((cartridge.mapper.read_prg(0x6000), cartridge.mapper.read_prg(0x6001)), (0x42, 0));
+        assert_eq!(cartridge.battery_ram().unwrap().len(), PRG_RAM_SIZE);
+
+        cartridge.power_cycle();
+        assert_eq!(cartridge.mapper.read_prg(0x6000), 0x42);
+
+        let mut cartridge = Cartridge::new(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        assert!(cartridge.battery_ram().is_none());
+        assert!(!cartridge.load_battery_ram(&[0x42]));
+        assert!(cartridge.trainer().is_none());
+        assert!(Cartridge::new(Rom::new(&ines(1, 1, 0x10, 0)).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_prg_patches() {
+        let mut cartridge = Cartridge::new(Rom::new(&ines(1, 1, 0x02, 0)).unwrap()).unwrap();
+        assert!(cartridge.patch_prg(0xC000, 0xEA));
+        assert!(!cartridge.patch_prg(0x6000, 0xEA));
+        // a 16KB image is mirrored, the patch is only where it was made
+        assert_eq!((cartridge.read_prg(0xC000), cartridge.peek_prg(0x8000)), (0xEA, 0));
+        cartridge.power_cycle();
+        assert_eq!(cartridge.peek_prg(0xC000), 0xEA);
+        cartridge.clear_patches();
+        assert_eq!(cartridge.read_prg(0xC000), 0);
+    }
+}
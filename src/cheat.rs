@@ -0,0 +1,230 @@
+use std::fs;
+use std::path::Path;
+
+use crate::config::ConfigError;
+use crate::cpu::RAM_SIZE;
+use crate::nes::Nes;
+
+// A RAM cheat: the byte at `address` is rewritten every frame, freezing it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cheat {
+    pub name: String,
+    pub address: u16,
+    pub value: u8,
+    pub enabled: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CheatList {
+    pub cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Replaces any cheat already on the address
+    pub fn add(&mut self, name: impl Into<String>, address: u16, value: u8) {
+        self.cheats.retain(|cheat| cheat.address != address);
+        self.cheats.push(Cheat { name: name.into(), address, value, enabled: true });
+    }
+
+    pub fn remove(&mut self, address: u16) -> bool {
+        let count = self.cheats.len();
+        self.cheats.retain(|cheat| cheat.address != address);
+        self.cheats.len() != count
+    }
+
+    // A cheat file has one cheat a line, the address and value in hex and then
+    // its name, and # comments:
+    //
+    //   0075:09 Infinite lives
+    //   -07A0:01 Start on world 8, off until switched on
+    pub fn parse(text: &str) -> Result<CheatList, ConfigError> {
+        let mut list = CheatList::new();
+        for (index, line) in text.lines().enumerate() {
+            let syntax = |message: &str| ConfigError::Syntax { line: index + 1, message: message.to_string() };
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (enabled, line) = match line.strip_prefix('-') {
+                Some(line) => (false, line),
+                None => (true, line),
+            };
+            let (code, name) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let (address, value) = code.split_once(':').ok_or_else(|| syntax("expected ADDRESS:VALUE"))?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| syntax("invalid address"))?;
+            let value = u8::from_str_radix(value, 16).map_err(|_| syntax("invalid value"))?;
+            list.add(name.trim(), address, value);
+            if let Some(cheat) = list.cheats.last_mut() {
+                cheat.enabled = enabled;
+            }
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<CheatList, ConfigError> {
+        CheatList::parse(&fs::read_to_string(path)?)
+    }
+
+    // Called once per frame, before the frame runs
+    pub fn apply(&self, nes: &mut Nes) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            nes.poke(cheat.address, cheat.value);
+        }
+    }
+}
+
+// How a RAM search narrows its candidates, like FCEUX's cheat finder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchFilter {
+    // against the previous snapshot
+    Changed,
+    Unchanged,
+    Increased,
+    Decreased,
+    ChangedBy(i16),
+    // against a known value
+    Equal(u8),
+    NotEqual(u8),
+    Greater(u8),
+    Less(u8),
+}
+
+impl SearchFilter {
+    fn matches(self, previous: u8, current: u8) -> bool {
+        match self {
+            SearchFilter::Changed => current != previous,
+            SearchFilter::Unchanged => current == previous,
+            SearchFilter::Increased => current > previous,
+            SearchFilter::Decreased => current < previous,
+            SearchFilter::ChangedBy(delta) => current as i16 - previous as i16 == delta,
+            SearchFilter::Equal(value) => current == value,
+            SearchFilter::NotEqual(value) => current != value,
+            SearchFilter::Greater(value) => current > value,
+            SearchFilter::Less(value) => current < value,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SearchResult {
+    pub address: u16,
+    pub previous: u8,
+    pub current: u8,
+}
+
+// Scans the 2KB of internal RAM for a game variable. Start with every address,
+// then after each change in the game filter against the last snapshot, e.g.
+// "decreased" after losing a life, until a handful of candidates remain.
+#[derive(Debug, Clone)]
+pub struct RamSearch {
+    pub snapshot: [u8; RAM_SIZE],
+    pub candidates: Vec<u16>,
+}
+
+impl RamSearch {
+    pub fn new(ram: &[u8; RAM_SIZE]) -> Self {
+        RamSearch { snapshot: *ram, candidates: (0..RAM_SIZE as u16).collect() }
+    }
+
+    // Starts over with every address
+    pub fn reset(&mut self, ram: &[u8; RAM_SIZE]) {
+        *self = RamSearch::new(ram);
+    }
+
+    // Keeps the candidates that pass and takes a new snapshot. Returns how many are left.
+    pub fn filter(&mut self, ram: &[u8; RAM_SIZE], filter: SearchFilter) -> usize {
+        let snapshot = &self.snapshot;
+        self.candidates
+            .retain(|&address| filter.matches(snapshot[address as usize], ram[address as usize]));
+        self.snapshot = *ram;
+        self.candidates.len()
+    }
+
+    pub fn results(&self, ram: &[u8; RAM_SIZE]) -> Vec<SearchResult> {
+        self.candidates
+            .iter()
+            .map(|&address| SearchResult {
+                address,
+                previous: self.snapshot[address as usize],
+                current: ram[address as usize],
+            })
+            .collect()
+    }
+
+    // Turns a candidate into a cheat holding its current value
+    pub fn promote(&self, address: u16, ram: &[u8; RAM_SIZE], cheats: &mut CheatList) -> bool {
+        if !self.candidates.contains(&address) {
+            return false;
+        }
+        cheats.add(format!("${:04X}", address), address, ram[address as usize]);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_parse_cheat_file() {
+        let list = CheatList::parse("# SMB\n0075:09 Infinite lives\n\n-07a0:1  Start on world 8 # later\n").unwrap();
+        assert_eq!(
+            list.cheats,
+            [
+                Cheat { name: "Infinite lives".to_string(), address: 0x0075, value: 0x09, enabled: true },
+                Cheat { name: "Start on world 8".to_string(), address: 0x07A0, value: 0x01, enabled: false },
+            ]
+        );
+        assert!(matches!(CheatList::parse("0075 09"), Err(ConfigError::Syntax { line: 1, .. })));
+        assert!(matches!(CheatList::parse("\n0075:100"), Err(ConfigError::Syntax { line: 2, .. })));
+    }
+
+    #[test]
+    fn test_ram_search() {
+        let mut ram = [0; RAM_SIZE];
+        ram[0x0075] = 3;
+        ram[0x0300] = 3;
+        let mut search = RamSearch::new(&ram);
+        assert_eq!(search.filter(&ram, SearchFilter::Equal(3)), 2);
+
+        // lose a life
+        ram[0x0075] = 2;
+        ram[0x0300] = 4;
+        assert_eq!(search.filter(&ram, SearchFilter::ChangedBy(-1)), 1);
+        ram[0x0075] = 1;
+        let results = search.results(&ram);
+        assert_eq!(results, [SearchResult { address: 0x0075, previous: 2, current: 1 }]);
+        assert_eq!(search.filter(&ram, SearchFilter::Decreased), 1);
+
+        search.reset(&ram);
+        assert_eq!(search.filter(&ram, SearchFilter::Unchanged), RAM_SIZE);
+    }
+
+    #[test]
+    fn test_promote_to_cheat() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        nes.poke(0x0075, 9);
+        let search = RamSearch::new(&nes.cpu().bus.ram);
+        let mut cheats = CheatList::new();
+        assert!(search.promote(0x0075, &nes.cpu().bus.ram, &mut cheats));
+        assert!(!search.promote(0x0800, &nes.cpu().bus.ram, &mut cheats));
+        assert_eq!(cheats.cheats[0].name, "$0075");
+        *nes.cheats_mut() = cheats;
+
+        nes.poke(0x0075, 0);
+        nes.step_frame();
+        assert_eq!(nes.peek(0x0075), 9);
+        nes.cheats_mut().cheats[0].enabled = false;
+        nes.poke(0x0075, 0);
+        nes.step_frame();
+        assert_eq!(nes.peek(0x0075), 0);
+        assert!(nes.cheats_mut().remove(0x0075));
+    }
+}
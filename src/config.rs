@@ -0,0 +1,911 @@
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use bitflags::bitflags;
+
+use crate::bus::RamPattern;
+use crate::filter::Filter;
+use crate::gamedb::Region;
+use crate::hash::to_hex;
+use crate::input::{Device, PowerPadButton};
+use crate::joypad::{JoypadButton, Turbo};
+use crate::mixer::{Channel, Mixer, Mixing, MAX_GAIN};
+use crate::nes::MAX_RUN_AHEAD;
+use crate::netplay::NetplayConfig;
+use crate::options::EmulatorOptions;
+use crate::palette::Palette;
+use crate::ppu::Layers;
+use crate::rom::Rom;
+use crate::symbols::SymbolTable;
+use crate::timing::SyncMode;
+use crate::trace::{TraceChannel, TraceFilter};
+use crate::view::{ToolWindows, View, MAX_OVERSCAN};
+
+// Host input name (keyboard key or game controller button) to the button it presses
+pub type Bindings = HashMap<String, JoypadButton>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub scale: u32,
+    // multiple of real time, 0 for uncapped
+    pub speed: f32,
+    // what paces emulation, see SyncMode
+    pub sync: SyncMode,
+    // built-in palette name or .pal file, see Palette::select
+    pub palette: String,
+    pub filter: Filter,
+    pub view: View,
+    // crop_left hides a layer from the leftmost 8 pixels whatever the game's PPUMASK says
+    pub layers: Layers,
+    // tint the leftmost 8 pixels where a layer is clipped, see filter::mark_left_clip
+    pub show_left_clip: bool,
+    // master volume, per-channel gains and mutes
+    pub mixer: Mixer,
+    // WAV file the sound is written to from startup, and the key that starts and
+    // stops writing it, see AudioManager
+    pub dump_audio: Option<PathBuf>,
+    pub dump_audio_key: String,
+    // indexed by player
+    pub keyboard: [Bindings; 2],
+    pub gamepad: [Bindings; 2],
+    // inputs that autofire their button, e.g. turbo_a = "S"
+    pub turbo_keyboard: [Bindings; 2],
+    pub turbo_gamepad: [Bindings; 2],
+    pub turbo: Turbo,
+    // what's plugged into controller ports 1 and 2
+    pub ports: [Device; 2],
+    // keys for the Power Pad's squares, e.g. button1 = "U"
+    pub power_pad: HashMap<String, PowerPadButton>,
+    pub trace: TraceChannel,
+    pub trace_file: Option<PathBuf>,
+    pub trace_buffer: usize,
+    // e.g. trace_filter = "pc:$C000-$C0FF, write:$2006-$2007"
+    pub trace_filters: Vec<TraceFilter>,
+    // port to serve the debugger on, see remote.rs
+    pub remote_port: Option<u16>,
+    // the Famicom Disk System BIOS, disksys.rom in the config directory when unset
+    pub fds_bios: Option<PathBuf>,
+    // key that flips the disk to its next side
+    pub fds_switch_side_key: String,
+    // the console's reset button, and switching it off and on
+    pub reset_key: String,
+    pub power_cycle_key: String,
+    // rereads the config file, see Config::reload
+    pub reload_config_key: String,
+    // internal RAM contents after a power cycle
+    pub ram_pattern: RamPattern,
+    // tool windows open at startup, and the keys that open and close them
+    pub tool_windows: ToolWindows,
+    pub debugger_key: String,
+    pub ppu_viewer_key: String,
+    pub netplay: NetplayConfig,
+    // where .sav files go, next to the ROM when unset
+    pub saves_directory: Option<PathBuf>,
+    // how often battery RAM is written out while playing, zero for only on exit
+    pub autosave_interval: Duration,
+    // older .sav files to keep, see BatterySave
+    pub save_backups: usize,
+    // while the window is in the background, see FrameLimiter::window_event
+    pub pause_on_focus_loss: bool,
+    pub mute_on_focus_loss: bool,
+    // the console's region whatever the ROM says, when set
+    pub region: Option<Region>,
+    // frames to run ahead of the one shown, see Nes::step_frame_ahead
+    pub run_ahead: u32,
+    // a cheat file to load with the game, from its [game] section
+    pub cheats: Option<PathBuf>,
+    // [game."<sha1>"] sections, keyed by upper case SHA-1, see Config::for_game
+    pub games: HashMap<String, GameConfig>,
+}
+
+bitflags! {
+    // What Config::reload changed, for the parts of the emulator that hold on to
+    // those settings to pick them up
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ConfigChanges: u8 {
+        // keyboard, gamepad, turbo and Power Pad bindings and the hotkeys
+        const Bindings = 1 << 0;
+        const Palette = 1 << 1;
+        // volumes, mutes and mixing
+        const Audio = 1 << 2;
+        // filter, scaling, overscan and layers
+        const Video = 1 << 3;
+        // anything else changed in the file, which is left alone until a restart
+        const NeedsRestart = 1 << 4;
+    }
+}
+
+// Notices the config file being saved, by its modification time. Polled from the
+// UI loop, which is cheaper than it sounds at a stat a second.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigWatcher {
+    pub path: PathBuf,
+    modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        let path = path.into();
+        let modified = ConfigWatcher::modified(&path);
+        ConfigWatcher { path, modified }
+    }
+
+    fn modified(path: &Path) -> Option<SystemTime> {
+        fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+    }
+
+    // True once for every time the file has changed since the last call. A file
+    // that's been deleted doesn't count until it's back.
+    pub fn poll(&mut self) -> bool {
+        let modified = ConfigWatcher::modified(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
+
+// Settings for one game that go over the global ones
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GameConfig {
+    pub region: Option<Region>,
+    pub port2: Option<Device>,
+    pub overscan: Option<u32>,
+    pub cheats: Option<PathBuf>,
+    pub run_ahead: Option<u32>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Syntax { line: usize, message: String },
+    InvalidValue { key: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(error) => write!(f, "Could not read config: {}", error),
+            ConfigError::Syntax { line, message } => write!(f, "Config line {}: {}", line, message),
+            ConfigError::InvalidValue { key, message } => write!(f, "Config key {}: {}", key, message),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(error: io::Error) -> Self {
+        ConfigError::Io(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+}
+
+fn bindings(pairs: &[(&str, JoypadButton)]) -> Bindings {
+    pairs.iter().map(|(name, button)| (name.to_string(), *button)).collect()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            scale: 3,
+            speed: 1.0,
+            sync: SyncMode::Timer,
+            palette: "ntsc".to_string(),
+            filter: Filter::Nearest,
+            view: View::default(),
+            layers: Layers::default(),
+            show_left_clip: false,
+            mixer: Mixer::default(),
+            dump_audio: None,
+            dump_audio_key: "F7".to_string(),
+            keyboard: [
+                bindings(&[
+                    ("X", JoypadButton::A),
+                    ("Z", JoypadButton::B),
+                    ("RShift", JoypadButton::Select),
+                    ("Return", JoypadButton::Start),
+                    ("Up", JoypadButton::Up),
+                    ("Down", JoypadButton::Down),
+                    ("Left", JoypadButton::Left),
+                    ("Right", JoypadButton::Right),
+                ]),
+                Bindings::new(),
+            ],
+            gamepad: [
+                bindings(&[
+                    ("b", JoypadButton::A),
+                    ("a", JoypadButton::B),
+                    ("back", JoypadButton::Select),
+                    ("start", JoypadButton::Start),
+                    ("dpup", JoypadButton::Up),
+                    ("dpdown", JoypadButton::Down),
+                    ("dpleft", JoypadButton::Left),
+                    ("dpright", JoypadButton::Right),
+                ]),
+                Bindings::new(),
+            ],
+            turbo_keyboard: [bindings(&[("S", JoypadButton::A), ("A", JoypadButton::B)]), Bindings::new()],
+            turbo_gamepad: [Bindings::new(), Bindings::new()],
+            turbo: Turbo::default(),
+            ports: [Device::Joypad; 2],
+            // the mat's 3x4 grid on the right hand side of the keyboard
+            power_pad: [
+                "U", "I", "O", "P", "J", "K", "L", "Semicolon", "M", "Comma", "Period", "Slash",
+            ]
+            .iter()
+            .enumerate()
+            .map(|(index, key)| (key.to_string(), PowerPadButton::from_bits_truncate(1 << index)))
+            .collect(),
+            trace: TraceChannel::empty(),
+            trace_file: None,
+            trace_buffer: 0,
+            trace_filters: Vec::new(),
+            remote_port: None,
+            fds_bios: None,
+            fds_switch_side_key: "F6".to_string(),
+            reset_key: "R".to_string(),
+            power_cycle_key: "F2".to_string(),
+            reload_config_key: "F8".to_string(),
+            ram_pattern: RamPattern::Zeros,
+            tool_windows: ToolWindows::empty(),
+            debugger_key: "F12".to_string(),
+            ppu_viewer_key: "F11".to_string(),
+            netplay: NetplayConfig::default(),
+            saves_directory: None,
+            autosave_interval: Duration::from_secs(30),
+            save_backups: 0,
+            pause_on_focus_loss: false,
+            mute_on_focus_loss: false,
+            region: None,
+            run_ahead: 0,
+            cheats: None,
+            games: HashMap::new(),
+        }
+    }
+}
+
+impl Config {
+    // $XDG_CONFIG_HOME/madnes, falling back to ~/.config
+    pub fn directory() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(base.join("madnes"))
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        Some(Config::directory()?.join("config.toml"))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Config, ConfigError> {
+        Config::parse(&fs::read_to_string(path)?)
+    }
+
+    // Loads the given file, or the default one when it exists
+    pub fn load_or_default(path: Option<&Path>) -> Result<Config, ConfigError> {
+        match path {
+            Some(path) => Config::load(path),
+            None => match Config::default_path() {
+                Some(path) if path.exists() => Config::load(path),
+                _ => Ok(Config::default()),
+            },
+        }
+    }
+
+    // Parses the TOML subset used by the config file: [sections], key = value pairs
+    // with string, integer, float and boolean values, and # comments
+    pub fn parse(text: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        parse_toml(text, |section, key, value| config.set(section, key, value))?;
+        Ok(config)
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: Value) -> Result<(), ConfigError> {
+        let name = format!("{}.{}", section, key);
+        let invalid = |message: &str| ConfigError::InvalidValue { key: name.clone(), message: message.to_string() };
+
+        if let Some(hash) = section.strip_prefix("game.") {
+            let hash = hash.trim_matches('"');
+            if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(invalid("section must be game.\"<SHA-1>\""));
+            }
+            let game = self.games.entry(hash.to_ascii_uppercase()).or_default();
+            match (key, value) {
+                ("region", Value::String(region)) => {
+                    game.region = Some(Region::parse(&region).ok_or_else(|| invalid("unknown region"))?)
+                }
+                ("port2", Value::String(device)) => {
+                    game.port2 = Some(Device::parse(&device).ok_or_else(|| invalid("unknown device"))?)
+                }
+                ("overscan", Value::Integer(lines)) if (0..=MAX_OVERSCAN as i64).contains(&lines) => {
+                    game.overscan = Some(lines as u32)
+                }
+                ("cheats", Value::String(path)) => game.cheats = Some(path.into()),
+                ("run_ahead", Value::Integer(frames)) if (0..=MAX_RUN_AHEAD as i64).contains(&frames) => {
+                    game.run_ahead = Some(frames as u32)
+                }
+                _ => return Err(invalid("unknown key or invalid value")),
+            }
+            return Ok(());
+        }
+
+        match (section, key, value) {
+            ("video", "scale", Value::Integer(scale)) if (1..=16).contains(&scale) => self.scale = scale as u32,
+            ("emulation", "speed", Value::Float(speed)) if speed >= 0.0 => self.speed = speed as f32,
+            ("emulation", "speed", Value::Integer(speed)) if speed >= 0 => self.speed = speed as f32,
+            ("emulation", "pause_on_focus_loss", Value::Boolean(enabled)) => self.pause_on_focus_loss = enabled,
+            ("emulation", "sync", Value::String(mode)) => {
+                self.sync = SyncMode::parse(&mode).ok_or_else(|| invalid("must be \"timer\", \"audio\" or \"video\""))?
+            }
+            ("emulation", "ram_pattern", Value::String(pattern)) => {
+                self.ram_pattern = RamPattern::parse(&pattern).ok_or_else(|| invalid("must be \"zeros\", \"ones\" or \"alternating\""))?
+            }
+            ("emulation", "reset_key", Value::String(key)) => self.reset_key = key,
+            ("emulation", "region", Value::String(region)) => {
+                self.region = Some(Region::parse(&region).ok_or_else(|| invalid("unknown region"))?)
+            }
+            ("emulation", "run_ahead", Value::Integer(frames)) if (0..=MAX_RUN_AHEAD as i64).contains(&frames) => {
+                self.run_ahead = frames as u32
+            }
+            ("emulation", "power_cycle_key", Value::String(key)) => self.power_cycle_key = key,
+            ("emulation", "reload_config_key", Value::String(key)) => self.reload_config_key = key,
+            ("video", "palette", Value::String(palette)) => self.palette = palette,
+            ("video", "filter", Value::String(filter)) => {
+                self.filter = Filter::parse(&filter).ok_or_else(|| invalid("unknown filter"))?
+            }
+            ("video", "aspect_correction", Value::Boolean(enabled)) => self.view.aspect_correction = enabled,
+            ("video", "integer_scaling", Value::Boolean(enabled)) => self.view.integer_scaling = enabled,
+            ("video", "fullscreen", Value::Boolean(enabled)) => self.view.fullscreen = enabled,
+            ("video", "overscan", Value::Integer(lines)) if (0..=MAX_OVERSCAN as i64).contains(&lines) => {
+                self.view.overscan = lines as u32
+            }
+            ("video", "crop_left", Value::String(layers)) => {
+                if !self.layers.set_crop_left(&layers) {
+                    return Err(invalid("must be \"none\", \"background\", \"sprites\" or \"both\""));
+                }
+            }
+            ("audio", "volume", Value::Float(volume)) if (0.0..=1.0).contains(&volume) => self.mixer.master = volume as f32,
+            ("audio", "volume", Value::Integer(volume)) if (0..=1).contains(&volume) => self.mixer.master = volume as f32,
+            ("audio", "mute", Value::String(channels)) => {
+                for name in channels.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                    let channel = Channel::parse(name).ok_or_else(|| invalid("unknown channel"))?;
+                    self.mixer.muted[channel as usize] = true;
+                }
+            }
+            ("audio", "mute_on_focus_loss", Value::Boolean(enabled)) => self.mute_on_focus_loss = enabled,
+            ("audio", "dump_key", Value::String(key)) => self.dump_audio_key = key,
+            ("audio", "mixing", Value::String(mixing)) => {
+                self.mixer.mixing = Mixing::parse(&mixing).ok_or_else(|| invalid("must be \"linear\" or \"accurate\""))?
+            }
+            ("audio", "solo", Value::String(channel)) => {
+                self.mixer.solo = Some(Channel::parse(&channel).ok_or_else(|| invalid("unknown channel"))?)
+            }
+            ("audio", "expansion_volume", Value::Float(gain)) if (0.0..=MAX_GAIN as f64).contains(&gain) => {
+                self.mixer.expansion_gain = gain as f32
+            }
+            ("audio", "expansion_volume", Value::Integer(gain)) if (0..=MAX_GAIN as i64).contains(&gain) => {
+                self.mixer.expansion_gain = gain as f32
+            }
+            ("audio", key, value) if key.ends_with("_volume") => {
+                let channel = Channel::parse(&key[..key.len() - "_volume".len()]).ok_or_else(|| invalid("unknown channel"))?;
+                let gain = match value {
+                    Value::Float(gain) => gain as f32,
+                    Value::Integer(gain) => gain as f32,
+                    _ => return Err(invalid("must be a number")),
+                };
+                if !(0.0..=MAX_GAIN).contains(&gain) {
+                    return Err(invalid("must be between 0 and 2"));
+                }
+                self.mixer.gains[channel as usize] = gain;
+            }
+            ("debug", "trace", Value::String(channels)) => {
+                self.trace = TraceChannel::parse(&channels).ok_or_else(|| invalid("unknown trace channel"))?
+            }
+            ("debug", "trace_file", Value::String(path)) => self.trace_file = Some(path.into()),
+            ("debug", "trace_buffer", Value::Integer(lines)) if lines >= 0 => self.trace_buffer = lines as usize,
+            ("debug", "trace_filter", Value::String(filters)) => {
+                self.trace_filters = TraceFilter::parse_list(&filters, &SymbolTable::new()).ok_or_else(|| invalid("invalid trace filter"))?
+            }
+            ("debug", "remote_port", Value::Integer(port)) if (1..=0xFFFF).contains(&port) => self.remote_port = Some(port as u16),
+            ("debug", "windows", Value::String(windows)) => {
+                self.tool_windows = ToolWindows::parse(&windows).ok_or_else(|| invalid("unknown window"))?
+            }
+            ("debug", "debugger_key", Value::String(key)) => self.debugger_key = key,
+            ("debug", "show_left_clip", Value::Boolean(enabled)) => self.show_left_clip = enabled,
+            ("debug", "ppu_viewer_key", Value::String(key)) => self.ppu_viewer_key = key,
+            ("fds", "bios", Value::String(path)) => self.fds_bios = Some(path.into()),
+            ("fds", "switch_side_key", Value::String(key)) => self.fds_switch_side_key = key,
+            ("saves", "directory", Value::String(path)) => self.saves_directory = Some(path.into()),
+            ("saves", "autosave_interval", Value::Integer(seconds)) if seconds >= 0 => {
+                self.autosave_interval = Duration::from_secs(seconds as u64)
+            }
+            ("saves", "backups", Value::Integer(count)) if (0..=99).contains(&count) => self.save_backups = count as usize,
+            ("netplay", "input_delay", Value::Integer(frames)) if (0..=10).contains(&frames) => {
+                self.netplay.input_delay = frames as u8
+            }
+            ("netplay", "timeout", Value::Integer(seconds)) if seconds > 0 => {
+                self.netplay.timeout = Duration::from_secs(seconds as u64)
+            }
+            ("input", "turbo_on_frames", Value::Integer(frames)) if (1..=255).contains(&frames) => {
+                self.turbo.on_frames = frames as u8
+            }
+            ("input", "turbo_off_frames", Value::Integer(frames)) if (1..=255).contains(&frames) => {
+                self.turbo.off_frames = frames as u8
+            }
+            ("input", "port1" | "port2", Value::String(device)) => {
+                let port = if key == "port1" { 0 } else { 1 };
+                self.ports[port] = Device::parse(&device).ok_or_else(|| invalid("unknown device"))?
+            }
+            ("powerpad", key, Value::String(input)) => {
+                let button = PowerPadButton::parse(key).ok_or_else(|| invalid("unknown square"))?;
+                self.power_pad.retain(|_, bound| *bound != button);
+                self.power_pad.insert(input, button);
+            }
+            (section, key, Value::String(input)) if section.starts_with("keyboard.") || section.starts_with("gamepad.") => {
+                let (name, turbo) = match key.strip_prefix("turbo_") {
+                    Some(name) => (name, true),
+                    None => (key, false),
+                };
+                let button = JoypadButton::parse(name).ok_or_else(|| invalid("unknown button"))?;
+                let bindings = self.bindings_mut(section, turbo).ok_or_else(|| invalid("player must be 1 or 2"))?;
+                // a section replaces the default binding for that button
+                bindings.retain(|_, bound| *bound != button);
+                bindings.insert(input, button);
+            }
+            _ => return Err(invalid("unknown key or invalid value")),
+        }
+        Ok(())
+    }
+
+    fn bindings_mut(&mut self, section: &str, turbo: bool) -> Option<&mut Bindings> {
+        let (device, player) = section.split_once('.')?;
+        let player = match player {
+            "1" => 0,
+            "2" => 1,
+            _ => return None,
+        };
+        match (device, turbo) {
+            ("keyboard", false) => Some(&mut self.keyboard[player]),
+            ("gamepad", false) => Some(&mut self.gamepad[player]),
+            ("keyboard", true) => Some(&mut self.turbo_keyboard[player]),
+            ("gamepad", true) => Some(&mut self.turbo_gamepad[player]),
+            _ => None,
+        }
+    }
+
+    // What `player`'s controller has pressed while the named host inputs are held.
+    // Turbo bindings autofire in step with the emulated frame.
+    pub fn buttons(&self, player: usize, held: &HashSet<String>, frame: u64) -> JoypadButton {
+        let pressed = |bindings: &Bindings| {
+            held.iter().filter_map(|input| bindings.get(input)).fold(JoypadButton::empty(), |buttons, &button| buttons | button)
+        };
+        let turbo = pressed(&self.turbo_keyboard[player]) | pressed(&self.turbo_gamepad[player]);
+        pressed(&self.keyboard[player]) | pressed(&self.gamepad[player]) | self.turbo.buttons(turbo, frame)
+    }
+
+    // The Power Pad squares pressed while the named keys are held
+    pub fn mat(&self, held: &HashSet<String>) -> PowerPadButton {
+        held.iter().filter_map(|input| self.power_pad.get(input)).fold(PowerPadButton::empty(), |buttons, &button| buttons | button)
+    }
+
+    // The tool window a key opens or closes
+    pub fn tool_window_for_key(&self, key: &str) -> Option<ToolWindows> {
+        if key == self.debugger_key {
+            Some(ToolWindows::Debugger)
+        } else if key == self.ppu_viewer_key {
+            Some(ToolWindows::PpuViewer)
+        } else {
+            None
+        }
+    }
+
+    // The settings to play `rom` with: the global ones, then the game's section,
+    // then the command line, each winning over the one before
+    pub fn for_game(&self, rom: &Rom, options: &EmulatorOptions) -> Config {
+        let mut config = self.clone();
+        if let Some(game) = self.games.get(&to_hex(&rom.sha1())) {
+            if game.region.is_some() {
+                config.region = game.region;
+            }
+            if let Some(device) = game.port2 {
+                config.ports[1] = device;
+            }
+            if let Some(lines) = game.overscan {
+                config.view.overscan = lines;
+            }
+            if game.cheats.is_some() {
+                config.cheats = game.cheats.clone();
+            }
+            if let Some(frames) = game.run_ahead {
+                config.run_ahead = frames;
+            }
+        }
+        config.apply_options(options);
+        config
+    }
+
+    // Takes the settings that can change while a game runs from a freshly loaded
+    // config, with the game's section and the command line applied over it as at
+    // startup, and says which changed. Nothing changes when the new palette can't
+    // be loaded.
+    pub fn reload(&mut self, new: Config, rom: &Rom, options: &EmulatorOptions) -> Result<ConfigChanges, ConfigError> {
+        let mut new = new.for_game(rom, options);
+        if new.palette != self.palette {
+            Palette::select(&new.palette).map_err(|error| ConfigError::InvalidValue {
+                key: "video.palette".to_string(),
+                message: error.to_string(),
+            })?;
+        }
+        // Alt+Enter's choice stays
+        new.view.fullscreen = self.view.fullscreen;
+
+        let mut changes = ConfigChanges::empty();
+        let bindings = |config: &Config| {
+            (
+                config.keyboard.clone(),
+                config.gamepad.clone(),
+                config.turbo_keyboard.clone(),
+                config.turbo_gamepad.clone(),
+                config.turbo,
+                config.power_pad.clone(),
+                [&config.reset_key, &config.power_cycle_key, &config.reload_config_key, &config.dump_audio_key].map(String::clone),
+                [&config.debugger_key, &config.ppu_viewer_key, &config.fds_switch_side_key].map(String::clone),
+            )
+        };
+        if bindings(self) != bindings(&new) {
+            changes |= ConfigChanges::Bindings;
+            self.keyboard = new.keyboard.clone();
+            self.gamepad = new.gamepad.clone();
+            self.turbo_keyboard = new.turbo_keyboard.clone();
+            self.turbo_gamepad = new.turbo_gamepad.clone();
+            self.turbo = new.turbo;
+            self.power_pad = new.power_pad.clone();
+            self.reset_key = new.reset_key.clone();
+            self.power_cycle_key = new.power_cycle_key.clone();
+            self.reload_config_key = new.reload_config_key.clone();
+            self.dump_audio_key = new.dump_audio_key.clone();
+            self.debugger_key = new.debugger_key.clone();
+            self.ppu_viewer_key = new.ppu_viewer_key.clone();
+            self.fds_switch_side_key = new.fds_switch_side_key.clone();
+        }
+        if self.palette != new.palette {
+            changes |= ConfigChanges::Palette;
+            self.palette = new.palette.clone();
+        }
+        if (self.mixer, self.mute_on_focus_loss) != (new.mixer, new.mute_on_focus_loss) {
+            changes |= ConfigChanges::Audio;
+            self.mixer = new.mixer;
+            self.mute_on_focus_loss = new.mute_on_focus_loss;
+        }
+        if (self.filter, self.view, self.layers, self.show_left_clip) != (new.filter, new.view, new.layers, new.show_left_clip) {
+            changes |= ConfigChanges::Video;
+            (self.filter, self.view, self.layers, self.show_left_clip) = (new.filter, new.view, new.layers, new.show_left_clip);
+        }
+        if *self != new {
+            changes |= ConfigChanges::NeedsRestart;
+        }
+        Ok(changes)
+    }
+
+    // Command line options take precedence over the config file
+    pub fn apply_options(&mut self, options: &EmulatorOptions) {
+        if let Some(palette) = &options.palette {
+            self.palette = palette.clone();
+        }
+        if !options.trace.is_empty() {
+            self.trace = options.trace;
+        }
+        if options.trace_file.is_some() {
+            self.trace_file = options.trace_file.clone();
+        }
+        if options.trace_buffer > 0 {
+            self.trace_buffer = options.trace_buffer;
+        }
+        if !options.trace_filters.is_empty() {
+            self.trace_filters = options.trace_filters.clone();
+        }
+        if options.dump_audio.is_some() {
+            self.dump_audio = options.dump_audio.clone();
+        }
+    }
+}
+
+// Calls `set` with the section, key and value of every pair in the file
+pub(crate) fn parse_toml(
+    text: &str,
+    mut set: impl FnMut(&str, &str, Value) -> Result<(), ConfigError>,
+) -> Result<(), ConfigError> {
+    let mut section = String::new();
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let syntax = |message: &str| ConfigError::Syntax { line: line_number, message: message.to_string() };
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(name) = line.strip_prefix('[') {
+            section = name.strip_suffix(']').ok_or_else(|| syntax("unterminated section"))?.trim().to_string();
+            continue;
+        }
+        let (key, value) = line.split_once('=').ok_or_else(|| syntax("expected key = value"))?;
+        let value = parse_value(value.trim()).ok_or_else(|| syntax("invalid value"))?;
+        set(&section, key.trim(), value)?;
+    }
+    Ok(())
+}
+
+fn strip_comment(line: &str) -> &str {
+    // a # inside a string value is not a comment
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn parse_value(text: &str) -> Option<Value> {
+    if let Some(string) = text.strip_prefix('"') {
+        return Some(Value::String(string.strip_suffix('"')?.to_string()));
+    }
+    match text {
+        "true" => Some(Value::Boolean(true)),
+        "false" => Some(Value::Boolean(false)),
+        _ => text
+            .parse()
+            .map(Value::Integer)
+            .or_else(|_| text.parse().map(Value::Float))
+            .ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::parse(
+            r#"
+            # madNES config
+            [video]
+            scale = 2
+            palette = "classic"
+            filter = "scanlines"
+            integer_scaling = true
+            crop_left = "sprites"
+
+            [emulation]
+            sync = "audio"
+            ram_pattern = "alternating"
+            power_cycle_key = "F5"
+
+            [audio]
+            volume = 0.5 # half
+            triangle_volume = 1.5
+            expansion_volume = 0.5
+            mixing = "linear"
+            mute = "noise, dmc"
+            mute_on_focus_loss = true
+            dump_key = "F10"
+
+            [debug]
+            trace = "cpu,ppu"
+            trace_file = "out/#trace.log"
+            trace_filter = "pc:$C000-$C0FF"
+            remote_port = 6502
+            windows = "debugger"
+            debugger_key = "F9"
+            show_left_clip = true
+
+            [keyboard.1]
+            a = "K"
+
+            [gamepad.2]
+            start = "start"
+            turbo_b = "x"
+
+            [input]
+            turbo_on_frames = 3
+            turbo_off_frames = 1
+            port2 = "powerpad"
+
+            [powerpad]
+            button12 = "Q"
+
+            [fds]
+            bios = "bios/disksys.rom"
+
+            [netplay]
+            input_delay = 4
+
+            [saves]
+            directory = "saves"
+            autosave_interval = 0
+            backups = 3
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.scale, 2);
+        assert_eq!(config.palette, "classic");
+        assert_eq!(config.filter, Filter::Scanlines);
+        assert!(config.view.integer_scaling && config.view.aspect_correction);
+        assert!(config.layers.background_left && !config.layers.sprites_left);
+        assert!(config.show_left_clip);
+        assert_eq!(config.mixer.master, 0.5);
+        assert_eq!(config.mixer.mixing, Mixing::Linear);
+        assert_eq!(config.mixer.gains[Channel::Triangle as usize], 1.5);
+        assert_eq!(config.mixer.expansion_gain, 0.5);
+        assert!(!config.mixer.is_audible(Channel::Noise) && !config.mixer.is_audible(Channel::Dmc));
+        assert!(config.mixer.is_audible(Channel::Pulse1));
+        assert_eq!(config.trace, TraceChannel::Cpu | TraceChannel::Ppu);
+        assert_eq!(config.trace_file, Some("out/#trace.log".into()));
+        assert_eq!(config.trace_filters, [TraceFilter::Pc(0xC000..=0xC0FF)]);
+        assert_eq!(config.remote_port, Some(6502));
+        assert_eq!(config.tool_windows, ToolWindows::Debugger);
+        assert_eq!(config.tool_window_for_key("F9"), Some(ToolWindows::Debugger));
+        assert_eq!(config.tool_window_for_key("F11"), Some(ToolWindows::PpuViewer));
+        assert_eq!(config.tool_window_for_key("F12"), None);
+        assert_eq!(config.keyboard[0].get("K"), Some(&JoypadButton::A));
+        assert_eq!(config.keyboard[0].get("X"), None);
+        assert_eq!(config.keyboard[0].get("Z"), Some(&JoypadButton::B));
+        assert_eq!(config.gamepad[1].get("start"), Some(&JoypadButton::Start));
+        assert_eq!(config.turbo_gamepad[1].get("x"), Some(&JoypadButton::B));
+        assert_eq!(config.turbo_keyboard[0].get("S"), Some(&JoypadButton::A));
+        assert_eq!(config.turbo, Turbo { on_frames: 3, off_frames: 1 });
+        assert_eq!(config.ports, [Device::Joypad, Device::PowerPad]);
+        let held = ["Q", "Slash", "U"].iter().map(|key| key.to_string()).collect();
+        assert_eq!(config.mat(&held), PowerPadButton::B1 | PowerPadButton::B12);
+        assert_eq!(config.fds_bios, Some("bios/disksys.rom".into()));
+        assert_eq!(config.fds_switch_side_key, "F6");
+        assert_eq!(config.netplay.input_delay, 4);
+        assert_eq!(config.saves_directory, Some("saves".into()));
+        assert_eq!((config.autosave_interval, config.save_backups), (Duration::ZERO, 3));
+        assert!(config.mute_on_focus_loss && !config.pause_on_focus_loss);
+        assert_eq!(config.dump_audio_key, "F10");
+        assert_eq!(config.ram_pattern, RamPattern::Alternating);
+        assert_eq!(config.sync, SyncMode::Audio);
+        assert_eq!((config.reset_key.as_str(), config.power_cycle_key.as_str()), ("R", "F5"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(Config::parse("[video"), Err(ConfigError::Syntax { line: 1, .. })));
+        assert!(matches!(Config::parse("\nscale 2"), Err(ConfigError::Syntax { line: 2, .. })));
+        assert!(matches!(Config::parse("[video]\nscale = 0"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[video]\nzoom = 2"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[video]\ncrop_left = \"top\""), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[keyboard.3]\na = \"X\""), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[keyboard.1]\nturbo = \"X\""), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[input]\nturbo_on_frames = 0"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[input]\nport1 = \"rob\""), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[audio]\nbass_volume = 1"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[audio]\nnoise_volume = 3.0"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[audio]\nmute = \"pulse3\""), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[netplay]\ninput_delay = 11"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse("[emulation]\nram_pattern = \"random\""), Err(ConfigError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn test_buttons() {
+        let config = Config::default();
+        let held: HashSet<String> = ["Z", "Up", "S", "F1"].iter().map(|input| input.to_string()).collect();
+        assert_eq!(config.buttons(0, &held, 0), JoypadButton::B | JoypadButton::Up | JoypadButton::A);
+        // turbo A is released on frames 2 and 3
+        assert_eq!(config.buttons(0, &held, 2), JoypadButton::B | JoypadButton::Up);
+        assert_eq!(config.buttons(1, &held, 0), JoypadButton::empty());
+    }
+
+    #[test]
+    fn test_game_overrides() {
+        let rom = Rom::new(&crate::rom::tests::ines(1, 1, 0, 0)).unwrap();
+        let text = format!(
+            "[video]\noverscan = 8\n[input]\nport2 = \"zapper\"\n[emulation]\nrun_ahead = 1\n\n\
+             [game.\"{}\"]\nregion = \"pal\"\noverscan = 0\ncheats = \"smb.cht\"\nrun_ahead = 2\n\n\
+             [game.\"{}\"]\nport2 = \"joypad\"",
+            to_hex(&rom.sha1()).to_ascii_lowercase(),
+            "0".repeat(40)
+        );
+        let config = Config::parse(&text).unwrap();
+        assert_eq!(config.games.len(), 2);
+
+        // the game's section wins over the global settings it sets, and only those
+        let options = EmulatorOptions { palette: Some("classic".to_string()), ..EmulatorOptions::default() };
+        let game = config.for_game(&rom, &options);
+        assert_eq!(game.region, Some(Region::Pal));
+        assert_eq!((game.view.overscan, game.run_ahead), (0, 2));
+        assert_eq!(game.cheats, Some("smb.cht".into()));
+        assert_eq!(game.ports[1], Device::Zapper);
+        assert_eq!(game.palette, "classic");
+        assert_eq!((config.view.overscan, config.run_ahead), (8, 1));
+
+        let other = Rom::new(&crate::rom::tests::ines(2, 1, 0, 0)).unwrap();
+        assert_eq!(config.for_game(&other, &EmulatorOptions::default()), config);
+
+        assert!(matches!(Config::parse("[game.\"ABC\"]\nregion = \"pal\""), Err(ConfigError::InvalidValue { .. })));
+        let section = format!("[game.\"{}\"]\n", "A".repeat(40));
+        assert!(matches!(Config::parse(&format!("{}speed = 2", section)), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(Config::parse(&format!("{}run_ahead = 5", section)), Err(ConfigError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn test_options_override_config() {
+        let mut config = Config::parse("[debug]\ntrace = \"ppu\"\ntrace_buffer = 10\nwindows = \"debugger\"\n[emulation]\nspeed = 2").unwrap();
+        assert_eq!(config.speed, 2.0);
+        let options = EmulatorOptions {
+            trace: TraceChannel::Cpu,
+            dump_audio: Some("music.wav".into()),
+            ..EmulatorOptions::default()
+        };
+        config.apply_options(&options);
+        assert_eq!(config.trace, TraceChannel::Cpu);
+        assert_eq!(config.trace_buffer, 10);
+        assert_eq!(config.speed, 2.0);
+        assert_eq!(config.dump_audio, Some("music.wav".into()));
+    }
+
+    #[test]
+    fn test_reload() {
+        let rom = Rom::new(&crate::rom::tests::ines(1, 1, 0, 0)).unwrap();
+        let options = EmulatorOptions { palette: Some("ntsc".to_string()), ..EmulatorOptions::default() };
+        let mut config = Config::default();
+        config.apply_options(&options);
+        config.view.fullscreen = true;
+        let text = "[video]\npalette = \"missing.pal\"\noverscan = 8\n[audio]\nvolume = 0.5\n[keyboard.1]\na = \"K\"\n";
+        let changes = config.reload(Config::parse(text).unwrap(), &rom, &options).unwrap();
+        assert_eq!(changes, ConfigChanges::Bindings | ConfigChanges::Audio | ConfigChanges::Video);
+        assert_eq!((config.view.overscan, config.mixer.master), (8, 0.5));
+        assert!(config.view.fullscreen);
+        assert_eq!(config.reload(config.clone(), &rom, &options).unwrap(), ConfigChanges::empty());
+
+        // a palette that isn't there leaves everything as it was
+        let before = config.clone();
+        let error = config.reload(Config::parse(text).unwrap(), &rom, &EmulatorOptions::default()).unwrap_err();
+        assert!(matches!(error, ConfigError::InvalidValue { key, .. } if key == "video.palette"));
+        assert_eq!(config, before);
+
+        // the rest waits for a restart
+        let changes = config.reload(Config::parse("[emulation]\nspeed = 2.0\n").unwrap(), &rom, &options).unwrap();
+        assert!(changes.contains(ConfigChanges::NeedsRestart));
+        assert_eq!(config.speed, 1.0);
+
+        // the game's own section still goes over the file's settings
+        let text = format!("[video]\noverscan = 8\n[game.\"{}\"]\noverscan = 0\n", to_hex(&rom.sha1()));
+        let mut config = Config::parse(&text).unwrap().for_game(&rom, &options);
+        assert_eq!(config.reload(Config::parse(&text).unwrap(), &rom, &options).unwrap(), ConfigChanges::empty());
+        assert_eq!(config.view.overscan, 0);
+    }
+
+    #[test]
+    fn test_config_watcher() {
+        let dir = TestDir::new("config");
+        let path = dir.join("madnes.toml");
+        let mut watcher = ConfigWatcher::new(&path);
+        assert!(!watcher.poll());
+        fs::write(&path, "[video]\nscale = 2\n").unwrap();
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(1)).unwrap();
+        assert!(watcher.poll());
+        fs::remove_file(&path).unwrap();
+        assert!(!watcher.poll());
+    }
+}
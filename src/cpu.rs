@@ -1,16 +1,17 @@
 use lazy_static::lazy_static;
-use std::collections::HashMap;
 use bitflags::bitflags;
-use crate::instruction::Instruction;
+use crate::instruction::{Instruction, InstructionTable, Operation};
+use crate::ppu::Ppu;
+use crate::savestate::{SaveState, StateError, StateReader};
 
-trait Memory {
+pub trait Memory {
     fn read_byte(&self, address: u16) -> u8;
 
     fn write_byte(&mut self, address: u16, value: u8);
 
     fn read_word(&self, address: u16) -> u16 {
         let lo = self.read_byte(address) as u16;
-        let hi = self.read_byte(address + 1) as u16;
+        let hi = self.read_byte(address.wrapping_add(1)) as u16;
         (hi << 8) | lo
     }
 
@@ -18,62 +19,306 @@ trait Memory {
         let lo = value as u8;
         let hi = (value >> 8) as u8;
         self.write_byte(address, lo);
-        self.write_byte(address + 1, hi);
+        self.write_byte(address.wrapping_add(1), hi);
+    }
+}
+
+// Everything the CPU can address. Reads can have side effects on devices
+// (acknowledging PPU status, shifting controller bits), so tools that only
+// look at memory use peek instead.
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, value: u8);
+
+    // Reads without side effects
+    fn peek(&self, address: u16) -> u8;
+
+    // Runs the other devices for `cycles` CPU cycles. The CPU calls it with 1 before
+    // each access, so a register read sees the PPU where the real one would be, and at
+    // the end of an instruction with the cycles it spent off the bus.
+    fn tick(&mut self, _cycles: u8) {}
+
+    // The page written to $4014 for the CPU to copy into OAM, taken once the
+    // instruction that wrote it has finished
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        None
+    }
+
+    // The PPU on buses that have one, for the debugger's PPU breakpoints
+    fn ppu_mut(&mut self) -> Option<&mut Ppu> {
+        None
+    }
+
+    // The PPU address space for the debugger, on buses with a PPU
+    fn read_ppu(&mut self, _address: u16) -> Option<u8> {
+        None
+    }
+
+    fn write_ppu(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    // Replaces a byte of PRG ROM as the CPU sees it, without changing the ROM
+    fn patch_prg(&mut self, _address: u16, _value: u8) -> bool {
+        false
+    }
+
+    // Through Cpu::clear_prg_patches, which also drops what the decode cache saw
+    fn clear_prg_patches(&mut self) {}
+
+    // Whether writing `address` can change what the CPU reads at $8000-$FFFF, by
+    // switching banks or writing RAM mapped there. The decode cache is dropped on those.
+    fn changes_prg(&self, address: u16) -> bool {
+        address >= MAPPER_SPACE_START
+    }
+}
+
+// A plain 64KB address space with nothing mapped, for tests and nestest
+pub struct FlatBus {
+    pub memory: [u8; 0x10000],
+}
+
+impl FlatBus {
+    pub fn new() -> Self {
+        FlatBus { memory: [0; 0x10000] }
+    }
+}
+
+impl Default for FlatBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatBus {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    // nothing here is read-only, so a patch is a plain write
+    fn patch_prg(&mut self, address: u16, value: u8) -> bool {
+        self.memory[address as usize] = value;
+        true
+    }
+
+    fn changes_prg(&self, address: u16) -> bool {
+        address >= DECODE_CACHE_START
     }
 }
 
 lazy_static! {
-    static ref INSTRUCTIONS: HashMap<u8, Instruction> = {
-        let mut map = HashMap::new();
+    pub static ref INSTRUCTIONS: InstructionTable = {
+        let mut map = InstructionTable::new();
         // interrupts
-        map.insert(0x00, Instruction::new("BRK", 0x00, AddressingMode::Implied, 7, 1));
-        map.insert(0x40, Instruction::new("RTI", 0x40, AddressingMode::Implied, 6, 1));
+        map.insert(0x00, Instruction::new(Operation::Brk, 0x00, AddressingMode::Implied, 7, 1));
+        map.insert(0x40, Instruction::new(Operation::Rti, 0x40, AddressingMode::Implied, 6, 1));
+        // logic
+        map.insert(0x09, Instruction::new(Operation::Ora, 0x09, AddressingMode::Immediate, 2, 2));
+        map.insert(0x05, Instruction::new(Operation::Ora, 0x05, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x15, Instruction::new(Operation::Ora, 0x15, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x0D, Instruction::new(Operation::Ora, 0x0D, AddressingMode::Absolute, 4, 3));
+        map.insert(0x1D, Instruction::new(Operation::Ora, 0x1D, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0x19, Instruction::new(Operation::Ora, 0x19, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0x01, Instruction::new(Operation::Ora, 0x01, AddressingMode::IndirectX, 6, 2));
+        map.insert(0x11, Instruction::new(Operation::Ora, 0x11, AddressingMode::IndirectY, 5, 2));
+        map.insert(0x29, Instruction::new(Operation::And, 0x29, AddressingMode::Immediate, 2, 2));
+        map.insert(0x25, Instruction::new(Operation::And, 0x25, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x35, Instruction::new(Operation::And, 0x35, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x2D, Instruction::new(Operation::And, 0x2D, AddressingMode::Absolute, 4, 3));
+        map.insert(0x3D, Instruction::new(Operation::And, 0x3D, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0x39, Instruction::new(Operation::And, 0x39, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0x21, Instruction::new(Operation::And, 0x21, AddressingMode::IndirectX, 6, 2));
+        map.insert(0x31, Instruction::new(Operation::And, 0x31, AddressingMode::IndirectY, 5, 2));
+        map.insert(0x49, Instruction::new(Operation::Eor, 0x49, AddressingMode::Immediate, 2, 2));
+        map.insert(0x45, Instruction::new(Operation::Eor, 0x45, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x55, Instruction::new(Operation::Eor, 0x55, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x4D, Instruction::new(Operation::Eor, 0x4D, AddressingMode::Absolute, 4, 3));
+        map.insert(0x5D, Instruction::new(Operation::Eor, 0x5D, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0x59, Instruction::new(Operation::Eor, 0x59, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0x41, Instruction::new(Operation::Eor, 0x41, AddressingMode::IndirectX, 6, 2));
+        map.insert(0x51, Instruction::new(Operation::Eor, 0x51, AddressingMode::IndirectY, 5, 2));
+        map.insert(0x24, Instruction::new(Operation::Bit, 0x24, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x2C, Instruction::new(Operation::Bit, 0x2C, AddressingMode::Absolute, 4, 3));
         // compare
-        map.insert(0x09, Instruction::new("ORA", 0x09, AddressingMode::Immediate, 2, 2));
-        map.insert(0x05, Instruction::new("ORA", 0x05, AddressingMode::ZeroPage, 3, 2));
-        map.insert(0x15, Instruction::new("ORA", 0x15, AddressingMode::ZeroPageX, 4, 2));
-        map.insert(0x0D, Instruction::new("ORA", 0x0D, AddressingMode::Absolute, 4, 3));        
-        map.insert(0x1D, Instruction::new("ORA", 0x1D, AddressingMode::AbsoluteX, 4, 3));
-        map.insert(0x19, Instruction::new("ORA", 0x19, AddressingMode::AbsoluteY, 4, 3));
-        map.insert(0x01, Instruction::new("ORA", 0x01, AddressingMode::IndirectX, 6, 2));
-        map.insert(0x11, Instruction::new("ORA", 0x11, AddressingMode::IndirectY, 5, 2));
+        map.insert(0xC9, Instruction::new(Operation::Cmp, 0xC9, AddressingMode::Immediate, 2, 2));
+        map.insert(0xC5, Instruction::new(Operation::Cmp, 0xC5, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xD5, Instruction::new(Operation::Cmp, 0xD5, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0xCD, Instruction::new(Operation::Cmp, 0xCD, AddressingMode::Absolute, 4, 3));
+        map.insert(0xDD, Instruction::new(Operation::Cmp, 0xDD, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0xD9, Instruction::new(Operation::Cmp, 0xD9, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0xC1, Instruction::new(Operation::Cmp, 0xC1, AddressingMode::IndirectX, 6, 2));
+        map.insert(0xD1, Instruction::new(Operation::Cmp, 0xD1, AddressingMode::IndirectY, 5, 2));
+        map.insert(0xE0, Instruction::new(Operation::Cpx, 0xE0, AddressingMode::Immediate, 2, 2));
+        map.insert(0xE4, Instruction::new(Operation::Cpx, 0xE4, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xEC, Instruction::new(Operation::Cpx, 0xEC, AddressingMode::Absolute, 4, 3));
+        map.insert(0xC0, Instruction::new(Operation::Cpy, 0xC0, AddressingMode::Immediate, 2, 2));
+        map.insert(0xC4, Instruction::new(Operation::Cpy, 0xC4, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xCC, Instruction::new(Operation::Cpy, 0xCC, AddressingMode::Absolute, 4, 3));
         // transfer
-        map.insert(0xA9, Instruction::new("LDA", 0xA9, AddressingMode::Immediate, 2, 2));
-        map.insert(0xA5, Instruction::new("LDA", 0xA5, AddressingMode::ZeroPage, 3, 2));
-        map.insert(0xB5, Instruction::new("LDA", 0xB5, AddressingMode::ZeroPageX, 4, 2));
-        map.insert(0xAD, Instruction::new("LDA", 0xAD, AddressingMode::Absolute, 4, 3));
-        map.insert(0xBD, Instruction::new("LDA", 0xBD, AddressingMode::AbsoluteX, 4, 3));
-        map.insert(0xB9, Instruction::new("LDA", 0xB9, AddressingMode::AbsoluteY, 4, 3));
-        map.insert(0xA1, Instruction::new("LDA", 0xA1, AddressingMode::IndirectX, 6, 2));
-        map.insert(0xB1, Instruction::new("LDA", 0xB1, AddressingMode::IndirectY, 5, 2));
-        map.insert(0xA2, Instruction::new("LDX", 0xA2, AddressingMode::Immediate, 2, 2));
-        map.insert(0xA6, Instruction::new("LDX", 0xA6, AddressingMode::ZeroPage, 3, 2));
-        map.insert(0xB6, Instruction::new("LDX", 0xB6, AddressingMode::ZeroPageY, 4, 2));
-        map.insert(0xAE, Instruction::new("LDX", 0xAE, AddressingMode::Absolute, 4, 3));
-        map.insert(0xBE, Instruction::new("LDX", 0xBE, AddressingMode::AbsoluteY, 4, 3));
-        map.insert(0xA0, Instruction::new("LDY", 0xA0, AddressingMode::Immediate, 2, 2));
-        map.insert(0xA4, Instruction::new("LDY", 0xA4, AddressingMode::ZeroPage, 3, 2));
-        map.insert(0xB4, Instruction::new("LDY", 0xB4, AddressingMode::ZeroPageX, 4, 2));
-        map.insert(0xAC, Instruction::new("LDY", 0xAC, AddressingMode::Absolute, 4, 3));
-        map.insert(0xBC, Instruction::new("LDY", 0xBC, AddressingMode::AbsoluteX, 4, 3));
-        map.insert(0x85, Instruction::new("STA", 0x85, AddressingMode::ZeroPage, 3, 2));
-        map.insert(0xAA, Instruction::new("TAX", 0xAA, AddressingMode::Implied, 2, 1));
-        map.insert(0xE8, Instruction::new("INX", 0xE8, AddressingMode::Implied, 2, 1));
-
+        map.insert(0xA9, Instruction::new(Operation::Lda, 0xA9, AddressingMode::Immediate, 2, 2));
+        map.insert(0xA5, Instruction::new(Operation::Lda, 0xA5, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xB5, Instruction::new(Operation::Lda, 0xB5, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0xAD, Instruction::new(Operation::Lda, 0xAD, AddressingMode::Absolute, 4, 3));
+        map.insert(0xBD, Instruction::new(Operation::Lda, 0xBD, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0xB9, Instruction::new(Operation::Lda, 0xB9, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0xA1, Instruction::new(Operation::Lda, 0xA1, AddressingMode::IndirectX, 6, 2));
+        map.insert(0xB1, Instruction::new(Operation::Lda, 0xB1, AddressingMode::IndirectY, 5, 2));
+        map.insert(0xA2, Instruction::new(Operation::Ldx, 0xA2, AddressingMode::Immediate, 2, 2));
+        map.insert(0xA6, Instruction::new(Operation::Ldx, 0xA6, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xB6, Instruction::new(Operation::Ldx, 0xB6, AddressingMode::ZeroPageY, 4, 2));
+        map.insert(0xAE, Instruction::new(Operation::Ldx, 0xAE, AddressingMode::Absolute, 4, 3));
+        map.insert(0xBE, Instruction::new(Operation::Ldx, 0xBE, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0xA0, Instruction::new(Operation::Ldy, 0xA0, AddressingMode::Immediate, 2, 2));
+        map.insert(0xA4, Instruction::new(Operation::Ldy, 0xA4, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xB4, Instruction::new(Operation::Ldy, 0xB4, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0xAC, Instruction::new(Operation::Ldy, 0xAC, AddressingMode::Absolute, 4, 3));
+        map.insert(0xBC, Instruction::new(Operation::Ldy, 0xBC, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0x85, Instruction::new(Operation::Sta, 0x85, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x95, Instruction::new(Operation::Sta, 0x95, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x8D, Instruction::new(Operation::Sta, 0x8D, AddressingMode::Absolute, 4, 3));
+        map.insert(0x9D, Instruction::new(Operation::Sta, 0x9D, AddressingMode::AbsoluteX, 5, 3));
+        map.insert(0x99, Instruction::new(Operation::Sta, 0x99, AddressingMode::AbsoluteY, 5, 3));
+        map.insert(0x81, Instruction::new(Operation::Sta, 0x81, AddressingMode::IndirectX, 6, 2));
+        map.insert(0x91, Instruction::new(Operation::Sta, 0x91, AddressingMode::IndirectY, 6, 2));
+        map.insert(0x86, Instruction::new(Operation::Stx, 0x86, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x96, Instruction::new(Operation::Stx, 0x96, AddressingMode::ZeroPageY, 4, 2));
+        map.insert(0x8E, Instruction::new(Operation::Stx, 0x8E, AddressingMode::Absolute, 4, 3));
+        map.insert(0x84, Instruction::new(Operation::Sty, 0x84, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x94, Instruction::new(Operation::Sty, 0x94, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x8C, Instruction::new(Operation::Sty, 0x8C, AddressingMode::Absolute, 4, 3));
+        map.insert(0xAA, Instruction::new(Operation::Tax, 0xAA, AddressingMode::Implied, 2, 1));
+        map.insert(0xA8, Instruction::new(Operation::Tay, 0xA8, AddressingMode::Implied, 2, 1));
+        map.insert(0xBA, Instruction::new(Operation::Tsx, 0xBA, AddressingMode::Implied, 2, 1));
+        map.insert(0x8A, Instruction::new(Operation::Txa, 0x8A, AddressingMode::Implied, 2, 1));
+        map.insert(0x9A, Instruction::new(Operation::Txs, 0x9A, AddressingMode::Implied, 2, 1));
+        map.insert(0x98, Instruction::new(Operation::Tya, 0x98, AddressingMode::Implied, 2, 1));
         // arithmetic
-        map.insert(0x69, Instruction::new("ADC", 0x69, AddressingMode::Immediate, 2, 2));
-        map.insert(0xE9, Instruction::new("SBC", 0xE9, AddressingMode::Immediate, 2, 2));
+        map.insert(0x69, Instruction::new(Operation::Adc, 0x69, AddressingMode::Immediate, 2, 2));
+        map.insert(0x65, Instruction::new(Operation::Adc, 0x65, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x75, Instruction::new(Operation::Adc, 0x75, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0x6D, Instruction::new(Operation::Adc, 0x6D, AddressingMode::Absolute, 4, 3));
+        map.insert(0x7D, Instruction::new(Operation::Adc, 0x7D, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0x79, Instruction::new(Operation::Adc, 0x79, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0x61, Instruction::new(Operation::Adc, 0x61, AddressingMode::IndirectX, 6, 2));
+        map.insert(0x71, Instruction::new(Operation::Adc, 0x71, AddressingMode::IndirectY, 5, 2));
+        map.insert(0xE9, Instruction::new(Operation::Sbc, 0xE9, AddressingMode::Immediate, 2, 2));
+        map.insert(0xE5, Instruction::new(Operation::Sbc, 0xE5, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xF5, Instruction::new(Operation::Sbc, 0xF5, AddressingMode::ZeroPageX, 4, 2));
+        map.insert(0xED, Instruction::new(Operation::Sbc, 0xED, AddressingMode::Absolute, 4, 3));
+        map.insert(0xFD, Instruction::new(Operation::Sbc, 0xFD, AddressingMode::AbsoluteX, 4, 3));
+        map.insert(0xF9, Instruction::new(Operation::Sbc, 0xF9, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0xE1, Instruction::new(Operation::Sbc, 0xE1, AddressingMode::IndirectX, 6, 2));
+        map.insert(0xF1, Instruction::new(Operation::Sbc, 0xF1, AddressingMode::IndirectY, 5, 2));
+        // increment/decrement
+        map.insert(0xE6, Instruction::new(Operation::Inc, 0xE6, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0xF6, Instruction::new(Operation::Inc, 0xF6, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0xEE, Instruction::new(Operation::Inc, 0xEE, AddressingMode::Absolute, 6, 3));
+        map.insert(0xFE, Instruction::new(Operation::Inc, 0xFE, AddressingMode::AbsoluteX, 7, 3));
+        map.insert(0xC6, Instruction::new(Operation::Dec, 0xC6, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0xD6, Instruction::new(Operation::Dec, 0xD6, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0xCE, Instruction::new(Operation::Dec, 0xCE, AddressingMode::Absolute, 6, 3));
+        map.insert(0xDE, Instruction::new(Operation::Dec, 0xDE, AddressingMode::AbsoluteX, 7, 3));
+        map.insert(0xE8, Instruction::new(Operation::Inx, 0xE8, AddressingMode::Implied, 2, 1));
+        map.insert(0xC8, Instruction::new(Operation::Iny, 0xC8, AddressingMode::Implied, 2, 1));
+        map.insert(0xCA, Instruction::new(Operation::Dex, 0xCA, AddressingMode::Implied, 2, 1));
+        map.insert(0x88, Instruction::new(Operation::Dey, 0x88, AddressingMode::Implied, 2, 1));
+        // shift
+        map.insert(0x0A, Instruction::new(Operation::Asl, 0x0A, AddressingMode::Accumulator, 2, 1));
+        map.insert(0x06, Instruction::new(Operation::Asl, 0x06, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0x16, Instruction::new(Operation::Asl, 0x16, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0x0E, Instruction::new(Operation::Asl, 0x0E, AddressingMode::Absolute, 6, 3));
+        map.insert(0x1E, Instruction::new(Operation::Asl, 0x1E, AddressingMode::AbsoluteX, 7, 3));
+        map.insert(0x4A, Instruction::new(Operation::Lsr, 0x4A, AddressingMode::Accumulator, 2, 1));
+        map.insert(0x46, Instruction::new(Operation::Lsr, 0x46, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0x56, Instruction::new(Operation::Lsr, 0x56, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0x4E, Instruction::new(Operation::Lsr, 0x4E, AddressingMode::Absolute, 6, 3));
+        map.insert(0x5E, Instruction::new(Operation::Lsr, 0x5E, AddressingMode::AbsoluteX, 7, 3));
+        map.insert(0x2A, Instruction::new(Operation::Rol, 0x2A, AddressingMode::Accumulator, 2, 1));
+        map.insert(0x26, Instruction::new(Operation::Rol, 0x26, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0x36, Instruction::new(Operation::Rol, 0x36, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0x2E, Instruction::new(Operation::Rol, 0x2E, AddressingMode::Absolute, 6, 3));
+        map.insert(0x3E, Instruction::new(Operation::Rol, 0x3E, AddressingMode::AbsoluteX, 7, 3));
+        map.insert(0x6A, Instruction::new(Operation::Ror, 0x6A, AddressingMode::Accumulator, 2, 1));
+        map.insert(0x66, Instruction::new(Operation::Ror, 0x66, AddressingMode::ZeroPage, 5, 2));
+        map.insert(0x76, Instruction::new(Operation::Ror, 0x76, AddressingMode::ZeroPageX, 6, 2));
+        map.insert(0x6E, Instruction::new(Operation::Ror, 0x6E, AddressingMode::Absolute, 6, 3));
+        map.insert(0x7E, Instruction::new(Operation::Ror, 0x7E, AddressingMode::AbsoluteX, 7, 3));
         // jump
-        map.insert(0xB0, Instruction::new("BCS", 0xB0, AddressingMode::Implied, 2, 2));
-        map.insert(0x90, Instruction::new("BCC", 0x90, AddressingMode::Implied, 2, 2));
-        map.insert(0xF0, Instruction::new("BEQ", 0xF0, AddressingMode::Implied, 2, 2));
-        map.insert(0xD0, Instruction::new("BNE", 0xD0, AddressingMode::Implied, 2, 2));
-        map.insert(0x20, Instruction::new("JSR", 0x20, AddressingMode::Absolute, 6, 3));
-        map.insert(0x30, Instruction::new("BIM", 0x30, AddressingMode::Implied, 2, 2));
+        map.insert(0x4C, Instruction::new(Operation::Jmp, 0x4C, AddressingMode::Absolute, 3, 3));
+        map.insert(0x6C, Instruction::new(Operation::Jmp, 0x6C, AddressingMode::Indirect, 5, 3));
+        map.insert(0x20, Instruction::new(Operation::Jsr, 0x20, AddressingMode::Absolute, 6, 3));
+        map.insert(0x60, Instruction::new(Operation::Rts, 0x60, AddressingMode::Implied, 6, 1));
+        // branch
+        map.insert(0xB0, Instruction::new(Operation::Bcs, 0xB0, AddressingMode::Relative, 2, 2));
+        map.insert(0x90, Instruction::new(Operation::Bcc, 0x90, AddressingMode::Relative, 2, 2));
+        map.insert(0xF0, Instruction::new(Operation::Beq, 0xF0, AddressingMode::Relative, 2, 2));
+        map.insert(0xD0, Instruction::new(Operation::Bne, 0xD0, AddressingMode::Relative, 2, 2));
+        map.insert(0x30, Instruction::new(Operation::Bmi, 0x30, AddressingMode::Relative, 2, 2));
+        map.insert(0x10, Instruction::new(Operation::Bpl, 0x10, AddressingMode::Relative, 2, 2));
+        map.insert(0x70, Instruction::new(Operation::Bvs, 0x70, AddressingMode::Relative, 2, 2));
+        map.insert(0x50, Instruction::new(Operation::Bvc, 0x50, AddressingMode::Relative, 2, 2));
         // stack
-        map.insert(0x48, Instruction::new("PHA", 0x48, AddressingMode::Implied, 1, 3));
-        map.insert(0x08, Instruction::new("PHP", 0x08, AddressingMode::Implied, 1, 3));
-        map.insert(0x68, Instruction::new("PLA", 0x68, AddressingMode::Implied, 1, 4));
+        map.insert(0x48, Instruction::new(Operation::Pha, 0x48, AddressingMode::Implied, 3, 1));
+        map.insert(0x08, Instruction::new(Operation::Php, 0x08, AddressingMode::Implied, 3, 1));
+        map.insert(0x68, Instruction::new(Operation::Pla, 0x68, AddressingMode::Implied, 4, 1));
+        map.insert(0x28, Instruction::new(Operation::Plp, 0x28, AddressingMode::Implied, 4, 1));
+        // flags
+        map.insert(0x18, Instruction::new(Operation::Clc, 0x18, AddressingMode::Implied, 2, 1));
+        map.insert(0x38, Instruction::new(Operation::Sec, 0x38, AddressingMode::Implied, 2, 1));
+        map.insert(0x58, Instruction::new(Operation::Cli, 0x58, AddressingMode::Implied, 2, 1));
+        map.insert(0x78, Instruction::new(Operation::Sei, 0x78, AddressingMode::Implied, 2, 1));
+        map.insert(0xB8, Instruction::new(Operation::Clv, 0xB8, AddressingMode::Implied, 2, 1));
+        map.insert(0xD8, Instruction::new(Operation::Cld, 0xD8, AddressingMode::Implied, 2, 1));
+        map.insert(0xF8, Instruction::new(Operation::Sed, 0xF8, AddressingMode::Implied, 2, 1));
+        map.insert(0xEA, Instruction::new(Operation::Nop, 0xEA, AddressingMode::Implied, 2, 1));
+
+        // unofficial
+        for opcode in [0x1A, 0x3A, 0x5A, 0x7A, 0xDA, 0xFA] {
+            map.insert(opcode, Instruction::unofficial(Operation::Nop, opcode, AddressingMode::Implied, 2, 1));
+        }
+        for opcode in [0x80, 0x82, 0x89, 0xC2, 0xE2] {
+            map.insert(opcode, Instruction::unofficial(Operation::Nop, opcode, AddressingMode::Immediate, 2, 2));
+        }
+        for opcode in [0x04, 0x44, 0x64] {
+            map.insert(opcode, Instruction::unofficial(Operation::Nop, opcode, AddressingMode::ZeroPage, 3, 2));
+        }
+        for opcode in [0x14, 0x34, 0x54, 0x74, 0xD4, 0xF4] {
+            map.insert(opcode, Instruction::unofficial(Operation::Nop, opcode, AddressingMode::ZeroPageX, 4, 2));
+        }
+        map.insert(0x0C, Instruction::unofficial(Operation::Nop, 0x0C, AddressingMode::Absolute, 4, 3));
+        for opcode in [0x1C, 0x3C, 0x5C, 0x7C, 0xDC, 0xFC] {
+            map.insert(opcode, Instruction::unofficial(Operation::Nop, opcode, AddressingMode::AbsoluteX, 4, 3));
+        }
+        map.insert(0xA7, Instruction::unofficial(Operation::Lax, 0xA7, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0xB7, Instruction::unofficial(Operation::Lax, 0xB7, AddressingMode::ZeroPageY, 4, 2));
+        map.insert(0xAF, Instruction::unofficial(Operation::Lax, 0xAF, AddressingMode::Absolute, 4, 3));
+        map.insert(0xBF, Instruction::unofficial(Operation::Lax, 0xBF, AddressingMode::AbsoluteY, 4, 3));
+        map.insert(0xA3, Instruction::unofficial(Operation::Lax, 0xA3, AddressingMode::IndirectX, 6, 2));
+        map.insert(0xB3, Instruction::unofficial(Operation::Lax, 0xB3, AddressingMode::IndirectY, 5, 2));
+        map.insert(0x87, Instruction::unofficial(Operation::Sax, 0x87, AddressingMode::ZeroPage, 3, 2));
+        map.insert(0x97, Instruction::unofficial(Operation::Sax, 0x97, AddressingMode::ZeroPageY, 4, 2));
+        map.insert(0x8F, Instruction::unofficial(Operation::Sax, 0x8F, AddressingMode::Absolute, 4, 3));
+        map.insert(0x83, Instruction::unofficial(Operation::Sax, 0x83, AddressingMode::IndirectX, 6, 2));
+        map.insert(0xEB, Instruction::unofficial(Operation::Sbc, 0xEB, AddressingMode::Immediate, 2, 2));
+        // read-modify-write combos share one opcode layout: aaa bbb 11
+        for (operation, base) in [(Operation::Slo, 0x00), (Operation::Rla, 0x20), (Operation::Sre, 0x40), (Operation::Rra, 0x60), (Operation::Dcp, 0xC0), (Operation::Isb, 0xE0)] {
+            map.insert(base + 0x07, Instruction::unofficial(operation, base + 0x07, AddressingMode::ZeroPage, 5, 2));
+            map.insert(base + 0x17, Instruction::unofficial(operation, base + 0x17, AddressingMode::ZeroPageX, 6, 2));
+            map.insert(base + 0x0F, Instruction::unofficial(operation, base + 0x0F, AddressingMode::Absolute, 6, 3));
+            map.insert(base + 0x1F, Instruction::unofficial(operation, base + 0x1F, AddressingMode::AbsoluteX, 7, 3));
+            map.insert(base + 0x1B, Instruction::unofficial(operation, base + 0x1B, AddressingMode::AbsoluteY, 7, 3));
+            map.insert(base + 0x03, Instruction::unofficial(operation, base + 0x03, AddressingMode::IndirectX, 8, 2));
+            map.insert(base + 0x13, Instruction::unofficial(operation, base + 0x13, AddressingMode::IndirectY, 8, 2));
+        }
         map
     };
 }
@@ -81,7 +326,95 @@ lazy_static! {
 #[allow(dead_code)]
 const PROGRAM_ADDRESS: u16 = 0x8000;
 
-pub struct Cpu {
+// Internal RAM, mirrored up to 0x1FFF
+pub const RAM_SIZE: usize = 0x800;
+
+// The stack lives in page 1 (0x0100-0x01FF)
+const STACK_ADDRESS: u16 = 0x0100;
+
+// Interrupt vectors
+const NMI_VECTOR: u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+const INTERRUPT_CYCLES: u8 = 7;
+
+// OAM DMA copies a page to OAMDATA, a read and a write per byte
+const OAMDATA_ADDRESS: u16 = 0x2004;
+
+// Cartridge space where writes may reach mapper registers
+const MAPPER_SPACE_START: u16 = 0x4020;
+// PRG ROM, the only code the decode cache keeps
+const DECODE_CACHE_START: u16 = 0x8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+// Why the CPU stopped executing. A faulted CPU stays put with PC on the offending
+// instruction until the fault is cleared, so a debugger can patch it or skip it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFault {
+    UnknownOpcode { pc: u16, opcode: u8 },
+}
+
+impl fmt::Display for CpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CpuFault::UnknownOpcode { pc, opcode } => write!(f, "unknown opcode ${:02X} at ${:04X}", opcode, pc),
+        }
+    }
+}
+
+// Instructions decoded from PRG ROM by address, so code that runs again skips the
+// opcode fetch and lookup. Any write to mapper space could switch banks, so it
+// drops everything at once by moving to a new generation.
+pub struct DecodeCache {
+    entries: Vec<(u32, Option<&'static Instruction>)>,
+    generation: u32,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DecodeCache {
+    pub fn new() -> Self {
+        DecodeCache {
+            entries: vec![(0, None); 0x10000 - DECODE_CACHE_START as usize],
+            generation: 1,
+        }
+    }
+
+    pub fn get(&self, address: u16) -> Option<&'static Instruction> {
+        let index = address.checked_sub(DECODE_CACHE_START)? as usize;
+        match self.entries[index] {
+            (generation, instruction) if generation == self.generation => instruction,
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, address: u16, instruction: &'static Instruction) {
+        if let Some(index) = address.checked_sub(DECODE_CACHE_START) {
+            self.entries[index as usize] = (self.generation, Some(instruction));
+        }
+    }
+
+    pub fn invalidate(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+        // entries from before the wrap would look current again
+        if self.generation == 0 {
+            self.entries.fill((0, None));
+            self.generation = 1;
+        }
+    }
+}
+
+pub struct Cpu<B: Bus = FlatBus> {
     // Accumulator
     pub a: u8,
 
@@ -102,22 +435,129 @@ pub struct Cpu {
     
     // Status register
     pub p: StatusFlag,
-    pub memory: [u8; 0xFFFF],
-    pub cycles: u8,
+    pub bus: B,
+
+    // Total number of cycles executed since reset
+    pub cycles: u64,
+
+    // Cycles of the current step the bus has been ticked for
+    bus_cycles: u16,
+
+    // Interrupt lines and the interrupts polled at the end of the last instruction
+    nmi_line: bool,
+    nmi_edge: bool,
+    irq_lines: IrqSource,
+    nmi_pending: bool,
+    irq_pending: bool,
+
+    // None unless enabled with set_decode_cache
+    decode_cache: Option<DecodeCache>,
+
+    // Set when an instruction can't be executed, step() does nothing until it's cleared
+    pub fault: Option<CpuFault>,
 }
 
-impl Memory for Cpu {
+// Plain copy of the CPU registers and internal state, for debuggers and tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuState {
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub pc: u16,
+    pub sp: u8,
+    pub p: StatusFlag,
+    pub cycles: u64,
+    pub nmi_line: bool,
+    pub irq_lines: IrqSource,
+    pub nmi_pending: bool,
+    pub irq_pending: bool,
+}
+
+// Byte access for tools and tests: reads are peeks, writes go to the bus
+impl<B: Bus> Memory for Cpu<B> {
     fn read_byte(&self, address: u16) -> u8 {
-        self.memory[address as usize]
+        self.bus.peek(address)
     }
 
     fn write_byte(&mut self, address: u16, value: u8) {
-        self.memory[address as usize] = value;
+        if self.bus.changes_prg(address) {
+            self.invalidate_decode_cache();
+        }
+        self.bus.write(address, value);
+    }
+}
+
+// The interrupt lines go in too, so a state saved between an NMI edge and its
+// service still takes the NMI after loading
+impl<B: Bus + SaveState> SaveState for Cpu<B> {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.a, self.x, self.y, self.sp, self.p.bits()]);
+        out.extend_from_slice(&self.pc.to_le_bytes());
+        out.extend_from_slice(&self.cycles.to_le_bytes());
+        out.extend_from_slice(&[
+            self.nmi_line as u8,
+            self.nmi_edge as u8,
+            self.nmi_pending as u8,
+            self.irq_pending as u8,
+            self.irq_lines.bits(),
+        ]);
+        match self.fault {
+            Some(CpuFault::UnknownOpcode { pc, opcode }) => {
+                out.push(1);
+                out.extend_from_slice(&pc.to_le_bytes());
+                out.push(opcode);
+            }
+            None => out.extend_from_slice(&[0; 4]),
+        }
+        self.bus.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.a = input.read_u8()?;
+        self.x = input.read_u8()?;
+        self.y = input.read_u8()?;
+        self.sp = input.read_u8()?;
+        self.p = StatusFlag::from_bits_truncate(input.read_u8()?);
+        self.pc = input.read_u16()?;
+        self.cycles = input.read_u64()?;
+        self.nmi_line = input.read_u8()? != 0;
+        self.nmi_edge = input.read_u8()? != 0;
+        self.nmi_pending = input.read_u8()? != 0;
+        self.irq_pending = input.read_u8()? != 0;
+        self.irq_lines = IrqSource::from_bits_truncate(input.read_u8()?);
+        let faulted = input.read_u8()? != 0;
+        let (pc, opcode) = (input.read_u16()?, input.read_u8()?);
+        self.fault = faulted.then_some(CpuFault::UnknownOpcode { pc, opcode });
+        self.invalidate_decode_cache();
+        self.bus.load_state(input)
+    }
+
+    fn rom_crc32(&self) -> u32 {
+        self.bus.rom_crc32()
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 impl Cpu {
     pub fn new() -> Self {
+        Cpu::with_bus(FlatBus::new())
+    }
+
+    // Loads the given program to PRG ROM memory range (0x8000-0xFFFF)
+    pub fn load_program(&mut self, program: Vec<u8>, address: u16) {
+        let start = address as usize;
+        self.bus.memory[start..start + program.len()].copy_from_slice(&program);
+        self.write_word(RESET_VECTOR, address);
+    }
+}
+
+impl<B: Bus> Cpu<B> {
+    pub fn with_bus(bus: B) -> Self {
         Cpu {
             a: 0,
             x: 0,
@@ -125,8 +565,16 @@ impl Cpu {
             pc: 0,
             sp: 0,
             p: StatusFlag::empty(),
-            memory: [0; 0xFFFF],
+            bus,
             cycles: 0,
+            bus_cycles: 0,
+            nmi_line: false,
+            nmi_edge: false,
+            irq_lines: IrqSource::empty(),
+            nmi_pending: false,
+            irq_pending: false,
+            decode_cache: None,
+            fault: None,
         }
     }
 
@@ -136,10 +584,15 @@ impl Cpu {
         self.y = 0;
 
         // set PC to the address stored at 0xFFFC
-        self.pc = self.read_word(0xFFFC);
+        self.pc = self.read_word(RESET_VECTOR);
         self.sp = 0xFD;
         self.p = StatusFlag::empty();
         self.cycles = 0;
+        self.nmi_edge = false;
+        self.nmi_pending = false;
+        self.irq_pending = false;
+        self.fault = None;
+        self.invalidate_decode_cache();
 
         self.set_flag(StatusFlag::InterruptDisable, true);
     }
@@ -156,66 +609,804 @@ impl Cpu {
         self.p & flag != StatusFlag::empty()
     }
 
-    // Loads the given program to PRG ROM memory range (0x8000-0xFFFF)
-    pub fn load_program(&mut self, program: Vec<u8>, address: u16) {
-        self.memory[address as usize..(address + program.len() as u16) as usize].copy_from_slice(&program);
-        self.write_word(0xFFFC, address);
-    }
-
+    // Runs until a BRK instruction is executed or the CPU faults
     pub fn run(&mut self) {
         loop {
-            // get opcode at program counter
             let opcode = self.read_byte(self.pc);
-            self.pc += 1;
-
-            // get instruction metadata for opcode
-            let instruction = INSTRUCTIONS
-                .get(&opcode)
-                .unwrap();
-            
-            // get operand address for instruction
-            let operand_address = self.get_operand_address(instruction);
-            
-            // execute instruction and return number of cycles
-            let operate_cycles = match instruction.opcode {
-                0x00 => self.brk(operand_address, &instruction.addressing_mode),
-                0xA9 => self.lda(operand_address, &instruction.addressing_mode),
-                _ => panic!("Instruction {} not implemented!", instruction.mnemonic),
-            };
-        }
-    }
-
-    fn get_operand_address(&self, instruction: &Instruction) -> u16 {
+            self.step();
+            if opcode == 0x00 || self.fault.is_some() {
+                break;
+            }
+        }
+    }
+
+    // Drives the NMI line, the PPU holds it while vblank and NMI output are both on.
+    // NMI is edge triggered: only a low to high transition requests an interrupt.
+    pub fn set_nmi(&mut self, level: bool) {
+        if level && !self.nmi_line {
+            self.nmi_edge = true;
+        }
+        self.nmi_line = level;
+    }
+
+    // Drives one of the IRQ sources. IRQ is level triggered and stays requested
+    // until every source has been acknowledged.
+    pub fn set_irq(&mut self, source: IrqSource, active: bool) {
+        self.irq_lines.set(source, active);
+    }
+
+    pub fn irq_lines(&self) -> IrqSource {
+        self.irq_lines
+    }
+
+    pub fn snapshot(&self) -> CpuState {
+        CpuState {
+            a: self.a,
+            x: self.x,
+            y: self.y,
+            pc: self.pc,
+            sp: self.sp,
+            p: self.p,
+            cycles: self.cycles,
+            nmi_line: self.nmi_line,
+            irq_lines: self.irq_lines,
+            nmi_pending: self.nmi_pending || self.nmi_edge,
+            irq_pending: self.irq_pending,
+        }
+    }
+
+    // Interrupts are polled at the end of every instruction. A line that changes
+    // after an instruction has finished is therefore only seen at the end of the
+    // next one, which is the one instruction delay games rely on.
+    fn poll_interrupts(&mut self, interrupt_disable: bool) {
+        self.nmi_pending = self.nmi_edge;
+        self.irq_pending = !self.irq_lines.is_empty() && !interrupt_disable;
+    }
+
+    fn interrupt(&mut self, vector: u16) -> u8 {
+        self.push_word(self.pc);
+        self.push(((self.p | StatusFlag::Unused) - StatusFlag::Break).bits());
+        self.set_flag(StatusFlag::InterruptDisable, true);
+        // an IRQ polled alongside an NMI is masked by the I flag just set
+        self.irq_pending = false;
+        self.pc = self.read_vector(vector);
+        INTERRUPT_CYCLES
+    }
+
+    // Executes a single instruction, or enters a pending interrupt handler, and
+    // returns the number of cycles it took, with the OAM DMA it started. Takes no
+    // cycles while faulted.
+    pub fn step(&mut self) -> u16 {
+        if self.fault.is_some() {
+            return 0;
+        }
+        self.bus_cycles = 0;
+        let cycles = if self.nmi_pending {
+            self.nmi_edge = false;
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR)
+        } else if self.irq_pending {
+            self.irq_pending = false;
+            self.interrupt(IRQ_VECTOR)
+        } else {
+            self.execute()
+        };
+        // the cycles the instruction spent without an access, like an implied
+        // instruction's, happen at its end
+        let cycles = (cycles as u16).max(self.bus_cycles);
+        self.bus.tick((cycles - self.bus_cycles) as u8);
+        self.cycles += cycles as u64;
+        match self.bus.take_oam_dma() {
+            Some(page) => cycles + self.oam_dma(page),
+            None => cycles,
+        }
+    }
+
+    // The CPU halts for a cycle, one more when that leaves it on an odd cycle so the
+    // reads line up, then copies the page a read and a write at a time: 513 or 514
+    // cycles in all
+    fn oam_dma(&mut self, page: u8) -> u16 {
+        let start = self.bus_cycles;
+        for _ in 0..1 + self.cycles % 2 {
+            self.cycle();
+        }
+        for low in 0..=0xFF {
+            let value = self.read(u16::from_le_bytes([low, page]));
+            self.write(OAMDATA_ADDRESS, value);
+        }
+        let cycles = self.bus_cycles - start;
+        self.cycles += cycles as u64;
+        cycles
+    }
+
+    fn execute(&mut self) -> u8 {
+        let interrupt_disable = self.get_flag(StatusFlag::InterruptDisable);
+
+        // get instruction metadata for the opcode at program counter
+        let Some(instruction) = self.decode() else {
+            return 0;
+        };
+        self.pc = self.pc.wrapping_add(1);
+
+        // get operand address for instruction
+        let (address, page_crossed) = self.get_operand_address(instruction);
+        let mode = &instruction.addressing_mode;
+
+        // execute instruction and return number of extra cycles
+        let extra_cycles = match instruction.operation {
+            Operation::Adc => self.adc(address, page_crossed),
+            Operation::And => self.and(address, page_crossed),
+            Operation::Asl => self.asl(address, mode),
+            Operation::Bcc => self.branch(!self.get_flag(StatusFlag::Carry), address, page_crossed),
+            Operation::Bcs => self.branch(self.get_flag(StatusFlag::Carry), address, page_crossed),
+            Operation::Beq => self.branch(self.get_flag(StatusFlag::Zero), address, page_crossed),
+            Operation::Bmi => self.branch(self.get_flag(StatusFlag::Negative), address, page_crossed),
+            Operation::Bne => self.branch(!self.get_flag(StatusFlag::Zero), address, page_crossed),
+            Operation::Bpl => self.branch(!self.get_flag(StatusFlag::Negative), address, page_crossed),
+            Operation::Bvc => self.branch(!self.get_flag(StatusFlag::Overflow), address, page_crossed),
+            Operation::Bvs => self.branch(self.get_flag(StatusFlag::Overflow), address, page_crossed),
+            Operation::Bit => self.bit(address),
+            Operation::Brk => self.brk(),
+            Operation::Clc => self.flag(StatusFlag::Carry, false),
+            Operation::Cld => self.flag(StatusFlag::Decimal, false),
+            Operation::Cli => self.flag(StatusFlag::InterruptDisable, false),
+            Operation::Clv => self.flag(StatusFlag::Overflow, false),
+            Operation::Cmp => self.compare(self.a, address, page_crossed),
+            Operation::Cpx => self.compare(self.x, address, page_crossed),
+            Operation::Cpy => self.compare(self.y, address, page_crossed),
+            Operation::Dec => self.dec(address),
+            Operation::Dex => self.dex(),
+            Operation::Dey => self.dey(),
+            Operation::Eor => self.eor(address, page_crossed),
+            Operation::Inc => self.inc(address),
+            Operation::Inx => self.inx(),
+            Operation::Iny => self.iny(),
+            Operation::Jmp => self.jmp(address),
+            Operation::Jsr => self.jsr(address),
+            Operation::Lda => self.lda(address, page_crossed),
+            Operation::Ldx => self.ldx(address, page_crossed),
+            Operation::Ldy => self.ldy(address, page_crossed),
+            Operation::Lsr => self.lsr(address, mode),
+            Operation::Nop => page_crossed as u8,
+            Operation::Ora => self.ora(address, page_crossed),
+            Operation::Pha => self.pha(),
+            Operation::Php => self.php(),
+            Operation::Pla => self.pla(),
+            Operation::Plp => self.plp(),
+            Operation::Rol => self.rol(address, mode),
+            Operation::Ror => self.ror(address, mode),
+            Operation::Rti => self.rti(),
+            Operation::Rts => self.rts(),
+            Operation::Sbc => self.sbc(address, page_crossed),
+            Operation::Sec => self.flag(StatusFlag::Carry, true),
+            Operation::Sed => self.flag(StatusFlag::Decimal, true),
+            Operation::Sei => self.flag(StatusFlag::InterruptDisable, true),
+            Operation::Sta => self.store(address, self.a),
+            Operation::Stx => self.store(address, self.x),
+            Operation::Sty => self.store(address, self.y),
+            Operation::Tax => self.tax(),
+            Operation::Tay => self.tay(),
+            Operation::Tsx => self.tsx(),
+            Operation::Txa => self.txa(),
+            Operation::Txs => self.txs(),
+            Operation::Tya => self.tya(),
+            // unofficial
+            Operation::Lax => self.lax(address, page_crossed),
+            Operation::Sax => self.store(address, self.a & self.x),
+            Operation::Dcp => self.dcp(address),
+            Operation::Isb => self.isb(address),
+            Operation::Slo => self.slo(address),
+            Operation::Rla => self.rla(address),
+            Operation::Sre => self.sre(address),
+            Operation::Rra => self.rra(address),
+        };
+
+        // CLI, SEI and PLP change the I flag after the interrupt poll
+        let polled_interrupt_disable = match instruction.operation {
+            Operation::Cli | Operation::Sei | Operation::Plp => interrupt_disable,
+            _ => self.get_flag(StatusFlag::InterruptDisable),
+        };
+        self.poll_interrupts(polled_interrupt_disable);
+
+        instruction.cycles + extra_cycles
+    }
+
+    // The interrupt the next step() services instead of executing an instruction
+    pub fn pending_interrupt(&self) -> Option<Interrupt> {
+        if self.nmi_pending {
+            Some(Interrupt::Nmi)
+        } else if self.irq_pending {
+            Some(Interrupt::Irq)
+        } else {
+            None
+        }
+    }
+
+    // Fetches and looks up the opcode at PC, through the decode cache when enabled.
+    // Faults the CPU on an opcode without an instruction.
+    fn decode(&mut self) -> Option<&'static Instruction> {
+        let pc = self.pc;
+        if let Some(instruction) = self.decode_cache.as_ref().and_then(|cache| cache.get(pc)) {
+            // the fetch still takes its cycle
+            self.cycle();
+            return Some(instruction);
+        }
+        let opcode = self.read(pc);
+        let Some(instruction) = INSTRUCTIONS.get(opcode) else {
+            self.fault = Some(CpuFault::UnknownOpcode { pc, opcode });
+            return None;
+        };
+        if let Some(cache) = &mut self.decode_cache {
+            cache.insert(pc, instruction);
+        }
+        Some(instruction)
+    }
+
+    // Resumes a faulted CPU by stepping over the offending opcode as if it were a
+    // one byte NOP. To retry it instead, patch memory and clear `fault`.
+    pub fn skip_faulted_instruction(&mut self) {
+        if let Some(CpuFault::UnknownOpcode { pc, .. }) = self.fault.take() {
+            self.pc = pc.wrapping_add(1);
+        }
+    }
+
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = enabled.then(DecodeCache::new);
+    }
+
+    pub fn decode_cache_enabled(&self) -> bool {
+        self.decode_cache.is_some()
+    }
+
+    // Needed after changing PRG memory behind the CPU's back, e.g. loading a state
+    pub fn invalidate_decode_cache(&mut self) {
+        if let Some(cache) = &mut self.decode_cache {
+            cache.invalidate();
+        }
+    }
+
+    // Puts back the PRG ROM that Bus::patch_prg replaced
+    pub fn clear_prg_patches(&mut self) {
+        self.bus.clear_prg_patches();
+        self.invalidate_decode_cache();
+    }
+
+    // Returns the effective address of the operand and whether indexing crossed a page boundary.
+    // Advances the program counter past the operand bytes.
+    // Fetches the operand bytes and pointers through the bus like the real CPU, with
+    // its dummy reads: indexing reads the unindexed zero page address, and an indexed
+    // absolute read first reads the address before the page carry is fixed up. That
+    // read only happens on a page cross for loads, but always for stores and
+    // read-modify-write instructions, which can't know the carry in time.
+    fn get_operand_address(&mut self, instruction: &Instruction) -> (u16, bool) {
+        let address = self.pc;
+        self.pc = self.pc.wrapping_add(instruction.bytes as u16 - 1);
+        let fetch_word = |cpu: &mut Self| {
+            let lo = cpu.read(address) as u16;
+            let hi = cpu.read(address.wrapping_add(1)) as u16;
+            (hi << 8) | lo
+        };
+        let indexed = |cpu: &mut Self, base: u16, index: u8| {
+            let effective = base.wrapping_add(index as u16);
+            let crossed = page_crossed(base, effective);
+            if crossed || instruction.writes_memory() {
+                cpu.read((base & 0xFF00) | (effective & 0x00FF));
+            }
+            (effective, crossed)
+        };
         match instruction.addressing_mode {
-            AddressingMode::Immediate => {
-                self.pc
+            AddressingMode::Implied | AddressingMode::Accumulator => (0, false),
+            AddressingMode::Immediate => (address, false),
+            AddressingMode::ZeroPage => (self.read(address) as u16, false),
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let base = self.read(address);
+                self.read(base as u16);
+                let index = if let AddressingMode::ZeroPageX = instruction.addressing_mode { self.x } else { self.y };
+                (base.wrapping_add(index) as u16, false)
+            }
+            AddressingMode::Absolute => (fetch_word(self), false),
+            AddressingMode::AbsoluteX => {
+                let base = fetch_word(self);
+                indexed(self, base, self.x)
+            }
+            AddressingMode::AbsoluteY => {
+                let base = fetch_word(self);
+                indexed(self, base, self.y)
+            }
+            AddressingMode::Indirect => {
+                let pointer = fetch_word(self);
+                let lo = self.read(pointer) as u16;
+                let hi = self.read((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as u16;
+                ((hi << 8) | lo, false)
+            }
+            AddressingMode::IndirectX => {
+                let base = self.read(address);
+                self.read(base as u16);
+                let pointer = base.wrapping_add(self.x);
+                let lo = self.read(pointer as u16) as u16;
+                let hi = self.read(pointer.wrapping_add(1) as u16) as u16;
+                ((hi << 8) | lo, false)
+            }
+            AddressingMode::IndirectY => {
+                let pointer = self.read(address);
+                let lo = self.read(pointer as u16) as u16;
+                let hi = self.read(pointer.wrapping_add(1) as u16) as u16;
+                indexed(self, (hi << 8) | lo, self.y)
+            }
+            AddressingMode::Relative => {
+                let next = address.wrapping_add(1);
+                let target = next.wrapping_add(self.read(address) as i8 as u16);
+                (target, page_crossed(next, target))
             }
             AddressingMode::None => {
                 panic!("Addressing mode {} not supported!", instruction.addressing_mode);
             }
-            _ => panic!("Addressing mode {} not implemented!", instruction.addressing_mode),
         }
     }
 
-    fn lda(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
-        self.a = self.read_byte(address);
-        self.pc += 1;
-        self.set_flag(StatusFlag::Zero, self.a == 0);
-        self.set_flag(StatusFlag::Negative, self.a & 0x80 != 0);
+    // Computes the effective address for the operand bytes starting at the given address,
+    // without side effects, so debugging tools can peek at what an instruction will access
+    pub fn operand_address_at(&self, address: u16, addressing_mode: &AddressingMode) -> (u16, bool) {
+        match addressing_mode {
+            AddressingMode::Implied | AddressingMode::Accumulator => (0, false),
+            AddressingMode::Immediate => (address, false),
+            AddressingMode::ZeroPage => (self.read_byte(address) as u16, false),
+            AddressingMode::ZeroPageX => (self.read_byte(address).wrapping_add(self.x) as u16, false),
+            AddressingMode::ZeroPageY => (self.read_byte(address).wrapping_add(self.y) as u16, false),
+            AddressingMode::Absolute => (self.read_word(address), false),
+            AddressingMode::AbsoluteX => {
+                let base = self.read_word(address);
+                let effective = base.wrapping_add(self.x as u16);
+                (effective, page_crossed(base, effective))
+            }
+            AddressingMode::AbsoluteY => {
+                let base = self.read_word(address);
+                let effective = base.wrapping_add(self.y as u16);
+                (effective, page_crossed(base, effective))
+            }
+            AddressingMode::Indirect => {
+                let pointer = self.read_word(address);
+                // the 6502 never carries into the high byte of the pointer,
+                // so JMP ($xxFF) fetches its high byte from $xx00
+                let lo = self.read_byte(pointer) as u16;
+                let hi = self.read_byte((pointer & 0xFF00) | (pointer.wrapping_add(1) & 0x00FF)) as u16;
+                ((hi << 8) | lo, false)
+            }
+            AddressingMode::IndirectX => {
+                let pointer = self.read_byte(address).wrapping_add(self.x);
+                (self.read_zero_page_word(pointer), false)
+            }
+            AddressingMode::IndirectY => {
+                let base = self.read_zero_page_word(self.read_byte(address));
+                let effective = base.wrapping_add(self.y as u16);
+                (effective, page_crossed(base, effective))
+            }
+            AddressingMode::Relative => {
+                // branches are relative to the address of the next instruction
+                let next = address.wrapping_add(1);
+                let target = next.wrapping_add(self.read_byte(address) as i8 as u16);
+                (target, page_crossed(next, target))
+            }
+            AddressingMode::None => {
+                panic!("Addressing mode {} not supported!", addressing_mode);
+            }
+        }
+    }
+
+    // Reads a pointer from the zero page, wrapping around at 0xFF
+    pub fn read_zero_page_word(&self, pointer: u8) -> u16 {
+        let lo = self.read_byte(pointer as u16) as u16;
+        let hi = self.read_byte(pointer.wrapping_add(1) as u16) as u16;
+        (hi << 8) | lo
+    }
+
+    // One CPU cycle on the bus, which runs the other devices up to it
+    fn cycle(&mut self) {
+        self.bus.tick(1);
+        self.bus_cycles += 1;
+    }
+
+    // Reads through the bus, with device side effects
+    fn read(&mut self, address: u16) -> u8 {
+        self.cycle();
+        self.bus.read(address)
+    }
+
+    // Writes through the bus on the instruction's own cycle, unlike write_byte
+    fn write(&mut self, address: u16, value: u8) {
+        self.cycle();
+        self.write_byte(address, value);
+    }
+
+    fn read_vector(&mut self, vector: u16) -> u16 {
+        let lo = self.read(vector) as u16;
+        let hi = self.read(vector.wrapping_add(1)) as u16;
+        (hi << 8) | lo
+    }
+
+    fn push(&mut self, value: u8) {
+        self.write(STACK_ADDRESS | self.sp as u16, value);
+        self.sp = self.sp.wrapping_sub(1);
+    }
+
+    fn pull(&mut self) -> u8 {
+        self.sp = self.sp.wrapping_add(1);
+        self.read(STACK_ADDRESS | self.sp as u16)
+    }
+
+    fn push_word(&mut self, value: u16) {
+        self.push((value >> 8) as u8);
+        self.push(value as u8);
+    }
+
+    fn pull_word(&mut self) -> u16 {
+        let lo = self.pull() as u16;
+        let hi = self.pull() as u16;
+        (hi << 8) | lo
+    }
+
+    fn set_zero_and_negative(&mut self, value: u8) {
+        self.set_flag(StatusFlag::Zero, value == 0);
+        self.set_flag(StatusFlag::Negative, value & 0x80 != 0);
+    }
+
+    // Adds the value and carry to the accumulator, shared by ADC and SBC
+    fn add_to_accumulator(&mut self, value: u8) {
+        let sum = self.a as u16 + value as u16 + self.get_flag(StatusFlag::Carry) as u16;
+        let result = sum as u8;
+        self.set_flag(StatusFlag::Carry, sum > 0xFF);
+        self.set_flag(StatusFlag::Overflow, (self.a ^ result) & (value ^ result) & 0x80 != 0);
+        self.a = result;
+        self.set_zero_and_negative(result);
+    }
+
+    // Applies a read-modify-write operation on the accumulator or memory
+    fn modify(&mut self, address: u16, addressing_mode: &AddressingMode, operation: fn(&mut Self, u8) -> u8) -> u8 {
+        if let AddressingMode::Accumulator = addressing_mode {
+            self.a = operation(self, self.a);
+            self.a
+        } else {
+            let value = self.read(address);
+            // the 6502 writes the unmodified value back while it computes the result
+            self.write(address, value);
+            let result = operation(self, value);
+            self.write(address, result);
+            result
+        }
+    }
+
+    fn shift_left(&mut self, value: u8) -> u8 {
+        self.set_flag(StatusFlag::Carry, value & 0x80 != 0);
+        let result = value << 1;
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn shift_right(&mut self, value: u8) -> u8 {
+        self.set_flag(StatusFlag::Carry, value & 0x01 != 0);
+        let result = value >> 1;
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn rotate_left(&mut self, value: u8) -> u8 {
+        let result = (value << 1) | self.get_flag(StatusFlag::Carry) as u8;
+        self.set_flag(StatusFlag::Carry, value & 0x80 != 0);
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn rotate_right(&mut self, value: u8) -> u8 {
+        let result = (value >> 1) | ((self.get_flag(StatusFlag::Carry) as u8) << 7);
+        self.set_flag(StatusFlag::Carry, value & 0x01 != 0);
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn adc(&mut self, address: u16, page_crossed: bool) -> u8 {
+        let value = self.read(address);
+        self.add_to_accumulator(value);
+        page_crossed as u8
+    }
+
+    fn sbc(&mut self, address: u16, page_crossed: bool) -> u8 {
+        let value = self.read(address);
+        self.add_to_accumulator(!value);
+        page_crossed as u8
+    }
+
+    fn and(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.a &= self.read(address);
+        self.set_zero_and_negative(self.a);
+        page_crossed as u8
+    }
+
+    fn ora(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.a |= self.read(address);
+        self.set_zero_and_negative(self.a);
+        page_crossed as u8
+    }
+
+    fn eor(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.a ^= self.read(address);
+        self.set_zero_and_negative(self.a);
+        page_crossed as u8
+    }
+
+    fn bit(&mut self, address: u16) -> u8 {
+        let value = self.read(address);
+        self.set_flag(StatusFlag::Zero, self.a & value == 0);
+        self.set_flag(StatusFlag::Overflow, value & 0x40 != 0);
+        self.set_flag(StatusFlag::Negative, value & 0x80 != 0);
+        0
+    }
+
+    fn compare(&mut self, register: u8, address: u16, page_crossed: bool) -> u8 {
+        let value = self.read(address);
+        self.compare_value(register, value);
+        page_crossed as u8
+    }
+
+    fn compare_value(&mut self, register: u8, value: u8) {
+        self.set_flag(StatusFlag::Carry, register >= value);
+        self.set_zero_and_negative(register.wrapping_sub(value));
+    }
+
+    fn asl(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
+        self.modify(address, addressing_mode, Self::shift_left);
+        0
+    }
+
+    fn lsr(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
+        self.modify(address, addressing_mode, Self::shift_right);
+        0
+    }
+
+    fn rol(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
+        self.modify(address, addressing_mode, Self::rotate_left);
+        0
+    }
+
+    fn ror(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
+        self.modify(address, addressing_mode, Self::rotate_right);
+        0
+    }
+
+    fn inc(&mut self, address: u16) -> u8 {
+        self.modify(address, &AddressingMode::Absolute, Self::increment);
+        0
+    }
+
+    fn dec(&mut self, address: u16) -> u8 {
+        self.modify(address, &AddressingMode::Absolute, Self::decrement);
+        0
+    }
+
+    fn increment(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_add(1);
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn decrement(&mut self, value: u8) -> u8 {
+        let result = value.wrapping_sub(1);
+        self.set_zero_and_negative(result);
+        result
+    }
+
+    fn inx(&mut self) -> u8 {
+        self.x = self.x.wrapping_add(1);
+        self.set_zero_and_negative(self.x);
+        0
+    }
+
+    fn iny(&mut self) -> u8 {
+        self.y = self.y.wrapping_add(1);
+        self.set_zero_and_negative(self.y);
+        0
+    }
+
+    fn dex(&mut self) -> u8 {
+        self.x = self.x.wrapping_sub(1);
+        self.set_zero_and_negative(self.x);
+        0
+    }
+
+    fn dey(&mut self) -> u8 {
+        self.y = self.y.wrapping_sub(1);
+        self.set_zero_and_negative(self.y);
+        0
+    }
+
+    // Taken branches cost one extra cycle, and another one when crossing a page
+    fn branch(&mut self, condition: bool, address: u16, page_crossed: bool) -> u8 {
+        if !condition {
+            return 0;
+        }
+        self.pc = address;
+        1 + page_crossed as u8
+    }
+
+    fn jmp(&mut self, address: u16) -> u8 {
+        self.pc = address;
+        0
+    }
+
+    fn jsr(&mut self, address: u16) -> u8 {
+        // the return address pushed is the last byte of the JSR instruction
+        self.push_word(self.pc.wrapping_sub(1));
+        self.pc = address;
+        0
+    }
+
+    fn rts(&mut self) -> u8 {
+        self.pc = self.pull_word().wrapping_add(1);
+        0
+    }
+
+    fn rti(&mut self) -> u8 {
+        self.plp();
+        self.pc = self.pull_word();
+        0
+    }
+
+    fn lda(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.a = self.read(address);
+        self.set_zero_and_negative(self.a);
+        page_crossed as u8
+    }
+
+    fn ldx(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.x = self.read(address);
+        self.set_zero_and_negative(self.x);
+        page_crossed as u8
+    }
+
+    fn ldy(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.y = self.read(address);
+        self.set_zero_and_negative(self.y);
+        page_crossed as u8
+    }
+
+    fn store(&mut self, address: u16, value: u8) -> u8 {
+        self.write(address, value);
+        0
+    }
+
+    fn flag(&mut self, flag: StatusFlag, value: bool) -> u8 {
+        self.set_flag(flag, value);
         0
     }
 
-    fn brk(&mut self, address: u16, addressing_mode: &AddressingMode) -> u8 {
+    fn tax(&mut self) -> u8 {
+        self.x = self.a;
+        self.set_zero_and_negative(self.x);
         0
     }
+
+    fn tay(&mut self) -> u8 {
+        self.y = self.a;
+        self.set_zero_and_negative(self.y);
+        0
+    }
+
+    fn tsx(&mut self) -> u8 {
+        self.x = self.sp;
+        self.set_zero_and_negative(self.x);
+        0
+    }
+
+    fn txa(&mut self) -> u8 {
+        self.a = self.x;
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    // TXS is the only transfer that leaves the flags untouched
+    fn txs(&mut self) -> u8 {
+        self.sp = self.x;
+        0
+    }
+
+    fn tya(&mut self) -> u8 {
+        self.a = self.y;
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    fn pha(&mut self) -> u8 {
+        self.push(self.a);
+        0
+    }
+
+    // The pushed copy of the status register always has Break and Unused set
+    fn php(&mut self) -> u8 {
+        self.push((self.p | StatusFlag::Break | StatusFlag::Unused).bits());
+        0
+    }
+
+    fn pla(&mut self) -> u8 {
+        self.a = self.pull();
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    // Break only exists on the stack, and Unused always reads back as set
+    fn plp(&mut self) -> u8 {
+        self.p = StatusFlag::from_bits_truncate(self.pull());
+        self.set_flag(StatusFlag::Break, false);
+        self.set_flag(StatusFlag::Unused, true);
+        0
+    }
+
+    fn brk(&mut self) -> u8 {
+        // BRK has a padding byte after the opcode which is skipped on return
+        self.push_word(self.pc.wrapping_add(1));
+        self.php();
+        self.set_flag(StatusFlag::InterruptDisable, true);
+        self.pc = self.read_vector(IRQ_VECTOR);
+        0
+    }
+
+    fn lax(&mut self, address: u16, page_crossed: bool) -> u8 {
+        self.lda(address, page_crossed);
+        self.x = self.a;
+        page_crossed as u8
+    }
+
+    // The unofficial read-modify-write combinations use the value they wrote
+    // rather than reading memory a second time
+    fn dcp(&mut self, address: u16) -> u8 {
+        let value = self.modify(address, &AddressingMode::Absolute, Self::decrement);
+        self.compare_value(self.a, value);
+        0
+    }
+
+    fn isb(&mut self, address: u16) -> u8 {
+        let value = self.modify(address, &AddressingMode::Absolute, Self::increment);
+        self.add_to_accumulator(!value);
+        0
+    }
+
+    fn slo(&mut self, address: u16) -> u8 {
+        self.a |= self.modify(address, &AddressingMode::Absolute, Self::shift_left);
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    fn rla(&mut self, address: u16) -> u8 {
+        self.a &= self.modify(address, &AddressingMode::Absolute, Self::rotate_left);
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    fn sre(&mut self, address: u16) -> u8 {
+        self.a ^= self.modify(address, &AddressingMode::Absolute, Self::shift_right);
+        self.set_zero_and_negative(self.a);
+        0
+    }
+
+    fn rra(&mut self, address: u16) -> u8 {
+        let value = self.modify(address, &AddressingMode::Absolute, Self::rotate_right);
+        self.add_to_accumulator(value);
+        0
+    }
+}
+
+fn page_crossed(a: u16, b: u16) -> bool {
+    a & 0xFF00 != b & 0xFF00
 }
 
 use std::fmt;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AddressingMode {
     None,
     Immediate,
     Implied,
+    Accumulator,
+    Relative,
+    Indirect,
     IndirectX,
     IndirectY,
     ZeroPage,
@@ -232,6 +1423,9 @@ impl fmt::Display for AddressingMode {
             AddressingMode::None => write!(f, "None"),
             AddressingMode::Immediate => write!(f, "Immediate"),
             AddressingMode::Implied => write!(f, "Implied"),
+            AddressingMode::Accumulator => write!(f, "Accumulator"),
+            AddressingMode::Relative => write!(f, "Relative"),
+            AddressingMode::Indirect => write!(f, "Indirect"),
             AddressingMode::IndirectX => write!(f, "IndirectX"),
             AddressingMode::IndirectY => write!(f, "IndirectY"),
             AddressingMode::ZeroPage => write!(f, "ZeroPage"),
@@ -258,6 +1452,16 @@ bitflags! {
     }
 }
 
+bitflags! {
+    // Devices that can hold the IRQ line low
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct IrqSource: u8 {
+        const FrameCounter = 1 << 0;
+        const Dmc = 1 << 1;
+        const Mapper = 1 << 2;
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -295,9 +1499,9 @@ mod tests {
         let mut cpu = Cpu::new();
         let flag = StatusFlag::Carry;
         cpu.set_flag(flag, true);
-        assert_eq!(cpu.get_flag(flag), true);
+        assert!(cpu.get_flag(flag));
         cpu.set_flag(flag, false);
-        assert_eq!(cpu.get_flag(flag), false);
+        assert!(!cpu.get_flag(flag));
     }
 
     #[test]
@@ -305,11 +1509,11 @@ mod tests {
         let mut cpu = Cpu::new();
         let program = vec![0x42, 0x42];
         cpu.load_program(program, PROGRAM_ADDRESS);
-        assert_eq!(cpu.read_byte(PROGRAM_ADDRESS+0), 0x42);
+        assert_eq!(cpu.read_byte(PROGRAM_ADDRESS), 0x42);
         assert_eq!(cpu.read_byte(PROGRAM_ADDRESS+1), 0x42);
     }
 
-    //#[test]
+    #[test]
     fn test_run_program_with_5_instructions() {
 
         // assambly:
@@ -329,5 +1533,427 @@ mod tests {
 
         // assert
         assert_eq!(cpu.x, 0xC1);
-    }    
-}
\ No newline at end of file
+    }
+
+    // Loads the program at PROGRAM_ADDRESS and executes the given number of instructions
+    fn run_instructions(program: Vec<u8>, count: usize) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program(program, PROGRAM_ADDRESS);
+        cpu.reset();
+        for _ in 0..count {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn test_adc_sets_carry_and_overflow() {
+        // LDA #$7F; ADC #$01
+        let cpu = run_instructions(vec![0xA9, 0x7F, 0x69, 0x01], 2);
+        assert_eq!(cpu.a, 0x80);
+        assert!(cpu.get_flag(StatusFlag::Overflow));
+        assert!(cpu.get_flag(StatusFlag::Negative));
+        assert!(!cpu.get_flag(StatusFlag::Carry));
+
+        // LDA #$FF; ADC #$01
+        let cpu = run_instructions(vec![0xA9, 0xFF, 0x69, 0x01], 2);
+        assert_eq!(cpu.a, 0x00);
+        assert!(cpu.get_flag(StatusFlag::Carry));
+        assert!(cpu.get_flag(StatusFlag::Zero));
+    }
+
+    #[test]
+    fn test_sbc_borrows_without_carry() {
+        // SEC; LDA #$05; SBC #$06
+        let cpu = run_instructions(vec![0x38, 0xA9, 0x05, 0xE9, 0x06], 3);
+        assert_eq!(cpu.a, 0xFF);
+        assert!(!cpu.get_flag(StatusFlag::Carry));
+        assert!(cpu.get_flag(StatusFlag::Negative));
+    }
+
+    #[test]
+    fn test_compare() {
+        // LDX #$10; CPX #$10
+        let cpu = run_instructions(vec![0xA2, 0x10, 0xE0, 0x10], 2);
+        assert!(cpu.get_flag(StatusFlag::Zero));
+        assert!(cpu.get_flag(StatusFlag::Carry));
+    }
+
+    #[test]
+    fn test_jsr_rts() {
+        // JSR $8004; BRK; INX; RTS
+        let mut cpu = run_instructions(vec![0x20, 0x04, 0x80, 0x00, 0xE8, 0x60], 1);
+        assert_eq!(cpu.pc, 0x8004);
+        assert_eq!(cpu.sp, 0xFB);
+        assert_eq!(cpu.read_word(0x01FC), 0x8002);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(cpu.x, 1);
+        assert_eq!(cpu.sp, 0xFD);
+    }
+
+    #[test]
+    fn test_jmp_indirect_page_wrap() {
+        // JMP ($02FF)
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0x6C, 0xFF, 0x02], PROGRAM_ADDRESS);
+        cpu.write_byte(0x02FF, 0x34);
+        cpu.write_byte(0x0200, 0x12);
+        cpu.write_byte(0x0300, 0x56);
+        cpu.reset();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x1234);
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // BNE +2 not taken (Z set by LDA #$00)
+        let mut cpu = run_instructions(vec![0xA9, 0x00, 0xD0, 0x02], 1);
+        assert_eq!(cpu.step(), 2);
+
+        // BEQ taken within the page
+        let mut cpu = run_instructions(vec![0xA9, 0x00, 0xF0, 0x02], 1);
+        assert_eq!(cpu.step(), 3);
+        assert_eq!(cpu.pc, 0x8006);
+
+        // BEQ taken backwards across a page boundary
+        let mut cpu = run_instructions(vec![0xA9, 0x00, 0xF0, 0x80], 1);
+        assert_eq!(cpu.step(), 4);
+        assert_eq!(cpu.pc, 0x7F84);
+    }
+
+    #[test]
+    fn test_page_cross_cycles() {
+        // LDX #$01; LDA $80FF,X
+        let mut cpu = run_instructions(vec![0xA2, 0x01, 0xBD, 0xFF, 0x80], 1);
+        assert_eq!(cpu.step(), 5);
+
+        // LDX #$01; STA $80FF,X always takes 5 cycles
+        let mut cpu = run_instructions(vec![0xA2, 0x01, 0x9D, 0xFF, 0x80], 1);
+        assert_eq!(cpu.step(), 5);
+    }
+
+    // NOPs at 0x8000 with interrupt handlers at 0x9000 (NMI) and 0xA000 (IRQ)
+    fn interrupt_cpu() -> Cpu {
+        let mut cpu = run_instructions(vec![0xEA; 8], 0);
+        cpu.write_word(NMI_VECTOR, 0x9000);
+        cpu.write_word(IRQ_VECTOR, 0xA000);
+        cpu.bus.memory[0x9000..0x9008].fill(0xEA);
+        cpu
+    }
+
+    #[test]
+    fn test_nmi_edge_and_delay() {
+        let mut cpu = interrupt_cpu();
+        cpu.set_nmi(true);
+        // seen at the end of the next instruction
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8001);
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x9000);
+        assert_eq!(cpu.read_word(0x01FC), 0x8001);
+        // B clear, U set
+        assert_eq!(cpu.read_byte(0x01FB), 0x24);
+
+        // holding the line doesn't retrigger
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9002);
+        cpu.set_nmi(false);
+        cpu.set_nmi(true);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000);
+    }
+
+    #[test]
+    fn test_irq_level_and_interrupt_disable() {
+        let mut cpu = interrupt_cpu();
+        cpu.set_irq(IrqSource::Mapper, true);
+        cpu.step();
+        cpu.step();
+        // masked by the I flag set on reset
+        assert_eq!(cpu.pc, 0x8002);
+
+        // CLI; NOP: the IRQ is taken after the NOP, not right after CLI
+        cpu.write_byte(0x8002, 0x58);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8004);
+        cpu.step();
+        assert_eq!(cpu.pc, 0xA000);
+        assert!(cpu.get_flag(StatusFlag::InterruptDisable));
+
+        cpu.set_irq(IrqSource::Mapper, false);
+        assert!(cpu.irq_lines().is_empty());
+    }
+
+    #[test]
+    fn test_nmi_masks_irq() {
+        let mut cpu = interrupt_cpu();
+        cpu.write_byte(0x8000, 0x58);
+        cpu.step();
+        cpu.set_nmi(true);
+        cpu.set_irq(IrqSource::Mapper, true);
+        cpu.step();
+        // both polled, the NMI wins and its handler runs with the IRQ masked
+        assert_eq!(cpu.pending_interrupt(), Some(Interrupt::Nmi));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9001);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut cpu = interrupt_cpu();
+        cpu.set_nmi(true);
+        cpu.set_irq(IrqSource::Dmc, true);
+        let state = cpu.snapshot();
+        assert_eq!((state.pc, state.sp, state.cycles), (0x8000, 0xFD, 0));
+        assert!(state.nmi_line && state.nmi_pending);
+        assert_eq!(state.irq_lines, IrqSource::Dmc);
+        cpu.step();
+        cpu.step();
+        assert!(!cpu.snapshot().nmi_pending);
+    }
+
+    #[test]
+    fn test_php_plp() {
+        // SEC; PHP; CLC; PLP
+        let cpu = run_instructions(vec![0x38, 0x08, 0x18, 0x28], 2);
+        assert_eq!(cpu.read_byte(0x01FD), 0x35);
+        let cpu = run_instructions(vec![0x38, 0x08, 0x18, 0x28], 4);
+        assert!(cpu.get_flag(StatusFlag::Carry));
+        assert!(!cpu.get_flag(StatusFlag::Break));
+        assert!(cpu.get_flag(StatusFlag::Unused));
+    }
+
+    #[test]
+    fn test_brk_rti() {
+        let mut cpu = Cpu::new();
+        // BRK; (padding); INX
+        cpu.load_program(vec![0x00, 0x00, 0xE8], PROGRAM_ADDRESS);
+        // RTI at 0x9000
+        cpu.write_byte(0x9000, 0x40);
+        cpu.write_word(IRQ_VECTOR, 0x9000);
+        cpu.reset();
+
+        assert_eq!(cpu.step(), 7);
+        assert_eq!(cpu.pc, 0x9000);
+        assert!(cpu.get_flag(StatusFlag::InterruptDisable));
+        cpu.step();
+        assert_eq!(cpu.pc, 0x8002);
+        cpu.step();
+        assert_eq!(cpu.x, 1);
+    }
+
+    #[test]
+    fn test_rotate_accumulator() {
+        // SEC; LDA #$80; ROL A
+        let cpu = run_instructions(vec![0x38, 0xA9, 0x80, 0x2A], 3);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.get_flag(StatusFlag::Carry));
+    }
+
+    #[test]
+    fn test_unofficial_dcp() {
+        // LDA #$41; DCP $10
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xA9, 0x41, 0xC7, 0x10], PROGRAM_ADDRESS);
+        cpu.write_byte(0x10, 0x42);
+        cpu.reset();
+        cpu.step();
+        assert_eq!(cpu.step(), 5);
+        assert_eq!(cpu.read_byte(0x10), 0x41);
+        assert!(cpu.get_flag(StatusFlag::Zero));
+    }
+
+    #[test]
+    fn test_decode_cache() {
+        // INX; JMP $8000
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xE8, 0x4C, 0x00, 0x80], PROGRAM_ADDRESS);
+        cpu.set_decode_cache(true);
+        cpu.reset();
+        cpu.step();
+        cpu.step();
+
+        // swapped in behind the CPU's back, the cached INX still runs
+        cpu.bus.memory[0x8000] = 0xC8;
+        cpu.step();
+        assert_eq!((cpu.x, cpu.y), (2, 0));
+
+        // RAM below $8000 can't change the code, the INX is still cached
+        cpu.step();
+        cpu.write_byte(0x6000, 0xFF);
+        cpu.step();
+        assert_eq!((cpu.x, cpu.y), (3, 0));
+
+        // a write at $8000 may switch banks, so the INY is decoded now
+        cpu.step();
+        cpu.write_byte(0x8000, 0xC8);
+        cpu.step();
+        assert_eq!((cpu.x, cpu.y), (3, 1));
+    }
+
+    #[test]
+    fn test_unknown_opcode_faults() {
+        // INX; $02; INY
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xE8, 0x02, 0xC8], PROGRAM_ADDRESS);
+        cpu.reset();
+        cpu.run();
+        assert_eq!(cpu.fault, Some(CpuFault::UnknownOpcode { pc: 0x8001, opcode: 0x02 }));
+        assert_eq!(cpu.fault.unwrap().to_string(), "unknown opcode $02 at $8001");
+
+        // stuck in place
+        let cycles = cpu.cycles;
+        assert_eq!(cpu.step(), 0);
+        assert_eq!((cpu.pc, cpu.cycles), (0x8001, cycles));
+
+        // patched to a NOP and retried
+        cpu.write_byte(0x8001, 0xEA);
+        cpu.fault = None;
+        cpu.step();
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.x, cpu.y), (0x8003, 1, 1));
+
+        // or skipped over
+        cpu.reset();
+        cpu.write_byte(0x8001, 0x02);
+        cpu.step();
+        cpu.step();
+        cpu.skip_faulted_instruction();
+        cpu.step();
+        assert_eq!((cpu.pc, cpu.fault), (0x8003, None));
+    }
+
+    #[test]
+    fn test_instruction_table() {
+        assert_eq!(INSTRUCTIONS.get(0xEA).map(|instruction| instruction.operation), Some(Operation::Nop));
+        assert!(INSTRUCTIONS.get(0x02).is_none());
+        assert!(INSTRUCTIONS.len() > 151);
+    }
+
+    // Records every bus access as (write, address, value), and how many cycles the
+    // bus had been ticked for when it happened
+    struct LogBus {
+        memory: FlatBus,
+        log: Vec<(bool, u16, u8)>,
+        cycles: u64,
+        access_cycles: Vec<u64>,
+        oam_dma: Option<u8>,
+    }
+
+    impl Bus for LogBus {
+        fn read(&mut self, address: u16) -> u8 {
+            let value = self.memory.read(address);
+            self.log.push((false, address, value));
+            self.access_cycles.push(self.cycles);
+            value
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.log.push((true, address, value));
+            self.access_cycles.push(self.cycles);
+            if address == 0x4014 {
+                self.oam_dma = Some(value);
+            }
+            self.memory.write(address, value);
+        }
+
+        fn peek(&self, address: u16) -> u8 {
+            self.memory.peek(address)
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.cycles += cycles as u64;
+        }
+
+        fn take_oam_dma(&mut self) -> Option<u8> {
+            self.oam_dma.take()
+        }
+    }
+
+    fn logged_cpu(program: Vec<u8>, x: u8) -> Cpu<LogBus> {
+        let bus = LogBus { memory: FlatBus::new(), log: Vec::new(), cycles: 0, access_cycles: Vec::new(), oam_dma: None };
+        let mut cpu = Cpu::with_bus(bus);
+        cpu.bus.memory.memory[0x8000..0x8000 + program.len()].copy_from_slice(&program);
+        cpu.write_word(RESET_VECTOR, 0x8000);
+        cpu.reset();
+        cpu.x = x;
+        cpu.bus.log.clear();
+        cpu.bus.access_cycles.clear();
+        cpu
+    }
+
+    fn logged_step(program: Vec<u8>, x: u8) -> Vec<(bool, u16, u8)> {
+        let mut cpu = logged_cpu(program, x);
+        cpu.step();
+        cpu.bus.log
+    }
+
+    #[test]
+    fn test_bus_ticked_per_access() {
+        // LDA $0200; INX
+        let mut cpu = logged_cpu(vec![0xAD, 0x00, 0x02, 0xE8], 0);
+        assert_eq!(cpu.step(), 4);
+        // each access comes after the cycles before it have run
+        assert_eq!((cpu.bus.access_cycles.as_slice(), cpu.bus.cycles), ([1, 2, 3, 4].as_slice(), 4));
+        // INX's second cycle doesn't touch the bus, it's ticked at the end
+        assert_eq!(cpu.step(), 2);
+        assert_eq!((cpu.bus.access_cycles[4], cpu.bus.cycles), (5, 6));
+
+        // a cached decode still spends the fetch's cycle
+        let mut cpu = logged_cpu(vec![0xE8, 0x4C, 0x00, 0x80], 0);
+        cpu.set_decode_cache(true);
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!((cpu.bus.cycles, cpu.cycles), (10, 10));
+        assert_eq!(cpu.bus.log.len(), 6);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        // STA $4014, then NOP; STA $4014
+        let mut cpu = logged_cpu(vec![0x8D, 0x14, 0x40, 0xEA, 0x8D, 0x14, 0x40], 0);
+        cpu.a = 0x03;
+        cpu.bus.memory.memory[0x0300..0x0400].fill(0x5A);
+        // on an even cycle it halts for one, then reads and writes 256 bytes
+        assert_eq!(cpu.step(), 4 + 513);
+        let copies = &cpu.bus.log[4..];
+        assert_eq!(copies.len(), 512);
+        assert_eq!((copies[0], copies[1]), ((false, 0x0300, 0x5A), (true, OAMDATA_ADDRESS, 0x5A)));
+        assert_eq!(copies[511], (true, OAMDATA_ADDRESS, 0x5A));
+        assert_eq!(cpu.bus.access_cycles[4], 4 + 2);
+        assert_eq!((cpu.cycles, cpu.bus.cycles), (517, 517));
+
+        // on an odd one it takes another to line up
+        cpu.step();
+        assert_eq!(cpu.step(), 4 + 514);
+        assert_eq!((cpu.cycles, cpu.bus.cycles), (517 + 2 + 518, 517 + 2 + 518));
+    }
+
+    #[test]
+    fn test_dummy_reads() {
+        // LDA $20F0,X only reads the wrong page when the index carries
+        assert_eq!(logged_step(vec![0xBD, 0xF0, 0x20], 0x01).len(), 4);
+        let log = logged_step(vec![0xBD, 0xF0, 0x20], 0x20);
+        assert_eq!(log[3], (false, 0x2010, 0));
+        assert_eq!(log[4].1, 0x2110);
+
+        // STA $20F0,X always does
+        let log = logged_step(vec![0x9D, 0xF0, 0x20], 0x01);
+        assert_eq!((log[3], log[4]), ((false, 0x20F1, 0), (true, 0x20F1, 0)));
+    }
+
+    #[test]
+    fn test_read_modify_write_dummy_write() {
+        // INC $10 writes the old value back before the new one
+        let log = logged_step(vec![0xE6, 0x10], 0);
+        assert_eq!(&log[2..], [(false, 0x10, 0), (true, 0x10, 0), (true, 0x10, 1)]);
+    }
+}
@@ -0,0 +1,690 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::cpu::{Bus, Cpu, CpuFault, Interrupt, Memory, INSTRUCTIONS};
+use crate::ppu::PpuBreakpoint;
+use crate::symbols::SymbolTable;
+use crate::trace::TraceFilter;
+use crate::watch::{WatchError, WatchList};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    Sp,
+    P,
+    Pc,
+}
+
+impl Register {
+    pub const ALL: [Register; 6] = [Register::A, Register::X, Register::Y, Register::Sp, Register::P, Register::Pc];
+
+    pub fn parse(name: &str) -> Option<Register> {
+        match name.to_ascii_uppercase().as_str() {
+            "A" => Some(Register::A),
+            "X" => Some(Register::X),
+            "Y" => Some(Register::Y),
+            "SP" => Some(Register::Sp),
+            "P" => Some(Register::P),
+            "PC" => Some(Register::Pc),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Register::A => "a",
+            Register::X => "x",
+            Register::Y => "y",
+            Register::Sp => "sp",
+            Register::P => "p",
+            Register::Pc => "pc",
+        }
+    }
+
+    pub fn value<B: Bus>(&self, cpu: &Cpu<B>) -> u16 {
+        match self {
+            Register::A => cpu.a as u16,
+            Register::X => cpu.x as u16,
+            Register::Y => cpu.y as u16,
+            Register::Sp => cpu.sp as u16,
+            Register::P => cpu.p.bits() as u16,
+            Register::Pc => cpu.pc,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+// A register comparison such as "A == $42"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+impl Condition {
+    // Parses "<register> <comparison> <value>", where the value is
+    // decimal or hexadecimal prefixed with $ or 0x
+    pub fn parse(expression: &str) -> Option<Condition> {
+        let mut parts = expression.split_whitespace();
+        let register = Register::parse(parts.next()?)?;
+        let comparison = match parts.next()? {
+            "==" => Comparison::Equal,
+            "!=" => Comparison::NotEqual,
+            "<" => Comparison::Less,
+            "<=" => Comparison::LessOrEqual,
+            ">" => Comparison::Greater,
+            ">=" => Comparison::GreaterOrEqual,
+            _ => return None,
+        };
+        let value = parse_number(parts.next()?)?;
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(Condition { register, comparison, value })
+    }
+
+    pub fn matches<B: Bus>(&self, cpu: &Cpu<B>) -> bool {
+        let register = self.register.value(cpu);
+        match self.comparison {
+            Comparison::Equal => register == self.value,
+            Comparison::NotEqual => register != self.value,
+            Comparison::Less => register < self.value,
+            Comparison::LessOrEqual => register <= self.value,
+            Comparison::Greater => register > self.value,
+            Comparison::GreaterOrEqual => register >= self.value,
+        }
+    }
+}
+
+pub fn parse_number(text: &str) -> Option<u16> {
+    if let Some(hex) = text.strip_prefix('$').or_else(|| text.strip_prefix("0x")) {
+        u16::from_str_radix(hex, 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// Where the debugger reads and writes bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum MemorySpace {
+    // as the CPU sees it, so writes to $8000-$FFFF go to the mapper's registers
+    #[default]
+    Cpu,
+    // pattern tables, nametables and palettes
+    Ppu,
+    // PRG ROM through the cartridge's patch overlay
+    Prg,
+}
+
+impl MemorySpace {
+    pub fn parse(name: &str) -> Option<MemorySpace> {
+        match name.to_ascii_lowercase().as_str() {
+            "cpu" => Some(MemorySpace::Cpu),
+            "ppu" => Some(MemorySpace::Ppu),
+            "prg" | "rom" => Some(MemorySpace::Prg),
+            _ => None,
+        }
+    }
+}
+
+// Reads without side effects. None when the bus has no such space.
+pub fn peek_memory<B: Bus>(cpu: &mut Cpu<B>, space: MemorySpace, address: u16) -> Option<u8> {
+    match space {
+        MemorySpace::Cpu | MemorySpace::Prg => Some(cpu.read_byte(address)),
+        MemorySpace::Ppu => cpu.bus.read_ppu(address),
+    }
+}
+
+// Writes bytes while execution is stopped. False when the bus has no such space or,
+// for PRG, the address isn't in ROM.
+pub fn poke_memory<B: Bus>(cpu: &mut Cpu<B>, space: MemorySpace, address: u16, data: &[u8]) -> bool {
+    let addresses = (0..data.len() as u16).map(|offset| address.wrapping_add(offset));
+    let written = match space {
+        MemorySpace::Cpu => {
+            addresses.zip(data).for_each(|(address, &value)| cpu.write_byte(address, value));
+            true
+        }
+        MemorySpace::Ppu => return addresses.zip(data).all(|(address, &value)| cpu.bus.write_ppu(address, value)),
+        MemorySpace::Prg => {
+            let patched = addresses.zip(data).all(|(address, &value)| cpu.bus.patch_prg(address, value));
+            cpu.invalidate_decode_cache();
+            patched
+        }
+    };
+    // patched over, the faulted instruction is retried
+    if cpu.fault.is_some() && cpu.pc.wrapping_sub(address) < data.len() as u16 {
+        cpu.fault = None;
+    }
+    written
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    fn overlaps(&self, reads: bool, writes: bool) -> Option<Access> {
+        match self {
+            Access::Read if reads => Some(Access::Read),
+            Access::Write if writes => Some(Access::Write),
+            Access::ReadWrite if writes => Some(Access::Write),
+            Access::ReadWrite if reads => Some(Access::Read),
+            _ => None,
+        }
+    }
+}
+
+pub struct Breakpoint {
+    pub address: u16,
+    // only break when the condition holds
+    pub condition: Option<Condition>,
+}
+
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakReason {
+    Breakpoint(u16),
+    Watchpoint { address: u16, access: Access },
+    Condition(Condition),
+    // stops after the instruction during which the PPU got there
+    Ppu(PpuBreakpoint),
+    // the CPU can't go on until the fault is cleared, see Cpu::skip_faulted_instruction
+    Fault(CpuFault),
+}
+
+impl fmt::Display for BreakReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BreakReason::Breakpoint(address) => write!(f, "breakpoint at ${:04X}", address),
+            BreakReason::Watchpoint { address, access } => write!(f, "{:?} watchpoint at ${:04X}", access, address),
+            BreakReason::Condition(condition) => write!(f, "condition {:?}", condition),
+            BreakReason::Ppu(breakpoint) => write!(f, "PPU {}", breakpoint),
+            BreakReason::Fault(fault) => write!(f, "{}", fault),
+        }
+    }
+}
+
+const JSR: u8 = 0x20;
+const RTS: u8 = 0x60;
+const RTI: u8 = 0x40;
+const BRK: u8 = 0x00;
+
+// An entry on the virtual call stack, pushed by JSR or an interrupt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackFrame {
+    // None for JSR
+    pub interrupt: Option<Interrupt>,
+    // the JSR or the instruction that was interrupted
+    pub call_site: u16,
+    pub target: u16,
+    // SP after the return address was pushed; the frame is gone once SP is back above it
+    pub stack_pointer: u8,
+}
+
+// Checked before every instruction. A break leaves the instruction unexecuted;
+// the next call to step resumes past it.
+#[derive(Default)]
+pub struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    pub watchpoints: Vec<Watchpoint>,
+    pub conditions: Vec<Condition>,
+    // handed to the PPU before each instruction, see PpuBreakpoint
+    pub ppu_breakpoints: Vec<PpuBreakpoint>,
+    // what the frontend's Tracer logs while debugging
+    pub trace_filters: Vec<TraceFilter>,
+    // names to show and accept instead of addresses
+    pub symbols: SymbolTable,
+    // shown in the debug window with their live values
+    pub watches: WatchList,
+    // innermost call last
+    pub call_stack: Vec<StackFrame>,
+    // PC of the last break, skipped once so execution can resume
+    suspended_at: Option<u16>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // "reset ($C000)" when the address has a symbol, "$C000" otherwise
+    pub fn describe(&self, address: u16) -> String {
+        match self.symbols.get(address) {
+            Some(name) => format!("{} (${:04X})", name, address),
+            None => format!("${:04X}", address),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16, condition: Option<Condition>) {
+        self.remove_breakpoint(address);
+        self.breakpoints.push(Breakpoint { address, condition });
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        let count = self.breakpoints.len();
+        self.breakpoints.retain(|breakpoint| breakpoint.address != address);
+        self.breakpoints.len() != count
+    }
+
+    // Adds an unconditional breakpoint, or removes the one already at the address.
+    // Returns whether a breakpoint is now set.
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.remove_breakpoint(address) {
+            false
+        } else {
+            self.add_breakpoint(address, None);
+            true
+        }
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.iter().any(|breakpoint| breakpoint.address == address)
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, access: Access) {
+        self.watchpoints.push(Watchpoint { range, access });
+    }
+
+    // The watch command, symbols resolve against the loaded symbol table
+    pub fn add_watch(&mut self, expression: &str) -> Result<(), WatchError> {
+        self.watches.add(expression, &self.symbols)
+    }
+
+    pub fn add_condition(&mut self, condition: Condition) {
+        self.conditions.push(condition);
+    }
+
+    pub fn add_ppu_breakpoint(&mut self, breakpoint: PpuBreakpoint) {
+        if !self.ppu_breakpoints.contains(&breakpoint) {
+            self.ppu_breakpoints.push(breakpoint);
+        }
+    }
+
+    pub fn remove_ppu_breakpoint(&mut self, breakpoint: PpuBreakpoint) -> bool {
+        let count = self.ppu_breakpoints.len();
+        self.ppu_breakpoints.retain(|&existing| existing != breakpoint);
+        self.ppu_breakpoints.len() != count
+    }
+
+    // Returns why execution should stop before the instruction at PC, if it should
+    pub fn check<B: Bus>(&self, cpu: &Cpu<B>) -> Option<BreakReason> {
+        let breakpoint = self.breakpoints.iter().find(|breakpoint| {
+            breakpoint.address == cpu.pc && breakpoint.condition.is_none_or(|condition| condition.matches(cpu))
+        });
+        if let Some(breakpoint) = breakpoint {
+            return Some(BreakReason::Breakpoint(breakpoint.address));
+        }
+
+        if let Some(condition) = self.conditions.iter().find(|condition| condition.matches(cpu)) {
+            return Some(BreakReason::Condition(*condition));
+        }
+
+        // watchpoints look at the operand of the instruction about to execute
+        let instruction = INSTRUCTIONS.get(cpu.read_byte(cpu.pc))?;
+        let (reads, writes) = (instruction.reads_memory(), instruction.writes_memory());
+        if !reads && !writes {
+            return None;
+        }
+        let (address, _) = cpu.operand_address_at(cpu.pc.wrapping_add(1), &instruction.addressing_mode);
+        self.watchpoints
+            .iter()
+            .filter(|watchpoint| watchpoint.range.contains(&address))
+            .find_map(|watchpoint| watchpoint.access.overlaps(reads, writes))
+            .map(|access| BreakReason::Watchpoint { address, access })
+    }
+
+    // Executes one instruction unless a break triggers first
+    pub fn step<B: Bus>(&mut self, cpu: &mut Cpu<B>) -> Option<BreakReason> {
+        if let Some(fault) = cpu.fault {
+            return Some(BreakReason::Fault(fault));
+        }
+        if self.suspended_at.take() != Some(cpu.pc) {
+            if let Some(reason) = self.check(cpu) {
+                self.suspended_at = Some(cpu.pc);
+                return Some(reason);
+            }
+        }
+        if let Some(ppu) = cpu.bus.ppu_mut() {
+            if ppu.breakpoints != self.ppu_breakpoints {
+                ppu.breakpoints.clone_from(&self.ppu_breakpoints);
+            }
+            ppu.breakpoint_hit = None;
+        }
+        let (pc, opcode, interrupt) = (cpu.pc, cpu.read_byte(cpu.pc), cpu.pending_interrupt());
+        cpu.step();
+        if let Some(fault) = cpu.fault {
+            // a breakpoint here already had its turn
+            self.suspended_at = Some(pc);
+            return Some(BreakReason::Fault(fault));
+        }
+        self.track_calls(cpu, pc, opcode, interrupt);
+        cpu.bus.ppu_mut().and_then(|ppu| ppu.breakpoint_hit.take()).map(BreakReason::Ppu)
+    }
+
+    fn track_calls<B: Bus>(&mut self, cpu: &Cpu<B>, pc: u16, opcode: u8, interrupt: Option<Interrupt>) {
+        let interrupt = match (interrupt, opcode) {
+            (Some(interrupt), _) => Some(interrupt),
+            (None, BRK) => Some(Interrupt::Brk),
+            (None, JSR) => None,
+            (None, RTS | RTI) => {
+                while self.call_stack.last().is_some_and(|frame| frame.stack_pointer < cpu.sp) {
+                    self.call_stack.pop();
+                }
+                return;
+            }
+            _ => return,
+        };
+        self.call_stack.push(StackFrame { interrupt, call_site: pc, target: cpu.pc, stack_pointer: cpu.sp });
+    }
+
+    // Outermost call first, e.g. "nmi ($C123) from $C005"
+    pub fn format_call_stack(&self) -> Vec<String> {
+        self.call_stack
+            .iter()
+            .map(|frame| {
+                let kind = match frame.interrupt {
+                    Some(interrupt) => format!("{:?} ", interrupt).to_uppercase(),
+                    None => String::new(),
+                };
+                format!("{}{} from {}", kind, self.describe(frame.target), self.describe(frame.call_site))
+            })
+            .collect()
+    }
+
+    // Runs until a break triggers or the instruction budget runs out
+    pub fn run<B: Bus>(&mut self, cpu: &mut Cpu<B>, max_instructions: usize) -> Option<BreakReason> {
+        (0..max_instructions).find_map(|_| self.step(cpu))
+    }
+
+    // Runs until the call stack is no deeper than `depth`
+    fn run_to_depth<B: Bus>(&mut self, cpu: &mut Cpu<B>, depth: usize, max_instructions: usize) -> Option<BreakReason> {
+        for _ in 0..max_instructions {
+            if let Some(reason) = self.step(cpu) {
+                return Some(reason);
+            }
+            if self.call_stack.len() <= depth {
+                break;
+            }
+        }
+        None
+    }
+
+    // Single-steps, but runs a JSR through to its return
+    pub fn step_over<B: Bus>(&mut self, cpu: &mut Cpu<B>, max_instructions: usize) -> Option<BreakReason> {
+        let depth = self.call_stack.len();
+        if cpu.pending_interrupt().is_none() && cpu.read_byte(cpu.pc) == JSR {
+            self.run_to_depth(cpu, depth, max_instructions)
+        } else {
+            self.step(cpu)
+        }
+    }
+
+    // Runs until the current subroutine or interrupt handler returns
+    pub fn step_out<B: Bus>(&mut self, cpu: &mut Cpu<B>, max_instructions: usize) -> Option<BreakReason> {
+        let depth = self.call_stack.len().saturating_sub(1);
+        self.run_to_depth(cpu, depth, max_instructions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LDA #$42; STA $10; LDX $10; INX
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xA9, 0x42, 0x85, 0x10, 0xA6, 0x10, 0xE8], 0x8000);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_parse_condition() {
+        assert_eq!(
+            Condition::parse("A == $42"),
+            Some(Condition { register: Register::A, comparison: Comparison::Equal, value: 0x42 })
+        );
+        assert_eq!(
+            Condition::parse("pc >= 0xC000"),
+            Some(Condition { register: Register::Pc, comparison: Comparison::GreaterOrEqual, value: 0xC000 })
+        );
+        assert_eq!(Condition::parse("Q == 1"), None);
+        assert_eq!(Condition::parse("A = 1"), None);
+        assert_eq!(Condition::parse("A == 1 2"), None);
+    }
+
+    #[test]
+    fn test_breakpoint_stops_before_instruction_and_resumes() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x8002, None);
+
+        assert_eq!(debugger.run(&mut cpu, 10), Some(BreakReason::Breakpoint(0x8002)));
+        assert_eq!(cpu.pc, 0x8002);
+        assert_eq!(cpu.read_byte(0x10), 0);
+
+        assert_eq!(debugger.step(&mut cpu), None);
+        assert_eq!(cpu.read_byte(0x10), 0x42);
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_breakpoint(0x8002, Condition::parse("A == $41"));
+        assert_eq!(debugger.run(&mut cpu, 4), None);
+    }
+
+    #[test]
+    fn test_condition_break() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_condition(Condition::parse("X == $42").unwrap());
+        assert!(matches!(debugger.run(&mut cpu, 10), Some(BreakReason::Condition(_))));
+        assert_eq!(cpu.pc, 0x8006);
+    }
+
+    #[test]
+    fn test_fault() {
+        let mut cpu = cpu();
+        cpu.write_byte(0x8004, 0x02);
+        let mut debugger = Debugger::new();
+        let fault = CpuFault::UnknownOpcode { pc: 0x8004, opcode: 0x02 };
+        assert_eq!(debugger.run(&mut cpu, 10), Some(BreakReason::Fault(fault)));
+        assert_eq!(debugger.run(&mut cpu, 10), Some(BreakReason::Fault(fault)));
+        assert_eq!(cpu.pc, 0x8004);
+
+        // patch in LDX $10 again and resume
+        cpu.write_byte(0x8004, 0xA6);
+        cpu.fault = None;
+        assert_eq!(debugger.run(&mut cpu, 2), None);
+        assert_eq!((cpu.pc, cpu.x), (0x8007, 0x43));
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x10..=0x1F, Access::Read);
+        assert_eq!(
+            debugger.run(&mut cpu, 10),
+            Some(BreakReason::Watchpoint { address: 0x10, access: Access::Read })
+        );
+        assert_eq!(cpu.pc, 0x8004);
+
+        let mut cpu = self::cpu();
+        let mut debugger = Debugger::new();
+        debugger.add_watchpoint(0x10..=0x10, Access::ReadWrite);
+        assert_eq!(
+            debugger.run(&mut cpu, 10),
+            Some(BreakReason::Watchpoint { address: 0x10, access: Access::Write })
+        );
+        assert_eq!(cpu.pc, 0x8002);
+    }
+
+    #[test]
+    fn test_toggle_breakpoint() {
+        let mut debugger = Debugger::new();
+        assert!(debugger.toggle_breakpoint(0x8000));
+        assert!(debugger.has_breakpoint(0x8000));
+        assert!(!debugger.toggle_breakpoint(0x8000));
+        assert!(!debugger.has_breakpoint(0x8000));
+    }
+
+    // JSR $8010; LDX #1; JMP $8005, with JSR $8020; RTS at 0x8010 and LDY #2; RTS at 0x8020
+    fn call_cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        let mut program = vec![0xEA; 0x30];
+        program[..8].copy_from_slice(&[0x20, 0x10, 0x80, 0xA2, 0x01, 0x4C, 0x05, 0x80]);
+        program[0x10..0x14].copy_from_slice(&[0x20, 0x20, 0x80, 0x60]);
+        program[0x20..0x23].copy_from_slice(&[0xA0, 0x02, 0x60]);
+        cpu.load_program(program, 0x8000);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let mut cpu = call_cpu();
+        let mut debugger = Debugger::new();
+        debugger.symbols = SymbolTable::parse_fceux("$8020#inner#\n");
+        debugger.run(&mut cpu, 2);
+        assert_eq!(cpu.pc, 0x8020);
+        assert_eq!(debugger.call_stack.len(), 2);
+        assert_eq!(debugger.call_stack[1], StackFrame { interrupt: None, call_site: 0x8010, target: 0x8020, stack_pointer: 0xF9 });
+        assert_eq!(debugger.format_call_stack(), ["$8010 from $8000", "inner ($8020) from $8010"]);
+        debugger.run(&mut cpu, 3);
+        assert_eq!(cpu.pc, 0x8003);
+        assert!(debugger.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_interrupt_frames() {
+        let mut cpu = call_cpu();
+        cpu.write_word(0xFFFA, 0x8020);
+        let mut debugger = Debugger::new();
+        cpu.set_nmi(true);
+        debugger.step(&mut cpu);
+        debugger.step(&mut cpu);
+        assert_eq!(cpu.pc, 0x8020);
+        assert_eq!(debugger.call_stack.last().unwrap().interrupt, Some(Interrupt::Nmi));
+        assert_eq!(debugger.format_call_stack().last().unwrap(), "NMI $8020 from $8010");
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        let mut cpu = call_cpu();
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut cpu, 100), None);
+        assert_eq!(cpu.pc, 0x8003);
+        assert_eq!(cpu.x, 0);
+        // not a JSR, so a single step
+        debugger.step_over(&mut cpu, 100);
+        assert_eq!(cpu.x, 1);
+
+        let mut cpu = call_cpu();
+        debugger.run(&mut cpu, 2);
+        assert_eq!(debugger.step_out(&mut cpu, 100), None);
+        assert_eq!(cpu.pc, 0x8013);
+        assert_eq!(cpu.y, 2);
+        // breakpoints still stop a step over
+        debugger.add_breakpoint(0x8020, None);
+        let mut cpu = call_cpu();
+        debugger.call_stack.clear();
+        assert_eq!(debugger.step_over(&mut cpu, 100), Some(BreakReason::Breakpoint(0x8020)));
+    }
+
+    #[test]
+    fn test_symbol_names() {
+        let mut debugger = Debugger::new();
+        debugger.symbols = SymbolTable::parse_fceux("$8000#reset#\n");
+        debugger.add_breakpoint(debugger.symbols.resolve("reset").unwrap(), None);
+        assert!(debugger.has_breakpoint(0x8000));
+        assert_eq!(debugger.describe(0x8000), "reset ($8000)");
+        assert_eq!(debugger.describe(0x8001), "$8001");
+    }
+
+    #[test]
+    fn test_ppu_breakpoints() {
+        use crate::rom::tests::nes_with_program;
+
+        // JMP $8000
+        let mut nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+        let cpu = nes.cpu_mut();
+
+        let mut debugger = Debugger::new();
+        let scanline = PpuBreakpoint::Dot { scanline: 100, dot: 0 };
+        debugger.add_ppu_breakpoint(scanline);
+        debugger.add_ppu_breakpoint(PpuBreakpoint::Vblank);
+        assert_eq!(debugger.run(cpu, 100_000), Some(BreakReason::Ppu(scanline)));
+        // stopped after the JMP that was running when the PPU got there
+        assert_eq!((cpu.bus.ppu.scanline, cpu.pc), (100, 0x8000));
+        assert!(cpu.bus.ppu.dot <= 9);
+
+        assert_eq!(debugger.run(cpu, 100_000), Some(BreakReason::Ppu(PpuBreakpoint::Vblank)));
+        assert_eq!(cpu.bus.ppu.scanline, 241);
+        assert_eq!(BreakReason::Ppu(PpuBreakpoint::Vblank).to_string(), "PPU vblank");
+
+        assert!(debugger.remove_ppu_breakpoint(PpuBreakpoint::Vblank));
+        assert!(debugger.remove_ppu_breakpoint(scanline));
+        assert_eq!(debugger.run(cpu, 10_000), None);
+        assert!(cpu.bus.ppu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn test_poke_memory() {
+        use crate::nes::Nes;
+        use crate::rom::tests::ines;
+        use crate::rom::Rom;
+
+        // JMP $8000, with CHR RAM
+        let mut image = ines(1, 0, 0, 0);
+        image[16..19].copy_from_slice(&[0x4C, 0x00, 0x80]);
+        image[16 + 0x3FFC..16 + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&image).unwrap()).unwrap();
+        let cpu = nes.cpu_mut();
+        cpu.set_decode_cache(true);
+        cpu.step();
+
+        // INX before the jump, in ROM
+        assert!(poke_memory(cpu, MemorySpace::Prg, 0x8000, &[0xE8, 0x4C, 0x00, 0x80]));
+        assert!(!poke_memory(cpu, MemorySpace::Prg, 0x6000, &[1]));
+        cpu.step();
+        assert_eq!((cpu.x, cpu.pc), (1, 0x8001));
+        assert_eq!(peek_memory(cpu, MemorySpace::Prg, 0x8000), Some(0xE8));
+
+        assert!(poke_memory(cpu, MemorySpace::Ppu, 0x0010, &[0x55]));
+        assert!(poke_memory(cpu, MemorySpace::Ppu, 0x3F10, &[0x21]));
+        assert_eq!(peek_memory(cpu, MemorySpace::Ppu, 0x0010), Some(0x55));
+        assert_eq!(peek_memory(cpu, MemorySpace::Ppu, 0x3F00), Some(0x21));
+        assert_eq!(MemorySpace::parse("ROM"), Some(MemorySpace::Prg));
+
+        cpu.clear_prg_patches();
+        assert_eq!(peek_memory(cpu, MemorySpace::Cpu, 0x8000), Some(0x4C));
+        // the JMP runs again, not the INX the decode cache saw
+        cpu.pc = 0x8000;
+        cpu.step();
+        assert_eq!((cpu.x, cpu.pc), (1, 0x8000));
+        assert_eq!(peek_memory(&mut Cpu::new(), MemorySpace::Ppu, 0), None);
+    }
+}
@@ -0,0 +1,489 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write};
+
+use crate::cpu::{AddressingMode, Memory, INSTRUCTIONS};
+use crate::instruction::{Instruction, Operation};
+use crate::rom::Rom;
+use crate::symbols::SymbolTable;
+
+const VECTORS: [(u16, &str); 3] = [(0xFFFA, "nmi"), (0xFFFC, "reset"), (0xFFFE, "irq")];
+// The most PRG ROM visible at once, without knowing the mapper
+const MAX_WINDOW: usize = 0x8000;
+const BYTES_PER_LINE: usize = 8;
+// Runs of the same byte at least this long are written as .res
+const MIN_FILL_RUN: usize = 16;
+
+// Where a Disassembler fetches instruction bytes from
+enum Source<'a> {
+    Memory(&'a dyn Memory),
+    // a window of ROM or RAM starting at base, with nothing outside it
+    Bytes { bytes: &'a [u8], base: u16 },
+}
+
+// Decodes single instructions without a CPU, so the ca65 output, the trace logger
+// and the remote debugger read code the same way
+pub struct Disassembler<'a> {
+    source: Source<'a>,
+}
+
+// One instruction as it sits in memory
+#[derive(Clone, Copy)]
+pub struct DecodedInstruction {
+    pub address: u16,
+    pub instruction: &'static Instruction,
+    // the opcode and operand, only the first instruction.bytes of them are used
+    pub bytes: [u8; 3],
+}
+
+impl DecodedInstruction {
+    pub fn length(&self) -> u16 {
+        self.instruction.bytes as u16
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.instruction.bytes as usize]
+    }
+
+    // The address after it, where a branch not taken goes
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.length())
+    }
+
+    // The one byte operand, or the low byte of a two byte one
+    pub fn operand_byte(&self) -> u8 {
+        self.bytes[1]
+    }
+
+    pub fn operand_word(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[1], self.bytes[2]])
+    }
+
+    // Where a branch, JMP or JSR goes, when that's known without running it
+    pub fn target(&self) -> Option<u16> {
+        match (self.instruction.operation, &self.instruction.addressing_mode) {
+            (_, AddressingMode::Relative) => Some(self.next().wrapping_add(self.operand_byte() as i8 as u16)),
+            (Operation::Jmp | Operation::Jsr, AddressingMode::Absolute) => Some(self.operand_word()),
+            _ => None,
+        }
+    }
+}
+
+// Plain 6502 syntax with branch targets resolved: "BNE $C010", "LDA ($10),Y"
+impl fmt::Display for DecodedInstruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (byte, word) = (self.operand_byte(), self.operand_word());
+        write!(f, "{}", self.instruction.mnemonic())?;
+        match self.instruction.addressing_mode {
+            AddressingMode::None | AddressingMode::Implied => Ok(()),
+            AddressingMode::Accumulator => write!(f, " A"),
+            AddressingMode::Immediate => write!(f, " #${:02X}", byte),
+            AddressingMode::ZeroPage => write!(f, " ${:02X}", byte),
+            AddressingMode::ZeroPageX => write!(f, " ${:02X},X", byte),
+            AddressingMode::ZeroPageY => write!(f, " ${:02X},Y", byte),
+            AddressingMode::Absolute => write!(f, " ${:04X}", word),
+            AddressingMode::AbsoluteX => write!(f, " ${:04X},X", word),
+            AddressingMode::AbsoluteY => write!(f, " ${:04X},Y", word),
+            AddressingMode::Indirect => write!(f, " (${:04X})", word),
+            AddressingMode::IndirectX => write!(f, " (${:02X},X)", byte),
+            AddressingMode::IndirectY => write!(f, " (${:02X}),Y", byte),
+            AddressingMode::Relative => write!(f, " ${:04X}", self.target().unwrap_or(0)),
+        }
+    }
+}
+
+impl<'a> Disassembler<'a> {
+    // Over a live address space, e.g. a Cpu, peeking so nothing has side effects
+    pub fn new(memory: &'a dyn Memory) -> Disassembler<'a> {
+        Disassembler { source: Source::Memory(memory) }
+    }
+
+    pub fn from_bytes(bytes: &'a [u8], base: u16) -> Disassembler<'a> {
+        Disassembler { source: Source::Bytes { bytes, base } }
+    }
+
+    pub fn byte(&self, address: u16) -> Option<u8> {
+        match self.source {
+            Source::Memory(memory) => Some(memory.read_byte(address)),
+            Source::Bytes { bytes, base } => bytes.get(address.checked_sub(base)? as usize).copied(),
+        }
+    }
+
+    // None for an opcode without an instruction, or one cut off by the end of the bytes
+    pub fn decode(&self, address: u16) -> Option<DecodedInstruction> {
+        let instruction = INSTRUCTIONS.get(self.byte(address)?)?;
+        let mut bytes = [0; 3];
+        for (offset, byte) in bytes.iter_mut().enumerate().take(instruction.bytes as usize) {
+            *byte = self.byte(address.wrapping_add(offset as u16))?;
+        }
+        Some(DecodedInstruction { address, instruction, bytes })
+    }
+
+    // A listing of count instructions from address, like "C000  4C F5 C5  JMP $C5F5".
+    // Bytes that don't decode are listed one at a time as data.
+    pub fn lines(&self, mut address: u16, count: usize) -> Vec<String> {
+        let mut lines = Vec::new();
+        while lines.len() < count {
+            let Some(byte) = self.byte(address) else {
+                break;
+            };
+            let Some(decoded) = self.decode(address) else {
+                lines.push(format!("{:04X}  {:02X}        .byte ${:02X}", address, byte, byte));
+                address = address.wrapping_add(1);
+                continue;
+            };
+            let bytes: Vec<String> = decoded.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+            let marker = if decoded.instruction.official { ' ' } else { '*' };
+            lines.push(format!("{:04X}  {:<8} {}{}", address, bytes.join(" "), marker, decoded));
+            address = decoded.next();
+        }
+        lines
+    }
+}
+
+// PRG ROM split into code, found by following control flow from the vectors, and data
+pub struct Disassembly {
+    pub base: u16,
+    pub bytes: Vec<u8>,
+    // addresses where an instruction starts
+    pub code: BTreeSet<u16>,
+    pub labels: BTreeMap<u16, String>,
+    // named addresses outside the ROM window, like RAM variables and registers
+    pub variables: BTreeMap<u16, String>,
+}
+
+
+impl Disassembly {
+    // The fixed bank of a cartridge: the last 32KB, or a 16KB image mirrored at 0xC000
+    pub fn from_rom(rom: &Rom) -> Disassembly {
+        let size = rom.prg_rom.len().min(MAX_WINDOW);
+        let bytes = &rom.prg_rom[rom.prg_rom.len() - size..];
+        Disassembly::new(bytes, (0x10000 - size) as u16)
+    }
+
+    pub fn new(bytes: &[u8], base: u16) -> Disassembly {
+        let mut disassembly = Disassembly {
+            base,
+            bytes: bytes.to_vec(),
+            code: BTreeSet::new(),
+            labels: BTreeMap::new(),
+            variables: BTreeMap::new(),
+        };
+        disassembly.analyze();
+        disassembly
+    }
+
+    fn end(&self) -> u32 {
+        self.base as u32 + self.bytes.len() as u32
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        address >= self.base && (address as u32) < self.end()
+    }
+
+    pub fn byte(&self, address: u16) -> Option<u8> {
+        self.bytes.get(address.checked_sub(self.base)? as usize).copied()
+    }
+
+    fn decoder(&self) -> Disassembler<'_> {
+        Disassembler::from_bytes(&self.bytes, self.base)
+    }
+
+    // Undocumented opcodes in a code path are far more likely to be data
+    fn decode(&self, address: u16) -> Option<DecodedInstruction> {
+        self.decoder().decode(address).filter(|decoded| decoded.instruction.official)
+    }
+
+    fn word(&self, address: u16) -> Option<u16> {
+        Some(u16::from_le_bytes([self.byte(address)?, self.byte(address.wrapping_add(1))?]))
+    }
+
+    pub fn is_code(&self, address: u16) -> bool {
+        self.code.contains(&address)
+    }
+
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    // Names from a symbol file replace the generated labels
+    pub fn apply_symbols(&mut self, symbols: &SymbolTable) {
+        for (&address, name) in &symbols.names {
+            if self.is_code(address) {
+                self.labels.insert(address, name.clone());
+            } else if !self.contains(address) {
+                self.variables.insert(address, name.clone());
+            }
+        }
+    }
+
+    fn name(&self, address: u16) -> Option<&str> {
+        self.label(address).or_else(|| self.variables.get(&address).map(String::as_str))
+    }
+
+    fn add_label(&mut self, address: u16, prefix: &str) {
+        self.labels.entry(address).or_insert_with(|| format!("{}_{:04X}", prefix, address));
+    }
+
+    // Walks every path from the vectors, stopping at returns, indirect jumps and
+    // anything that doesn't decode as an official instruction
+    fn analyze(&mut self) {
+        let mut pending = Vec::new();
+        for (vector, name) in VECTORS {
+            if let Some(target) = self.word(vector).filter(|&target| self.contains(target)) {
+                self.labels.entry(target).or_insert_with(|| name.to_string());
+                pending.push(target);
+            }
+        }
+        // bytes already claimed by an instruction, to stop at overlapping decodes
+        let mut covered = BTreeSet::new();
+
+        while let Some(mut pc) = pending.pop() {
+            loop {
+                if self.code.contains(&pc) || covered.contains(&pc) {
+                    break;
+                }
+                // also stops at an instruction cut off by the end of the window
+                let Some(decoded) = self.decode(pc) else {
+                    break;
+                };
+                self.code.insert(pc);
+                covered.extend((0..decoded.length()).map(|offset| pc.wrapping_add(offset)));
+
+                if let Some(target) = decoded.target().filter(|&target| self.contains(target)) {
+                    self.add_label(target, if decoded.instruction.operation == Operation::Jsr { "sub" } else { "loc" });
+                    pending.push(target);
+                }
+                if let Operation::Jmp | Operation::Rts | Operation::Rti | Operation::Brk = decoded.instruction.operation {
+                    break;
+                }
+                pc = decoded.next();
+            }
+        }
+        // a label that points into the middle of an instruction can't be emitted
+        let code = &self.code;
+        self.labels.retain(|address, _| code.contains(address));
+    }
+
+    fn operand(&self, decoded: &DecodedInstruction) -> String {
+        let (byte, word) = (decoded.operand_byte(), decoded.operand_word());
+        let address = |address: u16| {
+            let name = self.name(address).map_or_else(|| format!("${:04X}", address), str::to_string);
+            // ca65 would otherwise shrink it to zero page and change the size
+            if address < 0x100 {
+                format!("a:{}", name)
+            } else {
+                name
+            }
+        };
+        let zero_page = self.name(byte as u16).map_or_else(|| format!("${:02X}", byte), str::to_string);
+        match decoded.instruction.addressing_mode {
+            AddressingMode::None | AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => "A".to_string(),
+            AddressingMode::Immediate => format!("#${:02X}", byte),
+            AddressingMode::ZeroPage => zero_page,
+            AddressingMode::ZeroPageX => format!("{},X", zero_page),
+            AddressingMode::ZeroPageY => format!("{},Y", zero_page),
+            AddressingMode::Absolute => address(word),
+            AddressingMode::AbsoluteX => format!("{},X", address(word)),
+            AddressingMode::AbsoluteY => format!("{},Y", address(word)),
+            AddressingMode::Indirect => format!("({})", address(word)),
+            AddressingMode::IndirectX => format!("({},X)", zero_page),
+            AddressingMode::IndirectY => format!("({}),Y", zero_page),
+            AddressingMode::Relative => {
+                let target = decoded.target().unwrap_or(0);
+                self.label(target).map_or_else(|| format!("${:04X}", target), str::to_string)
+            }
+        }
+    }
+
+    fn write_data(&self, out: &mut String, start: u16, end: u32) {
+        let bytes = &self.bytes[(start - self.base) as usize..(end - self.base as u32) as usize];
+        let mut index = 0;
+        while index < bytes.len() {
+            let run = bytes[index..].iter().take_while(|&&byte| byte == bytes[index]).count();
+            if run >= MIN_FILL_RUN {
+                writeln!(out, "    .res {}, ${:02X}", run, bytes[index]).unwrap();
+                index += run;
+                continue;
+            }
+            // stop the line before the next long run
+            let line = &bytes[index..(index + BYTES_PER_LINE).min(bytes.len())];
+            let length = (1..line.len())
+                .find(|&offset| bytes[index + offset..].iter().take_while(|&&byte| byte == line[offset]).count() >= MIN_FILL_RUN)
+                .unwrap_or(line.len());
+            let values: Vec<String> = line[..length].iter().map(|byte| format!("${:02X}", byte)).collect();
+            writeln!(out, "    .byte {}", values.join(", ")).unwrap();
+            index += length;
+        }
+    }
+
+    // ca65 source that assembles back to the same bytes
+    pub fn to_ca65(&self) -> String {
+        let mut out = String::new();
+        for (address, name) in &self.variables {
+            writeln!(out, "{} = ${:04X}", name, address).unwrap();
+        }
+        writeln!(out, ".segment \"CODE\"").unwrap();
+        writeln!(out, ".org ${:04X}", self.base).unwrap();
+        let vectors = VECTORS[0].0 as u32;
+        let vectors_are_data = self.end() == 0x10000 && !self.code.range(vectors as u16..).any(|_| true);
+        let data_end = if vectors_are_data { vectors } else { self.end() };
+
+        let mut address = self.base as u32;
+        while address < data_end {
+            let pc = address as u16;
+            if self.is_code(pc) {
+                if let Some(label) = self.label(pc) {
+                    writeln!(out, "{}:", label).unwrap();
+                }
+                let decoded = self.decode(pc).unwrap();
+                let operand = self.operand(&decoded);
+                if operand.is_empty() {
+                    writeln!(out, "    {}", decoded.instruction.mnemonic()).unwrap();
+                } else {
+                    writeln!(out, "    {} {}", decoded.instruction.mnemonic(), operand).unwrap();
+                }
+                address += decoded.length() as u32;
+            } else {
+                let next = self.code.range(pc..).next().map_or(data_end, |&next| next as u32).min(data_end);
+                self.write_data(&mut out, pc, next);
+                address = next;
+            }
+        }
+
+        if vectors_are_data {
+            let names: Vec<String> = VECTORS
+                .iter()
+                .map(|&(vector, _)| {
+                    let target = self.word(vector).unwrap_or(0);
+                    self.label(target).map_or_else(|| format!("${:04X}", target), str::to_string)
+                })
+                .collect();
+            writeln!(out, "    .word {}", names.join(", ")).unwrap();
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // reset at 0xC000 calls a subroutine in a loop, with a data byte between the
+    // loop and the subroutine. NMI and IRQ share an RTI.
+    fn program() -> Vec<u8> {
+        let mut bytes = vec![0; 0x4000];
+        bytes[..12].copy_from_slice(&[
+            0x78, // SEI
+            0xA2, 0xFF, // LDX #$FF
+            0x20, 0x0A, 0xC0, // JSR $C00A
+            0x4C, 0x03, 0xC0, // JMP $C003
+            0xFF, // data
+            0x60, // RTS
+            0x40, // RTI
+        ]);
+        bytes[0x3FFA..].copy_from_slice(&[0x0B, 0xC0, 0x00, 0xC0, 0x0B, 0xC0]);
+        bytes
+    }
+
+    #[test]
+    fn test_follows_control_flow() {
+        let disassembly = Disassembly::new(&program(), 0xC000);
+        let code: Vec<u16> = disassembly.code.iter().copied().collect();
+        assert_eq!(code, [0xC000, 0xC001, 0xC003, 0xC006, 0xC00A, 0xC00B]);
+        assert!(!disassembly.is_code(0xC009));
+        assert_eq!(disassembly.label(0xC000), Some("reset"));
+        assert_eq!(disassembly.label(0xC003), Some("loc_C003"));
+        assert_eq!(disassembly.label(0xC00A), Some("sub_C00A"));
+        assert_eq!(disassembly.label(0xC00B), Some("nmi"));
+    }
+
+    #[test]
+    fn test_ca65_output() {
+        let source = Disassembly::new(&program(), 0xC000).to_ca65();
+        let lines: Vec<&str> = source.lines().collect();
+        assert_eq!(
+            lines[..14],
+            [
+                ".segment \"CODE\"",
+                ".org $C000",
+                "reset:",
+                "    SEI",
+                "    LDX #$FF",
+                "loc_C003:",
+                "    JSR sub_C00A",
+                "    JMP loc_C003",
+                "    .byte $FF",
+                "sub_C00A:",
+                "    RTS",
+                "nmi:",
+                "    RTI",
+                "    .res 16366, $00",
+            ]
+        );
+        assert_eq!(lines.last(), Some(&"    .word nmi, reset, nmi"));
+    }
+
+    #[test]
+    fn test_branches_and_zero_page_absolute() {
+        let mut bytes = program();
+        bytes[..9].copy_from_slice(&[
+            0xAD, 0x10, 0x00, // LDA $0010, absolute
+            0xD0, 0xFB, // BNE $C000
+            0x4C, 0x00, 0xC0, // JMP $C000
+            0x02, // not an official opcode
+        ]);
+        let source = Disassembly::new(&bytes, 0xC000).to_ca65();
+        assert!(source.contains("reset:\n    LDA a:$0010\n    BNE reset\n    JMP reset\n    .byte $02"));
+    }
+
+    #[test]
+    fn test_disassembler() {
+        let bytes = [
+            0xA9, 0x05, // LDA #$05
+            0xD0, 0xFC, // BNE $C000
+            0x20, 0x00, 0xD0, // JSR $D000
+            0x02, // KIL
+            0xB1, 0x10, // LDA ($10),Y
+            0x6C, // JMP ( cut off
+        ];
+        let decoder = Disassembler::from_bytes(&bytes, 0xC000);
+        let branch = decoder.decode(0xC002).unwrap();
+        assert_eq!((branch.target(), branch.to_string()), (Some(0xC000), "BNE $C000".to_string()));
+        assert_eq!(decoder.decode(0xC004).unwrap().target(), Some(0xD000));
+        assert_eq!(decoder.decode(0xC000).unwrap().target(), None);
+        assert!(decoder.decode(0xC00A).is_none());
+        assert_eq!(
+            decoder.lines(0xC000, 10),
+            [
+                "C000  A9 05     LDA #$05",
+                "C002  D0 FC     BNE $C000",
+                "C004  20 00 D0  JSR $D000",
+                "C007  02        .byte $02",
+                "C008  B1 10     LDA ($10),Y",
+                "C00A  6C        .byte $6C",
+            ]
+        );
+
+        // the same over a live address space
+        let mut cpu = crate::cpu::Cpu::new();
+        cpu.load_program(bytes.to_vec(), 0xC000);
+        assert_eq!(Disassembler::new(&cpu).lines(0xC002, 2), decoder.lines(0xC002, 2));
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut bytes = program();
+        bytes[1..3].copy_from_slice(&[0xA5, 0x10]); // LDA $10
+        let mut disassembly = Disassembly::new(&bytes, 0xC000);
+        let symbols = SymbolTable::parse_fceux("$C00A#update#\n$0010#frame#\n$C009#table#\n");
+        disassembly.apply_symbols(&symbols);
+        let source = disassembly.to_ca65();
+        assert!(source.starts_with("frame = $0010\n"));
+        assert!(source.contains("    LDA frame\n"));
+        assert!(source.contains("update:\n    RTS"));
+        assert!(source.contains("    JSR update\n"));
+        // not an instruction, so there is nowhere to put the label
+        assert!(!source.contains("table"));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::mapper::{Mapper, PRG_RAM_SIZE};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+const PRG_BANK_SIZE: usize = 0x8000;
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Boards built from a latch and a few logic chips: one register picks a 32KB PRG bank
+// and maybe the CHR banks, mirroring is soldered
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiscreteBoard {
+    // mapper 66, PRG in bits 4-5 and 8KB CHR in bits 0-1
+    Gxrom,
+    // mapper 11, PRG in bits 0-1 and 8KB CHR in bits 4-7
+    ColorDreams,
+    // mapper 34 with CHR RAM, just the PRG bank
+    Bnrom,
+    // mapper 34 with CHR ROM, registers at $7FFD-$7FFF over the PRG RAM
+    Nina001,
+}
+
+impl DiscreteBoard {
+    // Mapper 34 is two unrelated boards; NES 2.0 says which, otherwise CHR ROM means NINA
+    fn for_rom(rom: &Rom) -> DiscreteBoard {
+        match (rom.mapper, rom.submapper) {
+            (11, _) => DiscreteBoard::ColorDreams,
+            (66, _) => DiscreteBoard::Gxrom,
+            (_, 1) => DiscreteBoard::Nina001,
+            (_, 2) => DiscreteBoard::Bnrom,
+            _ if rom.chr_rom.len() > 2 * CHR_BANK_SIZE => DiscreteBoard::Nina001,
+            _ => DiscreteBoard::Bnrom,
+        }
+    }
+
+    // The latch is driven by the ROM too, so a write only keeps the bits both agree on
+    fn has_bus_conflicts(self) -> bool {
+        matches!(self, DiscreteBoard::Gxrom | DiscreteBoard::Bnrom)
+    }
+}
+
+// Mappers 11, 34 and 66
+pub struct Discrete {
+    pub board: DiscreteBoard,
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; PRG_RAM_SIZE],
+    pub chr: Vec<u8>,
+    pub chr_writable: bool,
+    pub prg_bank: u8,
+    // 4KB banks for each pattern table half; 8KB boards switch both together
+    pub chr_banks: [u8; 2],
+    pub mirroring: Mirroring,
+}
+
+impl Discrete {
+    pub fn new(rom: &Rom) -> Self {
+        Discrete {
+            board: DiscreteBoard::for_rom(rom),
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            chr_writable: !rom.chr_ram.is_empty(),
+            prg_bank: 0,
+            chr_banks: [0, 1],
+            mirroring: rom.mirroring,
+        }
+    }
+
+    fn set_chr_8k(&mut self, bank: u8) {
+        self.chr_banks = [bank * 2, bank * 2 + 1];
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let half = (address as usize >> 12) & 0x01;
+        (self.chr_banks[half] as usize * CHR_BANK_SIZE + (address as usize & 0x0FFF)) % self.chr.len()
+    }
+}
+
+impl Mapper for Discrete {
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                let offset = self.prg_bank as usize * PRG_BANK_SIZE + (address as usize - 0x8000);
+                self.prg_rom[offset % self.prg_rom.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7FFF = address {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+        let value = if self.board.has_bus_conflicts() { value & self.peek_prg(address) } else { value };
+        match (self.board, address) {
+            (DiscreteBoard::Gxrom, 0x8000..=0xFFFF) => {
+                self.prg_bank = (value >> 4) & 0x03;
+                self.set_chr_8k(value & 0x03);
+            }
+            (DiscreteBoard::ColorDreams, 0x8000..=0xFFFF) => {
+                self.prg_bank = value & 0x03;
+                self.set_chr_8k(value >> 4);
+            }
+            (DiscreteBoard::Bnrom, 0x8000..=0xFFFF) => self.prg_bank = value,
+            (DiscreteBoard::Nina001, 0x7FFD) => self.prg_bank = value & 0x01,
+            (DiscreteBoard::Nina001, 0x7FFE) => self.chr_banks[0] = value & 0x0F,
+            (DiscreteBoard::Nina001, 0x7FFF) => self.chr_banks[1] = value & 0x0F,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // NINA-001's bank registers sit at the top of PRG RAM
+    fn changes_prg(&self, address: u16) -> bool {
+        match self.board {
+            DiscreteBoard::Nina001 => address >= 0x7FFD,
+            _ => address >= 0x8000,
+        }
+    }
+}
+
+impl SaveState for Discrete {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        out.extend_from_slice(&[self.prg_bank, self.chr_banks[0], self.chr_banks[1]]);
+        if self.chr_writable {
+            out.extend_from_slice(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        self.prg_bank = input.read_u8()?;
+        self.chr_banks = [input.read_u8()?, input.read_u8()?];
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr.copy_from_slice(input.read_bytes(size)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    // 32KB PRG banks and 4KB CHR banks, each filled with its own number
+    fn cartridge(mapper: u8, prg_banks: u8, chr_8k_banks: u8) -> Discrete {
+        let mut data = ines(prg_banks * 2, chr_8k_banks, (mapper & 0x0F) << 4, mapper & 0xF0);
+        let chr_start = 16 + prg_banks as usize * PRG_BANK_SIZE;
+        for (bank, chunk) in data[16..chr_start].chunks_mut(PRG_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        for (bank, chunk) in data[chr_start..].chunks_mut(CHR_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        Discrete::new(&Rom::new(&data).unwrap())
+    }
+
+    #[test]
+    fn test_gxrom() {
+        let mut gxrom = cartridge(66, 4, 4);
+        assert_eq!(gxrom.board, DiscreteBoard::Gxrom);
+        // the ROM byte under the write is 0, so bus conflicts eat every bit
+        gxrom.write_prg(0x8000, 0x32);
+        assert_eq!(gxrom.prg_bank, 0);
+
+        gxrom.prg_rom[0x7FFF] = 0xFF;
+        gxrom.write_prg(0xFFFF, 0x32);
+        assert_eq!((gxrom.peek_prg(0x8000), gxrom.read_chr(0x0000), gxrom.read_chr(0x1000)), (3, 4, 5));
+    }
+
+    #[test]
+    fn test_color_dreams() {
+        let mut color_dreams = cartridge(11, 4, 4);
+        color_dreams.write_prg(0x8000, 0x21);
+        assert_eq!((color_dreams.peek_prg(0xC000), color_dreams.read_chr(0x1000)), (1, 5));
+    }
+
+    #[test]
+    fn test_bnrom_chr_ram() {
+        let mut bnrom = cartridge(34, 4, 0);
+        assert_eq!(bnrom.board, DiscreteBoard::Bnrom);
+        bnrom.prg_rom[0x4000] = 0xFF;
+        bnrom.write_prg(0xC000, 2);
+        assert_eq!(bnrom.peek_prg(0x8000), 2);
+        bnrom.write_chr(0x1234, 0x42);
+        assert_eq!(bnrom.read_chr(0x1234), 0x42);
+
+        let mut state = Vec::new();
+        bnrom.save_state(&mut state);
+        let mut restored = cartridge(34, 4, 0);
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!((restored.prg_bank, restored.read_chr(0x1234)), (2, 0x42));
+    }
+
+    #[test]
+    fn test_nina001() {
+        let mut nina = cartridge(34, 2, 4);
+        assert_eq!(nina.board, DiscreteBoard::Nina001);
+        nina.write_prg(0x7FFD, 1);
+        nina.write_prg(0x7FFE, 3);
+        nina.write_prg(0x7FFF, 6);
+        assert_eq!((nina.peek_prg(0x8000), nina.read_chr(0x0000), nina.read_chr(0x1000)), (1, 3, 6));
+        // the registers sit on top of PRG RAM, which keeps the byte too
+        assert_eq!(nina.peek_prg(0x7FFF), 6);
+        assert!(nina.changes_prg(0x7FFD) && !nina.changes_prg(0x7FFC));
+    }
+}
@@ -0,0 +1,921 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bitflags::bitflags;
+
+use crate::config::Config;
+use crate::mapper::Mapper;
+use crate::rom::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader};
+
+// fwNES header: "FDS" followed by MS-DOS end-of-file, then the number of sides
+const FDS_TAG: [u8; 4] = [0x46, 0x44, 0x53, 0x1A];
+const FDS_HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+// Block 1, the disk info block, starts every side
+const DISK_VERIFICATION: &[u8] = b"\x01*NINTENDO-HVC*";
+const GAME_NAME_OFFSET: usize = 16;
+pub const BIOS_SIZE: usize = 0x2000;
+pub const BIOS_FILE_NAME: &str = "disksys.rom";
+// $6000-$DFFF
+const RAM_SIZE: usize = 0x8000;
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// The drive reads a byte about every 150 CPU cycles. The .fds format leaves out
+// the gaps between blocks and the CRCs, which are put back for the drive to read.
+const BYTE_CYCLES: u32 = 149;
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+// Time the head takes to return to the start of the disk
+const HEAD_RETURN_CYCLES: u32 = 50000;
+// A disk has to be out for a while before the BIOS notices the side changed
+pub const SIDE_SWITCH_CYCLES: u32 = 1_789_773;
+
+#[derive(Debug)]
+pub enum FdsError {
+    Io(io::Error),
+    InvalidImage,
+    NoBios,
+    InvalidBios(usize),
+}
+
+impl fmt::Display for FdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdsError::Io(error) => write!(f, "Could not read disk: {}", error),
+            FdsError::InvalidImage => write!(f, "File is not a Famicom Disk System image"),
+            FdsError::NoBios => write!(f, "The FDS BIOS ({}) was not found", BIOS_FILE_NAME),
+            FdsError::InvalidBios(size) => write!(f, "FDS BIOS should be {} bytes, not {}", BIOS_SIZE, size),
+        }
+    }
+}
+
+impl From<io::Error> for FdsError {
+    fn from(error: io::Error) -> Self {
+        FdsError::Io(error)
+    }
+}
+
+// A .fds disk image, with or without the fwNES header
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdsImage {
+    pub sides: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    pub fn new(data: &[u8]) -> Result<FdsImage, FdsError> {
+        let data = if data.starts_with(&FDS_TAG) { &data[FDS_HEADER_SIZE.min(data.len())..] } else { data };
+        if data.is_empty() || !data.len().is_multiple_of(SIDE_SIZE) {
+            return Err(FdsError::InvalidImage);
+        }
+        let sides: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(<[u8]>::to_vec).collect();
+        if !sides.iter().all(|side| side.starts_with(DISK_VERIFICATION)) {
+            return Err(FdsError::InvalidImage);
+        }
+        Ok(FdsImage { sides })
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<FdsImage, FdsError> {
+        FdsImage::new(&fs::read(path)?)
+    }
+
+    pub fn is_fds_file(path: &Path) -> bool {
+        path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("fds"))
+    }
+
+    // The three letter game code from the disk info block, e.g. "ZEL"
+    pub fn game_name(&self) -> String {
+        let name = &self.sides[0][GAME_NAME_OFFSET..GAME_NAME_OFFSET + 3];
+        String::from_utf8_lossy(name).trim().to_string()
+    }
+}
+
+// disksys.rom in the config directory, unless the config names another file
+pub fn bios_path(config: &Config) -> Option<PathBuf> {
+    config.fds_bios.clone().or_else(|| Some(Config::directory()?.join(BIOS_FILE_NAME)))
+}
+
+pub fn load_bios(path: &Path) -> Result<Vec<u8>, FdsError> {
+    let bios = fs::read(path).map_err(|error| match error.kind() {
+        io::ErrorKind::NotFound => FdsError::NoBios,
+        _ => FdsError::Io(error),
+    })?;
+    if bios.len() != BIOS_SIZE {
+        return Err(FdsError::InvalidBios(bios.len()));
+    }
+    Ok(bios)
+}
+
+// Lays a side out the way the drive sees it: a lead-in, then each block behind a
+// start mark and followed by its CRC and a gap. The CRC is never checked, the
+// drive reports every block as good, so zeros stand in for it.
+fn raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut position = 0;
+    let mut file_size = 0;
+    while position < side.len() {
+        let length = match side[position] {
+            1 => 56,
+            2 => 2,
+            3 if position + 15 < side.len() => {
+                file_size = u16::from_le_bytes([side[position + 13], side[position + 14]]) as usize;
+                16
+            }
+            4 => 1 + file_size,
+            _ => break,
+        };
+        let block = &side[position..(position + length).min(side.len())];
+        raw.push(BLOCK_START);
+        raw.extend_from_slice(block);
+        raw.extend_from_slice(&[0, 0]);
+        raw.resize(raw.len() + BLOCK_GAP, 0);
+        position += length;
+    }
+    raw.resize(raw.len().max(SIDE_SIZE + LEAD_IN_GAP), 0);
+    raw
+}
+
+bitflags! {
+    // $4025
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct DriveControl: u8 {
+        const MotorOn = 1 << 0;
+        // holds the head at the start of the disk
+        const TransferReset = 1 << 1;
+        // writes when clear
+        const ReadMode = 1 << 2;
+        const HorizontalMirroring = 1 << 3;
+        const CrcControl = 1 << 4;
+        // set once the BIOS is past the gap and wants data
+        const ReadWriteStart = 1 << 6;
+        const IrqEnabled = 1 << 7;
+    }
+}
+
+// The RAM adapter, which plugs into the cartridge slot: 32KB of PRG RAM, 8KB of
+// CHR RAM, the BIOS, an IRQ timer, the disk drive interface and a sound channel.
+pub struct Fds {
+    pub bios: Vec<u8>,
+    pub ram: Vec<u8>,
+    pub chr_ram: Vec<u8>,
+    // each side as the drive reads it, see raw_side()
+    pub sides: Vec<Vec<u8>>,
+    // None while the disk is out
+    pub side: Option<usize>,
+    pub disk_io_enabled: bool,
+    pub sound_io_enabled: bool,
+    pub timer_reload: u16,
+    pub timer_counter: u16,
+    pub timer_repeat: bool,
+    pub timer_enabled: bool,
+    pub timer_irq: bool,
+    pub control: DriveControl,
+    pub motor_on: bool,
+    pub read_data: u8,
+    pub write_data: u8,
+    pub transfer_complete: bool,
+    pub disk_irq: bool,
+    pub end_of_head: bool,
+    pub scanning: bool,
+    pub gap_ended: bool,
+    pub head_position: usize,
+    pub delay: u32,
+    // side going in once the switch delay is over
+    pub next_side: Option<usize>,
+    pub switch_delay: u32,
+    pub audio: FdsAudio,
+}
+
+impl Fds {
+    pub fn new(bios: Vec<u8>, disk: &FdsImage) -> Self {
+        Fds {
+            bios,
+            ram: vec![0; RAM_SIZE],
+            chr_ram: vec![0; CHR_RAM_SIZE],
+            sides: disk.sides.iter().map(|side| raw_side(side)).collect(),
+            side: Some(0),
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            control: DriveControl::empty(),
+            motor_on: false,
+            read_data: 0,
+            write_data: 0,
+            transfer_complete: false,
+            disk_irq: false,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            head_position: 0,
+            delay: 0,
+            next_side: None,
+            switch_delay: 0,
+            audio: FdsAudio::new(),
+        }
+    }
+
+    fn status(&self) -> u8 {
+        self.timer_irq as u8 | (self.transfer_complete as u8) << 1 | (self.end_of_head as u8) << 6
+    }
+
+    fn drive_status(&self) -> u8 {
+        let inserted = self.side.is_some();
+        0x40 | !inserted as u8 | ((!inserted || !self.scanning) as u8) << 1 | (!inserted as u8) << 2
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            self.timer_enabled = self.timer_repeat;
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    // One CPU cycle of the drive, modeled on the byte transfer the BIOS expects
+    fn clock_drive(&mut self) {
+        let Some(side) = self.side.filter(|_| self.motor_on) else {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        };
+        if self.control.contains(DriveControl::TransferReset) && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            self.delay = HEAD_RETURN_CYCLES;
+            self.end_of_head = false;
+            self.head_position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut irq = self.control.contains(DriveControl::IrqEnabled);
+        let start = self.control.contains(DriveControl::ReadWriteStart);
+        let disk = &mut self.sides[side];
+        let data = disk[self.head_position];
+        if self.control.contains(DriveControl::ReadMode) {
+            if !start {
+                self.gap_ended = false;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark is latched without an interrupt
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                self.disk_irq |= irq;
+            }
+        } else {
+            let mut data = 0;
+            if !self.control.contains(DriveControl::CrcControl) {
+                self.transfer_complete = true;
+                data = self.write_data;
+                self.disk_irq |= irq;
+            }
+            disk[self.head_position] = if start { data } else { 0 };
+            self.gap_ended = false;
+        }
+
+        self.head_position += 1;
+        if self.head_position >= disk.len() {
+            self.motor_on = false;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+    fn clock_side_switch(&mut self) {
+        if self.next_side.is_some() {
+            self.switch_delay = self.switch_delay.saturating_sub(1);
+            if self.switch_delay == 0 {
+                self.side = self.next_side.take();
+            }
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn read_prg(&mut self, address: u16) -> u8 {
+        let value = self.peek_prg(address);
+        match address {
+            0x4030 => {
+                self.timer_irq = false;
+                self.disk_irq = false;
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+        value
+    }
+
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x4030 if self.disk_io_enabled => self.status(),
+            0x4031 if self.disk_io_enabled => self.read_data,
+            0x4032 if self.disk_io_enabled => self.drive_status(),
+            // the expansion port; bit 7 is the battery being good
+            0x4033 if self.disk_io_enabled => 0x80,
+            0x4040..=0x4097 if self.sound_io_enabled => self.audio.read(address),
+            0x6000..=0xDFFF => self.ram[address as usize - 0x6000],
+            0xE000..=0xFFFF => self.bios[address as usize - 0xE000],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x4020 => self.timer_reload = (self.timer_reload & 0xFF00) | value as u16,
+            0x4021 => self.timer_reload = (self.timer_reload & 0x00FF) | (value as u16) << 8,
+            0x4022 if self.disk_io_enabled => {
+                self.timer_repeat = value & 0x01 != 0;
+                self.timer_enabled = value & 0x02 != 0;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = value & 0x01 != 0;
+                self.sound_io_enabled = value & 0x02 != 0;
+                if !self.disk_io_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                }
+            }
+            0x4024 if self.disk_io_enabled => {
+                self.write_data = value;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_io_enabled => {
+                self.control = DriveControl::from_bits_truncate(value);
+                self.motor_on = self.control.contains(DriveControl::MotorOn);
+                self.disk_irq = false;
+            }
+            0x4040..=0x4097 if self.sound_io_enabled => self.audio.write(address, value),
+            0x6000..=0xDFFF => self.ram[address as usize - 0x6000] = value,
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        self.chr_ram[address as usize % CHR_RAM_SIZE]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        self.chr_ram[address as usize % CHR_RAM_SIZE] = value;
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.control.contains(DriveControl::HorizontalMirroring) {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_drive();
+            self.clock_side_switch();
+            self.audio.clock();
+        }
+    }
+
+    fn switch_disk_side(&mut self) -> Option<usize> {
+        let current = self.side.or(self.next_side).unwrap_or(0);
+        let next = (current + 1) % self.sides.len();
+        self.side = None;
+        self.next_side = Some(next);
+        self.switch_delay = SIDE_SWITCH_CYCLES;
+        Some(next)
+    }
+}
+
+impl SaveState for Fds {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ram);
+        out.extend_from_slice(&self.chr_ram);
+        // writes land on the disk, so it's part of the state
+        for side in &self.sides {
+            out.extend_from_slice(side);
+        }
+        out.extend_from_slice(&[
+            self.side.map_or(0xFF, |side| side as u8),
+            self.next_side.map_or(0xFF, |side| side as u8),
+            self.disk_io_enabled as u8,
+            self.sound_io_enabled as u8,
+            self.timer_repeat as u8,
+            self.timer_enabled as u8,
+            self.timer_irq as u8,
+            self.control.bits(),
+            self.motor_on as u8,
+            self.read_data,
+            self.write_data,
+            self.transfer_complete as u8,
+            self.disk_irq as u8,
+            self.end_of_head as u8,
+            self.scanning as u8,
+            self.gap_ended as u8,
+        ]);
+        out.extend_from_slice(&self.timer_reload.to_le_bytes());
+        out.extend_from_slice(&self.timer_counter.to_le_bytes());
+        out.extend_from_slice(&(self.head_position as u64).to_le_bytes());
+        out.extend_from_slice(&(self.delay as u64).to_le_bytes());
+        out.extend_from_slice(&(self.switch_delay as u64).to_le_bytes());
+        self.audio.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let sides = self.sides.len();
+        let side = |value: u8| match value {
+            0xFF => Ok(None),
+            side if (side as usize) < sides => Ok(Some(side as usize)),
+            _ => Err(StateError::Invalid),
+        };
+        self.ram.copy_from_slice(input.read_bytes(RAM_SIZE)?);
+        self.chr_ram.copy_from_slice(input.read_bytes(CHR_RAM_SIZE)?);
+        for disk in &mut self.sides {
+            let size = disk.len();
+            disk.copy_from_slice(input.read_bytes(size)?);
+        }
+        let flags = input.read_bytes(16)?;
+        self.side = side(flags[0])?;
+        self.next_side = side(flags[1])?;
+        self.disk_io_enabled = flags[2] != 0;
+        self.sound_io_enabled = flags[3] != 0;
+        self.timer_repeat = flags[4] != 0;
+        self.timer_enabled = flags[5] != 0;
+        self.timer_irq = flags[6] != 0;
+        self.control = DriveControl::from_bits_truncate(flags[7]);
+        self.motor_on = flags[8] != 0;
+        self.read_data = flags[9];
+        self.write_data = flags[10];
+        self.transfer_complete = flags[11] != 0;
+        self.disk_irq = flags[12] != 0;
+        self.end_of_head = flags[13] != 0;
+        self.scanning = flags[14] != 0;
+        self.gap_ended = flags[15] != 0;
+        self.timer_reload = input.read_u16()?;
+        self.timer_counter = input.read_u16()?;
+        let side_size = self.sides.get(self.side.unwrap_or(0)).map_or(0, Vec::len);
+        self.head_position = (input.read_u64()? as usize).min(side_size.saturating_sub(1));
+        self.delay = input.read_u64()? as u32;
+        self.switch_delay = input.read_u64()? as u32;
+        self.audio.load_state(input)
+    }
+}
+
+const WAVE_TABLE_SIZE: usize = 64;
+const MOD_TABLE_SIZE: usize = 64;
+// Gains above 32 are stored but don't make the wave any louder
+const MAX_GAIN: u8 = 32;
+// $4089 bits 0-1: 2/2, 2/3, 2/4 and 2/5 of full volume
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 0.5, 0.4];
+// Added to the modulation counter by each mod table entry; 4 resets it instead
+const MOD_STEPS: [i8; 8] = [0, 1, 2, 4, 0, -4, -2, -1];
+
+// The volume and modulation envelopes, $4080 and $4084
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FdsEnvelope {
+    pub speed: u8,
+    pub gain: u8,
+    pub increase: bool,
+    pub disabled: bool,
+    pub timer: u32,
+}
+
+impl FdsEnvelope {
+    fn write(&mut self, value: u8) {
+        self.speed = value & 0x3F;
+        self.increase = value & 0x40 != 0;
+        self.disabled = value & 0x80 != 0;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.timer = 0;
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer >= 8 * (master_speed as u32 + 1) * (self.speed as u32 + 1) {
+            self.timer = 0;
+            if self.increase && self.gain < MAX_GAIN {
+                self.gain += 1;
+            } else if !self.increase && self.gain > 0 {
+                self.gain -= 1;
+            }
+        }
+    }
+}
+
+// The RAM adapter's sound channel: a 64 step, 6 bit wavetable with a volume
+// envelope and a second table that modulates its pitch, at $4040-$4097
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdsAudio {
+    pub wave_table: [u8; WAVE_TABLE_SIZE],
+    pub wave_write: bool,
+    pub master_volume: u8,
+    pub frequency: u16,
+    pub wave_halted: bool,
+    pub envelopes_halted: bool,
+    pub wave_accumulator: u32,
+    pub volume: FdsEnvelope,
+    pub modulator: FdsEnvelope,
+    pub mod_table: [u8; MOD_TABLE_SIZE],
+    pub mod_frequency: u16,
+    pub mod_halted: bool,
+    pub mod_position: usize,
+    pub mod_accumulator: u32,
+    // 7 bit signed
+    pub mod_counter: i8,
+    pub envelope_speed: u8,
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; WAVE_TABLE_SIZE],
+            wave_write: false,
+            master_volume: 0,
+            frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            wave_accumulator: 0,
+            volume: FdsEnvelope::default(),
+            modulator: FdsEnvelope::default(),
+            mod_table: [0; MOD_TABLE_SIZE],
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_position: 0,
+            mod_accumulator: 0,
+            mod_counter: 0,
+            envelope_speed: 0xE8,
+        }
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        // the top bits float, the BIOS sees them as set
+        match address {
+            0x4040..=0x407F => self.wave_table[address as usize - 0x4040] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.modulator.gain | 0x40,
+            _ => 0x40,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        match address {
+            0x4040..=0x407F if self.wave_write => self.wave_table[address as usize - 0x4040] = value & 0x3F,
+            0x4080 => self.volume.write(value),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | value as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.wave_halted = value & 0x80 != 0;
+                self.envelopes_halted = value & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.modulator.write(value),
+            0x4085 => self.mod_counter = sign_extend_7(value),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | value as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((value as u16 & 0x0F) << 8);
+                self.mod_halted = value & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // the table only takes writes while modulation is halted, two entries at a time
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position] = value & 0x07;
+                self.mod_table[(self.mod_position + 1) % MOD_TABLE_SIZE] = value & 0x07;
+                self.mod_position = (self.mod_position + 2) % MOD_TABLE_SIZE;
+            }
+            0x4089 => {
+                self.wave_write = value & 0x80 != 0;
+                self.master_volume = value & 0x03;
+            }
+            0x408A => self.envelope_speed = value,
+            _ => {}
+        }
+    }
+
+    // The wave's pitch bent by the modulator, from the nesdev wiki's FDS audio page
+    pub fn pitch(&self) -> u32 {
+        let counter = self.mod_counter as i32;
+        let mut offset = counter * self.modulator.gain as i32;
+        let remainder = offset & 0x0F;
+        offset >>= 4;
+        if remainder > 0 && offset & 0x80 == 0 {
+            offset += if counter < 0 { -1 } else { 2 };
+        }
+        if offset >= 192 {
+            offset -= 256;
+        } else if offset < -64 {
+            offset += 256;
+        }
+        let mut bend = self.frequency as i32 * offset;
+        let remainder = bend & 0x3F;
+        bend >>= 6;
+        if remainder >= 32 {
+            bend += 1;
+        }
+        (self.frequency as i32 + bend).max(0) as u32
+    }
+
+    fn clock(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.modulator.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency != 0 {
+            let accumulator = self.mod_accumulator + self.mod_frequency as u32;
+            if accumulator >> 16 != self.mod_accumulator >> 16 {
+                let step = self.mod_table[self.mod_position];
+                self.mod_counter = if step == 4 { 0 } else { sign_extend_7((self.mod_counter + MOD_STEPS[step as usize]) as u8) };
+                self.mod_position = (self.mod_position + 1) % MOD_TABLE_SIZE;
+            }
+            self.mod_accumulator = accumulator & 0xFFFF;
+        }
+
+        if !self.wave_halted && !self.wave_write {
+            self.wave_accumulator = (self.wave_accumulator + self.pitch()) & 0x3F_FFFF;
+        }
+    }
+
+    // 0.0-1.0, for the mixer
+    pub fn output(&self) -> f32 {
+        let position = (self.wave_accumulator >> 16) as usize % WAVE_TABLE_SIZE;
+        let level = self.wave_table[position] as f32 * self.volume.gain.min(MAX_GAIN) as f32;
+        level / (63.0 * MAX_GAIN as f32) * MASTER_VOLUMES[self.master_volume as usize]
+    }
+}
+
+fn sign_extend_7(value: u8) -> i8 {
+    ((value << 1) as i8) >> 1
+}
+
+impl SaveState for FdsAudio {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.wave_table);
+        out.extend_from_slice(&self.mod_table);
+        for envelope in [&self.volume, &self.modulator] {
+            out.extend_from_slice(&[envelope.speed, envelope.gain, envelope.increase as u8, envelope.disabled as u8]);
+            out.extend_from_slice(&(envelope.timer as u64).to_le_bytes());
+        }
+        out.extend_from_slice(&[
+            self.wave_write as u8,
+            self.master_volume,
+            self.wave_halted as u8,
+            self.envelopes_halted as u8,
+            self.mod_halted as u8,
+            self.mod_position as u8,
+            self.mod_counter as u8,
+            self.envelope_speed,
+        ]);
+        out.extend_from_slice(&self.frequency.to_le_bytes());
+        out.extend_from_slice(&self.mod_frequency.to_le_bytes());
+        out.extend_from_slice(&(self.wave_accumulator as u64).to_le_bytes());
+        out.extend_from_slice(&(self.mod_accumulator as u64).to_le_bytes());
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.wave_table.copy_from_slice(input.read_bytes(WAVE_TABLE_SIZE)?);
+        self.mod_table.copy_from_slice(input.read_bytes(MOD_TABLE_SIZE)?);
+        for envelope in [&mut self.volume, &mut self.modulator] {
+            let bytes = input.read_bytes(4)?;
+            envelope.speed = bytes[0];
+            envelope.gain = bytes[1];
+            envelope.increase = bytes[2] != 0;
+            envelope.disabled = bytes[3] != 0;
+            envelope.timer = input.read_u64()? as u32;
+        }
+        let bytes = input.read_bytes(8)?;
+        self.wave_write = bytes[0] != 0;
+        self.master_volume = bytes[1] & 0x03;
+        self.wave_halted = bytes[2] != 0;
+        self.envelopes_halted = bytes[3] != 0;
+        self.mod_halted = bytes[4] != 0;
+        self.mod_position = bytes[5] as usize % MOD_TABLE_SIZE;
+        self.mod_counter = sign_extend_7(bytes[6]);
+        self.envelope_speed = bytes[7];
+        self.frequency = input.read_u16()?;
+        self.mod_frequency = input.read_u16()?;
+        self.wave_accumulator = input.read_u64()? as u32;
+        self.mod_accumulator = input.read_u64()? as u32;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    // A side with the disk info block, a file count block and one 4 byte file
+    pub(crate) fn side(name: &[u8; 3]) -> Vec<u8> {
+        let mut side = DISK_VERIFICATION.to_vec();
+        side.resize(56, 0);
+        side[GAME_NAME_OFFSET..GAME_NAME_OFFSET + 3].copy_from_slice(name);
+        side.extend_from_slice(&[2, 1]);
+        let mut header = vec![3, 0, 0];
+        header.resize(16, 0);
+        header[13] = 4;
+        side.extend_from_slice(&header);
+        side.extend_from_slice(&[4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn image(sides: usize) -> Vec<u8> {
+        let mut data = FDS_TAG.to_vec();
+        data.push(sides as u8);
+        data.resize(FDS_HEADER_SIZE, 0);
+        for _ in 0..sides {
+            data.extend_from_slice(&side(b"TST"));
+        }
+        data
+    }
+
+    fn fds(sides: usize) -> Fds {
+        let mut fds = Fds::new(vec![0; BIOS_SIZE], &FdsImage::new(&image(sides)).unwrap());
+        fds.write_prg(0x4023, 0x03);
+        fds
+    }
+
+    #[test]
+    fn test_parse_image() {
+        let disk = FdsImage::new(&image(2)).unwrap();
+        assert_eq!(disk.sides.len(), 2);
+        assert_eq!(disk.game_name(), "TST");
+        // without the fwNES header
+        assert_eq!(FdsImage::new(&image(1)[FDS_HEADER_SIZE..]).unwrap().sides.len(), 1);
+        assert!(matches!(FdsImage::new(&image(1)[..1000]), Err(FdsError::InvalidImage)));
+        let mut data = image(1);
+        data[FDS_HEADER_SIZE + 1] = b'X';
+        assert!(matches!(FdsImage::new(&data), Err(FdsError::InvalidImage)));
+        assert!(FdsImage::is_fds_file(Path::new("zelda.FDS")));
+    }
+
+    #[test]
+    fn test_raw_side() {
+        let raw = raw_side(&side(b"TST"));
+        assert_eq!(raw[LEAD_IN_GAP], BLOCK_START);
+        assert_eq!(&raw[LEAD_IN_GAP + 1..LEAD_IN_GAP + 16], DISK_VERIFICATION);
+        // block 2 follows block 1's CRC and gap
+        let block2 = LEAD_IN_GAP + 1 + 56 + 2 + BLOCK_GAP;
+        assert_eq!(&raw[block2..block2 + 3], [BLOCK_START, 2, 1]);
+        let file = block2 + 3 + 2 + BLOCK_GAP + 1 + 16 + 2 + BLOCK_GAP;
+        assert_eq!(&raw[file..file + 6], [BLOCK_START, 4, 0xDE, 0xAD, 0xBE, 0xEF]);
+    }
+
+    #[test]
+    fn test_read_disk() {
+        let mut fds = fds(1);
+        assert_eq!(fds.peek_prg(0x4032) & 0x01, 0);
+        let control = DriveControl::MotorOn | DriveControl::ReadMode | DriveControl::ReadWriteStart | DriveControl::IrqEnabled;
+        fds.write_prg(0x4025, control.bits());
+        let mut read = Vec::new();
+        // the lead-in alone takes over half a million cycles
+        for _ in 0..10_000 {
+            fds.tick(100);
+            if fds.irq() {
+                read.push(fds.read_prg(0x4031));
+                if read.len() == 15 {
+                    break;
+                }
+            }
+        }
+        // the start mark is skipped, the BIOS sees the block itself
+        assert_eq!(read, DISK_VERIFICATION);
+        assert_eq!(fds.peek_prg(0x4032) & 0x02, 0);
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut fds = fds(1);
+        fds.write_prg(0x4020, 10);
+        fds.write_prg(0x4021, 0);
+        fds.write_prg(0x4022, 0x03);
+        fds.tick(10);
+        assert!(!fds.irq());
+        fds.tick(1);
+        assert!(fds.irq());
+        assert_eq!(fds.read_prg(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq());
+        // repeating
+        fds.tick(11);
+        assert!(fds.irq());
+    }
+
+    #[test]
+    fn test_switch_side() {
+        let mut fds = fds(2);
+        assert_eq!(fds.switch_disk_side(), Some(1));
+        assert_eq!(fds.peek_prg(0x4032) & 0x01, 0x01);
+        fds.tick(255);
+        assert_eq!(fds.side, None);
+        for _ in 0..SIDE_SWITCH_CYCLES / 255 + 1 {
+            fds.tick(255);
+        }
+        assert_eq!(fds.side, Some(1));
+        assert_eq!(fds.switch_disk_side(), Some(0));
+    }
+
+    #[test]
+    fn test_wavetable() {
+        let mut fds = fds(1);
+        fds.write_prg(0x4089, 0x80);
+        for index in 0..64u16 {
+            fds.write_prg(0x4040 + index, if index < 32 { 63 } else { 0 });
+        }
+        fds.write_prg(0x4089, 0x00);
+        assert_eq!(fds.peek_prg(0x4040), 0x7F);
+        // full gain, 1/64 of the table per 0x400 pitch per cycle
+        fds.write_prg(0x4080, 0x80 | 32);
+        fds.write_prg(0x4082, 0x00);
+        fds.write_prg(0x4083, 0x04);
+        assert_eq!(fds.audio.output(), 1.0);
+        fds.tick(64);
+        assert_eq!(fds.audio.output(), 1.0);
+        for _ in 0..32 {
+            fds.tick(64);
+        }
+        assert_eq!(fds.audio.output(), 0.0);
+
+        let mut state = Vec::new();
+        fds.save_state(&mut state);
+        let mut restored = Fds::new(vec![0; BIOS_SIZE], &FdsImage::new(&image(1)).unwrap());
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.audio, fds.audio);
+    }
+
+    #[test]
+    fn test_load_state_side_out_of_range() {
+        let fds = fds(2);
+        let mut state = Vec::new();
+        fds.save_state(&mut state);
+        let flags = RAM_SIZE + CHR_RAM_SIZE + fds.sides.iter().map(Vec::len).sum::<usize>();
+        state[flags] = 2;
+        let mut restored = Fds::new(vec![0; BIOS_SIZE], &FdsImage::new(&image(2)).unwrap());
+        assert_eq!(restored.load_state(&mut StateReader::new(&state)), Err(StateError::Invalid));
+        state[flags] = 0xFF;
+        state[flags + 1] = 5;
+        assert_eq!(restored.load_state(&mut StateReader::new(&state)), Err(StateError::Invalid));
+    }
+
+    #[test]
+    fn test_pitch_modulation() {
+        let mut audio = FdsAudio::new();
+        audio.write(0x4082, 0x00);
+        audio.write(0x4083, 0x01);
+        assert_eq!(audio.pitch(), 0x100);
+        audio.write(0x4084, 0x80 | 0x10);
+        audio.write(0x4085, 0x10);
+        assert!(audio.pitch() > 0x100);
+        audio.write(0x4085, 0x70);
+        assert!(audio.pitch() < 0x100);
+    }
+}
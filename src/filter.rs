@@ -0,0 +1,265 @@
+use crate::ppu::{Layers, PpuMask, LEFT_CLIP_WIDTH};
+use crate::palette::Rgb;
+use crate::viewer::Image;
+
+// Brightness kept on the dark line between scanlines
+const SCANLINE_BRIGHTNESS: u32 = 160;
+
+// Luma and chroma low-pass kernels for the composite filter. Chroma has a much
+// narrower bandwidth than luma, which is what causes the color bleeding.
+const LUMA_KERNEL: [f32; 3] = [0.25, 0.5, 0.25];
+const CHROMA_KERNEL: [f32; 7] = [1.0 / 7.0; 7];
+
+// Tints for the left clip overlay: background hidden, sprites hidden, both
+const BACKGROUND_CLIP_TINT: Rgb = (0xFF, 0x00, 0x00);
+const SPRITES_CLIP_TINT: Rgb = (0x00, 0x60, 0xFF);
+const BOTH_CLIP_TINT: Rgb = (0xFF, 0x00, 0xFF);
+
+// Post-processing applied while scaling the 256x240 picture up to the window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Linear,
+    Scanlines,
+    Ntsc,
+}
+
+impl Filter {
+    pub const ALL: [Filter; 4] = [Filter::Nearest, Filter::Linear, Filter::Scanlines, Filter::Ntsc];
+
+    pub fn parse(name: &str) -> Option<Filter> {
+        match name.to_ascii_lowercase().as_str() {
+            "nearest" => Some(Filter::Nearest),
+            "linear" => Some(Filter::Linear),
+            "scanlines" => Some(Filter::Scanlines),
+            "ntsc" => Some(Filter::Ntsc),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Filter::Nearest => "nearest",
+            Filter::Linear => "linear",
+            Filter::Scanlines => "scanlines",
+            Filter::Ntsc => "ntsc",
+        }
+    }
+
+    // The filter after this one, for cycling through them with a hotkey
+    pub fn next(self) -> Filter {
+        let index = Filter::ALL.iter().position(|&filter| filter == self).unwrap_or(0);
+        Filter::ALL[(index + 1) % Filter::ALL.len()]
+    }
+
+    // Scales the frame by an integer factor with the filter applied
+    pub fn apply(self, frame: &Image, scale: usize) -> Image {
+        let scale = scale.max(1);
+        match self {
+            Filter::Nearest => scale_nearest(frame, scale),
+            Filter::Linear => scale_linear(frame, scale),
+            Filter::Scanlines => scanlines(scale_nearest(frame, scale), scale),
+            Filter::Ntsc => scale_nearest(&composite(frame), scale),
+        }
+    }
+}
+
+// Debug overlay over the leftmost 8 pixels of each line, tinted by which layers the
+// game's PPUMASK (as the line started, from Ppu::mask_lines) or the crop hid there
+pub fn mark_left_clip(frame: &mut Image, masks: &[PpuMask], layers: Layers) {
+    for (y, &mask) in masks.iter().enumerate().take(frame.height) {
+        let tint = match layers.left_clip(mask) {
+            (true, true) => BOTH_CLIP_TINT,
+            (true, false) => BACKGROUND_CLIP_TINT,
+            (false, true) => SPRITES_CLIP_TINT,
+            (false, false) => continue,
+        };
+        for x in 0..LEFT_CLIP_WIDTH.min(frame.width) {
+            let (r, g, b) = frame.get_pixel(x, y);
+            let blend = |value: u8, tint: u8| ((value as u32 + tint as u32) / 2) as u8;
+            frame.set_pixel(x, y, (blend(r, tint.0), blend(g, tint.1), blend(b, tint.2)));
+        }
+    }
+}
+
+fn scale_nearest(frame: &Image, scale: usize) -> Image {
+    let mut image = Image::new(frame.width * scale, frame.height * scale);
+    let row_bytes = image.width * 3;
+    for y in 0..frame.height {
+        let start = y * scale * row_bytes;
+        let row = &mut image.pixels[start..start + row_bytes];
+        for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+            let (r, g, b) = frame.get_pixel(x / scale, y);
+            pixel.copy_from_slice(&[r, g, b]);
+        }
+        for line in 1..scale {
+            image.pixels.copy_within(start..start + row_bytes, start + line * row_bytes);
+        }
+    }
+    image
+}
+
+fn scale_linear(frame: &Image, scale: usize) -> Image {
+    let mut image = Image::new(frame.width * scale, frame.height * scale);
+    // source coordinate of an output pixel center
+    let source = |position: usize, size: usize| {
+        let position = ((position as f32 + 0.5) / scale as f32 - 0.5).clamp(0.0, (size - 1) as f32);
+        let index = position.floor() as usize;
+        (index, (index + 1).min(size - 1), position - index as f32)
+    };
+    for y in 0..image.height {
+        let (y0, y1, fy) = source(y, frame.height);
+        for x in 0..image.width {
+            let (x0, x1, fx) = source(x, frame.width);
+            let corners = [frame.get_pixel(x0, y0), frame.get_pixel(x1, y0), frame.get_pixel(x0, y1), frame.get_pixel(x1, y1)];
+            let weights = [(1.0 - fx) * (1.0 - fy), fx * (1.0 - fy), (1.0 - fx) * fy, fx * fy];
+            let channel = |get: fn(&(u8, u8, u8)) -> u8| {
+                corners.iter().zip(weights).map(|(corner, weight)| get(corner) as f32 * weight).sum::<f32>().round() as u8
+            };
+            image.set_pixel(x, y, (channel(|c| c.0), channel(|c| c.1), channel(|c| c.2)));
+        }
+    }
+    image
+}
+
+// Darkens the last output row of every source line
+fn scanlines(mut image: Image, scale: usize) -> Image {
+    if scale < 2 {
+        return image;
+    }
+    let row_bytes = image.width * 3;
+    for (row, line) in image.pixels.chunks_exact_mut(row_bytes).enumerate() {
+        if row % scale == scale - 1 {
+            for value in line {
+                *value = (*value as u32 * SCANLINE_BRIGHTNESS / 255) as u8;
+            }
+        }
+    }
+    image
+}
+
+// Approximates composite video by low-passing luma and chroma separately along each line
+fn composite(frame: &Image) -> Image {
+    let mut image = Image::new(frame.width, frame.height);
+    let mut yiq = vec![(0.0, 0.0, 0.0); frame.width];
+    for y in 0..frame.height {
+        for (x, value) in yiq.iter_mut().enumerate() {
+            *value = rgb_to_yiq(frame.get_pixel(x, y));
+        }
+        for x in 0..frame.width {
+            let luma = convolve(&yiq, x, &LUMA_KERNEL, |value| value.0);
+            let i = convolve(&yiq, x, &CHROMA_KERNEL, |value| value.1);
+            let q = convolve(&yiq, x, &CHROMA_KERNEL, |value| value.2);
+            image.set_pixel(x, y, yiq_to_rgb(luma, i, q));
+        }
+    }
+    image
+}
+
+fn convolve(line: &[(f32, f32, f32)], x: usize, kernel: &[f32], get: fn(&(f32, f32, f32)) -> f32) -> f32 {
+    let radius = kernel.len() / 2;
+    kernel
+        .iter()
+        .enumerate()
+        .map(|(i, weight)| {
+            let position = (x + i).saturating_sub(radius).min(line.len() - 1);
+            get(&line[position]) * weight
+        })
+        .sum()
+}
+
+fn rgb_to_yiq((r, g, b): (u8, u8, u8)) -> (f32, f32, f32) {
+    let (r, g, b) = (r as f32, g as f32, b as f32);
+    (
+        0.299 * r + 0.587 * g + 0.114 * b,
+        0.596 * r - 0.274 * g - 0.322 * b,
+        0.211 * r - 0.523 * g + 0.312 * b,
+    )
+}
+
+fn yiq_to_rgb(y: f32, i: f32, q: f32) -> (u8, u8, u8) {
+    let clamp = |value: f32| value.round().clamp(0.0, 255.0) as u8;
+    (
+        clamp(y + 0.956 * i + 0.621 * q),
+        clamp(y - 0.272 * i - 0.647 * q),
+        clamp(y - 1.106 * i + 1.703 * q),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkerboard() -> Image {
+        let mut frame = Image::new(4, 2);
+        for (x, y) in [(0, 0), (2, 0), (1, 1), (3, 1)] {
+            frame.set_pixel(x, y, (0xFF, 0xFF, 0xFF));
+        }
+        frame
+    }
+
+    #[test]
+    fn test_nearest() {
+        let image = Filter::Nearest.apply(&checkerboard(), 2);
+        assert_eq!((image.width, image.height), (8, 4));
+        assert_eq!(image.get_pixel(1, 1), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.get_pixel(2, 1), (0, 0, 0));
+        assert_eq!(image.get_pixel(3, 3), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn test_linear_blends_neighbours() {
+        let image = Filter::Linear.apply(&checkerboard(), 2);
+        let (r, _, _) = image.get_pixel(1, 0);
+        assert!(r > 0 && r < 0xFF);
+        assert_eq!(image.get_pixel(0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.get_pixel(1, 1), (159, 159, 159));
+    }
+
+    #[test]
+    fn test_scanlines() {
+        let image = Filter::Scanlines.apply(&checkerboard(), 2);
+        assert_eq!(image.get_pixel(0, 0), (0xFF, 0xFF, 0xFF));
+        assert_eq!(image.get_pixel(0, 1), (160, 160, 160));
+        assert_eq!(Filter::Scanlines.apply(&checkerboard(), 1).get_pixel(0, 0), (0xFF, 0xFF, 0xFF));
+    }
+
+    #[test]
+    fn test_ntsc_bleeds_color() {
+        let mut frame = Image::new(16, 1);
+        frame.set_pixel(8, 0, (0xFF, 0, 0));
+        let image = Filter::Ntsc.apply(&frame, 1);
+        let (r, g, b) = image.get_pixel(8, 0);
+        assert!(r < 0xFF && r > g && r > b);
+        assert_ne!(image.get_pixel(10, 0), (0, 0, 0));
+        assert_eq!(image.get_pixel(0, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_mark_left_clip() {
+        let mut frame = Image::new(16, 3);
+        let shown = PpuMask::ShowBackground | PpuMask::ShowBackgroundLeft | PpuMask::ShowSprites | PpuMask::ShowSpritesLeft;
+        let masks = [shown, shown.difference(PpuMask::ShowBackgroundLeft), PpuMask::ShowSprites];
+        mark_left_clip(&mut frame, &masks, Layers::default());
+        assert_eq!(frame.get_pixel(0, 0), (0, 0, 0));
+        assert_eq!(frame.get_pixel(7, 1), (0x7F, 0, 0));
+        assert_eq!(frame.get_pixel(8, 1), (0, 0, 0));
+        assert_eq!(frame.get_pixel(0, 2), (0, 0x30, 0x7F));
+
+        // cropping counts as clipping
+        let mut frame = Image::new(16, 1);
+        let mut layers = Layers::default();
+        layers.set_crop_left("both");
+        mark_left_clip(&mut frame, &[shown], layers);
+        assert_eq!(frame.get_pixel(3, 0), (0x7F, 0, 0x7F));
+    }
+
+    #[test]
+    fn test_parse_and_cycle() {
+        assert_eq!(Filter::parse("NTSC"), Some(Filter::Ntsc));
+        assert_eq!(Filter::parse("crt"), None);
+        assert_eq!(Filter::Ntsc.next(), Filter::Nearest);
+        assert!(Filter::ALL.iter().all(|filter| Filter::parse(filter.name()) == Some(*filter)));
+    }
+}
@@ -0,0 +1,525 @@
+use crate::mapper::{Mapper, PRG_RAM_SIZE};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// The 5B's tone, noise and envelope counters all run off the CPU clock divided by 16
+const AUDIO_DIVIDER: u8 = 16;
+const ENVELOPE_STEPS: u8 = 32;
+
+// $6000 bank register bits
+const PRG_RAM_SELECT: u8 = 0x40;
+const PRG_RAM_ENABLE: u8 = 0x80;
+
+// Mapper 69: Sunsoft FME-7 and the 5A/5B with it. A command register at $8000 picks
+// which of 16 registers the byte written to $A000 goes to: eight 1KB CHR banks,
+// four 8KB PRG banks, mirroring and a 16 bit IRQ counter clocked by the CPU.
+pub struct Fme7 {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; PRG_RAM_SIZE],
+    pub chr: Vec<u8>,
+    pub chr_writable: bool,
+    pub command: u8,
+    pub chr_banks: [u8; 8],
+    // $6000, $8000, $A000 and $C000; $E000 is fixed to the last bank
+    pub prg_banks: [u8; 4],
+    pub mirroring: Mirroring,
+    pub irq_enabled: bool,
+    pub counter_enabled: bool,
+    pub irq_pending: bool,
+    pub irq_counter: u16,
+    // only Gimmick! has the 5B, the other boards leave it unused
+    pub audio: Sunsoft5b,
+}
+
+impl Fme7 {
+    pub fn new(rom: &Rom) -> Self {
+        Fme7 {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            chr_writable: !rom.chr_ram.is_empty(),
+            command: 0,
+            chr_banks: [0; 8],
+            prg_banks: [0; 4],
+            mirroring: Mirroring::Vertical,
+            irq_enabled: false,
+            counter_enabled: false,
+            irq_pending: false,
+            irq_counter: 0,
+            audio: Sunsoft5b::new(),
+        }
+    }
+
+    fn read_prg_rom(&self, bank: u8, address: u16) -> u8 {
+        let offset = bank as usize * PRG_BANK_SIZE + address as usize % PRG_BANK_SIZE;
+        self.prg_rom[offset % self.prg_rom.len()]
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_banks[0] & (PRG_RAM_SELECT | PRG_RAM_ENABLE) == PRG_RAM_SELECT | PRG_RAM_ENABLE
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank = self.chr_banks[(address as usize >> 10) & 0x07] as usize;
+        (bank * CHR_BANK_SIZE + (address as usize & 0x03FF)) % self.chr.len()
+    }
+
+    fn write_parameter(&mut self, value: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = value,
+            0x8..=0xB => self.prg_banks[self.command as usize - 0x8] = value,
+            0xC => self.mirroring = mirroring(value),
+            0xD => {
+                self.irq_enabled = value & 0x01 != 0;
+                self.counter_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.irq_counter = (self.irq_counter & 0xFF00) | value as u16,
+            _ => self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16) << 8,
+        }
+    }
+}
+
+fn mirroring(value: u8) -> Mirroring {
+    match value & 0x03 {
+        0 => Mirroring::Vertical,
+        1 => Mirroring::Horizontal,
+        2 => Mirroring::SingleScreenLow,
+        _ => Mirroring::SingleScreenHigh,
+    }
+}
+
+impl Mapper for Fme7 {
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF if self.prg_banks[0] & PRG_RAM_SELECT == 0 && !self.prg_rom.is_empty() => {
+                self.read_prg_rom(self.prg_banks[0] & 0x3F, address)
+            }
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xDFFF if !self.prg_rom.is_empty() => {
+                let bank = self.prg_banks[(address as usize - 0x6000) / PRG_BANK_SIZE] & 0x3F;
+                self.read_prg_rom(bank, address)
+            }
+            0xE000..=0xFFFF if !self.prg_rom.is_empty() => {
+                let last = (self.prg_rom.len() / PRG_BANK_SIZE).max(1) - 1;
+                self.read_prg_rom(last as u8, address)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.prg_ram[address as usize - 0x6000] = value,
+            0x8000..=0x9FFF => self.command = value & 0x0F,
+            0xA000..=0xBFFF => self.write_parameter(value),
+            0xC000..=0xDFFF => self.audio.write_address(value),
+            0xE000..=0xFFFF => self.audio.write_data(value),
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            if self.counter_enabled {
+                self.irq_counter = self.irq_counter.wrapping_sub(1);
+                if self.irq_counter == 0xFFFF && self.irq_enabled {
+                    self.irq_pending = true;
+                }
+            }
+            self.audio.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl SaveState for Fme7 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        out.push(self.command);
+        out.extend_from_slice(&self.chr_banks);
+        out.extend_from_slice(&self.prg_banks);
+        let mirroring = match self.mirroring {
+            Mirroring::Horizontal => 1,
+            Mirroring::SingleScreenLow => 2,
+            Mirroring::SingleScreenHigh => 3,
+            _ => 0,
+        };
+        out.extend_from_slice(&[mirroring, self.irq_enabled as u8, self.counter_enabled as u8, self.irq_pending as u8]);
+        out.extend_from_slice(&self.irq_counter.to_le_bytes());
+        self.audio.save_state(out);
+        if self.chr_writable {
+            out.extend_from_slice(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        self.command = input.read_u8()? & 0x0F;
+        self.chr_banks.copy_from_slice(input.read_bytes(8)?);
+        self.prg_banks.copy_from_slice(input.read_bytes(4)?);
+        let bytes = input.read_bytes(4)?;
+        self.mirroring = mirroring(bytes[0]);
+        self.irq_enabled = bytes[1] != 0;
+        self.counter_enabled = bytes[2] != 0;
+        self.irq_pending = bytes[3] != 0;
+        self.irq_counter = input.read_u16()?;
+        self.audio.load_state(input)?;
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr.copy_from_slice(input.read_bytes(size)?);
+        }
+        Ok(())
+    }
+}
+
+// The Sunsoft 5B's sound: a YM2149 (AY-3-8910) core with three square waves that can
+// each mix in a shared noise generator, and a shared 32 step volume envelope.
+// $C000 selects one of its 16 registers and $E000 writes it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sunsoft5b {
+    pub registers: [u8; 16],
+    pub address: u8,
+    pub divider: u8,
+    pub tone_counters: [u16; 3],
+    pub tone_high: [bool; 3],
+    pub noise_counter: u8,
+    // 17 bit LFSR, the output is bit 0
+    pub noise_shift: u32,
+    pub envelope_counter: u16,
+    pub envelope_step: u8,
+    // counting up rather than down
+    pub envelope_attack: bool,
+    pub envelope_holding: bool,
+}
+
+impl Default for Sunsoft5b {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sunsoft5b {
+    pub fn new() -> Self {
+        Sunsoft5b {
+            registers: [0; 16],
+            address: 0,
+            divider: 0,
+            tone_counters: [0; 3],
+            tone_high: [false; 3],
+            noise_counter: 0,
+            noise_shift: 1,
+            envelope_counter: 0,
+            envelope_step: 0,
+            envelope_attack: false,
+            envelope_holding: false,
+        }
+    }
+
+    pub fn write_address(&mut self, value: u8) {
+        // the upper bits must be 0 or the chip ignores the data writes that follow
+        self.address = value;
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        if self.address > 0x0F {
+            return;
+        }
+        self.registers[self.address as usize] = value;
+        // writing the shape restarts the envelope
+        if self.address == 0x0D {
+            self.envelope_step = 0;
+            self.envelope_counter = 0;
+            self.envelope_attack = value & 0x04 != 0;
+            self.envelope_holding = false;
+        }
+    }
+
+    fn tone_period(&self, channel: usize) -> u16 {
+        (self.registers[channel * 2] as u16 | (self.registers[channel * 2 + 1] as u16 & 0x0F) << 8).max(1)
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self) {
+        self.divider += 1;
+        if self.divider < AUDIO_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+
+        for channel in 0..3 {
+            self.tone_counters[channel] += 1;
+            if self.tone_counters[channel] >= self.tone_period(channel) {
+                self.tone_counters[channel] = 0;
+                self.tone_high[channel] = !self.tone_high[channel];
+            }
+        }
+
+        self.noise_counter += 1;
+        if self.noise_counter >= (self.registers[6] & 0x1F).max(1) {
+            self.noise_counter = 0;
+            let feedback = (self.noise_shift ^ (self.noise_shift >> 3)) & 0x01;
+            self.noise_shift = (self.noise_shift >> 1) | feedback << 16;
+        }
+
+        if !self.envelope_holding {
+            self.envelope_counter += 1;
+            let period = (self.registers[0x0B] as u16 | (self.registers[0x0C] as u16) << 8).max(1);
+            if self.envelope_counter >= period {
+                self.envelope_counter = 0;
+                self.step_envelope();
+            }
+        }
+    }
+
+    // Shape bits: 3 continue, 2 attack, 1 alternate, 0 hold
+    fn step_envelope(&mut self) {
+        if self.envelope_step < ENVELOPE_STEPS - 1 {
+            self.envelope_step += 1;
+            return;
+        }
+        let shape = self.registers[0x0D];
+        if shape & 0x08 == 0 {
+            // one ramp, then silence
+            self.envelope_attack = false;
+            self.envelope_holding = true;
+        } else {
+            if shape & 0x02 != 0 {
+                self.envelope_attack = !self.envelope_attack;
+            }
+            if shape & 0x01 != 0 {
+                self.envelope_holding = true;
+            } else {
+                self.envelope_step = 0;
+            }
+        }
+    }
+
+    pub fn envelope_level(&self) -> u8 {
+        if self.envelope_attack {
+            self.envelope_step
+        } else {
+            ENVELOPE_STEPS - 1 - self.envelope_step
+        }
+    }
+
+    // 0-31 on the envelope's scale; the 4 bit volumes land on its odd steps
+    fn channel_level(&self, channel: usize) -> u8 {
+        let volume = self.registers[8 + channel];
+        match volume & 0x0F {
+            _ if volume & 0x10 != 0 => self.envelope_level(),
+            0 => 0,
+            volume => volume * 2 + 1,
+        }
+    }
+
+    // 0.0-1.0, for the mixer
+    pub fn output(&self) -> f32 {
+        let mixer = self.registers[7];
+        let noise_high = self.noise_shift & 0x01 != 0;
+        let sum: f32 = (0..3)
+            .filter(|&channel| {
+                // a disabled tone or noise holds its input high
+                (self.tone_high[channel] || mixer & (0x01 << channel) != 0)
+                    && (noise_high || mixer & (0x08 << channel) != 0)
+            })
+            .map(|channel| amplitude(self.channel_level(channel)))
+            .sum();
+        sum / 3.0
+    }
+}
+
+// The DAC is logarithmic, 1.5dB per level
+fn amplitude(level: u8) -> f32 {
+    if level == 0 {
+        return 0.0;
+    }
+    10f32.powf((level as f32 - (ENVELOPE_STEPS - 1) as f32) * 1.5 / 20.0)
+}
+
+impl SaveState for Sunsoft5b {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.registers);
+        out.extend_from_slice(&[self.address, self.divider, self.noise_counter, self.envelope_step]);
+        for (counter, high) in self.tone_counters.iter().zip(self.tone_high) {
+            out.extend_from_slice(&counter.to_le_bytes());
+            out.push(high as u8);
+        }
+        out.extend_from_slice(&(self.noise_shift as u64).to_le_bytes());
+        out.extend_from_slice(&self.envelope_counter.to_le_bytes());
+        out.extend_from_slice(&[self.envelope_attack as u8, self.envelope_holding as u8]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.registers.copy_from_slice(input.read_bytes(16)?);
+        let bytes = input.read_bytes(4)?;
+        self.address = bytes[0];
+        self.divider = bytes[1] % AUDIO_DIVIDER;
+        self.noise_counter = bytes[2];
+        self.envelope_step = bytes[3] % ENVELOPE_STEPS;
+        for channel in 0..3 {
+            self.tone_counters[channel] = input.read_u16()?;
+            self.tone_high[channel] = input.read_u8()? != 0;
+        }
+        self.noise_shift = input.read_u64()? as u32 & 0x1_FFFF;
+        self.envelope_counter = input.read_u16()?;
+        let bytes = input.read_bytes(2)?;
+        self.envelope_attack = bytes[0] != 0;
+        self.envelope_holding = bytes[1] != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    // 8KB PRG banks and 1KB CHR banks, each filled with its own number
+    fn cartridge() -> Fme7 {
+        let mut data = ines(8, 2, 0x50, 0x40);
+        for (bank, chunk) in data[16..16 + 8 * 0x4000].chunks_mut(PRG_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        for (bank, chunk) in data[16 + 8 * 0x4000..].chunks_mut(CHR_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        Fme7::new(&Rom::new(&data).unwrap())
+    }
+
+    fn command(fme7: &mut Fme7, command: u8, value: u8) {
+        fme7.write_prg(0x8000, command);
+        fme7.write_prg(0xA000, value);
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut fme7 = cartridge();
+        command(&mut fme7, 0x9, 3);
+        command(&mut fme7, 0xA, 4);
+        command(&mut fme7, 0xB, 5);
+        command(&mut fme7, 0x7, 9);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| fme7.peek_prg(a)), [3, 4, 5, 15]);
+        assert_eq!(fme7.read_chr(0x1C00), 9);
+
+        // ROM at $6000, then RAM that only takes writes once enabled
+        command(&mut fme7, 0x8, 7);
+        assert_eq!(fme7.peek_prg(0x6000), 7);
+        command(&mut fme7, 0x8, PRG_RAM_SELECT);
+        fme7.write_prg(0x6000, 0x42);
+        assert_eq!(fme7.peek_prg(0x6000), 0);
+        command(&mut fme7, 0x8, PRG_RAM_SELECT | PRG_RAM_ENABLE);
+        fme7.write_prg(0x6000, 0x42);
+        assert_eq!(fme7.peek_prg(0x6000), 0x42);
+
+        command(&mut fme7, 0xC, 3);
+        assert_eq!(fme7.mirroring(), Mirroring::SingleScreenHigh);
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut fme7 = cartridge();
+        command(&mut fme7, 0xE, 10);
+        command(&mut fme7, 0xF, 0);
+        command(&mut fme7, 0xD, 0x81);
+        fme7.tick(10);
+        assert!(!fme7.irq());
+        // fires on the wrap from 0 to $FFFF
+        fme7.tick(1);
+        assert!(fme7.irq());
+        command(&mut fme7, 0xD, 0x81);
+        assert!(!fme7.irq());
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut fme7 = cartridge();
+        for (register, value) in [(0, 2), (1, 0), (7, 0x3E), (8, 0x0F)] {
+            fme7.write_prg(0xC000, register);
+            fme7.write_prg(0xE000, value);
+        }
+        // channel A alone, a square wave with a period of 2 * 2 * 16 cycles
+        let levels: Vec<f32> = (0..4)
+            .map(|_| {
+                fme7.tick(32);
+                fme7.audio_output()
+            })
+            .collect();
+        assert_eq!(levels, [1.0 / 3.0, 0.0, 1.0 / 3.0, 0.0]);
+    }
+
+    #[test]
+    fn test_envelope() {
+        let mut audio = Sunsoft5b::new();
+        // attack, then hold at the top
+        for (register, value) in [(0x0B, 1), (0x0C, 0), (0x0D, 0x0D)] {
+            audio.write_address(register);
+            audio.write_data(value);
+        }
+        assert_eq!(audio.envelope_level(), 0);
+        for _ in 0..40 * AUDIO_DIVIDER as usize {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 31);
+        // decay once, then silence
+        audio.write_data(0x00);
+        assert_eq!(audio.envelope_level(), 31);
+        for _ in 0..40 * AUDIO_DIVIDER as usize {
+            audio.clock();
+        }
+        assert_eq!(audio.envelope_level(), 0);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut fme7 = cartridge();
+        command(&mut fme7, 0x9, 6);
+        command(&mut fme7, 0xC, 2);
+        fme7.write_prg(0xC000, 0x08);
+        fme7.write_prg(0xE000, 0x1F);
+        fme7.tick(100);
+
+        let mut state = Vec::new();
+        fme7.save_state(&mut state);
+        let mut restored = cartridge();
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.peek_prg(0x8000), 6);
+        assert_eq!(restored.mirroring(), Mirroring::SingleScreenLow);
+        assert_eq!(restored.audio, fme7.audio);
+    }
+}
@@ -0,0 +1,124 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::viewer::Image;
+
+// Hands finished frames from the emulation thread to the render thread. There are
+// three images: the producer draws into its own, the consumer shows its own, and
+// the third sits between them holding the newest finished frame. Publishing or
+// picking up a frame only swaps two images under the lock, so neither side waits
+// on the other drawing or uploading, and the consumer never sees a frame being
+// written. Frames the consumer is too slow for are dropped, never queued.
+struct Shared {
+    middle: Mutex<Image>,
+    // the middle image hasn't been picked up yet
+    fresh: AtomicBool,
+}
+
+pub struct FrameProducer {
+    back: Image,
+    shared: Arc<Shared>,
+}
+
+pub struct FrameConsumer {
+    front: Image,
+    shared: Arc<Shared>,
+}
+
+pub fn triple_buffer(width: usize, height: usize) -> (FrameProducer, FrameConsumer) {
+    let shared = Arc::new(Shared { middle: Mutex::new(Image::new(width, height)), fresh: AtomicBool::new(false) });
+    (
+        FrameProducer { back: Image::new(width, height), shared: shared.clone() },
+        FrameConsumer { front: Image::new(width, height), shared },
+    )
+}
+
+impl FrameProducer {
+    // The image to draw the next frame into. It holds an older frame, not the last one published.
+    pub fn back_mut(&mut self) -> &mut Image {
+        &mut self.back
+    }
+
+    // Makes the back image the newest frame, replacing one the consumer hasn't picked up
+    pub fn publish(&mut self) {
+        let mut middle = self.shared.middle.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::mem::swap(&mut self.back, &mut middle);
+        self.shared.fresh.store(true, Ordering::Release);
+    }
+
+    // Copies a frame finished elsewhere, e.g. Nes::frame(), and publishes it
+    pub fn publish_copy(&mut self, frame: &Image) {
+        self.back.clone_from(frame);
+        self.publish();
+    }
+}
+
+impl FrameConsumer {
+    // The newest frame if one was published since the last call, to upload to the screen
+    pub fn latest(&mut self) -> Option<&Image> {
+        if !self.shared.fresh.load(Ordering::Acquire) {
+            return None;
+        }
+        let mut middle = self.shared.middle.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        std::mem::swap(&mut self.front, &mut middle);
+        self.shared.fresh.store(false, Ordering::Release);
+        drop(middle);
+        Some(&self.front)
+    }
+
+    // The frame picked up last, for redrawing when no new one has arrived
+    pub fn front(&self) -> &Image {
+        &self.front
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn frame(value: u8) -> Image {
+        let mut image = Image::new(4, 2);
+        image.pixels.fill(value);
+        image
+    }
+
+    #[test]
+    fn test_latest_frame_wins() {
+        let (mut producer, mut consumer) = triple_buffer(4, 2);
+        assert!(consumer.latest().is_none());
+
+        producer.publish_copy(&frame(1));
+        producer.publish_copy(&frame(2));
+        // frame 1 was never shown
+        assert_eq!(consumer.latest(), Some(&frame(2)));
+        assert!(consumer.latest().is_none());
+        assert_eq!(consumer.front(), &frame(2));
+
+        producer.back_mut().pixels.fill(3);
+        producer.publish();
+        assert_eq!(consumer.latest(), Some(&frame(3)));
+    }
+
+    #[test]
+    fn test_threaded_handoff() {
+        let (mut producer, mut consumer) = triple_buffer(4, 2);
+        let emulation = thread::spawn(move || {
+            for value in 1..=200 {
+                // a whole frame is drawn before it's published
+                producer.back_mut().pixels.fill(value);
+                producer.publish();
+            }
+        });
+        let mut last = 0;
+        while last < 200 {
+            if let Some(image) = consumer.latest() {
+                let value = image.pixels[0];
+                assert!(image.pixels.iter().all(|&pixel| pixel == value), "torn frame");
+                assert!(value > last);
+                last = value;
+            }
+        }
+        emulation.join().unwrap();
+    }
+}
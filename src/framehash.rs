@@ -0,0 +1,180 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hash;
+use crate::movie::{Movie, MovieError};
+use crate::nes::{Nes, NesError};
+use crate::rom::{Rom, RomError};
+use crate::viewer::Image;
+
+// Golden frame hashes, for catching rendering regressions in `cargo test`.
+// Each line of the list is a ROM run headlessly and the CRC-32 its frame should have:
+//   <rom> <frames> <crc32> [<fm2 movie>]
+// Paths are relative to the ROM directory. Blank lines and lines starting with # are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameHashCase {
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub hash: u32,
+    // input to play, the controllers are left alone without one
+    pub movie: Option<PathBuf>,
+}
+
+impl fmt::Display for FrameHashCase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} {:08X}", self.rom.display(), self.frames, self.hash)?;
+        if let Some(movie) = &self.movie {
+            write!(f, " {}", movie.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum FrameHashError {
+    Io(io::Error),
+    InvalidLine(usize),
+    Rom(RomError),
+    Nes(NesError),
+    Movie(MovieError),
+    // `line` is what to put in the list if the new picture is right
+    Mismatch { expected: u32, actual: u32, line: String },
+}
+
+impl fmt::Display for FrameHashError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameHashError::Io(error) => write!(f, "{}", error),
+            FrameHashError::InvalidLine(line) => write!(f, "Invalid frame hash line {}", line),
+            FrameHashError::Rom(error) => write!(f, "{}", error),
+            FrameHashError::Nes(error) => write!(f, "{}", error),
+            FrameHashError::Movie(error) => write!(f, "{}", error),
+            FrameHashError::Mismatch { expected, actual, line } => {
+                write!(f, "Frame hash is {:08X}, expected {:08X}\n  {}", actual, expected, line)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for FrameHashError {
+    fn from(error: io::Error) -> Self {
+        FrameHashError::Io(error)
+    }
+}
+
+impl From<RomError> for FrameHashError {
+    fn from(error: RomError) -> Self {
+        FrameHashError::Rom(error)
+    }
+}
+
+impl From<NesError> for FrameHashError {
+    fn from(error: NesError) -> Self {
+        FrameHashError::Nes(error)
+    }
+}
+
+impl From<MovieError> for FrameHashError {
+    fn from(error: MovieError) -> Self {
+        FrameHashError::Movie(error)
+    }
+}
+
+pub fn frame_hash(image: &Image) -> u32 {
+    hash::crc32(&image.pixels)
+}
+
+pub fn parse_cases(text: &str) -> Result<Vec<FrameHashCase>, FrameHashError> {
+    let mut cases = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || FrameHashError::InvalidLine(number + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (rom, frames, hash, movie) = match fields.as_slice() {
+            [rom, frames, hash] => (rom, frames, hash, None),
+            [rom, frames, hash, movie] => (rom, frames, hash, Some(PathBuf::from(movie))),
+            _ => return Err(invalid()),
+        };
+        cases.push(FrameHashCase {
+            rom: PathBuf::from(rom),
+            frames: frames.parse().map_err(|_| invalid())?,
+            hash: u32::from_str_radix(hash, 16).map_err(|_| invalid())?,
+            movie,
+        });
+    }
+    Ok(cases)
+}
+
+// Runs `frames` frames with the movie's input from its anchor, then hashes the last
+// frame the PPU drew. Frames past the movie's end keep its last buttons held.
+pub fn run(nes: &mut Nes, frames: u64, movie: Option<&Movie>) -> Result<u32, FrameHashError> {
+    if let Some(movie) = movie {
+        movie.start(nes)?;
+    }
+    for frame in 0..frames as usize {
+        if !movie.map_or(Ok(false), |movie| movie.play_frame(nes, frame))? {
+            nes.step_frame();
+        }
+    }
+    Ok(frame_hash(nes.frame()))
+}
+
+// Runs one case, with its paths relative to `directory`
+pub fn check(case: &FrameHashCase, directory: &Path) -> Result<(), FrameHashError> {
+    let movie = match &case.movie {
+        Some(path) => Some(Movie::from_fm2(&fs::read_to_string(directory.join(path))?)?),
+        None => None,
+    };
+    let mut nes = Nes::new();
+    nes.insert_cartridge(Rom::load(directory.join(&case.rom))?)?;
+    let hash = run(&mut nes, case.frames, movie.as_ref())?;
+    if hash != case.hash {
+        let line = FrameHashCase { hash, ..case.clone() }.to_string();
+        return Err(FrameHashError::Mismatch { expected: case.hash, actual: hash, line });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::JoypadButton;
+    use crate::movie::{Anchor, FrameInput, COMMAND_POWER};
+    use crate::rom::tests::ines;
+
+    #[test]
+    fn test_parse_cases() {
+        let text = "# golden hashes\n\nsmb.nes 120 0badf00d\nzelda.nes 600 12345678 zelda.fm2\n";
+        let cases = parse_cases(text).unwrap();
+        assert_eq!(cases[0], FrameHashCase { rom: "smb.nes".into(), frames: 120, hash: 0x0BAD_F00D, movie: None });
+        assert_eq!(cases[1].movie.as_deref(), Some(Path::new("zelda.fm2")));
+        assert_eq!(cases[1].to_string(), "zelda.nes 600 12345678 zelda.fm2");
+        assert!(matches!(parse_cases("smb.nes 120"), Err(FrameHashError::InvalidLine(1))));
+        assert!(matches!(parse_cases("\nsmb.nes x 0"), Err(FrameHashError::InvalidLine(2))));
+    }
+
+    #[test]
+    fn test_run_with_movie() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        nes.cpu_mut().bus.ppu.palette[0] = 0x21;
+        let mut movie = Movie::new(Anchor::PowerOn, "test.nes");
+        movie.record(FrameInput::default());
+        movie.record(FrameInput { commands: COMMAND_POWER, ports: [JoypadButton::Start, JoypadButton::empty()] });
+        // power-on clears the palette, so it's all color $00
+        assert_eq!(run(&mut nes, 3, Some(&movie)).unwrap(), 0x3554_2832);
+        assert_eq!(nes.buttons(0), JoypadButton::Start);
+        assert_eq!(nes.frame_count(), 2);
+
+        // a savestate anchor starts from the state, all color $21
+        nes.cpu_mut().bus.ppu.palette[0] = 0x21;
+        let movie = Movie::from_savestate(&nes, "test.nes");
+        nes.cpu_mut().bus.ppu.palette[0] = 0x00;
+        assert_eq!(run(&mut nes, 2, Some(&movie)).unwrap(), 0x5DF3_38A7);
+    }
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::{parse_toml, Config, ConfigError, Value};
+use crate::hash::to_hex;
+use crate::rom::{Mirroring, Rom};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
+impl Region {
+    pub fn parse(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Ntsc => write!(f, "NTSC"),
+            Region::Pal => write!(f, "PAL"),
+            Region::Dendy => write!(f, "Dendy"),
+        }
+    }
+}
+
+// Per-game settings, for dumps with wrong or missing header information.
+// Only the fields an entry sets override the iNES header.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GameEntry {
+    pub name: Option<String>,
+    pub mapper: Option<u8>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    // battery backed PRG RAM
+    pub battery: Option<bool>,
+    pub region: Option<Region>,
+}
+
+impl GameEntry {
+    pub fn apply(&self, rom: &mut Rom) {
+        if let Some(mapper) = self.mapper {
+            rom.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper {
+            rom.submapper = submapper;
+        }
+        if let Some(mirroring) = self.mirroring {
+            rom.mirroring = mirroring;
+        }
+        if let Some(battery) = self.battery {
+            rom.has_battery = battery;
+        }
+        if self.region.is_some() {
+            rom.region = self.region;
+        }
+    }
+}
+
+// games.toml: one section per game, named by the CRC-32 or SHA-1 of its PRG and CHR ROM
+//
+//   [A1B2C3D4]
+//   name = "Some Game (USA)"
+//   mapper = 1
+//   mirroring = "vertical"
+//   battery = true
+//   region = "pal"
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GameDatabase {
+    // keyed by upper case hex
+    pub entries: HashMap<String, GameEntry>,
+}
+
+impl GameDatabase {
+    pub fn parse(text: &str) -> Result<GameDatabase, ConfigError> {
+        let mut database = GameDatabase::default();
+        parse_toml(text, |section, key, value| {
+            let invalid = |message: &str| ConfigError::InvalidValue {
+                key: format!("{}.{}", section, key),
+                message: message.to_string(),
+            };
+            let is_hash = matches!(section.len(), 8 | 40) && section.chars().all(|c| c.is_ascii_hexdigit());
+            if !is_hash {
+                return Err(invalid("section must be a CRC-32 or SHA-1"));
+            }
+            let entry = database.entries.entry(section.to_ascii_uppercase()).or_default();
+            match (key, value) {
+                ("name", Value::String(name)) => entry.name = Some(name),
+                ("mapper", Value::Integer(mapper)) if (0..=255).contains(&mapper) => entry.mapper = Some(mapper as u8),
+                ("submapper", Value::Integer(submapper)) if (0..=15).contains(&submapper) => {
+                    entry.submapper = Some(submapper as u8)
+                }
+                ("mirroring", Value::String(mirroring)) => {
+                    entry.mirroring = Some(Mirroring::parse(&mirroring).ok_or_else(|| invalid("unknown mirroring"))?)
+                }
+                ("battery", Value::Boolean(battery)) => entry.battery = Some(battery),
+                ("region", Value::String(region)) => {
+                    entry.region = Some(Region::parse(&region).ok_or_else(|| invalid("unknown region"))?)
+                }
+                _ => return Err(invalid("unknown key or invalid value")),
+            }
+            Ok(())
+        })?;
+        Ok(database)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<GameDatabase, ConfigError> {
+        GameDatabase::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn default_path() -> Option<PathBuf> {
+        Some(Config::directory()?.join("games.toml"))
+    }
+
+    // The user's database, empty when there is none
+    pub fn load_default() -> Result<GameDatabase, ConfigError> {
+        match GameDatabase::default_path() {
+            Some(path) if path.exists() => GameDatabase::load(path),
+            _ => Ok(GameDatabase::default()),
+        }
+    }
+
+    // SHA-1 entries win over CRC-32 ones
+    pub fn find(&self, rom: &Rom) -> Option<&GameEntry> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        self.entries
+            .get(&to_hex(&rom.sha1()))
+            .or_else(|| self.entries.get(&format!("{:08X}", rom.crc32())))
+    }
+
+    // Applies the ROM's entry, if it has one
+    pub fn apply(&self, rom: &mut Rom) -> Option<&GameEntry> {
+        let entry = self.find(rom)?;
+        entry.apply(rom);
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    #[test]
+    fn test_apply_entry() {
+        let mut rom = Rom::new(&ines(1, 1, 0, 0)).unwrap();
+        let text = format!(
+            "[{:08X}]\nname = \"Test\"\nmapper = 5\nmirroring = \"vertical\"\nbattery = true\nregion = \"pal\"\n\n[{}]\nname = \"Other\"",
+            rom.crc32(),
+            "0".repeat(40)
+        );
+        let database = GameDatabase::parse(&text).unwrap();
+        let entry = database.apply(&mut rom).unwrap();
+        assert_eq!(entry.name.as_deref(), Some("Test"));
+        assert_eq!(entry.region, Some(Region::Pal));
+        assert_eq!(rom.region, Some(Region::Pal));
+        assert_eq!((rom.mapper, rom.mirroring, rom.has_battery), (5, Mirroring::Vertical, true));
+    }
+
+    #[test]
+    fn test_sha1_wins() {
+        let rom = Rom::new(&ines(1, 1, 0, 0)).unwrap();
+        let text = format!("[{:08x}]\nname = \"crc\"\n[{}]\nname = \"sha1\"", rom.crc32(), to_hex(&rom.sha1()));
+        let database = GameDatabase::parse(&text).unwrap();
+        assert_eq!(database.find(&rom).unwrap().name.as_deref(), Some("sha1"));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(GameDatabase::parse("[mario]\nmapper = 1"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(GameDatabase::parse("[A1B2C3D4]\nmapper = 300"), Err(ConfigError::InvalidValue { .. })));
+        assert!(matches!(GameDatabase::parse("[A1B2C3D4]\nregion = \"secam\""), Err(ConfigError::InvalidValue { .. })));
+    }
+}
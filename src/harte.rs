@@ -0,0 +1,491 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::cpu::{Bus, Cpu, StatusFlag};
+
+// Tom Harte's ProcessorTests (github.com/SingleStepTests/ProcessorTests), the nes6502
+// set: one JSON file per opcode, each holding 10,000 cases of random registers and
+// memory with the state after that one instruction and every bus cycle it made:
+//   {"name": "a9 3f 12", "initial": {"pc": 1234, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36,
+//    "ram": [[1234, 169], [1235, 63]]}, "final": {...}, "cycles": [[1234, 169, "read"], ...]}
+// Every bus cycle is compared: address, value and direction, in order, dummy reads
+// and writes included.
+
+// B and the unused bit only exist on the stack, so they aren't compared
+const IGNORED_FLAGS: u8 = 0x30;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorState {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub ram: Vec<(u16, u8)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusCycle {
+    pub address: u16,
+    pub value: u8,
+    pub write: bool,
+}
+
+impl fmt::Display for BusCycle {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.write { "write" } else { "read" };
+        write!(f, "{} ${:04X} = {:02X}", kind, self.address, self.value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VectorCase {
+    pub name: String,
+    pub initial: VectorState,
+    pub expected: VectorState,
+    pub bus: Vec<BusCycle>,
+}
+
+#[derive(Debug)]
+pub enum VectorError {
+    Io(std::io::Error),
+    Parse(String),
+    // the CPU has no instruction for the opcode, so the whole file is skipped
+    Unsupported(u8),
+    Mismatch { name: String, differences: Vec<String> },
+}
+
+impl fmt::Display for VectorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VectorError::Io(error) => write!(f, "Could not read test vectors: {}", error),
+            VectorError::Parse(message) => write!(f, "Invalid test vectors: {}", message),
+            VectorError::Unsupported(opcode) => write!(f, "Opcode {:02X} is not implemented", opcode),
+            VectorError::Mismatch { name, differences } => write!(f, "\"{}\": {}", name, differences.join(", ")),
+        }
+    }
+}
+
+// Just enough JSON for the vector files
+#[derive(Debug, Clone, PartialEq)]
+enum Json {
+    Number(i64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+struct JsonParser<'a> {
+    text: &'a [u8],
+    position: usize,
+}
+
+impl JsonParser<'_> {
+    fn error(&self, expected: &str) -> VectorError {
+        VectorError::Parse(format!("expected {} at byte {}", expected, self.position))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.position).is_some_and(u8::is_ascii_whitespace) {
+            self.position += 1;
+        }
+    }
+
+    // Skips whitespace and takes the given byte if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.skip_whitespace();
+        let found = self.text.get(self.position) == Some(&byte);
+        self.position += found as usize;
+        found
+    }
+
+    fn expect(&mut self, byte: u8) -> Result<(), VectorError> {
+        if self.eat(byte) {
+            Ok(())
+        } else {
+            Err(self.error(&format!("'{}'", byte as char)))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, VectorError> {
+        self.skip_whitespace();
+        match self.text.get(self.position) {
+            Some(b'[') => {
+                self.position += 1;
+                let mut items = Vec::new();
+                if self.eat(b']') {
+                    return Ok(Json::Array(items));
+                }
+                loop {
+                    items.push(self.value()?);
+                    if self.eat(b']') {
+                        return Ok(Json::Array(items));
+                    }
+                    self.expect(b',')?;
+                }
+            }
+            Some(b'{') => {
+                self.position += 1;
+                let mut fields = Vec::new();
+                if self.eat(b'}') {
+                    return Ok(Json::Object(fields));
+                }
+                loop {
+                    self.skip_whitespace();
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    fields.push((key, self.value()?));
+                    if self.eat(b'}') {
+                        return Ok(Json::Object(fields));
+                    }
+                    self.expect(b',')?;
+                }
+            }
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.position;
+                self.position += 1;
+                while self.text.get(self.position).is_some_and(u8::is_ascii_digit) {
+                    self.position += 1;
+                }
+                let digits = std::str::from_utf8(&self.text[start..self.position]).unwrap_or_default();
+                digits.parse().map(Json::Number).map_err(|_| self.error("a whole number"))
+            }
+            _ => Err(self.error("a value")),
+        }
+    }
+
+    // Names and bus cycle kinds are plain ASCII, so escapes are taken as the character
+    fn string(&mut self) -> Result<String, VectorError> {
+        if self.text.get(self.position) != Some(&b'"') {
+            return Err(self.error("a string"));
+        }
+        self.position += 1;
+        let mut string = String::new();
+        loop {
+            match self.text.get(self.position) {
+                None => return Err(self.error("'\"'")),
+                Some(b'"') => break,
+                Some(b'\\') => self.position += 1,
+                Some(_) => {}
+            }
+            if let Some(&byte) = self.text.get(self.position) {
+                string.push(byte as char);
+                self.position += 1;
+            }
+        }
+        self.position += 1;
+        Ok(string)
+    }
+}
+
+fn parse_json(text: &str) -> Result<Json, VectorError> {
+    let mut parser = JsonParser { text: text.as_bytes(), position: 0 };
+    let value = parser.value()?;
+    parser.skip_whitespace();
+    if parser.position != text.len() {
+        return Err(parser.error("the end"));
+    }
+    Ok(value)
+}
+
+fn field<'a>(object: &'a Json, name: &str) -> Result<&'a Json, VectorError> {
+    match object {
+        Json::Object(fields) => fields.iter().find(|(key, _)| key == name).map(|(_, value)| value),
+        _ => None,
+    }
+    .ok_or_else(|| VectorError::Parse(format!("missing \"{}\"", name)))
+}
+
+fn number(value: &Json) -> Result<i64, VectorError> {
+    match value {
+        Json::Number(number) => Ok(*number),
+        _ => Err(VectorError::Parse(format!("expected a number, found {:?}", value))),
+    }
+}
+
+fn array(value: &Json) -> Result<&[Json], VectorError> {
+    match value {
+        Json::Array(items) => Ok(items),
+        _ => Err(VectorError::Parse(format!("expected an array, found {:?}", value))),
+    }
+}
+
+fn parse_state(state: &Json) -> Result<VectorState, VectorError> {
+    let register = |name| number(field(state, name)?).map(|value| value as u8);
+    let ram = array(field(state, "ram")?)?
+        .iter()
+        .map(|entry| match array(entry)? {
+            [address, value] => Ok((number(address)? as u16, number(value)? as u8)),
+            _ => Err(VectorError::Parse("expected [address, value]".to_string())),
+        })
+        .collect::<Result<_, _>>()?;
+    Ok(VectorState {
+        pc: number(field(state, "pc")?)? as u16,
+        s: register("s")?,
+        a: register("a")?,
+        x: register("x")?,
+        y: register("y")?,
+        p: register("p")?,
+        ram,
+    })
+}
+
+// Parses one of the vector files, a JSON array of cases
+pub fn parse_cases(text: &str) -> Result<Vec<VectorCase>, VectorError> {
+    let json = parse_json(text)?;
+    array(&json)?
+        .iter()
+        .map(|case| {
+            let name = match field(case, "name")? {
+                Json::String(name) => name.clone(),
+                _ => return Err(VectorError::Parse("expected \"name\" to be a string".to_string())),
+            };
+            let bus = array(field(case, "cycles")?)?
+                .iter()
+                .map(|cycle| match array(cycle)? {
+                    [address, value, Json::String(kind)] => Ok(BusCycle {
+                        address: number(address)? as u16,
+                        value: number(value)? as u8,
+                        write: kind == "write",
+                    }),
+                    _ => Err(VectorError::Parse("expected [address, value, kind]".to_string())),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(VectorCase {
+                name,
+                initial: parse_state(field(case, "initial")?)?,
+                expected: parse_state(field(case, "final")?)?,
+                bus,
+            })
+        })
+        .collect()
+}
+
+// 64KB of RAM that remembers every access made to it
+struct VectorBus {
+    memory: Vec<u8>,
+    cycles: Vec<BusCycle>,
+}
+
+impl Bus for VectorBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.cycles.push(BusCycle { address, value, write: false });
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.cycles.push(BusCycle { address, value, write: true });
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+}
+
+// Runs the case's instruction from its initial state and compares registers, the
+// RAM it lists, the bus accesses and the cycle count with the expected ones
+pub fn run_case(case: &VectorCase) -> Result<(), VectorError> {
+    let mut bus = VectorBus { memory: vec![0; 0x10000], cycles: Vec::new() };
+    for &(address, value) in &case.initial.ram {
+        bus.memory[address as usize] = value;
+    }
+    let mut cpu = Cpu::with_bus(bus);
+    let initial = &case.initial;
+    (cpu.pc, cpu.sp, cpu.a, cpu.x, cpu.y) = (initial.pc, initial.s, initial.a, initial.x, initial.y);
+    cpu.p = StatusFlag::from_bits_truncate(initial.p);
+
+    let cycles = cpu.step() as usize;
+    if cpu.fault.is_some() {
+        return Err(VectorError::Unsupported(cpu.bus.memory[initial.pc as usize]));
+    }
+
+    let expected = &case.expected;
+    let mut differences = Vec::new();
+    let mut compare = |what: &str, expected: u16, actual: u16| {
+        if expected != actual {
+            differences.push(format!("{} expected {:02X}, was {:02X}", what, expected, actual));
+        }
+    };
+    compare("PC", expected.pc, cpu.pc);
+    compare("S", expected.s as u16, cpu.sp as u16);
+    compare("A", expected.a as u16, cpu.a as u16);
+    compare("X", expected.x as u16, cpu.x as u16);
+    compare("Y", expected.y as u16, cpu.y as u16);
+    compare("P", (expected.p & !IGNORED_FLAGS) as u16, (cpu.p.bits() & !IGNORED_FLAGS) as u16);
+    compare("cycles", case.bus.len() as u16, cycles as u16);
+    for &(address, value) in &expected.ram {
+        compare(&format!("${:04X}", address), value as u16, cpu.bus.memory[address as usize] as u16);
+    }
+    // the first cycle that differs, or is missing on either side
+    let describe = |cycle: Option<&BusCycle>| cycle.map_or("nothing".to_string(), BusCycle::to_string);
+    if let Some(index) = (0..case.bus.len().max(cpu.bus.cycles.len())).find(|&index| case.bus.get(index) != cpu.bus.cycles.get(index)) {
+        let (expected, actual) = (describe(case.bus.get(index)), describe(cpu.bus.cycles.get(index)));
+        differences.push(format!("cycle {} expected {}, was {}", index + 1, expected, actual));
+    }
+
+    if differences.is_empty() {
+        Ok(())
+    } else {
+        Err(VectorError::Mismatch { name: case.name.clone(), differences })
+    }
+}
+
+// How the cases for one opcode went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpcodeResult {
+    pub opcode: u8,
+    pub cases: usize,
+    pub failed: usize,
+    pub first_failure: Option<String>,
+    pub unsupported: bool,
+}
+
+// Runs every case in a vector file, carrying on past failures to count them
+pub fn run_file(path: impl AsRef<Path>) -> Result<OpcodeResult, VectorError> {
+    let text = fs::read_to_string(path).map_err(VectorError::Io)?;
+    let cases = parse_cases(&text)?;
+    let opcode = cases.first().map_or(0, |case| {
+        case.initial.ram.iter().find(|(address, _)| *address == case.initial.pc).map_or(0, |(_, value)| *value)
+    });
+    let mut result = OpcodeResult { opcode, cases: cases.len(), failed: 0, first_failure: None, unsupported: false };
+    for case in &cases {
+        match run_case(case) {
+            Ok(()) => {}
+            Err(VectorError::Unsupported(_)) => {
+                result.unsupported = true;
+                break;
+            }
+            Err(error) => {
+                result.failed += 1;
+                result.first_failure.get_or_insert_with(|| error.to_string());
+            }
+        }
+    }
+    Ok(result)
+}
+
+// The summary of a directory of vector files, one per opcode
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VectorReport {
+    pub results: Vec<OpcodeResult>,
+}
+
+impl VectorReport {
+    pub fn failures(&self) -> impl Iterator<Item = &OpcodeResult> {
+        self.results.iter().filter(|result| result.failed > 0)
+    }
+
+    pub fn passed(&self) -> bool {
+        self.failures().next().is_none()
+    }
+}
+
+impl fmt::Display for VectorReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let unsupported: Vec<_> = self.results.iter().filter(|result| result.unsupported).collect();
+        let failed = self.failures().count();
+        let passed = self.results.len() - failed - unsupported.len();
+        write!(f, "{} opcodes passed, {} failed, {} not implemented", passed, failed, unsupported.len())?;
+        for result in self.failures() {
+            write!(f, "\n  {:02X}: {} of {} cases failed", result.opcode, result.failed, result.cases)?;
+            if let Some(failure) = &result.first_failure {
+                write!(f, ", first {}", failure)?;
+            }
+        }
+        if !unsupported.is_empty() {
+            let opcodes: Vec<_> = unsupported.iter().map(|result| format!("{:02X}", result.opcode)).collect();
+            write!(f, "\nnot implemented: {}", opcodes.join(" "))?;
+        }
+        Ok(())
+    }
+}
+
+// Runs every .json file in the directory, in opcode order
+pub fn run_dir(dir: impl AsRef<Path>) -> Result<VectorReport, VectorError> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).map_err(VectorError::Io)? {
+        let path = entry.map_err(VectorError::Io)?.path();
+        if path.extension().is_some_and(|extension| extension == "json") {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    let results = paths.iter().map(run_file).collect::<Result<_, _>>()?;
+    Ok(VectorReport { results })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // INC $10 from the nes6502 set's format, with the dummy write of the old value
+    const INC: &str = r#"[{"name": "e6 10 ff",
+        "initial": {"pc": 512, "s": 253, "a": 1, "x": 2, "y": 3, "p": 36, "ram": [[512, 230], [513, 16], [16, 127]]},
+        "final": {"pc": 514, "s": 253, "a": 1, "x": 2, "y": 3, "p": 164, "ram": [[512, 230], [513, 16], [16, 128]]},
+        "cycles": [[512, 230, "read"], [513, 16, "read"], [16, 127, "read"], [16, 127, "write"], [16, 128, "write"]]}]"#;
+
+    #[test]
+    fn test_run_case() {
+        let cases = parse_cases(INC).unwrap();
+        assert_eq!(cases.len(), 1);
+        assert_eq!(cases[0].bus.len(), 5);
+        run_case(&cases[0]).unwrap();
+
+        // reading an address the real CPU doesn't
+        let mut stray = cases[0].clone();
+        stray.bus.remove(2);
+        match run_case(&stray) {
+            Err(VectorError::Mismatch { differences, .. }) => {
+                assert_eq!(differences[1], "cycle 3 expected write $0010 = 7F, was read $0010 = 7F")
+            }
+            result => panic!("expected a mismatch, got {:?}", result),
+        }
+
+        // the dummy write of the old value left out
+        let mut collapsed = cases[0].clone();
+        collapsed.bus.remove(3);
+        collapsed.bus.push(BusCycle { address: 0x0011, value: 0, write: false });
+        match run_case(&collapsed) {
+            Err(VectorError::Mismatch { differences, .. }) => {
+                assert_eq!(differences, ["cycle 4 expected write $0010 = 80, was write $0010 = 7F"])
+            }
+            result => panic!("expected a mismatch, got {:?}", result),
+        }
+
+        let mut wrong = cases[0].clone();
+        wrong.expected.a = 2;
+        wrong.expected.ram[2].1 = 0x7F;
+        match run_case(&wrong) {
+            Err(VectorError::Mismatch { differences, .. }) => assert_eq!(differences.len(), 2),
+            result => panic!("expected a mismatch, got {:?}", result),
+        }
+
+        // KIL
+        let mut jam = cases[0].clone();
+        jam.initial.ram[0].1 = 0x02;
+        assert!(matches!(run_case(&jam), Err(VectorError::Unsupported(0x02))));
+        assert!(matches!(parse_cases("[{\"name\": 1}]"), Err(VectorError::Parse(_))));
+    }
+
+    #[test]
+    fn test_report() {
+        let result = |opcode, failed, unsupported| OpcodeResult {
+            opcode,
+            cases: 10000,
+            failed,
+            first_failure: (failed > 0).then(|| "\"69 01 02\": A expected 03, was 04".to_string()),
+            unsupported,
+        };
+        let report = VectorReport { results: vec![result(0x02, 0, true), result(0x69, 12, false), result(0xA9, 0, false)] };
+        assert!(!report.passed());
+        assert_eq!(
+            report.to_string(),
+            "1 opcodes passed, 1 failed, 1 not implemented\n  69: 12 of 10000 cases failed, first \"69 01 02\": A expected 03, was 04\nnot implemented: 02"
+        );
+    }
+}
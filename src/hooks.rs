@@ -0,0 +1,207 @@
+use std::ops::RangeInclusive;
+
+use crate::bus::NesBus;
+use crate::cpu::Cpu;
+use crate::ppu::SCANLINES_PER_FRAME;
+
+// Something that happened during an instruction, handed to the hooks that asked for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    // the PPU finished a frame
+    Frame,
+    // the PPU raised NMI, whether or not the CPU has taken it yet
+    Nmi,
+    // the PPU started this scanline
+    Scanline(u16),
+    // the CPU wrote to memory, including registers and the cartridge
+    MemoryWrite { address: u16, value: u8 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Trigger {
+    Frame,
+    Nmi,
+    Scanline(u16),
+    MemoryWrite(RangeInclusive<u16>),
+}
+
+impl Trigger {
+    fn matches(&self, event: &Event) -> bool {
+        match (self, event) {
+            (Trigger::Frame, Event::Frame) | (Trigger::Nmi, Event::Nmi) => true,
+            (Trigger::Scanline(wanted), Event::Scanline(scanline)) => wanted == scanline,
+            (Trigger::MemoryWrite(range), Event::MemoryWrite { address, .. }) => range.contains(address),
+            _ => false,
+        }
+    }
+}
+
+// Returned when subscribing, to unsubscribe with
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HookId(u64);
+
+// Callbacks get the CPU and through it the whole console, to read or patch memory
+pub type HookCallback = Box<dyn FnMut(&Event, &mut Cpu<NesBus>) + Send>;
+
+struct Hook {
+    id: HookId,
+    trigger: Trigger,
+    callback: HookCallback,
+}
+
+// Subscriptions to what the console does, shared by embedders, scripts and tools so
+// none of them needs its own hook point in the step loop. Events are collected over
+// an instruction and dispatched after it, in the order writes, scanlines, NMI, frame.
+#[derive(Default)]
+pub struct Hooks {
+    hooks: Vec<Hook>,
+    next_id: u64,
+    // the PPU's NMI output after the last instruction, to catch it rising
+    nmi: bool,
+}
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn add(&mut self, trigger: Trigger, callback: impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static) -> HookId {
+        let id = HookId(self.next_id);
+        self.next_id += 1;
+        self.hooks.push(Hook { id, trigger, callback: Box::new(callback) });
+        id
+    }
+
+    pub fn on_frame(&mut self, callback: impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static) -> HookId {
+        self.add(Trigger::Frame, callback)
+    }
+
+    pub fn on_nmi(&mut self, callback: impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static) -> HookId {
+        self.add(Trigger::Nmi, callback)
+    }
+
+    pub fn on_scanline(&mut self, scanline: u16, callback: impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static) -> HookId {
+        self.add(Trigger::Scanline(scanline), callback)
+    }
+
+    pub fn on_memory_write(
+        &mut self,
+        range: RangeInclusive<u16>,
+        callback: impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static,
+    ) -> HookId {
+        self.add(Trigger::MemoryWrite(range), callback)
+    }
+
+    // False when the hook was already removed
+    pub fn remove(&mut self, id: HookId) -> bool {
+        let count = self.hooks.len();
+        self.hooks.retain(|hook| hook.id != id);
+        self.hooks.len() != count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.hooks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.hooks.len()
+    }
+
+    // Whether the bus needs to log writes for the next instruction
+    pub fn watches_writes(&self) -> bool {
+        self.hooks.iter().any(|hook| matches!(hook.trigger, Trigger::MemoryWrite(_)))
+    }
+
+    pub fn dispatch(&mut self, event: &Event, cpu: &mut Cpu<NesBus>) {
+        for hook in self.hooks.iter_mut().filter(|hook| hook.trigger.matches(event)) {
+            (hook.callback)(event, cpu);
+        }
+    }
+
+    // Works out what the instruction just executed did, given where the PPU was before it
+    pub(crate) fn after_instruction(&mut self, cpu: &mut Cpu<NesBus>, frame: u64, scanline: u16, writes: &[(u16, u8)]) {
+        for &(address, value) in writes {
+            self.dispatch(&Event::MemoryWrite { address, value }, cpu);
+        }
+        // an OAM DMA stall can cross several scanlines
+        let mut line = scanline;
+        while line != cpu.bus.ppu.scanline {
+            line = (line + 1) % SCANLINES_PER_FRAME as u16;
+            self.dispatch(&Event::Scanline(line), cpu);
+        }
+        let nmi = cpu.bus.ppu.nmi_output();
+        if nmi && !self.nmi {
+            self.dispatch(&Event::Nmi, cpu);
+        }
+        self.nmi = nmi;
+        for _ in frame..cpu.bus.ppu.frame {
+            self.dispatch(&Event::Frame, cpu);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::rom::tests::ines_with_program;
+    use crate::rom::Rom;
+    use std::sync::{Arc, Mutex};
+
+    // LDA #$80; STA $2000; INC $10; JMP $8005, with an empty NMI handler
+    fn console() -> Nes {
+        let mut image = ines_with_program(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0xE6, 0x10, 0x4C, 0x05, 0x80]);
+        image[16 + 0x100] = 0x40;
+        image[16 + 0x3FFA..16 + 0x3FFC].copy_from_slice(&[0x00, 0x81]);
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&image).unwrap()).unwrap();
+        nes
+    }
+
+    type Recorded = Arc<Mutex<Vec<Event>>>;
+
+    fn recorder() -> (Recorded, impl FnMut(&Event, &mut Cpu<NesBus>) + Send + 'static) {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let recorded = events.clone();
+        (events, move |event: &Event, _: &mut Cpu<NesBus>| recorded.lock().unwrap().push(*event))
+    }
+
+    #[test]
+    fn test_frame_nmi_and_scanline() {
+        let mut nes = console();
+        let (events, record) = recorder();
+        nes.hooks_mut().on_frame(record);
+        let (nmis, record) = recorder();
+        nes.hooks_mut().on_nmi(record);
+        let (lines, record) = recorder();
+        nes.hooks_mut().on_scanline(100, record);
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        assert_eq!(events.lock().unwrap().len(), 3);
+        assert_eq!(nmis.lock().unwrap().len(), 3);
+        assert_eq!(*lines.lock().unwrap(), [Event::Scanline(100); 3]);
+    }
+
+    #[test]
+    fn test_memory_write() {
+        let mut nes = console();
+        let (writes, record) = recorder();
+        let id = nes.hooks_mut().on_memory_write(0x0010..=0x001F, record);
+        // callbacks can change memory too
+        nes.hooks_mut().on_memory_write(0x2000..=0x2007, |_, cpu| cpu.bus.ram[0x20] = 0x42);
+        for _ in 0..4 {
+            nes.step_instruction();
+        }
+        // INC writes the old value back before the new one, as on the real bus
+        let expected = [Event::MemoryWrite { address: 0x10, value: 0 }, Event::MemoryWrite { address: 0x10, value: 1 }];
+        assert_eq!(*writes.lock().unwrap(), expected);
+        assert_eq!(nes.peek(0x20), 0x42);
+
+        assert!(nes.hooks_mut().remove(id));
+        assert!(!nes.hooks_mut().remove(id));
+        assert!(nes.hooks_mut().watches_writes());
+        nes.step_frame();
+        assert_eq!(writes.lock().unwrap().len(), 2);
+    }
+}
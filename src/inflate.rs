@@ -0,0 +1,285 @@
+use std::fmt;
+
+// DEFLATE decoder (RFC 1951) for the compressed ROMs in .zip and .gz files.
+// Huffman codes are decoded a bit at a time from their canonical counts, which is
+// plenty fast for files the size of a cartridge.
+
+const MAX_BITS: usize = 15;
+const END_OF_BLOCK: u16 = 256;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145,
+    8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// Order the code length code lengths are sent in
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InflateError {
+    UnexpectedEnd,
+    InvalidBlockType,
+    InvalidStoredLength,
+    InvalidCode,
+    InvalidDistance,
+    // more data than the caller said to expect
+    TooLong,
+}
+
+impl fmt::Display for InflateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InflateError::UnexpectedEnd => write!(f, "Compressed data ends unexpectedly"),
+            InflateError::InvalidBlockType => write!(f, "Invalid deflate block type"),
+            InflateError::InvalidStoredLength => write!(f, "Stored block length does not match its complement"),
+            InflateError::InvalidCode => write!(f, "Invalid Huffman code"),
+            InflateError::InvalidDistance => write!(f, "Back reference before the start of the data"),
+            InflateError::TooLong => write!(f, "Decompressed data is longer than expected"),
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    bit: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, position: 0, bit: 0 }
+    }
+
+    // Reads `count` bits, least significant first
+    fn bits(&mut self, count: u32) -> Result<u32, InflateError> {
+        let mut value = 0;
+        for index in 0..count {
+            let byte = *self.data.get(self.position).ok_or(InflateError::UnexpectedEnd)?;
+            value |= ((byte as u32 >> self.bit) & 1) << index;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.position += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align_to_byte(&mut self) {
+        if self.bit != 0 {
+            self.bit = 0;
+            self.position += 1;
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8], InflateError> {
+        let bytes = self.data.get(self.position..self.position + count).ok_or(InflateError::UnexpectedEnd)?;
+        self.position += count;
+        Ok(bytes)
+    }
+}
+
+// A canonical Huffman code: how many codes of each length, and the symbols in code order
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut counts = [0; MAX_BITS + 1];
+        for &length in lengths {
+            counts[length as usize] += 1;
+        }
+        counts[0] = 0;
+        let mut offsets = [0; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; offsets[MAX_BITS + 1] as usize];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbols[offsets[length as usize] as usize] = symbol as u16;
+                offsets[length as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> Result<u16, InflateError> {
+        // codes of each length are consecutive, so track the first code and index of the current length
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for length in 1..=MAX_BITS {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[length] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(InflateError::InvalidCode)
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic_codes(input: &mut BitReader) -> Result<(Huffman, Huffman), InflateError> {
+    let literal_count = input.bits(5)? as usize + 257;
+    let distance_count = input.bits(5)? as usize + 1;
+    let code_length_count = input.bits(4)? as usize + 4;
+
+    let mut code_lengths = [0u8; 19];
+    for &index in &CODE_LENGTH_ORDER[..code_length_count] {
+        code_lengths[index] = input.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&code_lengths);
+
+    let mut lengths = vec![0u8; literal_count + distance_count];
+    let mut index = 0;
+    while index < lengths.len() {
+        let symbol = code_length_code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..index].last().ok_or(InflateError::InvalidCode)?;
+                (previous, 3 + input.bits(2)? as usize)
+            }
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize),
+        };
+        let end = index + repeat;
+        lengths.get_mut(index..end).ok_or(InflateError::InvalidCode)?.fill(value);
+        index = end;
+    }
+    let (literals, distances) = lengths.split_at(literal_count);
+    Ok((Huffman::new(literals), Huffman::new(distances)))
+}
+
+// Errors instead of growing `output` past `limit`
+fn reserve(output: &[u8], count: usize, limit: usize) -> Result<(), InflateError> {
+    match output.len().checked_add(count) {
+        Some(length) if length <= limit => Ok(()),
+        _ => Err(InflateError::TooLong),
+    }
+}
+
+fn inflate_block(input: &mut BitReader, output: &mut Vec<u8>, codes: &(Huffman, Huffman), limit: usize) -> Result<(), InflateError> {
+    let (literals, distances) = codes;
+    loop {
+        let symbol = literals.decode(input)?;
+        match symbol {
+            0..=255 => {
+                reserve(output, 1, limit)?;
+                output.push(symbol as u8);
+            }
+            END_OF_BLOCK => return Ok(()),
+            _ => {
+                let index = symbol as usize - 257;
+                let base = *LENGTH_BASE.get(index).ok_or(InflateError::InvalidCode)? as usize;
+                let length = base + input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                let index = distances.decode(input)? as usize;
+                let base = *DISTANCE_BASE.get(index).ok_or(InflateError::InvalidCode)? as usize;
+                let distance = base + input.bits(DISTANCE_EXTRA[index] as u32)? as usize;
+                if distance > output.len() {
+                    return Err(InflateError::InvalidDistance);
+                }
+                reserve(output, length, limit)?;
+                // the copy may overlap what it writes, so go a byte at a time
+                let start = output.len() - distance;
+                for offset in 0..length {
+                    output.push(output[start + offset]);
+                }
+            }
+        }
+    }
+}
+
+// Decompresses a raw DEFLATE stream
+pub fn inflate(data: &[u8]) -> Result<Vec<u8>, InflateError> {
+    inflate_limited(data, usize::MAX)
+}
+
+// Decompresses a stream whose size is known up front, giving up as soon as it
+// makes more than `limit` bytes instead of filling memory
+pub fn inflate_limited(data: &[u8], limit: usize) -> Result<Vec<u8>, InflateError> {
+    let mut input = BitReader::new(data);
+    let mut output = Vec::new();
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align_to_byte();
+                let header = input.bytes(4)?;
+                let length = u16::from_le_bytes([header[0], header[1]]);
+                if length != !u16::from_le_bytes([header[2], header[3]]) {
+                    return Err(InflateError::InvalidStoredLength);
+                }
+                reserve(&output, length as usize, limit)?;
+                output.extend_from_slice(input.bytes(length as usize)?);
+            }
+            1 => inflate_block(&mut input, &mut output, &fixed_codes(), limit)?,
+            2 => {
+                let codes = dynamic_codes(&mut input)?;
+                inflate_block(&mut input, &mut output, &codes, limit)?;
+            }
+            _ => return Err(InflateError::InvalidBlockType),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_block() {
+        let data = [0x01, 0x03, 0x00, 0xFC, 0xFF, b'N', b'E', b'S'];
+        assert_eq!(inflate(&data).unwrap(), b"NES");
+        let data = [0x01, 0x03, 0x00, 0x00, 0x00, b'N', b'E', b'S'];
+        assert_eq!(inflate(&data), Err(InflateError::InvalidStoredLength));
+    }
+
+    #[test]
+    fn test_fixed_codes() {
+        // zlib's raw deflate of "madNES madNES madNES", with a back reference
+        let data = [0xCB, 0x4D, 0x4C, 0xF1, 0x73, 0x0D, 0x56, 0xC8, 0x45, 0xA6, 0x00];
+        assert_eq!(inflate(&data).unwrap(), b"madNES madNES madNES");
+        assert_eq!(inflate_limited(&data, 20).unwrap(), b"madNES madNES madNES");
+        // stopped partway through the back reference
+        assert_eq!(inflate_limited(&data, 19), Err(InflateError::TooLong));
+    }
+
+    #[test]
+    fn test_dynamic_codes() {
+        // zlib's raw deflate of 300 pseudo-random letters
+        let data = [
+            0xE5, 0xCB, 0x49, 0x01, 0xC0, 0x40, 0x10, 0x02, 0x30, 0x2B, 0x58, 0xE3, 0x58, 0x18, 0xFF, 0x0A, 0x2A, 0xA4,
+            0xF9, 0x87, 0x7A, 0xBC, 0x63, 0x2D, 0x57, 0xA0, 0x17, 0x67, 0x91, 0x72, 0x2F, 0xC5, 0xB3, 0x1F, 0xD6, 0xB1,
+            0xC9, 0x74, 0x3B, 0xAD, 0x3D, 0xE3, 0x10, 0x6C, 0x08, 0xF9, 0xA7, 0xFE, 0x01,
+        ];
+        let expected: Vec<u8> = (0..300).map(|i| b"abcdefgh "[(i * i + i / 7) % 9]).collect();
+        assert_eq!(inflate(&data).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(inflate(&[]), Err(InflateError::UnexpectedEnd));
+        assert_eq!(inflate(&[0x07]), Err(InflateError::InvalidBlockType));
+    }
+}
@@ -0,0 +1,399 @@
+use bitflags::bitflags;
+
+use crate::joypad::{Joypad, JoypadButton};
+use crate::ppu::Ppu;
+use crate::savestate::{SaveState, StateError, StateReader};
+use crate::zapper::Zapper;
+
+// Whatever is plugged into a controller port. Bit 0 of $4016 writes is the strobe,
+// wired to both ports; reads of $4016/$4017 return the data lines of port 1/2.
+// Devices only drive the low five bits, the bus fills in the rest with open bus.
+pub trait InputDevice: SaveState + Send {
+    fn strobe(&mut self, value: u8);
+
+    fn read(&mut self, ppu: &Ppu) -> u8;
+
+    // Reads without shifting anything out
+    fn peek(&self, ppu: &Ppu) -> u8;
+
+    fn device(&self) -> Device;
+
+    // Host input. Each device takes the kinds it has a use for and ignores the rest.
+    fn set_buttons(&mut self, _buttons: JoypadButton) {}
+
+    fn buttons(&self) -> JoypadButton {
+        JoypadButton::empty()
+    }
+
+    // Where the mouse points in NES pixels, None when it's off screen
+    fn set_pointer(&mut self, _position: Option<(usize, usize)>, _buttons: MouseButton) {}
+
+    // Mouse movement since the last call, in host pixels
+    fn move_pointer(&mut self, _dx: i32, _dy: i32, _buttons: MouseButton) {}
+
+    // The squares stood on, for mats
+    fn set_mat(&mut self, _buttons: PowerPadButton) {}
+}
+
+bitflags! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct MouseButton: u8 {
+        const Left = 1 << 0;
+        const Right = 1 << 1;
+    }
+}
+
+bitflags! {
+    // Power Pad squares by the numbers printed on side B:
+    //    1  2  3  4
+    //    5  6  7  8
+    //    9 10 11 12
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct PowerPadButton: u16 {
+        const B1 = 1 << 0;
+        const B2 = 1 << 1;
+        const B3 = 1 << 2;
+        const B4 = 1 << 3;
+        const B5 = 1 << 4;
+        const B6 = 1 << 5;
+        const B7 = 1 << 6;
+        const B8 = 1 << 7;
+        const B9 = 1 << 8;
+        const B10 = 1 << 9;
+        const B11 = 1 << 10;
+        const B12 = 1 << 11;
+    }
+}
+
+impl PowerPadButton {
+    // "button1" to "button12"
+    pub fn parse(name: &str) -> Option<PowerPadButton> {
+        let number: u16 = name.to_ascii_lowercase().strip_prefix("button")?.parse().ok()?;
+        (1..=12).contains(&number).then(|| PowerPadButton::from_bits_truncate(1 << (number - 1)))
+    }
+
+    fn number(number: u8) -> PowerPadButton {
+        PowerPadButton::from_bits_truncate(1 << (number - 1))
+    }
+}
+
+// The devices madNES can plug into a port, as named in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Joypad,
+    Zapper,
+    ArkanoidPaddle,
+    SnesMouse,
+    PowerPad,
+}
+
+impl Device {
+    pub fn parse(name: &str) -> Option<Device> {
+        match name.to_ascii_lowercase().as_str() {
+            "joypad" => Some(Device::Joypad),
+            "zapper" => Some(Device::Zapper),
+            "arkanoid" => Some(Device::ArkanoidPaddle),
+            "mouse" => Some(Device::SnesMouse),
+            "powerpad" => Some(Device::PowerPad),
+            _ => None,
+        }
+    }
+
+    pub fn create(self) -> Box<dyn InputDevice> {
+        match self {
+            Device::Joypad => Box::new(Joypad::default()),
+            Device::Zapper => Box::new(Zapper::new()),
+            Device::ArkanoidPaddle => Box::new(ArkanoidPaddle::new()),
+            Device::SnesMouse => Box::new(SnesMouse::new()),
+            Device::PowerPad => Box::new(PowerPad::default()),
+        }
+    }
+}
+
+// The NES Arkanoid "Vaus" controller turns the mouse's X movement into its knob
+const PADDLE_MIN: u8 = 0x62;
+const PADDLE_MAX: u8 = 0xF2;
+const PADDLE_FIRE: u8 = 1 << 3;
+const PADDLE_DATA_SHIFT: u8 = 4;
+
+// Arkanoid controller: a knob read as 8 bits, inverted and MSB first, on D4, and the
+// fire button on D3. The strobe latches the knob's position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArkanoidPaddle {
+    pub position: u8,
+    pub fire: bool,
+    pub strobe: bool,
+    pub shift: u8,
+}
+
+impl Default for ArkanoidPaddle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ArkanoidPaddle {
+    pub fn new() -> Self {
+        let middle = PADDLE_MIN + (PADDLE_MAX - PADDLE_MIN) / 2;
+        ArkanoidPaddle { position: middle, fire: false, strobe: false, shift: 0 }
+    }
+}
+
+impl InputDevice for ArkanoidPaddle {
+    fn strobe(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.shift = self.position;
+        }
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        let value = self.peek(ppu);
+        if !self.strobe {
+            self.shift <<= 1;
+        }
+        value
+    }
+
+    fn peek(&self, _ppu: &Ppu) -> u8 {
+        let data = (!self.shift >> 7) & 0x01;
+        data << PADDLE_DATA_SHIFT | if self.fire { PADDLE_FIRE } else { 0 }
+    }
+
+    fn device(&self) -> Device {
+        Device::ArkanoidPaddle
+    }
+
+    fn move_pointer(&mut self, dx: i32, _dy: i32, buttons: MouseButton) {
+        self.position = (self.position as i32 + dx).clamp(PADDLE_MIN as i32, PADDLE_MAX as i32) as u8;
+        self.fire = buttons.contains(MouseButton::Left);
+    }
+}
+
+impl SaveState for ArkanoidPaddle {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.strobe as u8, self.shift]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = input.read_u8()? != 0;
+        self.shift = input.read_u8()?;
+        Ok(())
+    }
+}
+
+const MOUSE_REPORT_BITS: u8 = 32;
+const MOUSE_SIGNATURE: u32 = 0x01;
+const MOUSE_MAX_MOTION: i32 = 0x7F;
+
+// Super NES mouse through an adapter: a 32 bit report on D0, MSB first. After a zero
+// byte come the buttons and the 0001 signature, then Y and X motion since the last
+// report as sign and magnitude, 1 meaning up or left. Reads past the end return 1.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SnesMouse {
+    pub buttons: MouseButton,
+    // movement not reported yet
+    pub dx: i32,
+    pub dy: i32,
+    pub strobe: bool,
+    pub report: u32,
+    pub index: u8,
+}
+
+impl SnesMouse {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn latch(&mut self) {
+        let motion = |delta: i32| ((delta < 0) as u32) << 7 | delta.unsigned_abs().min(MOUSE_MAX_MOTION as u32);
+        let buttons = (self.buttons.contains(MouseButton::Right) as u32) << 7 | (self.buttons.contains(MouseButton::Left) as u32) << 6;
+        self.report = (buttons | MOUSE_SIGNATURE) << 16 | motion(self.dy) << 8 | motion(self.dx);
+        self.dx = 0;
+        self.dy = 0;
+        self.index = 0;
+    }
+}
+
+impl InputDevice for SnesMouse {
+    fn strobe(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        let value = self.peek(ppu);
+        if !self.strobe && self.index < MOUSE_REPORT_BITS {
+            self.index += 1;
+        }
+        value
+    }
+
+    fn peek(&self, _ppu: &Ppu) -> u8 {
+        if self.index >= MOUSE_REPORT_BITS {
+            1
+        } else {
+            (self.report >> (MOUSE_REPORT_BITS - 1 - self.index)) as u8 & 0x01
+        }
+    }
+
+    fn device(&self) -> Device {
+        Device::SnesMouse
+    }
+
+    fn move_pointer(&mut self, dx: i32, dy: i32, buttons: MouseButton) {
+        self.dx += dx;
+        self.dy += dy;
+        self.buttons = buttons;
+    }
+}
+
+impl SaveState for SnesMouse {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.strobe as u8, self.index]);
+        out.extend_from_slice(&self.report.to_le_bytes());
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = input.read_u8()? != 0;
+        self.index = input.read_u8()?;
+        self.report = u32::from_le_bytes(input.read_bytes(4)?.try_into().unwrap());
+        Ok(())
+    }
+}
+
+// The order the Power Pad's two shift registers report its squares in
+const POWER_PAD_D3_ORDER: [u8; 8] = [2, 1, 5, 9, 6, 10, 11, 7];
+const POWER_PAD_D4_ORDER: [u8; 4] = [4, 3, 12, 8];
+const POWER_PAD_D3: u8 = 1 << 3;
+const POWER_PAD_D4: u8 = 1 << 4;
+
+// Power Pad (Family Trainer mat): the strobe latches the squares into two shift
+// registers read out on D3 and D4, 1 for pressed. Once a register is empty it reads 1.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PowerPad {
+    pub buttons: PowerPadButton,
+    pub strobe: bool,
+    pub shift: [u8; 2],
+}
+
+impl PowerPad {
+    fn latch(&mut self) {
+        let pressed = |order: &[u8]| {
+            order.iter().enumerate().fold(0, |bits, (index, &number)| {
+                bits | (self.buttons.contains(PowerPadButton::number(number)) as u8) << index
+            })
+        };
+        // the D4 register is half as long, the rest of it reads as 1
+        self.shift = [pressed(&POWER_PAD_D3_ORDER), pressed(&POWER_PAD_D4_ORDER) | 0xF0];
+    }
+}
+
+impl InputDevice for PowerPad {
+    fn strobe(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.latch();
+        }
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        let value = self.peek(ppu);
+        if !self.strobe {
+            self.shift = self.shift.map(|shift| shift >> 1 | 0x80);
+        }
+        value
+    }
+
+    fn peek(&self, _ppu: &Ppu) -> u8 {
+        let d3 = if self.shift[0] & 0x01 != 0 { POWER_PAD_D3 } else { 0 };
+        let d4 = if self.shift[1] & 0x01 != 0 { POWER_PAD_D4 } else { 0 };
+        d3 | d4
+    }
+
+    fn device(&self) -> Device {
+        Device::PowerPad
+    }
+
+    fn set_mat(&mut self, buttons: PowerPadButton) {
+        self.buttons = buttons;
+    }
+}
+
+impl SaveState for PowerPad {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.strobe as u8, self.shift[0], self.shift[1]]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = input.read_u8()? != 0;
+        self.shift = [input.read_u8()?, input.read_u8()?];
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_bits(device: &mut dyn InputDevice, count: usize) -> Vec<u8> {
+        let ppu = Ppu::new();
+        device.strobe(1);
+        device.strobe(0);
+        (0..count).map(|_| device.read(&ppu)).collect()
+    }
+
+    #[test]
+    fn test_arkanoid_paddle() {
+        let mut paddle = ArkanoidPaddle::new();
+        paddle.move_pointer(-1000, 0, MouseButton::Left);
+        assert_eq!(paddle.position, PADDLE_MIN);
+        paddle.move_pointer(0x10, 0, MouseButton::Left);
+        // 0x72 = 0111 0010, sent inverted on D4 with fire held on D3
+        let bits: Vec<u8> = read_bits(&mut paddle, 8).iter().map(|value| value >> PADDLE_DATA_SHIFT).collect();
+        assert_eq!(bits, [1, 0, 0, 0, 1, 1, 0, 1]);
+        assert_eq!(paddle.peek(&Ppu::new()) & PADDLE_FIRE, PADDLE_FIRE);
+        paddle.move_pointer(1000, 0, MouseButton::empty());
+        assert_eq!((paddle.position, paddle.fire), (PADDLE_MAX, false));
+    }
+
+    #[test]
+    fn test_snes_mouse() {
+        let mut mouse = SnesMouse::new();
+        mouse.move_pointer(3, -200, MouseButton::Left);
+        let bits = read_bits(&mut mouse, 33);
+        let byte = |index: usize| bits[index * 8..index * 8 + 8].iter().fold(0, |byte, bit| byte << 1 | bit);
+        assert_eq!((byte(0), byte(1), byte(2), byte(3)), (0x00, 0x41, 0xFF, 0x03));
+        assert_eq!(bits[32], 1);
+        // the motion was used up by the report
+        let bits = read_bits(&mut mouse, 32);
+        assert!(bits[16..].iter().all(|&bit| bit == 0));
+    }
+
+    #[test]
+    fn test_create() {
+        for name in ["joypad", "zapper", "arkanoid", "mouse", "powerpad"] {
+            let device = Device::parse(name).unwrap();
+            assert_eq!(device.create().device(), device);
+        }
+        assert_eq!(Device::parse("rob"), None);
+    }
+
+    #[test]
+    fn test_power_pad() {
+        assert_eq!(PowerPadButton::parse("Button12"), Some(PowerPadButton::B12));
+        assert_eq!(PowerPadButton::parse("button13"), None);
+
+        let mut pad = PowerPad::default();
+        pad.set_mat(PowerPadButton::B1 | PowerPadButton::B7 | PowerPadButton::B3);
+        let bits = read_bits(&mut pad, 9);
+        let d3: Vec<bool> = bits.iter().map(|value| value & POWER_PAD_D3 != 0).collect();
+        let d4: Vec<bool> = bits.iter().map(|value| value & POWER_PAD_D4 != 0).collect();
+        // 2 1 5 9 6 10 11 7, then 1s
+        assert_eq!(d3, [false, true, false, false, false, false, false, true, true]);
+        // 4 3 12 8, then 1s
+        assert_eq!(d4, [false, true, false, false, true, true, true, true, true]);
+    }
+}
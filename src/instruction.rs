@@ -1,22 +1,253 @@
 use crate::cpu::AddressingMode;
 
+// What an instruction does, independent of how it addresses its operand
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Adc,
+    And,
+    Asl,
+    Bcc,
+    Bcs,
+    Beq,
+    Bit,
+    Bmi,
+    Bne,
+    Bpl,
+    Brk,
+    Bvc,
+    Bvs,
+    Clc,
+    Cld,
+    Cli,
+    Clv,
+    Cmp,
+    Cpx,
+    Cpy,
+    Dec,
+    Dex,
+    Dey,
+    Eor,
+    Inc,
+    Inx,
+    Iny,
+    Jmp,
+    Jsr,
+    Lda,
+    Ldx,
+    Ldy,
+    Lsr,
+    Nop,
+    Ora,
+    Pha,
+    Php,
+    Pla,
+    Plp,
+    Rol,
+    Ror,
+    Rti,
+    Rts,
+    Sbc,
+    Sec,
+    Sed,
+    Sei,
+    Sta,
+    Stx,
+    Sty,
+    Tax,
+    Tay,
+    Tsx,
+    Txa,
+    Txs,
+    Tya,
+    // unofficial
+    Lax,
+    Sax,
+    Dcp,
+    Isb,
+    Slo,
+    Rla,
+    Sre,
+    Rra,
+}
+
+impl Operation {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Operation::Adc => "ADC",
+            Operation::And => "AND",
+            Operation::Asl => "ASL",
+            Operation::Bcc => "BCC",
+            Operation::Bcs => "BCS",
+            Operation::Beq => "BEQ",
+            Operation::Bit => "BIT",
+            Operation::Bmi => "BMI",
+            Operation::Bne => "BNE",
+            Operation::Bpl => "BPL",
+            Operation::Brk => "BRK",
+            Operation::Bvc => "BVC",
+            Operation::Bvs => "BVS",
+            Operation::Clc => "CLC",
+            Operation::Cld => "CLD",
+            Operation::Cli => "CLI",
+            Operation::Clv => "CLV",
+            Operation::Cmp => "CMP",
+            Operation::Cpx => "CPX",
+            Operation::Cpy => "CPY",
+            Operation::Dec => "DEC",
+            Operation::Dex => "DEX",
+            Operation::Dey => "DEY",
+            Operation::Eor => "EOR",
+            Operation::Inc => "INC",
+            Operation::Inx => "INX",
+            Operation::Iny => "INY",
+            Operation::Jmp => "JMP",
+            Operation::Jsr => "JSR",
+            Operation::Lda => "LDA",
+            Operation::Ldx => "LDX",
+            Operation::Ldy => "LDY",
+            Operation::Lsr => "LSR",
+            Operation::Nop => "NOP",
+            Operation::Ora => "ORA",
+            Operation::Pha => "PHA",
+            Operation::Php => "PHP",
+            Operation::Pla => "PLA",
+            Operation::Plp => "PLP",
+            Operation::Rol => "ROL",
+            Operation::Ror => "ROR",
+            Operation::Rti => "RTI",
+            Operation::Rts => "RTS",
+            Operation::Sbc => "SBC",
+            Operation::Sec => "SEC",
+            Operation::Sed => "SED",
+            Operation::Sei => "SEI",
+            Operation::Sta => "STA",
+            Operation::Stx => "STX",
+            Operation::Sty => "STY",
+            Operation::Tax => "TAX",
+            Operation::Tay => "TAY",
+            Operation::Tsx => "TSX",
+            Operation::Txa => "TXA",
+            Operation::Txs => "TXS",
+            Operation::Tya => "TYA",
+            Operation::Lax => "LAX",
+            Operation::Sax => "SAX",
+            Operation::Dcp => "DCP",
+            Operation::Isb => "ISB",
+            Operation::Slo => "SLO",
+            Operation::Rla => "RLA",
+            Operation::Sre => "SRE",
+            Operation::Rra => "RRA",
+        }
+    }
+}
+
 pub struct Instruction {
-    // fixed set of strings for the instructions that are stored in the binary
-    pub mnemonic: &'static str,
+    pub operation: Operation,
     pub opcode: u8,
     pub addressing_mode: AddressingMode,
     pub cycles: u8,
     pub bytes: u8,
+    // false for the undocumented opcodes some games (and nestest) rely on
+    pub official: bool,
 }
 
 impl Instruction {
-    pub fn new(mnemonic: &'static str, opcode: u8, addressing_mode: AddressingMode, cycles: u8, bytes: u8) -> Self {
+    pub fn new(operation: Operation, opcode: u8, addressing_mode: AddressingMode, cycles: u8, bytes: u8) -> Self {
         Instruction {
-            mnemonic,
+            operation,
             opcode,
             addressing_mode,
             cycles,
             bytes,
+            official: true,
+        }
+    }
+
+    pub fn unofficial(operation: Operation, opcode: u8, addressing_mode: AddressingMode, cycles: u8, bytes: u8) -> Self {
+        Instruction {
+            official: false,
+            ..Instruction::new(operation, opcode, addressing_mode, cycles, bytes)
         }
     }
-}
\ No newline at end of file
+
+    pub fn mnemonic(&self) -> &'static str {
+        self.operation.mnemonic()
+    }
+
+    // Whether executing the instruction reads its operand from memory
+    pub fn reads_memory(&self) -> bool {
+        match self.addressing_mode {
+            AddressingMode::None
+            | AddressingMode::Implied
+            | AddressingMode::Accumulator
+            | AddressingMode::Immediate
+            | AddressingMode::Relative => false,
+            _ => matches!(
+                self.operation,
+                Operation::Adc | Operation::And | Operation::Bit | Operation::Cmp | Operation::Cpx | Operation::Cpy | Operation::Eor | Operation::Lda | Operation::Ldx | Operation::Ldy | Operation::Ora | Operation::Sbc | Operation::Lax
+            ) || self.is_read_modify_write(),
+        }
+    }
+
+    // Whether executing the instruction writes its operand back to memory
+    pub fn writes_memory(&self) -> bool {
+        match self.addressing_mode {
+            AddressingMode::None
+            | AddressingMode::Implied
+            | AddressingMode::Accumulator
+            | AddressingMode::Immediate
+            | AddressingMode::Relative => false,
+            _ => matches!(self.operation, Operation::Sta | Operation::Stx | Operation::Sty | Operation::Sax) || self.is_read_modify_write(),
+        }
+    }
+
+    fn is_read_modify_write(&self) -> bool {
+        matches!(
+            self.operation,
+            Operation::Asl | Operation::Lsr | Operation::Rol | Operation::Ror | Operation::Inc | Operation::Dec | Operation::Slo | Operation::Rla | Operation::Sre | Operation::Rra | Operation::Dcp | Operation::Isb
+        )
+    }
+}
+
+// Opcode lookup, indexed directly by the opcode byte
+pub struct InstructionTable {
+    entries: Vec<Option<Instruction>>,
+}
+
+impl Default for InstructionTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InstructionTable {
+    pub fn new() -> Self {
+        InstructionTable { entries: (0..=u8::MAX).map(|_| None).collect() }
+    }
+
+    pub fn insert(&mut self, opcode: u8, instruction: Instruction) {
+        self.entries[opcode as usize] = Some(instruction);
+    }
+
+    pub fn get(&self, opcode: u8) -> Option<&Instruction> {
+        self.entries[opcode as usize].as_ref()
+    }
+
+    // The opcode for a mnemonic and addressing mode, preferring the official one
+    // where an undocumented opcode does the same
+    pub fn find(&self, mnemonic: &str, addressing_mode: AddressingMode) -> Option<&Instruction> {
+        let mut matching = self.entries.iter().flatten().filter(|instruction| {
+            instruction.mnemonic() == mnemonic && instruction.addressing_mode == addressing_mode
+        });
+        let first = matching.next()?;
+        Some(if first.official { first } else { matching.find(|instruction| instruction.official).unwrap_or(first) })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.iter().flatten().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt;
+
+use crate::cpu::RAM_SIZE;
+use crate::hash::{from_hex, to_hex};
+use crate::inflate::{inflate, InflateError};
+use crate::nes::Nes;
+use crate::remote::{escape, parse_object, JsonValue};
+
+// Moving progress between emulators. Nobody's savestates line up with anyone
+// else's past the memory the game itself keeps, so what travels is RAM and PRG RAM.
+//
+// export() writes one flat JSON object: the registers as numbers, memory as upper
+// case hex strings and the game's CRC-32 (of PRG and CHR ROM, as in games.toml)
+//
+//   {"format": "madnes-interchange", "version": 1, "rom_crc32": "1A2B3C4D",
+//    "pc": 49152, "a": 0, "x": 0, "y": 0, "s": 253, "p": 36, "cycles": 7,
+//    "ppu_ctrl": 0, "ppu_mask": 0, "ppu_status": 0, "oam_address": 0,
+//    "v": 0, "t": 0, "fine_x": 0, "write_toggle": false, "scanline": 0, "dot": 21, "frame": 0,
+//    "ram": "...", "prg_ram": "...", "vram": "...", "palette": "...", "oam": "..."}
+//
+// prg_ram is null for boards without any. import() reads these back, and FCEUX's
+// .fc0-.fc9 states. Mesen's states are recognised but not read.
+pub const FORMAT: &str = "madnes-interchange";
+pub const VERSION: i64 = 1;
+
+// FCEUX: "FCSX", the size of the state data, the FCEUX version and the size of the
+// zlib compressed data, 0xFFFFFFFF when it isn't. Older versions wrote "FCS" and a
+// version byte, uncompressed.
+const FCEUX_MAGIC: &[u8] = b"FCSX";
+const FCEUX_OLD_MAGIC: &[u8] = b"FCS";
+const FCEUX_HEADER_SIZE: usize = 16;
+const FCEUX_UNCOMPRESSED: u32 = 0xFFFF_FFFF;
+// The CPU section, whose "RAM" entry is the 2KB of work RAM
+const FCEUX_CPU_SECTION: u8 = 1;
+const ZLIB_HEADER_SIZE: usize = 2;
+// Mesen's .mst and Mesen 2's .mss
+const MESEN_MAGICS: [&[u8]; 2] = [b"MST", b"MSS"];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportError {
+    UnknownFormat,
+    Unsupported(&'static str),
+    Invalid(String),
+    Decompress(InflateError),
+}
+
+impl fmt::Display for ImportError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImportError::UnknownFormat => write!(f, "Not a madNES interchange dump or FCEUX savestate"),
+            ImportError::Unsupported(emulator) => write!(f, "{} savestates can't be imported", emulator),
+            ImportError::Invalid(message) => write!(f, "Savestate is corrupt: {}", message),
+            ImportError::Decompress(error) => write!(f, "Could not decompress savestate: {}", error),
+        }
+    }
+}
+
+impl From<InflateError> for ImportError {
+    fn from(error: InflateError) -> Self {
+        ImportError::Decompress(error)
+    }
+}
+
+fn invalid(message: &str) -> ImportError {
+    ImportError::Invalid(message.to_string())
+}
+
+// The memory sections of another emulator's state
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForeignState {
+    // "FCEUX" or "madNES"
+    pub emulator: &'static str,
+    pub ram: Vec<u8>,
+    pub prg_ram: Option<Vec<u8>>,
+}
+
+impl ForeignState {
+    // Tells the format from the first bytes
+    pub fn parse(data: &[u8]) -> Result<ForeignState, ImportError> {
+        if data.trim_ascii_start().starts_with(b"{") {
+            parse_interchange(data)
+        } else if data.starts_with(FCEUX_OLD_MAGIC) {
+            parse_fceux(data)
+        } else if MESEN_MAGICS.iter().any(|magic| data.starts_with(magic)) {
+            Err(ImportError::Unsupported("Mesen"))
+        } else {
+            Err(ImportError::UnknownFormat)
+        }
+    }
+
+    // Copies the sections into the running game. RAM sizes that differ from the
+    // cartridge's are cut short or padded with zeros, like .sav files. Returns whether
+    // the PRG RAM went in: false when the state or the board has none.
+    pub fn apply(&self, nes: &mut Nes) -> bool {
+        nes.cpu_mut().bus.ram.copy_from_slice(&self.ram);
+        let (Some(data), Some(ram)) = (&self.prg_ram, nes.prg_ram_mut()) else {
+            return false;
+        };
+        let count = data.len().min(ram.len());
+        ram[..count].copy_from_slice(&data[..count]);
+        ram[count..].fill(0);
+        true
+    }
+}
+
+pub fn export(nes: &Nes) -> String {
+    let cpu = nes.cpu();
+    let ppu = &cpu.bus.ppu;
+    let crc32 = nes.cartridge().map_or("null".to_string(), |rom| format!("\"{:08X}\"", rom.crc32()));
+    let prg_ram = nes.prg_ram().map_or("null".to_string(), |ram| format!("\"{}\"", to_hex(ram)));
+    let fields = [
+        ("format", format!("\"{}\"", escape(FORMAT))),
+        ("version", VERSION.to_string()),
+        ("rom_crc32", crc32),
+        ("pc", cpu.pc.to_string()),
+        ("a", cpu.a.to_string()),
+        ("x", cpu.x.to_string()),
+        ("y", cpu.y.to_string()),
+        ("s", cpu.sp.to_string()),
+        ("p", cpu.p.bits().to_string()),
+        ("cycles", cpu.cycles.to_string()),
+        ("ppu_ctrl", ppu.ctrl.bits().to_string()),
+        ("ppu_mask", ppu.mask.bits().to_string()),
+        ("ppu_status", ppu.status.bits().to_string()),
+        ("oam_address", ppu.oam_address.to_string()),
+        ("v", ppu.v.to_string()),
+        ("t", ppu.t.to_string()),
+        ("fine_x", ppu.fine_x.to_string()),
+        ("write_toggle", ppu.write_toggle.to_string()),
+        ("scanline", ppu.scanline.to_string()),
+        ("dot", ppu.dot.to_string()),
+        ("frame", ppu.frame.to_string()),
+        ("ram", format!("\"{}\"", to_hex(&cpu.bus.ram))),
+        ("prg_ram", prg_ram),
+        ("vram", format!("\"{}\"", to_hex(&ppu.vram))),
+        ("palette", format!("\"{}\"", to_hex(&ppu.palette))),
+        ("oam", format!("\"{}\"", to_hex(&ppu.oam))),
+    ];
+    let fields: Vec<String> = fields.iter().map(|(key, value)| format!("\"{}\": {}", key, value)).collect();
+    format!("{{{}}}\n", fields.join(", "))
+}
+
+// Only the memory comes back; the registers are there for people and other tools
+fn parse_interchange(data: &[u8]) -> Result<ForeignState, ImportError> {
+    let text = std::str::from_utf8(data).map_err(|_| invalid("not UTF-8"))?;
+    let fields = parse_object(text).ok_or_else(|| invalid("not a flat JSON object"))?;
+    let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+    if field("format") != Some(&JsonValue::String(FORMAT.to_string())) {
+        return Err(ImportError::UnknownFormat);
+    }
+    if field("version") != Some(&JsonValue::Number(VERSION)) {
+        return Err(invalid("unknown version"));
+    }
+    let memory = |name: &str| match field(name) {
+        Some(JsonValue::String(hex)) => from_hex(hex).map(Some).ok_or_else(|| invalid(&format!("{} isn't hex", name))),
+        None | Some(JsonValue::Null) => Ok(None),
+        Some(_) => Err(invalid(&format!("{} isn't a string", name))),
+    };
+    let ram = memory("ram")?.filter(|ram| ram.len() == RAM_SIZE).ok_or_else(|| invalid("ram isn't 2KB"))?;
+    Ok(ForeignState { emulator: "madNES", ram, prg_ram: memory("prg_ram")? })
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+// The state is a list of sections, a type byte and a 32 bit size each, holding
+// entries of a 4 byte name padded with zeros, a 32 bit size and the data
+fn parse_fceux(data: &[u8]) -> Result<ForeignState, ImportError> {
+    if data.len() < FCEUX_HEADER_SIZE {
+        return Err(invalid("truncated header"));
+    }
+    let body = &data[FCEUX_HEADER_SIZE..];
+    let inflated;
+    let body = if data.starts_with(FCEUX_MAGIC) && u32_at(data, 12) != Some(FCEUX_UNCOMPRESSED) {
+        inflated = inflate(body.get(ZLIB_HEADER_SIZE..).ok_or_else(|| invalid("truncated"))?)?;
+        &inflated[..]
+    } else {
+        body
+    };
+
+    let (mut ram, mut prg_ram) = (None, None);
+    let mut position = 0;
+    while position < body.len() {
+        let section = body[position];
+        let size = u32_at(body, position + 1).ok_or_else(|| invalid("truncated section"))? as usize;
+        position += 5;
+        let end = position.checked_add(size).filter(|&end| end <= body.len()).ok_or_else(|| invalid("truncated section"))?;
+        while position < end {
+            let name = body.get(position..position + 4).ok_or_else(|| invalid("truncated entry"))?;
+            let length = u32_at(body, position + 4).ok_or_else(|| invalid("truncated entry"))? as usize;
+            position += 8;
+            let entry = body.get(position..position + length).filter(|_| position + length <= end);
+            let entry = entry.ok_or_else(|| invalid("truncated entry"))?;
+            position += length;
+            match (section, name) {
+                (FCEUX_CPU_SECTION, b"RAM\0") => ram = Some(entry.to_vec()),
+                // the boards with PRG RAM name it WRAM, in the cartridge section
+                (_, b"WRAM") => prg_ram = Some(entry.to_vec()),
+                _ => {}
+            }
+        }
+    }
+    let ram = ram.filter(|ram| ram.len() == RAM_SIZE).ok_or_else(|| invalid("no 2KB RAM section"))?;
+    Ok(ForeignState { emulator: "FCEUX", ram, prg_ram })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines_with_program;
+    use crate::rom::Rom;
+
+    fn nes() -> Nes {
+        let mut nes = Nes::new();
+        let mut image = ines_with_program(&[]);
+        // battery backed PRG RAM
+        image[6] |= 0x02;
+        nes.insert_cartridge(Rom::new(&image).unwrap()).unwrap();
+        nes
+    }
+
+    fn entry(name: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut entry = name.to_vec();
+        entry.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        entry.extend_from_slice(data);
+        entry
+    }
+
+    fn section(kind: u8, entries: &[Vec<u8>]) -> Vec<u8> {
+        let entries = entries.concat();
+        let mut section = vec![kind];
+        section.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+        section.extend_from_slice(&entries);
+        section
+    }
+
+    fn fceux_state(compressed: bool) -> Vec<u8> {
+        let mut ram = vec![0; RAM_SIZE];
+        ram[0x10] = 0x42;
+        let body = [
+            section(1, &[entry(b"PC\0\0", &[0x00, 0x80]), entry(b"A\0\0\0", &[7]), entry(b"RAM\0", &ram)]),
+            section(3, &[entry(b"NTAR", &[0; 16])]),
+            section(0x10, &[entry(b"WRAM", &[0x99; 0x2000])]),
+        ]
+        .concat();
+        let mut state = b"FCSX".to_vec();
+        state.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        state.extend_from_slice(&22020u32.to_le_bytes());
+        if !compressed {
+            state.extend_from_slice(&FCEUX_UNCOMPRESSED.to_le_bytes());
+            state.extend_from_slice(&body);
+            return state;
+        }
+        // zlib with stored blocks; the trailing checksum isn't checked
+        let mut zlib = vec![0x78, 0x01];
+        let chunks: Vec<&[u8]> = body.chunks(0xFFFF).collect();
+        for (index, chunk) in chunks.iter().enumerate() {
+            zlib.push((index == chunks.len() - 1) as u8);
+            zlib.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            zlib.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+            zlib.extend_from_slice(chunk);
+        }
+        zlib.extend_from_slice(&[0; 4]);
+        state.extend_from_slice(&(zlib.len() as u32).to_le_bytes());
+        state.extend_from_slice(&zlib);
+        state
+    }
+
+    #[test]
+    fn test_fceux_import() {
+        for compressed in [false, true] {
+            let state = ForeignState::parse(&fceux_state(compressed)).unwrap();
+            assert_eq!(state.emulator, "FCEUX");
+            assert_eq!(state.ram[0x10], 0x42);
+            assert_eq!(state.prg_ram.as_deref().map(<[u8]>::len), Some(0x2000));
+
+            let mut nes = nes();
+            assert!(state.apply(&mut nes));
+            assert_eq!((nes.peek(0x0010), nes.peek(0x6000)), (0x42, 0x99));
+        }
+
+        let state = fceux_state(false);
+        assert!(matches!(ForeignState::parse(&state[..state.len() - 10]), Err(ImportError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_interchange_roundtrip() {
+        let mut nes = nes();
+        nes.poke(0x0123, 0x45);
+        nes.poke(0x6001, 0x67);
+        let dump = export(&nes);
+        let fields = parse_object(&dump).unwrap();
+        assert!(fields.contains(&("pc".to_string(), JsonValue::Number(0x8000))));
+        assert!(fields.contains(&("rom_crc32".to_string(), JsonValue::String(format!("{:08X}", nes.cartridge().unwrap().crc32())))));
+
+        let state = ForeignState::parse(dump.as_bytes()).unwrap();
+        let mut other = self::nes();
+        assert!(state.apply(&mut other));
+        assert_eq!((other.peek(0x0123), other.peek(0x6001)), (0x45, 0x67));
+    }
+
+    #[test]
+    fn test_unknown_formats() {
+        assert_eq!(ForeignState::parse(b"MST\x1a\x00\x00"), Err(ImportError::Unsupported("Mesen")));
+        assert_eq!(ForeignState::parse(b"NES\x1a"), Err(ImportError::UnknownFormat));
+        assert_eq!(ForeignState::parse(b"{\"format\": \"other\"}"), Err(ImportError::UnknownFormat));
+        assert!(matches!(ForeignState::parse(b"FCSX\x00"), Err(ImportError::Invalid(_))));
+    }
+}
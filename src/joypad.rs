@@ -0,0 +1,137 @@
+use bitflags::bitflags;
+
+use crate::input::{Device, InputDevice};
+use crate::ppu::Ppu;
+use crate::savestate::{SaveState, StateError, StateReader};
+
+bitflags! {
+    // Standard controller buttons, in the order the shift register reports them
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct JoypadButton: u8 {
+        const A = 1 << 0;
+        const B = 1 << 1;
+        const Select = 1 << 2;
+        const Start = 1 << 3;
+        const Up = 1 << 4;
+        const Down = 1 << 5;
+        const Left = 1 << 6;
+        const Right = 1 << 7;
+    }
+}
+
+impl JoypadButton {
+    pub fn parse(name: &str) -> Option<JoypadButton> {
+        match name.to_ascii_lowercase().as_str() {
+            "a" => Some(JoypadButton::A),
+            "b" => Some(JoypadButton::B),
+            "select" => Some(JoypadButton::Select),
+            "start" => Some(JoypadButton::Start),
+            "up" => Some(JoypadButton::Up),
+            "down" => Some(JoypadButton::Down),
+            "left" => Some(JoypadButton::Left),
+            "right" => Some(JoypadButton::Right),
+            _ => None,
+        }
+    }
+}
+
+// Autofire duty cycle: a held turbo button is pressed for `on_frames` then released
+// for `off_frames`. The phase comes from the emulated frame count rather than the
+// wall clock, so the same input replays the same way in a movie.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Turbo {
+    pub on_frames: u8,
+    pub off_frames: u8,
+}
+
+impl Default for Turbo {
+    // 15 presses a second
+    fn default() -> Self {
+        Turbo { on_frames: 2, off_frames: 2 }
+    }
+}
+
+impl Turbo {
+    // The held turbo buttons that are pressed on the given frame
+    pub fn buttons(&self, held: JoypadButton, frame: u64) -> JoypadButton {
+        let period = self.on_frames as u64 + self.off_frames as u64;
+        if period == 0 || frame % period < self.on_frames as u64 {
+            held
+        } else {
+            JoypadButton::empty()
+        }
+    }
+}
+
+// Standard controller as read through $4016/$4017. Writing 1 to bit 0 reloads
+// the shift register with the held buttons, then each read returns the next
+// button in JoypadButton order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Joypad {
+    pub buttons: JoypadButton,
+    pub strobe: bool,
+    pub index: u8,
+}
+
+impl InputDevice for Joypad {
+    fn strobe(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        let value = self.peek(ppu);
+        if !self.strobe && self.index < 8 {
+            self.index += 1;
+        }
+        value
+    }
+
+    // Official controllers report 1 once all eight buttons have been read
+    fn peek(&self, _ppu: &Ppu) -> u8 {
+        if self.index >= 8 {
+            1
+        } else {
+            (self.buttons.bits() >> self.index) & 0x01
+        }
+    }
+
+    fn device(&self) -> Device {
+        Device::Joypad
+    }
+
+    fn set_buttons(&mut self, buttons: JoypadButton) {
+        self.buttons = buttons;
+    }
+
+    fn buttons(&self) -> JoypadButton {
+        self.buttons
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.strobe as u8, self.index]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = input.read_u8()? != 0;
+        self.index = input.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_turbo() {
+        let turbo = Turbo { on_frames: 1, off_frames: 2 };
+        let pressed: Vec<bool> = (0..6).map(|frame| turbo.buttons(JoypadButton::A, frame) == JoypadButton::A).collect();
+        assert_eq!(pressed, [true, false, false, true, false, false]);
+        assert_eq!(turbo.buttons(JoypadButton::empty(), 0), JoypadButton::empty());
+    }
+}
@@ -0,0 +1,71 @@
+pub mod instruction;
+pub mod cpu;
+pub mod bus;
+pub mod rom;
+pub mod hash;
+pub mod inflate;
+pub mod archive;
+pub mod patch;
+pub mod mapper;
+pub mod cartridge;
+pub mod gamedb;
+pub mod mmc5;
+pub mod mmc2;
+pub mod discrete;
+pub mod fme7;
+pub mod namco163;
+pub mod fds;
+pub mod trace;
+pub mod nestest;
+pub mod harte;
+pub mod blargg;
+pub mod bench;
+pub mod framehash;
+pub mod options;
+pub mod debugger;
+pub mod disassembler;
+pub mod assembler;
+pub mod symbols;
+pub mod watch;
+pub mod remote;
+pub mod cheat;
+pub mod palette;
+pub mod viewer;
+pub mod view;
+pub mod osd;
+pub mod textcache;
+pub mod font;
+pub mod savestate;
+pub mod interchange;
+pub mod ppudump;
+pub mod rewind;
+pub mod slots;
+pub mod battery;
+pub mod joypad;
+pub mod input;
+pub mod config;
+pub mod browser;
+pub mod filter;
+pub mod framebuffer;
+pub mod timing;
+pub mod audio;
+pub mod apu;
+pub mod mixer;
+pub mod blip;
+pub mod zapper;
+pub mod recording;
+pub mod movie;
+pub mod repro;
+pub mod netplay;
+pub mod hooks;
+pub mod nes;
+pub mod ppu;
+pub mod worker;
+#[cfg(test)]
+mod testdir;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "libretro")]
+pub mod libretro;
+
+pub use nes::Nes;
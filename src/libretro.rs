@@ -0,0 +1,425 @@
+// libretro core API, so the core can be loaded by RetroArch and other frontends.
+// Build the shared library with `cargo build --release --features libretro`.
+
+use std::ffi::{c_char, c_uint, c_void};
+use std::sync::Mutex;
+
+use crate::apu;
+use crate::cpu::RAM_SIZE;
+use crate::joypad::JoypadButton;
+use crate::nes::{Nes, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::rom::Rom;
+use crate::savestate;
+use crate::timing::NTSC_FRAME_DURATION;
+
+const RETRO_API_VERSION: c_uint = 1;
+
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_MEMORY_SYSTEM_RAM: c_uint = 2;
+
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+
+const SAMPLE_RATE: f64 = apu::SAMPLE_RATE as f64;
+
+// libretro joypad ids of the NES buttons
+const JOYPAD_BUTTONS: [(c_uint, JoypadButton); 8] = [
+    (0, JoypadButton::B),
+    (2, JoypadButton::Select),
+    (3, JoypadButton::Start),
+    (4, JoypadButton::Up),
+    (5, JoypadButton::Down),
+    (6, JoypadButton::Left),
+    (7, JoypadButton::Right),
+    (8, JoypadButton::A),
+];
+
+type EnvironmentFn = unsafe extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = unsafe extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = unsafe extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = unsafe extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = unsafe extern "C" fn();
+type InputStateFn = unsafe extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    pub library_name: *const c_char,
+    pub library_version: *const c_char,
+    pub valid_extensions: *const c_char,
+    pub need_fullpath: bool,
+    pub block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    pub base_width: c_uint,
+    pub base_height: c_uint,
+    pub max_width: c_uint,
+    pub max_height: c_uint,
+    pub aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    pub fps: f64,
+    pub sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    pub geometry: RetroGameGeometry,
+    pub timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    pub path: *const c_char,
+    pub data: *const c_void,
+    pub size: usize,
+    pub meta: *const c_char,
+}
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct State {
+    nes: Option<Nes>,
+    // XRGB8888 copy of the frame handed to the video callback
+    framebuffer: Vec<u32>,
+    audio: Vec<i16>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static STATE: Mutex<State> = Mutex::new(State {
+    nes: None,
+    framebuffer: Vec::new(),
+    audio: Vec::new(),
+});
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {
+    let mut state = STATE.lock().unwrap();
+    state.framebuffer = vec![0; SCREEN_WIDTH * SCREEN_HEIGHT];
+}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    let mut state = STATE.lock().unwrap();
+    state.nes = None;
+    state.framebuffer = Vec::new();
+}
+
+/// # Safety
+///
+/// `info` must point to a writable `RetroSystemInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: c"madNES".as_ptr(),
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: c"nes".as_ptr(),
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+/// # Safety
+///
+/// `info` must point to a writable `RetroSystemAvInfo`.
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: SCREEN_WIDTH as c_uint,
+            base_height: SCREEN_HEIGHT as c_uint,
+            max_width: SCREEN_WIDTH as c_uint,
+            max_height: SCREEN_HEIGHT as c_uint,
+            // NES pixels are 8:7
+            aspect_ratio: (SCREEN_WIDTH as f32 * 8.0 / 7.0) / SCREEN_HEIGHT as f32,
+        },
+        timing: RetroSystemTiming {
+            fps: 1.0 / NTSC_FRAME_DURATION.as_secs_f64(),
+            sample_rate: SAMPLE_RATE,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(callback: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(callback: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(callback);
+}
+
+// Samples are always sent in batches
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_callback: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(callback: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(callback: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(callback);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(callback: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(callback);
+}
+
+// Both ports always have a standard controller
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(nes) = STATE.lock().unwrap().nes.as_mut() {
+        let _ = nes.reset();
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    let callbacks = CALLBACKS.lock().unwrap();
+    let state = &mut *STATE.lock().unwrap();
+    let Some(nes) = state.nes.as_mut() else {
+        return;
+    };
+
+    if let Some(input_poll) = callbacks.input_poll {
+        unsafe { input_poll() };
+    }
+    if let Some(input_state) = callbacks.input_state {
+        for player in 0..2 {
+            let buttons = JOYPAD_BUTTONS
+                .iter()
+                .filter(|(id, _)| unsafe { input_state(player, RETRO_DEVICE_JOYPAD, 0, *id) } != 0)
+                .fold(JoypadButton::empty(), |buttons, (_, button)| buttons | *button);
+            nes.set_buttons(player as usize, buttons);
+        }
+    }
+
+    let output = nes.step_frame();
+
+    if let Some(video_refresh) = callbacks.video_refresh {
+        for (pixel, rgb) in state.framebuffer.iter_mut().zip(output.image.pixels.chunks_exact(3)) {
+            *pixel = u32::from_be_bytes([0, rgb[0], rgb[1], rgb[2]]);
+        }
+        let pitch = SCREEN_WIDTH * 4;
+        unsafe {
+            video_refresh(
+                state.framebuffer.as_ptr() as *const c_void,
+                SCREEN_WIDTH as c_uint,
+                SCREEN_HEIGHT as c_uint,
+                pitch,
+            )
+        };
+    }
+
+    if let Some(audio_sample_batch) = callbacks.audio_sample_batch {
+        // mono to interleaved stereo
+        state.audio.clear();
+        for sample in output.audio {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            state.audio.extend([value, value]);
+        }
+        if !state.audio.is_empty() {
+            unsafe { audio_sample_batch(state.audio.as_ptr(), state.audio.len() / 2) };
+        }
+    }
+}
+
+/// # Safety
+///
+/// `game` must be null or point to a valid `RetroGameInfo` whose `data` holds `size` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+    let data = std::slice::from_raw_parts((*game).data as *const u8, (*game).size);
+    let Ok(rom) = Rom::new(data) else {
+        return false;
+    };
+    let mut nes = Nes::new();
+    if nes.insert_cartridge(rom).is_err() {
+        return false;
+    }
+
+    if let Some(environment) = CALLBACKS.lock().unwrap().environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            return false;
+        }
+    }
+    STATE.lock().unwrap().nes = Some(nes);
+    true
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    STATE.lock().unwrap().nes = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match STATE.lock().unwrap().nes.as_ref() {
+        Some(nes) => savestate::save(nes.cpu()).len(),
+        None => 0,
+    }
+}
+
+/// # Safety
+///
+/// `data` must point to `size` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = STATE.lock().unwrap();
+    let Some(nes) = state.nes.as_ref() else {
+        return false;
+    };
+    let bytes = savestate::save(nes.cpu());
+    if bytes.len() > size {
+        return false;
+    }
+    std::ptr::copy_nonoverlapping(bytes.as_ptr(), data as *mut u8, bytes.len());
+    true
+}
+
+/// # Safety
+///
+/// `data` must point to `size` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    let mut state = STATE.lock().unwrap();
+    let Some(nes) = state.nes.as_mut() else {
+        return false;
+    };
+    let bytes = std::slice::from_raw_parts(data as *const u8, size);
+    savestate::load(nes.cpu_mut(), bytes).is_ok()
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_set(_index: c_uint, _enabled: bool, _code: *const c_char) {}
+
+// Exposes the 2KB internal RAM for frontend cheat search and achievements, and
+// battery RAM for the frontend to keep in its .sav files
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match STATE.lock().unwrap().nes.as_mut() {
+        Some(nes) if id == RETRO_MEMORY_SYSTEM_RAM => nes.cpu_mut().bus.ram.as_mut_ptr() as *mut c_void,
+        Some(nes) if id == RETRO_MEMORY_SAVE_RAM => {
+            nes.battery_ram_mut().map_or(std::ptr::null_mut(), |ram| ram.as_mut_ptr() as *mut c_void)
+        }
+        _ => std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match id {
+        RETRO_MEMORY_SYSTEM_RAM => RAM_SIZE,
+        RETRO_MEMORY_SAVE_RAM => STATE.lock().unwrap().nes.as_ref().and_then(Nes::battery_ram).map_or(0, <[u8]>::len),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines_with_program;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert!(!data.is_null());
+        assert_eq!((width, height, pitch), (256, 240, 1024));
+        FRAMES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    unsafe extern "C" fn input_state(port: c_uint, _device: c_uint, _index: c_uint, id: c_uint) -> i16 {
+        // player 1 holds A
+        (port == 0 && id == 8) as i16
+    }
+
+    #[test]
+    fn test_load_run_and_serialize() {
+        // JMP $8000
+        let data = ines_with_program(&[0x4C, 0x00, 0x80]);
+        let game = RetroGameInfo {
+            path: std::ptr::null(),
+            data: data.as_ptr() as *const c_void,
+            size: data.len(),
+            meta: std::ptr::null(),
+        };
+
+        retro_init();
+        retro_set_video_refresh(video_refresh);
+        retro_set_input_state(input_state);
+        assert!(unsafe { retro_load_game(&game) });
+        retro_run();
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 1);
+        assert_eq!(STATE.lock().unwrap().nes.as_ref().unwrap().buttons(0), JoypadButton::A);
+
+        let mut state = vec![0u8; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+        let cycles = STATE.lock().unwrap().nes.as_ref().unwrap().cpu().cycles;
+        retro_run();
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+        assert_eq!(STATE.lock().unwrap().nes.as_ref().unwrap().cpu().cycles, cycles);
+
+        retro_unload_game();
+        assert_eq!(retro_serialize_size(), 0);
+        retro_deinit();
+    }
+
+    #[test]
+    fn test_system_av_info() {
+        let mut info = std::mem::MaybeUninit::<RetroSystemAvInfo>::uninit();
+        let info = unsafe {
+            retro_get_system_av_info(info.as_mut_ptr());
+            info.assume_init()
+        };
+        assert_eq!((info.geometry.base_width, info.geometry.base_height), (256, 240));
+        assert!((info.timing.fps - 60.0988).abs() < 0.001);
+    }
+}
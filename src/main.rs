@@ -1,6 +1,381 @@
-pub mod instruction;
-pub mod cpu;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use madnes::apu;
+use madnes::audio::AudioManager;
+use madnes::battery::BatterySave;
+use madnes::bench;
+use madnes::config::Config;
+use madnes::disassembler::Disassembly;
+use madnes::fds::FdsImage;
+use madnes::gamedb::{GameDatabase, GameEntry};
+use madnes::harte;
+use madnes::hash;
+use madnes::interchange::{self, ForeignState};
+use madnes::mapper;
+use madnes::nes::Nes;
+use madnes::nestest;
+use madnes::options::{Command, EmulatorOptions, USAGE};
+use madnes::palette::Palette;
+use madnes::ppudump::PpuDump;
+use madnes::repro::{ReproCapture, ReproPlayer};
+use madnes::rom::Rom;
+use madnes::savestate;
+use madnes::symbols::SymbolTable;
+use madnes::trace::{TraceSink, Tracer};
+
+fn create_tracer(config: &Config) -> Tracer {
+    let mut tracer = Tracer::new(config.trace);
+    tracer.filters = config.trace_filters.clone();
+    if config.trace.is_empty() {
+        return tracer;
+    }
+    match &config.trace_file {
+        Some(path) => match TraceSink::file(path) {
+            Ok(sink) => tracer.add_sink(sink),
+            Err(error) => eprintln!("Could not open trace file {}: {}", path.display(), error),
+        },
+        None => tracer.add_sink(TraceSink::stdout()),
+    }
+    if config.trace_buffer > 0 {
+        tracer.add_sink(TraceSink::memory(config.trace_buffer));
+    }
+    tracer
+}
+
+fn list_mappers() {
+    for info in mapper::registered() {
+        let number = match info.submapper {
+            Some(submapper) => format!("{}.{}", info.number, submapper),
+            None => info.number.to_string(),
+        };
+        let features: Vec<&str> = info.features.iter_names().map(|(name, _)| name).collect();
+        println!("{:>5}  {:<6} {:<28} {}", number, info.name, info.boards, features.join(", "));
+    }
+}
+
+fn print_disk_info(path: &Path) {
+    match FdsImage::load(path) {
+        Ok(disk) => {
+            println!("game       {}", disk.game_name());
+            println!("sides      {}", disk.sides.len());
+        }
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_info(path: &Path, patches: &[PathBuf]) {
+    if FdsImage::is_fds_file(path) {
+        return print_disk_info(path);
+    }
+    let rom = match Rom::load_patched(path, patches) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            process::exit(1);
+        }
+    };
+    let mapper = mapper::name(rom.mapper).unwrap_or("unsupported");
+    println!("mapper     {}.{} ({})", rom.mapper, rom.submapper, mapper);
+    println!("prg rom    {}KB", rom.prg_rom.len() / 1024);
+    println!("chr {}    {}KB", if rom.chr_rom.is_empty() { "ram" } else { "rom" }, rom.chr().len() / 1024);
+    println!("mirroring  {:?}", rom.mirroring);
+    println!("battery    {}", rom.has_battery);
+    println!("trainer    {}", rom.trainer.is_some());
+    println!("vs system  {}", rom.is_vs_system);
+    println!("region     {}", rom.region.map_or("any".to_string(), |region| region.to_string()));
+    println!("crc32      {:08X}", rom.crc32());
+    println!("sha1       {}", hash::to_hex(&rom.sha1()));
+    match GameDatabase::load_default() {
+        Ok(database) => match database.find(&rom) {
+            Some(entry) => println!("database   {}", entry.name.as_deref().unwrap_or("(unnamed)")),
+            None => println!("database   no entry"),
+        },
+        Err(error) => eprintln!("games.toml: {}", error),
+    }
+    for warning in &rom.warnings {
+        println!("warning    {}", warning);
+    }
+}
+
+// Writes the ROM back out with a clean header, after games.toml and the command line's fixes
+fn fix_header(path: &Path, out: &Path, patches: &[PathBuf], fix: &GameEntry) {
+    let mut rom = match Rom::load_patched(path, patches) {
+        Ok(rom) => rom,
+        Err(error) => {
+            eprintln!("{}: {}", path.display(), error);
+            process::exit(1);
+        }
+    };
+    match GameDatabase::load_default() {
+        Ok(database) => {
+            database.apply(&mut rom);
+        }
+        Err(error) => eprintln!("games.toml: {}", error),
+    }
+    fix.apply(&mut rom);
+    if let Err(error) = fs::write(out, rom.to_ines()) {
+        eprintln!("{}: {}", out.display(), error);
+        process::exit(1);
+    }
+    println!("{}: {}", out.display(), rom.describe());
+}
+
+// Prints a savestate's registers and memory as JSON for other tools and emulators
+fn export_state(state_path: &Path, rom: &Path, patches: &[PathBuf]) {
+    let mut nes = Nes::new();
+    if let Err(error) = nes.load_patched_rom_file(rom, patches) {
+        eprintln!("{}: {}", rom.display(), error);
+        process::exit(1);
+    }
+    let result = fs::read(state_path)
+        .map_err(|error| error.to_string())
+        .and_then(|state| savestate::load(nes.cpu_mut(), &state).map_err(|error| error.to_string()));
+    if let Err(error) = result {
+        eprintln!("{}: {}", state_path.display(), error);
+        process::exit(1);
+    }
+    print!("{}", interchange::export(&nes));
+}
+
+// Moves the PRG RAM out of another emulator's state into the game's battery save,
+// which is the part of a state that survives the trip
+fn import_state(state_path: &Path, rom: &Path, patches: &[PathBuf], config: &Config) {
+    let state = fs::read(state_path)
+        .map_err(|error| error.to_string())
+        .and_then(|data| ForeignState::parse(&data).map_err(|error| error.to_string()));
+    let state = state.unwrap_or_else(|error| {
+        eprintln!("{}: {}", state_path.display(), error);
+        process::exit(1);
+    });
+    let mut nes = Nes::new();
+    if let Err(error) = nes.load_patched_rom_file(rom, patches) {
+        eprintln!("{}: {}", rom.display(), error);
+        process::exit(1);
+    }
+    if nes.battery_ram().is_none() {
+        eprintln!("{}: game has no battery to save to", rom.display());
+        process::exit(1);
+    }
+    if !state.apply(&mut nes) {
+        eprintln!("{}: {} state has no PRG RAM", state_path.display(), state.emulator);
+        process::exit(1);
+    }
+    let mut save = BatterySave::for_rom(rom, config);
+    match save.flush(&nes) {
+        Ok(_) => println!("{}: PRG RAM from the {} state", save.path.display(), state.emulator),
+        Err(error) => {
+            eprintln!("{}: {}", save.path.display(), error);
+            process::exit(1);
+        }
+    }
+}
+
+// No sound device here, so the sound only goes to the --dump-audio WAV
+fn open_audio(config: &Config) -> AudioManager {
+    AudioManager::with_config(apu::SAMPLE_RATE, None, config).unwrap_or_else(|error| {
+        eprintln!("{}: {}", config.dump_audio.as_deref().unwrap_or(Path::new("")).display(), error);
+        process::exit(1);
+    })
+}
+
+fn close_audio(mut audio: AudioManager) {
+    match audio.stop_dump() {
+        Ok(Some((path, samples))) => println!("{}: {:.1}s of audio", path.display(), samples as f64 / audio.sample_rate as f64),
+        Ok(None) => {}
+        Err(error) => eprintln!("Could not finish the audio dump: {}", error),
+    }
+}
+
+// Runs the ROM headless and writes the PPU as the last frame ended
+fn dump_ppu(path: &Path, patches: &[PathBuf], frames: u64, out: &Path, config: &Config) {
+    let mut nes = Nes::new();
+    nes.set_ram_pattern(config.ram_pattern);
+    *nes.mixer_mut() = config.mixer;
+    if let Err(error) = nes.load_patched_rom_file(path, patches) {
+        eprintln!("{}: {}", path.display(), error);
+        process::exit(1);
+    }
+    report_rom(path, &nes);
+    let mut audio = open_audio(config);
+    for _ in 0..frames {
+        if let Err(error) = audio.queue(nes.step_frame().audio) {
+            eprintln!("Could not write the audio dump: {}", error);
+            process::exit(1);
+        }
+        if let Some(fault) = nes.fault() {
+            eprintln!("{}: the CPU stopped: {}", path.display(), fault);
+            break;
+        }
+    }
+    close_audio(audio);
+    let dump = PpuDump::capture(&mut nes);
+    if let Err(error) = fs::write(out, dump.to_json()) {
+        eprintln!("{}: {}", out.display(), error);
+        process::exit(1);
+    }
+    println!("{}: frame {}", out.display(), dump.frame);
+}
+
+// Draws a PPU dump's frame offline, with the configured palette and layers
+fn render_ppu_dump(dump_path: &Path, out: &Path, config: &Config) {
+    let palette = Palette::select(&config.palette).unwrap_or_else(|error| {
+        eprintln!("{}: {}", config.palette, error);
+        process::exit(2);
+    });
+    let dump = fs::read_to_string(dump_path)
+        .map_err(|error| error.to_string())
+        .and_then(|text| PpuDump::parse(&text).map_err(|error| error.to_string()));
+    let dump = dump.unwrap_or_else(|error| {
+        eprintln!("{}: {}", dump_path.display(), error);
+        process::exit(1);
+    });
+    if let Err(error) = fs::write(out, dump.render(&palette, config.layers).to_ppm()) {
+        eprintln!("{}: {}", out.display(), error);
+        process::exit(1);
+    }
+}
+
+// Says what was loaded on stderr, and what looks wrong with its header
+fn report_rom(path: &Path, nes: &Nes) {
+    if let Some(rom) = nes.cartridge() {
+        eprintln!("{}: {}", path.display(), rom.describe());
+        for warning in &rom.warnings {
+            eprintln!("{}: warning: {}", path.display(), warning);
+        }
+    }
+}
+
+// Runs the ROM headless as fast as it goes, without the decode cache and then
+// with it, tracing if enabled
+fn bench(path: &Path, patches: &[PathBuf], frames: u64, tracer: &mut Tracer) {
+    for decode_cache in [false, true] {
+        let mut nes = Nes::new();
+        nes.cpu_mut().set_decode_cache(decode_cache);
+        if let Err(error) = nes.load_patched_rom_file(path, patches) {
+            eprintln!("{}: {}", path.display(), error);
+            process::exit(1);
+        }
+        if decode_cache {
+            println!();
+        } else {
+            report_rom(path, &nes);
+        }
+        println!("decode cache {}\n{}", if decode_cache { "on" } else { "off" }, bench::run(&mut nes, frames, tracer));
+    }
+}
+
+// Replays a capture from a bug report and says how far it got
+fn replay_repro(capture_path: &Path, rom: &Path, patches: &[PathBuf], config: &Config) {
+    let capture = match fs::read_to_string(capture_path) {
+        Ok(text) => ReproCapture::parse(&text).map_err(|error| error.to_string()),
+        Err(error) => Err(error.to_string()),
+    };
+    let capture = capture.unwrap_or_else(|error| {
+        eprintln!("{}: {}", capture_path.display(), error);
+        process::exit(1);
+    });
+    let mut nes = Nes::new();
+    nes.set_ram_pattern(config.ram_pattern);
+    *nes.mixer_mut() = config.mixer;
+    if let Err(error) = nes.load_patched_rom_file(rom, patches) {
+        eprintln!("{}: {}", rom.display(), error);
+        process::exit(1);
+    }
+    report_rom(rom, &nes);
+    let mut audio = open_audio(config);
+    let result = ReproPlayer::start(capture, &mut nes).and_then(|mut player| {
+        while !player.is_finished() && nes.fault().is_none() {
+            player.step_frame(&mut nes, config)?;
+            if let Err(error) = audio.queue(nes.audio()) {
+                eprintln!("Could not write the audio dump: {}", error);
+                process::exit(1);
+            }
+        }
+        Ok(player.frame())
+    });
+    close_audio(audio);
+    match (result, nes.fault()) {
+        (Ok(frames), None) => println!("replayed {} frames", frames),
+        (Ok(frames), Some(fault)) => println!("replayed {} frames, then the CPU stopped: {}", frames, fault),
+        (Err(error), _) => {
+            eprintln!("{}: {}", capture_path.display(), error);
+            process::exit(1);
+        }
+    }
+}
 
 fn main() {
-    println!("Hello, world!");
+    let options = match EmulatorOptions::parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(error) => {
+            eprintln!("{}\n{}", error, USAGE);
+            process::exit(2);
+        }
+    };
+    let mut config = match Config::load_or_default(options.config.as_deref()) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+    };
+    config.apply_options(&options);
+    let mut tracer = create_tracer(&config);
+
+    match &options.command {
+        Command::VerifyNestest { rom, log } => match nestest::verify_files(rom, log, &mut tracer) {
+            Ok(lines) => println!("nestest passed: {} lines match", lines),
+            Err(error) => {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+        },
+        Command::VerifyCpuVectors { dir } => match harte::run_dir(dir) {
+            Ok(report) => {
+                println!("{}", report);
+                if !report.passed() {
+                    process::exit(1);
+                }
+            }
+            Err(error) => {
+                eprintln!("{}: {}", dir.display(), error);
+                process::exit(1);
+            }
+        },
+        Command::ListMappers => list_mappers(),
+        Command::Info { rom } => print_info(rom, &options.patches),
+        Command::Disassemble { rom: path } => match Rom::load_patched(path, &options.patches) {
+            Ok(rom) => {
+                let mut disassembly = Disassembly::from_rom(&rom);
+                match SymbolTable::find_for_rom(path) {
+                    Ok(Some(symbols)) => disassembly.apply_symbols(&symbols),
+                    Ok(None) => {}
+                    Err(error) => eprintln!("Could not read symbols for {}: {}", path.display(), error),
+                }
+                print!("{}", disassembly.to_ca65());
+            }
+            Err(error) => {
+                eprintln!("{}: {}", path.display(), error);
+                process::exit(1);
+            }
+        },
+        Command::Bench { frames, rom } => bench(rom, &options.patches, *frames, &mut tracer),
+        Command::ReplayRepro { capture, rom } => replay_repro(capture, rom, &options.patches, &config),
+        Command::ExportState { state, rom } => export_state(state, rom, &options.patches),
+        Command::ImportState { state, rom } => import_state(state, rom, &options.patches, &config),
+        Command::DumpPpu { frames, rom, out } => dump_ppu(rom, &options.patches, *frames, out, &config),
+        Command::RenderPpuDump { dump, out } => render_ppu_dump(dump, out, &config),
+        Command::FixHeader { rom, out } => fix_header(rom, out, &options.patches, &options.header_fix),
+        // this binary has no window to play in, only the headless tools above
+        Command::Usage => {
+            eprintln!("{}", USAGE);
+            process::exit(2);
+        }
+    }
 }
@@ -0,0 +1,382 @@
+use std::sync::RwLock;
+
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+
+use crate::discrete::Discrete;
+use crate::fme7::Fme7;
+use crate::namco163::Namco163;
+use crate::mmc2::Mmc2;
+use crate::mmc5::Mmc5;
+use crate::rom::{Mirroring, Rom, RomError};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+pub const PRG_RAM_SIZE: usize = 0x2000;
+
+// The cartridge hardware seen from both buses. The CPU side covers 0x4020-0xFFFF,
+// the PPU side the pattern tables at 0x0000-0x1FFF.
+pub trait Mapper: SaveState + Send {
+    fn read_prg(&mut self, address: u16) -> u8 {
+        self.peek_prg(address)
+    }
+
+    // Reads without the side effects of a real bus read
+    fn peek_prg(&self, address: u16) -> u8;
+
+    fn write_prg(&mut self, address: u16, value: u8);
+
+    fn read_chr(&mut self, address: u16) -> u8;
+
+    fn write_chr(&mut self, address: u16, value: u8);
+
+    // Called by the PPU with the address of every pattern table fetch, for boards that
+    // watch the PPU bus like MMC2's tile latches and MMC3's A12 counter
+    fn ppu_fetch(&mut self, _address: u16) {}
+
+    fn mirroring(&self) -> Mirroring;
+
+    // Boards with their own nametable mapping return the byte instead of the PPU's
+    // CIRAM lookup. `ciram` is the console's 2KB of nametable RAM.
+    fn read_nametable(&self, _address: u16, _ciram: &[u8]) -> Option<u8> {
+        None
+    }
+
+    // The write side of read_nametable; true when the board took the write
+    fn write_nametable(&mut self, _address: u16, _value: u8, _ciram: &mut [u8]) -> bool {
+        false
+    }
+
+    // Level of the cartridge's IRQ line
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Called by the PPU at the start of every rendered scanline, for scanline counters
+    fn scanline(&mut self) {}
+
+    // Called by the PPU when vblank starts
+    fn end_frame(&mut self) {}
+
+    // Called with the CPU cycles of every instruction, for cycle based timers
+    fn tick(&mut self, _cycles: u8) {}
+
+    // PRG RAM, which cartridges with a battery keep while the console is off.
+    // See Nes::battery_ram.
+    fn save_ram(&self) -> Option<&[u8]> {
+        None
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        None
+    }
+
+    // Level of the cartridge's sound chip, 0.0-1.0, for boards with ExpansionAudio.
+    // See Mixer::mix_with_expansion.
+    fn audio_output(&self) -> f32 {
+        0.0
+    }
+
+    // Whether a CPU write to `address` can change what's read at $8000-$FFFF. Only
+    // the PRG RAM at $6000-$7FFF can't on most boards.
+    fn changes_prg(&self, address: u16) -> bool {
+        !matches!(address, 0x6000..=0x7FFF)
+    }
+
+    // Disk systems: takes the disk out and puts its next side in a moment later,
+    // long enough for the BIOS to notice. Returns the side going in.
+    fn switch_disk_side(&mut self) -> Option<usize> {
+        None
+    }
+}
+
+bitflags! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MapperFeatures: u8 {
+        const PrgRam = 1 << 0;
+        const ChrRam = 1 << 1;
+        const Irq = 1 << 2;
+        const ExpansionAudio = 1 << 3;
+        // reacts to PPU fetches through Mapper::ppu_fetch
+        const PpuFetch = 1 << 4;
+    }
+}
+
+// A registered mapper and what it can do, for --list-mappers and error messages
+#[derive(Debug, Clone, Copy)]
+pub struct MapperInfo {
+    pub number: u8,
+    // None matches every submapper
+    pub submapper: Option<u8>,
+    pub name: &'static str,
+    pub boards: &'static str,
+    pub features: MapperFeatures,
+    pub create: fn(&Rom) -> Box<dyn Mapper>,
+}
+
+pub struct MapperRegistry {
+    mappers: Vec<MapperInfo>,
+}
+
+impl Default for MapperRegistry {
+    // The mappers built into madNES
+    fn default() -> Self {
+        let mut registry = MapperRegistry::new();
+        registry.register(MapperInfo {
+            number: 0,
+            submapper: None,
+            name: "NROM",
+            boards: "NROM-128, NROM-256",
+            features: MapperFeatures::PrgRam | MapperFeatures::ChrRam,
+            create: |rom| Box::new(Nrom::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 5,
+            submapper: None,
+            name: "MMC5",
+            boards: "EKROM, ELROM, ETROM, EWROM",
+            features: MapperFeatures::PrgRam | MapperFeatures::ChrRam | MapperFeatures::Irq,
+            create: |rom| Box::new(Mmc5::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 9,
+            submapper: None,
+            name: "MMC2",
+            boards: "PNROM, PEEOROM",
+            features: MapperFeatures::PrgRam | MapperFeatures::PpuFetch,
+            create: |rom| Box::new(Mmc2::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 10,
+            submapper: None,
+            name: "MMC4",
+            boards: "FJROM, FKROM",
+            features: MapperFeatures::PrgRam | MapperFeatures::PpuFetch,
+            create: |rom| Box::new(Mmc2::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 11,
+            submapper: None,
+            name: "Color Dreams",
+            boards: "Color Dreams, Wisdom Tree",
+            features: MapperFeatures::empty(),
+            create: |rom| Box::new(Discrete::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 19,
+            submapper: None,
+            name: "Namco 163",
+            boards: "Namco 129, Namco 163",
+            features: MapperFeatures::PrgRam | MapperFeatures::Irq | MapperFeatures::ExpansionAudio,
+            create: |rom| Box::new(Namco163::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 34,
+            submapper: None,
+            name: "BNROM/NINA-001",
+            boards: "BNROM, NINA-001",
+            features: MapperFeatures::PrgRam | MapperFeatures::ChrRam,
+            create: |rom| Box::new(Discrete::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 66,
+            submapper: None,
+            name: "GxROM",
+            boards: "GNROM, MHROM",
+            features: MapperFeatures::empty(),
+            create: |rom| Box::new(Discrete::new(rom)),
+        });
+        registry.register(MapperInfo {
+            number: 69,
+            submapper: None,
+            name: "FME-7",
+            boards: "JLROM, JSROM, NES-BTR, Sunsoft 5B",
+            features: MapperFeatures::PrgRam | MapperFeatures::Irq | MapperFeatures::ExpansionAudio,
+            create: |rom| Box::new(Fme7::new(rom)),
+        });
+        registry
+    }
+}
+
+impl MapperRegistry {
+    // An empty registry, see default() for the built in mappers
+    pub fn new() -> Self {
+        MapperRegistry { mappers: Vec::new() }
+    }
+
+    // Adds a mapper, replacing any registered for the same number and submapper
+    pub fn register(&mut self, info: MapperInfo) {
+        self.mappers.retain(|mapper| (mapper.number, mapper.submapper) != (info.number, info.submapper));
+        self.mappers.push(info);
+        self.mappers.sort_by_key(|mapper| (mapper.number, mapper.submapper));
+    }
+
+    // An exact submapper match wins over one registered for all submappers
+    pub fn find(&self, number: u8, submapper: u8) -> Option<&MapperInfo> {
+        let candidates = || self.mappers.iter().filter(|mapper| mapper.number == number);
+        candidates()
+            .find(|mapper| mapper.submapper == Some(submapper))
+            .or_else(|| candidates().find(|mapper| mapper.submapper.is_none()))
+    }
+
+    pub fn create(&self, rom: &Rom) -> Result<Box<dyn Mapper>, RomError> {
+        let info = self.find(rom.mapper, rom.submapper).ok_or(RomError::UnsupportedMapper {
+            mapper: rom.mapper,
+            submapper: rom.submapper,
+        })?;
+        Ok((info.create)(rom))
+    }
+
+    pub fn mappers(&self) -> &[MapperInfo] {
+        &self.mappers
+    }
+}
+
+lazy_static! {
+    static ref REGISTRY: RwLock<MapperRegistry> = RwLock::new(MapperRegistry::default());
+}
+
+// Makes a mapper available to every cartridge inserted afterwards
+pub fn register(info: MapperInfo) {
+    REGISTRY.write().unwrap().register(info);
+}
+
+// Creates the mapper for a cartridge from the global registry
+pub fn create(rom: &Rom) -> Result<Box<dyn Mapper>, RomError> {
+    REGISTRY.read().unwrap().create(rom)
+}
+
+pub fn registered() -> Vec<MapperInfo> {
+    REGISTRY.read().unwrap().mappers().to_vec()
+}
+
+// e.g. "MMC1", None when no registered mapper has the number
+pub fn name(number: u8) -> Option<&'static str> {
+    REGISTRY.read().unwrap().mappers().iter().find(|info| info.number == number).map(|info| info.name)
+}
+
+// Mapper 0: no banking, 16KB images are mirrored into both PRG banks
+pub struct Nrom {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; PRG_RAM_SIZE],
+    pub chr: Vec<u8>,
+    pub chr_writable: bool,
+    pub mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(rom: &Rom) -> Self {
+        Nrom {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            chr_writable: !rom.chr_ram.is_empty(),
+            mirroring: rom.mirroring,
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => {
+                self.prg_rom[(address as usize - 0x8000) % self.prg_rom.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        if let 0x6000..=0x7FFF = address {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[address as usize % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr[address as usize % size] = value;
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl SaveState for Nrom {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        if self.chr_writable {
+            out.extend_from_slice(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr.copy_from_slice(input.read_bytes(size)?);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    #[test]
+    fn test_create() {
+        assert!(create(&Rom::new(&ines(1, 1, 0x00, 0)).unwrap()).is_ok());
+        assert!(create(&Rom::new(&ines(1, 1, 0x50, 0)).unwrap()).is_ok());
+        assert!(matches!(
+            create(&Rom::new(&ines(1, 1, 0x10, 0)).unwrap()),
+            Err(RomError::UnsupportedMapper { mapper: 1, submapper: 0 })
+        ));
+        assert!(registered().iter().any(|mapper| mapper.name == "MMC5"));
+    }
+
+    #[test]
+    fn test_registry_submappers() {
+        let mut registry = MapperRegistry::default();
+        let nrom = *registry.find(0, 0).unwrap();
+        registry.register(MapperInfo { submapper: Some(2), name: "NROM variant", ..nrom });
+        assert_eq!(registry.find(0, 2).unwrap().name, "NROM variant");
+        assert_eq!(registry.find(0, 1).unwrap().name, "NROM");
+        // registering the same number again replaces it
+        registry.register(MapperInfo { name: "NROM replacement", ..nrom });
+        assert_eq!(registry.find(0, 0).unwrap().name, "NROM replacement");
+        assert_eq!(registry.mappers().iter().filter(|mapper| mapper.number == 0).count(), 2);
+        assert!(MapperRegistry::new().find(0, 0).is_none());
+    }
+
+    #[test]
+    fn test_nrom_chr_ram() {
+        let mut nrom = Nrom::new(&Rom::new(&ines(1, 0, 0, 0)).unwrap());
+        nrom.write_chr(0x0010, 0x42);
+        assert_eq!(nrom.read_chr(0x0010), 0x42);
+
+        let mut state = Vec::new();
+        nrom.save_state(&mut state);
+        let mut restored = Nrom::new(&Rom::new(&ines(1, 0, 0, 0)).unwrap());
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.read_chr(0x0010), 0x42);
+    }
+}
@@ -0,0 +1,348 @@
+use std::collections::VecDeque;
+
+// The APU's five channels, in register order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+pub const CHANNEL_COUNT: usize = 5;
+
+// Linear approximation of the mixer's weights, from the nesdev wiki. A channel's
+// output level times its weight gives its share of the 0.0-1.0 output.
+const PULSE_WEIGHT: f32 = 0.00752;
+const TRIANGLE_WEIGHT: f32 = 0.00851;
+const NOISE_WEIGHT: f32 = 0.00494;
+const DMC_WEIGHT: f32 = 0.00335;
+// A cartridge sound chip at full volume, relative to the APU at full volume
+const EXPANSION_WEIGHT: f32 = 0.5;
+
+// The filters between the console's DAC and the RCA jack
+const HIGH_PASS_1_HZ: f32 = 90.0;
+const HIGH_PASS_2_HZ: f32 = 440.0;
+const LOW_PASS_HZ: f32 = 14_000.0;
+
+// Per-channel gains go up to double, to bring out quiet channels when ripping
+pub const MAX_GAIN: f32 = 2.0;
+
+impl Channel {
+    pub const ALL: [Channel; CHANNEL_COUNT] =
+        [Channel::Pulse1, Channel::Pulse2, Channel::Triangle, Channel::Noise, Channel::Dmc];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Channel::Pulse1 => "pulse1",
+            Channel::Pulse2 => "pulse2",
+            Channel::Triangle => "triangle",
+            Channel::Noise => "noise",
+            Channel::Dmc => "dmc",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Channel> {
+        Channel::ALL.into_iter().find(|channel| channel.name() == name.to_ascii_lowercase())
+    }
+
+    // The highest DAC input the channel produces
+    pub fn max_output(self) -> u8 {
+        match self {
+            Channel::Dmc => 127,
+            _ => 15,
+        }
+    }
+
+    fn weight(self) -> f32 {
+        match self {
+            Channel::Pulse1 | Channel::Pulse2 => PULSE_WEIGHT,
+            Channel::Triangle => TRIANGLE_WEIGHT,
+            Channel::Noise => NOISE_WEIGHT,
+            Channel::Dmc => DMC_WEIGHT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mixing {
+    // a weighted sum, cheap and close enough at low volumes
+    Linear,
+    // the DAC's nonlinear response, where loud channels duck the others
+    #[default]
+    Accurate,
+}
+
+impl Mixing {
+    pub fn parse(name: &str) -> Option<Mixing> {
+        match name.to_ascii_lowercase().as_str() {
+            "linear" => Some(Mixing::Linear),
+            "accurate" => Some(Mixing::Accurate),
+            _ => None,
+        }
+    }
+}
+
+// Keys 1-5 mute a channel, Shift+1-5 solo it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MixerAction {
+    ToggleMute(Channel),
+    ToggleSolo(Channel),
+}
+
+impl MixerAction {
+    pub fn from_key(key: &str, shift: bool) -> Option<MixerAction> {
+        let index: usize = key.parse().ok()?;
+        let channel = *Channel::ALL.get(index.checked_sub(1)?)?;
+        Some(if shift { MixerAction::ToggleSolo(channel) } else { MixerAction::ToggleMute(channel) })
+    }
+}
+
+// Combines the channel outputs into one sample, with the user's gains and mutes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Mixer {
+    pub master: f32,
+    // indexed by Channel
+    pub gains: [f32; CHANNEL_COUNT],
+    pub muted: [bool; CHANNEL_COUNT],
+    // only this channel is heard, whatever is muted
+    pub solo: Option<Channel>,
+    pub mixing: Mixing,
+    // the cartridge's sound chip, also silenced by a solo
+    pub expansion_gain: f32,
+}
+
+impl Default for Mixer {
+    fn default() -> Self {
+        Mixer {
+            mixing: Mixing::default(),
+            master: 1.0,
+            gains: [1.0; CHANNEL_COUNT],
+            muted: [false; CHANNEL_COUNT],
+            solo: None,
+            expansion_gain: 1.0,
+        }
+    }
+}
+
+impl Mixer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn apply(&mut self, action: MixerAction) {
+        match action {
+            MixerAction::ToggleMute(channel) => self.muted[channel as usize] = !self.muted[channel as usize],
+            MixerAction::ToggleSolo(channel) if self.solo == Some(channel) => self.solo = None,
+            MixerAction::ToggleSolo(channel) => self.solo = Some(channel),
+        }
+    }
+
+    pub fn is_audible(&self, channel: Channel) -> bool {
+        match self.solo {
+            Some(solo) => solo == channel,
+            None => !self.muted[channel as usize],
+        }
+    }
+
+    // The level each channel contributes after mutes and gains
+    pub fn channel_levels(&self, outputs: [u8; CHANNEL_COUNT]) -> [f32; CHANNEL_COUNT] {
+        Channel::ALL.map(|channel| {
+            if self.is_audible(channel) {
+                outputs[channel as usize] as f32 * self.gains[channel as usize]
+            } else {
+                0.0
+            }
+        })
+    }
+
+    // `outputs` are the channels' DAC inputs: 0-15, and 0-127 for the DMC.
+    // The result is 0.0-1.0 before the master volume, see FilterChain for the rest.
+    pub fn mix(&self, outputs: [u8; CHANNEL_COUNT]) -> f32 {
+        self.mix_with_expansion(outputs, 0.0)
+    }
+
+    // Like mix, plus `expansion` from Mapper::audio_output
+    pub fn mix_with_expansion(&self, outputs: [u8; CHANNEL_COUNT], expansion: f32) -> f32 {
+        let levels = self.channel_levels(outputs);
+        let level = |channel: Channel| levels[channel as usize];
+        let sum = match self.mixing {
+            Mixing::Linear => Channel::ALL.iter().map(|&channel| level(channel) * channel.weight()).sum(),
+            // the formulas from the nesdev wiki's APU Mixer page
+            Mixing::Accurate => {
+                let pulse = level(Channel::Pulse1) + level(Channel::Pulse2);
+                let pulse_out = if pulse > 0.0 { 95.88 / (8128.0 / pulse + 100.0) } else { 0.0 };
+                let tnd = level(Channel::Triangle) / 8227.0 + level(Channel::Noise) / 12241.0 + level(Channel::Dmc) / 22638.0;
+                let tnd_out = if tnd > 0.0 { 159.79 / (1.0 / tnd + 100.0) } else { 0.0 };
+                pulse_out + tnd_out
+            }
+        };
+        let expansion = if self.solo.is_none() { expansion * self.expansion_gain * EXPANSION_WEIGHT } else { 0.0 };
+        (sum + expansion) * self.master
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+// First order RC filter
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct OnePole {
+    kind: FilterKind,
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl OnePole {
+    fn new(kind: FilterKind, cutoff: f32, sample_rate: f32) -> Self {
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt = 1.0 / sample_rate;
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+        OnePole { kind, alpha, previous_input: 0.0, previous_output: 0.0 }
+    }
+
+    fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => self.alpha * (self.previous_output + input - self.previous_input),
+            FilterKind::LowPass => self.previous_output + self.alpha * (input - self.previous_output),
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
+
+// The console's output filters: high-passes at 90Hz and 440Hz, which also take
+// out the mixer's DC offset, then a low-pass at 14kHz. Used with accurate mixing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilterChain {
+    filters: [OnePole; 3],
+}
+
+impl FilterChain {
+    pub fn new(sample_rate: f32) -> Self {
+        FilterChain {
+            filters: [
+                OnePole::new(FilterKind::HighPass, HIGH_PASS_1_HZ, sample_rate),
+                OnePole::new(FilterKind::HighPass, HIGH_PASS_2_HZ, sample_rate),
+                OnePole::new(FilterKind::LowPass, LOW_PASS_HZ, sample_rate),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        self.filters.iter_mut().fold(sample, |sample, filter| filter.process(sample))
+    }
+}
+
+// The recent outputs of each channel, fed the same values as Mixer::mix, for the
+// debugger's oscilloscope (see viewer::channel_scopes). The oldest are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelScope {
+    pub capacity: usize,
+    history: [VecDeque<u8>; CHANNEL_COUNT],
+}
+
+impl ChannelScope {
+    pub fn new(capacity: usize) -> Self {
+        ChannelScope { capacity, history: std::array::from_fn(|_| VecDeque::with_capacity(capacity)) }
+    }
+
+    pub fn record(&mut self, outputs: [u8; CHANNEL_COUNT]) {
+        for (history, output) in self.history.iter_mut().zip(outputs) {
+            if history.len() == self.capacity {
+                history.pop_front();
+            }
+            history.push_back(output);
+        }
+    }
+
+    // Oldest first
+    pub fn history(&self, channel: Channel) -> &VecDeque<u8> {
+        &self.history[channel as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut mixer = Mixer { mixing: Mixing::Linear, ..Mixer::new() };
+        let outputs = [15, 15, 15, 15, 127];
+        let full = mixer.mix(outputs);
+        assert!((full - 0.8528).abs() < 0.001);
+
+        mixer.apply(MixerAction::from_key("4", false).unwrap());
+        assert!(!mixer.is_audible(Channel::Noise));
+        assert!((mixer.mix(outputs) - (full - 15.0 * NOISE_WEIGHT)).abs() < 1e-6);
+
+        // solo wins over mutes, and toggles back off
+        mixer.apply(MixerAction::from_key("3", true).unwrap());
+        assert_eq!(mixer.mix(outputs), 15.0 * TRIANGLE_WEIGHT);
+        mixer.apply(MixerAction::ToggleSolo(Channel::Triangle));
+        assert_eq!(mixer.solo, None);
+        assert_eq!(MixerAction::from_key("6", false), None);
+        assert_eq!(MixerAction::from_key("0", false), None);
+    }
+
+    #[test]
+    fn test_gains() {
+        let mut mixer = Mixer { mixing: Mixing::Linear, ..Mixer::new() };
+        mixer.gains[Channel::Pulse1 as usize] = 2.0;
+        mixer.master = 0.5;
+        assert_eq!(mixer.mix([10, 0, 0, 0, 0]), 10.0 * PULSE_WEIGHT);
+        assert_eq!(Channel::parse("DMC"), Some(Channel::Dmc));
+
+        mixer.expansion_gain = 0.5;
+        assert_eq!(mixer.mix_with_expansion([0; CHANNEL_COUNT], 1.0), 0.25 * EXPANSION_WEIGHT);
+        mixer.solo = Some(Channel::Pulse1);
+        assert_eq!(mixer.mix_with_expansion([0; CHANNEL_COUNT], 1.0), 0.0);
+    }
+
+    #[test]
+    fn test_nonlinear_mixing() {
+        let mixer = Mixer::new();
+        assert_eq!(mixer.mix([0; CHANNEL_COUNT]), 0.0);
+        // full pulses, and everything at full
+        assert!((mixer.mix([15, 15, 0, 0, 0]) - 0.2585).abs() < 0.0001);
+        assert!((mixer.mix([15, 15, 15, 15, 127]) - 1.0).abs() < 0.02);
+        // two pulses are quieter together than the sum of each alone
+        let one = mixer.mix([15, 0, 0, 0, 0]);
+        assert!(mixer.mix([15, 15, 0, 0, 0]) < 2.0 * one);
+        assert_eq!(Mixing::parse("Linear"), Some(Mixing::Linear));
+    }
+
+    #[test]
+    fn test_channel_scope() {
+        let mut scope = ChannelScope::new(3);
+        for level in 0..5 {
+            scope.record([level, 0, 0, 0, level * 10]);
+        }
+        assert_eq!(scope.history(Channel::Pulse1), &[2, 3, 4]);
+        assert_eq!(scope.history(Channel::Dmc), &[20, 30, 40]);
+        assert_eq!(scope.history(Channel::Noise).len(), 3);
+    }
+
+    #[test]
+    fn test_filter_chain() {
+        let mut filters = FilterChain::new(44100.0);
+        // a constant level decays to nothing
+        let settled = (0..44100).map(|_| filters.process(0.5)).last().unwrap();
+        assert!(settled.abs() < 0.001);
+        // a 1kHz tone passes mostly unchanged
+        let mut filters = FilterChain::new(44100.0);
+        let tone = |n: usize| (2.0 * std::f32::consts::PI * 1000.0 * n as f32 / 44100.0).sin();
+        let peak = (0..44100).map(|n| filters.process(tone(n))).skip(22050).fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        assert!(peak > 0.8 && peak < 1.0);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::mapper::{Mapper, PRG_RAM_SIZE};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+const CHR_BANK_SIZE: usize = 0x1000;
+
+// Which of the two CHR banks a pattern table half currently uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Latch {
+    Fd,
+    Fe,
+}
+
+// Mappers 9 (MMC2) and 10 (MMC4). Each pattern table half has an FD and an FE bank,
+// and the PPU fetching tile 0xFD or 0xFE switches the latch after that fetch.
+pub struct Mmc2 {
+    // MMC4 switches 16KB of PRG instead of 8KB and latches on a wider range of addresses
+    pub mmc4: bool,
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; PRG_RAM_SIZE],
+    pub chr: Vec<u8>,
+    pub prg_bank: u8,
+    // [half][latch], from 0xB000-0xE000
+    pub chr_banks: [[u8; 2]; 2],
+    pub latches: [Latch; 2],
+    pub mirroring: Mirroring,
+}
+
+impl Mmc2 {
+    pub fn new(rom: &Rom) -> Self {
+        Mmc2 {
+            mmc4: rom.mapper == 10,
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [Latch::Fe; 2],
+            mirroring: Mirroring::Vertical,
+        }
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let bank_size = if self.mmc4 { 0x4000 } else { 0x2000 };
+        let banks = (self.prg_rom.len() / bank_size).max(1);
+        let slots = 0x8000 / bank_size;
+        let slot = (address as usize - 0x8000) / bank_size;
+        // everything after the switchable bank is fixed to the end of the ROM,
+        // which a ROM smaller than the window mirrors
+        let bank = if slot == 0 {
+            self.prg_bank as usize % banks
+        } else {
+            (banks * slots - (slots - slot)) % banks
+        };
+        bank * bank_size + address as usize % bank_size
+    }
+}
+
+impl Mapper for Mmc2 {
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xFFFF if !self.prg_rom.is_empty() => self.prg_rom[self.prg_offset(address) % self.prg_rom.len()],
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000] = value,
+            0xA000..=0xAFFF => self.prg_bank = value & 0x0F,
+            0xB000..=0xEFFF => {
+                let register = (address as usize - 0xB000) / 0x1000;
+                self.chr_banks[register / 2][register % 2] = value & 0x1F;
+            }
+            0xF000..=0xFFFF => {
+                self.mirroring = if value & 0x01 != 0 { Mirroring::Horizontal } else { Mirroring::Vertical }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        let half = (address as usize >> 12) & 0x01;
+        let bank = self.chr_banks[half][self.latches[half] as usize] as usize;
+        self.chr[(bank * CHR_BANK_SIZE + (address as usize & 0x0FFF)) % self.chr.len()]
+    }
+
+    // Both boards only have CHR ROM
+    fn write_chr(&mut self, _address: u16, _value: u8) {}
+
+    fn ppu_fetch(&mut self, address: u16) {
+        let half = (address as usize >> 12) & 0x01;
+        // MMC2 only latches on the exact address in the lower half
+        let exact = half == 0 && !self.mmc4;
+        match address & 0x0FF8 {
+            0x0FD8 if !exact || address & 0x0FFF == 0x0FD8 => self.latches[half] = Latch::Fd,
+            0x0FE8 if !exact || address & 0x0FFF == 0x0FE8 => self.latches[half] = Latch::Fe,
+            _ => {}
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+}
+
+impl SaveState for Mmc2 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        out.extend_from_slice(&[
+            self.prg_bank,
+            self.chr_banks[0][0],
+            self.chr_banks[0][1],
+            self.chr_banks[1][0],
+            self.chr_banks[1][1],
+            self.latches[0] as u8,
+            self.latches[1] as u8,
+            (self.mirroring == Mirroring::Horizontal) as u8,
+        ]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let latch = |value: u8| if value == Latch::Fd as u8 { Latch::Fd } else { Latch::Fe };
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        self.prg_bank = input.read_u8()?;
+        for half in &mut self.chr_banks {
+            *half = [input.read_u8()?, input.read_u8()?];
+        }
+        self.latches = [latch(input.read_u8()?), latch(input.read_u8()?)];
+        self.mirroring = if input.read_u8()? != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    // 8KB PRG banks and 4KB CHR banks, each filled with its own number
+    fn cartridge(mapper: u8) -> Mmc2 {
+        let mut data = ines(8, 4, (mapper & 0x0F) << 4, mapper & 0xF0);
+        for (bank, chunk) in data[16..16 + 8 * 0x4000].chunks_mut(0x2000).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        for (bank, chunk) in data[16 + 8 * 0x4000..].chunks_mut(CHR_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        Mmc2::new(&Rom::new(&data).unwrap())
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc2 = cartridge(9);
+        mmc2.write_prg(0xA000, 3);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| mmc2.peek_prg(a)), [3, 13, 14, 15]);
+
+        let mut mmc4 = cartridge(10);
+        assert!(mmc4.mmc4);
+        mmc4.write_prg(0xA000, 2);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| mmc4.peek_prg(a)), [4, 5, 14, 15]);
+    }
+
+    #[test]
+    fn test_small_prg_rom() {
+        // a single 16KB bank: two 8KB MMC2 banks mirrored across the fixed ones
+        let mut data = ines(1, 1, 0x90, 0);
+        data[16..16 + 0x2000].fill(0);
+        data[16 + 0x2000..16 + 0x4000].fill(1);
+        let mmc2 = Mmc2::new(&Rom::new(&data).unwrap());
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| mmc2.peek_prg(a)), [0, 1, 0, 1]);
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mmc2 = cartridge(9);
+        mmc2.write_prg(0xB000, 1);
+        mmc2.write_prg(0xC000, 2);
+        mmc2.write_prg(0xD000, 3);
+        mmc2.write_prg(0xE000, 4);
+        // both latches power on as FE
+        assert_eq!((mmc2.read_chr(0x0000), mmc2.read_chr(0x1000)), (2, 4));
+
+        mmc2.ppu_fetch(0x0FD8);
+        mmc2.ppu_fetch(0x1FDA);
+        assert_eq!((mmc2.read_chr(0x0000), mmc2.read_chr(0x1000)), (1, 3));
+        // MMC2 ignores the rest of the tile's rows in the lower half
+        mmc2.ppu_fetch(0x0FE9);
+        assert_eq!(mmc2.latches[0], Latch::Fd);
+        mmc2.ppu_fetch(0x0FE8);
+        assert_eq!(mmc2.read_chr(0x0000), 2);
+    }
+
+    #[test]
+    fn test_mmc4_latch_range_and_mirroring() {
+        let mut mmc4 = cartridge(10);
+        mmc4.ppu_fetch(0x0FDD);
+        assert_eq!(mmc4.latches[0], Latch::Fd);
+        mmc4.write_prg(0xF000, 1);
+        assert_eq!(mmc4.mirroring(), Mirroring::Horizontal);
+    }
+}
@@ -0,0 +1,470 @@
+use crate::mapper::Mapper;
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+const PRG_RAM_SIZE: usize = 64 * 1024;
+const EXRAM_SIZE: usize = 0x400;
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x400;
+// Offset of the attribute table inside a nametable
+const ATTRIBUTE_OFFSET: usize = 0x3C0;
+
+// Where each of the four nametables comes from, two bits each in 0x5105
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NametableSource {
+    CiramA,
+    CiramB,
+    ExRam,
+    Fill,
+}
+
+impl NametableSource {
+    fn from_bits(bits: u8) -> NametableSource {
+        match bits & 0x03 {
+            0 => NametableSource::CiramA,
+            1 => NametableSource::CiramB,
+            2 => NametableSource::ExRam,
+            _ => NametableSource::Fill,
+        }
+    }
+}
+
+// Mapper 5. Implements PRG/CHR banking in every mode, PRG RAM, ExRAM as RAM and
+// as a nametable, fill mode, the multiplier and the scanline IRQ. Split screen and
+// extended attributes need to know which tile the PPU is fetching, so they wait for the PPU.
+pub struct Mmc5 {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: Vec<u8>,
+    pub chr: Vec<u8>,
+    pub chr_writable: bool,
+    pub exram: [u8; EXRAM_SIZE],
+    pub prg_mode: u8,
+    pub chr_mode: u8,
+    pub prg_ram_protect: [u8; 2],
+    pub exram_mode: u8,
+    pub nametable_mapping: u8,
+    pub fill_tile: u8,
+    pub fill_attribute: u8,
+    // 0x5113-0x5117, bit 7 of the 0x5114-0x5116 banks selects ROM
+    pub prg_banks: [u8; 5],
+    // 0x5120-0x5127 for sprites and 8x8 backgrounds, 0x5128-0x512B for 8x16 backgrounds
+    pub chr_banks: [u16; 12],
+    pub chr_upper: u8,
+    // the set the PPU reads from; real hardware picks by fetch type, this follows the last set written
+    pub use_background_banks: bool,
+    pub irq_scanline: u8,
+    pub irq_enabled: bool,
+    pub irq_pending: bool,
+    pub in_frame: bool,
+    pub scanline: u8,
+    pub multiplicand: u8,
+    pub multiplier: u8,
+}
+
+impl Mmc5 {
+    pub fn new(rom: &Rom) -> Self {
+        Mmc5 {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: vec![0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            chr_writable: !rom.chr_ram.is_empty(),
+            exram: [0; EXRAM_SIZE],
+            prg_mode: 3,
+            chr_mode: 0,
+            prg_ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0, 0, 0, 0, 0xFF],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            use_background_banks: false,
+            irq_scanline: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            multiplicand: 0xFF,
+            multiplier: 0xFF,
+        }
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_protect == [0x02, 0x01]
+    }
+
+    // The 0x5114-0x5117 register and bank size for an address in 0x8000-0xFFFF
+    fn prg_register(&self, address: u16) -> (usize, usize) {
+        let slot = (address as usize - 0x8000) / PRG_BANK_SIZE;
+        match (self.prg_mode, slot) {
+            (0, _) => (4, 4),
+            (1, 0..=1) => (2, 2),
+            (1, _) => (4, 2),
+            (2, 0..=1) => (2, 2),
+            (2, 2) => (3, 1),
+            (2, _) => (4, 1),
+            (_, slot) => (slot + 1, 1),
+        }
+    }
+
+    // Resolves a CPU address to ROM (true) or RAM and an offset into it
+    fn map_prg(&self, address: u16) -> (bool, usize) {
+        if address < 0x8000 {
+            let bank = self.prg_banks[0] as usize & 0x07;
+            return (false, bank * PRG_BANK_SIZE + (address as usize & 0x1FFF));
+        }
+        let (register, pages) = self.prg_register(address);
+        let value = self.prg_banks[register];
+        // 0x5117 is always ROM
+        let rom = register == 4 || value & 0x80 != 0;
+        let bank = (value as usize & 0x7F) & !(pages - 1);
+        let offset = (address as usize - 0x8000) % (pages * PRG_BANK_SIZE);
+        (rom, bank * PRG_BANK_SIZE + offset)
+    }
+
+    fn map_chr(&self, address: u16) -> usize {
+        let address = address as usize & 0x1FFF;
+        let (banks, address) = if self.use_background_banks {
+            // the background set only has four registers, repeated for both halves
+            (&self.chr_banks[8..12], address & 0x0FFF)
+        } else {
+            (&self.chr_banks[..8], address)
+        };
+        let count = banks.len();
+        let (index, pages) = match self.chr_mode {
+            0 => (count - 1, 8),
+            1 => ((address / 0x1000 + 1) * 4 - 1, 4),
+            2 => ((address / 0x800 + 1) * 2 - 1, 2),
+            _ => (address / CHR_BANK_SIZE, 1),
+        };
+        let index = index % count;
+        let bank = banks[index] as usize & !(pages - 1);
+        bank * CHR_BANK_SIZE + address % (pages * CHR_BANK_SIZE)
+    }
+
+    pub fn nametable_source(&self, table: usize) -> NametableSource {
+        NametableSource::from_bits(self.nametable_mapping >> (table * 2))
+    }
+
+    fn read_register(&mut self, address: u16) -> u8 {
+        let value = self.peek_prg(address);
+        if address == 0x5204 {
+            self.irq_pending = false;
+        }
+        value
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x5100 => self.prg_mode = value & 0x03,
+            0x5101 => self.chr_mode = value & 0x03,
+            0x5102 => self.prg_ram_protect[0] = value & 0x03,
+            0x5103 => self.prg_ram_protect[1] = value & 0x03,
+            0x5104 => self.exram_mode = value & 0x03,
+            0x5105 => self.nametable_mapping = value,
+            0x5106 => self.fill_tile = value,
+            0x5107 => self.fill_attribute = value & 0x03,
+            0x5113..=0x5117 => self.prg_banks[address as usize - 0x5113] = value,
+            0x5120..=0x512B => {
+                self.chr_banks[address as usize - 0x5120] = value as u16 | (self.chr_upper as u16) << 8;
+                self.use_background_banks = address >= 0x5128;
+            }
+            0x5130 => self.chr_upper = value & 0x03,
+            0x5203 => self.irq_scanline = value,
+            0x5204 => self.irq_enabled = value & 0x80 != 0,
+            0x5205 => self.multiplicand = value,
+            0x5206 => self.multiplier = value,
+            // writable outside of rendering in every mode but read-only
+            0x5C00..=0x5FFF if self.exram_mode != 3 => self.exram[address as usize - 0x5C00] = value,
+            _ => {}
+        }
+    }
+}
+
+impl Mapper for Mmc5 {
+    fn read_prg(&mut self, address: u16) -> u8 {
+        match address {
+            0x5000..=0x5FFF => self.read_register(address),
+            _ => self.peek_prg(address),
+        }
+    }
+
+    fn peek_prg(&self, address: u16) -> u8 {
+        let product = self.multiplicand as u16 * self.multiplier as u16;
+        match address {
+            0x5204 => (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6,
+            0x5205 => product as u8,
+            0x5206 => (product >> 8) as u8,
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => self.exram[address as usize - 0x5C00],
+            0x6000..=0xFFFF => {
+                let (rom, offset) = self.map_prg(address);
+                let memory = if rom { &self.prg_rom } else { &self.prg_ram };
+                if memory.is_empty() {
+                    return 0;
+                }
+                memory[offset % memory.len()]
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x5000..=0x5FFF => self.write_register(address, value),
+            0x6000..=0xFFFF => {
+                let (rom, offset) = self.map_prg(address);
+                if !rom && self.prg_ram_writable() {
+                    let size = self.prg_ram.len();
+                    self.prg_ram[offset % size] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[self.map_chr(address) % self.chr.len()]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let offset = self.map_chr(address) % self.chr.len();
+            self.chr[offset] = value;
+        }
+    }
+
+    // The common layouts; anything else needs read_nametable
+    fn mirroring(&self) -> Mirroring {
+        match self.nametable_mapping {
+            0x44 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    // a PRG RAM bank at $6000 can be switched in above $8000 as well
+    fn changes_prg(&self, _address: u16) -> bool {
+        true
+    }
+
+    fn read_nametable(&self, address: u16, ciram: &[u8]) -> Option<u8> {
+        let table = (address as usize >> 10) & 0x03;
+        let offset = address as usize & 0x3FF;
+        Some(match self.nametable_source(table) {
+            NametableSource::CiramA => ciram[offset],
+            NametableSource::CiramB => ciram[0x400 + offset],
+            NametableSource::ExRam if self.exram_mode < 2 => self.exram[offset],
+            NametableSource::ExRam => 0,
+            NametableSource::Fill if offset < ATTRIBUTE_OFFSET => self.fill_tile,
+            // the same palette for all four quadrants
+            NametableSource::Fill => self.fill_attribute * 0x55,
+        })
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_enabled && self.irq_pending
+    }
+
+    fn scanline(&mut self) {
+        if !self.in_frame {
+            self.in_frame = true;
+            self.scanline = 0;
+            return;
+        }
+        self.scanline = self.scanline.wrapping_add(1);
+        if self.scanline == self.irq_scanline {
+            self.irq_pending = true;
+        }
+    }
+
+    fn end_frame(&mut self) {
+        self.in_frame = false;
+    }
+}
+
+impl SaveState for Mmc5 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        if self.chr_writable {
+            out.extend_from_slice(&self.chr);
+        }
+        out.extend_from_slice(&self.exram);
+        out.extend_from_slice(&[
+            self.prg_mode,
+            self.chr_mode,
+            self.prg_ram_protect[0],
+            self.prg_ram_protect[1],
+            self.exram_mode,
+            self.nametable_mapping,
+            self.fill_tile,
+            self.fill_attribute,
+        ]);
+        out.extend_from_slice(&self.prg_banks);
+        for bank in self.chr_banks {
+            out.extend_from_slice(&bank.to_le_bytes());
+        }
+        out.extend_from_slice(&[
+            self.chr_upper,
+            self.use_background_banks as u8,
+            self.irq_scanline,
+            self.irq_enabled as u8,
+            self.irq_pending as u8,
+            self.in_frame as u8,
+            self.scanline,
+            self.multiplicand,
+            self.multiplier,
+        ]);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr.copy_from_slice(input.read_bytes(size)?);
+        }
+        self.exram.copy_from_slice(input.read_bytes(EXRAM_SIZE)?);
+        self.prg_mode = input.read_u8()?;
+        self.chr_mode = input.read_u8()?;
+        self.prg_ram_protect = [input.read_u8()?, input.read_u8()?];
+        self.exram_mode = input.read_u8()?;
+        self.nametable_mapping = input.read_u8()?;
+        self.fill_tile = input.read_u8()?;
+        self.fill_attribute = input.read_u8()?;
+        self.prg_banks.copy_from_slice(input.read_bytes(5)?);
+        for bank in &mut self.chr_banks {
+            *bank = input.read_u16()?;
+        }
+        self.chr_upper = input.read_u8()?;
+        self.use_background_banks = input.read_u8()? != 0;
+        self.irq_scanline = input.read_u8()?;
+        self.irq_enabled = input.read_u8()? != 0;
+        self.irq_pending = input.read_u8()? != 0;
+        self.in_frame = input.read_u8()? != 0;
+        self.scanline = input.read_u8()?;
+        self.multiplicand = input.read_u8()?;
+        self.multiplier = input.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    // 8 PRG banks and 16 CHR banks, each filled with its own number
+    fn mmc5() -> Mmc5 {
+        let mut data = ines(4, 2, 0x50, 0);
+        for (bank, chunk) in data[16..16 + 4 * 0x4000].chunks_mut(PRG_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        for (bank, chunk) in data[16 + 4 * 0x4000..].chunks_mut(CHR_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        Mmc5::new(&Rom::new(&data).unwrap())
+    }
+
+    #[test]
+    fn test_prg_banking_modes() {
+        let mut mmc5 = mmc5();
+        // power on maps the last bank at 0xE000
+        assert_eq!(mmc5.read_prg(0xFFFC), 7);
+        mmc5.write_prg(0x5114, 0x82);
+        mmc5.write_prg(0x5115, 0x83);
+        mmc5.write_prg(0x5116, 0x84);
+        assert_eq!([0x8000, 0xA000, 0xC000].map(|a| mmc5.read_prg(a)), [2, 3, 4]);
+
+        mmc5.write_prg(0x5100, 0);
+        mmc5.write_prg(0x5117, 0x05);
+        assert_eq!([0x8000, 0xA000, 0xE000].map(|a| mmc5.read_prg(a)), [4, 5, 7]);
+
+        mmc5.write_prg(0x5100, 1);
+        mmc5.write_prg(0x5115, 0x83);
+        assert_eq!([0x8000, 0xA000, 0xC000].map(|a| mmc5.read_prg(a)), [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mmc5 = mmc5();
+        mmc5.write_prg(0x6000, 0x42);
+        assert_eq!(mmc5.read_prg(0x6000), 0, "protected until 0x5102/0x5103 are set");
+        mmc5.write_prg(0x5102, 0x02);
+        mmc5.write_prg(0x5103, 0x01);
+        mmc5.write_prg(0x5113, 1);
+        mmc5.write_prg(0x6000, 0x42);
+        // RAM banked into 0x8000
+        mmc5.write_prg(0x5114, 0x01);
+        assert_eq!(mmc5.read_prg(0x8000), 0x42);
+        mmc5.write_prg(0x5113, 0);
+        assert_eq!(mmc5.read_prg(0x6000), 0);
+    }
+
+    #[test]
+    fn test_chr_banking() {
+        let mut mmc5 = mmc5();
+        mmc5.write_prg(0x5101, 3);
+        mmc5.write_prg(0x5120, 5);
+        mmc5.write_prg(0x5127, 9);
+        assert_eq!((mmc5.read_chr(0x0000), mmc5.read_chr(0x1C00)), (5, 9));
+        mmc5.write_prg(0x5101, 1);
+        mmc5.write_prg(0x5123, 4);
+        assert_eq!((mmc5.read_chr(0x0000), mmc5.read_chr(0x0C00)), (4, 7));
+        // background set repeats in both halves
+        mmc5.write_prg(0x512B, 8);
+        assert_eq!((mmc5.read_chr(0x0000), mmc5.read_chr(0x1000)), (8, 8));
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mmc5 = mmc5();
+        assert_eq!((mmc5.read_prg(0x5205), mmc5.read_prg(0x5206)), (0x01, 0xFE));
+        mmc5.write_prg(0x5205, 200);
+        mmc5.write_prg(0x5206, 3);
+        assert_eq!((mmc5.read_prg(0x5205), mmc5.read_prg(0x5206)), (600u16 as u8, 2));
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc5 = mmc5();
+        mmc5.write_prg(0x5203, 2);
+        mmc5.write_prg(0x5204, 0x80);
+        mmc5.scanline();
+        mmc5.scanline();
+        assert!(!mmc5.irq());
+        assert_eq!(mmc5.read_prg(0x5204), 0x40);
+        mmc5.scanline();
+        assert!(mmc5.irq());
+        // reading the status acknowledges
+        assert_eq!(mmc5.read_prg(0x5204), 0xC0);
+        assert!(!mmc5.irq());
+        mmc5.end_frame();
+        assert_eq!(mmc5.peek_prg(0x5204), 0);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut mmc5 = mmc5();
+        let mut ciram = [0; 0x800];
+        ciram[0x401] = 0x11;
+        // table 0 CIRAM B, table 1 ExRAM, table 2 fill
+        mmc5.write_prg(0x5105, 0b00_11_10_01);
+        mmc5.write_prg(0x5106, 0x24);
+        mmc5.write_prg(0x5107, 2);
+        mmc5.write_prg(0x5C01, 0x33);
+        assert_eq!(mmc5.read_nametable(0x2001, &ciram), Some(0x11));
+        assert_eq!(mmc5.read_nametable(0x2401, &ciram), Some(0x33));
+        assert_eq!(mmc5.read_nametable(0x2801, &ciram), Some(0x24));
+        assert_eq!(mmc5.read_nametable(0x2BC0, &ciram), Some(0xAA));
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt;
+
+use crate::joypad::JoypadButton;
+use crate::nes::{Nes, NesError};
+use crate::savestate::{self, StateError};
+
+// FM2 lists buttons in this order, from bit 7 down to bit 0
+const FM2_BUTTONS: [(char, JoypadButton); 8] = [
+    ('R', JoypadButton::Right),
+    ('L', JoypadButton::Left),
+    ('D', JoypadButton::Down),
+    ('U', JoypadButton::Up),
+    ('T', JoypadButton::Start),
+    ('S', JoypadButton::Select),
+    ('B', JoypadButton::B),
+    ('A', JoypadButton::A),
+];
+
+// FM2 command bits
+pub const COMMAND_SOFT_RESET: u8 = 1 << 0;
+pub const COMMAND_POWER: u8 = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FrameInput {
+    pub commands: u8,
+    pub ports: [JoypadButton; 2],
+}
+
+// The machine state a movie starts from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Anchor {
+    PowerOn,
+    Savestate(Vec<u8>),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    InvalidLine(usize),
+    UnsupportedAnchor,
+    State(StateError),
+    Nes(NesError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::InvalidLine(line) => write!(f, "Invalid movie line {}", line),
+            MovieError::UnsupportedAnchor => write!(f, "FM2 movies can only start from power-on"),
+            MovieError::State(error) => write!(f, "{}", error),
+            MovieError::Nes(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+// Controller input for every frame, replayed from a fixed starting point
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub anchor: Anchor,
+    pub frames: Vec<FrameInput>,
+    pub rom_filename: String,
+    pub rerecord_count: u32,
+}
+
+impl Movie {
+    pub fn new(anchor: Anchor, rom_filename: &str) -> Self {
+        Movie {
+            anchor,
+            frames: Vec::new(),
+            rom_filename: rom_filename.to_string(),
+            rerecord_count: 0,
+        }
+    }
+
+    // Starts recording from the current machine state
+    pub fn from_savestate(nes: &Nes, rom_filename: &str) -> Self {
+        Movie::new(Anchor::Savestate(savestate::save(nes.cpu())), rom_filename)
+    }
+
+    // Puts the console in the movie's starting state. Power-on is a real power
+    // cycle, RAM pattern and all, so a replay starts from the same machine every time.
+    pub fn start(&self, nes: &mut Nes) -> Result<(), MovieError> {
+        match &self.anchor {
+            Anchor::PowerOn => nes.power_cycle().map_err(MovieError::Nes),
+            Anchor::Savestate(state) => savestate::load(nes.cpu_mut(), state).map_err(MovieError::State),
+        }
+    }
+
+    pub fn record(&mut self, input: FrameInput) {
+        self.frames.push(input);
+    }
+
+    // Runs the next frame with the movie's input for it, after start. False once
+    // the movie has ended, leaving the frame unplayed.
+    pub fn play_frame(&self, nes: &mut Nes, frame: usize) -> Result<bool, MovieError> {
+        let Some(input) = self.input(frame) else {
+            return Ok(false);
+        };
+        apply_input(nes, input)?;
+        nes.step_frame();
+        Ok(true)
+    }
+
+    // Runs the next frame with `input` and adds it to the movie
+    pub fn record_frame(&mut self, nes: &mut Nes, input: FrameInput) -> Result<(), MovieError> {
+        apply_input(nes, input)?;
+        nes.step_frame();
+        self.record(input);
+        Ok(())
+    }
+
+    // Input for the given frame, or None once the movie has ended
+    pub fn input(&self, frame: usize) -> Option<FrameInput> {
+        self.frames.get(frame).copied()
+    }
+
+    // Drops everything after `frame` so recording can continue from there
+    pub fn truncate(&mut self, frame: usize) {
+        self.frames.truncate(frame);
+        self.rerecord_count += 1;
+    }
+
+    // Exports to the FCEUX .fm2 text format
+    pub fn to_fm2(&self) -> Result<String, MovieError> {
+        if self.anchor != Anchor::PowerOn {
+            return Err(MovieError::UnsupportedAnchor);
+        }
+        let mut text = format!(
+            "version 3\nemuVersion 22020\nrerecordCount {}\npalFlag 0\nromFilename {}\nfourscore 0\nmicrophone 0\nport0 1\nport1 1\nport2 0\n",
+            self.rerecord_count, self.rom_filename
+        );
+        for frame in &self.frames {
+            text.push_str(&format!(
+                "|{}|{}|{}||\n",
+                frame.commands,
+                format_buttons(frame.ports[0]),
+                format_buttons(frame.ports[1])
+            ));
+        }
+        Ok(text)
+    }
+
+    // Imports an FCEUX .fm2 text movie. Header keys other than the ROM name
+    // and rerecord count are ignored.
+    pub fn from_fm2(text: &str) -> Result<Movie, MovieError> {
+        let mut movie = Movie::new(Anchor::PowerOn, "");
+        for (index, line) in text.lines().enumerate() {
+            let invalid = MovieError::InvalidLine(index + 1);
+            if let Some(fields) = line.strip_prefix('|') {
+                let mut fields = fields.split('|');
+                let commands = fields.next().and_then(|commands| commands.parse().ok()).ok_or(invalid)?;
+                let port0 = parse_buttons(fields.next().unwrap_or(""));
+                let port1 = parse_buttons(fields.next().unwrap_or(""));
+                movie.record(FrameInput { commands, ports: [port0, port1] });
+                continue;
+            }
+            match line.split_once(' ') {
+                Some(("romFilename", name)) => movie.rom_filename = name.to_string(),
+                Some(("rerecordCount", count)) => movie.rerecord_count = count.parse().map_err(|_| invalid)?,
+                Some(("savestate", _)) => return Err(MovieError::UnsupportedAnchor),
+                _ => {}
+            }
+        }
+        Ok(movie)
+    }
+}
+
+// The frame's reset or power command, then its buttons
+fn apply_input(nes: &mut Nes, input: FrameInput) -> Result<(), MovieError> {
+    if input.commands & COMMAND_POWER != 0 {
+        nes.power_cycle().map_err(MovieError::Nes)?;
+    } else if input.commands & COMMAND_SOFT_RESET != 0 {
+        nes.reset().map_err(MovieError::Nes)?;
+    }
+    for (player, buttons) in input.ports.into_iter().enumerate() {
+        nes.set_buttons(player, buttons);
+    }
+    Ok(())
+}
+
+fn format_buttons(buttons: JoypadButton) -> String {
+    FM2_BUTTONS
+        .iter()
+        .map(|&(name, button)| if buttons.contains(button) { name } else { '.' })
+        .collect()
+}
+
+// Any character other than '.' or ' ' means the button is held
+fn parse_buttons(field: &str) -> JoypadButton {
+    field
+        .chars()
+        .zip(FM2_BUTTONS.iter())
+        .filter(|(c, _)| *c != '.' && *c != ' ')
+        .fold(JoypadButton::empty(), |buttons, (_, &(_, button))| buttons | button)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::RamPattern;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_fm2_roundtrip() {
+        let mut movie = Movie::new(Anchor::PowerOn, "game.nes");
+        movie.record(FrameInput { commands: COMMAND_POWER, ports: [JoypadButton::empty(); 2] });
+        movie.record(FrameInput {
+            commands: 0,
+            ports: [JoypadButton::A | JoypadButton::Right, JoypadButton::Start],
+        });
+        let text = movie.to_fm2().unwrap();
+        assert!(text.contains("romFilename game.nes\n"));
+        assert!(text.ends_with("|2|........|........||\n|0|R......A|....T...||\n"));
+        assert_eq!(Movie::from_fm2(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn test_fm2_import_spaces_and_letters() {
+        let movie = Movie::from_fm2("version 3\nrerecordCount 7\n|0|   U   A|||\n").unwrap();
+        assert_eq!(movie.rerecord_count, 7);
+        assert_eq!(movie.input(0).unwrap().ports[0], JoypadButton::Up | JoypadButton::A);
+        assert_eq!(movie.input(1), None);
+        assert_eq!(Movie::from_fm2("|x|........|"), Err(MovieError::InvalidLine(1)));
+    }
+
+    #[test]
+    fn test_savestate_anchor_replays_from_state() {
+        let mut nes = nes();
+        nes.poke(0x0010, 0x42);
+        let mut movie = Movie::from_savestate(&nes, "game.nes");
+        assert_eq!(movie.to_fm2(), Err(MovieError::UnsupportedAnchor));
+
+        nes.poke(0x0010, 0);
+        movie.start(&mut nes).unwrap();
+        assert_eq!(nes.peek(0x0010), 0x42);
+
+        movie.record(FrameInput::default());
+        movie.record(FrameInput::default());
+        movie.truncate(1);
+        assert_eq!(movie.frames.len(), 1);
+        assert_eq!(movie.rerecord_count, 1);
+    }
+
+    #[test]
+    fn test_power_on_anchor_power_cycles() {
+        let mut nes = nes();
+        nes.set_ram_pattern(RamPattern::Ones);
+        nes.step_frame();
+        nes.cpu_mut().bus.ppu.palette[0] = 0x30;
+        Movie::new(Anchor::PowerOn, "").start(&mut nes).unwrap();
+        assert_eq!((nes.peek(0x0010), nes.frame_count()), (0xFF, 0));
+        assert_eq!(nes.cpu().bus.ppu.palette[0], 0);
+        assert_eq!(Movie::new(Anchor::PowerOn, "").start(&mut Nes::new()), Err(MovieError::Nes(NesError::NoCartridge)));
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let mut nes = nes();
+        let mut movie = Movie::new(Anchor::PowerOn, "game.nes");
+        movie.start(&mut nes).unwrap();
+        let inputs = [
+            FrameInput { commands: 0, ports: [JoypadButton::A, JoypadButton::empty()] },
+            FrameInput { commands: COMMAND_SOFT_RESET, ports: [JoypadButton::empty(), JoypadButton::Start] },
+            FrameInput { commands: 0, ports: [JoypadButton::Right, JoypadButton::empty()] },
+        ];
+        for input in inputs {
+            movie.record_frame(&mut nes, input).unwrap();
+        }
+        let recorded = (nes.cpu().cycles, nes.buttons(0), nes.buttons(1));
+        assert_eq!(movie.frames, inputs);
+
+        // playback from the anchor ends up in the same place
+        nes.poke(0x0010, 0x99);
+        movie.start(&mut nes).unwrap();
+        let mut frame = 0;
+        while movie.play_frame(&mut nes, frame).unwrap() {
+            frame += 1;
+        }
+        assert_eq!(frame, 3);
+        assert_eq!((nes.cpu().cycles, nes.buttons(0), nes.buttons(1)), recorded);
+        // the soft reset started the frame count over
+        assert_eq!(nes.frame_count(), 2);
+    }
+
+    fn nes() -> Nes {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        nes
+    }
+}
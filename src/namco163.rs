@@ -0,0 +1,481 @@
+use crate::mapper::{Mapper, PRG_RAM_SIZE};
+use crate::rom::{Mirroring, Rom};
+use crate::savestate::{SaveState, StateError, StateReader};
+
+const PRG_BANK_SIZE: usize = 0x2000;
+const CHR_BANK_SIZE: usize = 0x0400;
+// Bank numbers from here up select a CIRAM page instead of CHR ROM
+const CIRAM_BANKS: u8 = 0xE0;
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+const SOUND_RAM_SIZE: usize = 128;
+// The chip updates one channel every 15 CPU cycles, taking turns
+const CHANNEL_CYCLES: u8 = 15;
+// Channel registers fill the top of sound RAM, 8 bytes each, channel 7 last
+const CHANNEL_REGISTERS: usize = 0x40;
+// A 4 bit sample times a 4 bit volume
+const MAX_CHANNEL_OUTPUT: f32 = 225.0;
+
+// Mapper 19: Namco 129 and 163. Eight 1KB CHR banks and four nametables that can
+// each be CHR ROM or CIRAM, three 8KB PRG banks, a 15 bit IRQ counter and on the
+// 163 a wavetable sound chip with 128 bytes of RAM shared by up to 8 channels.
+pub struct Namco163 {
+    pub prg_rom: Vec<u8>,
+    pub prg_ram: [u8; PRG_RAM_SIZE],
+    pub chr: Vec<u8>,
+    pub chr_writable: bool,
+    // $0000-$1FFF in 1KB banks, then the nametables at $2000, $2400, $2800 and $2C00
+    pub chr_banks: [u8; 12],
+    // $8000, $A000 and $C000; $E000 is fixed to the last bank
+    pub prg_banks: [u8; 3],
+    // $F800: PRG RAM takes writes when the top nibble is 4, the low bits protect 2KB each
+    pub write_protect: u8,
+    pub irq_counter: u16,
+    pub irq_enabled: bool,
+    pub irq_pending: bool,
+    pub audio: Namco163Audio,
+}
+
+impl Namco163 {
+    pub fn new(rom: &Rom) -> Self {
+        Namco163 {
+            prg_rom: rom.prg_rom.clone(),
+            prg_ram: [0; PRG_RAM_SIZE],
+            chr: rom.chr().to_vec(),
+            chr_writable: !rom.chr_ram.is_empty(),
+            chr_banks: [0; 12],
+            prg_banks: [0; 3],
+            write_protect: 0,
+            irq_counter: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            audio: Namco163Audio::new(),
+        }
+    }
+
+    fn read_prg_rom(&self, bank: u8, address: u16) -> u8 {
+        let offset = bank as usize * PRG_BANK_SIZE + address as usize % PRG_BANK_SIZE;
+        self.prg_rom[offset % self.prg_rom.len()]
+    }
+
+    fn prg_ram_writable(&self, address: u16) -> bool {
+        let area = (address as usize - 0x6000) / 0x800;
+        self.write_protect & 0xF0 == 0x40 && self.write_protect & (1 << area) == 0
+    }
+
+    // CHR ROM offset of a 1KB bank
+    fn chr_offset(&self, bank: u8, address: u16) -> usize {
+        (bank as usize * CHR_BANK_SIZE + (address as usize & 0x03FF)) % self.chr.len()
+    }
+
+    fn read_register(&mut self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => self.audio.read_data(),
+            _ => self.peek_prg(address),
+        }
+    }
+}
+
+impl Mapper for Namco163 {
+    fn read_prg(&mut self, address: u16) -> u8 {
+        self.read_register(address)
+    }
+
+    fn peek_prg(&self, address: u16) -> u8 {
+        match address {
+            0x4800..=0x4FFF => self.audio.ram[self.audio.address as usize],
+            0x5000..=0x57FF => self.irq_counter as u8,
+            0x5800..=0x5FFF => (self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7,
+            0x6000..=0x7FFF => self.prg_ram[address as usize - 0x6000],
+            0x8000..=0xDFFF if !self.prg_rom.is_empty() => {
+                self.read_prg_rom(self.prg_banks[(address as usize - 0x8000) / PRG_BANK_SIZE] & 0x3F, address)
+            }
+            0xE000..=0xFFFF if !self.prg_rom.is_empty() => {
+                let last = (self.prg_rom.len() / PRG_BANK_SIZE).max(1) - 1;
+                self.read_prg_rom(last as u8, address)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write_prg(&mut self, address: u16, value: u8) {
+        match address {
+            0x4800..=0x4FFF => self.audio.write_data(value),
+            0x5000..=0x57FF => {
+                self.irq_counter = (self.irq_counter & 0x7F00) | value as u16;
+                self.irq_pending = false;
+            }
+            0x5800..=0x5FFF => {
+                self.irq_counter = (self.irq_counter & 0x00FF) | (value as u16 & 0x7F) << 8;
+                self.irq_enabled = value & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0x6000..=0x7FFF if self.prg_ram_writable(address) => self.prg_ram[address as usize - 0x6000] = value,
+            0x8000..=0xDFFF => self.chr_banks[(address as usize - 0x8000) / 0x800] = value,
+            0xE000..=0xE7FF => {
+                self.prg_banks[0] = value & 0x3F;
+                self.audio.disabled = value & 0x40 != 0;
+            }
+            0xE800..=0xEFFF => self.prg_banks[1] = value & 0x3F,
+            0xF000..=0xF7FF => self.prg_banks[2] = value & 0x3F,
+            0xF800..=0xFFFF => {
+                self.write_protect = value;
+                self.audio.write_address(value);
+            }
+            _ => {}
+        }
+    }
+
+    // Banks $E0 and up in the pattern tables would be CIRAM, which no game relies on;
+    // they read CHR ROM here
+    fn read_chr(&mut self, address: u16) -> u8 {
+        if self.chr.is_empty() {
+            return 0;
+        }
+        self.chr[self.chr_offset(self.chr_banks[(address as usize >> 10) & 0x07], address)]
+    }
+
+    fn write_chr(&mut self, address: u16, value: u8) {
+        if self.chr_writable {
+            let offset = self.chr_offset(self.chr_banks[(address as usize >> 10) & 0x07], address);
+            self.chr[offset] = value;
+        }
+    }
+
+    // Only right for the common layouts, read_nametable and write_nametable do the rest
+    fn mirroring(&self) -> Mirroring {
+        if self.chr_banks[8] == self.chr_banks[9] {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        }
+    }
+
+    fn save_ram(&self) -> Option<&[u8]> {
+        Some(&self.prg_ram)
+    }
+
+    fn save_ram_mut(&mut self) -> Option<&mut [u8]> {
+        Some(&mut self.prg_ram)
+    }
+
+    fn read_nametable(&self, address: u16, ciram: &[u8]) -> Option<u8> {
+        let bank = self.chr_banks[8 + ((address as usize >> 10) & 0x03)];
+        Some(if bank >= CIRAM_BANKS {
+            ciram[(bank as usize & 0x01) * 0x400 + (address as usize & 0x03FF)]
+        } else if self.chr.is_empty() {
+            0
+        } else {
+            self.chr[self.chr_offset(bank, address)]
+        })
+    }
+
+    fn write_nametable(&mut self, address: u16, value: u8, ciram: &mut [u8]) -> bool {
+        let bank = self.chr_banks[8 + ((address as usize >> 10) & 0x03)];
+        if bank >= CIRAM_BANKS {
+            ciram[(bank as usize & 0x01) * 0x400 + (address as usize & 0x03FF)] = value;
+        }
+        true
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles {
+            // counts up and stops at the top
+            if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+                self.irq_counter += 1;
+                if self.irq_counter == IRQ_COUNTER_MAX {
+                    self.irq_pending = true;
+                }
+            }
+            self.audio.clock();
+        }
+    }
+
+    fn audio_output(&self) -> f32 {
+        self.audio.output()
+    }
+}
+
+impl SaveState for Namco163 {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.prg_ram);
+        out.extend_from_slice(&self.chr_banks);
+        out.extend_from_slice(&self.prg_banks);
+        out.extend_from_slice(&[self.write_protect, self.irq_enabled as u8, self.irq_pending as u8]);
+        out.extend_from_slice(&self.irq_counter.to_le_bytes());
+        self.audio.save_state(out);
+        if self.chr_writable {
+            out.extend_from_slice(&self.chr);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.prg_ram.copy_from_slice(input.read_bytes(PRG_RAM_SIZE)?);
+        self.chr_banks.copy_from_slice(input.read_bytes(12)?);
+        self.prg_banks.copy_from_slice(input.read_bytes(3)?);
+        let bytes = input.read_bytes(3)?;
+        self.write_protect = bytes[0];
+        self.irq_enabled = bytes[1] != 0;
+        self.irq_pending = bytes[2] != 0;
+        self.irq_counter = input.read_u16()? & IRQ_COUNTER_MAX;
+        self.audio.load_state(input)?;
+        if self.chr_writable {
+            let size = self.chr.len();
+            self.chr.copy_from_slice(input.read_bytes(size)?);
+        }
+        Ok(())
+    }
+}
+
+// The 163's sound: 128 bytes of RAM holding 4 bit samples two to a byte, with the
+// registers of up to 8 channels at the top. Each channel plays a wave of up to 256
+// samples from anywhere in the RAM. More channels share the chip's time, so each
+// one updates less often.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Namco163Audio {
+    pub ram: [u8; SOUND_RAM_SIZE],
+    // sound RAM address for $4800, from $F800
+    pub address: u8,
+    pub auto_increment: bool,
+    // $E000 bit 6
+    pub disabled: bool,
+    pub timer: u8,
+    // the channel updated next, counting down from 7
+    pub channel: u8,
+    // each channel's sample times volume, as of its last update
+    pub outputs: [u8; 8],
+}
+
+impl Default for Namco163Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Namco163Audio {
+    pub fn new() -> Self {
+        Namco163Audio {
+            ram: [0; SOUND_RAM_SIZE],
+            address: 0,
+            auto_increment: false,
+            disabled: false,
+            timer: 0,
+            channel: 7,
+            outputs: [0; 8],
+        }
+    }
+
+    pub fn write_address(&mut self, value: u8) {
+        self.address = value & 0x7F;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    fn advance_address(&mut self) {
+        if self.auto_increment {
+            self.address = (self.address + 1) & 0x7F;
+        }
+    }
+
+    pub fn read_data(&mut self) -> u8 {
+        let value = self.ram[self.address as usize];
+        self.advance_address();
+        value
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.ram[self.address as usize] = value;
+        self.advance_address();
+    }
+
+    // Channels 7 down to 8 - this are playing
+    pub fn active_channels(&self) -> u8 {
+        ((self.ram[SOUND_RAM_SIZE - 1] >> 4) & 0x07) + 1
+    }
+
+    fn sample(&self, index: u8) -> u8 {
+        (self.ram[index as usize / 2] >> ((index & 0x01) * 4)) & 0x0F
+    }
+
+    // Registers: frequency (18 bits) and phase (24 bits) interleaved in bytes 0-5,
+    // the wave length in the top of byte 4, the wave's start sample in 6, volume in 7
+    fn update_channel(&mut self, channel: u8) {
+        let base = CHANNEL_REGISTERS + channel as usize * 8;
+        let registers = &mut self.ram[base..base + 8];
+        let frequency = registers[0] as u32 | (registers[2] as u32) << 8 | (registers[4] as u32 & 0x03) << 16;
+        let length = 256 - (registers[4] & 0xFC) as u32;
+        let mut phase = registers[1] as u32 | (registers[3] as u32) << 8 | (registers[5] as u32) << 16;
+        phase = (phase + frequency) % (length << 16);
+        registers[1] = phase as u8;
+        registers[3] = (phase >> 8) as u8;
+        registers[5] = (phase >> 16) as u8;
+        let index = registers[6].wrapping_add((phase >> 16) as u8);
+        let volume = registers[7] & 0x0F;
+        self.outputs[channel as usize] = self.sample(index) * volume;
+    }
+
+    // One CPU cycle
+    pub fn clock(&mut self) {
+        if self.disabled {
+            return;
+        }
+        self.timer += 1;
+        if self.timer < CHANNEL_CYCLES {
+            return;
+        }
+        self.timer = 0;
+        self.update_channel(self.channel);
+        let lowest = 8 - self.active_channels();
+        self.channel = if self.channel <= lowest { 7 } else { self.channel - 1 };
+    }
+
+    // 0.0-1.0, for the mixer. The chip plays one channel at a time, so more
+    // channels means each is quieter.
+    pub fn output(&self) -> f32 {
+        if self.disabled {
+            return 0.0;
+        }
+        let active = self.active_channels();
+        let sum: u32 = self.outputs[(8 - active) as usize..].iter().map(|&output| output as u32).sum();
+        sum as f32 / active as f32 / MAX_CHANNEL_OUTPUT
+    }
+}
+
+impl SaveState for Namco163Audio {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.ram);
+        out.extend_from_slice(&[self.address, self.auto_increment as u8, self.disabled as u8, self.timer, self.channel]);
+        out.extend_from_slice(&self.outputs);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.ram.copy_from_slice(input.read_bytes(SOUND_RAM_SIZE)?);
+        let bytes = input.read_bytes(5)?;
+        self.address = bytes[0] & 0x7F;
+        self.auto_increment = bytes[1] != 0;
+        self.disabled = bytes[2] != 0;
+        self.timer = bytes[3] % CHANNEL_CYCLES;
+        self.channel = bytes[4] & 0x07;
+        self.outputs.copy_from_slice(input.read_bytes(8)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+
+    // 8KB PRG banks and 1KB CHR banks, each filled with its own number
+    fn cartridge() -> Namco163 {
+        let mut data = ines(8, 2, 0x30, 0x10);
+        for (bank, chunk) in data[16..16 + 8 * 0x4000].chunks_mut(PRG_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        for (bank, chunk) in data[16 + 8 * 0x4000..].chunks_mut(CHR_BANK_SIZE).enumerate() {
+            chunk.fill(bank as u8);
+        }
+        Namco163::new(&Rom::new(&data).unwrap())
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut namco = cartridge();
+        namco.write_prg(0xE000, 3);
+        namco.write_prg(0xE800, 4);
+        namco.write_prg(0xF000, 5);
+        namco.write_prg(0xB800, 9);
+        assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|a| namco.peek_prg(a)), [3, 4, 5, 15]);
+        assert_eq!(namco.read_chr(0x1C00), 9);
+
+        // PRG RAM only takes writes with the right key in $F800
+        namco.write_prg(0x6000, 0x42);
+        assert_eq!(namco.peek_prg(0x6000), 0);
+        namco.write_prg(0xF800, 0x40);
+        namco.write_prg(0x6000, 0x42);
+        assert_eq!(namco.peek_prg(0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_nametables() {
+        let mut namco = cartridge();
+        let mut ciram = [0; 0x800];
+        // CIRAM page 1 at $2400, CHR ROM bank 6 at $2800
+        namco.write_prg(0xC800, 0xE1);
+        namco.write_prg(0xD000, 6);
+        assert!(namco.write_nametable(0x2405, 0x77, &mut ciram));
+        assert_eq!(ciram[0x405], 0x77);
+        assert_eq!(namco.read_nametable(0x2405, &ciram), Some(0x77));
+        assert_eq!(namco.read_nametable(0x2805, &ciram), Some(6));
+    }
+
+    #[test]
+    fn test_irq_counter() {
+        let mut namco = cartridge();
+        namco.write_prg(0x5000, 0xFD);
+        namco.write_prg(0x5800, 0xFF);
+        namco.tick(1);
+        assert!(!namco.irq());
+        namco.tick(1);
+        assert!(namco.irq());
+        // stopped at the top
+        namco.tick(10);
+        assert_eq!(namco.irq_counter, IRQ_COUNTER_MAX);
+        namco.write_prg(0x5800, 0x00);
+        assert!(!namco.irq());
+    }
+
+    #[test]
+    fn test_sound_ram_port() {
+        let mut namco = cartridge();
+        namco.write_prg(0xF800, 0x80 | 0x10);
+        namco.write_prg(0x4800, 0x12);
+        namco.write_prg(0x4800, 0x34);
+        assert_eq!(&namco.audio.ram[0x10..0x12], &[0x12, 0x34]);
+        namco.write_prg(0xF800, 0x80 | 0x10);
+        assert_eq!((namco.read_prg(0x4800), namco.read_prg(0x4800)), (0x12, 0x34));
+        assert_eq!(namco.peek_prg(0x4800), 0);
+    }
+
+    #[test]
+    fn test_wavetable() {
+        let mut audio = Namco163Audio::new();
+        // a 4 sample wave 15, 0, 15, 0 at sample 0, one channel at full volume
+        // stepping one sample per update
+        audio.ram[0..2].copy_from_slice(&[0x0F, 0x0F]);
+        audio.ram[0x78..0x80].copy_from_slice(&[0x00, 0x00, 0x00, 0x00, 0xFD, 0x00, 0x00, 0x0F]);
+        let mut levels = Vec::new();
+        for _ in 0..4 {
+            for _ in 0..CHANNEL_CYCLES {
+                audio.clock();
+            }
+            levels.push(audio.output());
+        }
+        assert_eq!(levels, [0.0, 1.0, 0.0, 1.0]);
+
+        // a second channel halves the first's share
+        audio.ram[0x7F] |= 0x10;
+        assert_eq!(audio.active_channels(), 2);
+        assert_eq!(audio.output(), 0.5);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut namco = cartridge();
+        namco.write_prg(0xE000, 6);
+        namco.write_prg(0xF800, 0x20);
+        namco.write_prg(0x4800, 0x99);
+        namco.write_prg(0x5800, 0x80);
+        namco.tick(100);
+
+        let mut state = Vec::new();
+        namco.save_state(&mut state);
+        let mut restored = cartridge();
+        restored.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(restored.peek_prg(0x8000), 6);
+        assert_eq!(restored.irq_counter, 100);
+        assert_eq!(restored.audio, namco.audio);
+    }
+}
@@ -0,0 +1,808 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::apu::ApuState;
+use crate::bus::{NesBus, RamPattern};
+use crate::cartridge::Cartridge;
+use crate::cheat::CheatList;
+use crate::cpu::{Cpu, CpuFault, CpuState, IrqSource, Memory};
+use crate::fds::{self, Fds, FdsError, FdsImage};
+use crate::gamedb::GameDatabase;
+use crate::hooks::Hooks;
+use crate::input::InputDevice;
+use crate::joypad::JoypadButton;
+use crate::mixer::{ChannelScope, Mixer};
+use crate::palette::Palette;
+use crate::ppu::{Layers, Ppu, PpuState};
+use crate::rom::{Rom, RomError};
+use crate::savestate;
+use crate::viewer::Image;
+
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NesError {
+    NoCartridge,
+    UnsupportedMapper(u8),
+}
+
+impl fmt::Display for NesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NesError::NoCartridge => write!(f, "No cartridge inserted"),
+            NesError::UnsupportedMapper(mapper) => write!(f, "Mapper {} is not supported", mapper),
+        }
+    }
+}
+
+// Inspectable state of the whole console
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NesState {
+    pub cpu: CpuState,
+    pub ppu: PpuState,
+    pub apu: ApuState,
+    pub frame: u64,
+    pub scanline: u64,
+    pub dot: u64,
+}
+
+// More than this and the rolled back frames cost more than they hide
+pub const MAX_RUN_AHEAD: u32 = 4;
+
+// What step_frame produced, borrowed from the console until it runs again
+pub struct FrameOutput<'a> {
+    pub image: &'a Image,
+    pub audio: &'a [f32],
+    // false when the CPU faulted part way, see Nes::fault
+    pub complete: bool,
+}
+
+// The console as a whole, for embedding madNES in other programs.
+// frame() is the PPU's last finished picture and audio() the APU's samples
+// for it, see NesBus::end_audio_frame. Everything it owns is Send, so it can run on a thread of its own, see
+// EmulationThread.
+pub struct Nes {
+    cpu: Box<Cpu<NesBus>>,
+    frame: Image,
+    audio: Vec<f32>,
+    frames: u64,
+    // applied at the start of every frame, cleared with the cartridge
+    cheats: CheatList,
+    hooks: Hooks,
+    // what internal RAM holds after a power cycle
+    ram_pattern: RamPattern,
+}
+
+impl Default for Nes {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Nes {
+    pub fn new() -> Self {
+        Nes {
+            cpu: Box::new(Cpu::with_bus(NesBus::new())),
+            frame: Image::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            audio: Vec::new(),
+            frames: 0,
+            cheats: CheatList::new(),
+            hooks: Hooks::new(),
+            ram_pattern: RamPattern::default(),
+        }
+    }
+
+    // Inserts the cartridge and powers the console on
+    pub fn insert_cartridge(&mut self, rom: Rom) -> Result<(), NesError> {
+        let mapper = rom.mapper;
+        let cartridge = Cartridge::new(rom).map_err(|_| NesError::UnsupportedMapper(mapper))?;
+        self.power_on(cartridge);
+        self.cheats = CheatList::new();
+        self.reset()
+    }
+
+    // Plugs the cartridge into a console fresh from power off
+    fn power_on(&mut self, cartridge: Cartridge) {
+        self.power_off();
+        let trainer = cartridge.trainer().map(|(address, trainer)| (address, trainer.to_vec()));
+        self.cpu.bus.insert_cartridge(cartridge);
+        if let Some((start, trainer)) = trainer {
+            for (address, value) in (start..).zip(trainer) {
+                self.poke(address, value);
+            }
+        }
+    }
+
+    // Swaps in the game from an iNES file, e.g. one dropped onto the window.
+    // The current game keeps running when the file can't be loaded.
+    // Fixes from the user's games.toml are applied; an unreadable database is ignored.
+    pub fn load_rom_file(&mut self, path: impl AsRef<Path>) -> Result<(), RomError> {
+        self.load_patched_rom_file(path, &[])
+    }
+
+    // Like load_rom_file, with the IPS or BPS patches applied first
+    pub fn load_patched_rom_file(&mut self, path: impl AsRef<Path>, patches: &[PathBuf]) -> Result<(), RomError> {
+        let mut rom = Rom::load_patched(path, patches)?;
+        if let Ok(database) = GameDatabase::load_default() {
+            database.apply(&mut rom);
+        }
+        let (mapper, submapper) = (rom.mapper, rom.submapper);
+        self.insert_cartridge(rom)
+            .map_err(|_| RomError::UnsupportedMapper { mapper, submapper })
+    }
+
+    // Inserts a Famicom Disk System disk into the RAM adapter and powers on.
+    // There's no cartridge to speak of, so cartridge() returns None.
+    pub fn insert_disk(&mut self, disk: &FdsImage, bios: Vec<u8>) -> Result<(), NesError> {
+        self.power_on(Cartridge::from_mapper(Box::new(Fds::new(bios, disk))));
+        self.cheats = CheatList::new();
+        self.reset()
+    }
+
+    // Like load_rom_file, for .fds images. The BIOS comes from `bios`.
+    pub fn load_disk_file(&mut self, path: impl AsRef<Path>, bios: &Path) -> Result<(), FdsError> {
+        let disk = FdsImage::load(path)?;
+        let bios = fds::load_bios(bios)?;
+        self.insert_disk(&disk, bios).map_err(|_| FdsError::InvalidImage)
+    }
+
+    // Flips the disk over, see Mapper::switch_disk_side. None without a disk.
+    pub fn switch_disk_side(&mut self) -> Option<usize> {
+        self.cpu.bus.cartridge.as_mut()?.mapper.switch_disk_side()
+    }
+
+    // Removes the cartridge, leaving a console with nothing to run
+    pub fn eject_cartridge(&mut self) -> Option<Rom> {
+        let cartridge = self.cpu.bus.cartridge.take();
+        self.power_off();
+        self.frames = 0;
+        self.audio.clear();
+        cartridge?.into_rom()
+    }
+
+    // Fresh CPU and bus, keeping settings like the decode cache, the palette and the mixer
+    fn power_off(&mut self) {
+        let decode_cache = self.cpu.decode_cache_enabled();
+        let ppu = &mut self.cpu.bus.ppu;
+        let (colors, layers, mixer) = (std::mem::take(&mut ppu.colors), ppu.layers, self.cpu.bus.mixer);
+        let scope = self.cpu.bus.scope.take();
+        *self.cpu = Cpu::with_bus(NesBus::new());
+        self.cpu.set_decode_cache(decode_cache);
+        (self.cpu.bus.ppu.colors, self.cpu.bus.ppu.layers) = (colors, layers);
+        (self.cpu.bus.mixer, self.cpu.bus.scope) = (mixer, scope);
+        self.cpu.bus.fill_ram(self.ram_pattern);
+    }
+
+    // Applies from the next power cycle or cartridge change
+    pub fn set_ram_pattern(&mut self, pattern: RamPattern) {
+        self.ram_pattern = pattern;
+    }
+
+    pub fn ram_pattern(&self) -> RamPattern {
+        self.ram_pattern
+    }
+
+    // The inserted game's image, None for a disk
+    pub fn cartridge(&self) -> Option<&Rom> {
+        self.cpu.bus.cartridge.as_ref()?.rom()
+    }
+
+    // See Cartridge::battery_ram
+    pub fn battery_ram(&self) -> Option<&[u8]> {
+        self.cpu.bus.cartridge.as_ref()?.battery_ram()
+    }
+
+    pub fn battery_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.cpu.bus.cartridge.as_mut()?.battery_ram_mut()
+    }
+
+    pub fn load_battery_ram(&mut self, data: &[u8]) -> bool {
+        self.cpu.bus.cartridge.as_mut().is_some_and(|cartridge| cartridge.load_battery_ram(data))
+    }
+
+    // The cartridge's PRG RAM, battery or not
+    pub fn prg_ram(&self) -> Option<&[u8]> {
+        self.cpu.bus.cartridge.as_ref()?.mapper.save_ram()
+    }
+
+    pub fn prg_ram_mut(&mut self) -> Option<&mut [u8]> {
+        self.cpu.bus.cartridge.as_mut()?.mapper.save_ram_mut()
+    }
+
+    // The console's reset button: the CPU starts over from the reset vector and the
+    // PPU's registers clear, but RAM, VRAM and the cartridge keep their contents
+    pub fn reset(&mut self) -> Result<(), NesError> {
+        if self.cpu.bus.cartridge.is_none() {
+            return Err(NesError::NoCartridge);
+        }
+        self.cpu.bus.ppu.reset();
+        self.cpu.bus.apu.reset();
+        self.cpu.reset();
+        self.frames = 0;
+        Ok(())
+    }
+
+    // Switching the console off and on again: everything starts over, internal RAM
+    // from the RAM pattern, except what survives on the cartridge's battery.
+    // A disk stays in the drive as it is.
+    pub fn power_cycle(&mut self) -> Result<(), NesError> {
+        let Some(mut cartridge) = self.cpu.bus.cartridge.take() else {
+            return Err(NesError::NoCartridge);
+        };
+        cartridge.power_cycle();
+        self.power_on(cartridge);
+        self.audio.clear();
+        self.reset()
+    }
+
+    // Executes one CPU instruction and returns the cycles it took
+    pub fn step_instruction(&mut self) -> u16 {
+        if self.hooks.is_empty() {
+            return self.execute_instruction();
+        }
+        let (frame, scanline) = (self.cpu.bus.ppu.frame, self.cpu.bus.ppu.scanline);
+        if self.hooks.watches_writes() {
+            self.cpu.bus.write_log = Some(Vec::new());
+        }
+        let cycles = self.execute_instruction();
+        let writes = self.cpu.bus.write_log.take().unwrap_or_default();
+        self.hooks.after_instruction(&mut self.cpu, frame, scanline, &writes);
+        cycles
+    }
+
+    fn execute_instruction(&mut self) -> u16 {
+        let cycles = self.cpu.step();
+        let ppu = &mut self.cpu.bus.ppu;
+        if std::mem::take(&mut ppu.frame_complete) {
+            std::mem::swap(&mut self.frame, &mut ppu.front_buffer);
+        }
+        let irq = self.cpu.bus.cartridge.as_ref().is_some_and(|cartridge| cartridge.mapper.irq());
+        self.cpu.set_irq(IrqSource::Mapper, irq);
+        let apu = &self.cpu.bus.apu;
+        let (frame_irq, dmc_irq) = (apu.frame_irq, apu.dmc.irq);
+        self.cpu.set_irq(IrqSource::FrameCounter, frame_irq);
+        self.cpu.set_irq(IrqSource::Dmc, dmc_irq);
+        self.cpu.set_nmi(self.cpu.bus.ppu.nmi_output());
+        cycles
+    }
+
+    // Runs until the end of the current video frame and hands back the picture and
+    // the sound it made. The GUI, headless runs, libretro and netplay all advance
+    // the console this way.
+    pub fn step_frame(&mut self) -> FrameOutput<'_> {
+        self.audio.clear();
+        let cheats = std::mem::take(&mut self.cheats);
+        cheats.apply(self);
+        self.cheats = cheats;
+        let frame = self.cpu.bus.ppu.frame;
+        self.run_until(|ppu| ppu.frame != frame);
+        self.cpu.bus.end_audio_frame(&mut self.audio);
+        FrameOutput {
+            image: &self.frame,
+            audio: &self.audio,
+            complete: self.cpu.bus.ppu.frame != frame,
+        }
+    }
+
+    // Run-ahead: runs the frame, then `frames` more with the same input from a
+    // savestate that's loaded again after, so the picture shows the input's effect
+    // that many frames sooner than the game's own lag would. The sound is the real
+    // frame's. Hooks see the frames that get rolled back too.
+    pub fn step_frame_ahead(&mut self, frames: u32) -> FrameOutput<'_> {
+        if frames == 0 {
+            return self.step_frame();
+        }
+        let complete = self.step_frame().complete;
+        let audio = std::mem::take(&mut self.audio);
+        let (state, count, fault) = (savestate::save(&*self.cpu), self.frames, self.cpu.fault);
+        // the sound still to come out of the filters isn't either
+        let (blip, filters) = (self.cpu.bus.blip.clone(), self.cpu.bus.filters.clone());
+        for _ in 0..frames {
+            self.step_frame();
+        }
+        // the frame drawn ahead stays in self.frame, which isn't part of the state
+        savestate::load(&mut *self.cpu, &state).expect("reloading a state just saved");
+        (self.cpu.bus.blip, self.cpu.bus.filters) = (blip, filters);
+        (self.frames, self.cpu.fault, self.audio) = (count, fault, audio);
+        FrameOutput { image: &self.frame, audio: &self.audio, complete }
+    }
+
+    // Runs until the end of the current scanline, for stepping through raster effects
+    pub fn step_scanline(&mut self) {
+        let scanline = self.cpu.bus.ppu.scanline;
+        self.run_until(|ppu| ppu.scanline != scanline);
+    }
+
+    // Runs whole instructions until the PPU gets to where `done` wants it.
+    // Stops early if the CPU faults, leaving the machine paused on the faulted instruction.
+    fn run_until(&mut self, done: impl Fn(&Ppu) -> bool) {
+        let frame = self.cpu.bus.ppu.frame;
+        while !done(&self.cpu.bus.ppu) && self.cpu.fault.is_none() {
+            self.step_instruction();
+        }
+        self.frames += self.cpu.bus.ppu.frame - frame;
+    }
+
+    // The PPU (scanline, dot) the CPU has reached
+    pub fn position(&self) -> (u64, u64) {
+        let ppu = &self.cpu.bus.ppu;
+        (ppu.scanline as u64, ppu.dot as u64)
+    }
+
+    // Number of frames completed since reset
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    // Reads CPU memory
+    pub fn peek(&self, address: u16) -> u8 {
+        self.cpu.read_byte(address)
+    }
+
+    // Writes CPU memory
+    pub fn poke(&mut self, address: u16, value: u8) {
+        self.cpu.write_byte(address, value);
+    }
+
+    // The last completed frame as 256x240 RGB
+    pub fn frame(&self) -> &Image {
+        &self.frame
+    }
+
+    // The colors frames are drawn in from now on, see Palette::select
+    pub fn set_palette(&mut self, palette: Palette) {
+        self.cpu.bus.ppu.colors = palette;
+    }
+
+    // Debug switches hiding the background or the sprites from the picture
+    pub fn layers_mut(&mut self) -> &mut Layers {
+        &mut self.cpu.bus.ppu.layers
+    }
+
+    // Samples generated during the last frame, mono at apu::SAMPLE_RATE
+    pub fn audio(&self) -> &[f32] {
+        &self.audio
+    }
+
+    // Channel gains, mutes and solo. Like the palette they outlast a power cycle.
+    pub fn mixer_mut(&mut self) -> &mut Mixer {
+        &mut self.cpu.bus.mixer
+    }
+
+    // Starts keeping the last `capacity` outputs of each channel for the debugger's
+    // oscilloscope, see viewer::channel_scopes. 0 stops it.
+    pub fn set_channel_scope(&mut self, capacity: usize) {
+        self.cpu.bus.scope = (capacity > 0).then(|| ChannelScope::new(capacity));
+    }
+
+    pub fn channel_scope(&self) -> Option<&ChannelScope> {
+        self.cpu.bus.scope.as_ref()
+    }
+
+    // Sets the buttons currently held on the controller of player 0 or 1
+    pub fn set_buttons(&mut self, player: usize, buttons: JoypadButton) {
+        self.cpu.bus.ports[player].set_buttons(buttons);
+    }
+
+    pub fn buttons(&self, player: usize) -> JoypadButton {
+        self.cpu.bus.ports[player].buttons()
+    }
+
+    // Plugs a device into controller port 0 or 1, replacing what was there
+    pub fn connect(&mut self, port: usize, device: Box<dyn InputDevice>) {
+        self.cpu.bus.ports[port] = device;
+    }
+
+    pub fn port_mut(&mut self, port: usize) -> &mut dyn InputDevice {
+        self.cpu.bus.ports[port].as_mut()
+    }
+
+    pub fn cheats(&self) -> &CheatList {
+        &self.cheats
+    }
+
+    pub fn cheats_mut(&mut self) -> &mut CheatList {
+        &mut self.cheats
+    }
+
+    // Callbacks on frames, NMIs, scanlines and memory writes, see Hooks
+    pub fn hooks_mut(&mut self) -> &mut Hooks {
+        &mut self.hooks
+    }
+
+    // Set while the CPU is stuck on an instruction it can't execute. Frames stop
+    // advancing until it's skipped or patched over through cpu_mut.
+    pub fn fault(&self) -> Option<CpuFault> {
+        self.cpu.fault
+    }
+
+    pub fn cpu(&self) -> &Cpu<NesBus> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<NesBus> {
+        &mut self.cpu
+    }
+
+    pub fn snapshot(&self) -> NesState {
+        let (scanline, dot) = self.position();
+        NesState {
+            cpu: self.cpu.snapshot(),
+            ppu: self.cpu.bus.ppu.snapshot(),
+            apu: self.cpu.bus.apu.snapshot(),
+            frame: self.frames,
+            scanline,
+            dot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::{ines, ines_with_program};
+    use crate::ppu::DOTS_PER_FRAME;
+    use crate::rom::TRAINER_SIZE;
+    use crate::mapper::PRG_RAM_SIZE;
+    use crate::patch::PatchError;
+    use crate::testdir::TestDir;
+
+    // JMP $8000
+    fn image(mapper: u8) -> Vec<u8> {
+        let mut data = ines_with_program(&[0x4C, 0x00, 0x80]);
+        data[6] = mapper << 4;
+        data
+    }
+
+    fn rom(mapper: u8) -> Rom {
+        Rom::new(&image(mapper)).unwrap()
+    }
+
+    #[test]
+    fn test_insert_cartridge() {
+        let mut nes = Nes::new();
+        assert_eq!(nes.reset(), Err(NesError::NoCartridge));
+        assert_eq!(nes.insert_cartridge(rom(1)), Err(NesError::UnsupportedMapper(1)));
+        nes.insert_cartridge(rom(0)).unwrap();
+        assert_eq!(nes.cpu().pc, 0x8000);
+        // 16KB PRG ROM is mirrored at 0xC000
+        assert_eq!(nes.peek(0xC000), 0x4C);
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0x02, 0)).unwrap()).unwrap();
+        nes.poke(0x6001, 0x11);
+        assert!(nes.load_battery_ram(&[0x42]));
+        // short files are padded out
+        assert_eq!((nes.peek(0x6000), nes.peek(0x6001)), (0x42, 0));
+        assert_eq!(nes.battery_ram().unwrap().len(), PRG_RAM_SIZE);
+
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        assert!(nes.battery_ram().is_none());
+        assert!(!nes.load_battery_ram(&[0x42]));
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        let mut image = image(0);
+        // battery backed PRG RAM
+        image[6] |= 0x02;
+        let mut nes = Nes::new();
+        nes.set_ram_pattern(RamPattern::Alternating);
+        nes.insert_cartridge(Rom::new(&image).unwrap()).unwrap();
+        assert_eq!((nes.peek(0x0003), nes.peek(0x0004)), (0x00, 0xFF));
+
+        nes.poke(0x0014, 0x42);
+        nes.poke(0x6000, 0x99);
+        nes.poke(0x2000, 0x80);
+        nes.cpu_mut().bus.ppu.vram[0] = 0x24;
+        nes.step_frame();
+        nes.reset().unwrap();
+        assert_eq!((nes.peek(0x0014), nes.peek(0x6000)), (0x42, 0x99));
+        assert_eq!(nes.cpu().bus.ppu.vram[0], 0x24);
+        assert!(nes.cpu().bus.ppu.ctrl.is_empty());
+        assert_eq!((nes.cpu().pc, nes.frame_count()), (0x8000, 0));
+
+        nes.power_cycle().unwrap();
+        assert_eq!((nes.peek(0x0014), nes.peek(0x6000)), (0xFF, 0x99));
+        assert_eq!((nes.cpu().bus.ppu.vram[0], nes.cpu().bus.ppu.frame), (0, 0));
+        assert_eq!(nes.cpu().pc, 0x8000);
+
+        assert!(nes.eject_cartridge().is_some());
+        assert_eq!(nes.power_cycle(), Err(NesError::NoCartridge));
+    }
+
+    #[test]
+    fn test_trainer_in_prg_ram() {
+        let mut data = ines(1, 1, 0x04, 0);
+        data[16..16 + TRAINER_SIZE].fill(0x5A);
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&data).unwrap()).unwrap();
+        assert_eq!((nes.peek(0x6FFF), nes.peek(0x7000), nes.peek(0x71FF), nes.peek(0x7200)), (0, 0x5A, 0x5A, 0));
+    }
+
+    #[test]
+    fn test_insert_disk() {
+        // JMP $E000 with the reset vector pointing at it
+        let mut bios = vec![0; fds::BIOS_SIZE];
+        bios[..3].copy_from_slice(&[0x4C, 0x00, 0xE0]);
+        bios[0x1FFC..0x1FFE].copy_from_slice(&[0x00, 0xE0]);
+        let disk = FdsImage { sides: vec![crate::fds::tests::side(b"TST"); 2] };
+        let mut nes = Nes::new();
+        assert_eq!(nes.switch_disk_side(), None);
+        nes.insert_disk(&disk, bios).unwrap();
+        assert_eq!(nes.cpu().pc, 0xE000);
+        assert!(nes.cartridge().is_none());
+        nes.poke(0x6000, 0x42);
+        assert_eq!(nes.peek(0x6000), 0x42);
+        assert_eq!(nes.switch_disk_side(), Some(1));
+        nes.step_frame();
+    }
+
+    #[test]
+    fn test_load_rom_file() {
+        let dir = TestDir::new("nes");
+        std::fs::write(dir.join("game.nes"), image(0)).unwrap();
+        std::fs::write(dir.join("mmc1.nes"), image(1)).unwrap();
+
+        let mut nes = Nes::new();
+        nes.load_rom_file(dir.join("game.nes")).unwrap();
+        nes.poke(0x0000, 0x42);
+        assert!(matches!(nes.load_rom_file(dir.join("mmc1.nes")), Err(RomError::UnsupportedMapper { mapper: 1, .. })));
+        assert!(matches!(nes.load_rom_file(dir.join("missing.nes")), Err(RomError::Io(_))));
+        // a failed swap leaves the running game alone
+        assert_eq!(nes.peek(0x0000), 0x42);
+
+        // an IPS patch turning the mapper 1 header byte into mapper 0
+        std::fs::write(dir.join("mmc1.ips"), b"PATCH\x00\x00\x06\x00\x01\x00EOF").unwrap();
+        nes.load_patched_rom_file(dir.join("mmc1.nes"), &[dir.join("mmc1.ips")]).unwrap();
+        assert!(matches!(
+            nes.load_patched_rom_file(dir.join("game.nes"), &[dir.join("missing.ips")]),
+            Err(RomError::Patch(_, PatchError::Io(_)))
+        ));
+
+        // swapping in a cartridge starts from a clean machine
+        nes.load_rom_file(dir.join("game.nes")).unwrap();
+        assert_eq!(nes.peek(0x0000), 0);
+        assert!(nes.eject_cartridge().is_some());
+        assert_eq!(nes.reset(), Err(NesError::NoCartridge));
+    }
+
+    #[test]
+    fn test_step_frame() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(rom(0)).unwrap();
+        assert_eq!(nes.step_instruction(), 3);
+        nes.step_frame();
+        let output = nes.step_frame();
+        // 44100 / 60.1 samples, silent with nothing playing once the filters settle
+        assert!(output.complete && (730..740).contains(&output.audio.len()));
+        assert!(output.audio.iter().all(|sample| sample.abs() < 0.001));
+        assert_eq!((output.image.width, output.image.height), (256, 240));
+        assert_eq!(nes.frame_count(), 2);
+        // a frame is 29780.67 CPU cycles
+        assert!(nes.cpu().cycles * 3 >= 2 * DOTS_PER_FRAME);
+        assert!(nes.cpu().cycles * 3 < 2 * DOTS_PER_FRAME + 9);
+        assert_eq!((nes.frame().width, nes.frame().height), (256, 240));
+        assert!(!nes.audio().is_empty());
+    }
+
+    #[test]
+    fn test_apu() {
+        let mut data = image(0);
+        let program = [
+            // enable pulse 1: 50% duty at full volume, period $0FD
+            0xA9, 0x01, 0x8D, 0x15, 0x40,
+            0xA9, 0xBF, 0x8D, 0x00, 0x40,
+            0xA9, 0xFD, 0x8D, 0x02, 0x40,
+            0xA9, 0x08, 0x8D, 0x03, 0x40,
+            // CLI; JMP $8015
+            0x58, 0x4C, 0x15, 0x80,
+            // IRQ: INC $10; LDA $4015; RTI
+            0xE6, 0x10, 0xAD, 0x15, 0x40, 0x40,
+        ];
+        data[16..16 + program.len()].copy_from_slice(&program);
+        data[16 + 0x3FFE..16 + 0x4000].copy_from_slice(&[0x18, 0x80]);
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&data).unwrap()).unwrap();
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        // the frame counter interrupts once a frame, acknowledged by reading $4015
+        assert!((2..=3).contains(&nes.peek(0x0010)));
+        let audio = nes.audio();
+        let (low, high) = audio.iter().fold((0.0f32, 0.0f32), |(low, high), &sample| (low.min(sample), high.max(sample)));
+        assert!(low < -0.05 && high > 0.05);
+
+        // muting the channel silences it
+        nes.mixer_mut().muted[0] = true;
+        nes.step_frame();
+        nes.step_frame();
+        assert!(nes.audio().iter().all(|sample| sample.abs() < 0.001));
+    }
+
+    // Pulse 1 at full volume with a 50% duty and the triangle, for three frames
+    // through `mixer`. Returns the last frame's sound.
+    fn play_tones(mixer: Mixer, scope: usize) -> (Vec<f32>, Nes) {
+        let mut data = image(0);
+        let mut program = Vec::new();
+        for (address, value) in [(0x4015u16, 0x05u8), (0x4000, 0xBF), (0x4002, 0xFD), (0x4003, 0x08), (0x4008, 0xFF), (0x400A, 0x40), (0x400B, 0x08)] {
+            program.extend_from_slice(&[0xA9, value, 0x8D, address as u8, (address >> 8) as u8]);
+        }
+        program.extend_from_slice(&[0x4C, 0x23, 0x80]);
+        data[16..16 + program.len()].copy_from_slice(&program);
+        let mut nes = Nes::new();
+        *nes.mixer_mut() = mixer;
+        nes.set_channel_scope(scope);
+        nes.insert_cartridge(Rom::new(&data).unwrap()).unwrap();
+        for _ in 0..3 {
+            nes.step_frame();
+        }
+        (nes.audio().to_vec(), nes)
+    }
+
+    fn mean(samples: &[f32]) -> f32 {
+        samples.iter().sum::<f32>() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_mixer() {
+        use crate::mixer::{Channel, Mixing};
+        let linear = Mixer { mixing: Mixing::Linear, ..Mixer::new() };
+
+        // a solo leaves only its channel, as muting the others does
+        let (triangle, _) = play_tones(Mixer { solo: Some(Channel::Triangle), ..linear }, 0);
+        let mut muted = linear;
+        muted.muted[Channel::Pulse1 as usize] = true;
+        assert_eq!(play_tones(muted, 0).0, triangle);
+        assert!(mean(&triangle) > 0.01);
+
+        // the square wave averages half its level, and its band-limited edges settle
+        // on the levels with only a little ringing
+        let pulse = Mixer { solo: Some(Channel::Pulse1), ..linear };
+        let high = pulse.mix([15, 0, 0, 0, 0]);
+        let (samples, _) = play_tones(pulse, 0);
+        assert!((mean(&samples) / (high / 2.0) - 1.0).abs() < 0.05);
+        assert!(samples.iter().all(|&sample| (-0.15 * high..1.15 * high).contains(&sample)));
+        assert!(samples.iter().filter(|&&sample| (sample - high).abs() < 0.001).count() > samples.len() / 4);
+
+        // gains scale it, the master volume silences everything
+        let mut louder = pulse;
+        louder.gains[Channel::Pulse1 as usize] = 2.0;
+        assert!((mean(&play_tones(louder, 0).0) / mean(&samples) - 2.0).abs() < 0.01);
+        assert!(play_tones(Mixer { master: 0.0, ..linear }, 0).0.iter().all(|&sample| sample == 0.0));
+
+        // accurate mixing's high-passes take the DC offset out
+        let (accurate, _) = play_tones(Mixer::new(), 0);
+        assert!(mean(&accurate).abs() < 0.01 && accurate.iter().any(|&sample| sample > 0.05));
+
+        // the scope sees what the channels put out
+        let (_, nes) = play_tones(Mixer::new(), 256);
+        let scope = nes.channel_scope().unwrap();
+        assert_eq!(scope.history(Channel::Pulse1).len(), 256);
+        assert!(scope.history(Channel::Pulse1).iter().all(|&output| output == 0 || output == 15));
+        assert!(scope.history(Channel::Pulse1).contains(&0) && scope.history(Channel::Pulse1).contains(&15));
+        assert!(scope.history(Channel::Triangle).contains(&0) && scope.history(Channel::Triangle).contains(&15));
+        assert!(scope.history(Channel::Noise).iter().all(|&output| output == 0));
+    }
+
+    #[test]
+    fn test_run_ahead() {
+        let (mut ahead, mut plain) = (Nes::new(), Nes::new());
+        ahead.insert_cartridge(rom(0)).unwrap();
+        plain.insert_cartridge(rom(0)).unwrap();
+        for _ in 0..2 {
+            assert!(ahead.step_frame_ahead(2).complete);
+            plain.step_frame();
+        }
+        // only the real frames count
+        assert_eq!(ahead.frame_count(), 2);
+        assert_eq!(ahead.cpu().cycles, plain.cpu().cycles);
+        assert_eq!(ahead.position(), plain.position());
+    }
+
+    #[test]
+    fn test_frame_buffer_swap() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(rom(0)).unwrap();
+        nes.set_palette(Palette::classic());
+        nes.step_frame();
+        let backdrop = nes.frame().get_pixel(0, 100);
+        assert_eq!(backdrop, Palette::classic().color(0x00));
+        nes.cpu_mut().bus.ppu.palette[0] = 0x30;
+        // mid frame the drawing stays in the back buffer
+        for _ in 0..120 {
+            nes.step_scanline();
+        }
+        assert_eq!(nes.frame().get_pixel(0, 100), backdrop);
+        assert_eq!(nes.cpu().bus.ppu.back_buffer.get_pixel(0, 100), Palette::classic().color(0x30));
+        nes.cpu_mut().bus.ppu.palette[0] = 0x16;
+        nes.step_frame();
+        assert_eq!(nes.frame().get_pixel(0, 100), Palette::classic().color(0x30));
+        assert_eq!(nes.frame().get_pixel(0, 200), Palette::classic().color(0x16));
+        assert!(!nes.cpu().bus.ppu.frame_complete);
+        // the last instruction ran into the next frame, whose first dots belong to it
+        assert!((0..256).all(|x| nes.frame().get_pixel(x, 0) != Palette::classic().color(0x16)));
+        assert_eq!(nes.cpu().bus.ppu.back_buffer.get_pixel(0, 0), Palette::classic().color(0x16));
+
+        // the palette is a setting, it outlasts the console being switched off
+        nes.power_cycle().unwrap();
+        assert_eq!(nes.cpu().bus.ppu.colors, Palette::classic());
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        // LDA #$80; STA $2000; JMP $8005, and an NMI handler doing INC $10; RTI
+        let mut image = image(0);
+        image[16..24].copy_from_slice(&[0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x05, 0x80]);
+        image[16 + 0x100..16 + 0x103].copy_from_slice(&[0xE6, 0x10, 0x40]);
+        image[16 + 0x3FFA..16 + 0x3FFC].copy_from_slice(&[0x00, 0x81]);
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&image).unwrap()).unwrap();
+
+        while nes.position() < (241, 0) {
+            nes.step_instruction();
+        }
+        assert_eq!(nes.peek(0x10), 0);
+        // the flag goes up on dot 1, the CPU takes the NMI a little after
+        nes.step_scanline();
+        assert_eq!(nes.peek(0x10), 1);
+        nes.step_frame();
+        nes.step_frame();
+        assert_eq!(nes.peek(0x10), 2);
+    }
+
+    #[test]
+    fn test_fault_pauses() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(rom(0)).unwrap();
+        // $02; JMP $8000
+        for (address, value) in [0x02, 0x4C, 0x00, 0x80].into_iter().enumerate() {
+            nes.cpu_mut().write_byte(address as u16, value);
+        }
+        nes.cpu_mut().pc = 0x0000;
+        nes.step_frame();
+        assert!(!nes.step_frame().complete);
+        assert_eq!(nes.fault(), Some(CpuFault::UnknownOpcode { pc: 0x0000, opcode: 0x02 }));
+        assert_eq!(nes.frame_count(), 0);
+
+        nes.cpu_mut().skip_faulted_instruction();
+        nes.step_frame();
+        assert_eq!(nes.fault(), None);
+        assert_eq!(nes.frame_count(), 1);
+    }
+
+    #[test]
+    fn test_step_scanline() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(rom(0)).unwrap();
+        nes.step_scanline();
+        let (scanline, dot) = nes.position();
+        assert_eq!(scanline, 1);
+        assert!(dot < 9);
+        for _ in 0..261 {
+            nes.step_scanline();
+        }
+        assert_eq!(nes.frame_count(), 1);
+        assert_eq!(nes.position().0, 0);
+        nes.step_frame();
+        assert_eq!(nes.frame_count(), 2);
+
+        let state = nes.snapshot();
+        assert_eq!((state.frame, state.scanline), (2, 0));
+        assert_eq!(state.cpu.pc, nes.cpu().pc);
+        assert_eq!((state.ppu.scanline, state.ppu.dot), (0, state.dot as u16));
+        assert_eq!(state.ppu.frame, nes.cpu().bus.ppu.frame);
+        assert_eq!(state.apu.frame_cycle, nes.cpu().bus.apu.frame_cycle);
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut nes = Nes::new();
+        nes.poke(0x0010, 0x42);
+        assert_eq!(nes.peek(0x0010), 0x42);
+    }
+}
@@ -0,0 +1,247 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::bus::NesBus;
+use crate::cartridge::Cartridge;
+use crate::cpu::{Cpu, StatusFlag};
+use crate::rom::{Rom, RomError};
+use crate::trace::{trace, Tracer};
+
+// nestest's automated mode starts at 0xC000 with this state
+const START_ADDRESS: u16 = 0xC000;
+const START_STATUS: u8 = 0x24;
+const START_CYCLES: u64 = 7;
+
+// The fields compared between the golden log and our own trace.
+// The disassembly and PPU columns are informational only.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceState {
+    pub pc: u16,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: u8,
+    pub sp: u8,
+    // older nestest.log revisions don't have a CYC column
+    pub cycles: Option<u64>,
+}
+
+impl TraceState {
+    pub fn parse(line: &str) -> Option<TraceState> {
+        let hex_field = |name: &str| {
+            let start = line.find(name)? + name.len();
+            u8::from_str_radix(line.get(start..start + 2)?, 16).ok()
+        };
+        let cycles = match line.find("CYC:") {
+            Some(start) => Some(line[start + 4..].trim().parse().ok()?),
+            None => None,
+        };
+
+        Some(TraceState {
+            pc: u16::from_str_radix(line.get(0..4)?, 16).ok()?,
+            a: hex_field(" A:")?,
+            x: hex_field(" X:")?,
+            y: hex_field(" Y:")?,
+            p: hex_field(" P:")?,
+            sp: hex_field(" SP:")?,
+            cycles,
+        })
+    }
+
+    // Names the fields that differ, e.g. "A, CYC"
+    fn diff(&self, other: &TraceState) -> String {
+        let mut fields = Vec::new();
+        if self.pc != other.pc {
+            fields.push("PC");
+        }
+        if self.a != other.a {
+            fields.push("A");
+        }
+        if self.x != other.x {
+            fields.push("X");
+        }
+        if self.y != other.y {
+            fields.push("Y");
+        }
+        if self.p != other.p {
+            fields.push("P");
+        }
+        if self.sp != other.sp {
+            fields.push("SP");
+        }
+        if self.cycles.is_some() && self.cycles != other.cycles {
+            fields.push("CYC");
+        }
+        fields.join(", ")
+    }
+
+    fn matches(&self, other: &TraceState) -> bool {
+        self.diff(other).is_empty()
+    }
+}
+
+// The first line where our trace disagrees with the golden log
+#[derive(Debug)]
+pub struct Divergence {
+    pub line: usize,
+    pub fields: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "nestest diverged at line {} ({})", self.line, self.fields)?;
+        writeln!(f, "expected: {}", self.expected)?;
+        write!(f, "actual:   {}", self.actual)
+    }
+}
+
+#[derive(Debug)]
+pub enum NestestError {
+    Rom(RomError),
+    Log(std::io::Error),
+    InvalidLogLine(usize),
+    Divergence(Divergence),
+}
+
+impl fmt::Display for NestestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NestestError::Rom(error) => write!(f, "{}", error),
+            NestestError::Log(error) => write!(f, "Could not read golden log: {}", error),
+            NestestError::InvalidLogLine(line) => write!(f, "Golden log line {} is not in nestest format", line),
+            NestestError::Divergence(divergence) => write!(f, "{}", divergence),
+        }
+    }
+}
+
+impl From<RomError> for NestestError {
+    fn from(error: RomError) -> Self {
+        NestestError::Rom(error)
+    }
+}
+
+// Runs nestest from 0xC000 and compares every traced instruction with the golden log.
+// Returns the number of matching lines. Executed instructions are also sent to the tracer.
+// The ROM goes in a cartridge on the console's bus, so its 16KB is mirrored by the
+// NROM board like it is for games.
+pub fn verify(rom: Rom, golden_log: &str, tracer: &mut Tracer) -> Result<usize, NestestError> {
+    let mut bus = NesBus::new();
+    bus.insert_cartridge(Cartridge::new(rom)?);
+    let mut cpu = Cpu::with_bus(bus);
+    cpu.reset();
+    cpu.pc = START_ADDRESS;
+    cpu.p = StatusFlag::from_bits_truncate(START_STATUS);
+    cpu.cycles = START_CYCLES;
+
+    let mut count = 0;
+    for (index, expected_line) in golden_log.lines().enumerate() {
+        if expected_line.trim().is_empty() {
+            continue;
+        }
+        let line = index + 1;
+        let expected = TraceState::parse(expected_line).ok_or(NestestError::InvalidLogLine(line))?;
+        let actual_line = trace(&cpu);
+        tracer.log_cpu(&cpu);
+        let actual = TraceState::parse(&actual_line).ok_or(NestestError::InvalidLogLine(line))?;
+
+        if !expected.matches(&actual) {
+            return Err(NestestError::Divergence(Divergence {
+                line,
+                fields: expected.diff(&actual),
+                expected: expected_line.to_string(),
+                actual: actual_line,
+            }));
+        }
+
+        cpu.step();
+        count += 1;
+    }
+    Ok(count)
+}
+
+pub fn verify_files(
+    rom_path: impl AsRef<Path>,
+    log_path: impl AsRef<Path>,
+    tracer: &mut Tracer,
+) -> Result<usize, NestestError> {
+    let rom = Rom::load(rom_path)?;
+    let golden_log = fs::read_to_string(log_path).map_err(NestestError::Log)?;
+    verify(rom, &golden_log, tracer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines;
+    use crate::trace::{TraceChannel, TraceSink};
+
+    // LDA #$01, LDX #$02, NOP at 0xC000
+    fn rom() -> Rom {
+        let mut data = ines(1, 0, 0, 0);
+        data[16..21].copy_from_slice(&[0xA9, 0x01, 0xA2, 0x02, 0xEA]);
+        Rom::new(&data).unwrap()
+    }
+
+    const GOLDEN_LOG: &str = "\
+C000  A9 01     LDA #$01                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+C002  A2 02     LDX #$02                        A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 27 CYC:9
+C004  EA        NOP                             A:01 X:02 Y:00 P:24 SP:FD PPU:  0, 33 CYC:11
+";
+
+    #[test]
+    fn test_parse_trace_state() {
+        let state = TraceState::parse(GOLDEN_LOG.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(
+            state,
+            TraceState { pc: 0xC002, a: 0x01, x: 0, y: 0, p: 0x24, sp: 0xFD, cycles: Some(9) }
+        );
+    }
+
+    #[test]
+    fn test_parse_trace_state_without_cycles() {
+        let state = TraceState::parse("C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD").unwrap();
+        assert_eq!(state.cycles, None);
+    }
+
+    #[test]
+    fn test_verify_matching_log() {
+        assert_eq!(verify(rom(), GOLDEN_LOG, &mut Tracer::default()).unwrap(), 3);
+    }
+
+    #[test]
+    fn test_verify_traces_executed_lines() {
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(8));
+        verify(rom(), GOLDEN_LOG, &mut tracer).unwrap();
+        assert_eq!(tracer.history().collect::<Vec<_>>(), GOLDEN_LOG.lines().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_verify_reports_first_divergence() {
+        let golden_log = GOLDEN_LOG.replace("A:01 X:00", "A:02 X:00");
+        match verify(rom(), &golden_log, &mut Tracer::default()) {
+            Err(NestestError::Divergence(divergence)) => {
+                assert_eq!(divergence.line, 2);
+                assert_eq!(divergence.fields, "A");
+            }
+            _ => panic!("expected a divergence"),
+        }
+    }
+
+    // Runs the real test ROM when it has been placed in roms/
+    #[test]
+    fn test_nestest() {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms");
+        let (rom_path, log_path) = (dir.join("nestest.nes"), dir.join("nestest.log"));
+        if !rom_path.exists() || !log_path.exists() {
+            eprintln!("skipping nestest: roms/nestest.nes or roms/nestest.log not found");
+            return;
+        }
+        if let Err(error) = verify_files(rom_path, log_path, &mut Tracer::default()) {
+            panic!("{}", error);
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::time::{Duration, Instant};
+
+use crate::hash;
+use crate::joypad::JoypadButton;
+use crate::nes::Nes;
+use crate::savestate::{self, StateError};
+
+// Two player netplay over UDP. Both peers run the whole game and only trade
+// controller input, in lockstep: a frame runs once both players' input for it is
+// in. Input is sent `input_delay` frames ahead to hide the round trip. The host
+// sends a checksum of its state every SYNC_INTERVAL frames, and a guest that
+// disagrees asks for the host's savestate and replays the frames since.
+pub const PROTOCOL_VERSION: u8 = 1;
+pub const DEFAULT_PORT: u16 = 7654;
+const SYNC_INTERVAL: u64 = 60;
+// Every input packet repeats this many of the latest inputs, so a lost packet
+// is covered by the next one
+const REDUNDANT_INPUTS: usize = 16;
+const RESEND_INTERVAL: Duration = Duration::from_millis(10);
+const STATE_CHUNK_SIZE: usize = 1024;
+// Sync points the host keeps states for
+const SYNC_STATES: usize = 2;
+const MAX_PACKET_SIZE: usize = 2048;
+
+const HELLO: u8 = 1;
+const WELCOME: u8 = 2;
+const REJECT: u8 = 3;
+const INPUT: u8 = 4;
+const CHECKSUM: u8 = 5;
+const RESYNC: u8 = 6;
+const STATE: u8 = 7;
+const QUIT: u8 = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RejectReason {
+    Version,
+    // the players have different games or dumps
+    Rom,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Hello { version: u8, rom_crc: u32 },
+    Welcome { input_delay: u8 },
+    Reject(RejectReason),
+    // input for `first_frame` and the frames after it
+    Input { first_frame: u64, buttons: Vec<JoypadButton> },
+    Checksum { frame: u64, crc: u32 },
+    // asks the host for its state at a sync point
+    Resync { frame: u64 },
+    State { frame: u64, index: u16, count: u16, data: Vec<u8> },
+    Quit,
+}
+
+impl Message {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match self {
+            Message::Hello { version, rom_crc } => {
+                out.extend_from_slice(&[HELLO, *version]);
+                out.extend_from_slice(&rom_crc.to_le_bytes());
+            }
+            Message::Welcome { input_delay } => out.extend_from_slice(&[WELCOME, *input_delay]),
+            Message::Reject(reason) => out.extend_from_slice(&[REJECT, *reason as u8]),
+            Message::Input { first_frame, buttons } => {
+                out.push(INPUT);
+                out.extend_from_slice(&first_frame.to_le_bytes());
+                out.extend(buttons.iter().map(|buttons| buttons.bits()));
+            }
+            Message::Checksum { frame, crc } => {
+                out.push(CHECKSUM);
+                out.extend_from_slice(&frame.to_le_bytes());
+                out.extend_from_slice(&crc.to_le_bytes());
+            }
+            Message::Resync { frame } => {
+                out.push(RESYNC);
+                out.extend_from_slice(&frame.to_le_bytes());
+            }
+            Message::State { frame, index, count, data } => {
+                out.push(STATE);
+                out.extend_from_slice(&frame.to_le_bytes());
+                out.extend_from_slice(&index.to_le_bytes());
+                out.extend_from_slice(&count.to_le_bytes());
+                out.extend_from_slice(data);
+            }
+            Message::Quit => out.push(QUIT),
+        }
+        out
+    }
+
+    pub fn decode(packet: &[u8]) -> Option<Message> {
+        let (&tag, body) = packet.split_first()?;
+        let u64_at = |offset: usize| Some(u64::from_le_bytes(body.get(offset..offset + 8)?.try_into().ok()?));
+        let u32_at = |offset: usize| Some(u32::from_le_bytes(body.get(offset..offset + 4)?.try_into().ok()?));
+        let u16_at = |offset: usize| Some(u16::from_le_bytes(body.get(offset..offset + 2)?.try_into().ok()?));
+        Some(match tag {
+            HELLO => Message::Hello { version: *body.first()?, rom_crc: u32_at(1)? },
+            WELCOME => Message::Welcome { input_delay: *body.first()? },
+            REJECT => Message::Reject(match body.first()? {
+                0 => RejectReason::Version,
+                1 => RejectReason::Rom,
+                _ => return None,
+            }),
+            INPUT => Message::Input {
+                first_frame: u64_at(0)?,
+                buttons: body[8..].iter().map(|&bits| JoypadButton::from_bits_truncate(bits)).collect(),
+            },
+            CHECKSUM => Message::Checksum { frame: u64_at(0)?, crc: u32_at(8)? },
+            RESYNC => Message::Resync { frame: u64_at(0)? },
+            STATE => Message::State { frame: u64_at(0)?, index: u16_at(8)?, count: u16_at(10)?, data: body[12..].to_vec() },
+            QUIT => Message::Quit,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum NetplayError {
+    Io(io::Error),
+    // nothing from the other player for the configured timeout
+    Timeout,
+    Rejected(RejectReason),
+    Disconnected,
+    State(StateError),
+}
+
+impl fmt::Display for NetplayError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetplayError::Io(error) => write!(f, "Netplay connection failed: {}", error),
+            NetplayError::Timeout => write!(f, "The other player stopped responding"),
+            NetplayError::Rejected(RejectReason::Version) => write!(f, "The other player runs a different madNES version"),
+            NetplayError::Rejected(RejectReason::Rom) => write!(f, "The other player has a different ROM loaded"),
+            NetplayError::Disconnected => write!(f, "The other player left"),
+            NetplayError::State(error) => write!(f, "Could not resync: {}", error),
+        }
+    }
+}
+
+impl From<io::Error> for NetplayError {
+    fn from(error: io::Error) -> Self {
+        NetplayError::Io(error)
+    }
+}
+
+impl From<StateError> for NetplayError {
+    fn from(error: StateError) -> Self {
+        NetplayError::State(error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetplayConfig {
+    // frames between pressing a button and the game seeing it, on both sides
+    pub input_delay: u8,
+    pub timeout: Duration,
+}
+
+impl Default for NetplayConfig {
+    fn default() -> Self {
+        NetplayConfig { input_delay: 2, timeout: Duration::from_secs(5) }
+    }
+}
+
+pub struct NetplaySession {
+    socket: UdpSocket,
+    pub remote: SocketAddr,
+    // 0 for the host, which is player 1
+    pub player: usize,
+    pub input_delay: u8,
+    timeout: Duration,
+    // the next frame to run
+    pub frame: u64,
+    local_inputs: BTreeMap<u64, JoypadButton>,
+    remote_inputs: BTreeMap<u64, JoypadButton>,
+    // both ports as each frame was run, for replaying after a resync
+    played: BTreeMap<u64, [JoypadButton; 2]>,
+    // guest: its own and the host's checksums by frame, until they're compared
+    checksums: BTreeMap<u64, u32>,
+    remote_checksums: BTreeMap<u64, u32>,
+    // host: states at the last sync points
+    sync_states: BTreeMap<u64, Vec<u8>>,
+    // guest: the sync point asked for and the chunks of it received so far
+    resync: Option<(u64, Vec<Option<Vec<u8>>>)>,
+    pub desyncs: u64,
+}
+
+impl NetplaySession {
+    // Waits on `bind` for a player to join with the same ROM
+    pub fn host(bind: impl ToSocketAddrs, rom_crc: u32, config: NetplayConfig) -> Result<NetplaySession, NetplayError> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+        let deadline = Instant::now() + config.timeout;
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while Instant::now() < deadline {
+            let (size, from) = match socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(error) if is_timeout(&error) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            let reply = match Message::decode(&buffer[..size]) {
+                Some(Message::Hello { version, .. }) if version != PROTOCOL_VERSION => Message::Reject(RejectReason::Version),
+                Some(Message::Hello { rom_crc: crc, .. }) if crc != rom_crc => Message::Reject(RejectReason::Rom),
+                Some(Message::Hello { .. }) => {
+                    socket.send_to(&Message::Welcome { input_delay: config.input_delay }.encode(), from)?;
+                    return Ok(NetplaySession::new(socket, from, 0, config.input_delay, config.timeout));
+                }
+                _ => continue,
+            };
+            socket.send_to(&reply.encode(), from)?;
+        }
+        Err(NetplayError::Timeout)
+    }
+
+    // Joins the host at `address`. The input delay is the host's.
+    pub fn join(
+        bind: impl ToSocketAddrs,
+        address: impl ToSocketAddrs,
+        rom_crc: u32,
+        config: NetplayConfig,
+    ) -> Result<NetplaySession, NetplayError> {
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+        let remote = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to connect to"))?;
+        let hello = Message::Hello { version: PROTOCOL_VERSION, rom_crc }.encode();
+        let deadline = Instant::now() + config.timeout;
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        while Instant::now() < deadline {
+            socket.send_to(&hello, remote)?;
+            let size = match socket.recv_from(&mut buffer) {
+                Ok((size, from)) if from == remote => size,
+                Ok(_) => continue,
+                Err(error) if is_timeout(&error) => continue,
+                Err(error) => return Err(error.into()),
+            };
+            match Message::decode(&buffer[..size]) {
+                Some(Message::Welcome { input_delay }) => {
+                    return Ok(NetplaySession::new(socket, remote, 1, input_delay, config.timeout));
+                }
+                Some(Message::Reject(reason)) => return Err(NetplayError::Rejected(reason)),
+                _ => {}
+            }
+        }
+        Err(NetplayError::Timeout)
+    }
+
+    fn new(socket: UdpSocket, remote: SocketAddr, player: usize, input_delay: u8, timeout: Duration) -> Self {
+        // nobody has pressed anything during the delay at the start
+        let empty = || (0..input_delay as u64).map(|frame| (frame, JoypadButton::empty())).collect();
+        NetplaySession {
+            socket,
+            remote,
+            player,
+            input_delay,
+            timeout,
+            frame: 0,
+            local_inputs: empty(),
+            remote_inputs: empty(),
+            played: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            remote_checksums: BTreeMap::new(),
+            sync_states: BTreeMap::new(),
+            resync: None,
+            desyncs: 0,
+        }
+    }
+
+    pub fn is_host(&self) -> bool {
+        self.player == 0
+    }
+
+    // Trades this frame's input and waits for the other player's. Returns the
+    // buttons for both controller ports.
+    pub fn exchange(&mut self, buttons: JoypadButton) -> Result<[JoypadButton; 2], NetplayError> {
+        self.local_inputs.insert(self.frame + self.input_delay as u64, buttons);
+        self.send_inputs()?;
+        let deadline = Instant::now() + self.timeout;
+        while !self.remote_inputs.contains_key(&self.frame) {
+            if Instant::now() >= deadline {
+                return Err(NetplayError::Timeout);
+            }
+            if !self.receive()? {
+                self.send_inputs()?;
+            }
+        }
+        let local = self.local_inputs[&self.frame];
+        let remote = self.remote_inputs[&self.frame];
+        let oldest = self.frame.saturating_sub(REDUNDANT_INPUTS as u64);
+        self.local_inputs = self.local_inputs.split_off(&oldest);
+        self.remote_inputs = self.remote_inputs.split_off(&self.frame);
+        self.frame += 1;
+        Ok(if self.is_host() { [local, remote] } else { [remote, local] })
+    }
+
+    // Runs one frame of `nes` with both players' input, checking for desyncs
+    pub fn run_frame(&mut self, nes: &mut Nes, buttons: JoypadButton) -> Result<(), NetplayError> {
+        let frame = self.frame;
+        let ports = self.exchange(buttons)?;
+        self.played.insert(frame, ports);
+        self.played = self.played.split_off(&self.frame.saturating_sub(SYNC_INTERVAL * SYNC_STATES as u64));
+        for (player, buttons) in ports.into_iter().enumerate() {
+            nes.set_buttons(player, buttons);
+        }
+        nes.step_frame();
+
+        if self.frame.is_multiple_of(SYNC_INTERVAL) {
+            let state = savestate::save(nes.cpu());
+            let crc = hash::crc32(&state);
+            // only the guest compares, the host sends its checksums over
+            if self.is_host() {
+                self.send(&Message::Checksum { frame: self.frame, crc })?;
+                self.sync_states.insert(self.frame, state);
+                while self.sync_states.len() > SYNC_STATES {
+                    self.sync_states.pop_first();
+                }
+            } else {
+                self.checksums.insert(self.frame, crc);
+            }
+        }
+        if !self.is_host() {
+            self.check_sync(nes)?;
+        }
+        Ok(())
+    }
+
+    // Guest side: asks for the host's state on a checksum mismatch, and loads it
+    // once all of it is in
+    fn check_sync(&mut self, nes: &mut Nes) -> Result<(), NetplayError> {
+        let mismatch = self
+            .remote_checksums
+            .iter()
+            .find(|(frame, crc)| self.checksums.get(frame).is_some_and(|local| local != *crc))
+            .map(|(&frame, _)| frame);
+        // a newer mismatch replaces a resync that never finished, in case its state was lost
+        if let Some(frame) = mismatch.filter(|&frame| self.resync.as_ref().is_none_or(|(pending, _)| *pending < frame)) {
+            self.desyncs += 1;
+            self.resync = Some((frame, Vec::new()));
+            self.send(&Message::Resync { frame })?;
+        }
+        let compared: Vec<u64> = self.remote_checksums.keys().copied().filter(|frame| self.checksums.contains_key(frame)).collect();
+        for frame in compared {
+            self.remote_checksums.remove(&frame);
+            self.checksums.remove(&frame);
+        }
+        // a checksum whose counterpart was lost would never be compared, and past
+        // the states the host keeps it couldn't be resynced to anyway
+        let oldest = self.frame.saturating_sub(SYNC_INTERVAL * SYNC_STATES as u64);
+        self.checksums = self.checksums.split_off(&oldest);
+        self.remote_checksums = self.remote_checksums.split_off(&oldest);
+
+        let Some((frame, chunks)) = &self.resync else {
+            return Ok(());
+        };
+        if chunks.is_empty() || chunks.iter().any(Option::is_none) {
+            return Ok(());
+        }
+        let frame = *frame;
+        let state: Vec<u8> = chunks.iter().flatten().flatten().copied().collect();
+        self.resync = None;
+        savestate::load(nes.cpu_mut(), &state)?;
+        // back to where we were, with the input already played
+        for replayed in frame..self.frame {
+            for (player, buttons) in self.played.get(&replayed).copied().unwrap_or_default().into_iter().enumerate() {
+                nes.set_buttons(player, buttons);
+            }
+            nes.step_frame();
+        }
+        Ok(())
+    }
+
+    fn send(&self, message: &Message) -> Result<(), NetplayError> {
+        self.socket.send_to(&message.encode(), self.remote)?;
+        Ok(())
+    }
+
+    fn send_inputs(&self) -> Result<(), NetplayError> {
+        let inputs: Vec<(&u64, &JoypadButton)> = self.local_inputs.iter().rev().take(REDUNDANT_INPUTS).collect();
+        let Some((&first_frame, _)) = inputs.last() else {
+            return Ok(());
+        };
+        let buttons = inputs.iter().rev().map(|(_, &buttons)| buttons).collect();
+        self.send(&Message::Input { first_frame, buttons })
+    }
+
+    // Handles one packet from the other player. False when none came in time.
+    fn receive(&mut self) -> Result<bool, NetplayError> {
+        let mut buffer = [0; MAX_PACKET_SIZE];
+        let size = match self.socket.recv_from(&mut buffer) {
+            Ok((size, from)) if from == self.remote => size,
+            Ok(_) => return Ok(true),
+            Err(error) if is_timeout(&error) => return Ok(false),
+            Err(error) => return Err(error.into()),
+        };
+        match Message::decode(&buffer[..size]) {
+            // the other player can't be more than the input delay ahead of us, and
+            // sends its input the input delay ahead of itself
+            Some(Message::Input { first_frame, buttons }) if first_frame.checked_add(buttons.len() as u64).is_some() => {
+                let last = self.frame + 2 * self.input_delay as u64;
+                for (frame, buttons) in (first_frame..).zip(buttons) {
+                    if (self.frame..=last).contains(&frame) {
+                        self.remote_inputs.insert(frame, buttons);
+                    }
+                }
+            }
+            // the guest is still waiting for its welcome
+            Some(Message::Hello { .. }) if self.is_host() => self.send(&Message::Welcome { input_delay: self.input_delay })?,
+            Some(Message::Checksum { frame, crc }) => {
+                self.remote_checksums.insert(frame, crc);
+            }
+            Some(Message::Resync { frame }) if self.is_host() => {
+                if let Some(state) = self.sync_states.get(&frame) {
+                    let count = state.len().div_ceil(STATE_CHUNK_SIZE) as u16;
+                    for (index, data) in state.chunks(STATE_CHUNK_SIZE).enumerate() {
+                        let chunk = Message::State { frame, index: index as u16, count, data: data.to_vec() };
+                        self.send(&chunk)?;
+                    }
+                }
+            }
+            Some(Message::State { frame, index, count, data }) => {
+                if let Some((_, chunks)) = self.resync.as_mut().filter(|(wanted, _)| *wanted == frame) {
+                    chunks.resize(count as usize, None);
+                    if let Some(chunk) = chunks.get_mut(index as usize) {
+                        *chunk = Some(data);
+                    }
+                }
+            }
+            Some(Message::Quit) => return Err(NetplayError::Disconnected),
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    pub fn quit(self) -> Result<(), NetplayError> {
+        self.send(&Message::Quit)
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::nes_with_program;
+    use std::thread;
+
+    fn connect(host_crc: u32, guest_crc: u32, timeout: Duration) -> (Result<NetplaySession, NetplayError>, Result<NetplaySession, NetplayError>) {
+        let config = NetplayConfig { input_delay: 2, timeout };
+        let probe = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = probe.local_addr().unwrap();
+        drop(probe);
+        let host = thread::spawn(move || NetplaySession::host(address, host_crc, config));
+        let guest = NetplaySession::join("127.0.0.1:0", address, guest_crc, config);
+        (host.join().unwrap(), guest)
+    }
+
+    // JMP $8000
+    fn nes() -> Nes {
+        nes_with_program(&[0x4C, 0x00, 0x80])
+    }
+
+    #[test]
+    fn test_messages() {
+        let messages = [
+            Message::Hello { version: PROTOCOL_VERSION, rom_crc: 0xDEAD_BEEF },
+            Message::Welcome { input_delay: 3 },
+            Message::Reject(RejectReason::Rom),
+            Message::Input { first_frame: 1 << 40, buttons: vec![JoypadButton::A, JoypadButton::Start | JoypadButton::Left] },
+            Message::Checksum { frame: 120, crc: 0x1234_5678 },
+            Message::Resync { frame: 120 },
+            Message::State { frame: 120, index: 2, count: 5, data: vec![1, 2, 3] },
+            Message::Quit,
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(Message::decode(&[CHECKSUM, 1, 2]), None);
+        assert_eq!(Message::decode(&[]), None);
+    }
+
+    #[test]
+    fn test_rom_mismatch() {
+        let (_, guest) = connect(1, 2, Duration::from_millis(300));
+        assert!(matches!(guest, Err(NetplayError::Rejected(RejectReason::Rom))));
+    }
+
+    #[test]
+    fn test_input_outside_window() {
+        let (host, guest) = connect(7, 7, Duration::from_secs(2));
+        let (mut host, guest) = (host.unwrap(), guest.unwrap());
+        let buttons = vec![JoypadButton::A; 3];
+        guest.send(&Message::Input { first_frame: u64::MAX - 1, buttons: buttons.clone() }).unwrap();
+        guest.send(&Message::Input { first_frame: 1 << 40, buttons: buttons.clone() }).unwrap();
+        guest.send(&Message::Input { first_frame: 3, buttons }).unwrap();
+        for _ in 0..3 {
+            assert!(host.receive().unwrap());
+        }
+        // only frames 3 and 4 fit in the window ahead of frame 0
+        assert_eq!(host.remote_inputs.keys().copied().collect::<Vec<_>>(), [0, 1, 3, 4]);
+    }
+
+    #[test]
+    fn test_lockstep_and_resync() {
+        let (host, guest) = connect(7, 7, Duration::from_secs(2));
+        let (mut host, mut guest) = (host.unwrap(), guest.unwrap());
+        assert_eq!((host.player, guest.player, guest.input_delay), (0, 1, 2));
+
+        let frames = SYNC_INTERVAL * 3;
+        let host_thread = thread::spawn(move || {
+            let mut nes = nes();
+            let mut ports = Vec::new();
+            for frame in 0..frames {
+                host.run_frame(&mut nes, if frame % 2 == 0 { JoypadButton::A } else { JoypadButton::empty() }).unwrap();
+                ports.push([nes.buttons(0), nes.buttons(1)]);
+            }
+            assert!(host.checksums.is_empty());
+            (ports, nes.cpu().bus.ram)
+        });
+        let mut nes = nes();
+        let mut ports = Vec::new();
+        for frame in 0..frames {
+            // the guest's RAM goes bad before the first sync point
+            if frame == 10 {
+                nes.poke(0x0010, 0x99);
+            }
+            guest.run_frame(&mut nes, JoypadButton::Start).unwrap();
+            ports.push([nes.buttons(0), nes.buttons(1)]);
+        }
+        let (host_ports, host_ram) = host_thread.join().unwrap();
+        assert_eq!(ports, host_ports);
+        // held back by the input delay
+        assert_eq!(ports[0], [JoypadButton::empty(), JoypadButton::empty()]);
+        assert_eq!(ports[2], [JoypadButton::A, JoypadButton::Start]);
+        assert_eq!(guest.desyncs, 1);
+        assert_eq!(nes.cpu().bus.ram, host_ram);
+        assert!(guest.checksums.keys().all(|&frame| frame >= frames - SYNC_INTERVAL * SYNC_STATES as u64));
+    }
+}
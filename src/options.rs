@@ -0,0 +1,341 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::gamedb::{GameEntry, Region};
+use crate::rom::Mirroring;
+use crate::symbols::SymbolTable;
+use crate::trace::{TraceChannel, TraceFilter};
+
+pub const USAGE: &str = "\
+usage: madnes [options]
+  --verify-nestest [ROM] [LOG]  run nestest.nes and diff it against nestest.log
+  --verify-cpu-vectors DIR      run the nes6502 SingleStepTests JSON files in DIR
+  --list-mappers                list the supported mappers and exit
+  --info ROM                    print the ROM's header, hashes and database entry
+  --disassemble ROM             print ca65 source for the ROM's fixed PRG bank
+  --bench FRAMES ROM            run ROM headless for FRAMES frames, decode cache off and on
+  --replay-repro CAPTURE ROM    replay a repro capture against ROM headless
+  --export-state STATE ROM      print a madNES savestate of ROM as an interchange JSON dump
+  --import-state FILE ROM       put the PRG RAM of an FCEUX state or dump in ROM's .sav
+  --dump-ppu FRAMES ROM OUT     run ROM headless for FRAMES frames and write a PPU dump to OUT
+  --render-ppu-dump DUMP OUT    draw the frame in a PPU dump to OUT as a PPM image
+  --fix-header ROM OUT          write ROM to OUT with a clean header and the fixes below
+    --mapper N, --submapper N   with games.toml applied first
+    --mirroring NAME            horizontal, vertical or four_screen
+    --battery, --no-battery
+    --region NAME               ntsc, pal or dendy
+  --patch FILE                  apply an IPS or BPS patch to the ROM, repeatable
+  --config PATH                 read settings from PATH instead of ~/.config/madnes/config.toml
+  --palette NAME|FILE           ntsc, classic or a 192 byte .pal file
+  --trace CHANNELS              trace cpu,ppu,apu,mapper or all
+  --trace-file PATH             write trace lines to PATH instead of stdout
+  --trace-buffer LINES          keep the last LINES trace lines in memory
+  --trace-filter FILTERS        trace only pc:START-END ranges and write:START-END writes
+  --dump-audio PATH             write the sound of --replay-repro and --dump-ppu runs to PATH as a 16 bit WAV";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    // none given, which prints the usage
+    Usage,
+    VerifyNestest { rom: PathBuf, log: PathBuf },
+    VerifyCpuVectors { dir: PathBuf },
+    ListMappers,
+    Info { rom: PathBuf },
+    Disassemble { rom: PathBuf },
+    Bench { frames: u64, rom: PathBuf },
+    ReplayRepro { capture: PathBuf, rom: PathBuf },
+    FixHeader { rom: PathBuf, out: PathBuf },
+    ExportState { state: PathBuf, rom: PathBuf },
+    ImportState { state: PathBuf, rom: PathBuf },
+    DumpPpu { frames: u64, rom: PathBuf, out: PathBuf },
+    RenderPpuDump { dump: PathBuf, out: PathBuf },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmulatorOptions {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    // applied to the ROM in the order given
+    pub patches: Vec<PathBuf>,
+    // header fields for --fix-header to override
+    pub header_fix: GameEntry,
+    // the palette and trace settings override the config file when given
+    pub palette: Option<String>,
+    pub trace: TraceChannel,
+    pub trace_file: Option<PathBuf>,
+    pub trace_buffer: usize,
+    pub trace_filters: Vec<TraceFilter>,
+    pub dump_audio: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum OptionsError {
+    MissingValue(String),
+    InvalidValue { option: String, value: String },
+    UnknownOption(String),
+}
+
+impl fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OptionsError::MissingValue(option) => write!(f, "{} needs a value", option),
+            OptionsError::InvalidValue { option, value } => write!(f, "invalid value for {}: {}", option, value),
+            OptionsError::UnknownOption(option) => write!(f, "unknown option {}", option),
+        }
+    }
+}
+
+impl Default for EmulatorOptions {
+    fn default() -> Self {
+        EmulatorOptions {
+            command: Command::Usage,
+            config: None,
+            patches: Vec::new(),
+            header_fix: GameEntry::default(),
+            palette: None,
+            trace: TraceChannel::empty(),
+            trace_file: None,
+            trace_buffer: 0,
+            trace_filters: Vec::new(),
+            dump_audio: None,
+        }
+    }
+}
+
+impl EmulatorOptions {
+    // Parses the command line arguments, without the program name
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Result<EmulatorOptions, OptionsError> {
+        let mut options = EmulatorOptions::default();
+        let mut args = args.into_iter().peekable();
+
+        while let Some(arg) = args.next() {
+            let mut value = |option: &str| args.next().ok_or_else(|| OptionsError::MissingValue(option.to_string()));
+            match arg.as_str() {
+                "--verify-nestest" => {
+                    // both paths are optional positionals
+                    let rom = args.next_if(|arg| !arg.starts_with("--")).unwrap_or_else(|| "nestest.nes".to_string());
+                    let log = args.next_if(|arg| !arg.starts_with("--")).unwrap_or_else(|| "nestest.log".to_string());
+                    options.command = Command::VerifyNestest { rom: rom.into(), log: log.into() };
+                }
+                "--verify-cpu-vectors" => options.command = Command::VerifyCpuVectors { dir: value(&arg)?.into() },
+                "--list-mappers" => options.command = Command::ListMappers,
+                "--info" => options.command = Command::Info { rom: value(&arg)?.into() },
+                "--disassemble" => options.command = Command::Disassemble { rom: value(&arg)?.into() },
+                "--bench" => {
+                    let frames = value(&arg)?;
+                    let frames = match frames.parse::<u64>() {
+                        Ok(count) if count > 0 => count,
+                        _ => return Err(OptionsError::InvalidValue { option: arg, value: frames }),
+                    };
+                    options.command = Command::Bench { frames, rom: value("--bench")?.into() };
+                }
+                "--replay-repro" => {
+                    let capture = value(&arg)?.into();
+                    options.command = Command::ReplayRepro { capture, rom: value("--replay-repro")?.into() };
+                }
+                "--fix-header" => {
+                    let rom = value(&arg)?.into();
+                    options.command = Command::FixHeader { rom, out: value("--fix-header")?.into() };
+                }
+                "--export-state" => {
+                    let state = value(&arg)?.into();
+                    options.command = Command::ExportState { state, rom: value("--export-state")?.into() };
+                }
+                "--import-state" => {
+                    let state = value(&arg)?.into();
+                    options.command = Command::ImportState { state, rom: value("--import-state")?.into() };
+                }
+                "--dump-ppu" => {
+                    let frames = value(&arg)?;
+                    let frames = match frames.parse::<u64>() {
+                        Ok(count) if count > 0 => count,
+                        _ => return Err(OptionsError::InvalidValue { option: arg, value: frames }),
+                    };
+                    let rom = value("--dump-ppu")?.into();
+                    options.command = Command::DumpPpu { frames, rom, out: value("--dump-ppu")?.into() };
+                }
+                "--render-ppu-dump" => {
+                    let dump = value(&arg)?.into();
+                    options.command = Command::RenderPpuDump { dump, out: value("--render-ppu-dump")?.into() };
+                }
+                "--mapper" => {
+                    let mapper = value(&arg)?;
+                    options.header_fix.mapper =
+                        Some(mapper.parse().map_err(|_| OptionsError::InvalidValue { option: arg, value: mapper })?);
+                }
+                "--submapper" => {
+                    let submapper = value(&arg)?;
+                    options.header_fix.submapper = match submapper.parse::<u8>() {
+                        Ok(number) if number < 16 => Some(number),
+                        _ => return Err(OptionsError::InvalidValue { option: arg, value: submapper }),
+                    };
+                }
+                "--mirroring" => {
+                    let mirroring = value(&arg)?;
+                    options.header_fix.mirroring = Some(
+                        Mirroring::parse(&mirroring).ok_or(OptionsError::InvalidValue { option: arg, value: mirroring })?,
+                    );
+                }
+                "--battery" => options.header_fix.battery = Some(true),
+                "--no-battery" => options.header_fix.battery = Some(false),
+                "--region" => {
+                    let region = value(&arg)?;
+                    options.header_fix.region =
+                        Some(Region::parse(&region).ok_or(OptionsError::InvalidValue { option: arg, value: region })?);
+                }
+                "--patch" => options.patches.push(value(&arg)?.into()),
+                "--config" => options.config = Some(value(&arg)?.into()),
+                "--palette" => options.palette = Some(value(&arg)?),
+                "--trace" => {
+                    let channels = value(&arg)?;
+                    options.trace = TraceChannel::parse(&channels)
+                        .ok_or(OptionsError::InvalidValue { option: arg, value: channels })?;
+                }
+                "--trace-file" => options.trace_file = Some(value(&arg)?.into()),
+                "--trace-buffer" => {
+                    let lines = value(&arg)?;
+                    options.trace_buffer = lines
+                        .parse()
+                        .map_err(|_| OptionsError::InvalidValue { option: arg, value: lines })?;
+                }
+                "--trace-filter" => {
+                    // there is no ROM yet to find symbols for, so only numbers here
+                    let filters = value(&arg)?;
+                    options.trace_filters = TraceFilter::parse_list(&filters, &SymbolTable::new())
+                        .ok_or(OptionsError::InvalidValue { option: arg, value: filters })?;
+                }
+                "--dump-audio" => options.dump_audio = Some(value(&arg)?.into()),
+                _ => return Err(OptionsError::UnknownOption(arg)),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<EmulatorOptions, OptionsError> {
+        EmulatorOptions::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_defaults() {
+        assert_eq!(parse(&[]).unwrap(), EmulatorOptions::default());
+    }
+
+    #[test]
+    fn test_verify_nestest() {
+        let options = parse(&["--verify-nestest", "--trace", "cpu"]).unwrap();
+        assert_eq!(
+            options.command,
+            Command::VerifyNestest { rom: "nestest.nes".into(), log: "nestest.log".into() }
+        );
+        assert_eq!(options.trace, TraceChannel::Cpu);
+
+        let options = parse(&["--verify-nestest", "a.nes", "b.log"]).unwrap();
+        assert_eq!(options.command, Command::VerifyNestest { rom: "a.nes".into(), log: "b.log".into() });
+        assert_eq!(parse(&["--list-mappers"]).unwrap().command, Command::ListMappers);
+        assert_eq!(parse(&["--info", "a.nes"]).unwrap().command, Command::Info { rom: "a.nes".into() });
+        assert_eq!(parse(&["--disassemble", "a.nes"]).unwrap().command, Command::Disassemble { rom: "a.nes".into() });
+        assert_eq!(
+            parse(&["--verify-cpu-vectors", "v1"]).unwrap().command,
+            Command::VerifyCpuVectors { dir: "v1".into() }
+        );
+        assert_eq!(parse(&["--bench", "600", "a.nes"]).unwrap().command, Command::Bench { frames: 600, rom: "a.nes".into() });
+        assert!(matches!(parse(&["--bench", "0", "a.nes"]), Err(OptionsError::InvalidValue { .. })));
+        assert_eq!(parse(&["--bench", "600"]), Err(OptionsError::MissingValue("--bench".to_string())));
+        assert_eq!(
+            parse(&["--replay-repro", "bug.repro", "a.nes"]).unwrap().command,
+            Command::ReplayRepro { capture: "bug.repro".into(), rom: "a.nes".into() }
+        );
+    }
+
+    #[test]
+    fn test_trace_options() {
+        let options = parse(&["--config", "madnes.toml", "--trace", "cpu,mapper", "--trace-file", "madnes.log", "--trace-buffer", "100"]).unwrap();
+        assert_eq!(options.config, Some("madnes.toml".into()));
+        assert_eq!(options.trace, TraceChannel::Cpu | TraceChannel::Mapper);
+        assert_eq!(options.trace_file, Some("madnes.log".into()));
+        assert_eq!(options.trace_buffer, 100);
+
+        let options = parse(&["--trace-filter", "pc:$C000-$C0FF,write:$2006-$2007"]).unwrap();
+        assert_eq!(options.trace_filters, [TraceFilter::Pc(0xC000..=0xC0FF), TraceFilter::Write(0x2006..=0x2007)]);
+        assert!(matches!(parse(&["--trace-filter", "pc:$C000-"]), Err(OptionsError::InvalidValue { .. })));
+    }
+
+    #[test]
+    fn test_patches() {
+        let options = parse(&["--patch", "a.ips", "--bench", "10", "game.nes", "--patch", "b.bps"]).unwrap();
+        assert_eq!(options.patches, [PathBuf::from("a.ips"), PathBuf::from("b.bps")]);
+        assert_eq!(parse(&["--patch"]), Err(OptionsError::MissingValue("--patch".to_string())));
+    }
+
+    #[test]
+    fn test_fix_header() {
+        let options =
+            parse(&["--fix-header", "a.nes", "b.nes", "--mapper", "4", "--mirroring", "vertical", "--no-battery"]).unwrap();
+        assert_eq!(options.command, Command::FixHeader { rom: "a.nes".into(), out: "b.nes".into() });
+        assert_eq!(
+            options.header_fix,
+            GameEntry {
+                mapper: Some(4),
+                mirroring: Some(Mirroring::Vertical),
+                battery: Some(false),
+                ..GameEntry::default()
+            }
+        );
+        let options = parse(&["--submapper", "2", "--region", "dendy"]).unwrap();
+        assert_eq!((options.header_fix.submapper, options.header_fix.region), (Some(2), Some(Region::Dendy)));
+        assert!(matches!(parse(&["--mapper", "256"]), Err(OptionsError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--submapper", "16"]), Err(OptionsError::InvalidValue { .. })));
+        assert!(matches!(parse(&["--mirroring", "diagonal"]), Err(OptionsError::InvalidValue { .. })));
+        assert_eq!(parse(&["--fix-header", "a.nes"]), Err(OptionsError::MissingValue("--fix-header".to_string())));
+    }
+
+    #[test]
+    fn test_state_interchange() {
+        assert_eq!(
+            parse(&["--export-state", "a.state", "a.nes"]).unwrap().command,
+            Command::ExportState { state: "a.state".into(), rom: "a.nes".into() }
+        );
+        assert_eq!(
+            parse(&["--import-state", "a.fc0", "a.nes"]).unwrap().command,
+            Command::ImportState { state: "a.fc0".into(), rom: "a.nes".into() }
+        );
+        assert_eq!(parse(&["--import-state", "a.fc0"]), Err(OptionsError::MissingValue("--import-state".to_string())));
+    }
+
+    #[test]
+    fn test_ppu_dump() {
+        assert_eq!(
+            parse(&["--dump-ppu", "60", "a.nes", "a.json"]).unwrap().command,
+            Command::DumpPpu { frames: 60, rom: "a.nes".into(), out: "a.json".into() }
+        );
+        assert!(matches!(parse(&["--dump-ppu", "0", "a.nes", "a.json"]), Err(OptionsError::InvalidValue { .. })));
+        assert_eq!(parse(&["--dump-ppu", "60", "a.nes"]), Err(OptionsError::MissingValue("--dump-ppu".to_string())));
+        assert_eq!(
+            parse(&["--render-ppu-dump", "a.json", "a.ppm"]).unwrap().command,
+            Command::RenderPpuDump { dump: "a.json".into(), out: "a.ppm".into() }
+        );
+    }
+
+    #[test]
+    fn test_dump_audio() {
+        assert_eq!(parse(&["--dump-audio", "music.wav"]).unwrap().dump_audio, Some("music.wav".into()));
+        assert_eq!(parse(&["--dump-audio"]), Err(OptionsError::MissingValue("--dump-audio".to_string())));
+    }
+
+    #[test]
+    fn test_palette() {
+        assert_eq!(parse(&["--palette", "smooth.pal"]).unwrap().palette, Some("smooth.pal".to_string()));
+    }
+
+    #[test]
+    fn test_invalid_options() {
+        assert_eq!(parse(&["--trace"]), Err(OptionsError::MissingValue("--trace".to_string())));
+        assert!(matches!(parse(&["--trace", "gpu"]), Err(OptionsError::InvalidValue { .. })));
+        assert_eq!(parse(&["--fast"]), Err(OptionsError::UnknownOption("--fast".to_string())));
+    }
+}
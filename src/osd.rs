@@ -0,0 +1,170 @@
+use std::collections::VecDeque;
+
+use crate::rom::Rom;
+
+// How long a message is shown at full strength, then how long it fades, in frames
+const MESSAGE_FRAMES: u64 = 120;
+const FADE_FRAMES: u64 = 30;
+const MAX_MESSAGES: usize = 4;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OsdMessage {
+    pub text: String,
+    // messages with the same tag replace each other, e.g. repeated volume changes
+    pub tag: Option<&'static str>,
+    pub shown_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OsdPosition {
+    // notifications, newest at the bottom
+    BottomLeft,
+    TopLeft,
+    TopRight,
+    Center,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsdLine {
+    pub text: String,
+    pub position: OsdPosition,
+    // 0.0 to 1.0, for fading out
+    pub alpha: f32,
+}
+
+// Text drawn over the game: notifications, the pause banner and the frame rate.
+// Its clock is ticked once per presented frame, paused or not, so messages
+// still fade while the game is paused.
+#[derive(Debug, Clone, Default)]
+pub struct Osd {
+    pub messages: VecDeque<OsdMessage>,
+    pub paused: bool,
+    pub show_fps: bool,
+    pub fps: f32,
+    // profiler overlay lines, see Profiler::lines
+    pub stats: Vec<String>,
+    frame: u64,
+}
+
+impl Osd {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn tick(&mut self) {
+        self.frame += 1;
+        let frame = self.frame;
+        self.messages.retain(|message| frame - message.shown_at < MESSAGE_FRAMES + FADE_FRAMES);
+    }
+
+    pub fn notify(&mut self, text: impl Into<String>) {
+        self.push(None, text.into());
+    }
+
+    pub fn notify_tagged(&mut self, tag: &'static str, text: impl Into<String>) {
+        self.messages.retain(|message| message.tag != Some(tag));
+        self.push(Some(tag), text.into());
+    }
+
+    fn push(&mut self, tag: Option<&'static str>, text: String) {
+        if self.messages.len() == MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(OsdMessage { text, tag, shown_at: self.frame });
+    }
+
+    pub fn state_saved(&mut self, slot: usize) {
+        self.notify(format!("State saved to slot {}", slot));
+    }
+
+    pub fn state_loaded(&mut self, slot: usize) {
+        self.notify(format!("State loaded from slot {}", slot));
+    }
+
+    // What was loaded, then anything odd about its header
+    pub fn rom_loaded(&mut self, rom: &Rom) {
+        self.notify(rom.describe());
+        for warning in &rom.warnings {
+            self.notify(format!("Warning: {}", warning));
+        }
+    }
+
+    pub fn rewinding(&mut self) {
+        self.notify_tagged("rewind", "Rewinding");
+    }
+
+    pub fn volume(&mut self, volume: f32) {
+        self.notify_tagged("volume", format!("Volume {:.0}%", volume * 100.0));
+    }
+
+    fn alpha(&self, message: &OsdMessage) -> f32 {
+        let age = self.frame - message.shown_at;
+        if age < MESSAGE_FRAMES {
+            1.0
+        } else {
+            1.0 - (age - MESSAGE_FRAMES) as f32 / FADE_FRAMES as f32
+        }
+    }
+
+    // Everything to draw this frame
+    pub fn lines(&self) -> Vec<OsdLine> {
+        let mut lines: Vec<OsdLine> = self
+            .messages
+            .iter()
+            .map(|message| OsdLine { text: message.text.clone(), position: OsdPosition::BottomLeft, alpha: self.alpha(message) })
+            .collect();
+        if self.paused {
+            lines.push(OsdLine { text: "Paused".to_string(), position: OsdPosition::Center, alpha: 1.0 });
+        }
+        lines.extend(self.stats.iter().map(|text| OsdLine { text: text.clone(), position: OsdPosition::TopLeft, alpha: 1.0 }));
+        if self.show_fps {
+            lines.push(OsdLine { text: format!("{:.1} FPS", self.fps), position: OsdPosition::TopRight, alpha: 1.0 });
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_message_fades_out() {
+        let mut osd = Osd::new();
+        osd.state_saved(3);
+        assert_eq!(osd.lines()[0].text, "State saved to slot 3");
+        for _ in 0..MESSAGE_FRAMES + FADE_FRAMES / 2 {
+            osd.tick();
+        }
+        assert_eq!(osd.lines()[0].alpha, 0.5);
+        for _ in 0..FADE_FRAMES / 2 {
+            osd.tick();
+        }
+        assert!(osd.lines().is_empty());
+    }
+
+    #[test]
+    fn test_tagged_messages_replace() {
+        let mut osd = Osd::new();
+        osd.volume(0.5);
+        osd.notify("Reset");
+        osd.volume(0.6);
+        let texts: Vec<String> = osd.lines().into_iter().map(|line| line.text).collect();
+        assert_eq!(texts, ["Reset", "Volume 60%"]);
+
+        osd.paused = true;
+        assert_eq!(osd.lines().last().unwrap().position, OsdPosition::Center);
+    }
+
+    #[test]
+    fn test_rom_warnings() {
+        let mut data = crate::rom::tests::ines(1, 1, 0x09, 0);
+        data.push(0);
+        let mut osd = Osd::new();
+        osd.rom_loaded(&Rom::new(&data).unwrap());
+        let texts: Vec<String> = osd.lines().into_iter().map(|line| line.text).collect();
+        assert_eq!(texts.len(), 3);
+        assert_eq!(texts[1], "Warning: File is 24593 bytes but the header declares 24592");
+        assert_eq!(texts[2], "Warning: Header sets both four-screen and vertical mirroring");
+    }
+}
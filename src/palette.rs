@@ -0,0 +1,256 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+pub type Rgb = (u8, u8, u8);
+
+// The 64 colors the 2C02 PPU can output, indexed by palette RAM values
+pub static SYSTEM_PALETTE: [Rgb; 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96),
+    (0xA1, 0x00, 0x5E), (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00),
+    (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E),
+    (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05), (0x05, 0x05, 0x05),
+    (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00),
+    (0xC4, 0x62, 0x00), (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55),
+    (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21), (0x09, 0x09, 0x09), (0x09, 0x09, 0x09),
+    (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF), (0xD4, 0x80, 0xFF),
+    (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4),
+    (0x05, 0xFB, 0xFF), (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D),
+    (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF), (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB),
+    (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), (0xFF, 0xEF, 0xA6),
+    (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+// Composite signal levels in volts, relative to sync, for the four luma
+// levels with the square wave low (first four) and high (last four)
+const SIGNAL_LEVELS: [f32; 8] = [0.350, 0.518, 0.962, 1.550, 1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+// Signal level multiplier while an emphasis bit is active
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// For palettes given as RGB, each emphasis bit darkens the two other channels instead
+const RGB_EMPHASIS_ATTENUATION: f32 = 0.816328;
+// One variant of the palette for each combination of the three PPUMASK emphasis bits
+pub const EMPHASIS_VARIANTS: usize = 8;
+// Rotates the decoded hues to line up with the colors of a real NTSC TV, in color clock phases
+const HUE_SHIFT: f32 = 3.75;
+const DISPLAY_GAMMA: f32 = 2.2;
+const NTSC_GAMMA: f32 = 1.8;
+
+// Size of a .pal file: 64 colors, 3 bytes each
+pub const PAL_FILE_SIZE: usize = 64 * 3;
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    InvalidSize(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(error) => write!(f, "Could not read palette: {}", error),
+            PaletteError::InvalidSize(size) => write!(f, "Palette files must be {} bytes, got {}", PAL_FILE_SIZE, size),
+        }
+    }
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(error: io::Error) -> Self {
+        PaletteError::Io(error)
+    }
+}
+
+// The 64 colors used to turn palette RAM values into RGB, precomputed for every
+// emphasis setting so the renderer only does a lookup per pixel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Palette {
+    pub colors: [Rgb; 64],
+    // indexed by the emphasis bits (PPUMASK >> 5: red, green, blue), variant 0 is `colors`
+    pub emphasized: [[Rgb; 64]; EMPHASIS_VARIANTS],
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::ntsc()
+    }
+}
+
+impl Palette {
+    // Decodes the composite video signal the PPU generates for each color
+    pub fn ntsc() -> Self {
+        let mut emphasized = [[(0, 0, 0); 64]; EMPHASIS_VARIANTS];
+        for (emphasis, colors) in emphasized.iter_mut().enumerate() {
+            for (index, color) in colors.iter_mut().enumerate() {
+                *color = ntsc_color(index as u8, emphasis as u8);
+            }
+        }
+        Palette { colors: emphasized[0], emphasized }
+    }
+
+    // The fixed palette madNES has always shipped with
+    pub fn classic() -> Self {
+        Palette::from_colors(SYSTEM_PALETTE)
+    }
+
+    // A palette given as plain RGB, with emphasis approximated by dimming the other channels
+    pub fn from_colors(colors: [Rgb; 64]) -> Self {
+        let mut emphasized = [colors; EMPHASIS_VARIANTS];
+        for (emphasis, variant) in emphasized.iter_mut().enumerate() {
+            for color in variant.iter_mut() {
+                *color = emphasize(*color, emphasis as u8);
+            }
+        }
+        Palette { colors, emphasized }
+    }
+
+    // Parses a .pal file, 64 RGB triplets
+    pub fn from_pal(data: &[u8]) -> Result<Self, PaletteError> {
+        if data.len() != PAL_FILE_SIZE {
+            return Err(PaletteError::InvalidSize(data.len()));
+        }
+        let mut colors = [(0, 0, 0); 64];
+        for (color, rgb) in colors.iter_mut().zip(data.chunks_exact(3)) {
+            *color = (rgb[0], rgb[1], rgb[2]);
+        }
+        Ok(Palette::from_colors(colors))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, PaletteError> {
+        Palette::from_pal(&fs::read(path)?)
+    }
+
+    // Resolves the --palette value: a built-in palette name or a .pal file path
+    pub fn select(name: &str) -> Result<Self, PaletteError> {
+        match name {
+            "ntsc" => Ok(Palette::ntsc()),
+            "classic" => Ok(Palette::classic()),
+            path => Palette::load(path),
+        }
+    }
+
+    pub fn color(&self, value: u8) -> Rgb {
+        self.colors[(value & 0x3F) as usize]
+    }
+
+    // `emphasis` is the top three bits of PPUMASK shifted down
+    pub fn emphasized_color(&self, value: u8, emphasis: u8) -> Rgb {
+        self.emphasized[(emphasis & 0x07) as usize][(value & 0x3F) as usize]
+    }
+}
+
+fn emphasize((r, g, b): Rgb, emphasis: u8) -> Rgb {
+    let attenuate = |channel: u8, own: u8| {
+        let others = (emphasis & !own & 0x07).count_ones();
+        (channel as f32 * RGB_EMPHASIS_ATTENUATION.powi(others as i32)).round() as u8
+    };
+    (attenuate(r, 0x01), attenuate(g, 0x02), attenuate(b, 0x04))
+}
+
+// Generates the color for a palette value (0x00-0x3F) with the PPUMASK
+// emphasis bits (0-7, red green blue) by sampling the 12 phases of the
+// PPU's square wave and decoding them as YIQ
+pub fn ntsc_color(value: u8, emphasis: u8) -> Rgb {
+    let hue = (value & 0x0F) as usize;
+    let mut level = ((value >> 4) & 0x03) as usize;
+    // columns $xE and $xF output black
+    if hue > 13 {
+        level = 1;
+    }
+    let mut low = SIGNAL_LEVELS[level];
+    let mut high = SIGNAL_LEVELS[4 + level];
+    if hue == 0 {
+        low = high;
+    } else if hue > 12 {
+        high = low;
+    }
+
+    let in_phase = |color: usize, phase: usize| (color + phase) % 12 < 6;
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let mut signal = if in_phase(hue, phase) { high } else { low };
+        let emphasized = (emphasis & 0x01 != 0 && in_phase(0, phase))
+            || (emphasis & 0x02 != 0 && in_phase(4, phase))
+            || (emphasis & 0x04 != 0 && in_phase(8, phase));
+        if emphasized && hue < 14 {
+            signal *= EMPHASIS_ATTENUATION;
+        }
+        let signal = (signal - BLACK) / (WHITE - BLACK) / 12.0;
+        let angle = std::f32::consts::PI * (phase as f32 + HUE_SHIFT) / 6.0;
+        y += signal;
+        // multiplying by the subcarrier halves its amplitude, double it back
+        i += 2.0 * signal * angle.cos();
+        q += 2.0 * signal * angle.sin();
+    }
+
+    let gamma = |value: f32| {
+        let value = value.clamp(0.0, 1.0).powf(NTSC_GAMMA / DISPLAY_GAMMA);
+        (value * 255.0).round() as u8
+    };
+    (
+        gamma(y + 0.946882 * i + 0.623557 * q),
+        gamma(y - 0.274788 * i - 0.635691 * q),
+        gamma(y - 1.108545 * i + 1.709007 * q),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_dominant(color: Rgb, channel: usize) -> bool {
+        let channels = [color.0, color.1, color.2];
+        (0..3).all(|other| other == channel || channels[channel] > channels[other])
+    }
+
+    #[test]
+    fn test_ntsc_greys_and_black() {
+        let palette = Palette::ntsc();
+        assert_eq!(palette.color(0x0F), (0, 0, 0));
+        assert_eq!(palette.color(0x1D), (0, 0, 0));
+        assert_eq!(palette.color(0x20), (0xFF, 0xFF, 0xFF));
+        let (r, g, b) = palette.color(0x00);
+        assert!(r == g && g == b && r > 0x40 && r < 0xC0);
+    }
+
+    #[test]
+    fn test_ntsc_hues() {
+        let palette = Palette::ntsc();
+        // $x6 red, $xA green, $x2 blue
+        assert!(is_dominant(palette.color(0x16), 0));
+        assert!(is_dominant(palette.color(0x1A), 1));
+        assert!(is_dominant(palette.color(0x12), 2));
+    }
+
+    #[test]
+    fn test_ntsc_emphasis() {
+        let (r, g, b) = ntsc_color(0x30, 0);
+        let (er, eg, eb) = ntsc_color(0x30, 0x01);
+        assert!(er <= r && eg < g && eb < b);
+        // black columns are not affected
+        assert_eq!(ntsc_color(0x0F, 0x07), (0, 0, 0));
+        assert_eq!(Palette::ntsc().emphasized_color(0x30, 0x01), (er, eg, eb));
+    }
+
+    #[test]
+    fn test_rgb_emphasis_variants() {
+        let palette = Palette::classic();
+        assert_eq!(palette.emphasized[0], SYSTEM_PALETTE);
+        assert_eq!(palette.emphasized_color(0x30, 0x01), (0xFF, 0xD0, 0xD0));
+        assert_eq!(palette.emphasized_color(0x30, 0x07), (0xAA, 0xAA, 0xAA));
+    }
+
+    #[test]
+    fn test_from_pal() {
+        let mut data = vec![0; PAL_FILE_SIZE];
+        data[3..6].copy_from_slice(&[1, 2, 3]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.color(0x41), (1, 2, 3));
+        assert!(matches!(Palette::from_pal(&data[1..]), Err(PaletteError::InvalidSize(191))));
+        assert_eq!(Palette::select("classic").unwrap().colors, SYSTEM_PALETTE);
+    }
+}
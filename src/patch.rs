@@ -0,0 +1,331 @@
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::hash;
+
+// Soft patches for translations and hacks, applied to the ROM file's bytes (header
+// included, as patches for NES games are made) before it is parsed.
+//   IPS  "PATCH", then records of a 3 byte offset, 2 byte size and the bytes, or a
+//        size of 0 with a 2 byte count and one byte to repeat, up to "EOF" and an
+//        optional 3 byte size to cut the file to
+//   BPS  "BPS1", then sizes and actions copying from the source, the patch or the
+//        output so far, and the CRC-32s of the source, the output and the patch
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const BPS_MAGIC: &[u8] = b"BPS1";
+const BPS_FOOTER_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum PatchError {
+    Io(std::io::Error),
+    UnknownFormat,
+    Truncated,
+    // a BPS action reaching outside the source, the patch or the output
+    OutOfRange,
+    // the BPS patch was made for a different ROM, e.g. one with another header
+    SourceChecksum { expected: u32, actual: u32 },
+    TargetChecksum { expected: u32, actual: u32 },
+    PatchChecksum { expected: u32, actual: u32 },
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::Io(error) => write!(f, "Could not read patch: {}", error),
+            PatchError::UnknownFormat => write!(f, "Patch is not in IPS or BPS format"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::OutOfRange => write!(f, "Patch is corrupt"),
+            PatchError::SourceChecksum { expected, actual } => {
+                write!(f, "Patch is for a different ROM: expected CRC32 {:08X}, got {:08X}", expected, actual)
+            }
+            PatchError::TargetChecksum { expected, actual } => {
+                write!(f, "Patched ROM has CRC32 {:08X} instead of {:08X}", actual, expected)
+            }
+            PatchError::PatchChecksum { expected, actual } => {
+                write!(f, "Patch is corrupt: expected CRC32 {:08X}, got {:08X}", expected, actual)
+            }
+        }
+    }
+}
+
+impl From<std::io::Error> for PatchError {
+    fn from(error: std::io::Error) -> Self {
+        PatchError::Io(error)
+    }
+}
+
+// Returns the patched copy of data, telling the format from the patch's magic
+pub fn apply(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(data, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(data, patch)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+pub fn apply_file(data: &[u8], path: impl AsRef<Path>) -> Result<Vec<u8>, PatchError> {
+    apply(data, &fs::read(path)?)
+}
+
+fn bytes(patch: &[u8], offset: usize, count: usize) -> Result<&[u8], PatchError> {
+    let end = offset.checked_add(count).ok_or(PatchError::Truncated)?;
+    patch.get(offset..end).ok_or(PatchError::Truncated)
+}
+
+fn big_endian(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |value, &byte| value << 8 | byte as usize)
+}
+
+fn apply_ips(data: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = data.to_vec();
+    let mut position = IPS_MAGIC.len();
+    loop {
+        let offset = bytes(patch, position, 3)?;
+        position += 3;
+        if offset == IPS_EOF {
+            break;
+        }
+        let offset = big_endian(offset);
+        let size = big_endian(bytes(patch, position, 2)?);
+        position += 2;
+        let (count, run) = if size == 0 {
+            let run = bytes(patch, position, 3)?;
+            position += 3;
+            (big_endian(&run[..2]), Some(run[2]))
+        } else {
+            (size, None)
+        };
+        // records past the end grow the file
+        if out.len() < offset + count {
+            out.resize(offset + count, 0);
+        }
+        match run {
+            Some(value) => out[offset..offset + count].fill(value),
+            None => {
+                out[offset..offset + count].copy_from_slice(bytes(patch, position, count)?);
+                position += count;
+            }
+        }
+    }
+    if let Ok(size) = bytes(patch, position, 3) {
+        out.truncate(big_endian(size));
+    }
+    Ok(out)
+}
+
+// BPS numbers: 7 bits a byte, low first, the top bit set on the last byte, and
+// each continuation adding one more so there's only one way to write a number.
+// Numbers that don't fit in a usize are corrupt.
+fn read_number(patch: &[u8], position: &mut usize) -> Result<usize, PatchError> {
+    let (mut value, mut shift) = (0usize, 1usize);
+    loop {
+        let byte = *patch.get(*position).ok_or(PatchError::Truncated)?;
+        *position += 1;
+        let digit = ((byte & 0x7F) as usize).checked_mul(shift).ok_or(PatchError::OutOfRange)?;
+        value = value.checked_add(digit).ok_or(PatchError::OutOfRange)?;
+        if byte & 0x80 != 0 {
+            return Ok(value);
+        }
+        shift = shift.checked_mul(0x80).ok_or(PatchError::OutOfRange)?;
+        value = value.checked_add(shift).ok_or(PatchError::OutOfRange)?;
+    }
+}
+
+fn range(start: usize, length: usize) -> Result<std::ops::Range<usize>, PatchError> {
+    Ok(start..start.checked_add(length).ok_or(PatchError::OutOfRange)?)
+}
+
+// A relative offset: the low bit is the sign
+fn read_offset(patch: &[u8], position: &mut usize, cursor: usize) -> Result<usize, PatchError> {
+    let number = read_number(patch, position)?;
+    let cursor = if number & 1 != 0 { cursor.checked_sub(number >> 1) } else { cursor.checked_add(number >> 1) };
+    cursor.ok_or(PatchError::OutOfRange)
+}
+
+fn crc_at(patch: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([patch[offset], patch[offset + 1], patch[offset + 2], patch[offset + 3]])
+}
+
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = patch.len() - BPS_FOOTER_SIZE;
+    let expected = crc_at(patch, footer + 8);
+    let actual = hash::crc32(&patch[..footer + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchChecksum { expected, actual });
+    }
+    let (expected, actual) = (crc_at(patch, footer), hash::crc32(source));
+    if expected != actual {
+        return Err(PatchError::SourceChecksum { expected, actual });
+    }
+
+    let mut position = BPS_MAGIC.len();
+    let _source_size = read_number(patch, &mut position)?;
+    let target_size = read_number(patch, &mut position)?;
+    let metadata_size = read_number(patch, &mut position)?;
+    position = position.checked_add(metadata_size).filter(|&end| end <= footer).ok_or(PatchError::Truncated)?;
+    // the size is only trusted as far as a patch this big could plausibly produce
+    let mut out = Vec::with_capacity(target_size.min(source.len() + patch.len()));
+    let (mut source_cursor, mut target_cursor) = (0, 0);
+    while position < footer {
+        let action = read_number(patch, &mut position)?;
+        let length = (action >> 2) + 1;
+        if range(out.len(), length)?.end > target_size {
+            return Err(PatchError::OutOfRange);
+        }
+        match action & 3 {
+            // source read: the source byte at the same offset
+            0 => {
+                let start = out.len();
+                out.extend_from_slice(source.get(range(start, length)?).ok_or(PatchError::OutOfRange)?);
+            }
+            // target read: bytes from the patch
+            1 => {
+                out.extend_from_slice(bytes(patch, position, length)?);
+                position += length;
+            }
+            // source copy: from anywhere in the source
+            2 => {
+                source_cursor = read_offset(patch, &mut position, source_cursor)?;
+                out.extend_from_slice(source.get(range(source_cursor, length)?).ok_or(PatchError::OutOfRange)?);
+                source_cursor += length;
+            }
+            // target copy: from the output so far, a byte at a time since it may overlap
+            _ => {
+                target_cursor = read_offset(patch, &mut position, target_cursor)?;
+                for _ in 0..length {
+                    let byte = *out.get(target_cursor).ok_or(PatchError::OutOfRange)?;
+                    out.push(byte);
+                    target_cursor += 1;
+                }
+            }
+        }
+    }
+    if position != footer || out.len() != target_size {
+        return Err(PatchError::OutOfRange);
+    }
+    let (expected, actual) = (crc_at(patch, footer + 4), hash::crc32(&out));
+    if expected != actual {
+        return Err(PatchError::TargetChecksum { expected, actual });
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        // two bytes at 1, then a run of four 0xEE at 6 growing the file
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xAA, 0xBB]);
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xEE]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply(&[0; 8], &patch).unwrap(), [0, 0xAA, 0xBB, 0, 0, 0, 0xEE, 0xEE, 0xEE, 0xEE]);
+
+        // cut down to three bytes
+        patch.extend_from_slice(&[0, 0, 3]);
+        assert_eq!(apply(&[0; 8], &patch).unwrap(), [0, 0xAA, 0xBB]);
+        assert!(matches!(apply(&[0; 8], &patch[..12]), Err(PatchError::Truncated)));
+        assert!(matches!(apply(&[0; 8], b"UPS1"), Err(PatchError::UnknownFormat)));
+    }
+
+    fn number(mut value: usize, out: &mut Vec<u8>) {
+        loop {
+            let byte = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte | 0x80);
+                return;
+            }
+            out.push(byte);
+            value -= 1;
+        }
+    }
+
+    fn bps(source: &[u8], target: &[u8], actions: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(target.len(), &mut patch);
+        number(0, &mut patch);
+        patch.extend_from_slice(actions);
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(target).to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"HELLO NES";
+        let target = b"HELLO HELLO WORLD";
+        let mut actions = Vec::new();
+        // source read "HELLO ", target copy "HELLO " from the start of the output,
+        // target read "WORLD"
+        number((6 - 1) << 2, &mut actions);
+        number(((6 - 1) << 2) | 3, &mut actions);
+        number(0, &mut actions);
+        number(((5 - 1) << 2) | 1, &mut actions);
+        actions.extend_from_slice(b"WORLD");
+        let patch = bps(source, target, &actions);
+        assert_eq!(apply(source, &patch).unwrap(), target);
+
+        match apply(b"HELLO SNES", &patch) {
+            Err(PatchError::SourceChecksum { actual, .. }) => assert_eq!(actual, hash::crc32(b"HELLO SNES")),
+            result => panic!("expected a source checksum error, got {:?}", result),
+        }
+        let mut corrupt = patch.clone();
+        corrupt[10] ^= 1;
+        assert!(matches!(apply(source, &corrupt), Err(PatchError::PatchChecksum { .. })));
+        // a source copy from before the start
+        let mut actions = Vec::new();
+        number(2, &mut actions);
+        number(3, &mut actions);
+        assert!(matches!(apply(source, &bps(source, b"H", &actions)), Err(PatchError::OutOfRange)));
+    }
+
+    #[test]
+    fn test_bps_overlong_number() {
+        // eleven continuation bytes are more than a 64 bit number holds
+        let mut patch = b"BPS1".to_vec();
+        patch.extend_from_slice(&[0x7F; 11]);
+        patch.push(0x80);
+        let mut position = BPS_MAGIC.len();
+        assert!(matches!(read_number(&patch, &mut position), Err(PatchError::OutOfRange)));
+        let mut position = BPS_MAGIC.len();
+        assert!(matches!(read_number(&[0; 40], &mut position), Err(PatchError::OutOfRange)));
+    }
+
+    #[test]
+    fn test_bps_huge_target_size() {
+        let source = b"HELLO NES";
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(1 << 40, &mut patch);
+        number(0, &mut patch);
+        number((4 - 1) << 2, &mut patch);
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(b"HELL").to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(apply(source, &patch), Err(PatchError::OutOfRange)));
+
+        // metadata running past the footer
+        let mut patch = b"BPS1".to_vec();
+        number(source.len(), &mut patch);
+        number(1, &mut patch);
+        number(usize::MAX >> 1, &mut patch);
+        patch.extend_from_slice(&hash::crc32(source).to_le_bytes());
+        patch.extend_from_slice(&hash::crc32(b"H").to_le_bytes());
+        let crc = hash::crc32(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        assert!(matches!(apply(source, &patch), Err(PatchError::Truncated)));
+    }
+}
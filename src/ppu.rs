@@ -0,0 +1,1510 @@
+use std::fmt;
+
+use bitflags::bitflags;
+
+use crate::mapper::Mapper;
+use crate::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::palette::{Palette, Rgb};
+use crate::rom::Mirroring;
+use crate::savestate::{SaveState, StateError, StateReader};
+use crate::viewer::Image;
+
+// Frame timing, the PPU runs 3 dots per CPU cycle
+pub const DOTS_PER_SCANLINE: u64 = 341;
+pub const SCANLINES_PER_FRAME: u64 = 262;
+pub const DOTS_PER_FRAME: u64 = DOTS_PER_SCANLINE * SCANLINES_PER_FRAME;
+pub const VISIBLE_SCANLINES: usize = 240;
+// The last scanline of the frame, which fetches for the first visible one
+pub const PRE_RENDER_SCANLINE: u16 = SCANLINES_PER_FRAME as u16 - 1;
+// Vblank starts on dot 1 of this scanline and ends on dot 1 of the pre-render line
+pub const VBLANK_SCANLINE: u16 = 241;
+// Dots after vblank starts before the CPU sees NMI, a $2002 read until then cancels it
+const NMI_DELAY_DOTS: u16 = 3;
+
+// Pixels at the left edge hidden by the PPUMASK clip bits
+pub const LEFT_CLIP_WIDTH: usize = 8;
+
+bitflags! {
+    // PPUMASK ($2001)
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuMask: u8 {
+        const Greyscale = 1 << 0;
+        const ShowBackgroundLeft = 1 << 1;
+        const ShowSpritesLeft = 1 << 2;
+        const ShowBackground = 1 << 3;
+        const ShowSprites = 1 << 4;
+        const EmphasizeRed = 1 << 5;
+        const EmphasizeGreen = 1 << 6;
+        const EmphasizeBlue = 1 << 7;
+    }
+}
+
+impl PpuMask {
+    pub const EMPHASIS: PpuMask = PpuMask::EmphasizeRed.union(PpuMask::EmphasizeGreen).union(PpuMask::EmphasizeBlue);
+
+    pub fn is_rendering(self) -> bool {
+        self.intersects(PpuMask::ShowBackground | PpuMask::ShowSprites)
+    }
+
+    fn background_visible(self, x: usize) -> bool {
+        self.contains(PpuMask::ShowBackground) && (x >= LEFT_CLIP_WIDTH || self.contains(PpuMask::ShowBackgroundLeft))
+    }
+
+    fn sprites_visible(self, x: usize) -> bool {
+        self.contains(PpuMask::ShowSprites) && (x >= LEFT_CLIP_WIDTH || self.contains(PpuMask::ShowSpritesLeft))
+    }
+}
+
+// Debug switches that hide a layer regardless of what the game writes to PPUMASK
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Layers {
+    pub background: bool,
+    pub sprites: bool,
+    // false crops the layer out of the leftmost 8 pixels like the PPUMASK clip bits,
+    // for games that leave scroll garbage there
+    pub background_left: bool,
+    pub sprites_left: bool,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Layers { background: true, sprites: true, background_left: true, sprites_left: true }
+    }
+}
+
+impl Layers {
+    // Crops "background", "sprites", "both" or "none" from the left column
+    pub fn set_crop_left(&mut self, layers: &str) -> bool {
+        let (background, sprites) = match layers.to_ascii_lowercase().as_str() {
+            "none" => (false, false),
+            "background" => (true, false),
+            "sprites" => (false, true),
+            "both" => (true, true),
+            _ => return false,
+        };
+        self.background_left = !background;
+        self.sprites_left = !sprites;
+        true
+    }
+
+    // Whether the background and the sprites, when on, are hidden from the left column
+    // on a line drawn with `mask`, by the game's clip bits or by the crop
+    pub fn left_clip(self, mask: PpuMask) -> (bool, bool) {
+        (
+            mask.contains(PpuMask::ShowBackground) && !(mask.contains(PpuMask::ShowBackgroundLeft) && self.background_left),
+            mask.contains(PpuMask::ShowSprites) && !(mask.contains(PpuMask::ShowSpritesLeft) && self.sprites_left),
+        )
+    }
+
+    pub fn toggle_background(&mut self) {
+        self.background = !self.background;
+    }
+
+    pub fn toggle_sprites(&mut self) {
+        self.sprites = !self.sprites;
+    }
+}
+
+// A sprite pixel: its palette RAM address (0x10-0x1F) and whether it is drawn behind the background
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpritePixel {
+    pub address: u8,
+    pub behind_background: bool,
+}
+
+// Picks the palette RAM address shown at column `x` from the background pixel
+// (0x00-0x0F) and the frontmost sprite pixel. Addresses with a low 2 bits of 0
+// are transparent and fall back to the universal background color at 0x00.
+pub fn compose_pixel(mask: PpuMask, layers: Layers, x: usize, background: u8, sprite: Option<SpritePixel>) -> u8 {
+    let background = if layers.background
+        && mask.background_visible(x)
+        && (x >= LEFT_CLIP_WIDTH || layers.background_left)
+        && background & 0x03 != 0
+    {
+        background
+    } else {
+        0
+    };
+    let sprite = sprite.filter(|sprite| {
+        layers.sprites && mask.sprites_visible(x) && (x >= LEFT_CLIP_WIDTH || layers.sprites_left) && sprite.address & 0x03 != 0
+    });
+    match sprite {
+        Some(sprite) if background == 0 || !sprite.behind_background => sprite.address,
+        _ => background,
+    }
+}
+
+// Final color for a palette RAM value, with greyscale and color emphasis applied
+pub fn output_color(mask: PpuMask, value: u8, palette: &Palette) -> Rgb {
+    let value = if mask.contains(PpuMask::Greyscale) { value & 0x30 } else { value };
+    palette.emphasized_color(value, (mask & PpuMask::EMPHASIS).bits() >> 5)
+}
+
+bitflags! {
+    // PPUCTRL ($2000)
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuCtrl: u8 {
+        const NametableX = 1 << 0;
+        const NametableY = 1 << 1;
+        const Increment32 = 1 << 2;
+        const SpritePatternTable = 1 << 3;
+        const BackgroundPatternTable = 1 << 4;
+        const TallSprites = 1 << 5;
+        const MasterSlave = 1 << 6;
+        const GenerateNmi = 1 << 7;
+    }
+}
+
+bitflags! {
+    // PPUSTATUS ($2002), the only bits the PPU drives when it is read
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuStatus: u8 {
+        const SpriteOverflow = 1 << 5;
+        const SpriteZeroHit = 1 << 6;
+        const VerticalBlank = 1 << 7;
+    }
+}
+
+// Register numbers, the CPU sees them mirrored every 8 bytes from $2000 to $3FFF
+const PPUCTRL: u16 = 0;
+const PPUMASK: u16 = 1;
+const PPUSTATUS: u16 = 2;
+const OAMADDR: u16 = 3;
+const OAMDATA: u16 = 4;
+const PPUSCROLL: u16 = 5;
+const PPUADDR: u16 = 6;
+const PPUDATA: u16 = 7;
+
+// Palette RAM only stores 6 bits, the top two come from the latch
+const PALETTE_BITS: u8 = 0x3F;
+// The unimplemented bits of each sprite's attribute byte read back as 0
+const OAM_ATTRIBUTE_BITS: u8 = 0xE3;
+
+pub const VRAM_SIZE: usize = 0x800;
+pub const PALETTE_SIZE: usize = 32;
+pub const OAM_SIZE: usize = 256;
+// four screen boards add 2KB of their own next to the console's CIRAM
+pub const FOUR_SCREEN_VRAM_SIZE: usize = 0x1000;
+
+// Where the debugger can stop on the PPU side. The PPU checks these every dot but
+// can't stop an instruction half way, so the first one hit is latched in
+// breakpoint_hit and the debugger stops after the instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PpuBreakpoint {
+    Dot { scanline: u16, dot: u16 },
+    Vblank,
+    SpriteZeroHit,
+}
+
+impl PpuBreakpoint {
+    // "vblank", "sprite0", "scanline 100" or "scanline 100 dot 256"
+    pub fn parse(text: &str) -> Option<PpuBreakpoint> {
+        let words: Vec<String> = text.split_whitespace().map(str::to_ascii_lowercase).collect();
+        let number = |word: &String| word.parse::<u16>().ok();
+        let breakpoint = match words.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+            ["vblank"] => PpuBreakpoint::Vblank,
+            ["sprite0"] => PpuBreakpoint::SpriteZeroHit,
+            ["scanline", _] => PpuBreakpoint::Dot { scanline: number(&words[1])?, dot: 0 },
+            ["scanline", _, "dot", _] => PpuBreakpoint::Dot { scanline: number(&words[1])?, dot: number(&words[3])? },
+            _ => return None,
+        };
+        match breakpoint {
+            PpuBreakpoint::Dot { scanline, dot } if scanline as u64 >= SCANLINES_PER_FRAME || dot as u64 >= DOTS_PER_SCANLINE => None,
+            breakpoint => Some(breakpoint),
+        }
+    }
+}
+
+impl fmt::Display for PpuBreakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpuBreakpoint::Dot { scanline, dot } => write!(f, "scanline {} dot {}", scanline, dot),
+            PpuBreakpoint::Vblank => write!(f, "vblank"),
+            PpuBreakpoint::SpriteZeroHit => write!(f, "sprite 0 hit"),
+        }
+    }
+}
+
+// The PPU's data bus holds the last value written to or read from its registers
+// and returns it for write-only registers. Bits that aren't refreshed fade to 0
+// after about 600ms, which the ppu_open_bus test ROM checks for.
+pub const IO_LATCH_DECAY_CYCLES: u64 = 1_073_864;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IoLatch {
+    pub value: u8,
+    // when each bit was last driven to 1
+    refreshed: [u64; 8],
+}
+
+impl IoLatch {
+    pub fn write(&mut self, value: u8, now: u64) {
+        self.value = value;
+        for (bit, refreshed) in self.refreshed.iter_mut().enumerate() {
+            if value & (1 << bit) != 0 {
+                *refreshed = now;
+            }
+        }
+    }
+
+    // Drives only the bits in `mask`, the others keep their value and age
+    pub fn write_bits(&mut self, value: u8, mask: u8, now: u64) {
+        let value = value & mask;
+        self.value = value | self.value & !mask;
+        for (bit, refreshed) in self.refreshed.iter_mut().enumerate() {
+            if value & (1 << bit) != 0 {
+                *refreshed = now;
+            }
+        }
+    }
+
+    pub fn read(&self, now: u64) -> u8 {
+        (0..8)
+            .filter(|&bit| now.saturating_sub(self.refreshed[bit]) < IO_LATCH_DECAY_CYCLES)
+            .fold(0, |value, bit| value | (self.value & (1 << bit)))
+    }
+}
+
+// The PPU's CPU-facing registers and its memory: nametable RAM, palette RAM and OAM.
+// Pattern tables and nametable mapping go through the cartridge's mapper. The dot
+// clock fetches the background and sprites like the real PPU and draws a pixel into
+// the back buffer on each visible dot, which is also where the sprite 0 hit and
+// overflow flags come from. VRAM stays accessible through $2007 while rendering.
+#[derive(Debug, Clone)]
+pub struct Ppu {
+    pub ctrl: PpuCtrl,
+    pub mask: PpuMask,
+    pub status: PpuStatus,
+    pub oam_address: u8,
+    pub oam: [u8; OAM_SIZE],
+    // CIRAM, plus the cartridge's extra 2KB on four screen boards
+    pub vram: [u8; FOUR_SCREEN_VRAM_SIZE],
+    pub palette: [u8; PALETTE_SIZE],
+    // current and temporary VRAM address, fine X scroll and the $2005/$2006 write toggle
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_toggle: bool,
+    // $2007 reads below the palettes return this, then refill it from VRAM
+    pub read_buffer: u8,
+    // saved by the bus, which has the clock it decays by
+    pub latch: IoLatch,
+    // position of the dot clock, scanline 0 is the first visible one
+    pub scanline: u16,
+    pub dot: u16,
+    // frames since power on; odd ones are a dot shorter while rendering
+    pub frame: u64,
+    // set by a $2002 read just before vblank starts, which then doesn't
+    suppress_vblank: bool,
+    // background shift registers, low and high bit planes and the attribute bits
+    // spread out per pixel. The top byte is the tile being drawn, the low byte the next.
+    pub background_patterns: [u16; 2],
+    pub background_attributes: [u16; 2],
+    // the sprites on the line being drawn, fetched at the end of the line before
+    pub sprite_rows: Vec<SpriteRow>,
+    // where each visible scanline was drawn from, None while rendering was off
+    pub scroll_lines: [Option<ScrollPosition>; VISIBLE_SCANLINES],
+    // PPUMASK as each visible scanline started, for showing where the left column was clipped
+    pub mask_lines: [PpuMask; VISIBLE_SCANLINES],
+    // what the pixels look like: the RGB color of each palette RAM value, and the
+    // debug switches for hiding layers
+    pub colors: Palette,
+    pub layers: Layers,
+    // the frame being drawn, and the last one finished. They trade places on the
+    // dot the frame ends, before the next frame's first pixels go in. The console
+    // takes the finished one when frame_complete goes up, so the renderer never
+    // sees a half drawn picture.
+    pub back_buffer: Image,
+    pub front_buffer: Image,
+    pub frame_complete: bool,
+    // set by the debugger, see PpuBreakpoint
+    pub breakpoints: Vec<PpuBreakpoint>,
+    pub breakpoint_hit: Option<PpuBreakpoint>,
+}
+
+// A scanline's top left corner in the 512x480 space of the four nametables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrollPosition {
+    pub x: u16,
+    pub y: u16,
+}
+
+impl ScrollPosition {
+    // Decodes the loopy VRAM address layout, yyy NN YYYYY XXXXX
+    pub fn from_address(v: u16, fine_x: u8) -> ScrollPosition {
+        let x = (v & 0x001F) * 8 + ((v >> 10) & 0x01) * 256 + fine_x as u16;
+        let y = ((v >> 5) & 0x001F) * 8 + ((v >> 11) & 0x01) * 240 + (v >> 12);
+        ScrollPosition { x, y }
+    }
+}
+
+// Plain copy of the PPU registers and dot clock, for debuggers and tests
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpuState {
+    pub ctrl: PpuCtrl,
+    pub mask: PpuMask,
+    pub status: PpuStatus,
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_toggle: bool,
+    pub oam_address: u8,
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+}
+
+impl Default for Ppu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Ppu {
+    pub fn new() -> Self {
+        Ppu {
+            ctrl: PpuCtrl::empty(),
+            mask: PpuMask::empty(),
+            status: PpuStatus::empty(),
+            oam_address: 0,
+            oam: [0; OAM_SIZE],
+            vram: [0; FOUR_SCREEN_VRAM_SIZE],
+            palette: [0; PALETTE_SIZE],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            latch: IoLatch::default(),
+            scanline: 0,
+            dot: 0,
+            frame: 0,
+            suppress_vblank: false,
+            background_patterns: [0; 2],
+            background_attributes: [0; 2],
+            sprite_rows: Vec::new(),
+            scroll_lines: [None; VISIBLE_SCANLINES],
+            mask_lines: [PpuMask::empty(); VISIBLE_SCANLINES],
+            colors: Palette::default(),
+            layers: Layers::default(),
+            back_buffer: Image::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            front_buffer: Image::new(SCREEN_WIDTH, SCREEN_HEIGHT),
+            frame_complete: false,
+            breakpoints: Vec::new(),
+            breakpoint_hit: None,
+        }
+    }
+
+    // The console's reset button clears the registers the CPU writes, and the
+    // latches behind them. Memory, OAM and the dot clock carry on.
+    pub fn reset(&mut self) {
+        self.ctrl = PpuCtrl::empty();
+        self.mask = PpuMask::empty();
+        self.t = 0;
+        self.fine_x = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+    }
+
+    // Advances one dot. While rendering, the background fetches step v across the
+    // nametables: a tile every 8 dots, which moves coarse X on, and fine Y at dot 256,
+    // then X comes back from t at dot 257 and, on the pre-render line, Y at dots
+    // 280-304. Writes to $2000/$2005/$2006 between those points are what split the
+    // screen. At dot 257 the sprites for the next line are evaluated and fetched. The
+    // cartridge hears about each rendered line and the start of vblank, for scanline
+    // counters like MMC5's.
+    pub fn tick(&mut self, mut mapper: Option<&mut Box<dyn Mapper>>) {
+        let sprite_zero_hit = self.status.contains(PpuStatus::SpriteZeroHit);
+        let pre_render = self.scanline == PRE_RENDER_SCANLINE;
+        let visible = (self.scanline as usize) < VISIBLE_SCANLINES;
+        if visible && self.dot == 0 {
+            // the first two tiles were fetched at the end of the line before
+            self.scroll_lines[self.scanline as usize] = self.mask.is_rendering().then(|| {
+                let position = ScrollPosition::from_address(self.v, self.fine_x);
+                ScrollPosition { x: (position.x + 512 - 16) % 512, y: position.y }
+            });
+            self.mask_lines[self.scanline as usize] = self.mask;
+        }
+        if let Some(mapper) = mapper.as_deref_mut() {
+            match (self.scanline, self.dot) {
+                (_, 0) if visible && self.mask.is_rendering() => mapper.scanline(),
+                (VBLANK_SCANLINE, 1) => mapper.end_frame(),
+                _ => {}
+            }
+        }
+        if visible && (1..=256).contains(&self.dot) {
+            self.render_pixel(self.dot as usize - 1);
+        }
+        if self.mask.is_rendering() && (visible || pre_render) {
+            match self.dot {
+                1..=256 | 321..=336 => {
+                    self.shift_background();
+                    if self.dot.is_multiple_of(8) {
+                        self.fetch_tile(mapper.as_deref_mut());
+                        self.increment_coarse_x();
+                        if self.dot == 256 {
+                            self.increment_y();
+                        }
+                    }
+                }
+                257 => {
+                    self.v = (self.v & !0x041F) | (self.t & 0x041F);
+                    // nothing is drawn from what the pre-render line finds
+                    self.sprite_rows = if visible {
+                        let (sprites, overflow) = self.evaluate_sprites(self.scanline);
+                        if overflow {
+                            self.status.insert(PpuStatus::SpriteOverflow);
+                        }
+                        self.fetch_sprites(self.scanline, &sprites, mapper)
+                    } else {
+                        Vec::new()
+                    };
+                }
+                280..=304 if pre_render => self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0),
+                _ => {}
+            }
+        } else if self.dot == 257 {
+            self.sprite_rows.clear();
+        }
+        match (self.scanline, self.dot) {
+            (VBLANK_SCANLINE, 1) if !std::mem::take(&mut self.suppress_vblank) => self.status.insert(PpuStatus::VerticalBlank),
+            (PRE_RENDER_SCANLINE, 1) => self.status.remove(PpuStatus::VerticalBlank | PpuStatus::SpriteZeroHit | PpuStatus::SpriteOverflow),
+            _ => {}
+        }
+        if !self.breakpoints.is_empty() && self.breakpoint_hit.is_none() {
+            self.breakpoint_hit = self.breakpoints.iter().copied().find(|breakpoint| match *breakpoint {
+                PpuBreakpoint::Dot { scanline, dot } => (scanline, dot) == (self.scanline, self.dot),
+                PpuBreakpoint::Vblank => (self.scanline, self.dot) == (VBLANK_SCANLINE, 1) && self.status.contains(PpuStatus::VerticalBlank),
+                PpuBreakpoint::SpriteZeroHit => !sprite_zero_hit && self.status.contains(PpuStatus::SpriteZeroHit),
+            });
+        }
+
+        self.dot += 1;
+        // odd frames skip the pre-render line's last dot while rendering
+        if pre_render && self.dot == 340 && self.frame % 2 == 1 && self.mask.is_rendering() {
+            self.dot += 1;
+        }
+        if self.dot as u64 == DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline as u64 == SCANLINES_PER_FRAME {
+                self.scanline = 0;
+                self.frame += 1;
+                self.frame_complete = true;
+                std::mem::swap(&mut self.front_buffer, &mut self.back_buffer);
+            }
+        }
+    }
+
+    // The NMI line to the CPU: vblank with NMI enabled in PPUCTRL. Turning NMI on
+    // during vblank raises it again, which is another NMI.
+    pub fn nmi_output(&self) -> bool {
+        let settling = self.scanline == VBLANK_SCANLINE && (2..=NMI_DELAY_DOTS + 1).contains(&self.dot);
+        self.status.contains(PpuStatus::VerticalBlank) && self.ctrl.contains(PpuCtrl::GenerateNmi) && !settling
+    }
+
+    pub fn snapshot(&self) -> PpuState {
+        PpuState {
+            ctrl: self.ctrl,
+            mask: self.mask,
+            status: self.status,
+            v: self.v,
+            t: self.t,
+            fine_x: self.fine_x,
+            write_toggle: self.write_toggle,
+            oam_address: self.oam_address,
+            scanline: self.scanline,
+            dot: self.dot,
+            frame: self.frame,
+        }
+    }
+
+    // Wraps from the 32nd tile into the horizontally adjacent nametable
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v = (self.v & !0x001F) ^ 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Row 29 wraps into the vertically adjacent nametable; rows 30 and 31 hold the
+    // attributes, scrolled into only by writes, and wrap without switching
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let coarse_y = match (self.v >> 5) & 0x1F {
+            29 => {
+                self.v ^= 0x0800;
+                0
+            }
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // Draws column `x` of the current line. With rendering off the screen shows the
+    // universal background color, or the palette entry v points at.
+    fn render_pixel(&mut self, x: usize) {
+        let address = if self.mask.is_rendering() {
+            let background = self.background_pixel();
+            if sprite_zero_hit(self.mask, x, background, &self.sprite_rows) {
+                self.status.insert(PpuStatus::SpriteZeroHit);
+            }
+            compose_pixel(self.mask, self.layers, x, background, sprite_pixel(&self.sprite_rows, x)) as u16
+        } else if self.v & 0x3F00 == 0x3F00 {
+            self.v
+        } else {
+            0
+        };
+        let color = output_color(self.mask, self.palette[palette_index(address)], &self.colors);
+        self.back_buffer.set_pixel(x, self.scanline as usize, color);
+    }
+
+    // The background pixel at the current dot, palette RAM address 0x00-0x0F
+    fn background_pixel(&self) -> u8 {
+        let bit = 15 - self.fine_x;
+        let plane = |registers: [u16; 2]| ((registers[1] >> bit) & 0x01) << 1 | (registers[0] >> bit) & 0x01;
+        let color = plane(self.background_patterns);
+        if color == 0 {
+            return 0;
+        }
+        (plane(self.background_attributes) << 2 | color) as u8
+    }
+
+    fn shift_background(&mut self) {
+        for register in self.background_patterns.iter_mut().chain(self.background_attributes.iter_mut()) {
+            *register <<= 1;
+        }
+    }
+
+    // Fetches the tile at v into the low byte of the shift registers: its nametable
+    // byte, the 2 attribute bits for its quadrant, then both pattern planes
+    fn fetch_tile(&mut self, mut mapper: Option<&mut Box<dyn Mapper>>) {
+        let tile = self.read_vram(0x2000 | (self.v & 0x0FFF), mapper.as_deref_mut()) as u16;
+        let attribute_address = 0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07);
+        let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+        let palette = self.read_vram(attribute_address, mapper.as_deref_mut()) >> shift;
+        let table = if self.ctrl.contains(PpuCtrl::BackgroundPatternTable) { 0x1000 } else { 0 };
+        let address = table + tile * 16 + (self.v >> 12);
+        let patterns = [self.read_vram(address, mapper.as_deref_mut()), self.read_vram(address + 8, mapper)];
+        for (plane, pattern) in patterns.into_iter().enumerate() {
+            let attribute = if palette & (1 << plane) != 0 { 0xFF } else { 0x00 };
+            self.background_patterns[plane] = (self.background_patterns[plane] & 0xFF00) | pattern as u16;
+            self.background_attributes[plane] = (self.background_attributes[plane] & 0xFF00) | attribute;
+        }
+    }
+
+    // `register` is the CPU address, `now` the CPU cycle count the latch decays by
+    pub fn read_register(&mut self, register: u16, mapper: Option<&mut Box<dyn Mapper>>, now: u64) -> u8 {
+        match register % 8 {
+            PPUSTATUS => {
+                // one dot early reads the flag clear and keeps it from being set. Reads
+                // during the NMI delay see it set, and clearing it cancels the NMI.
+                if (self.scanline, self.dot) == (VBLANK_SCANLINE, 1) {
+                    self.suppress_vblank = true;
+                }
+                let status = self.status.bits();
+                self.latch.write_bits(status, PpuStatus::all().bits(), now);
+                self.status.remove(PpuStatus::VerticalBlank);
+                self.write_toggle = false;
+                self.latch.read(now)
+            }
+            OAMDATA => {
+                let value = self.read_oam();
+                self.latch.write(value, now);
+                value
+            }
+            PPUDATA => {
+                let address = self.v & 0x3FFF;
+                let value = if address >= 0x3F00 {
+                    // palette reads skip the buffer, which picks up the nametable byte "underneath"
+                    let value = self.palette_value(address);
+                    self.read_buffer = self.read_vram(address - 0x1000, mapper);
+                    self.latch.write_bits(value, PALETTE_BITS, now);
+                    self.latch.read(now)
+                } else {
+                    let value = self.read_buffer;
+                    self.read_buffer = self.read_vram(address, mapper);
+                    self.latch.write(value, now);
+                    value
+                };
+                self.increment_address();
+                value
+            }
+            _ => self.latch.read(now),
+        }
+    }
+
+    // What a read would return, without touching the buffer, flags or latch ages
+    pub fn peek_register(&self, register: u16, now: u64) -> u8 {
+        let latch = self.latch.read(now);
+        match register % 8 {
+            PPUSTATUS => self.status.bits() | latch & !PpuStatus::all().bits(),
+            OAMDATA => self.read_oam(),
+            PPUDATA if self.v & 0x3FFF >= 0x3F00 => self.palette_value(self.v) | latch & !PALETTE_BITS,
+            PPUDATA => self.read_buffer,
+            _ => latch,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u16, value: u8, mapper: Option<&mut Box<dyn Mapper>>, now: u64) {
+        self.latch.write(value, now);
+        match register % 8 {
+            PPUCTRL => {
+                self.ctrl = PpuCtrl::from_bits_retain(value);
+                self.t = (self.t & !0x0C00) | ((value as u16 & 0x03) << 10);
+            }
+            PPUMASK => self.mask = PpuMask::from_bits_retain(value),
+            OAMADDR => self.oam_address = value,
+            OAMDATA => {
+                self.oam[self.oam_address as usize] = value;
+                self.oam_address = self.oam_address.wrapping_add(1);
+            }
+            PPUSCROLL => {
+                if self.write_toggle {
+                    self.t = (self.t & !0x73E0) | ((value as u16 & 0x07) << 12) | ((value as u16 & 0xF8) << 2);
+                } else {
+                    self.t = (self.t & !0x001F) | (value as u16 >> 3);
+                    self.fine_x = value & 0x07;
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            PPUADDR => {
+                if self.write_toggle {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v = self.t;
+                } else {
+                    self.t = (self.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            PPUDATA => {
+                self.write_vram(self.v & 0x3FFF, value, mapper);
+                self.increment_address();
+            }
+            _ => {}
+        }
+    }
+
+    fn read_oam(&self) -> u8 {
+        let value = self.oam[self.oam_address as usize];
+        if self.oam_address % 4 == 2 {
+            value & OAM_ATTRIBUTE_BITS
+        } else {
+            value
+        }
+    }
+
+    fn increment_address(&mut self) {
+        let step = if self.ctrl.contains(PpuCtrl::Increment32) { 32 } else { 1 };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    fn palette_value(&self, address: u16) -> u8 {
+        let value = self.palette[palette_index(address)];
+        if self.mask.contains(PpuMask::Greyscale) {
+            value & 0x30
+        } else {
+            value
+        }
+    }
+
+    // The PPU address space below the palettes: pattern tables, then nametables
+    pub fn read_vram(&self, address: u16, mapper: Option<&mut Box<dyn Mapper>>) -> u8 {
+        let address = address & 0x3FFF;
+        let Some(mapper) = mapper else {
+            return if address >= 0x2000 { self.vram[nametable_index(address, Mirroring::Vertical)] } else { 0 };
+        };
+        match address {
+            0x0000..=0x1FFF => {
+                mapper.ppu_fetch(address);
+                mapper.read_chr(address)
+            }
+            _ => mapper
+                .read_nametable(address, &self.vram[..VRAM_SIZE])
+                .unwrap_or_else(|| self.vram[nametable_index(address, mapper.mirroring())]),
+        }
+    }
+
+    // The whole PPU address space for the debugger, palettes included. Unlike
+    // read_vram this doesn't move latches like MMC2's.
+    pub fn peek_vram(&self, address: u16, mapper: Option<&mut Box<dyn Mapper>>) -> u8 {
+        let address = address & 0x3FFF;
+        match (address, mapper) {
+            (0x3F00..=0x3FFF, _) => self.palette[palette_index(address)],
+            (0x0000..=0x1FFF, Some(mapper)) => mapper.read_chr(address),
+            (_, mapper) => self.read_vram(address, mapper),
+        }
+    }
+
+    // $0000-$1FFF with the CHR banks the cartridge has switched in right now, for the
+    // viewers. Unlike a rendering fetch this doesn't move latches like MMC2's.
+    pub fn pattern_tables(mapper: Option<&mut Box<dyn Mapper>>) -> Vec<u8> {
+        match mapper {
+            Some(mapper) => (0x0000..0x2000).map(|address| mapper.read_chr(address)).collect(),
+            None => vec![0; 0x2000],
+        }
+    }
+
+    // The four logical nametables at $2000-$2FFF after mirroring, for the viewers
+    pub fn nametables(&self, mut mapper: Option<&mut Box<dyn Mapper>>) -> Vec<u8> {
+        (0x2000..0x3000).map(|address| self.read_vram(address, mapper.as_deref_mut())).collect()
+    }
+
+    pub fn write_vram(&mut self, address: u16, value: u8, mapper: Option<&mut Box<dyn Mapper>>) {
+        let address = address & 0x3FFF;
+        match (address, mapper) {
+            (0x3F00..=0x3FFF, _) => self.palette[palette_index(address)] = value & PALETTE_BITS,
+            (0x0000..=0x1FFF, Some(mapper)) => mapper.write_chr(address, value),
+            (0x0000..=0x1FFF, None) => {}
+            (_, Some(mapper)) => {
+                if !mapper.write_nametable(address, value, &mut self.vram[..VRAM_SIZE]) {
+                    self.vram[nametable_index(address, mapper.mirroring())] = value;
+                }
+            }
+            (_, None) => self.vram[nametable_index(address, Mirroring::Vertical)] = value,
+        }
+    }
+}
+
+// $3F10/$3F14/$3F18/$3F1C are the same bytes as $3F00/$3F04/$3F08/$3F0C
+fn palette_index(address: u16) -> usize {
+    let index = address as usize % PALETTE_SIZE;
+    if index.is_multiple_of(4) {
+        index & 0x0F
+    } else {
+        index
+    }
+}
+
+// Folds $2000-$3EFF onto CIRAM; $3000-$3EFF mirrors $2000-$2EFF
+fn nametable_index(address: u16, mirroring: Mirroring) -> usize {
+    let table = (address as usize >> 10) & 0x03;
+    let offset = address as usize & 0x03FF;
+    let bank = match mirroring {
+        Mirroring::Horizontal => table >> 1,
+        Mirroring::Vertical => table & 0x01,
+        Mirroring::FourScreen => table,
+        Mirroring::SingleScreenLow => 0,
+        Mirroring::SingleScreenHigh => 1,
+    };
+    bank * 0x400 + offset
+}
+
+impl SaveState for Ppu {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&[self.ctrl.bits(), self.mask.bits(), self.status.bits(), self.oam_address]);
+        out.extend_from_slice(&self.oam);
+        out.extend_from_slice(&self.vram);
+        out.extend_from_slice(&self.palette);
+        out.extend_from_slice(&self.v.to_le_bytes());
+        out.extend_from_slice(&self.t.to_le_bytes());
+        out.extend_from_slice(&[self.fine_x, self.write_toggle as u8, self.read_buffer]);
+        out.extend_from_slice(&self.scanline.to_le_bytes());
+        out.extend_from_slice(&self.dot.to_le_bytes());
+        out.extend_from_slice(&self.frame.to_le_bytes());
+        for register in self.background_patterns.iter().chain(&self.background_attributes) {
+            out.extend_from_slice(&register.to_le_bytes());
+        }
+        out.push(self.sprite_rows.len() as u8);
+        for sprite in &self.sprite_rows {
+            out.extend_from_slice(&[sprite.x, sprite.pattern_low, sprite.pattern_high, sprite.attributes.bits(), sprite.sprite_zero as u8]);
+        }
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.ctrl = PpuCtrl::from_bits_retain(input.read_u8()?);
+        self.mask = PpuMask::from_bits_retain(input.read_u8()?);
+        self.status = PpuStatus::from_bits_truncate(input.read_u8()?);
+        self.oam_address = input.read_u8()?;
+        self.oam.copy_from_slice(input.read_bytes(OAM_SIZE)?);
+        self.vram.copy_from_slice(input.read_bytes(FOUR_SCREEN_VRAM_SIZE)?);
+        self.palette.copy_from_slice(input.read_bytes(PALETTE_SIZE)?);
+        self.v = input.read_u16()?;
+        self.t = input.read_u16()?;
+        self.fine_x = input.read_u8()?;
+        self.write_toggle = input.read_u8()? != 0;
+        self.read_buffer = input.read_u8()?;
+        self.scanline = input.read_u16()? % SCANLINES_PER_FRAME as u16;
+        self.dot = input.read_u16()? % DOTS_PER_SCANLINE as u16;
+        self.frame = input.read_u64()?;
+        for register in self.background_patterns.iter_mut().chain(&mut self.background_attributes) {
+            *register = input.read_u16()?;
+        }
+        let sprites = input.read_u8()? as usize;
+        if sprites > SPRITES_PER_SCANLINE {
+            return Err(StateError::Invalid);
+        }
+        self.sprite_rows = (0..sprites)
+            .map(|_| {
+                let row = input.read_bytes(5)?;
+                Ok(SpriteRow {
+                    x: row[0],
+                    pattern_low: row[1],
+                    pattern_high: row[2],
+                    attributes: SpriteAttributes::from_bits_truncate(row[3]),
+                    sprite_zero: row[4] != 0,
+                })
+            })
+            .collect::<Result<_, StateError>>()?;
+        self.suppress_vblank = false;
+        Ok(())
+    }
+}
+
+// Sprites per scanline, more set the overflow flag
+pub const SPRITES_PER_SCANLINE: usize = 8;
+
+bitflags! {
+    // OAM byte 2
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct SpriteAttributes: u8 {
+        const Palette = 0x03;
+        const BehindBackground = 1 << 5;
+        const FlipHorizontal = 1 << 6;
+        const FlipVertical = 1 << 7;
+    }
+}
+
+// One OAM entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sprite {
+    pub index: u8,
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: SpriteAttributes,
+    pub x: u8,
+}
+
+impl Sprite {
+    pub fn from_oam(oam: &[u8; OAM_SIZE], index: u8) -> Sprite {
+        let entry = &oam[index as usize * 4..index as usize * 4 + 4];
+        Sprite {
+            index,
+            y: entry[0],
+            tile: entry[1],
+            attributes: SpriteAttributes::from_bits_truncate(entry[2]),
+            x: entry[3],
+        }
+    }
+
+    // PPU address of the low bit plane of the sprite's `row`, counted from its top
+    // edge before flipping. 8x16 sprites ignore PPUCTRL's pattern table: bit 0 of the
+    // tile number picks it, and the top half uses the even tile.
+    pub fn pattern_address(&self, ctrl: PpuCtrl, row: u8) -> u16 {
+        let height = sprite_height(ctrl);
+        let row = if self.attributes.contains(SpriteAttributes::FlipVertical) { height - 1 - row } else { row } as u16;
+        if ctrl.contains(PpuCtrl::TallSprites) {
+            let table = (self.tile as u16 & 0x01) * 0x1000;
+            let tile = (self.tile as u16 & 0xFE) + row / 8;
+            table + tile * 16 + row % 8
+        } else {
+            let table = if ctrl.contains(PpuCtrl::SpritePatternTable) { 0x1000 } else { 0 };
+            table + self.tile as u16 * 16 + row
+        }
+    }
+}
+
+pub fn sprite_height(ctrl: PpuCtrl) -> u8 {
+    if ctrl.contains(PpuCtrl::TallSprites) {
+        16
+    } else {
+        8
+    }
+}
+
+// A sprite's 8 pixels on one scanline, as fetched for the line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpriteRow {
+    pub x: u8,
+    // with horizontal flipping applied, bit 7 is the leftmost pixel
+    pub pattern_low: u8,
+    pub pattern_high: u8,
+    pub attributes: SpriteAttributes,
+    pub sprite_zero: bool,
+}
+
+impl SpriteRow {
+    // The 2 bit color at column `x`, 0 when transparent or outside the sprite
+    pub fn color(&self, x: usize) -> u8 {
+        let column = x.wrapping_sub(self.x as usize);
+        if column >= 8 {
+            return 0;
+        }
+        let bit = 7 - column;
+        ((self.pattern_high >> bit) & 0x01) << 1 | (self.pattern_low >> bit) & 0x01
+    }
+}
+
+impl Ppu {
+    // The first 8 sprites in OAM order on `scanline`, and whether there were more
+    pub fn evaluate_sprites(&self, scanline: u16) -> (Vec<Sprite>, bool) {
+        let height = sprite_height(self.ctrl) as u16;
+        let mut sprites = (0..64).map(|index| Sprite::from_oam(&self.oam, index)).filter(|sprite| {
+            let row = scanline.wrapping_sub(sprite.y as u16);
+            row < height
+        });
+        let visible: Vec<Sprite> = sprites.by_ref().take(SPRITES_PER_SCANLINE).collect();
+        let overflow = sprites.next().is_some();
+        (visible, overflow)
+    }
+
+    // Fetches the pattern bytes of the evaluated sprites, in the same order
+    pub fn fetch_sprites(&self, scanline: u16, sprites: &[Sprite], mut mapper: Option<&mut Box<dyn Mapper>>) -> Vec<SpriteRow> {
+        sprites
+            .iter()
+            .map(|sprite| {
+                let row = scanline.wrapping_sub(sprite.y as u16) as u8;
+                let address = sprite.pattern_address(self.ctrl, row);
+                let mut pattern_low = self.read_vram(address, mapper.as_deref_mut());
+                let mut pattern_high = self.read_vram(address + 8, mapper.as_deref_mut());
+                if sprite.attributes.contains(SpriteAttributes::FlipHorizontal) {
+                    pattern_low = pattern_low.reverse_bits();
+                    pattern_high = pattern_high.reverse_bits();
+                }
+                SpriteRow {
+                    x: sprite.x,
+                    pattern_low,
+                    pattern_high,
+                    attributes: sprite.attributes,
+                    sprite_zero: sprite.index == 0,
+                }
+            })
+            .collect()
+    }
+}
+
+// The sprite pixel at column `x` for compose_pixel. The first opaque sprite in OAM
+// order wins even when it is behind the background and a later sprite isn't, so a
+// low-priority sprite also hides the sprites it covers (the SMB3 mushroom blocks).
+pub fn sprite_pixel(sprites: &[SpriteRow], x: usize) -> Option<SpritePixel> {
+    sprites.iter().find_map(|sprite| {
+        let color = sprite.color(x);
+        (color != 0).then(|| SpritePixel {
+            address: 0x10 | (sprite.attributes & SpriteAttributes::Palette).bits() << 2 | color,
+            behind_background: sprite.attributes.contains(SpriteAttributes::BehindBackground),
+        })
+    })
+}
+
+// Sprite 0 hits where its opaque pixel overlaps an opaque background pixel, whatever
+// the priority. It needs both layers on, never happens at x=255, and not in the
+// leftmost 8 pixels when either layer is clipped there.
+pub fn sprite_zero_hit(mask: PpuMask, x: usize, background: u8, sprites: &[SpriteRow]) -> bool {
+    x != 255
+        && mask.background_visible(x)
+        && mask.sprites_visible(x)
+        && background & 0x03 != 0
+        && sprites.iter().any(|sprite| sprite.sprite_zero && sprite.color(x) != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::palette::SYSTEM_PALETTE;
+    use crate::rom::{tests::ines, Rom};
+
+    const SHOW_ALL: PpuMask = PpuMask::ShowBackground
+        .union(PpuMask::ShowSprites)
+        .union(PpuMask::ShowBackgroundLeft)
+        .union(PpuMask::ShowSpritesLeft);
+
+    fn sprite(address: u8, behind_background: bool) -> Option<SpritePixel> {
+        Some(SpritePixel { address, behind_background })
+    }
+
+    fn set_address(ppu: &mut Ppu, address: u16) {
+        ppu.write_register(0x2006, (address >> 8) as u8, None, 0);
+        ppu.write_register(0x2006, address as u8, None, 0);
+    }
+
+    fn write_data(ppu: &mut Ppu, address: u16, value: u8) {
+        set_address(ppu, address);
+        ppu.write_register(0x2007, value, None, 0);
+    }
+
+    fn read_data(ppu: &mut Ppu, address: u16) -> u8 {
+        set_address(ppu, address);
+        ppu.read_register(0x2007, None, 0)
+    }
+
+    #[test]
+    fn test_viewer_memory_follows_the_mapper() {
+        // GxROM with two CHR banks, bank 1 starting with $AB
+        let mut image = crate::rom::tests::ines(2, 2, 0x20, 0x40);
+        image[16] = 0xFF;
+        image[16 + 0x8000 + 0x2000] = 0xAB;
+        let mut mapper = crate::mapper::create(&crate::rom::Rom::new(&image).unwrap()).unwrap();
+        assert_eq!(Ppu::pattern_tables(Some(&mut mapper))[0], 0x00);
+        mapper.write_prg(0x8000, 1);
+        let chr = Ppu::pattern_tables(Some(&mut mapper));
+        assert_eq!((chr.len(), chr[0]), (0x2000, 0xAB));
+
+        // horizontal mirroring puts the first nametable at $2000 and $2400
+        let mut ppu = Ppu::new();
+        ppu.vram[0x005] = 0x12;
+        let nametables = ppu.nametables(Some(&mut mapper));
+        assert_eq!((nametables[0x005], nametables[0x405], nametables[0x805]), (0x12, 0x12, 0x00));
+    }
+
+    #[test]
+    fn test_mapper_scanline_hooks() {
+        // mapper 5
+        let mut mapper = crate::mapper::create(&Rom::new(&ines(4, 2, 0x50, 0)).unwrap()).unwrap();
+        mapper.write_prg(0x5203, 2);
+        mapper.write_prg(0x5204, 0x80);
+        let mut ppu = Ppu::new();
+        ppu.mask = PpuMask::ShowBackground;
+        let run_to = |ppu: &mut Ppu, mapper: &mut Box<dyn Mapper>, scanline: u16| {
+            while ppu.scanline != scanline {
+                ppu.tick(Some(&mut *mapper));
+            }
+        };
+        run_to(&mut ppu, &mut mapper, 2);
+        assert_eq!((mapper.irq(), mapper.peek_prg(0x5204)), (false, 0x40));
+        run_to(&mut ppu, &mut mapper, 3);
+        assert!(mapper.irq());
+        run_to(&mut ppu, &mut mapper, VBLANK_SCANLINE + 1);
+        assert_eq!(mapper.peek_prg(0x5204) & 0x40, 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, 0x80, None, 0);
+        ppu.write_register(0x2005, 0x7D, None, 0);
+        ppu.write_register(0x2003, 0x20, None, 0);
+        run_to(&mut ppu, 10, 5);
+        let state = ppu.snapshot();
+        assert_eq!((state.ctrl, state.mask, state.t, state.fine_x), (PpuCtrl::GenerateNmi, PpuMask::empty(), 0x000F, 5));
+        assert!(state.write_toggle);
+        assert_eq!((state.oam_address, state.scanline, state.dot, state.frame), (0x20, 10, 5, 0));
+    }
+
+    // The cases of blargg's vram_access ROM
+    #[test]
+    fn test_vram_read_buffer() {
+        let mut ppu = Ppu::new();
+        write_data(&mut ppu, 0x2345, 0x12);
+        write_data(&mut ppu, 0x2346, 0x34);
+        // reads are delayed by one
+        set_address(&mut ppu, 0x2345);
+        ppu.read_register(0x2007, None, 0);
+        assert_eq!(ppu.read_register(0x2007, None, 0), 0x12);
+        assert_eq!(ppu.read_register(0x2007, None, 0), 0x34);
+
+        // writes don't update the buffer
+        read_data(&mut ppu, 0x2345);
+        write_data(&mut ppu, 0x2346, 0x56);
+        assert_eq!(ppu.read_register(0x2007, None, 0), 0x12);
+
+        // neither do palette writes
+        read_data(&mut ppu, 0x2346);
+        write_data(&mut ppu, 0x3F05, 0x27);
+        assert_eq!(read_data(&mut ppu, 0x2000), 0x56);
+    }
+
+    #[test]
+    fn test_palette_reads_bypass_buffer() {
+        let mut ppu = Ppu::new();
+        write_data(&mut ppu, 0x2F12, 0x9A);
+        write_data(&mut ppu, 0x3F12, 0x21);
+        // the palette byte comes back directly, and the buffer gets the nametable byte underneath
+        assert_eq!(read_data(&mut ppu, 0x3F12), 0x21);
+        assert_eq!(ppu.read_buffer, 0x9A);
+        assert_eq!(read_data(&mut ppu, 0x2000), 0x9A);
+
+        // $3F10 mirrors $3F00, but the buffer still reads $2F10
+        write_data(&mut ppu, 0x2F10, 0x33);
+        write_data(&mut ppu, 0x3F10, 0x0F);
+        assert_eq!(read_data(&mut ppu, 0x3F00), 0x0F);
+        assert_eq!(read_data(&mut ppu, 0x3F10), 0x0F);
+        assert_eq!(ppu.read_buffer, 0x33);
+
+        // the top two bits are open bus
+        set_address(&mut ppu, 0x3F10);
+        ppu.write_register(0x2003, 0xC0, None, 0);
+        assert_eq!(ppu.peek_register(0x2007, 0), 0xCF);
+        assert_eq!(ppu.read_register(0x2007, None, 0), 0xCF);
+    }
+
+    fn run_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        while (ppu.scanline, ppu.dot) != (scanline, dot) {
+            ppu.tick(None);
+        }
+    }
+
+    // NROM with CHR RAM: tile 1 is solid color 1, and fills the first nametable
+    fn solid_tiles() -> (Ppu, Box<dyn Mapper>) {
+        let mut mapper = crate::mapper::create(&Rom::new(&ines(1, 0, 0, 0)).unwrap()).unwrap();
+        let mut ppu = Ppu::new();
+        for address in 0x0010..0x0018 {
+            ppu.write_vram(address, 0xFF, Some(&mut mapper));
+        }
+        ppu.vram[..0x3C0].fill(0x01);
+        ppu.oam.fill(0xFF);
+        (ppu, mapper)
+    }
+
+    fn run_with(ppu: &mut Ppu, mapper: &mut Box<dyn Mapper>, scanline: u16, dot: u16) {
+        while (ppu.scanline, ppu.dot) != (scanline, dot) {
+            ppu.tick(Some(&mut *mapper));
+        }
+    }
+
+    #[test]
+    fn test_rendering_sets_sprite_flags() {
+        let (mut ppu, mut mapper) = solid_tiles();
+        // sprite 0 on line 31, over the background from x=100
+        ppu.oam[..4].copy_from_slice(&[30, 0x01, 0x00, 100]);
+        ppu.mask = SHOW_ALL;
+        run_with(&mut ppu, &mut mapper, 31, 101);
+        assert!(!ppu.status.contains(PpuStatus::SpriteZeroHit));
+        ppu.tick(Some(&mut mapper));
+        assert_eq!(ppu.read_register(0x2002, None, 0) & 0x40, 0x40);
+        assert!(!ppu.status.contains(PpuStatus::SpriteOverflow));
+
+        // 9 sprites on line 51
+        for index in 1..10 {
+            ppu.oam[index * 4] = 50;
+        }
+        run_with(&mut ppu, &mut mapper, 50, 257);
+        assert!(!ppu.status.contains(PpuStatus::SpriteOverflow));
+        ppu.tick(Some(&mut mapper));
+        assert_eq!(ppu.read_register(0x2002, None, 0) & 0x60, 0x60);
+        assert_eq!(ppu.sprite_rows.len(), SPRITES_PER_SCANLINE);
+
+        // both clear for the next frame, and there's no hit over a transparent background
+        ppu.vram[..0x3C0].fill(0x00);
+        run_with(&mut ppu, &mut mapper, 40, 0);
+        assert_eq!(ppu.status & (PpuStatus::SpriteZeroHit | PpuStatus::SpriteOverflow), PpuStatus::empty());
+    }
+
+    #[test]
+    fn test_rendered_pixels() {
+        let (mut ppu, mut mapper) = solid_tiles();
+        ppu.palette[..2].copy_from_slice(&[0x0F, 0x16]);
+        ppu.palette[0x11] = 0x2A;
+        ppu.oam[..4].copy_from_slice(&[30, 0x01, 0x00, 100]);
+        ppu.mask = PpuMask::ShowBackground | PpuMask::ShowSprites;
+        let colors = ppu.colors.clone();
+        let frame = |ppu: &mut Ppu, mapper: &mut Box<dyn Mapper>| {
+            let frame = ppu.frame;
+            while ppu.frame == frame {
+                ppu.tick(Some(&mut *mapper));
+            }
+            std::mem::replace(&mut ppu.front_buffer, Image::new(SCREEN_WIDTH, SCREEN_HEIGHT))
+        };
+        frame(&mut ppu, &mut mapper);
+        let image = frame(&mut ppu, &mut mapper);
+        assert_eq!(image.get_pixel(50, 100), colors.color(0x16));
+        assert_eq!(image.get_pixel(103, 31), colors.color(0x2A));
+        assert_eq!(image.get_pixel(103, 30), colors.color(0x16));
+        // the clip bits show the backdrop in the leftmost column
+        assert_eq!(image.get_pixel(7, 100), colors.color(0x0F));
+        assert_eq!(image.get_pixel(8, 100), colors.color(0x16));
+
+        // the debug switches hide layers the game has on
+        ppu.layers.toggle_background();
+        let image = frame(&mut ppu, &mut mapper);
+        assert_eq!(image.get_pixel(50, 100), colors.color(0x0F));
+        assert_eq!(image.get_pixel(103, 31), colors.color(0x2A));
+
+        // and with rendering off it's all backdrop, with the mask's emphasis
+        ppu.mask = PpuMask::EmphasizeRed;
+        let image = frame(&mut ppu, &mut mapper);
+        assert_eq!(image.get_pixel(103, 31), colors.emphasized_color(0x0F, 1));
+    }
+
+    #[test]
+    fn test_scroll_split() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2001, PpuMask::ShowBackground.bits(), None, 0);
+        ppu.write_register(0x2000, PpuCtrl::NametableX.bits(), None, 0);
+        ppu.write_register(0x2005, 13, None, 0);
+        ppu.write_register(0x2005, 21, None, 0);
+        // the pre-render line loads the scroll for the whole frame
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, 100, 200);
+        assert_eq!(ppu.scroll_lines[0], Some(ScrollPosition { x: 256 + 13, y: 21 }));
+        assert_eq!(ppu.mask_lines[0], PpuMask::ShowBackground);
+        assert_eq!(ppu.scroll_lines[100], Some(ScrollPosition { x: 256 + 13, y: 121 }));
+
+        // $2005 mid-frame only changes X, from the next line on
+        ppu.write_register(0x2005, 40, None, 0);
+        ppu.write_register(0x2005, 200, None, 0);
+        run_to(&mut ppu, 101, 1);
+        assert_eq!(ppu.scroll_lines[101], Some(ScrollPosition { x: 256 + 40, y: 122 }));
+
+        // $2006 in hblank sets Y too; fine Y comes from $2005, whose writes share the toggle
+        run_to(&mut ppu, 101, 280);
+        ppu.write_register(0x2006, 0x08, None, 0);
+        ppu.write_register(0x2005, 0, None, 0);
+        ppu.write_register(0x2005, 0, None, 0);
+        ppu.write_register(0x2006, 0x00, None, 0);
+        run_to(&mut ppu, 103, 0);
+        assert_eq!(ppu.scroll_lines[102], Some(ScrollPosition { x: 0, y: 240 }));
+        run_to(&mut ppu, 104, 0);
+        assert_eq!(ppu.scroll_lines[103], Some(ScrollPosition { x: 0, y: 241 }));
+
+        // coarse Y wraps from row 29 into the nametable below
+        ppu.write_register(0x2000, PpuCtrl::NametableX.bits(), None, 0);
+        ppu.write_register(0x2005, 0, None, 0);
+        ppu.write_register(0x2005, 236, None, 0);
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 0);
+        run_to(&mut ppu, 6, 0);
+        assert_eq!(ppu.scroll_lines[3], Some(ScrollPosition { x: 256, y: 239 }));
+        assert_eq!(ppu.scroll_lines[4], Some(ScrollPosition { x: 256, y: 240 }));
+
+        // nothing moves while rendering is off
+        ppu.write_register(0x2001, 0, None, 0);
+        let v = ppu.v;
+        run_to(&mut ppu, 200, 0);
+        assert_eq!((ppu.v, ppu.scroll_lines[150]), (v, None));
+    }
+
+    #[test]
+    fn test_vblank_timing() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, PpuCtrl::GenerateNmi.bits(), None, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert!(!ppu.status.contains(PpuStatus::VerticalBlank));
+        ppu.tick(None);
+        assert!(ppu.status.contains(PpuStatus::VerticalBlank));
+        // the CPU doesn't see the NMI until a cycle later
+        assert!(!ppu.nmi_output());
+        run_to(&mut ppu, VBLANK_SCANLINE, NMI_DELAY_DOTS + 2);
+        assert!(ppu.nmi_output());
+
+        // turning NMI off and on again during vblank raises the line again
+        ppu.write_register(0x2000, 0, None, 0);
+        assert!(!ppu.nmi_output());
+        ppu.write_register(0x2000, PpuCtrl::GenerateNmi.bits(), None, 0);
+        assert!(ppu.nmi_output());
+
+        ppu.status.insert(PpuStatus::SpriteZeroHit | PpuStatus::SpriteOverflow);
+        run_to(&mut ppu, PRE_RENDER_SCANLINE, 2);
+        assert_eq!(ppu.status, PpuStatus::empty());
+        assert!(!ppu.nmi_output());
+    }
+
+    #[test]
+    fn test_vblank_read_race() {
+        let mut ppu = Ppu::new();
+        ppu.write_register(0x2000, PpuCtrl::GenerateNmi.bits(), None, 0);
+
+        // a dot early: reads clear, and the flag stays down all frame
+        run_to(&mut ppu, VBLANK_SCANLINE, 1);
+        assert_eq!(ppu.read_register(0x2002, None, 0) & 0x80, 0);
+        run_to(&mut ppu, VBLANK_SCANLINE + 1, 0);
+        assert!(!ppu.status.contains(PpuStatus::VerticalBlank));
+
+        // right after: reads set, but the NMI is cancelled
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert_eq!(ppu.read_register(0x2002, None, 0) & 0x80, 0x80);
+        run_to(&mut ppu, VBLANK_SCANLINE + 1, 0);
+        assert!(!ppu.nmi_output());
+
+        // late enough and the NMI has been seen
+        run_to(&mut ppu, VBLANK_SCANLINE, NMI_DELAY_DOTS + 2);
+        assert!(ppu.nmi_output());
+        assert_eq!(ppu.read_register(0x2002, None, 0) & 0x80, 0x80);
+        assert!(!ppu.nmi_output());
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        let mut ppu = Ppu::new();
+        let frame_length = |ppu: &mut Ppu| {
+            let frame = ppu.frame;
+            let mut dots = 0;
+            while ppu.frame == frame {
+                ppu.tick(None);
+                dots += 1;
+            }
+            dots
+        };
+        assert_eq!(frame_length(&mut ppu), DOTS_PER_FRAME);
+        assert_eq!(frame_length(&mut ppu), DOTS_PER_FRAME);
+        ppu.write_register(0x2001, PpuMask::ShowSprites.bits(), None, 0);
+        assert_eq!(frame_length(&mut ppu), DOTS_PER_FRAME);
+        assert_eq!(frame_length(&mut ppu), DOTS_PER_FRAME - 1);
+        assert_eq!(frame_length(&mut ppu), DOTS_PER_FRAME);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        assert_eq!(PpuBreakpoint::parse("Scanline 100"), Some(PpuBreakpoint::Dot { scanline: 100, dot: 0 }));
+        assert_eq!(PpuBreakpoint::parse("scanline 30 dot 256"), Some(PpuBreakpoint::Dot { scanline: 30, dot: 256 }));
+        assert_eq!(PpuBreakpoint::parse("scanline 262"), None);
+        assert_eq!(PpuBreakpoint::parse("sprite0"), Some(PpuBreakpoint::SpriteZeroHit));
+        assert_eq!(PpuBreakpoint::parse("hblank"), None);
+
+        let mut ppu = Ppu::new();
+        ppu.breakpoints = vec![PpuBreakpoint::Vblank, PpuBreakpoint::Dot { scanline: 30, dot: 256 }];
+        run_to(&mut ppu, 30, 256);
+        assert_eq!(ppu.breakpoint_hit, None);
+        ppu.tick(None);
+        assert_eq!(ppu.breakpoint_hit.take(), Some(PpuBreakpoint::Dot { scanline: 30, dot: 256 }));
+        run_to(&mut ppu, VBLANK_SCANLINE, 2);
+        assert_eq!(ppu.breakpoint_hit.take(), Some(PpuBreakpoint::Vblank));
+
+        // a real hit, sprite 0 over the background at (100, 31)
+        let (mut ppu, mut mapper) = solid_tiles();
+        ppu.oam[..4].copy_from_slice(&[30, 0x01, 0x00, 100]);
+        ppu.mask = SHOW_ALL;
+        ppu.breakpoints = vec![PpuBreakpoint::SpriteZeroHit];
+        run_with(&mut ppu, &mut mapper, 31, 101);
+        assert_eq!(ppu.breakpoint_hit, None);
+        ppu.tick(Some(&mut mapper));
+        assert_eq!(ppu.breakpoint_hit.take(), Some(PpuBreakpoint::SpriteZeroHit));
+        // it only counts when the flag goes up, not while it stays set
+        run_with(&mut ppu, &mut mapper, 32, 102);
+        assert!(ppu.status.contains(PpuStatus::SpriteZeroHit));
+        assert_eq!(ppu.breakpoint_hit, None);
+    }
+
+    #[test]
+    fn test_read_buffer_savestate() {
+        let mut ppu = Ppu::new();
+        write_data(&mut ppu, 0x2400, 0x77);
+        read_data(&mut ppu, 0x2400);
+        let mut state = Vec::new();
+        ppu.save_state(&mut state);
+
+        let mut loaded = Ppu::new();
+        loaded.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(loaded.read_buffer, 0x77);
+        assert_eq!(loaded.read_register(0x2007, None, 0), 0x77);
+    }
+
+    fn sprite_row(x: u8, attributes: SpriteAttributes, sprite_zero: bool) -> SpriteRow {
+        SpriteRow { x, pattern_low: 0xFF, pattern_high: 0x00, attributes, sprite_zero }
+    }
+
+    #[test]
+    fn test_tall_sprite_patterns() {
+        let mut ppu = Ppu::new();
+        ppu.oam[..4].copy_from_slice(&[10, 0x25, 0x00, 40]);
+        ppu.oam[4..8].copy_from_slice(&[100, 0x00, 0x00, 0]);
+        let sprite = Sprite::from_oam(&ppu.oam, 0);
+        // the PPUCTRL table bit is ignored, odd tiles come from $1000
+        let ctrl = PpuCtrl::TallSprites | PpuCtrl::SpritePatternTable;
+        assert_eq!(sprite.pattern_address(ctrl, 0), 0x1240);
+        assert_eq!(sprite.pattern_address(ctrl, 9), 0x1251);
+        assert_eq!(sprite.pattern_address(PpuCtrl::SpritePatternTable, 3), 0x1253);
+        let flipped = Sprite { attributes: SpriteAttributes::FlipVertical, ..sprite };
+        assert_eq!(flipped.pattern_address(ctrl, 0), 0x1257);
+        assert_eq!(flipped.pattern_address(ctrl, 15), 0x1240);
+
+        assert_eq!(ppu.evaluate_sprites(17).0.len(), 1);
+        ppu.ctrl = ctrl;
+        assert_eq!(ppu.evaluate_sprites(25).0, [sprite]);
+        assert!(ppu.evaluate_sprites(26).0.is_empty());
+    }
+
+    #[test]
+    fn test_sprite_overflow() {
+        let mut ppu = Ppu::new();
+        ppu.oam.fill(0xFF);
+        for index in 0..9 {
+            ppu.oam[index * 4] = 20;
+        }
+        let (sprites, overflow) = ppu.evaluate_sprites(20);
+        assert_eq!((sprites.len(), overflow), (SPRITES_PER_SCANLINE, true));
+        ppu.oam[32] = 0xFF;
+        assert!(!ppu.evaluate_sprites(20).1);
+    }
+
+    #[test]
+    fn test_sprite_priority_quirk() {
+        // a background priority sprite in front of a foreground one
+        let sprites = [
+            sprite_row(16, SpriteAttributes::BehindBackground, true),
+            sprite_row(16, SpriteAttributes::Palette, false),
+        ];
+        let pixel = sprite_pixel(&sprites, 18);
+        assert_eq!(pixel, sprite(0x11, true));
+        // the front sprite still hides the background, and the sprite behind it with it
+        assert_eq!(compose_pixel(SHOW_ALL, Layers::default(), 18, 0x05, pixel), 0x05);
+        assert_eq!(compose_pixel(SHOW_ALL, Layers::default(), 18, 0x04, pixel), 0x11);
+        // where the front sprite is transparent the next one shows
+        let sprites = [SpriteRow { pattern_low: 0x0F, ..sprites[0] }, sprites[1]];
+        assert_eq!(sprite_pixel(&sprites, 18), sprite(0x1D, false));
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let sprites = [sprite_row(0, SpriteAttributes::BehindBackground, true), sprite_row(248, SpriteAttributes::empty(), true)];
+        assert!(sprite_zero_hit(SHOW_ALL, 3, 0x01, &sprites));
+        assert!(!sprite_zero_hit(SHOW_ALL, 3, 0x00, &sprites));
+        assert!(!sprite_zero_hit(SHOW_ALL, 10, 0x01, &sprites));
+        // either clip bit hides the leftmost 8 pixels
+        assert!(!sprite_zero_hit(SHOW_ALL.difference(PpuMask::ShowSpritesLeft), 3, 0x01, &sprites));
+        assert!(!sprite_zero_hit(SHOW_ALL.difference(PpuMask::ShowBackgroundLeft), 7, 0x01, &sprites));
+        assert!(sprite_zero_hit(SHOW_ALL, 254, 0x01, &sprites));
+        assert!(!sprite_zero_hit(SHOW_ALL, 255, 0x01, &sprites));
+        assert!(!sprite_zero_hit(PpuMask::ShowSprites, 254, 0x01, &sprites));
+    }
+
+    #[test]
+    fn test_compose_priority() {
+        let layers = Layers::default();
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x05, sprite(0x11, false)), 0x11);
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x05, sprite(0x11, true)), 0x05);
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x04, sprite(0x11, true)), 0x11);
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x04, sprite(0x10, false)), 0);
+    }
+
+    #[test]
+    fn test_compose_enable_bits_and_left_clip() {
+        let layers = Layers::default();
+        let mask = PpuMask::ShowBackground | PpuMask::ShowSprites;
+        assert_eq!(compose_pixel(mask, layers, 7, 0x05, sprite(0x11, false)), 0);
+        assert_eq!(compose_pixel(mask, layers, 8, 0x05, sprite(0x11, true)), 0x05);
+        assert_eq!(compose_pixel(mask | PpuMask::ShowSpritesLeft, layers, 7, 0x05, sprite(0x11, true)), 0x11);
+        assert_eq!(compose_pixel(PpuMask::ShowSprites, layers, 20, 0x05, sprite(0x11, true)), 0x11);
+        assert!(!PpuMask::Greyscale.is_rendering());
+    }
+
+    #[test]
+    fn test_debug_layers() {
+        let mut layers = Layers::default();
+        layers.toggle_sprites();
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x05, sprite(0x11, false)), 0x05);
+        layers.toggle_background();
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 20, 0x05, sprite(0x11, false)), 0);
+    }
+
+    #[test]
+    fn test_crop_left() {
+        let mut layers = Layers::default();
+        assert!(layers.set_crop_left("sprites"));
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 7, 0x05, sprite(0x11, false)), 0x05);
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 8, 0x05, sprite(0x11, false)), 0x11);
+        assert_eq!(layers.left_clip(SHOW_ALL), (false, true));
+        assert!(layers.set_crop_left("Both"));
+        assert_eq!(compose_pixel(SHOW_ALL, layers, 7, 0x05, sprite(0x11, false)), 0);
+        assert!(!layers.set_crop_left("left"));
+
+        // the game's own clip bits, and nothing to clip on a layer that's off
+        let layers = Layers::default();
+        assert_eq!(layers.left_clip(SHOW_ALL), (false, false));
+        assert_eq!(layers.left_clip(PpuMask::ShowBackground | PpuMask::ShowSprites), (true, true));
+        assert_eq!(layers.left_clip(PpuMask::ShowSprites), (false, true));
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+        let palette = Palette::classic();
+        assert_eq!(output_color(PpuMask::Greyscale, 0x16, &palette), SYSTEM_PALETTE[0x10]);
+        assert_eq!(output_color(PpuMask::empty(), 0x30, &palette), (0xFF, 0xFF, 0xFF));
+        assert_eq!(output_color(PpuMask::EmphasizeRed, 0x30, &palette), (0xFF, 0xD0, 0xD0));
+        assert_eq!(output_color(PpuMask::EmphasizeRed | PpuMask::EmphasizeGreen, 0x30, &palette), (0xD0, 0xD0, 0xAA));
+    }
+}
@@ -0,0 +1,320 @@
+use std::fmt;
+
+use crate::hash::{from_hex, to_hex};
+use crate::nes::Nes;
+use crate::palette::Palette;
+use crate::ppu::{
+    compose_pixel, output_color, sprite_pixel, Layers, Ppu, PpuCtrl, PpuMask, PpuStatus, ScrollPosition, SpriteAttributes,
+    SpriteRow, FOUR_SCREEN_VRAM_SIZE, OAM_SIZE, PALETTE_SIZE, VISIBLE_SCANLINES,
+};
+use crate::remote::{escape, parse_object, JsonValue};
+use crate::viewer::Image;
+
+// Everything the PPU drew a frame from, for looking at a rendering bug away from
+// the emulator. Written at a frame boundary as one flat JSON object like the state
+// interchange dump: registers as numbers, memory as upper case hex strings.
+//
+//   {"format": "madnes-ppu-dump", "version": 1, "frame": 60, "scanline": 0, "dot": 0,
+//    "ctrl": 144, "mask": 30, "status": 0, "oam_address": 0, "v": 0, "t": 0,
+//    "fine_x": 0, "write_toggle": false, "read_buffer": 0,
+//    "vram": "...", "palette": "...", "oam": "...", "chr": "...", "nametables": "...",
+//    "mask_lines": "...", "scroll_lines": "..."}
+//
+// chr is $0000-$1FFF with the banks switched in at the end of the frame and
+// nametables is $2000-$2FFF after mirroring, so a dump renders without the
+// cartridge. mask_lines is PPUMASK for each visible line, scroll_lines each line's
+// x and y as two 16 bit big endian numbers, FFFFFFFF while rendering was off.
+pub const FORMAT: &str = "madnes-ppu-dump";
+pub const VERSION: i64 = 1;
+
+const PATTERN_TABLES_SIZE: usize = 0x2000;
+const NAMETABLES_SIZE: usize = 0x1000;
+const NAMETABLE_SIZE: usize = 0x400;
+const ATTRIBUTE_TABLE_OFFSET: usize = 0x3C0;
+const SCREEN_WIDTH: usize = 256;
+const NO_SCROLL: [u8; 4] = [0xFF; 4];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PpuDumpError {
+    UnknownFormat,
+    Invalid(String),
+}
+
+impl fmt::Display for PpuDumpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PpuDumpError::UnknownFormat => write!(f, "Not a madNES PPU dump"),
+            PpuDumpError::Invalid(message) => write!(f, "PPU dump is corrupt: {}", message),
+        }
+    }
+}
+
+fn invalid(message: &str) -> PpuDumpError {
+    PpuDumpError::Invalid(message.to_string())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpuDump {
+    pub frame: u64,
+    pub scanline: u16,
+    pub dot: u16,
+    pub ctrl: PpuCtrl,
+    pub mask: PpuMask,
+    pub status: PpuStatus,
+    pub oam_address: u8,
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_toggle: bool,
+    pub read_buffer: u8,
+    pub vram: Vec<u8>,
+    pub palette: [u8; PALETTE_SIZE],
+    pub oam: [u8; OAM_SIZE],
+    pub chr: Vec<u8>,
+    pub nametables: Vec<u8>,
+    pub mask_lines: [PpuMask; VISIBLE_SCANLINES],
+    pub scroll_lines: [Option<ScrollPosition>; VISIBLE_SCANLINES],
+}
+
+impl PpuDump {
+    // Call between frames, after Nes::step_frame, for the lines to all be from the
+    // frame just drawn
+    pub fn capture(nes: &mut Nes) -> PpuDump {
+        let bus = &mut nes.cpu_mut().bus;
+        let chr = Ppu::pattern_tables(bus.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper));
+        let nametables = bus.ppu.nametables(bus.cartridge.as_mut().map(|cartridge| &mut cartridge.mapper));
+        let ppu = &bus.ppu;
+        PpuDump {
+            frame: ppu.frame,
+            scanline: ppu.scanline,
+            dot: ppu.dot,
+            ctrl: ppu.ctrl,
+            mask: ppu.mask,
+            status: ppu.status,
+            oam_address: ppu.oam_address,
+            v: ppu.v,
+            t: ppu.t,
+            fine_x: ppu.fine_x,
+            write_toggle: ppu.write_toggle,
+            read_buffer: ppu.read_buffer,
+            vram: ppu.vram.to_vec(),
+            palette: ppu.palette,
+            oam: ppu.oam,
+            chr,
+            nametables,
+            mask_lines: ppu.mask_lines,
+            scroll_lines: ppu.scroll_lines,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        let masks: Vec<u8> = self.mask_lines.iter().map(|mask| mask.bits()).collect();
+        let scroll_bytes = |position: ScrollPosition| {
+            let (x, y) = (position.x.to_be_bytes(), position.y.to_be_bytes());
+            [x[0], x[1], y[0], y[1]]
+        };
+        let scrolls: Vec<u8> = self.scroll_lines.iter().flat_map(|line| line.map_or(NO_SCROLL, scroll_bytes)).collect();
+        let fields = [
+            ("format", format!("\"{}\"", escape(FORMAT))),
+            ("version", VERSION.to_string()),
+            ("frame", self.frame.to_string()),
+            ("scanline", self.scanline.to_string()),
+            ("dot", self.dot.to_string()),
+            ("ctrl", self.ctrl.bits().to_string()),
+            ("mask", self.mask.bits().to_string()),
+            ("status", self.status.bits().to_string()),
+            ("oam_address", self.oam_address.to_string()),
+            ("v", self.v.to_string()),
+            ("t", self.t.to_string()),
+            ("fine_x", self.fine_x.to_string()),
+            ("write_toggle", self.write_toggle.to_string()),
+            ("read_buffer", self.read_buffer.to_string()),
+            ("vram", format!("\"{}\"", to_hex(&self.vram))),
+            ("palette", format!("\"{}\"", to_hex(&self.palette))),
+            ("oam", format!("\"{}\"", to_hex(&self.oam))),
+            ("chr", format!("\"{}\"", to_hex(&self.chr))),
+            ("nametables", format!("\"{}\"", to_hex(&self.nametables))),
+            ("mask_lines", format!("\"{}\"", to_hex(&masks))),
+            ("scroll_lines", format!("\"{}\"", to_hex(&scrolls))),
+        ];
+        let fields: Vec<String> = fields.iter().map(|(key, value)| format!("\"{}\": {}", key, value)).collect();
+        format!("{{{}}}\n", fields.join(", "))
+    }
+
+    pub fn parse(text: &str) -> Result<PpuDump, PpuDumpError> {
+        let fields = parse_object(text).ok_or(PpuDumpError::UnknownFormat)?;
+        let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+        if field("format") != Some(&JsonValue::String(FORMAT.to_string())) {
+            return Err(PpuDumpError::UnknownFormat);
+        }
+        if field("version") != Some(&JsonValue::Number(VERSION)) {
+            return Err(invalid("unknown version"));
+        }
+        let number = |name: &str, max: u64| match field(name) {
+            Some(&JsonValue::Number(value)) if (0..=max as i64).contains(&value) => Ok(value as u64),
+            _ => Err(invalid(&format!("{} isn't a number up to {}", name, max))),
+        };
+        let memory = |name: &str, size: usize| match field(name) {
+            Some(JsonValue::String(hex)) => {
+                from_hex(hex).filter(|data| data.len() == size).ok_or_else(|| invalid(&format!("{} isn't {} bytes of hex", name, size)))
+            }
+            _ => Err(invalid(&format!("{} isn't a string", name))),
+        };
+        let write_toggle = match field("write_toggle") {
+            Some(&JsonValue::Bool(toggle)) => toggle,
+            _ => return Err(invalid("write_toggle isn't true or false")),
+        };
+
+        let masks = memory("mask_lines", VISIBLE_SCANLINES)?;
+        let scrolls = memory("scroll_lines", VISIBLE_SCANLINES * 4)?;
+        let mut mask_lines = [PpuMask::empty(); VISIBLE_SCANLINES];
+        let mut scroll_lines = [None; VISIBLE_SCANLINES];
+        for (line, bits) in mask_lines.iter_mut().zip(masks) {
+            *line = PpuMask::from_bits_retain(bits);
+        }
+        for (line, bytes) in scroll_lines.iter_mut().zip(scrolls.chunks_exact(4)) {
+            *line = (bytes != NO_SCROLL).then(|| ScrollPosition {
+                x: u16::from_be_bytes([bytes[0], bytes[1]]),
+                y: u16::from_be_bytes([bytes[2], bytes[3]]),
+            });
+        }
+        Ok(PpuDump {
+            frame: number("frame", u64::MAX >> 1)?,
+            scanline: number("scanline", u16::MAX as u64)? as u16,
+            dot: number("dot", u16::MAX as u64)? as u16,
+            ctrl: PpuCtrl::from_bits_retain(number("ctrl", 0xFF)? as u8),
+            mask: PpuMask::from_bits_retain(number("mask", 0xFF)? as u8),
+            status: PpuStatus::from_bits_truncate(number("status", 0xFF)? as u8),
+            oam_address: number("oam_address", 0xFF)? as u8,
+            v: number("v", 0x7FFF)? as u16,
+            t: number("t", 0x7FFF)? as u16,
+            fine_x: number("fine_x", 7)? as u8,
+            write_toggle,
+            read_buffer: number("read_buffer", 0xFF)? as u8,
+            vram: memory("vram", FOUR_SCREEN_VRAM_SIZE)?,
+            palette: memory("palette", PALETTE_SIZE)?.try_into().unwrap(),
+            oam: memory("oam", OAM_SIZE)?.try_into().unwrap(),
+            chr: memory("chr", PATTERN_TABLES_SIZE)?,
+            nametables: memory("nametables", NAMETABLES_SIZE)?,
+            mask_lines,
+            scroll_lines,
+        })
+    }
+
+    // The background pixel (0x00-0x0F, a palette RAM address) at column `x` of a
+    // line drawn from `position`
+    fn background_pixel(&self, position: ScrollPosition, x: usize) -> u8 {
+        let x = (position.x as usize + x) % (SCREEN_WIDTH * 2);
+        let y = position.y as usize % (VISIBLE_SCANLINES * 2);
+        let nametable = x / SCREEN_WIDTH + (y / VISIBLE_SCANLINES) * 2;
+        let (x, y) = (x % SCREEN_WIDTH, y % VISIBLE_SCANLINES);
+        let page = &self.nametables[nametable * NAMETABLE_SIZE..(nametable + 1) * NAMETABLE_SIZE];
+        let tile = page[(y / 8) * 32 + x / 8] as usize;
+        let table = if self.ctrl.contains(PpuCtrl::BackgroundPatternTable) { 0x1000 } else { 0 };
+        let address = table + tile * 16 + y % 8;
+        let bit = 7 - x % 8;
+        let color = ((self.chr[address + 8] >> bit) & 0x01) << 1 | (self.chr[address] >> bit) & 0x01;
+        if color == 0 {
+            return 0;
+        }
+        let attribute = page[ATTRIBUTE_TABLE_OFFSET + (y / 32) * 8 + x / 32];
+        let shift = ((y % 32) / 16) * 4 + ((x % 32) / 16) * 2;
+        ((attribute >> shift) & 0x03) << 2 | color
+    }
+
+    // Sprites are fetched on the line before the one they're drawn on
+    fn sprite_rows(&self, ppu: &Ppu, line: usize) -> Vec<SpriteRow> {
+        let Some(scanline) = line.checked_sub(1) else {
+            return Vec::new();
+        };
+        let (sprites, _) = ppu.evaluate_sprites(scanline as u16);
+        sprites
+            .iter()
+            .map(|sprite| {
+                let address = sprite.pattern_address(self.ctrl, (scanline as u16).wrapping_sub(sprite.y as u16) as u8) as usize;
+                let (mut pattern_low, mut pattern_high) = (self.chr[address], self.chr[address + 8]);
+                if sprite.attributes.contains(SpriteAttributes::FlipHorizontal) {
+                    pattern_low = pattern_low.reverse_bits();
+                    pattern_high = pattern_high.reverse_bits();
+                }
+                SpriteRow { x: sprite.x, pattern_low, pattern_high, attributes: sprite.attributes, sprite_zero: sprite.index == 0 }
+            })
+            .collect()
+    }
+
+    // Draws the frame again from the dump, each line with the scroll and PPUMASK it
+    // had. PPUCTRL, CHR banks and OAM are as at the end of the frame, so mid-frame
+    // pattern table or bank switches show the last ones.
+    pub fn render(&self, palette: &Palette, layers: Layers) -> Image {
+        let mut ppu = Ppu::new();
+        (ppu.ctrl, ppu.oam) = (self.ctrl, self.oam);
+        let mut image = Image::new(SCREEN_WIDTH, VISIBLE_SCANLINES);
+        for y in 0..VISIBLE_SCANLINES {
+            let mask = self.mask_lines[y];
+            let sprites = self.sprite_rows(&ppu, y);
+            for x in 0..SCREEN_WIDTH {
+                let background = self.scroll_lines[y].map_or(0, |position| self.background_pixel(position, x));
+                let address = compose_pixel(mask, layers, x, background, sprite_pixel(&sprites, x));
+                image.set_pixel(x, y, output_color(mask, self.palette[address as usize], palette));
+            }
+        }
+        image
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let mut nes = Nes::new();
+        let ppu = &mut nes.cpu_mut().bus.ppu;
+        (ppu.frame, ppu.v, ppu.fine_x, ppu.write_toggle) = (60, 0x2345, 3, true);
+        ppu.vram[0x7FF] = 0x12;
+        ppu.palette[0] = 0x0F;
+        ppu.oam[4] = 0x40;
+        ppu.mask_lines[10] = PpuMask::ShowBackground;
+        ppu.scroll_lines[10] = Some(ScrollPosition { x: 300, y: 250 });
+        let dump = PpuDump::capture(&mut nes);
+        assert_eq!((dump.chr.len(), dump.nametables.len(), dump.nametables[0x7FF]), (0x2000, 0x1000, 0x12));
+        assert_eq!(PpuDump::parse(&dump.to_json()), Ok(dump.clone()));
+
+        let corrupt = dump.to_json().replace("\"fine_x\": 3", "\"fine_x\": 9");
+        assert!(matches!(PpuDump::parse(&corrupt), Err(PpuDumpError::Invalid(_))));
+        let other = crate::interchange::export(&nes);
+        assert_eq!(PpuDump::parse(&other), Err(PpuDumpError::UnknownFormat));
+    }
+
+    #[test]
+    fn test_render() {
+        let mut nes = Nes::new();
+        let mut dump = PpuDump::capture(&mut nes);
+        // tile 1 is solid color 1, drawn at the left of nametable 1 with palette 1
+        dump.chr[0x10..0x18].fill(0xFF);
+        dump.nametables[NAMETABLE_SIZE] = 1;
+        dump.nametables[NAMETABLE_SIZE + ATTRIBUTE_TABLE_OFFSET] = 0x01;
+        dump.palette[..8].copy_from_slice(&[0x0F, 0x01, 0x02, 0x03, 0x0F, 0x16, 0x17, 0x18]);
+        dump.palette[0x11] = 0x2A;
+        // sprite 0 uses tile 1 at (20, 3), so it's drawn from line 4
+        dump.oam[..4].copy_from_slice(&[3, 1, 0x00, 20]);
+        let mask = PpuMask::ShowBackground | PpuMask::ShowSprites | PpuMask::ShowBackgroundLeft | PpuMask::ShowSpritesLeft;
+        dump.mask_lines = [mask; VISIBLE_SCANLINES];
+        dump.scroll_lines = [Some(ScrollPosition { x: 256, y: 0 }); VISIBLE_SCANLINES];
+        dump.scroll_lines[100] = None;
+        dump.mask_lines[100] = PpuMask::empty();
+
+        let palette = Palette::ntsc();
+        let image = dump.render(&palette, Layers::default());
+        let color = |value: u8| output_color(mask, value, &palette);
+        assert_eq!(image.get_pixel(0, 0), color(0x16));
+        assert_eq!(image.get_pixel(8, 0), color(0x0F));
+        assert_eq!(image.get_pixel(20, 3), color(0x0F));
+        assert_eq!(image.get_pixel(20, 4), color(0x2A));
+        assert_eq!(image.get_pixel(20, 12), color(0x0F));
+        assert_eq!(image.get_pixel(0, 100), color(0x0F));
+        // the background layer hidden
+        let layers = Layers { background: false, ..Layers::default() };
+        assert_eq!(dump.render(&palette, layers).get_pixel(0, 0), color(0x0F));
+    }
+}
@@ -0,0 +1,185 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::viewer::Image;
+
+// NTSC frame rate as a fraction, matching timing::NTSC_FRAME_DURATION
+const FRAME_RATE: (u32, u32) = (39_375_000, 655_171);
+
+// Writes RGB frames as an uncompressed YUV4MPEG2 stream (4:4:4, full range),
+// which ffmpeg and most players read directly
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    frames: usize,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut writer: W, width: usize, height: usize) -> io::Result<Self> {
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A8:7 C444 XCOLORRANGE=FULL",
+            width, height, FRAME_RATE.0, FRAME_RATE.1
+        )?;
+        Ok(Y4mWriter { writer, width, height, frames: 0 })
+    }
+
+    pub fn write_frame(&mut self, frame: &Image) -> io::Result<()> {
+        if (frame.width, frame.height) != (self.width, self.height) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame size changed during recording"));
+        }
+        let pixels = self.width * self.height;
+        let mut planes = vec![0; pixels * 3];
+        for (i, rgb) in frame.pixels.chunks_exact(3).enumerate() {
+            let (r, g, b) = (rgb[0] as f32, rgb[1] as f32, rgb[2] as f32);
+            planes[i] = (0.299 * r + 0.587 * g + 0.114 * b).round() as u8;
+            planes[pixels + i] = (128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b).round().clamp(0.0, 255.0) as u8;
+            planes[2 * pixels + i] = (128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b).round().clamp(0.0, 255.0) as u8;
+        }
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&planes)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> usize {
+        self.frames
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Writes mono 16 bit PCM samples to a WAV file.
+// The header sizes are filled in by finish.
+pub struct WavWriter<W: Write + Seek> {
+    writer: W,
+    samples: u32,
+}
+
+const WAV_HEADER_SIZE: u32 = 44;
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut writer: W, sample_rate: u32) -> io::Result<Self> {
+        let channels: u16 = 1;
+        let bits_per_sample: u16 = 16;
+        let block_align = channels * bits_per_sample / 8;
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVEfmt ")?;
+        writer.write_all(&16u32.to_le_bytes())?;
+        // PCM
+        writer.write_all(&1u16.to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&bits_per_sample.to_le_bytes())?;
+        writer.write_all(b"data")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        Ok(WavWriter { writer, samples: 0 })
+    }
+
+    // Samples are in the -1.0..=1.0 range and clipped beyond it
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            self.writer.write_all(&value.to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    pub fn samples(&self) -> u32 {
+        self.samples
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let data_size = self.samples * 2;
+        self.writer.seek(SeekFrom::Start(4))?;
+        self.writer.write_all(&(WAV_HEADER_SIZE - 8 + data_size).to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(40))?;
+        self.writer.write_all(&data_size.to_le_bytes())?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+// Records every emulated frame and its audio to <path>.y4m and <path>.wav.
+// Both streams are written in emulated time, so recordings are deterministic
+// regardless of how fast the host runs.
+pub struct Recorder {
+    video: Y4mWriter<BufWriter<File>>,
+    audio: WavWriter<BufWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: impl AsRef<Path>, width: usize, height: usize, sample_rate: u32) -> io::Result<Self> {
+        let path = path.as_ref();
+        let video = BufWriter::new(File::create(path.with_extension("y4m"))?);
+        let audio = BufWriter::new(File::create(path.with_extension("wav"))?);
+        Ok(Recorder {
+            video: Y4mWriter::new(video, width, height)?,
+            audio: WavWriter::new(audio, sample_rate)?,
+        })
+    }
+
+    // Called once per frame with the completed picture and the samples generated during it
+    pub fn record_frame(&mut self, frame: &Image, samples: &[f32]) -> io::Result<()> {
+        self.video.write_frame(frame)?;
+        self.audio.write_samples(samples)
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.video.finish()?;
+        self.audio.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_y4m_frame() {
+        let mut frame = Image::new(2, 1);
+        frame.set_pixel(0, 0, (0xFF, 0xFF, 0xFF));
+        let mut writer = Y4mWriter::new(Vec::new(), 2, 1).unwrap();
+        writer.write_frame(&frame).unwrap();
+        assert_eq!(writer.frames(), 1);
+        let data = writer.finish().unwrap();
+
+        let header = "YUV4MPEG2 W2 H1 F39375000:655171 Ip A8:7 C444 XCOLORRANGE=FULL\nFRAME\n";
+        assert!(data.starts_with(header.as_bytes()));
+        // Y plane white then black, neutral chroma
+        assert_eq!(&data[header.len()..], [255, 0, 128, 128, 128, 128]);
+    }
+
+    #[test]
+    fn test_y4m_rejects_size_change() {
+        let mut writer = Y4mWriter::new(Vec::new(), 2, 1).unwrap();
+        assert!(writer.write_frame(&Image::new(1, 1)).is_err());
+    }
+
+    #[test]
+    fn test_wav_header_sizes() {
+        let mut writer = WavWriter::new(Cursor::new(Vec::new()), 44100).unwrap();
+        writer.write_samples(&[0.0, 1.0, -2.0]).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+
+        assert_eq!(data.len(), 44 + 6);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(data[4..8].try_into().unwrap()), 36 + 6);
+        assert_eq!(u32::from_le_bytes(data[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(data[40..44].try_into().unwrap()), 6);
+        assert_eq!(&data[44..], [0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
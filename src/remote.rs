@@ -0,0 +1,606 @@
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::assembler;
+use crate::cpu::{Bus, Cpu};
+use crate::debugger::{self, BreakReason, Condition, Debugger, MemorySpace, Register};
+use crate::disassembler::Disassembler;
+use crate::hash;
+use crate::ppu::PpuBreakpoint;
+use crate::symbols::SymbolTable;
+use crate::trace::TraceFilter;
+
+// The debugger over TCP, for IDEs and scripts. Each request is a JSON object on
+// one line, and gets one line back:
+//   {"command": "set_breakpoint", "address": "$C000", "condition": "A == 3"}
+//   {"command": "continue"}
+//   {"command": "skip"} after the CPU faults on an unknown opcode
+//   {"command": "read_memory", "address": "player_x", "length": 4}
+//   {"command": "disassemble", "count": 8}, from PC unless an address is given
+//   {"command": "assemble", "address": "$8000", "source": "LDA #$C0\nTAX"}, see assembler.rs
+//   {"command": "write_memory", "space": "prg", "address": "$C123", "data": "EAEA"}
+//   {"command": "set_ppu_breakpoint", "event": "scanline 100"}, see PpuBreakpoint::parse
+//   {"command": "add_trace_filter", "filter": "write:$2006-$2007"}, see TraceFilter::parse
+// Addresses are numbers, "$C000"/"0xC000" strings or symbol names. Memory commands
+// take a "space" of "cpu" (the default), "ppu" or "prg", which patches PRG ROM until
+// "clear_patches" instead of writing to the mapper. Answers carry "ok", then either
+// the result or "error".
+pub const DEFAULT_PORT: u16 = 6502;
+// How far "continue", "step_over", "step_out" and "step" run without hitting a
+// break before giving control back
+const CONTINUE_INSTRUCTIONS: usize = 1_000_000;
+const MAX_READ_LENGTH: usize = 0x10000;
+const DEFAULT_DISASSEMBLE_COUNT: usize = 16;
+const MAX_DISASSEMBLE_COUNT: usize = 1024;
+// Longest request line a client can send, with room for a 64KB write_memory in
+// hex. A client that goes past it without a newline is dropped.
+const MAX_REQUEST_LENGTH: usize = 0x28000;
+// Answers queued for a client that isn't reading them. Past this, its requests
+// wait until it catches up.
+const MAX_QUEUED_OUTPUT: usize = 0x100000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    String(String),
+    Number(i64),
+    Bool(bool),
+    Null,
+}
+
+// Parses a flat JSON object, the only shape requests come in
+pub fn parse_object(text: &str) -> Option<Vec<(String, JsonValue)>> {
+    let mut chars = text.trim().chars().peekable();
+    let mut fields = Vec::new();
+    let skip_whitespace = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+        while chars.next_if(|c| c.is_whitespace()).is_some() {}
+    };
+    if chars.next()? != '{' {
+        return None;
+    }
+    skip_whitespace(&mut chars);
+    if chars.next_if_eq(&'}').is_some() {
+        return chars.next().is_none().then_some(fields);
+    }
+    loop {
+        skip_whitespace(&mut chars);
+        let key = parse_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next()? != ':' {
+            return None;
+        }
+        skip_whitespace(&mut chars);
+        let value = match chars.peek()? {
+            '"' => JsonValue::String(parse_string(&mut chars)?),
+            '-' | '0'..='9' => {
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|c| *c == '-' || c.is_ascii_digit()) {
+                    number.push(c);
+                }
+                JsonValue::Number(number.parse().ok()?)
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(char::is_ascii_alphabetic) {
+                    word.push(c);
+                }
+                match word.as_str() {
+                    "true" => JsonValue::Bool(true),
+                    "false" => JsonValue::Bool(false),
+                    "null" => JsonValue::Null,
+                    _ => return None,
+                }
+            }
+        };
+        fields.push((key, value));
+        skip_whitespace(&mut chars);
+        match chars.next()? {
+            ',' => continue,
+            '}' => return chars.next().is_none().then_some(fields),
+            _ => return None,
+        }
+    }
+}
+
+fn parse_string(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+    if chars.next()? != '"' {
+        return None;
+    }
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => string.push(match chars.next()? {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'u' => {
+                    let code: String = (0..4).map(|_| chars.next()).collect::<Option<_>>()?;
+                    char::from_u32(u32::from_str_radix(&code, 16).ok()?)?
+                }
+                c => c,
+            }),
+            c => string.push(c),
+        }
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Registers,
+    Step { count: usize },
+    StepOver,
+    StepOut,
+    Continue,
+    // steps past the instruction the CPU faulted on
+    Skip,
+    ReadMemory { space: MemorySpace, address: u16, length: usize },
+    // None for PC
+    Disassemble { address: Option<u16>, count: usize },
+    WriteMemory { space: MemorySpace, address: u16, data: Vec<u8> },
+    Assemble { space: MemorySpace, address: u16, source: String },
+    ClearPatches,
+    SetBreakpoint { address: u16, condition: Option<Condition> },
+    RemoveBreakpoint { address: u16 },
+    Breakpoints,
+    SetPpuBreakpoint(PpuBreakpoint),
+    RemovePpuBreakpoint(PpuBreakpoint),
+    AddTraceFilter(TraceFilter),
+    ClearTraceFilters,
+}
+
+impl Request {
+    pub fn parse(line: &str, symbols: &SymbolTable) -> Result<Request, String> {
+        let fields = parse_object(line).ok_or("request is not a JSON object")?;
+        let field = |name: &str| fields.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+        let number = |name: &str| match field(name) {
+            Some(JsonValue::Number(number)) if *number >= 0 => Ok(Some(*number as usize)),
+            None | Some(JsonValue::Null) => Ok(None),
+            _ => Err(format!("{} must be a positive number", name)),
+        };
+        let address = || match field("address") {
+            Some(JsonValue::Number(address)) => u16::try_from(*address).ok(),
+            Some(JsonValue::String(address)) => symbols.resolve(address),
+            _ => None,
+        }
+        .ok_or_else(|| "address is missing or unknown".to_string());
+        let event = || match field("event") {
+            Some(JsonValue::String(event)) => PpuBreakpoint::parse(event),
+            _ => None,
+        }
+        .ok_or_else(|| "event is missing or unknown".to_string());
+        let space = || match field("space") {
+            Some(JsonValue::String(space)) => MemorySpace::parse(space).ok_or("space must be cpu, ppu or prg"),
+            None | Some(JsonValue::Null) => Ok(MemorySpace::Cpu),
+            _ => Err("space must be cpu, ppu or prg"),
+        };
+
+        let Some(JsonValue::String(command)) = field("command") else {
+            return Err("command is missing".to_string());
+        };
+        Ok(match command.as_str() {
+            "registers" => Request::Registers,
+            "step" => Request::Step { count: number("count")?.unwrap_or(1).min(CONTINUE_INSTRUCTIONS) },
+            "step_over" => Request::StepOver,
+            "step_out" => Request::StepOut,
+            "continue" => Request::Continue,
+            "skip" => Request::Skip,
+            "read_memory" => Request::ReadMemory {
+                space: space()?,
+                address: address()?,
+                length: number("length")?.unwrap_or(1).min(MAX_READ_LENGTH),
+            },
+            "disassemble" => Request::Disassemble {
+                address: field("address").map(|_| address()).transpose()?,
+                count: number("count")?.unwrap_or(DEFAULT_DISASSEMBLE_COUNT).min(MAX_DISASSEMBLE_COUNT),
+            },
+            "write_memory" => {
+                let Some(JsonValue::String(data)) = field("data") else {
+                    return Err("data must be a hex string".to_string());
+                };
+                let data = hash::from_hex(data).ok_or("data must be a hex string")?;
+                Request::WriteMemory { space: space()?, address: address()?, data }
+            }
+            "assemble" => {
+                let Some(JsonValue::String(source)) = field("source") else {
+                    return Err("source must be a string".to_string());
+                };
+                Request::Assemble { space: space()?, address: address()?, source: source.clone() }
+            }
+            "clear_patches" => Request::ClearPatches,
+            "set_breakpoint" => {
+                let condition = match field("condition") {
+                    Some(JsonValue::String(condition)) => Some(Condition::parse(condition).ok_or("invalid condition")?),
+                    None | Some(JsonValue::Null) => None,
+                    _ => return Err("condition must be a string".to_string()),
+                };
+                Request::SetBreakpoint { address: address()?, condition }
+            }
+            "remove_breakpoint" => Request::RemoveBreakpoint { address: address()? },
+            "breakpoints" => Request::Breakpoints,
+            "set_ppu_breakpoint" => Request::SetPpuBreakpoint(event()?),
+            "remove_ppu_breakpoint" => Request::RemovePpuBreakpoint(event()?),
+            "add_trace_filter" => match field("filter") {
+                Some(JsonValue::String(filter)) => Request::AddTraceFilter(TraceFilter::parse(filter, symbols).ok_or("invalid trace filter")?),
+                _ => return Err("filter must be a string".to_string()),
+            },
+            "clear_trace_filters" => Request::ClearTraceFilters,
+            command => return Err(format!("unknown command {}", command)),
+        })
+    }
+}
+
+fn error_response(message: &str) -> String {
+    format!("{{\"ok\":false,\"error\":\"{}\"}}", escape(message))
+}
+
+// Where execution stopped, and why if a break triggered
+fn stop_response<B: Bus>(cpu: &Cpu<B>, reason: Option<BreakReason>) -> String {
+    match reason {
+        Some(reason) => format!("{{\"ok\":true,\"pc\":{},\"stopped\":\"{}\"}}", cpu.pc, escape(&reason.to_string())),
+        None => format!("{{\"ok\":true,\"pc\":{},\"stopped\":null}}", cpu.pc),
+    }
+}
+
+// Runs one request line against the debugger and returns the answer line
+pub fn handle<B: Bus>(debugger: &mut Debugger, cpu: &mut Cpu<B>, line: &str) -> String {
+    let request = match Request::parse(line, &debugger.symbols) {
+        Ok(request) => request,
+        Err(error) => return error_response(&error),
+    };
+    match request {
+        Request::Registers => {
+            let registers: Vec<String> =
+                Register::ALL.iter().map(|register| format!("\"{}\":{}", register.name(), register.value(cpu))).collect();
+            format!("{{\"ok\":true,{},\"cycles\":{}}}", registers.join(","), cpu.cycles)
+        }
+        Request::Step { count } => {
+            let reason = (0..count).find_map(|_| debugger.step(cpu));
+            stop_response(cpu, reason)
+        }
+        Request::StepOver => {
+            let reason = debugger.step_over(cpu, CONTINUE_INSTRUCTIONS);
+            stop_response(cpu, reason)
+        }
+        Request::StepOut => {
+            let reason = debugger.step_out(cpu, CONTINUE_INSTRUCTIONS);
+            stop_response(cpu, reason)
+        }
+        Request::Continue => {
+            let reason = debugger.run(cpu, CONTINUE_INSTRUCTIONS);
+            stop_response(cpu, reason)
+        }
+        Request::Skip => {
+            if cpu.fault.is_none() {
+                return error_response("the CPU has not faulted");
+            }
+            cpu.skip_faulted_instruction();
+            stop_response(cpu, None)
+        }
+        Request::ReadMemory { space, address, length } => {
+            let data: Option<Vec<u8>> =
+                (0..length).map(|offset| debugger::peek_memory(cpu, space, address.wrapping_add(offset as u16))).collect();
+            let Some(data) = data else {
+                return error_response("no such memory space");
+            };
+            format!("{{\"ok\":true,\"address\":{},\"data\":\"{}\"}}", address, hash::to_hex(&data))
+        }
+        Request::Disassemble { address, count } => {
+            let lines = Disassembler::new(cpu).lines(address.unwrap_or(cpu.pc), count);
+            let lines: Vec<String> = lines.iter().map(|line| format!("\"{}\"", escape(line))).collect();
+            format!("{{\"ok\":true,\"lines\":[{}]}}", lines.join(","))
+        }
+        Request::WriteMemory { space, address, data } => {
+            if debugger::poke_memory(cpu, space, address, &data) {
+                "{\"ok\":true}".to_string()
+            } else {
+                error_response("can't write there")
+            }
+        }
+        Request::Assemble { space, address, source } => match assembler::assemble_with_symbols(&source, address, &debugger.symbols) {
+            Ok(code) if debugger::poke_memory(cpu, space, address, &code) => format!("{{\"ok\":true,\"length\":{}}}", code.len()),
+            Ok(_) => error_response("can't write there"),
+            Err(error) => error_response(&error.to_string()),
+        },
+        Request::ClearPatches => {
+            cpu.clear_prg_patches();
+            "{\"ok\":true}".to_string()
+        }
+        Request::SetBreakpoint { address, condition } => {
+            debugger.add_breakpoint(address, condition);
+            "{\"ok\":true}".to_string()
+        }
+        Request::RemoveBreakpoint { address } => {
+            if debugger.remove_breakpoint(address) {
+                "{\"ok\":true}".to_string()
+            } else {
+                error_response("no breakpoint at that address")
+            }
+        }
+        Request::Breakpoints => {
+            let addresses: Vec<String> = debugger.breakpoints.iter().map(|breakpoint| breakpoint.address.to_string()).collect();
+            format!("{{\"ok\":true,\"breakpoints\":[{}]}}", addresses.join(","))
+        }
+        Request::SetPpuBreakpoint(breakpoint) => {
+            debugger.add_ppu_breakpoint(breakpoint);
+            "{\"ok\":true}".to_string()
+        }
+        Request::RemovePpuBreakpoint(breakpoint) => {
+            if debugger.remove_ppu_breakpoint(breakpoint) {
+                "{\"ok\":true}".to_string()
+            } else {
+                error_response("no such PPU breakpoint")
+            }
+        }
+        Request::AddTraceFilter(filter) => {
+            debugger.trace_filters.push(filter);
+            "{\"ok\":true}".to_string()
+        }
+        Request::ClearTraceFilters => {
+            debugger.trace_filters.clear();
+            "{\"ok\":true}".to_string()
+        }
+    }
+}
+
+struct Client {
+    stream: TcpStream,
+    // bytes of a request line still coming in
+    pending: Vec<u8>,
+    // answers the socket hasn't taken yet
+    output: Vec<u8>,
+}
+
+// Listens for debugger clients without blocking the emulator; the frontend
+// calls poll() once a frame
+pub struct RemoteServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+}
+
+impl RemoteServer {
+    pub fn bind(address: impl ToSocketAddrs) -> io::Result<RemoteServer> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        Ok(RemoteServer { listener, clients: Vec::new() })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    // Takes new connections and answers every complete request line.
+    // Returns the number of requests handled.
+    pub fn poll<B: Bus>(&mut self, debugger: &mut Debugger, cpu: &mut Cpu<B>) -> io::Result<usize> {
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    self.clients.push(Client { stream, pending: Vec::new(), output: Vec::new() });
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut handled = 0;
+        // a client that hangs up or errors is dropped, the others carry on
+        self.clients.retain_mut(|client| match serve(client, debugger, cpu) {
+            Ok(Some(count)) => {
+                handled += count;
+                true
+            }
+            Ok(None) | Err(_) => false,
+        });
+        Ok(handled)
+    }
+}
+
+// None once the client has disconnected, or sent a line too long to be a request
+fn serve<B: Bus>(client: &mut Client, debugger: &mut Debugger, cpu: &mut Cpu<B>) -> io::Result<Option<usize>> {
+    let mut handled = 0;
+    if client.output.len() < MAX_QUEUED_OUTPUT {
+        let mut buffer = [0; 4096];
+        while client.pending.len() <= MAX_REQUEST_LENGTH {
+            match client.stream.read(&mut buffer) {
+                Ok(0) => return Ok(None),
+                Ok(size) => client.pending.extend_from_slice(&buffer[..size]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        while client.output.len() < MAX_QUEUED_OUTPUT {
+            let Some(end) = client.pending.iter().position(|&byte| byte == b'\n') else {
+                break;
+            };
+            let line: Vec<u8> = client.pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            client.output.extend_from_slice(handle(debugger, cpu, &line).as_bytes());
+            client.output.push(b'\n');
+            handled += 1;
+        }
+        if client.pending.len() > MAX_REQUEST_LENGTH && !client.pending.contains(&b'\n') {
+            return Ok(None);
+        }
+    }
+
+    // answers can be larger than the socket buffer, the rest goes out on later polls
+    let mut written = 0;
+    while written < client.output.len() {
+        match client.stream.write(&client.output[written..]) {
+            Ok(0) => return Ok(None),
+            Ok(size) => written += size,
+            Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+            Err(error) => return Err(error),
+        }
+    }
+    client.output.drain(..written);
+    Ok(Some(handled))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+
+    // LDA #$42; STA $10; LDX $10; INX
+    fn cpu() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.load_program(assembler::assemble("LDA #$42\nSTA $10\nLDX $10\nINX", 0x8000).unwrap(), 0x8000);
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn test_parse_object() {
+        let fields = parse_object(r#" {"command": "step", "count": 3, "quiet": true, "name": "a\"bA", "x": null} "#).unwrap();
+        assert_eq!(fields[0], ("command".to_string(), JsonValue::String("step".to_string())));
+        assert_eq!(fields[1].1, JsonValue::Number(3));
+        assert_eq!(fields[2].1, JsonValue::Bool(true));
+        assert_eq!(fields[3].1, JsonValue::String("a\"bA".to_string()));
+        assert_eq!(fields[4].1, JsonValue::Null);
+        assert_eq!(parse_object("{}"), Some(Vec::new()));
+        assert_eq!(parse_object("{\"a\": 1,}"), None);
+        assert_eq!(parse_object("{\"a\": 1} trailing"), None);
+        assert_eq!(parse_object("[1]"), None);
+        assert_eq!(
+            Request::parse(r#"{"command": "step", "count": 1000000000000000000}"#, &SymbolTable::default()),
+            Ok(Request::Step { count: CONTINUE_INSTRUCTIONS })
+        );
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        debugger.symbols.insert(0x8004, "load_x");
+        let mut request = |line: &str| handle(&mut debugger, &mut cpu, line);
+
+        assert_eq!(request(r#"{"command": "set_breakpoint", "address": "load_x"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "breakpoints"}"#), r#"{"ok":true,"breakpoints":[32772]}"#);
+        assert_eq!(request(r#"{"command": "continue"}"#), r#"{"ok":true,"pc":32772,"stopped":"breakpoint at $8004"}"#);
+        assert_eq!(request(r#"{"command": "read_memory", "address": 16, "length": 2}"#), r#"{"ok":true,"address":16,"data":"4200"}"#);
+        assert_eq!(request(r#"{"command": "write_memory", "address": "$10", "data": "07"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "step"}"#), r#"{"ok":true,"pc":32774,"stopped":null}"#);
+        assert_eq!(
+            request(r#"{"command": "registers"}"#),
+            r#"{"ok":true,"a":66,"x":7,"y":0,"sp":253,"p":4,"pc":32774,"cycles":8}"#
+        );
+
+        // an unknown opcode stops execution until it's patched over
+        assert_eq!(request(r#"{"command": "write_memory", "address": "$8006", "data": "02"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "step"}"#), r#"{"ok":true,"pc":32774,"stopped":"unknown opcode $02 at $8006"}"#);
+        assert_eq!(request(r#"{"command": "write_memory", "address": "$8006", "data": "E8"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "step"}"#), r#"{"ok":true,"pc":32775,"stopped":null}"#);
+        assert_eq!(request(r#"{"command": "skip"}"#), r#"{"ok":false,"error":"the CPU has not faulted"}"#);
+        assert_eq!(
+            request(r#"{"command": "disassemble", "address": "$8006", "count": 1}"#),
+            r#"{"ok":true,"lines":["8006  E8        INX"]}"#
+        );
+        assert_eq!(request(r#"{"command": "assemble", "address": "$8007", "source": "INY\nJMP load_x"}"#), r#"{"ok":true,"length":4}"#);
+        assert_eq!(
+            request(r#"{"command": "disassemble", "count": 2}"#),
+            r#"{"ok":true,"lines":["8007  C8        INY","8008  4C 04 80  JMP $8004"]}"#
+        );
+        assert_eq!(request(r#"{"command": "read_memory", "space": "ppu", "address": 0}"#), r#"{"ok":false,"error":"no such memory space"}"#);
+        assert_eq!(request(r#"{"command": "write_memory", "space": "vram", "address": 0, "data": "00"}"#), r#"{"ok":false,"error":"space must be cpu, ppu or prg"}"#);
+        assert_eq!(request(r#"{"command": "write_memory", "space": "prg", "address": "$8007", "data": "E8"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "read_memory", "space": "prg", "address": "$8007"}"#), r#"{"ok":true,"address":32775,"data":"E8"}"#);
+        assert_eq!(request(r#"{"command": "assemble", "address": "$8007", "source": "INY #1"}"#), r#"{"ok":false,"error":"line 1: INY can't take that operand"}"#);
+
+        assert_eq!(request(r#"{"command": "remove_breakpoint", "address": "$9000"}"#), r#"{"ok":false,"error":"no breakpoint at that address"}"#);
+        assert_eq!(request(r#"{"command": "fly"}"#), r#"{"ok":false,"error":"unknown command fly"}"#);
+        assert_eq!(request(r#"{"command": "set_ppu_breakpoint", "event": "hblank"}"#), r#"{"ok":false,"error":"event is missing or unknown"}"#);
+        assert_eq!(request(r#"{"command": "remove_ppu_breakpoint", "event": "vblank"}"#), r#"{"ok":false,"error":"no such PPU breakpoint"}"#);
+        assert_eq!(request(r#"{"command": "add_trace_filter", "filter": "pc:$8000-load_x"}"#), r#"{"ok":true}"#);
+        assert_eq!(request(r#"{"command": "add_trace_filter", "filter": "pc:nowhere"}"#), r#"{"ok":false,"error":"invalid trace filter"}"#);
+        assert_eq!(request(r#"{"command": "read_memory", "address": "nowhere"}"#), r#"{"ok":false,"error":"address is missing or unknown"}"#);
+        assert_eq!(request("step"), r#"{"ok":false,"error":"request is not a JSON object"}"#);
+        assert_eq!(debugger.trace_filters, [TraceFilter::Pc(0x8000..=0x8004)]);
+    }
+
+    #[test]
+    fn test_server() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        let mut server = RemoteServer::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"{\"command\": \"step\", \"count\": 2}\n{\"command\": \"read_").unwrap();
+        let mut handled = 0;
+        while handled == 0 {
+            handled = server.poll(&mut debugger, &mut cpu).unwrap();
+        }
+        assert_eq!(handled, 1);
+        client.write_all(b"memory\", \"address\": 16}\n").unwrap();
+        while server.poll(&mut debugger, &mut cpu).unwrap() == 0 {}
+
+        let mut lines = BufReader::new(client.try_clone().unwrap()).lines();
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true,"pc":32772,"stopped":null}"#);
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"ok":true,"address":16,"data":"42"}"#);
+        drop(lines);
+        drop(client);
+        while server.client_count() > 0 {
+            server.poll(&mut debugger, &mut cpu).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let mut cpu = cpu();
+        let mut debugger = Debugger::new();
+        let mut server = RemoteServer::bind("127.0.0.1:0").unwrap();
+
+        // a large answer goes out over as many polls as the client takes to read it
+        let mut client = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        client.write_all(b"{\"command\": \"read_memory\", \"address\": 0, \"length\": 65536}\n").unwrap();
+        client.set_nonblocking(true).unwrap();
+        let mut response = Vec::new();
+        let mut buffer = [0; 4096];
+        while !response.ends_with(b"\n") {
+            server.poll(&mut debugger, &mut cpu).unwrap();
+            match client.read(&mut buffer) {
+                Ok(size) => response.extend_from_slice(&buffer[..size]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {}
+                Err(error) => panic!("{}", error),
+            }
+        }
+        assert!(response.starts_with(br#"{"ok":true,"address":0,"data":""#));
+        assert_eq!(response.len(), 31 + 2 * 65536 + 3);
+
+        // a line that never ends gets its client dropped, the others stay
+        let mut flood = TcpStream::connect(server.local_addr().unwrap()).unwrap();
+        flood.set_nonblocking(true).unwrap();
+        while server.client_count() < 2 {
+            server.poll(&mut debugger, &mut cpu).unwrap();
+        }
+        let mut sent = 0;
+        while server.client_count() == 2 {
+            if let Ok(size) = flood.write(&[b' '; 4096]) {
+                sent += size;
+            }
+            server.poll(&mut debugger, &mut cpu).unwrap();
+        }
+        assert!(sent > MAX_REQUEST_LENGTH);
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::time::Instant;
+
+use crate::config::Config;
+use crate::hash;
+use crate::nes::Nes;
+use crate::savestate::{self, StateError};
+
+// Repro captures, for turning "it broke when I did this" into something that can be
+// replayed. A movie records what the console saw each frame; a capture records what
+// the user did at the host: inputs by the name they're bound under, and emulator
+// commands like reset and state loads. Replaying it through a config with the same
+// bindings reproduces bugs in input handling along with the game's own.
+//
+//   madnes-repro 1
+//   rom 3C9F...            SHA-1 of the game's PRG and CHR, absent for disks
+//   state 4D4E4553...      the machine when capture started
+//   120 2016 press Return  frame, milliseconds since start, event
+//   131 2200 release Return
+//   300 5004 load_state 1 4D4E4553...
+const HEADER: &str = "madnes-repro 1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HostEvent {
+    Press(String),
+    Release(String),
+    Reset,
+    PowerCycle,
+    SaveState(usize),
+    // the state itself goes in the capture, the slot file stays on the reporter's machine
+    LoadState { slot: usize, state: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReproEvent {
+    // frames since the capture started, events apply before the frame runs
+    pub frame: u64,
+    // wall clock time since the capture started, for reading along
+    pub millis: u64,
+    pub event: HostEvent,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ReproError {
+    InvalidHeader,
+    InvalidLine(usize),
+    RomMismatch,
+    State(StateError),
+}
+
+impl fmt::Display for ReproError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReproError::InvalidHeader => write!(f, "Not a madNES repro capture"),
+            ReproError::InvalidLine(line) => write!(f, "Invalid repro capture line {}", line),
+            ReproError::RomMismatch => write!(f, "The capture was recorded with a different game"),
+            ReproError::State(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl From<StateError> for ReproError {
+    fn from(error: StateError) -> Self {
+        ReproError::State(error)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReproCapture {
+    pub rom_sha1: Option<String>,
+    pub state: Vec<u8>,
+    pub events: Vec<ReproEvent>,
+}
+
+impl ReproCapture {
+    pub fn to_text(&self) -> String {
+        let mut text = format!("{}\n", HEADER);
+        if let Some(sha1) = &self.rom_sha1 {
+            text.push_str(&format!("rom {}\n", sha1));
+        }
+        text.push_str(&format!("state {}\n", hash::to_hex(&self.state)));
+        for event in &self.events {
+            let description = match &event.event {
+                HostEvent::Press(input) => format!("press {}", input),
+                HostEvent::Release(input) => format!("release {}", input),
+                HostEvent::Reset => "reset".to_string(),
+                HostEvent::PowerCycle => "power_cycle".to_string(),
+                HostEvent::SaveState(slot) => format!("save_state {}", slot),
+                HostEvent::LoadState { slot, state } => format!("load_state {} {}", slot, hash::to_hex(state)),
+            };
+            text.push_str(&format!("{} {} {}\n", event.frame, event.millis, description));
+        }
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<ReproCapture, ReproError> {
+        let mut lines = text.lines().enumerate();
+        if lines.next().map(|(_, line)| line.trim_end()) != Some(HEADER) {
+            return Err(ReproError::InvalidHeader);
+        }
+        let mut capture = ReproCapture { rom_sha1: None, state: Vec::new(), events: Vec::new() };
+        for (index, line) in lines {
+            let invalid = ReproError::InvalidLine(index + 1);
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(sha1) = line.strip_prefix("rom ") {
+                capture.rom_sha1 = Some(sha1.trim().to_uppercase());
+            } else if let Some(state) = line.strip_prefix("state ") {
+                capture.state = hash::from_hex(state.trim()).ok_or(invalid)?;
+            } else {
+                let event = parse_event(line).ok_or(invalid)?;
+                // replay walks the events in order
+                if capture.events.last().is_some_and(|last| last.frame > event.frame) {
+                    return Err(ReproError::InvalidLine(index + 1));
+                }
+                capture.events.push(event);
+            }
+        }
+        if capture.state.is_empty() {
+            return Err(ReproError::InvalidHeader);
+        }
+        Ok(capture)
+    }
+}
+
+// "<frame> <millis> <event> [arguments]", input names may contain spaces
+fn parse_event(line: &str) -> Option<ReproEvent> {
+    let mut fields = line.splitn(4, ' ');
+    let frame = fields.next()?.parse().ok()?;
+    let millis = fields.next()?.parse().ok()?;
+    let kind = fields.next()?;
+    let argument = fields.next();
+    let event = match (kind, argument) {
+        ("press", Some(input)) => HostEvent::Press(input.to_string()),
+        ("release", Some(input)) => HostEvent::Release(input.to_string()),
+        ("reset", None) => HostEvent::Reset,
+        ("power_cycle", None) => HostEvent::PowerCycle,
+        ("save_state", Some(slot)) => HostEvent::SaveState(slot.parse().ok()?),
+        ("load_state", Some(arguments)) => {
+            let (slot, state) = arguments.split_once(' ')?;
+            HostEvent::LoadState { slot: slot.parse().ok()?, state: hash::from_hex(state)? }
+        }
+        _ => return None,
+    };
+    Some(ReproEvent { frame, millis, event })
+}
+
+fn rom_sha1(nes: &Nes) -> Option<String> {
+    nes.cartridge().map(|rom| hash::to_hex(&rom.sha1()))
+}
+
+// Logs host events as the frontend handles them. The frontend calls end_frame after
+// every emulated frame so events line up with the frame they affected.
+pub struct ReproRecorder {
+    capture: ReproCapture,
+    frame: u64,
+    started: Instant,
+}
+
+impl ReproRecorder {
+    // Starts capturing from the machine's current state
+    pub fn start(nes: &Nes) -> Self {
+        ReproRecorder {
+            capture: ReproCapture { rom_sha1: rom_sha1(nes), state: savestate::save(nes.cpu()), events: Vec::new() },
+            frame: 0,
+            started: Instant::now(),
+        }
+    }
+
+    pub fn record(&mut self, event: HostEvent) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.capture.events.push(ReproEvent { frame: self.frame, millis, event });
+    }
+
+    pub fn end_frame(&mut self) {
+        self.frame += 1;
+    }
+
+    pub fn finish(self) -> ReproCapture {
+        self.capture
+    }
+}
+
+// Feeds a capture back into the emulator frame by frame, in place of the host
+pub struct ReproPlayer {
+    capture: ReproCapture,
+    next: usize,
+    frame: u64,
+    held: HashSet<String>,
+}
+
+impl ReproPlayer {
+    // Restores the state the capture started from, after checking it's the same game
+    pub fn start(capture: ReproCapture, nes: &mut Nes) -> Result<Self, ReproError> {
+        if capture.rom_sha1 != rom_sha1(nes) {
+            return Err(ReproError::RomMismatch);
+        }
+        savestate::load(nes.cpu_mut(), &capture.state)?;
+        Ok(ReproPlayer { capture, next: 0, frame: 0, held: HashSet::new() })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next == self.capture.events.len()
+    }
+
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    // Applies this frame's events, sets the controllers from the held inputs and runs the frame.
+    // Saves are left out: the slots on this machine aren't the reporter's.
+    pub fn step_frame(&mut self, nes: &mut Nes, config: &Config) -> Result<(), ReproError> {
+        while let Some(event) = self.capture.events.get(self.next).filter(|event| event.frame <= self.frame) {
+            match &event.event {
+                HostEvent::Press(input) => {
+                    self.held.insert(input.clone());
+                }
+                HostEvent::Release(input) => {
+                    self.held.remove(input);
+                }
+                HostEvent::Reset => {
+                    // start() found the game loaded, so there's something to reset
+                    let _ = nes.reset();
+                }
+                HostEvent::PowerCycle => {
+                    let _ = nes.power_cycle();
+                }
+                HostEvent::SaveState(_) => {}
+                HostEvent::LoadState { state, .. } => savestate::load(nes.cpu_mut(), state)?,
+            }
+            self.next += 1;
+        }
+        for player in 0..2 {
+            nes.set_buttons(player, config.buttons(player, &self.held, nes.frame_count()));
+        }
+        nes.step_frame();
+        self.frame += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::joypad::JoypadButton;
+    use crate::rom::tests::nes_with_program;
+
+    // LDA #1; STA $4016; LDA #0; STA $4016; LDA $4016; STA $10; JMP $8000
+    fn nes() -> Nes {
+        nes_with_program(&[0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40, 0xAD, 0x16, 0x40, 0x85, 0x10, 0x4C, 0x00, 0x80])
+    }
+
+    #[test]
+    fn test_capture_text() {
+        let nes = nes();
+        let mut recorder = ReproRecorder::start(&nes);
+        recorder.record(HostEvent::Press("Left Shift".to_string()));
+        recorder.end_frame();
+        recorder.record(HostEvent::Release("Left Shift".to_string()));
+        recorder.record(HostEvent::SaveState(2));
+        recorder.record(HostEvent::LoadState { slot: 2, state: vec![1, 2, 3] });
+        recorder.end_frame();
+        recorder.record(HostEvent::Reset);
+        recorder.record(HostEvent::PowerCycle);
+        let capture = recorder.finish();
+        assert_eq!(capture.events[3].frame, 1);
+
+        let text = capture.to_text();
+        assert!(text.starts_with("madnes-repro 1\nrom "));
+        assert!(text.contains(" release Left Shift\n"));
+        assert!(text.contains(" load_state 2 010203\n"));
+        assert_eq!(ReproCapture::parse(&text), Ok(capture));
+
+        assert_eq!(ReproCapture::parse("madnes-movie 1"), Err(ReproError::InvalidHeader));
+        assert_eq!(ReproCapture::parse("madnes-repro 1\nstate 00\n1 0 jump"), Err(ReproError::InvalidLine(3)));
+        assert_eq!(ReproCapture::parse("madnes-repro 1\nstate 00\n5 0 reset\n4 0 reset"), Err(ReproError::InvalidLine(4)));
+    }
+
+    #[test]
+    fn test_replay() {
+        let mut nes = nes();
+        nes.step_frame();
+        let mut recorder = ReproRecorder::start(&nes);
+        recorder.end_frame();
+        recorder.record(HostEvent::Press("X".to_string()));
+        recorder.end_frame();
+        recorder.record(HostEvent::Release("X".to_string()));
+        recorder.record(HostEvent::LoadState { slot: 0, state: savestate::save(nes.cpu()) });
+        let capture = recorder.finish();
+
+        // the game reads A on the frame it's pressed and lets go after
+        let config = Config::default();
+        let mut nes = self::nes();
+        let mut player = ReproPlayer::start(capture.clone(), &mut nes).unwrap();
+        player.step_frame(&mut nes, &config).unwrap();
+        assert_eq!(nes.peek(0x10) & 1, 0);
+        player.step_frame(&mut nes, &config).unwrap();
+        assert_eq!(nes.buttons(0), JoypadButton::A);
+        assert_eq!(nes.peek(0x10) & 1, 1);
+        assert!(!player.is_finished());
+        player.step_frame(&mut nes, &config).unwrap();
+        assert_eq!(nes.buttons(0), JoypadButton::empty());
+        assert_eq!(nes.peek(0x10) & 1, 0);
+        assert!(player.is_finished());
+        assert_eq!(player.frame(), 3);
+
+        let mut other = nes_with_program(&[]);
+        assert!(matches!(ReproPlayer::start(capture, &mut other), Err(ReproError::RomMismatch)));
+    }
+}
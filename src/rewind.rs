@@ -0,0 +1,166 @@
+use std::collections::VecDeque;
+
+use crate::cpu::{Bus, Cpu};
+use crate::savestate::{self, SaveState};
+
+pub const FRAMES_PER_SECOND: usize = 60;
+
+// Keeps a bounded history of savestates, captured every few frames.
+// Only the newest state is stored in full; every older state is kept as the
+// run-length encoded XOR against the state after it, which is mostly zeros.
+pub struct Rewind {
+    interval: usize,
+    capacity: usize,
+    frame: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    // Captures every `interval` frames, keeping roughly `seconds` of history
+    pub fn new(interval: usize, seconds: usize) -> Self {
+        let interval = interval.max(1);
+        Rewind {
+            interval,
+            capacity: (seconds * FRAMES_PER_SECOND / interval).max(1),
+            frame: 0,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    // Number of states that can be rewound to
+    pub fn len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |_| self.deltas.len() + 1)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    pub fn clear(&mut self) {
+        self.frame = 0;
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    // Called once per emulated frame, captures a state every interval frames
+    pub fn tick_frame<B: Bus + SaveState>(&mut self, cpu: &Cpu<B>) {
+        if self.frame.is_multiple_of(self.interval) {
+            self.capture(cpu);
+        }
+        self.frame += 1;
+    }
+
+    pub fn capture<B: Bus + SaveState>(&mut self, cpu: &Cpu<B>) {
+        let state = savestate::save(cpu);
+        if let Some(latest) = self.latest.replace(state) {
+            let current = self.latest.as_ref().unwrap();
+            self.deltas.push_back(compress(&xor(&latest, current)));
+            // the history below the newest state is capacity - 1 deltas long
+            while self.deltas.len() >= self.capacity {
+                self.deltas.pop_front();
+            }
+        }
+    }
+
+    // Restores the most recent captured state and drops it from the history,
+    // so holding the rewind key keeps stepping further back
+    pub fn rewind<B: Bus + SaveState>(&mut self, cpu: &mut Cpu<B>) -> bool {
+        let Some(state) = self.latest.take() else {
+            return false;
+        };
+        if let Some(delta) = self.deltas.pop_back() {
+            self.latest = Some(xor(&state, &decompress(&delta)));
+        }
+        savestate::load(cpu, &state).is_ok()
+    }
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let len = a.len().max(b.len());
+    (0..len)
+        .map(|i| a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0))
+        .collect()
+}
+
+// Encodes the data as (zero run length, literal length, literal bytes) chunks,
+// with both lengths as u16 little endian
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..].iter().take(u16::MAX as usize).take_while(|&&byte| byte == 0).count();
+        i += zeros;
+        let literals = data[i..].iter().take(u16::MAX as usize).take_while(|&&byte| byte != 0).count();
+        out.extend_from_slice(&(zeros as u16).to_le_bytes());
+        out.extend_from_slice(&(literals as u16).to_le_bytes());
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i + 4 <= data.len() {
+        let zeros = u16::from_le_bytes([data[i], data[i + 1]]) as usize;
+        let literals = u16::from_le_bytes([data[i + 2], data[i + 3]]) as usize;
+        i += 4;
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_roundtrip() {
+        let mut data = vec![0; 70000];
+        data[5] = 1;
+        data[6] = 2;
+        data[69999] = 3;
+        let compressed = compress(&data);
+        assert!(compressed.len() < 32);
+        assert_eq!(decompress(&compressed), data);
+        assert_eq!(decompress(&compress(&[1, 2, 3])), [1, 2, 3]);
+    }
+
+    #[test]
+    fn test_rewind_steps_backwards() {
+        let mut cpu = Cpu::new();
+        let mut rewind = Rewind::new(2, 1);
+        for frame in 0..6 {
+            cpu.a = frame;
+            rewind.tick_frame(&cpu);
+        }
+        // frames 0, 2 and 4 were captured
+        assert_eq!(rewind.len(), 3);
+
+        for expected in [4, 2, 0] {
+            assert!(rewind.rewind(&mut cpu));
+            assert_eq!(cpu.a, expected);
+        }
+        assert!(!rewind.rewind(&mut cpu));
+        assert!(rewind.is_empty());
+    }
+
+    #[test]
+    fn test_rewind_is_bounded() {
+        let mut cpu = Cpu::new();
+        // one second at one capture per 20 frames holds 3 states
+        let mut rewind = Rewind::new(20, 1);
+        for i in 0..10 {
+            cpu.x = i;
+            rewind.capture(&cpu);
+        }
+        assert_eq!(rewind.len(), 3);
+        while rewind.rewind(&mut cpu) {}
+        assert_eq!(cpu.x, 7);
+    }
+}
@@ -0,0 +1,542 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::archive;
+use crate::gamedb::Region;
+use crate::hash;
+use crate::inflate::InflateError;
+use crate::mapper;
+use crate::patch::{self, PatchError};
+
+// "NES" followed by MS-DOS end-of-file
+const NES_TAG: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A];
+const HEADER_SIZE: usize = 16;
+pub const TRAINER_SIZE: usize = 512;
+// Where the trainer is loaded, in PRG RAM
+pub const TRAINER_ADDRESS: u16 = 0x7000;
+const PRG_ROM_PAGE_SIZE: usize = 16 * 1024;
+const CHR_ROM_PAGE_SIZE: usize = 8 * 1024;
+// Boards without CHR ROM carry this much RAM for tiles uploaded at runtime
+const CHR_RAM_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+    // every nametable is the first or second 1KB of CIRAM, switched by some mappers
+    SingleScreenLow,
+    SingleScreenHigh,
+}
+
+impl Mirroring {
+    // The names games.toml uses; single-screen is up to the mapper, not the header
+    pub fn parse(name: &str) -> Option<Mirroring> {
+        match name {
+            "horizontal" => Some(Mirroring::Horizontal),
+            "vertical" => Some(Mirroring::Vertical),
+            "four_screen" => Some(Mirroring::FourScreen),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RomError {
+    Io(std::io::Error),
+    InvalidTag,
+    Truncated { expected: usize, actual: usize },
+    // a header that declares no PRG ROM, leaving the CPU nothing to run
+    NoPrgRom,
+    UnsupportedMapper { mapper: u8, submapper: u8 },
+    // PlayChoice-10 dumps need the arcade's hint screen hardware
+    PlayChoice10,
+    InvalidArchive,
+    NoRomInArchive,
+    UnsupportedCompression(u16),
+    // what came out of the archive has another size or CRC-32 than it declares
+    ArchiveMismatch,
+    Decompress(InflateError),
+    Patch(PathBuf, PatchError),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::Io(error) => write!(f, "Could not read ROM: {}", error),
+            RomError::InvalidTag => write!(f, "File is not in iNES format"),
+            RomError::Truncated { expected, actual } => {
+                write!(f, "ROM is truncated: expected {} bytes, got {}", expected, actual)
+            }
+            RomError::NoPrgRom => write!(f, "ROM has no PRG ROM"),
+            RomError::UnsupportedMapper { mapper, submapper: 0 } => write!(f, "Mapper {} is not supported", mapper),
+            RomError::UnsupportedMapper { mapper, submapper } => {
+                write!(f, "Mapper {}.{} is not supported", mapper, submapper)
+            }
+            RomError::PlayChoice10 => write!(f, "PlayChoice-10 ROMs are not supported"),
+            RomError::InvalidArchive => write!(f, "Archive is corrupt"),
+            RomError::NoRomInArchive => write!(f, "Archive does not contain a .nes file"),
+            RomError::UnsupportedCompression(method) => write!(f, "Zip compression method {} is not supported", method),
+            RomError::ArchiveMismatch => write!(f, "Archive is corrupt: the ROM's size or CRC-32 does not match"),
+            RomError::Decompress(error) => write!(f, "Could not decompress ROM: {}", error),
+            RomError::Patch(path, error) => write!(f, "{}: {}", path.display(), error),
+        }
+    }
+}
+
+impl From<InflateError> for RomError {
+    fn from(error: InflateError) -> Self {
+        RomError::Decompress(error)
+    }
+}
+
+impl From<std::io::Error> for RomError {
+    fn from(error: std::io::Error) -> Self {
+        RomError::Io(error)
+    }
+}
+
+// Header oddities that don't stop the game from loading but may make it
+// run wrong. Rom::new works around what it can and lists the rest.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderWarning {
+    // more data than the header accounts for, e.g. a title appended by a ripper
+    TrailingData { declared: usize, actual: usize },
+    // junk in bytes 7-15 of an iNES 1.0 header, like "DiskDude!"; only the low mapper nibble is used
+    DirtyHeader,
+    // both the four-screen and vertical mirroring bits
+    ConflictingMirroring,
+    // NES 2.0 battery flag with no battery-backed PRG RAM declared in byte 10
+    BatteryWithoutNvram,
+}
+
+impl fmt::Display for HeaderWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HeaderWarning::TrailingData { declared, actual } => {
+                write!(f, "File is {} bytes but the header declares {}", actual, declared)
+            }
+            HeaderWarning::DirtyHeader => write!(f, "Header has junk in bytes 7-15, mapper number may be wrong"),
+            HeaderWarning::ConflictingMirroring => write!(f, "Header sets both four-screen and vertical mirroring"),
+            HeaderWarning::BatteryWithoutNvram => write!(f, "Header has a battery but no battery-backed RAM"),
+        }
+    }
+}
+
+pub struct Rom {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    // empty unless the cartridge has no CHR ROM
+    pub chr_ram: Vec<u8>,
+    pub mapper: u8,
+    // NES 2.0 headers only, 0 otherwise
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub has_battery: bool,
+    // 512 bytes some dumps want at $7000-$71FF, usually patches from copier devices
+    pub trainer: Option<Vec<u8>>,
+    // VS. Unisystem arcade board
+    pub is_vs_system: bool,
+    // from the header; None when it says the game runs on any console
+    pub region: Option<Region>,
+    pub warnings: Vec<HeaderWarning>,
+}
+
+impl Rom {
+    // Parses an iNES image
+    pub fn new(data: &[u8]) -> Result<Rom, RomError> {
+        if data.len() < HEADER_SIZE {
+            return Err(RomError::Truncated { expected: HEADER_SIZE, actual: data.len() });
+        }
+        if data[0..4] != NES_TAG {
+            return Err(RomError::InvalidTag);
+        }
+
+        let prg_rom_size = data[4] as usize * PRG_ROM_PAGE_SIZE;
+        let chr_rom_size = data[5] as usize * CHR_ROM_PAGE_SIZE;
+        let flags6 = data[6];
+        let flags7 = data[7];
+
+        let mirroring = if flags6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+        let has_trainer = flags6 & 0x04 != 0;
+        let nes2 = flags7 & 0x0C == 0x08;
+        // iNES 1.0 leaves bytes 12-15 zero; old tools wrote their name over bytes 7-15
+        let dirty = !nes2 && (flags7 & 0x0C != 0 || data[12..HEADER_SIZE].iter().any(|&byte| byte != 0));
+        let flags7 = if dirty { 0 } else { flags7 };
+        // the console type in NES 2.0, two flags in iNES; 3 is NES 2.0's extended types
+        let console_type = flags7 & 0x03;
+        if console_type == 2 || (!nes2 && console_type == 3) {
+            return Err(RomError::PlayChoice10);
+        }
+
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
+        let prg_rom_start = HEADER_SIZE + if has_trainer { TRAINER_SIZE } else { 0 };
+        let chr_rom_start = prg_rom_start + prg_rom_size;
+        let expected = chr_rom_start + chr_rom_size;
+        if data.len() < expected {
+            return Err(RomError::Truncated { expected, actual: data.len() });
+        }
+
+        let mapper = (flags7 & 0xF0) | (flags6 >> 4);
+        let mut warnings = Vec::new();
+        if data.len() > expected {
+            warnings.push(HeaderWarning::TrailingData { declared: expected, actual: data.len() });
+        }
+        if dirty {
+            warnings.push(HeaderWarning::DirtyHeader);
+        }
+        if flags6 & 0x09 == 0x09 {
+            warnings.push(HeaderWarning::ConflictingMirroring);
+        }
+        if nes2 && flags6 & 0x02 != 0 && data[10] & 0xF0 == 0 {
+            warnings.push(HeaderWarning::BatteryWithoutNvram);
+        }
+        let region = if nes2 {
+            match data[12] & 0x03 {
+                0 => Some(Region::Ntsc),
+                1 => Some(Region::Pal),
+                3 => Some(Region::Dendy),
+                _ => None,
+            }
+        } else if dirty {
+            None
+        } else if data[9] & 0x01 != 0 {
+            Some(Region::Pal)
+        } else {
+            Some(Region::Ntsc)
+        };
+
+        Ok(Rom {
+            prg_rom: data[prg_rom_start..chr_rom_start].to_vec(),
+            chr_rom: data[chr_rom_start..expected].to_vec(),
+            chr_ram: if chr_rom_size == 0 { vec![0; CHR_RAM_SIZE] } else { Vec::new() },
+            mapper,
+            submapper: if nes2 { data[8] >> 4 } else { 0 },
+            mirroring,
+            has_battery: flags6 & 0x02 != 0,
+            trainer: has_trainer.then(|| data[HEADER_SIZE..prg_rom_start].to_vec()),
+            is_vs_system: console_type == 1,
+            region,
+            warnings,
+        })
+    }
+
+    // Loads an iNES file, also from inside a .zip or .gz
+    pub fn load(path: impl AsRef<Path>) -> Result<Rom, RomError> {
+        Rom::load_patched(path, &[])
+    }
+
+    // Like load, with IPS or BPS patches applied in order to the unpacked file
+    pub fn load_patched(path: impl AsRef<Path>, patches: &[PathBuf]) -> Result<Rom, RomError> {
+        let mut data = archive::unpack(fs::read(path)?)?;
+        for patch in patches {
+            data = patch::apply_file(&data, patch).map_err(|error| RomError::Patch(patch.clone(), error))?;
+        }
+        Rom::new(&data)
+    }
+
+    // A clean iNES image of the ROM as parsed and fixed up: no junk in the header and
+    // no trailing data. NES 2.0 when the submapper or region needs it, iNES 1.0 otherwise.
+    pub fn to_ines(&self) -> Vec<u8> {
+        let nes2 = self.submapper != 0 || !matches!(self.region, Some(Region::Ntsc | Region::Pal));
+        let mut flags6 = self.mapper << 4;
+        flags6 |= match self.mirroring {
+            Mirroring::Vertical => 0x01,
+            Mirroring::FourScreen => 0x08,
+            _ => 0x00,
+        };
+        if self.has_battery {
+            flags6 |= 0x02;
+        }
+        if self.trainer.is_some() {
+            flags6 |= 0x04;
+        }
+        let mut flags7 = self.mapper & 0xF0;
+        if self.is_vs_system {
+            flags7 |= 0x01;
+        }
+        let mut header = [0; HEADER_SIZE];
+        header[..4].copy_from_slice(&NES_TAG);
+        header[4] = (self.prg_rom.len() / PRG_ROM_PAGE_SIZE) as u8;
+        header[5] = (self.chr_rom.len() / CHR_ROM_PAGE_SIZE) as u8;
+        if nes2 {
+            header[7] = flags7 | 0x08;
+            header[8] = self.submapper << 4;
+            // 8KB of PRG RAM, battery-backed or not, and of CHR RAM when there's no CHR ROM
+            header[10] = if self.has_battery { 0x70 } else { 0x07 };
+            header[11] = if self.chr_rom.is_empty() { 0x07 } else { 0x00 };
+            header[12] = match self.region {
+                Some(Region::Ntsc) => 0,
+                Some(Region::Pal) => 1,
+                None => 2,
+                Some(Region::Dendy) => 3,
+            };
+        } else {
+            header[7] = flags7;
+            header[9] = (self.region == Some(Region::Pal)) as u8;
+        }
+        header[6] = flags6;
+
+        let mut data = header.to_vec();
+        if let Some(trainer) = &self.trainer {
+            data.extend_from_slice(trainer);
+        }
+        data.extend_from_slice(&self.prg_rom);
+        data.extend_from_slice(&self.chr_rom);
+        data
+    }
+
+    // The pattern table memory, whether ROM or RAM
+    pub fn chr(&self) -> &[u8] {
+        if self.chr_ram.is_empty() {
+            &self.chr_rom
+        } else {
+            &self.chr_ram
+        }
+    }
+
+    pub fn read_chr(&self, address: u16) -> u8 {
+        let chr = self.chr();
+        if chr.is_empty() {
+            return 0;
+        }
+        chr[address as usize % chr.len()]
+    }
+
+    // Writes are dropped when the pattern tables are ROM
+    pub fn write_chr(&mut self, address: u16, value: u8) {
+        if !self.chr_ram.is_empty() {
+            let size = self.chr_ram.len();
+            self.chr_ram[address as usize % size] = value;
+        }
+    }
+
+    // One line for the load report, e.g. "Mapper 1 (MMC1), horizontal mirroring, battery, NTSC"
+    pub fn describe(&self) -> String {
+        let mut text = format!("Mapper {} ({})", self.mapper, mapper::name(self.mapper).unwrap_or("unsupported"));
+        text.push_str(match self.mirroring {
+            Mirroring::Horizontal => ", horizontal mirroring",
+            Mirroring::Vertical => ", vertical mirroring",
+            Mirroring::FourScreen => ", four-screen",
+            Mirroring::SingleScreenLow | Mirroring::SingleScreenHigh => ", single-screen",
+        });
+        if self.has_battery {
+            text.push_str(", battery");
+        }
+        if let Some(region) = self.region {
+            text.push_str(&format!(", {}", region));
+        }
+        text
+    }
+
+    // CRC-32 of the PRG and CHR ROM without the header, as used by ROM databases
+    // and to tell games apart whatever their file is called
+    pub fn crc32(&self) -> u32 {
+        hash::crc32(self.prg_rom.iter().chain(&self.chr_rom))
+    }
+
+    pub fn sha1(&self) -> [u8; 20] {
+        hash::sha1(&[self.prg_rom.as_slice(), &self.chr_rom].concat())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::nes::Nes;
+
+    // Builds an iNES image with the given header flags and zero-filled banks
+    pub(crate) fn ines(prg_pages: u8, chr_pages: u8, flags6: u8, flags7: u8) -> Vec<u8> {
+        let mut data = vec![0x4E, 0x45, 0x53, 0x1A, prg_pages, chr_pages, flags6, flags7];
+        data.resize(HEADER_SIZE, 0);
+        if flags6 & 0x04 != 0 {
+            data.resize(data.len() + TRAINER_SIZE, 0);
+        }
+        data.resize(data.len() + prg_pages as usize * PRG_ROM_PAGE_SIZE + chr_pages as usize * CHR_ROM_PAGE_SIZE, 0);
+        data
+    }
+
+    // An NROM image that runs `program` from $8000 at reset
+    pub(crate) fn ines_with_program(program: &[u8]) -> Vec<u8> {
+        let mut data = ines(1, 1, 0, 0);
+        data[HEADER_SIZE..HEADER_SIZE + program.len()].copy_from_slice(program);
+        data[HEADER_SIZE + 0x3FFC..HEADER_SIZE + 0x3FFE].copy_from_slice(&[0x00, 0x80]);
+        data
+    }
+
+    // A console with ines_with_program's cartridge in it
+    pub(crate) fn nes_with_program(program: &[u8]) -> Nes {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines_with_program(program)).unwrap()).unwrap();
+        nes
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let rom = Rom::new(&ines(2, 1, 0x13, 0x40)).unwrap();
+        assert_eq!(rom.prg_rom.len(), 2 * PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr_rom.len(), CHR_ROM_PAGE_SIZE);
+        assert_eq!(rom.mapper, 0x41);
+        assert_eq!(rom.mirroring, Mirroring::Vertical);
+        assert!(rom.has_battery);
+        assert_eq!(rom.submapper, 0);
+
+        let mut data = ines(1, 1, 0x10, 0x08);
+        data[8] = 0x30;
+        assert_eq!(Rom::new(&data).unwrap().submapper, 3);
+    }
+
+    #[test]
+    fn test_trainer() {
+        let mut data = ines(1, 0, 0x04, 0);
+        data[HEADER_SIZE] = 0x11;
+        data[HEADER_SIZE + TRAINER_SIZE] = 0x42;
+        let rom = Rom::new(&data).unwrap();
+        assert_eq!(rom.prg_rom[0], 0x42);
+        let trainer = rom.trainer.unwrap();
+        assert_eq!((trainer.len(), trainer[0]), (TRAINER_SIZE, 0x11));
+        assert!(Rom::new(&ines(1, 0, 0, 0)).unwrap().trainer.is_none());
+    }
+
+    #[test]
+    fn test_console_type() {
+        assert!(Rom::new(&ines(1, 1, 0, 0x01)).unwrap().is_vs_system);
+        assert!(Rom::new(&ines(1, 1, 0, 0x09)).unwrap().is_vs_system);
+        assert!(!Rom::new(&ines(1, 1, 0, 0)).unwrap().is_vs_system);
+        assert!(matches!(Rom::new(&ines(1, 1, 0, 0x02)), Err(RomError::PlayChoice10)));
+        assert!(matches!(Rom::new(&ines(1, 1, 0, 0x0A)), Err(RomError::PlayChoice10)));
+        // extended console types aren't PlayChoice
+        assert!(Rom::new(&ines(1, 1, 0, 0x0B)).is_ok());
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut rom = Rom::new(&ines(1, 0, 0, 0)).unwrap();
+        assert_eq!(rom.chr().len(), CHR_RAM_SIZE);
+        rom.write_chr(0x1234, 0x42);
+        assert_eq!(rom.read_chr(0x1234), 0x42);
+
+        let mut rom = Rom::new(&ines(1, 1, 0, 0)).unwrap();
+        assert!(rom.chr_ram.is_empty());
+        rom.write_chr(0x1234, 0x42);
+        assert_eq!(rom.read_chr(0x1234), 0);
+    }
+
+    #[test]
+    fn test_hashes() {
+        let mut rom = Rom::new(&ines(1, 0, 0, 0)).unwrap();
+        rom.prg_rom = b"12345".to_vec();
+        rom.chr_rom = b"6789".to_vec();
+        assert_eq!(rom.crc32(), 0xCBF4_3926);
+        assert_eq!(rom.sha1(), hash::sha1(b"123456789"));
+    }
+
+    #[test]
+    fn test_header_warnings() {
+        let rom = Rom::new(&ines(1, 1, 0x10, 0x20)).unwrap();
+        assert!(rom.warnings.is_empty());
+        assert_eq!(rom.region, Some(Region::Ntsc));
+
+        let mut data = ines(1, 1, 0x19, 0x20);
+        data[12..16].copy_from_slice(b"Dude");
+        data.extend_from_slice(&[0; 128]);
+        let rom = Rom::new(&data).unwrap();
+        assert_eq!(rom.mapper, 1);
+        assert_eq!(rom.region, None);
+        let declared = data.len() - 128;
+        assert_eq!(
+            rom.warnings,
+            vec![
+                HeaderWarning::TrailingData { declared, actual: data.len() },
+                HeaderWarning::DirtyHeader,
+                HeaderWarning::ConflictingMirroring
+            ]
+        );
+
+        let mut data = ines(1, 1, 0x02, 0x08);
+        assert_eq!(Rom::new(&data).unwrap().warnings, vec![HeaderWarning::BatteryWithoutNvram]);
+        data[10] = 0x70;
+        assert!(Rom::new(&data).unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn test_to_ines() {
+        // a dirty header with trailing data comes back clean, and the database's fix sticks
+        let mut data = ines(2, 1, 0x19, 0x20);
+        data[12..16].copy_from_slice(b"Dude");
+        data[16] = 0xA9;
+        data.extend_from_slice(&[0; 128]);
+        let mut rom = Rom::new(&data).unwrap();
+        rom.region = Some(Region::Pal);
+        rom.mirroring = Mirroring::Vertical;
+        let fixed = rom.to_ines();
+        assert_eq!(fixed[..16], [0x4E, 0x45, 0x53, 0x1A, 2, 1, 0x11, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(fixed.len(), data.len() - 128);
+        let again = Rom::new(&fixed).unwrap();
+        assert!(again.warnings.is_empty());
+        assert_eq!((again.mapper, again.mirroring, again.region), (1, Mirroring::Vertical, Some(Region::Pal)));
+        assert_eq!(again.crc32(), rom.crc32());
+
+        // a submapper, battery and Dendy need NES 2.0
+        rom.submapper = 3;
+        rom.has_battery = true;
+        rom.region = Some(Region::Dendy);
+        let again = Rom::new(&rom.to_ines()).unwrap();
+        assert!(again.warnings.is_empty());
+        assert_eq!((again.submapper, again.has_battery, again.region), (3, true, Some(Region::Dendy)));
+
+        let mut data = ines(1, 0, 0x04, 0);
+        data[16] = 0x42;
+        let rom = Rom::new(&data).unwrap();
+        assert_eq!(rom.to_ines(), data);
+    }
+
+    #[test]
+    fn test_region() {
+        let mut data = ines(1, 1, 0, 0);
+        data[9] = 0x01;
+        assert_eq!(Rom::new(&data).unwrap().region, Some(Region::Pal));
+
+        let mut data = ines(1, 1, 0, 0x08);
+        data[12] = 0x03;
+        assert_eq!(Rom::new(&data).unwrap().region, Some(Region::Dendy));
+        data[12] = 0x02;
+        assert_eq!(Rom::new(&data).unwrap().region, None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let rom = Rom::new(&ines(1, 1, 0x03, 0)).unwrap();
+        assert_eq!(rom.describe(), "Mapper 0 (NROM), vertical mirroring, battery, NTSC");
+    }
+
+    #[test]
+    fn test_invalid_tag() {
+        let mut data = ines(1, 0, 0, 0);
+        data[3] = 0;
+        assert!(matches!(Rom::new(&data), Err(RomError::InvalidTag)));
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut data = ines(1, 1, 0, 0);
+        data.truncate(HEADER_SIZE + 100);
+        assert!(matches!(Rom::new(&data), Err(RomError::Truncated { .. })));
+    }
+
+    #[test]
+    fn test_no_prg_rom() {
+        // not mistaken for a truncated file, however much follows the header
+        assert!(matches!(Rom::new(&ines(0, 1, 0, 0)), Err(RomError::NoPrgRom)));
+        assert!(matches!(Rom::new(&ines(0, 0, 0, 0)), Err(RomError::NoPrgRom)));
+    }
+}
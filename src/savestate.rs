@@ -0,0 +1,232 @@
+use std::fmt;
+
+use crate::cpu::{Bus, Cpu, FlatBus};
+
+const MAGIC: [u8; 4] = *b"MNES";
+const VERSION: u8 = 10;
+// magic, version and the ROM's CRC-32
+const HEADER_SIZE: usize = 9;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    InvalidHeader,
+    UnsupportedVersion(u8),
+    Truncated,
+    // a value in the state that the console it's loaded into can't take
+    Invalid,
+    // made with another game inserted
+    WrongRom,
+    // bytes left over after the whole machine was read
+    TrailingData,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidHeader => write!(f, "Not a madNES savestate"),
+            StateError::UnsupportedVersion(version) => write!(f, "Unsupported savestate version {}", version),
+            StateError::Truncated => write!(f, "Savestate is truncated"),
+            StateError::Invalid => write!(f, "Savestate is corrupt"),
+            StateError::WrongRom => write!(f, "Savestate is for a different game"),
+            StateError::TrailingData => write!(f, "Savestate has data past its end"),
+        }
+    }
+}
+
+// Components serialize themselves into a flat byte stream, in a fixed order
+pub trait SaveState {
+    fn save_state(&self, out: &mut Vec<u8>);
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError>;
+
+    // The CRC-32 of the inserted ROM, which states are only loaded back into.
+    // 0 when there's nothing to tell games apart by.
+    fn rom_crc32(&self) -> u32 {
+        0
+    }
+}
+
+// Cursor over a serialized state
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < count {
+            return Err(StateError::Truncated);
+        }
+        let (bytes, rest) = self.data.split_at(count);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+}
+
+impl SaveState for FlatBus {
+    fn save_state(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.memory);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let memory = input.read_bytes(self.memory.len())?;
+        self.memory.copy_from_slice(memory);
+        Ok(())
+    }
+}
+
+// Serializes the machine with a versioned header
+pub fn save<B: Bus + SaveState>(cpu: &Cpu<B>) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.push(VERSION);
+    out.extend_from_slice(&cpu.rom_crc32().to_le_bytes());
+    cpu.save_state(&mut out);
+    out
+}
+
+// Either loads the whole state or leaves the machine as it was. Components load
+// field by field, so a state that turns out bad partway through is undone by
+// loading a copy of the machine taken first.
+pub fn load<B: Bus + SaveState>(cpu: &mut Cpu<B>, data: &[u8]) -> Result<(), StateError> {
+    let mut input = StateReader::new(data);
+    if input.read_bytes(MAGIC.len()).map_err(|_| StateError::InvalidHeader)? != MAGIC {
+        return Err(StateError::InvalidHeader);
+    }
+    match input.read_u8()? {
+        VERSION => {}
+        version => return Err(StateError::UnsupportedVersion(version)),
+    }
+    if input.read_u32()? != cpu.rom_crc32() {
+        return Err(StateError::WrongRom);
+    }
+    let backup = save(cpu);
+    let result = cpu.load_state(&mut input).and_then(|_| match input.is_empty() {
+        true => Ok(()),
+        false => Err(StateError::TrailingData),
+    });
+    if result.is_err() {
+        cpu.load_state(&mut StateReader::new(&backup[HEADER_SIZE..])).expect("reloading a state just saved");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{CpuFault, Interrupt, IrqSource, Memory};
+    use crate::nes::Nes;
+    use crate::rom::tests::ines;
+    use crate::rom::Rom;
+
+    #[test]
+    fn test_save_load_roundtrip() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xA9, 0x42, 0xAA], 0x8000);
+        cpu.reset();
+        cpu.step();
+        let state = save(&cpu);
+
+        cpu.step();
+        cpu.write_byte(0x10, 0xFF);
+        load(&mut cpu, &state).unwrap();
+
+        assert_eq!(cpu.a, 0x42);
+        assert_eq!(cpu.x, 0);
+        assert_eq!(cpu.pc, 0x8002);
+        assert_eq!(cpu.cycles, 2);
+        assert_eq!(cpu.read_byte(0x10), 0);
+    }
+
+    #[test]
+    fn test_interrupt_state() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xEA; 4], 0x8000);
+        cpu.write_word(0xFFFA, 0x9000);
+        cpu.reset();
+        cpu.set_nmi(true);
+        cpu.set_irq(IrqSource::Mapper, true);
+        cpu.step();
+        let state = save(&cpu);
+
+        // taken, then loaded back from before it was
+        cpu.step();
+        cpu.set_nmi(false);
+        cpu.set_irq(IrqSource::Mapper, false);
+        load(&mut cpu, &state).unwrap();
+        assert_eq!(cpu.pending_interrupt(), Some(Interrupt::Nmi));
+        assert_eq!(cpu.irq_lines(), IrqSource::Mapper);
+        cpu.step();
+        assert_eq!(cpu.pc, 0x9000);
+
+        cpu.fault = Some(CpuFault::UnknownOpcode { pc: 0x9000, opcode: 0x02 });
+        let state = save(&cpu);
+        cpu.fault = None;
+        load(&mut cpu, &state).unwrap();
+        assert_eq!(cpu.fault, Some(CpuFault::UnknownOpcode { pc: 0x9000, opcode: 0x02 }));
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let mut cpu = Cpu::new();
+        assert_eq!(load(&mut cpu, b"NES"), Err(StateError::InvalidHeader));
+        assert_eq!(load(&mut cpu, b"MNES\x0B"), Err(StateError::UnsupportedVersion(11)));
+        let state = save(&cpu);
+        assert_eq!(load(&mut cpu, &state[..100]), Err(StateError::Truncated));
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(load(&mut cpu, &longer), Err(StateError::TrailingData));
+    }
+
+    #[test]
+    fn test_load_is_all_or_nothing() {
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        nes.poke(0x0010, 0x11);
+        let state = save(nes.cpu());
+        nes.poke(0x0010, 0x22);
+        nes.step_frame();
+        let before = save(nes.cpu());
+
+        // the CPU and RAM are read before the PPU fails, and put back after
+        assert_eq!(load(nes.cpu_mut(), &state[..state.len() - 10]), Err(StateError::Truncated));
+        assert_eq!(save(nes.cpu()), before);
+        assert_eq!(nes.peek(0x0010), 0x22);
+        load(nes.cpu_mut(), &state).unwrap();
+        assert_eq!(nes.peek(0x0010), 0x11);
+
+        // another game with the same board has the same layout, but not the same ROM
+        let mut data = ines(1, 1, 0, 0);
+        data[16] = 0xEA;
+        let mut other = Nes::new();
+        other.insert_cartridge(Rom::new(&data).unwrap()).unwrap();
+        assert_eq!(load(other.cpu_mut(), &state), Err(StateError::WrongRom));
+    }
+}
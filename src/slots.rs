@@ -0,0 +1,133 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use crate::config::Config;
+use crate::rom::Rom;
+
+// Numbered 1 to 10, for F1 to F10
+pub const SLOT_COUNT: usize = 10;
+
+// F1..F10 load a slot, Shift+F1..F10 save to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlotAction {
+    Save(usize),
+    Load(usize),
+}
+
+impl SlotAction {
+    pub fn from_key(key: &str, shift: bool) -> Option<SlotAction> {
+        let slot: usize = key.strip_prefix('F')?.parse().ok()?;
+        if !(1..=SLOT_COUNT).contains(&slot) {
+            return None;
+        }
+        Some(if shift { SlotAction::Save(slot) } else { SlotAction::Load(slot) })
+    }
+}
+
+// What the slot list shows for one slot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotInfo {
+    pub slot: usize,
+    pub path: PathBuf,
+    // None when nothing is saved there
+    pub modified: Option<SystemTime>,
+}
+
+impl SlotInfo {
+    pub fn label(&self, now: SystemTime) -> String {
+        match self.modified {
+            Some(modified) => {
+                let age = now.duration_since(modified).unwrap_or(Duration::ZERO).as_secs();
+                let age = match age {
+                    0..=59 => format!("{}s", age),
+                    60..=3599 => format!("{}m", age / 60),
+                    3600..=86399 => format!("{}h", age / 3600),
+                    _ => format!("{}d", age / 86400),
+                };
+                format!("{:>2}  saved {} ago", self.slot, age)
+            }
+            None => format!("{:>2}  empty", self.slot),
+        }
+    }
+}
+
+// The savestate slots of one game. They live in a directory named after the
+// ROM's CRC, so renaming or zipping the file keeps its states.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StateSlots {
+    pub directory: PathBuf,
+}
+
+impl StateSlots {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        StateSlots { directory: directory.into() }
+    }
+
+    // <config directory>/states/<crc>
+    pub fn for_rom(rom: &Rom) -> Option<StateSlots> {
+        Some(StateSlots::new(Config::directory()?.join("states").join(format!("{:08X}", rom.crc32()))))
+    }
+
+    pub fn path(&self, slot: usize) -> PathBuf {
+        self.directory.join(format!("slot{}.state", slot))
+    }
+
+    pub fn save(&self, slot: usize, state: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.path(slot), state)
+    }
+
+    pub fn load(&self, slot: usize) -> io::Result<Vec<u8>> {
+        fs::read(self.path(slot))
+    }
+
+    pub fn list(&self) -> Vec<SlotInfo> {
+        (1..=SLOT_COUNT)
+            .map(|slot| {
+                let path = self.path(slot);
+                let modified = fs::metadata(&path).and_then(|metadata| metadata.modified()).ok();
+                SlotInfo { slot, path, modified }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nes::Nes;
+    use crate::rom::tests::ines;
+    use crate::savestate;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_slot_keys() {
+        assert_eq!(SlotAction::from_key("F3", true), Some(SlotAction::Save(3)));
+        assert_eq!(SlotAction::from_key("F10", false), Some(SlotAction::Load(10)));
+        assert_eq!(SlotAction::from_key("F11", false), None);
+        assert_eq!(SlotAction::from_key("3", false), None);
+    }
+
+    #[test]
+    fn test_save_and_load_slot() {
+        let directory = TestDir::new("slots");
+        let slots = StateSlots::new(directory.path());
+        let mut nes = Nes::new();
+        nes.insert_cartridge(Rom::new(&ines(1, 1, 0, 0)).unwrap()).unwrap();
+        nes.poke(0x0010, 0x42);
+        slots.save(3, &savestate::save(nes.cpu())).unwrap();
+
+        nes.poke(0x0010, 0x00);
+        savestate::load(nes.cpu_mut(), &slots.load(3).unwrap()).unwrap();
+        assert_eq!(nes.peek(0x0010), 0x42);
+
+        let list = slots.list();
+        assert_eq!(list.len(), SLOT_COUNT);
+        assert!(list[2].modified.is_some() && list[0].modified.is_none());
+        assert_eq!(list[0].label(SystemTime::now()), " 1  empty");
+        let modified = list[2].modified.unwrap();
+        assert_eq!(list[2].label(modified + Duration::from_secs(125)), " 3  saved 2m ago");
+    }
+}
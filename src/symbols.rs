@@ -0,0 +1,163 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::debugger::parse_number;
+
+// FCEUX numbers its per-bank .nl files by 16KB PRG bank
+const MAX_FCEUX_BANKS: usize = 64;
+
+// Address to name, from cc65 debug info or FCEUX name lists
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SymbolTable {
+    pub names: BTreeMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    // Keeps the first name given to an address
+    pub fn insert(&mut self, address: u16, name: impl Into<String>) {
+        self.names.entry(address).or_insert_with(|| name.into());
+    }
+
+    pub fn get(&self, address: u16) -> Option<&str> {
+        self.names.get(&address).map(String::as_str)
+    }
+
+    pub fn address_of(&self, name: &str) -> Option<u16> {
+        self.names.iter().find(|(_, symbol)| *symbol == name).map(|(&address, _)| address)
+    }
+
+    // A symbol name or a number, for debugger commands
+    pub fn resolve(&self, text: &str) -> Option<u16> {
+        self.address_of(text).or_else(|| parse_number(text))
+    }
+
+    pub fn merge(&mut self, other: SymbolTable) {
+        for (address, name) in other.names {
+            self.insert(address, name);
+        }
+    }
+
+    // FCEUX .nl: one `$C000#name#comment` per line, other lines are ignored
+    pub fn parse_fceux(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let mut fields = line.trim().splitn(3, '#');
+            let (Some(address), Some(name)) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            if let (Some(address), false) = (address.strip_prefix('$').and_then(|hex| u16::from_str_radix(hex, 16).ok()), name.is_empty()) {
+                table.insert(address, name);
+            }
+        }
+        table
+    }
+
+    // cc65 .dbg: the `sym` lines of ld65's --dbgfile output, e.g.
+    // sym	id=0,name="reset",addrsize=absolute,scope=0,def=1,val=0xC000,seg=0,type=lab
+    pub fn parse_cc65(text: &str) -> SymbolTable {
+        let mut table = SymbolTable::new();
+        for line in text.lines() {
+            let Some(fields) = line.strip_prefix("sym\t") else {
+                continue;
+            };
+            let mut name = None;
+            let mut value = None;
+            for field in fields.split(',') {
+                match field.split_once('=') {
+                    Some(("name", quoted)) => name = Some(quoted.trim_matches('"')),
+                    Some(("val", number)) => value = parse_number(number),
+                    // imports only repeat a symbol defined elsewhere
+                    Some(("type", "imp")) => value = None,
+                    _ => {}
+                }
+            }
+            if let (Some(name), Some(value)) = (name, value) {
+                table.insert(value, name);
+            }
+        }
+        table
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<SymbolTable> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        Ok(match path.extension().and_then(|extension| extension.to_str()) {
+            Some("dbg") => SymbolTable::parse_cc65(&text),
+            _ => SymbolTable::parse_fceux(&text),
+        })
+    }
+
+    // The symbol files found next to a ROM: game.dbg or game.nes.dbg from cc65,
+    // game.nes.ram.nl and game.nes.<bank>.nl from FCEUX
+    pub fn candidate_paths(rom: &Path) -> Vec<PathBuf> {
+        let file_name = rom.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        let beside = |suffix: String| rom.with_file_name(format!("{}{}", file_name, suffix));
+        let mut paths = vec![rom.with_extension("dbg"), beside(".dbg".to_string()), beside(".ram.nl".to_string())];
+        paths.extend((0..MAX_FCEUX_BANKS).map(|bank| beside(format!(".{:X}.nl", bank))));
+        paths
+    }
+
+    // Loads and merges every symbol file next to the ROM, None when there are none
+    pub fn find_for_rom(rom: impl AsRef<Path>) -> io::Result<Option<SymbolTable>> {
+        let mut table = SymbolTable::new();
+        let mut found = false;
+        for path in SymbolTable::candidate_paths(rom.as_ref()) {
+            if path.is_file() {
+                table.merge(SymbolTable::load(&path)?);
+                found = true;
+            }
+        }
+        Ok(found.then_some(table))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    #[test]
+    fn test_parse_fceux() {
+        let table = SymbolTable::parse_fceux("$C000#reset#entry point\n$0010#player_x#\nnot a symbol\n$00FF##\n");
+        assert_eq!(table.get(0xC000), Some("reset"));
+        assert_eq!(table.get(0x0010), Some("player_x"));
+        assert_eq!(table.get(0x00FF), None);
+        assert_eq!(table.names.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_cc65() {
+        let text = "version\tmajor=2,minor=0\n\
+            sym\tid=0,name=\"nmi_handler\",addrsize=absolute,scope=0,def=3,val=0xC123,seg=0,type=lab\n\
+            sym\tid=1,name=\"frame\",addrsize=zeropage,scope=0,def=4,val=0x2,type=equ\n\
+            sym\tid=2,name=\"nmi_handler\",addrsize=absolute,scope=1,ref=5,type=imp\n";
+        let table = SymbolTable::parse_cc65(text);
+        assert_eq!(table.get(0xC123), Some("nmi_handler"));
+        assert_eq!(table.get(0x0002), Some("frame"));
+        assert_eq!(table.resolve("frame"), Some(2));
+        assert_eq!(table.resolve("$C000"), Some(0xC000));
+        assert_eq!(table.resolve("nowhere"), None);
+    }
+
+    #[test]
+    fn test_find_for_rom() {
+        let dir = TestDir::new("symbols");
+        let rom = dir.join("game.nes");
+        assert_eq!(SymbolTable::find_for_rom(&rom).unwrap(), None);
+
+        fs::write(dir.join("game.nes.ram.nl"), "$0300#oam#\n").unwrap();
+        fs::write(dir.join("game.nes.1.nl"), "$C000#reset#\n").unwrap();
+        let table = SymbolTable::find_for_rom(&rom).unwrap().unwrap();
+        assert_eq!((table.get(0x0300), table.get(0xC000)), (Some("oam"), Some("reset")));
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+use crate::palette::Rgb;
+
+// Keeps rendered text between frames, keyed by content and color. Debug and OSD
+// text is mostly the same from one frame to the next, so re-rasterizing every
+// string each frame is wasted work. `T` is whatever the frontend draws with, e.g.
+// a texture. Entries not drawn for a while are dropped so changing values like
+// register dumps don't pile up.
+pub struct TextCache<T> {
+    entries: HashMap<(String, Rgb), Entry<T>>,
+    frame: u64,
+    // frames an entry survives without being drawn
+    max_age: u64,
+}
+
+struct Entry<T> {
+    value: T,
+    last_used: u64,
+}
+
+impl<T> TextCache<T> {
+    pub fn new(max_age: u64) -> Self {
+        TextCache { entries: HashMap::new(), frame: 0, max_age }
+    }
+
+    // The cached rendering of `text`, calling `render` only when there is none
+    pub fn get_or_render(&mut self, text: &str, color: Rgb, render: impl FnOnce(&str, Rgb) -> T) -> &T {
+        let frame = self.frame;
+        let key = (text.to_string(), color);
+        let entry = self
+            .entries
+            .entry(key)
+            .or_insert_with(|| Entry { value: render(text, color), last_used: frame });
+        entry.last_used = frame;
+        &entry.value
+    }
+
+    // Called once per frame after drawing; returns the entries dropped, e.g. for
+    // freeing textures explicitly
+    pub fn end_frame(&mut self) -> Vec<T> {
+        let expired: Vec<(String, Rgb)> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| self.frame - entry.last_used > self.max_age)
+            .map(|(key, _)| key.clone())
+            .collect();
+        self.frame += 1;
+        expired.iter().filter_map(|key| self.entries.remove(key)).map(|entry| entry.value).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    // For when the font or scale changes and every rendering is stale
+    pub fn clear(&mut self) -> Vec<T> {
+        self.entries.drain().map(|(_, entry)| entry.value).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renders_once() {
+        let mut cache = TextCache::new(2);
+        let mut renders = 0;
+        let white = (0xFF, 0xFF, 0xFF);
+        for _ in 0..3 {
+            let width = *cache.get_or_render("PC $8000", white, |text, _| {
+                renders += 1;
+                text.len()
+            });
+            assert_eq!(width, 8);
+            cache.end_frame();
+        }
+        assert_eq!(renders, 1);
+        // a different color is a different rendering
+        cache.get_or_render("PC $8000", (0xFF, 0, 0), |text, _| text.len());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn test_unused_entries_expire() {
+        let mut cache = TextCache::new(1);
+        let white = (0xFF, 0xFF, 0xFF);
+        cache.get_or_render("A=$01", white, |_, _| 1);
+        cache.get_or_render("X=$00", white, |_, _| 2);
+        assert!(cache.end_frame().is_empty());
+        cache.get_or_render("X=$00", white, |_, _| 2);
+        assert!(cache.end_frame().is_empty());
+        assert_eq!(cache.end_frame(), vec![1]);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.clear(), vec![2]);
+        assert!(cache.is_empty());
+    }
+}
@@ -0,0 +1,448 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioThread;
+
+// NTSC frame rate: 39375000 / 655171 Hz
+pub const NTSC_FRAME_DURATION: Duration = Duration::from_nanos(16_639_267);
+
+// Audio sync keeps the sound device's buffer about this full
+const AUDIO_TARGET_FILL: f32 = 0.5;
+const AUDIO_POLL: Duration = Duration::from_micros(500);
+
+// What decides when the next frame runs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    // sleeping to the NTSC frame rate
+    #[default]
+    Timer,
+    // the sound device using up what was queued, so audio never starves or piles
+    // up and can't crackle. Falls back to the timer while audio is muted.
+    Audio,
+    // presenting with vsync, which blocks until the display is ready. Audio is
+    // resampled to match, see Resampler::corrected_ratio.
+    Video,
+}
+
+impl SyncMode {
+    pub fn parse(name: &str) -> Option<SyncMode> {
+        match name.to_ascii_lowercase().as_str() {
+            "timer" => Some(SyncMode::Timer),
+            "audio" => Some(SyncMode::Audio),
+            "video" | "vsync" => Some(SyncMode::Video),
+            _ => None,
+        }
+    }
+}
+
+// Paces emulated frames against the wall clock.
+// Runs at a speed multiplier of real time, uncapped while turbo is held, or not at all while paused.
+pub struct FrameLimiter {
+    pub speed: f32,
+    pub turbo: bool,
+    pub paused: bool,
+    // what to do while the window is in the background, from the config
+    pub pause_on_focus_loss: bool,
+    pub mute_on_focus_loss: bool,
+    pub sync: SyncMode,
+    advance: Option<Advance>,
+    next_frame: Option<Instant>,
+    focused: bool,
+    minimized: bool,
+    // the pause came from losing focus, so getting it back lifts it
+    focus_paused: bool,
+}
+
+// Window manager events the limiter cares about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowEvent {
+    FocusLost,
+    FocusGained,
+    Minimized,
+    Restored,
+}
+
+// How far to run while paused when the frame (F) or scanline advance key is pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Advance {
+    Frame,
+    Scanline,
+}
+
+impl FrameLimiter {
+    pub fn new(speed: f32) -> Self {
+        FrameLimiter {
+            speed,
+            turbo: false,
+            paused: false,
+            pause_on_focus_loss: false,
+            mute_on_focus_loss: false,
+            sync: SyncMode::Timer,
+            advance: None,
+            next_frame: None,
+            focused: true,
+            minimized: false,
+            focus_paused: false,
+        }
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+        self.focus_paused = false;
+        // don't try to catch up on the time spent paused
+        self.next_frame = None;
+    }
+
+    // Pauses if needed and queues a single step
+    pub fn request_advance(&mut self, advance: Advance) {
+        self.paused = true;
+        self.advance = Some(advance);
+    }
+
+    // The step to run this iteration of the paused main loop, if any
+    pub fn take_advance(&mut self) -> Option<Advance> {
+        self.advance.take()
+    }
+
+    // A pause from losing focus only lasts until focus comes back, unless the
+    // player paused or unpaused in between
+    pub fn window_event(&mut self, event: WindowEvent) {
+        match event {
+            WindowEvent::FocusLost => {
+                self.focused = false;
+                if self.pause_on_focus_loss && !self.paused {
+                    self.paused = true;
+                    self.focus_paused = true;
+                }
+            }
+            WindowEvent::FocusGained => {
+                self.focused = true;
+                if std::mem::take(&mut self.focus_paused) {
+                    self.paused = false;
+                    self.next_frame = None;
+                }
+            }
+            WindowEvent::Minimized => self.minimized = true,
+            WindowEvent::Restored => self.minimized = false,
+        }
+    }
+
+    // Nothing is drawn or presented while minimized. Emulation keeps being paced by
+    // frame_delay, so the loop still wakes every frame to handle events.
+    pub fn should_render(&self) -> bool {
+        !self.minimized
+    }
+
+    pub fn set_turbo(&mut self, turbo: bool) {
+        if self.turbo && !turbo {
+            self.next_frame = None;
+        }
+        self.turbo = turbo;
+    }
+
+    pub fn is_uncapped(&self) -> bool {
+        self.turbo || self.speed <= 0.0
+    }
+
+    // Audio can't keep up with anything but real time, so it is muted instead of pitched
+    pub fn mute_audio(&self) -> bool {
+        self.paused
+            || self.is_uncapped()
+            || (self.speed - 1.0).abs() > f32::EPSILON
+            || (self.mute_on_focus_loss && !self.focused)
+    }
+
+    pub fn frame_duration(&self) -> Duration {
+        NTSC_FRAME_DURATION.div_f64(self.speed as f64)
+    }
+
+    // Returns how long to wait at `now` before starting the next frame,
+    // and schedules the frame after it
+    pub fn frame_delay(&mut self, now: Instant) -> Duration {
+        if self.paused || self.is_uncapped() || self.sync == SyncMode::Video {
+            return Duration::ZERO;
+        }
+        let frame_duration = self.frame_duration();
+        let deadline = match self.next_frame {
+            // more than a frame behind, resynchronize instead of running fast to catch up
+            Some(deadline) if now.saturating_duration_since(deadline) <= frame_duration => deadline,
+            _ => now,
+        };
+        self.next_frame = Some(deadline + frame_duration);
+        deadline.saturating_duration_since(now)
+    }
+
+    // With audio sync, whether the sound device still has more queued than it needs
+    // and the next frame should wait. `fill` is how full its buffer is, 0.0-1.0.
+    pub fn audio_ahead(&self, fill: f32) -> bool {
+        self.sync == SyncMode::Audio && !self.mute_audio() && fill > AUDIO_TARGET_FILL
+    }
+
+    // Blocks until the next frame is due
+    pub fn wait(&mut self, audio: Option<&AudioThread>) {
+        match audio {
+            Some(audio) if self.sync == SyncMode::Audio && !self.mute_audio() => {
+                while self.audio_ahead(audio.fill()) {
+                    thread::sleep(AUDIO_POLL);
+                }
+            }
+            _ => {
+                let delay = self.frame_delay(Instant::now());
+                if !delay.is_zero() {
+                    thread::sleep(delay);
+                }
+            }
+        }
+    }
+}
+
+// Frames the profiler averages over
+const PROFILE_FRAMES: usize = 60;
+
+// Parts of a frame the profiler times separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Cpu,
+    Ppu,
+    Apu,
+    Render,
+    Audio,
+}
+
+impl Subsystem {
+    pub const ALL: [Subsystem; 5] = [Subsystem::Cpu, Subsystem::Ppu, Subsystem::Apu, Subsystem::Render, Subsystem::Audio];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Subsystem::Cpu => "cpu",
+            Subsystem::Ppu => "ppu",
+            Subsystem::Apu => "apu",
+            Subsystem::Render => "render",
+            Subsystem::Audio => "audio",
+        }
+    }
+}
+
+// Frame rates and per-subsystem frame times over the last PROFILE_FRAMES frames.
+// Timing costs a couple of clock reads per section, so it only runs when enabled.
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    pub enabled: bool,
+    current: [Duration; 5],
+    frames: VecDeque<[Duration; 5]>,
+    // when each frame was presented and how many emulated frames had run by then
+    presented: VecDeque<(Instant, u64)>,
+}
+
+impl Profiler {
+    pub fn new(enabled: bool) -> Self {
+        Profiler { enabled, ..Profiler::default() }
+    }
+
+    // Runs `f`, adding its time to the subsystem when profiling
+    pub fn measure<T>(&mut self, subsystem: Subsystem, f: impl FnOnce() -> T) -> T {
+        if !self.enabled {
+            return f();
+        }
+        let start = Instant::now();
+        let result = f();
+        self.add(subsystem, start.elapsed());
+        result
+    }
+
+    pub fn add(&mut self, subsystem: Subsystem, duration: Duration) {
+        self.current[subsystem as usize] += duration;
+    }
+
+    // Closes the frame presented at `now`, after `emulated_frames` frames of emulation.
+    // Paused or fast forwarding, the two counts differ.
+    pub fn end_frame(&mut self, now: Instant, emulated_frames: u64) {
+        if !self.enabled {
+            return;
+        }
+        if self.frames.len() == PROFILE_FRAMES {
+            self.frames.pop_front();
+            self.presented.pop_front();
+        }
+        self.frames.push_back(std::mem::take(&mut self.current));
+        self.presented.push_back((now, emulated_frames));
+    }
+
+    fn rate(&self, count: impl Fn(&(Instant, u64), &(Instant, u64)) -> f64) -> f64 {
+        match (self.presented.front(), self.presented.back()) {
+            (Some(first), Some(last)) if last.0 > first.0 => count(first, last) / (last.0 - first.0).as_secs_f64(),
+            _ => 0.0,
+        }
+    }
+
+    // Frames shown per second
+    pub fn real_fps(&self) -> f64 {
+        self.rate(|_, _| (self.presented.len() - 1) as f64)
+    }
+
+    // Frames emulated per second
+    pub fn emulated_fps(&self) -> f64 {
+        self.rate(|first, last| last.1.saturating_sub(first.1) as f64)
+    }
+
+    pub fn average(&self, subsystem: Subsystem) -> Duration {
+        let total: Duration = self.frames.iter().map(|frame| frame[subsystem as usize]).sum();
+        total / self.frames.len().max(1) as u32
+    }
+
+    // Text for the profiler overlay
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![format!("{:.1} fps ({:.1} emulated)", self.real_fps(), self.emulated_fps())];
+        lines.extend(Subsystem::ALL.iter().map(|subsystem| {
+            format!("{:<6} {:.2}ms", subsystem.name(), self.average(*subsystem).as_secs_f64() * 1000.0)
+        }));
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_delay_keeps_schedule() {
+        let mut limiter = FrameLimiter::new(1.0);
+        let start = Instant::now();
+        assert_eq!(limiter.frame_delay(start), Duration::ZERO);
+
+        // the frame took 5ms, wait for the rest of it
+        let now = start + Duration::from_millis(5);
+        assert_eq!(limiter.frame_delay(now), limiter.frame_duration() - Duration::from_millis(5));
+    }
+
+    #[test]
+    fn test_sync_modes() {
+        assert_eq!(SyncMode::parse("VSync"), Some(SyncMode::Video));
+        assert_eq!(SyncMode::parse("audio"), Some(SyncMode::Audio));
+        assert_eq!(SyncMode::parse("gsync"), None);
+
+        let mut limiter = FrameLimiter::new(1.0);
+        limiter.sync = SyncMode::Video;
+        let start = Instant::now();
+        limiter.frame_delay(start);
+        assert_eq!(limiter.frame_delay(start), Duration::ZERO);
+
+        limiter.sync = SyncMode::Audio;
+        assert!(limiter.audio_ahead(0.75));
+        assert!(!limiter.audio_ahead(0.25));
+        // muted audio doesn't drain, so it can't pace anything
+        limiter.speed = 2.0;
+        assert!(!limiter.audio_ahead(0.75));
+    }
+
+    #[test]
+    fn test_speed_multiplier() {
+        let mut limiter = FrameLimiter::new(2.0);
+        let start = Instant::now();
+        limiter.frame_delay(start);
+        let delay = limiter.frame_delay(start);
+        assert!(delay.abs_diff(NTSC_FRAME_DURATION / 2) < Duration::from_micros(1));
+        assert!(limiter.mute_audio());
+    }
+
+    #[test]
+    fn test_resync_when_far_behind() {
+        let mut limiter = FrameLimiter::new(1.0);
+        let start = Instant::now();
+        limiter.frame_delay(start);
+        let late = start + NTSC_FRAME_DURATION * 5;
+        assert_eq!(limiter.frame_delay(late), Duration::ZERO);
+        assert_eq!(limiter.frame_delay(late), limiter.frame_duration());
+    }
+
+    #[test]
+    fn test_turbo_and_pause_are_uncapped() {
+        let mut limiter = FrameLimiter::new(1.0);
+        let start = Instant::now();
+        assert!(!limiter.mute_audio());
+
+        limiter.set_turbo(true);
+        assert_eq!(limiter.frame_delay(start), Duration::ZERO);
+        assert_eq!(limiter.frame_delay(start), Duration::ZERO);
+        assert!(limiter.mute_audio());
+        limiter.set_turbo(false);
+
+        limiter.toggle_pause();
+        assert!(limiter.paused);
+        assert!(limiter.mute_audio());
+        limiter.toggle_pause();
+        assert_eq!(limiter.frame_delay(start), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_advance_pauses() {
+        let mut limiter = FrameLimiter::new(1.0);
+        assert_eq!(limiter.take_advance(), None);
+        limiter.request_advance(Advance::Frame);
+        assert!(limiter.paused);
+        assert_eq!(limiter.take_advance(), Some(Advance::Frame));
+        assert_eq!(limiter.take_advance(), None);
+    }
+
+    #[test]
+    fn test_focus_loss() {
+        let mut limiter = FrameLimiter::new(1.0);
+        limiter.window_event(WindowEvent::FocusLost);
+        assert!(!limiter.paused && !limiter.mute_audio());
+        limiter.window_event(WindowEvent::FocusGained);
+
+        limiter.pause_on_focus_loss = true;
+        limiter.window_event(WindowEvent::FocusLost);
+        assert!(limiter.paused && limiter.mute_audio());
+        limiter.window_event(WindowEvent::FocusGained);
+        assert!(!limiter.paused);
+
+        // a pause the player chose outlasts the focus change
+        limiter.toggle_pause();
+        limiter.window_event(WindowEvent::FocusLost);
+        limiter.window_event(WindowEvent::FocusGained);
+        assert!(limiter.paused);
+        limiter.toggle_pause();
+
+        limiter.pause_on_focus_loss = false;
+        limiter.mute_on_focus_loss = true;
+        limiter.window_event(WindowEvent::FocusLost);
+        assert!(!limiter.paused && limiter.mute_audio());
+    }
+
+    #[test]
+    fn test_minimized_stops_rendering() {
+        let mut limiter = FrameLimiter::new(1.0);
+        let start = Instant::now();
+        limiter.window_event(WindowEvent::Minimized);
+        assert!(!limiter.should_render());
+        // still paced, so the loop keeps running
+        limiter.frame_delay(start);
+        assert_eq!(limiter.frame_delay(start), limiter.frame_duration());
+        limiter.window_event(WindowEvent::Restored);
+        assert!(limiter.should_render());
+    }
+
+    #[test]
+    fn test_profiler() {
+        let mut profiler = Profiler::new(true);
+        let start = Instant::now();
+        for frame in 0..3u32 {
+            profiler.add(Subsystem::Cpu, Duration::from_millis(2 + frame as u64 * 2));
+            profiler.add(Subsystem::Render, Duration::from_millis(1));
+            // two emulated frames per presented one, as when fast forwarding
+            profiler.end_frame(start + Duration::from_millis(50) * frame, frame as u64 * 2);
+        }
+        assert_eq!(profiler.average(Subsystem::Cpu), Duration::from_millis(4));
+        assert_eq!(profiler.average(Subsystem::Apu), Duration::ZERO);
+        assert!((profiler.real_fps() - 20.0).abs() < 0.001);
+        assert!((profiler.emulated_fps() - 40.0).abs() < 0.001);
+        assert_eq!(profiler.lines()[1], "cpu    4.00ms");
+
+        let mut disabled = Profiler::new(false);
+        assert_eq!(disabled.measure(Subsystem::Cpu, || 42), 42);
+        disabled.end_frame(start, 1);
+        assert_eq!(disabled.lines()[0], "0.0 fps (0.0 emulated)");
+    }
+}
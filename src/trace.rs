@@ -0,0 +1,474 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Stdout, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+use bitflags::bitflags;
+
+use crate::cpu::{AddressingMode, Bus, Cpu, Memory};
+use crate::disassembler::{DecodedInstruction, Disassembler};
+use crate::instruction::Operation;
+use crate::ppu::{DOTS_PER_SCANLINE, SCANLINES_PER_FRAME};
+use crate::symbols::SymbolTable;
+
+// Formats the CPU state before executing the instruction at PC in nestest.log format:
+// C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+pub fn trace<B: Bus>(cpu: &Cpu<B>) -> String {
+    let (bytes, asm, official) = match Disassembler::new(cpu).decode(cpu.pc) {
+        Some(decoded) => {
+            let bytes = decoded.bytes().iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ");
+            let operand = format_operand(cpu, &decoded);
+            let mnemonic = decoded.instruction.mnemonic();
+            let asm = if operand.is_empty() { mnemonic.to_string() } else { format!("{} {}", mnemonic, operand) };
+            (bytes, asm, decoded.instruction.official)
+        }
+        None => (format!("{:02X}", cpu.read_byte(cpu.pc)), "???".to_string(), false),
+    };
+
+    let dots = cpu.cycles * 3;
+    format!(
+        "{:04X}  {:<8} {}{:<31} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        cpu.pc,
+        bytes,
+        if official { ' ' } else { '*' },
+        asm,
+        cpu.a,
+        cpu.x,
+        cpu.y,
+        cpu.p.bits(),
+        cpu.sp,
+        (dots / DOTS_PER_SCANLINE) % SCANLINES_PER_FRAME,
+        dots % DOTS_PER_SCANLINE,
+        cpu.cycles,
+    )
+}
+
+// Formats the operand the way nestest.log does, including the effective
+// address and the value currently stored there
+fn format_operand<B: Bus>(cpu: &Cpu<B>, decoded: &DecodedInstruction) -> String {
+    let (operand_byte, operand_word) = (decoded.operand_byte(), decoded.operand_word());
+    let addressing_mode = &decoded.instruction.addressing_mode;
+    let (address, _) = cpu.operand_address_at(cpu.pc.wrapping_add(1), addressing_mode);
+
+    match addressing_mode {
+        AddressingMode::None | AddressingMode::Implied => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate => format!("#${:02X}", operand_byte),
+        AddressingMode::ZeroPage => {
+            format!("${:02X} = {:02X}", operand_byte, cpu.read_byte(operand_byte as u16))
+        }
+        AddressingMode::ZeroPageX => {
+            format!("${:02X},X @ {:02X} = {:02X}", operand_byte, address, cpu.read_byte(address))
+        }
+        AddressingMode::ZeroPageY => {
+            format!("${:02X},Y @ {:02X} = {:02X}", operand_byte, address, cpu.read_byte(address))
+        }
+        AddressingMode::Absolute => match decoded.instruction.operation {
+            Operation::Jmp | Operation::Jsr => format!("${:04X}", operand_word),
+            _ => format!("${:04X} = {:02X}", operand_word, cpu.read_byte(operand_word)),
+        },
+        AddressingMode::AbsoluteX => {
+            format!("${:04X},X @ {:04X} = {:02X}", operand_word, address, cpu.read_byte(address))
+        }
+        AddressingMode::AbsoluteY => {
+            format!("${:04X},Y @ {:04X} = {:02X}", operand_word, address, cpu.read_byte(address))
+        }
+        AddressingMode::Indirect => format!("(${:04X}) = {:04X}", operand_word, address),
+        AddressingMode::IndirectX => {
+            let pointer = operand_byte.wrapping_add(cpu.x);
+            format!(
+                "(${:02X},X) @ {:02X} = {:04X} = {:02X}",
+                operand_byte,
+                pointer,
+                address,
+                cpu.read_byte(address)
+            )
+        }
+        AddressingMode::IndirectY => {
+            let base = cpu.read_zero_page_word(operand_byte);
+            format!(
+                "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
+                operand_byte,
+                base,
+                address,
+                cpu.read_byte(address)
+            )
+        }
+        AddressingMode::Relative => format!("${:04X}", decoded.target().unwrap_or(address)),
+    }
+}
+
+bitflags! {
+    // Components that can emit trace lines
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct TraceChannel: u8 {
+        const Cpu = 1 << 0;
+        const Ppu = 1 << 1;
+        const Apu = 1 << 2;
+        const Mapper = 1 << 3;
+    }
+}
+
+impl TraceChannel {
+    // Parses a comma separated list like "cpu,ppu" or "all"
+    pub fn parse(list: &str) -> Option<TraceChannel> {
+        list.split(',').try_fold(TraceChannel::empty(), |channels, name| {
+            let channel = match name.trim().to_ascii_lowercase().as_str() {
+                "cpu" => TraceChannel::Cpu,
+                "ppu" => TraceChannel::Ppu,
+                "apu" => TraceChannel::Apu,
+                "mapper" => TraceChannel::Mapper,
+                "all" => TraceChannel::all(),
+                _ => return None,
+            };
+            Some(channels | channel)
+        })
+    }
+}
+
+// Narrows the CPU trace down to what is being hunted. With no filters every
+// instruction is logged; with any, only what one of them selects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TraceFilter {
+    // instructions while PC is in the range
+    Pc(RangeInclusive<u16>),
+    // a line for every write into the range
+    Write(RangeInclusive<u16>),
+}
+
+impl TraceFilter {
+    // "pc:$C000-$C0FF", "write:$2006-$2007" or "write:$4014"; addresses can be symbol names
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Option<TraceFilter> {
+        let (kind, range) = text.trim().split_once(':')?;
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let (start, end) = (symbols.resolve(start.trim())?, symbols.resolve(end.trim())?);
+        if start > end {
+            return None;
+        }
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "pc" => Some(TraceFilter::Pc(start..=end)),
+            "write" => Some(TraceFilter::Write(start..=end)),
+            _ => None,
+        }
+    }
+
+    // A comma separated list, for the command line and config file
+    pub fn parse_list(list: &str, symbols: &SymbolTable) -> Option<Vec<TraceFilter>> {
+        list.split(',').map(|filter| TraceFilter::parse(filter, symbols)).collect()
+    }
+}
+
+// Where trace lines end up
+pub enum TraceSink {
+    File(BufWriter<File>),
+    Stdout(BufWriter<Stdout>),
+    // keeps only the most recent lines, for inspecting after the fact
+    Memory { lines: VecDeque<String>, capacity: usize },
+}
+
+impl TraceSink {
+    pub fn file(path: impl AsRef<Path>) -> io::Result<TraceSink> {
+        Ok(TraceSink::File(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn stdout() -> TraceSink {
+        TraceSink::Stdout(BufWriter::new(io::stdout()))
+    }
+
+    pub fn memory(capacity: usize) -> TraceSink {
+        TraceSink::Memory { lines: VecDeque::with_capacity(capacity), capacity }
+    }
+
+    fn write(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            TraceSink::File(writer) => writeln!(writer, "{}", line),
+            TraceSink::Stdout(writer) => writeln!(writer, "{}", line),
+            TraceSink::Memory { lines, capacity } => {
+                if *capacity > 0 {
+                    if lines.len() == *capacity {
+                        lines.pop_front();
+                    }
+                    lines.push_back(line.to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TraceSink::File(writer) => writer.flush(),
+            TraceSink::Stdout(writer) => writer.flush(),
+            TraceSink::Memory { .. } => Ok(()),
+        }
+    }
+}
+
+// Routes trace lines from the enabled channels to every sink.
+// Lines are built lazily so a disabled tracer costs a single branch.
+pub struct Tracer {
+    pub enabled: bool,
+    pub channels: TraceChannel,
+    pub filters: Vec<TraceFilter>,
+    sinks: Vec<TraceSink>,
+}
+
+impl Default for Tracer {
+    fn default() -> Self {
+        Self::new(TraceChannel::empty())
+    }
+}
+
+impl Tracer {
+    pub fn new(channels: TraceChannel) -> Self {
+        Tracer {
+            enabled: !channels.is_empty(),
+            channels,
+            filters: Vec::new(),
+            sinks: Vec::new(),
+        }
+    }
+
+    pub fn add_sink(&mut self, sink: TraceSink) {
+        self.sinks.push(sink);
+    }
+
+    pub fn is_enabled(&self, channel: TraceChannel) -> bool {
+        self.enabled && self.channels.contains(channel) && !self.sinks.is_empty()
+    }
+
+    pub fn log(&mut self, channel: TraceChannel, line: impl FnOnce() -> String) {
+        if !self.is_enabled(channel) {
+            return;
+        }
+        let line = line();
+        for sink in &mut self.sinks {
+            // tracing is best effort, a full disk shouldn't stop emulation
+            let _ = sink.write(&line);
+        }
+    }
+
+    // Logs the CPU state before executing the instruction at PC
+    pub fn log_cpu<B: Bus>(&mut self, cpu: &Cpu<B>) {
+        let pc = cpu.pc;
+        let selected = self.filters.is_empty()
+            || self.filters.iter().any(|filter| matches!(filter, TraceFilter::Pc(range) if range.contains(&pc)));
+        if selected {
+            self.log(TraceChannel::Cpu, || trace(cpu));
+        }
+    }
+
+    // Whether the caller should collect the bus writes for log_writes
+    pub fn traces_writes(&self) -> bool {
+        self.is_enabled(TraceChannel::Cpu) && self.filters.iter().any(|filter| matches!(filter, TraceFilter::Write(_)))
+    }
+
+    // Logs the writes made by the instruction at `pc` that a write filter selects
+    pub fn log_writes(&mut self, pc: u16, writes: &[(u16, u8)]) {
+        for &(address, value) in writes {
+            if self.filters.iter().any(|filter| matches!(filter, TraceFilter::Write(range) if range.contains(&address))) {
+                self.log(TraceChannel::Cpu, || format!("{:04X}  write ${:04X} = {:02X}", pc, address, value));
+            }
+        }
+    }
+
+    // Lines kept by the in-memory sinks, oldest first
+    pub fn history(&self) -> impl Iterator<Item = &String> {
+        self.sinks.iter().flat_map(|sink| match sink {
+            TraceSink::Memory { lines, .. } => Some(lines.iter()),
+            _ => None,
+        }).flatten()
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.sinks.iter().map(|sink| match sink {
+            TraceSink::Memory { lines, .. } => lines.len(),
+            _ => 0,
+        }).sum()
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.sinks.iter_mut().try_for_each(TraceSink::flush)
+    }
+}
+
+// Scrollback through the in-memory trace, for the debug pane after a break.
+// The offset counts lines back from the newest, so a view that is following
+// the end stays there as new lines arrive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceScroll {
+    pub offset: usize,
+    pub height: usize,
+}
+
+impl TraceScroll {
+    pub fn new(height: usize) -> Self {
+        TraceScroll { offset: 0, height }
+    }
+
+    fn max_offset(&self, total: usize) -> usize {
+        total.saturating_sub(self.height)
+    }
+
+    // Positive deltas scroll back in time
+    pub fn scroll(&mut self, delta: isize, total: usize) {
+        self.offset = self.offset.saturating_add_signed(delta).min(self.max_offset(total));
+    }
+
+    pub fn page_up(&mut self, total: usize) {
+        self.scroll(self.height as isize, total);
+    }
+
+    pub fn page_down(&mut self, total: usize) {
+        self.scroll(-(self.height as isize), total);
+    }
+
+    pub fn to_oldest(&mut self, total: usize) {
+        self.offset = self.max_offset(total);
+    }
+
+    pub fn to_newest(&mut self) {
+        self.offset = 0;
+    }
+
+    pub fn is_following(&self) -> bool {
+        self.offset == 0
+    }
+
+    // The lines on screen, oldest first
+    pub fn visible<'a>(&self, tracer: &'a Tracer) -> Vec<&'a String> {
+        let total = tracer.history_len();
+        let offset = self.offset.min(self.max_offset(total));
+        let end = total - offset;
+        tracer.history().skip(end.saturating_sub(self.height)).take(end.min(self.height)).collect()
+    }
+
+    // Scrolls back to the newest line containing `text` above the top of the view,
+    // e.g. an address to see when it last ran. Returns false when there is none.
+    pub fn find_previous(&mut self, tracer: &Tracer, text: &str) -> bool {
+        let total = tracer.history_len();
+        let top = total.saturating_sub(self.offset + self.height);
+        let lines: Vec<&String> = tracer.history().take(top).collect();
+        match lines.iter().rposition(|line| line.contains(text)) {
+            Some(index) => {
+                // put the match at the top of the view
+                self.offset = (total - index).saturating_sub(self.height).min(self.max_offset(total));
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::StatusFlag;
+
+    #[test]
+    fn test_trace_format() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0x4C, 0xF5, 0xC5], 0xC000);
+        cpu.reset();
+        cpu.p = StatusFlag::from_bits_truncate(0x24);
+        cpu.cycles = 7;
+        assert_eq!(
+            trace(&cpu),
+            "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7"
+        );
+    }
+
+    #[test]
+    fn test_trace_unofficial_opcode() {
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0x04, 0xA9], 0xC6BD);
+        cpu.reset();
+        cpu.bus.memory[0xA9] = 0x00;
+        assert!(trace(&cpu).starts_with("C6BD  04 A9    *NOP $A9 = 00                    A:00"));
+    }
+
+    #[test]
+    fn test_parse_channels() {
+        assert_eq!(TraceChannel::parse("cpu,PPU"), Some(TraceChannel::Cpu | TraceChannel::Ppu));
+        assert_eq!(TraceChannel::parse("all"), Some(TraceChannel::all()));
+        assert_eq!(TraceChannel::parse("cpu,gpu"), None);
+    }
+
+    #[test]
+    fn test_memory_sink_keeps_latest_lines() {
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(2));
+        for i in 0..3 {
+            tracer.log(TraceChannel::Cpu, || i.to_string());
+        }
+        assert_eq!(tracer.history().collect::<Vec<_>>(), ["1", "2"]);
+    }
+
+    #[test]
+    fn test_disabled_channels_are_not_formatted() {
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(8));
+        tracer.log(TraceChannel::Ppu, || panic!("ppu channel is disabled"));
+        tracer.enabled = false;
+        tracer.log(TraceChannel::Cpu, || panic!("tracer is disabled"));
+        assert_eq!(tracer.history().count(), 0);
+    }
+
+    #[test]
+    fn test_filters() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0xC080, "update");
+        assert_eq!(TraceFilter::parse("PC:$C000-update", &symbols), Some(TraceFilter::Pc(0xC000..=0xC080)));
+        assert_eq!(TraceFilter::parse("write:0x4014", &symbols), Some(TraceFilter::Write(0x4014..=0x4014)));
+        assert_eq!(TraceFilter::parse("read:$2002", &symbols), None);
+        assert_eq!(TraceFilter::parse("pc:$C0FF-$C000", &symbols), None);
+        assert_eq!(TraceFilter::parse_list("pc:$C000,write:nowhere", &symbols), None);
+
+        let mut cpu = Cpu::new();
+        cpu.load_program(vec![0xEA], 0xC100);
+        cpu.reset();
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(8));
+        tracer.filters = TraceFilter::parse_list("pc:$C000-$C0FF, write:$2006-$2007", &symbols).unwrap();
+        assert!(tracer.traces_writes());
+        tracer.log_cpu(&cpu);
+        tracer.log_writes(0xC100, &[(0x2000, 0x80), (0x2006, 0x3F)]);
+        cpu.pc = 0xC0FF;
+        tracer.log_cpu(&cpu);
+        let lines: Vec<&String> = tracer.history().collect();
+        assert_eq!(lines[0], "C100  write $2006 = 3F");
+        assert!(lines[1].starts_with("C0FF "));
+        assert_eq!(lines.len(), 2);
+    }
+
+    #[test]
+    fn test_trace_scroll() {
+        let mut tracer = Tracer::new(TraceChannel::Cpu);
+        tracer.add_sink(TraceSink::memory(10));
+        for i in 0..10 {
+            tracer.log(TraceChannel::Cpu, || format!("line {}", i));
+        }
+        let mut scroll = TraceScroll::new(3);
+        assert_eq!(scroll.visible(&tracer), ["line 7", "line 8", "line 9"]);
+        scroll.page_up(10);
+        assert_eq!(scroll.visible(&tracer), ["line 4", "line 5", "line 6"]);
+        scroll.scroll(100, 10);
+        assert_eq!(scroll.visible(&tracer), ["line 0", "line 1", "line 2"]);
+        scroll.page_down(10);
+        scroll.scroll(-1, 10);
+        assert_eq!(scroll.offset, 3);
+        scroll.to_newest();
+        assert!(scroll.is_following());
+
+        assert!(scroll.find_previous(&tracer, "line 5"));
+        assert_eq!(scroll.visible(&tracer), ["line 5", "line 6", "line 7"]);
+        assert!(!scroll.find_previous(&tracer, "line 9"));
+    }
+}
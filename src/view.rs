@@ -0,0 +1,273 @@
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bitflags::bitflags;
+
+use crate::gamedb::GameEntry;
+use crate::nes::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+const APP_NAME: &str = "madNES";
+// How often the title's frame rate is refreshed, more would flicker in the taskbar
+const TITLE_REFRESH: Duration = Duration::from_secs(1);
+
+// NES pixels are slightly wider than they are tall on an NTSC TV
+const PIXEL_ASPECT_RATIO: f64 = 8.0 / 7.0;
+// Most TVs hid about 8 lines at the top and bottom, which games left untidy
+pub const MAX_OVERSCAN: u32 = 16;
+
+// Where the picture goes inside the window, in window pixels
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// How the 256x240 picture is fitted to the window
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct View {
+    pub aspect_correction: bool,
+    // only scale by whole multiples, leaving a border instead of uneven pixels
+    pub integer_scaling: bool,
+    pub fullscreen: bool,
+    // lines hidden at the top and at the bottom, like a TV's overscan
+    pub overscan: u32,
+}
+
+impl Default for View {
+    fn default() -> Self {
+        View {
+            aspect_correction: true,
+            integer_scaling: false,
+            fullscreen: false,
+            overscan: 0,
+        }
+    }
+}
+
+impl View {
+    // Toggled by Alt+Enter
+    pub fn toggle_fullscreen(&mut self) {
+        self.fullscreen = !self.fullscreen;
+    }
+
+    fn picture_width(&self) -> f64 {
+        if self.aspect_correction {
+            SCREEN_WIDTH as f64 * PIXEL_ASPECT_RATIO
+        } else {
+            SCREEN_WIDTH as f64
+        }
+    }
+
+    fn picture_height(&self) -> u32 {
+        SCREEN_HEIGHT as u32 - 2 * self.overscan.min(MAX_OVERSCAN)
+    }
+
+    // The part of the 256x240 frame that's shown, in NES pixels
+    pub fn source(&self) -> Rect {
+        let overscan = self.overscan.min(MAX_OVERSCAN);
+        Rect { x: 0, y: overscan, width: SCREEN_WIDTH as u32, height: self.picture_height() }
+    }
+
+    // Initial window size for a scale factor
+    pub fn window_size(&self, scale: u32) -> (u32, u32) {
+        (
+            (self.picture_width() * scale as f64).round() as u32,
+            self.picture_height() * scale,
+        )
+    }
+
+    // The largest picture that fits the window, centered. Recomputed whenever the window is resized.
+    pub fn viewport(&self, window_width: u32, window_height: u32) -> Rect {
+        let picture_width = self.picture_width();
+        let picture_height = self.picture_height() as f64;
+        let mut scale = (window_width as f64 / picture_width).min(window_height as f64 / picture_height);
+        if self.integer_scaling && scale >= 1.0 {
+            scale = scale.floor();
+        }
+        let width = ((picture_width * scale).round() as u32).min(window_width);
+        let height = ((picture_height * scale).round() as u32).min(window_height);
+        Rect {
+            x: (window_width - width) / 2,
+            y: (window_height - height) / 2,
+            width,
+            height,
+        }
+    }
+}
+
+bitflags! {
+    // Tool windows besides the game's. None are open for normal play; each one is
+    // created when its hotkey opens it and destroyed again when it's closed.
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub struct ToolWindows: u8 {
+        // disassembly, registers, breakpoints and watches
+        const Debugger = 1 << 0;
+        // pattern tables, nametables, palettes and sprites, see viewer.rs
+        const PpuViewer = 1 << 1;
+    }
+}
+
+impl ToolWindows {
+    // A comma separated list like "debugger,ppu"
+    pub fn parse(list: &str) -> Option<ToolWindows> {
+        list.split(',').map(str::trim).filter(|name| !name.is_empty()).try_fold(ToolWindows::empty(), |windows, name| {
+            let window = match name.to_ascii_lowercase().as_str() {
+                "debugger" => ToolWindows::Debugger,
+                "ppu" => ToolWindows::PpuViewer,
+                _ => return None,
+            };
+            Some(windows | window)
+        })
+    }
+
+    // Title and initial size of a single window
+    pub fn title(self) -> String {
+        let name = match self {
+            ToolWindows::Debugger => "Debugger",
+            ToolWindows::PpuViewer => "PPU Viewer",
+            _ => "Tools",
+        };
+        format!("{} - {}", APP_NAME, name)
+    }
+
+    pub fn size(self) -> (u32, u32) {
+        match self {
+            // the 512x480 nametables, the 128x256 pattern tables over the 128x192 sprite
+            // previews, then the sprite list's 64 lines of text
+            ToolWindows::PpuViewer => (800, 576),
+            _ => (640, 480),
+        }
+    }
+}
+
+// The window title: the game, then the frame rate and speed once they are measured.
+// The game is the database name when the ROM has an entry, the file name otherwise.
+#[derive(Debug, Clone, Default)]
+pub struct WindowTitle {
+    pub game: Option<String>,
+    // frames per second and percent of full speed
+    pub status: Option<(f64, f64)>,
+    last_refresh: Option<Instant>,
+}
+
+impl WindowTitle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Called on every cartridge load, including hot swaps
+    pub fn set_game(&mut self, path: &Path, entry: Option<&GameEntry>) {
+        let name = entry.and_then(|entry| entry.name.clone());
+        self.game = name.or_else(|| path.file_stem().map(|stem| stem.to_string_lossy().into_owned()));
+        self.status = None;
+        self.last_refresh = None;
+    }
+
+    pub fn clear_game(&mut self) {
+        self.game = None;
+        self.status = None;
+        self.last_refresh = None;
+    }
+
+    // Returns the title to set when it changed: after a new game, then once a second
+    pub fn update(&mut self, now: Instant, fps: f64, speed: f64) -> Option<String> {
+        let due = self.last_refresh.is_none_or(|last| now.duration_since(last) >= TITLE_REFRESH);
+        if !due {
+            return None;
+        }
+        // the first call only shows the game, the rate isn't known yet
+        if self.last_refresh.is_some() {
+            self.status = Some((fps, speed));
+        }
+        self.last_refresh = Some(now);
+        Some(self.text())
+    }
+
+    pub fn text(&self) -> String {
+        match (&self.game, self.status) {
+            (Some(game), Some((fps, speed))) => format!("{} - {} - {:.1} FPS ({:.0}%)", game, APP_NAME, fps, speed),
+            (Some(game), None) => format!("{} - {}", game, APP_NAME),
+            (None, _) => APP_NAME.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_size() {
+        let view = View::default();
+        assert_eq!(view.window_size(3), (878, 720));
+        let view = View { aspect_correction: false, ..View::default() };
+        assert_eq!(view.window_size(2), (512, 480));
+    }
+
+    #[test]
+    fn test_viewport_letterbox() {
+        let view = View { aspect_correction: false, ..View::default() };
+        // wide window, bars on the sides
+        assert_eq!(view.viewport(1920, 1080), Rect { x: 384, y: 0, width: 1152, height: 1080 });
+        // tall window, bars at the top and bottom
+        assert_eq!(view.viewport(512, 1000), Rect { x: 0, y: 260, width: 512, height: 480 });
+    }
+
+    #[test]
+    fn test_viewport_integer_scaling() {
+        let view = View { integer_scaling: true, aspect_correction: false, ..View::default() };
+        assert_eq!(view.viewport(1920, 1080), Rect { x: 448, y: 60, width: 1024, height: 960 });
+        // smaller than 1x still fills the window
+        assert_eq!(view.viewport(128, 120), Rect { x: 0, y: 0, width: 128, height: 120 });
+    }
+
+    #[test]
+    fn test_viewport_aspect_correction() {
+        let mut view = View::default();
+        let rect = view.viewport(1920, 1080);
+        assert_eq!((rect.width, rect.height), (1317, 1080));
+        view.toggle_fullscreen();
+        assert!(view.fullscreen);
+    }
+
+    #[test]
+    fn test_overscan() {
+        let view = View { aspect_correction: false, overscan: 8, ..View::default() };
+        assert_eq!(view.source(), Rect { x: 0, y: 8, width: 256, height: 224 });
+        assert_eq!(view.window_size(2), (512, 448));
+        assert_eq!(view.viewport(512, 448), Rect { x: 0, y: 0, width: 512, height: 448 });
+        assert_eq!(View { overscan: 100, ..view }.source().height, 208);
+    }
+
+    #[test]
+    fn test_tool_windows() {
+        assert_eq!(ToolWindows::parse("debugger, PPU"), Some(ToolWindows::all()));
+        assert_eq!(ToolWindows::parse(""), Some(ToolWindows::empty()));
+        assert_eq!(ToolWindows::parse("memory"), None);
+        let mut open = ToolWindows::default();
+        open.toggle(ToolWindows::Debugger);
+        assert_eq!(open, ToolWindows::Debugger);
+        open.toggle(ToolWindows::Debugger);
+        assert!(open.is_empty());
+        assert_eq!(ToolWindows::PpuViewer.title(), "madNES - PPU Viewer");
+    }
+
+    #[test]
+    fn test_window_title() {
+        let mut title = WindowTitle::new();
+        assert_eq!(title.text(), "madNES");
+        title.set_game(Path::new("roms/smb.nes"), None);
+        let start = Instant::now();
+        assert_eq!(title.update(start, 0.0, 0.0).as_deref(), Some("smb - madNES"));
+        assert_eq!(title.update(start + Duration::from_millis(500), 60.0, 100.0), None);
+        let second = start + Duration::from_secs(1);
+        assert_eq!(title.update(second, 59.94, 99.9).as_deref(), Some("smb - madNES - 59.9 FPS (100%)"));
+
+        // a hot swap shows the new game right away
+        let entry = GameEntry { name: Some("Zelda".to_string()), ..GameEntry::default() };
+        title.set_game(Path::new("zelda.nes"), Some(&entry));
+        assert_eq!(title.update(second, 60.0, 100.0).as_deref(), Some("Zelda - madNES"));
+    }
+}
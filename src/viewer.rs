@@ -0,0 +1,443 @@
+// Debug views of PPU memory. These work on copies of CHR, nametables and palette
+// RAM as the PPU sees them (see Ppu::pattern_tables and Ppu::nametables), so they
+// can be redrawn from whatever owns that memory once per frame.
+
+use crate::mixer::{Channel, ChannelScope};
+use crate::palette::{Rgb, SYSTEM_PALETTE};
+use crate::ppu::{sprite_height, PpuCtrl, ScrollPosition, Sprite, SpriteAttributes, OAM_SIZE};
+
+const TILE_SIZE: usize = 8;
+const TILE_BYTES: usize = 16;
+const PATTERN_TABLE_SIZE: usize = 0x1000;
+const NAMETABLE_SIZE: usize = 0x400;
+const NAMETABLE_WIDTH: usize = 256;
+const NAMETABLE_HEIGHT: usize = 240;
+const ATTRIBUTE_TABLE_OFFSET: usize = 0x3C0;
+const SPRITE_COUNT: usize = OAM_SIZE / 4;
+// each sprite preview gets a cell with room for 8x16 sprites and a 2 pixel frame
+const SPRITE_CELL_WIDTH: usize = 16;
+const SPRITE_CELL_HEIGHT: usize = 24;
+const SPRITE_HIGHLIGHT: Rgb = (0xFF, 0xFF, 0x00);
+const SCOPE_TRACE: Rgb = (0x40, 0xFF, 0x40);
+const SCOPE_DIVIDER: Rgb = (0x40, 0x40, 0x40);
+
+// An RGB image, 3 bytes per pixel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image { width, height, pixels: vec![0; width * height * 3] }
+    }
+
+    pub fn get_pixel(&self, x: usize, y: usize) -> Rgb {
+        let i = (y * self.width + x) * 3;
+        (self.pixels[i], self.pixels[i + 1], self.pixels[i + 2])
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): Rgb) {
+        let i = (y * self.width + x) * 3;
+        self.pixels[i..i + 3].copy_from_slice(&[r, g, b]);
+    }
+
+    // Binary PPM (P6), which anything that opens images reads and needs no encoder
+    pub fn to_ppm(&self) -> Vec<u8> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        data.extend_from_slice(&self.pixels);
+        data
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: Rgb) {
+        for row in y..y + height {
+            for column in x..x + width {
+                self.set_pixel(column, row, color);
+            }
+        }
+    }
+}
+
+// Returns the four colors of one of the 8 palettes (0-3 background, 4-7 sprites).
+// Color 0 of every palette is the universal background color at 0x3F00.
+pub fn palette_colors(palette_ram: &[u8; 32], palette: usize) -> [Rgb; 4] {
+    let mut colors = [SYSTEM_PALETTE[(palette_ram[0] & 0x3F) as usize]; 4];
+    for (i, color) in colors.iter_mut().enumerate().skip(1) {
+        *color = SYSTEM_PALETTE[(palette_ram[palette * 4 + i] & 0x3F) as usize];
+    }
+    colors
+}
+
+// Decodes one 2 bitplane tile into color indices 0-3
+fn tile_pixels(chr: &[u8], table: usize, tile: usize) -> [[u8; TILE_SIZE]; TILE_SIZE] {
+    let mut pixels = [[0; TILE_SIZE]; TILE_SIZE];
+    let start = table * PATTERN_TABLE_SIZE + tile * TILE_BYTES;
+    let Some(bytes) = chr.get(start..start + TILE_BYTES) else {
+        return pixels;
+    };
+    for (y, row) in pixels.iter_mut().enumerate() {
+        let (lo, hi) = (bytes[y], bytes[y + TILE_SIZE]);
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let bit = 7 - x;
+            *pixel = ((hi >> bit) & 1) << 1 | ((lo >> bit) & 1);
+        }
+    }
+    pixels
+}
+
+fn draw_tile(image: &mut Image, chr: &[u8], table: usize, tile: usize, x: usize, y: usize, colors: &[Rgb; 4]) {
+    for (row, pixels) in tile_pixels(chr, table, tile).iter().enumerate() {
+        for (column, &pixel) in pixels.iter().enumerate() {
+            image.set_pixel(x + column, y + row, colors[pixel as usize]);
+        }
+    }
+}
+
+// Renders pattern table 0 or 1 as a 128x128 grid of 16x16 tiles
+pub fn pattern_table(chr: &[u8], table: usize, colors: &[Rgb; 4]) -> Image {
+    let mut image = Image::new(128, 128);
+    for tile in 0..256 {
+        draw_tile(&mut image, chr, table, tile, (tile % 16) * TILE_SIZE, (tile / 16) * TILE_SIZE, colors);
+    }
+    image
+}
+
+// Renders all four nametables as a 512x480 image laid out like the PPU address space.
+// `nametables` is $2000-$2FFF as the PPU sees it, see Ppu::nametables.
+pub fn nametables(nametables: &[u8], chr: &[u8], background_table: usize, palette_ram: &[u8; 32]) -> Image {
+    let mut image = Image::new(NAMETABLE_WIDTH * 2, NAMETABLE_HEIGHT * 2);
+    for nametable in 0..4 {
+        let start = nametable * NAMETABLE_SIZE;
+        let Some(page) = nametables.get(start..start + NAMETABLE_SIZE) else {
+            continue;
+        };
+        let origin_x = (nametable % 2) * NAMETABLE_WIDTH;
+        let origin_y = (nametable / 2) * NAMETABLE_HEIGHT;
+
+        for row in 0..30 {
+            for column in 0..32 {
+                // each attribute byte holds four 2 bit palettes for a 4x4 tile area
+                let attribute = page[ATTRIBUTE_TABLE_OFFSET + (row / 4) * 8 + column / 4];
+                let shift = ((row % 4) / 2) * 4 + ((column % 4) / 2) * 2;
+                let colors = palette_colors(palette_ram, ((attribute >> shift) & 0x03) as usize);
+                let tile = page[row * 32 + column] as usize;
+                draw_tile(
+                    &mut image,
+                    chr,
+                    background_table,
+                    tile,
+                    origin_x + column * TILE_SIZE,
+                    origin_y + row * TILE_SIZE,
+                    &colors,
+                );
+            }
+        }
+    }
+    image
+}
+
+// Outlines the visible 256x240 screen on the nametables image, wrapping around the edges
+pub fn draw_scroll_rect(image: &mut Image, scroll_x: usize, scroll_y: usize, color: Rgb) {
+    for i in 0..NAMETABLE_WIDTH {
+        let x = (scroll_x + i) % image.width;
+        image.set_pixel(x, scroll_y % image.height, color);
+        image.set_pixel(x, (scroll_y + NAMETABLE_HEIGHT - 1) % image.height, color);
+    }
+    for i in 0..NAMETABLE_HEIGHT {
+        let y = (scroll_y + i) % image.height;
+        image.set_pixel(scroll_x % image.width, y, color);
+        image.set_pixel((scroll_x + NAMETABLE_WIDTH - 1) % image.width, y, color);
+    }
+}
+
+// Traces where each scanline was drawn from, the per-line version of draw_scroll_rect
+// for split screens. Each run of lines that scrolled together gets its own outline.
+pub fn draw_scroll_lines(image: &mut Image, lines: &[Option<ScrollPosition>], color: Rgb) {
+    let draw_row = |image: &mut Image, position: ScrollPosition| {
+        for i in 0..NAMETABLE_WIDTH {
+            image.set_pixel((position.x as usize + i) % image.width, position.y as usize % image.height, color);
+        }
+    };
+    let mut previous: Option<ScrollPosition> = None;
+    for (line, position) in lines.iter().enumerate() {
+        let Some(position) = *position else {
+            if let Some(previous) = previous.take() {
+                draw_row(image, previous);
+            }
+            continue;
+        };
+        let continues = previous.is_some_and(|previous| previous.x == position.x && (previous.y + 1) % 480 == position.y % 480);
+        if !continues {
+            if let Some(previous) = previous {
+                draw_row(image, previous);
+            }
+            draw_row(image, position);
+        }
+        let (x, y) = (position.x as usize, position.y as usize % image.height);
+        image.set_pixel(x % image.width, y, color);
+        image.set_pixel((x + NAMETABLE_WIDTH - 1) % image.width, y, color);
+        if line == lines.len() - 1 {
+            draw_row(image, position);
+        }
+        previous = Some(position);
+    }
+}
+
+// Renders the 32 palette RAM entries as two rows of 16x16 swatches
+pub fn palette_ram(palette_ram: &[u8; 32]) -> Image {
+    const SWATCH: usize = 16;
+    let mut image = Image::new(16 * SWATCH, 2 * SWATCH);
+    for (i, &entry) in palette_ram.iter().enumerate() {
+        let color = SYSTEM_PALETTE[(entry & 0x3F) as usize];
+        image.fill((i % 16) * SWATCH, (i / 16) * SWATCH, SWATCH, SWATCH, color);
+    }
+    image
+}
+
+// Whether a sprite is drawn on `scanline`, by the same test as sprite evaluation
+fn on_scanline(sprite: &Sprite, ctrl: PpuCtrl, scanline: u16) -> bool {
+    scanline.wrapping_sub(sprite.y as u16) < sprite_height(ctrl) as u16
+}
+
+// One line per OAM entry for the sprite list: index, position, tile, palette, then
+// H/V for flips and B for behind the background. Sprites on `scanline` start with '>'.
+pub fn sprite_list(oam: &[u8; OAM_SIZE], ctrl: PpuCtrl, scanline: u16) -> Vec<String> {
+    (0..SPRITE_COUNT as u8)
+        .map(|index| {
+            let sprite = Sprite::from_oam(oam, index);
+            let flag = |attribute: SpriteAttributes, letter: char| if sprite.attributes.contains(attribute) { letter } else { '-' };
+            format!(
+                "{}{:02} X:{:3} Y:{:3} T:{:02X} P:{} {}{}{}",
+                if on_scanline(&sprite, ctrl, scanline) { '>' } else { ' ' },
+                index,
+                sprite.x,
+                sprite.y,
+                sprite.tile,
+                (sprite.attributes & SpriteAttributes::Palette).bits(),
+                flag(SpriteAttributes::FlipHorizontal, 'H'),
+                flag(SpriteAttributes::FlipVertical, 'V'),
+                flag(SpriteAttributes::BehindBackground, 'B'),
+            )
+        })
+        .collect()
+}
+
+// Renders all 64 sprites as an 8x8 grid of 16x24 cells, 128x192, each drawn with its
+// own palette and flips. Cells of sprites on `scanline` get a highlighted frame.
+pub fn sprite_previews(oam: &[u8; OAM_SIZE], ctrl: PpuCtrl, chr: &[u8], palette_ram: &[u8; 32], scanline: u16) -> Image {
+    let mut image = Image::new(8 * SPRITE_CELL_WIDTH, 8 * SPRITE_CELL_HEIGHT);
+    for index in 0..SPRITE_COUNT {
+        let sprite = Sprite::from_oam(oam, index as u8);
+        let (cell_x, cell_y) = ((index % 8) * SPRITE_CELL_WIDTH, (index / 8) * SPRITE_CELL_HEIGHT);
+        if on_scanline(&sprite, ctrl, scanline) {
+            image.fill(cell_x, cell_y, SPRITE_CELL_WIDTH, SPRITE_CELL_HEIGHT, SPRITE_HIGHLIGHT);
+            image.fill(cell_x + 2, cell_y + 2, SPRITE_CELL_WIDTH - 4, SPRITE_CELL_HEIGHT - 4, (0, 0, 0));
+        }
+        let colors = palette_colors(palette_ram, 4 + (sprite.attributes & SpriteAttributes::Palette).bits() as usize);
+        for row in 0..sprite_height(ctrl) {
+            let address = sprite.pattern_address(ctrl, row) as usize;
+            let (mut low, mut high) = (chr.get(address).copied().unwrap_or(0), chr.get(address + 8).copied().unwrap_or(0));
+            if sprite.attributes.contains(SpriteAttributes::FlipHorizontal) {
+                low = low.reverse_bits();
+                high = high.reverse_bits();
+            }
+            for column in 0..TILE_SIZE {
+                let bit = 7 - column;
+                let color = ((high >> bit) & 1) << 1 | ((low >> bit) & 1);
+                image.set_pixel(cell_x + 4 + column, cell_y + 4 + row as usize, colors[color as usize]);
+            }
+        }
+    }
+    image
+}
+
+// Plots each channel's recent output as a trace in its own `row_height` tall row, in
+// Channel order, scaled to the channel's full range. The history is stretched or
+// squeezed to fill `width`, with the newest output on the right.
+pub fn channel_scopes(scope: &ChannelScope, width: usize, row_height: usize) -> Image {
+    let mut image = Image::new(width, row_height * Channel::ALL.len());
+    for (row, &channel) in Channel::ALL.iter().enumerate() {
+        let top = row * row_height;
+        for x in 0..width {
+            image.set_pixel(x, top + row_height - 1, SCOPE_DIVIDER);
+        }
+        let history = scope.history(channel);
+        if history.is_empty() || row_height < 2 {
+            continue;
+        }
+        // the bottom line of the row is the divider
+        let span = row_height - 2;
+        let y = |x: usize| {
+            let level = history[x * history.len() / width] as usize;
+            top + span - level.min(channel.max_output() as usize) * span / channel.max_output() as usize
+        };
+        let mut previous = y(0);
+        for x in 0..width {
+            // join each point to the last so steps are drawn as edges
+            let current = y(x);
+            for line in previous.min(current)..=previous.max(current) {
+                image.set_pixel(x, line, SCOPE_TRACE);
+            }
+            previous = current;
+        }
+    }
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ppu::Ppu;
+
+    const COLORS: [Rgb; 4] = [(0, 0, 0), (1, 1, 1), (2, 2, 2), (3, 3, 3)];
+
+    #[test]
+    fn test_tile_pixels() {
+        let mut chr = vec![0; 0x2000];
+        // tile 1 of table 1: first row uses color 1 at x=0 and color 3 at x=7
+        chr[0x1010] = 0b1000_0001;
+        chr[0x1018] = 0b0000_0001;
+        let pixels = tile_pixels(&chr, 1, 1);
+        assert_eq!(pixels[0], [1, 0, 0, 0, 0, 0, 0, 3]);
+        assert_eq!(pixels[1], [0; 8]);
+    }
+
+    #[test]
+    fn test_pattern_table() {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10] = 0x80;
+        let image = pattern_table(&chr, 0, &COLORS);
+        assert_eq!((image.width, image.height), (128, 128));
+        assert_eq!(image.get_pixel(8, 0), COLORS[1]);
+        assert_eq!(image.get_pixel(9, 0), COLORS[0]);
+    }
+
+    #[test]
+    fn test_palette_colors_share_background() {
+        let mut palette_ram = [0; 32];
+        palette_ram[0] = 0x0F;
+        palette_ram[5..8].copy_from_slice(&[0x01, 0x02, 0x03]);
+        let colors = palette_colors(&palette_ram, 1);
+        assert_eq!(colors[0], SYSTEM_PALETTE[0x0F]);
+        assert_eq!(colors[3], SYSTEM_PALETTE[0x03]);
+    }
+
+    #[test]
+    fn test_nametables_use_attributes_and_mirroring() {
+        let mut chr = vec![0; 0x2000];
+        // tile 1 is solid color 1
+        chr[0x10..0x18].fill(0xFF);
+        let mut ppu = Ppu::new();
+        ppu.vram[0] = 1;
+        // bottom right quadrant of the first attribute area uses palette 2
+        ppu.vram[ATTRIBUTE_TABLE_OFFSET] = 0b1000_0000;
+        ppu.vram[2 * 32 + 2] = 1;
+        let mut palette_ram = [0; 32];
+        palette_ram[1] = 0x16;
+        palette_ram[9] = 0x2A;
+
+        // without a cartridge the PPU mirrors vertically
+        let image = nametables(&ppu.nametables(None), &chr, 0, &palette_ram);
+        assert_eq!(image.get_pixel(0, 0), SYSTEM_PALETTE[0x16]);
+        assert_eq!(image.get_pixel(16, 16), SYSTEM_PALETTE[0x2A]);
+        // vertical mirroring repeats the first nametable below it
+        assert_eq!(image.get_pixel(0, 240), SYSTEM_PALETTE[0x16]);
+        assert_eq!(image.get_pixel(256, 0), SYSTEM_PALETTE[0x00]);
+    }
+
+    #[test]
+    fn test_scroll_rect_wraps() {
+        let mut image = Image::new(512, 480);
+        let red = (0xFF, 0, 0);
+        draw_scroll_rect(&mut image, 400, 300, red);
+        assert_eq!(image.get_pixel(400, 300), red);
+        assert_eq!(image.get_pixel((400 + 255) % 512, 300), red);
+        assert_eq!(image.get_pixel(400, (300 + 239) % 480), red);
+        assert_eq!(image.get_pixel(401, 301), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_scroll_lines_outline_splits() {
+        let mut image = Image::new(512, 480);
+        let red = (0xFF, 0, 0);
+        // a status bar fixed at the top, then the playfield scrolled right by 100
+        let mut lines = [None; 240];
+        for (line, position) in lines.iter_mut().enumerate() {
+            let (x, y) = if line < 32 { (0u16, 240 + line) } else { (100, line) };
+            *position = Some(ScrollPosition { x, y: y as u16 });
+        }
+        draw_scroll_lines(&mut image, &lines, red);
+        assert_eq!(image.get_pixel(10, 240), red);
+        assert_eq!(image.get_pixel(10, 271), red);
+        assert_eq!(image.get_pixel(255, 250), red);
+        assert_eq!(image.get_pixel(100, 32), red);
+        assert_eq!(image.get_pixel(355, 100), red);
+        assert_eq!(image.get_pixel(150, 239), red);
+        assert_eq!(image.get_pixel(150, 100), (0, 0, 0));
+        assert_eq!(image.get_pixel(10, 31), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_sprite_list() {
+        let mut oam = [0xFF; OAM_SIZE];
+        oam[4..8].copy_from_slice(&[0x40, 0x3A, 0b0110_0001, 120]);
+        let lines = sprite_list(&oam, PpuCtrl::empty(), 0x45);
+        assert_eq!(lines.len(), 64);
+        assert_eq!(lines[1], ">01 X:120 Y: 64 T:3A P:1 H-B");
+        assert_eq!(lines[0], " 00 X:255 Y:255 T:FF P:3 HVB");
+        // 8x16 sprites reach further down
+        assert!(sprite_list(&oam, PpuCtrl::TallSprites, 0x4F)[1].starts_with('>'));
+        assert!(sprite_list(&oam, PpuCtrl::empty(), 0x4F)[1].starts_with(' '));
+    }
+
+    #[test]
+    fn test_sprite_previews() {
+        let mut chr = vec![0; 0x2000];
+        // tile 2, top row: color 1 in the leftmost pixel
+        chr[0x20] = 0x80;
+        let mut oam = [0; OAM_SIZE];
+        oam[8..12].copy_from_slice(&[10, 2, 0x01, 0]);
+        oam[12..16].copy_from_slice(&[100, 2, SpriteAttributes::FlipHorizontal.bits(), 0]);
+        let mut palette_ram = [0; 32];
+        palette_ram[0x15] = 0x16;
+        palette_ram[0x11] = 0x2A;
+
+        let image = sprite_previews(&oam, PpuCtrl::empty(), &chr, &palette_ram, 10);
+        assert_eq!((image.width, image.height), (128, 192));
+        // sprite 2 uses sprite palette 1 and is on the scanline
+        assert_eq!(image.get_pixel(2 * 16 + 4, 4), SYSTEM_PALETTE[0x16]);
+        assert_eq!(image.get_pixel(2 * 16, 0), SPRITE_HIGHLIGHT);
+        // sprite 3 is flipped, and not on the scanline
+        assert_eq!(image.get_pixel(3 * 16 + 4 + 7, 4), SYSTEM_PALETTE[0x2A]);
+        assert_eq!(image.get_pixel(3 * 16, 0), (0, 0, 0));
+    }
+
+    #[test]
+    fn test_channel_scopes() {
+        let mut scope = ChannelScope::new(4);
+        for output in [0, 15, 15, 0] {
+            scope.record([output, 0, 0, 0, 127]);
+        }
+        let image = channel_scopes(&scope, 8, 18);
+        assert_eq!((image.width, image.height), (8, 90));
+        // pulse 1: low, high, high, low with an edge where it changes
+        assert_eq!(image.get_pixel(0, 16), SCOPE_TRACE);
+        assert_eq!(image.get_pixel(0, 0), (0, 0, 0));
+        assert_eq!(image.get_pixel(2, 0), SCOPE_TRACE);
+        assert_eq!(image.get_pixel(2, 8), SCOPE_TRACE);
+        assert_eq!(image.get_pixel(4, 8), (0, 0, 0));
+        assert_eq!(image.get_pixel(7, 17), SCOPE_DIVIDER);
+        // the DMC is at its top
+        assert_eq!(image.get_pixel(3, 4 * 18), SCOPE_TRACE);
+    }
+
+    #[test]
+    fn test_palette_ram() {
+        let mut entries = [0; 32];
+        entries[17] = 0x30;
+        let image = palette_ram(&entries);
+        assert_eq!(image.get_pixel(16, 16), SYSTEM_PALETTE[0x30]);
+    }
+}
@@ -0,0 +1,130 @@
+// Browser frontend API, exported with the C ABI so it can be called from JS
+// without extra tooling:
+//
+//   const bytes = new Uint8Array(romFile);
+//   const ptr = exports.alloc(bytes.length);
+//   new Uint8Array(exports.memory.buffer, ptr, bytes.length).set(bytes);
+//   exports.load_rom(ptr, bytes.length);
+//   exports.frame();
+//   const pixels = new Uint8ClampedArray(exports.memory.buffer, exports.framebuffer_ptr(), 256 * 240 * 4);
+//   context.putImageData(new ImageData(pixels, 256, 240), 0, 0);
+
+use std::cell::RefCell;
+
+use crate::joypad::JoypadButton;
+use crate::nes::{Nes, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::rom::Rom;
+
+struct State {
+    nes: Nes,
+    // RGBA copy of the frame, the layout canvas ImageData expects
+    framebuffer: Vec<u8>,
+    running: bool,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State {
+        nes: Nes::new(),
+        framebuffer: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT * 4],
+        running: false,
+    });
+}
+
+// Reserves memory for JS to copy a ROM image into. The buffer is handed back to load_rom.
+#[no_mangle]
+pub extern "C" fn alloc(len: usize) -> *mut u8 {
+    let mut buffer = vec![0u8; len].into_boxed_slice();
+    let ptr = buffer.as_mut_ptr();
+    std::mem::forget(buffer);
+    ptr
+}
+
+/// Loads an iNES image from a buffer returned by `alloc` and takes ownership of it.
+/// Returns 0 on success, -1 for an invalid image and -2 for an unsupported cartridge.
+///
+/// # Safety
+///
+/// `ptr` and `len` must come from a single call to `alloc`.
+#[no_mangle]
+pub unsafe extern "C" fn load_rom(ptr: *mut u8, len: usize) -> i32 {
+    let data = Box::from_raw(std::ptr::slice_from_raw_parts_mut(ptr, len));
+    let Ok(rom) = Rom::new(&data) else {
+        return -1;
+    };
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.running = state.nes.insert_cartridge(rom).is_ok();
+        if state.running {
+            0
+        } else {
+            -2
+        }
+    })
+}
+
+// Runs one frame and refreshes the framebuffer
+#[no_mangle]
+pub extern "C" fn frame() {
+    STATE.with(|state| {
+        let state = &mut *state.borrow_mut();
+        if !state.running {
+            return;
+        }
+        let output = state.nes.step_frame();
+        for (rgba, rgb) in state.framebuffer.chunks_exact_mut(4).zip(output.image.pixels.chunks_exact(3)) {
+            rgba[..3].copy_from_slice(rgb);
+            rgba[3] = 0xFF;
+        }
+    })
+}
+
+// Address of the 256x240 RGBA framebuffer in wasm memory
+#[no_mangle]
+pub extern "C" fn framebuffer_ptr() -> *const u8 {
+    STATE.with(|state| state.borrow().framebuffer.as_ptr())
+}
+
+// Player 1 buttons in bits 0-7 and player 2 in bits 8-15, in JoypadButton order
+#[no_mangle]
+pub extern "C" fn set_buttons(bits: u32) {
+    STATE.with(|state| {
+        let mut state = state.borrow_mut();
+        state.nes.set_buttons(0, JoypadButton::from_bits_truncate(bits as u8));
+        state.nes.set_buttons(1, JoypadButton::from_bits_truncate((bits >> 8) as u8));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::ines_with_program;
+
+    #[test]
+    fn test_load_rom_and_run_frame() {
+        // JMP $8000
+        let data = ines_with_program(&[0x4C, 0x00, 0x80]);
+
+        let ptr = alloc(data.len());
+        unsafe {
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+            assert_eq!(load_rom(ptr, data.len()), 0);
+        }
+        frame();
+        set_buttons(0x0201);
+
+        STATE.with(|state| {
+            let state = state.borrow();
+            assert_eq!(state.nes.frame_count(), 1);
+            assert_eq!(state.nes.buttons(0), JoypadButton::A);
+            assert_eq!(state.nes.buttons(1), JoypadButton::B);
+            assert_eq!(state.framebuffer[3], 0xFF);
+        });
+        assert!(!framebuffer_ptr().is_null());
+    }
+
+    #[test]
+    fn test_load_invalid_rom() {
+        let ptr = alloc(4);
+        assert_eq!(unsafe { load_rom(ptr, 4) }, -1);
+    }
+}
@@ -0,0 +1,212 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cpu::Memory;
+use crate::symbols::SymbolTable;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchSize {
+    Byte,
+    // little endian, like the 6502's pointers
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchFormat {
+    Hex,
+    Decimal,
+    Signed,
+    Binary,
+}
+
+impl WatchFormat {
+    fn parse(name: &str) -> Option<WatchFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "hex" => Some(WatchFormat::Hex),
+            "decimal" | "dec" => Some(WatchFormat::Decimal),
+            "signed" => Some(WatchFormat::Signed),
+            "binary" | "bin" => Some(WatchFormat::Binary),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum WatchError {
+    Io(io::Error),
+    Invalid { line: usize, expression: String },
+}
+
+impl fmt::Display for WatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchError::Io(error) => write!(f, "Could not read watch file: {}", error),
+            WatchError::Invalid { line: 0, expression } => write!(f, "Invalid watch expression \"{}\"", expression),
+            WatchError::Invalid { line, expression } => {
+                write!(f, "Line {}: invalid watch expression \"{}\"", line, expression)
+            }
+        }
+    }
+}
+
+impl From<io::Error> for WatchError {
+    fn from(error: io::Error) -> Self {
+        WatchError::Io(error)
+    }
+}
+
+// A game variable shown in the debug window, e.g. "$00A3 as decimal" or
+// "word at player_x". Values are peeked so watching has no side effects.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watch {
+    // as typed, for the label
+    pub expression: String,
+    pub address: u16,
+    pub size: WatchSize,
+    pub format: WatchFormat,
+}
+
+impl Watch {
+    // "[byte at|word at] <address or symbol> [as hex|decimal|signed|binary]"
+    pub fn parse(expression: &str, symbols: &SymbolTable) -> Option<Watch> {
+        let words: Vec<&str> = expression.split_whitespace().collect();
+        let (size, rest) = match words.as_slice() {
+            [size, "at", rest @ ..] => match size.to_ascii_lowercase().as_str() {
+                "byte" => (WatchSize::Byte, rest),
+                "word" => (WatchSize::Word, rest),
+                _ => return None,
+            },
+            rest => (WatchSize::Byte, rest),
+        };
+        let (address, format) = match rest {
+            [address] => (address, WatchFormat::Hex),
+            [address, "as", format] => (address, WatchFormat::parse(format)?),
+            _ => return None,
+        };
+        Some(Watch {
+            expression: words.join(" "),
+            address: symbols.resolve(address)?,
+            size,
+            format,
+        })
+    }
+
+    pub fn value(&self, memory: &impl Memory) -> u16 {
+        match self.size {
+            WatchSize::Byte => memory.read_byte(self.address) as u16,
+            WatchSize::Word => memory.read_word(self.address),
+        }
+    }
+
+    pub fn format_value(&self, value: u16) -> String {
+        match (self.format, self.size) {
+            (WatchFormat::Hex, WatchSize::Byte) => format!("${:02X}", value),
+            (WatchFormat::Hex, WatchSize::Word) => format!("${:04X}", value),
+            (WatchFormat::Decimal, _) => value.to_string(),
+            (WatchFormat::Signed, WatchSize::Byte) => (value as u8 as i8).to_string(),
+            (WatchFormat::Signed, WatchSize::Word) => (value as i16).to_string(),
+            (WatchFormat::Binary, WatchSize::Byte) => format!("%{:08b}", value),
+            (WatchFormat::Binary, WatchSize::Word) => format!("%{:016b}", value),
+        }
+    }
+}
+
+// The watch panel's contents, from the debugger's watch command or a watch file
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WatchList {
+    pub watches: Vec<Watch>,
+}
+
+impl WatchList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, expression: &str, symbols: &SymbolTable) -> Result<(), WatchError> {
+        let watch = Watch::parse(expression, symbols)
+            .ok_or_else(|| WatchError::Invalid { line: 0, expression: expression.to_string() })?;
+        self.watches.push(watch);
+        Ok(())
+    }
+
+    pub fn remove(&mut self, index: usize) -> Option<Watch> {
+        (index < self.watches.len()).then(|| self.watches.remove(index))
+    }
+
+    // One expression per line; blank lines and lines starting with # are skipped
+    pub fn parse(text: &str, symbols: &SymbolTable) -> Result<WatchList, WatchError> {
+        let mut list = WatchList::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let watch = Watch::parse(line, symbols)
+                .ok_or_else(|| WatchError::Invalid { line: number + 1, expression: line.to_string() })?;
+            list.watches.push(watch);
+        }
+        Ok(list)
+    }
+
+    pub fn load(path: impl AsRef<Path>, symbols: &SymbolTable) -> Result<WatchList, WatchError> {
+        WatchList::parse(&fs::read_to_string(path)?, symbols)
+    }
+
+    // game.nes.watch, next to the ROM
+    pub fn path_for_rom(rom: &Path) -> PathBuf {
+        let file_name = rom.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        rom.with_file_name(format!("{}.watch", file_name))
+    }
+
+    // Redrawn every frame: "$00A3 as decimal    163"
+    pub fn lines(&self, memory: &impl Memory) -> Vec<String> {
+        let width = self.watches.iter().map(|watch| watch.expression.len()).max().unwrap_or(0);
+        self.watches
+            .iter()
+            .map(|watch| format!("{:<width$}  {}", watch.expression, watch.format_value(watch.value(memory)), width = width))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+
+    #[test]
+    fn test_parse_watch() {
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x00FE, "pointer");
+        let watch = Watch::parse("word  at pointer as hex", &symbols).unwrap();
+        assert_eq!((watch.address, watch.size, watch.format), (0x00FE, WatchSize::Word, WatchFormat::Hex));
+        assert_eq!(watch.expression, "word at pointer as hex");
+        let watch = Watch::parse("$00A3 as decimal", &symbols).unwrap();
+        assert_eq!((watch.address, watch.size, watch.format), (0x00A3, WatchSize::Byte, WatchFormat::Decimal));
+        assert!(Watch::parse("$00A3 as octal", &symbols).is_none());
+        assert!(Watch::parse("long at $00A3", &symbols).is_none());
+        assert!(Watch::parse("missing_symbol", &symbols).is_none());
+    }
+
+    #[test]
+    fn test_watch_lines() {
+        let mut cpu = Cpu::new();
+        cpu.write_byte(0x00A3, 0xFE);
+        cpu.write_word(0x00FE, 0x1234);
+        let text = "# player\n$00A3 as decimal\n$A3 as signed\n\nword at $FE\n$A3 as binary\n";
+        let list = WatchList::parse(text, &SymbolTable::new()).unwrap();
+        assert_eq!(
+            list.lines(&cpu),
+            [
+                "$00A3 as decimal  254",
+                "$A3 as signed     -2",
+                "word at $FE       $1234",
+                "$A3 as binary     %11111110",
+            ]
+        );
+
+        let error = WatchList::parse("$00\nbogus expression", &SymbolTable::new()).unwrap_err();
+        assert_eq!(error.to_string(), "Line 2: invalid watch expression \"bogus expression\"");
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+use crate::audio::AudioThread;
+use crate::framebuffer::{triple_buffer, FrameConsumer, FrameProducer};
+use crate::joypad::JoypadButton;
+use crate::nes::{Nes, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::timing::FrameLimiter;
+
+// What the UI thread can ask of the console running on the emulation thread
+pub enum EmulatorCommand {
+    SetButtons { player: usize, buttons: JoypadButton },
+    Reset,
+    PowerCycle,
+    SetPaused(bool),
+    SetTurbo(bool),
+    SetSpeed(f32),
+    // frames to run ahead of the one shown, see Nes::step_frame_ahead
+    SetRunAhead(u32),
+    // runs on the emulation thread between frames, for anything without a command,
+    // e.g. saving a state and sending it back over a channel of its own
+    Run(Box<dyn FnOnce(&mut Nes) + Send>),
+}
+
+// Runs the console on a thread of its own, paced by its own FrameLimiter. The UI
+// thread forwards input as commands and presents whatever frame is newest in the
+// triple buffer, so neither waits on the other. Dropping it stops the thread.
+pub struct EmulationThread {
+    commands: Sender<EmulatorCommand>,
+    handle: Option<JoinHandle<Nes>>,
+}
+
+impl EmulationThread {
+    pub fn spawn(nes: Nes, limiter: FrameLimiter, audio: Option<AudioThread>) -> (EmulationThread, FrameConsumer) {
+        let (commands, received) = mpsc::channel();
+        let (frames, consumer) = triple_buffer(SCREEN_WIDTH, SCREEN_HEIGHT);
+        let handle = thread::spawn(move || run(nes, limiter, audio, received, frames));
+        (EmulationThread { commands, handle: Some(handle) }, consumer)
+    }
+
+    // False once the emulation thread has gone, e.g. after a panic
+    pub fn send(&self, command: EmulatorCommand) -> bool {
+        self.commands.send(command).is_ok()
+    }
+
+    // Stops emulating after the current frame and hands the console back
+    pub fn stop(mut self) -> Option<Nes> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Option<Nes> {
+        // closing the channel is what tells the thread to finish
+        let (closed, _) = mpsc::channel();
+        drop(std::mem::replace(&mut self.commands, closed));
+        self.handle.take()?.join().ok()
+    }
+}
+
+impl Drop for EmulationThread {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+fn apply(nes: &mut Nes, limiter: &mut FrameLimiter, run_ahead: &mut u32, command: EmulatorCommand) {
+    match command {
+        EmulatorCommand::SetButtons { player, buttons } => nes.set_buttons(player, buttons),
+        EmulatorCommand::Reset => {
+            let _ = nes.reset();
+        }
+        EmulatorCommand::PowerCycle => {
+            let _ = nes.power_cycle();
+        }
+        EmulatorCommand::SetPaused(paused) => {
+            if paused != limiter.paused {
+                limiter.toggle_pause();
+            }
+        }
+        EmulatorCommand::SetTurbo(turbo) => limiter.set_turbo(turbo),
+        EmulatorCommand::SetSpeed(speed) => limiter.speed = speed,
+        EmulatorCommand::SetRunAhead(frames) => *run_ahead = frames,
+        EmulatorCommand::Run(f) => f(nes),
+    }
+}
+
+fn run(
+    mut nes: Nes,
+    mut limiter: FrameLimiter,
+    mut audio: Option<AudioThread>,
+    commands: Receiver<EmulatorCommand>,
+    mut frames: FrameProducer,
+) -> Nes {
+    let mut run_ahead = 0;
+    loop {
+        // nothing to do while paused but wait for the command that ends it
+        let pending = if limiter.paused { commands.recv().map_err(|_| TryRecvError::Disconnected) } else { commands.try_recv() };
+        match pending {
+            Ok(command) => {
+                apply(&mut nes, &mut limiter, &mut run_ahead, command);
+                continue;
+            }
+            Err(TryRecvError::Empty) => {}
+            Err(TryRecvError::Disconnected) => return nes,
+        }
+
+        limiter.wait(audio.as_ref());
+        let mute = limiter.mute_audio();
+        let output = nes.step_frame_ahead(run_ahead);
+        if let (Some(audio), false) = (&mut audio, mute) {
+            audio.queue(output.audio);
+        }
+        frames.publish_copy(output.image);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom::tests::nes_with_program;
+
+    #[test]
+    fn test_emulation_thread() {
+        // JMP $8000
+        let nes = nes_with_program(&[0x4C, 0x00, 0x80]);
+
+        // uncapped, so the test doesn't wait on the wall clock
+        let (emulation, mut frames) = EmulationThread::spawn(nes, FrameLimiter::new(0.0), None);
+        while frames.latest().is_none() {
+            thread::yield_now();
+        }
+        assert!(emulation.send(EmulatorCommand::SetRunAhead(2)));
+        assert!(emulation.send(EmulatorCommand::SetPaused(true)));
+        assert!(emulation.send(EmulatorCommand::SetButtons { player: 1, buttons: JoypadButton::Start }));
+        let (reply, frame_count) = mpsc::channel();
+        assert!(emulation.send(EmulatorCommand::Run(Box::new(move |nes| reply.send(nes.frame_count()).unwrap()))));
+        let paused_at = frame_count.recv().unwrap();
+        assert!(paused_at > 0);
+
+        let nes = emulation.stop().unwrap();
+        assert_eq!(nes.frame_count(), paused_at);
+        assert_eq!(nes.buttons(1), JoypadButton::Start);
+    }
+}
@@ -0,0 +1,156 @@
+use crate::input::{Device, InputDevice, MouseButton};
+use crate::ppu::Ppu;
+use crate::savestate::{SaveState, StateError, StateReader};
+use crate::viewer::Image;
+
+// $4017 bits driven by the Zapper
+const LIGHT_NOT_SENSED: u8 = 1 << 3;
+const TRIGGER_PULLED: u8 = 1 << 4;
+
+// The photodiode keeps reporting light for roughly this many scanlines after
+// the beam has drawn a bright pixel under it
+const LIGHT_SENSE_SCANLINES: usize = 20;
+// Minimum perceived brightness (0-255) that counts as light
+const BRIGHTNESS_THRESHOLD: u32 = 85;
+
+// Light gun, usually on controller port 2, aimed with the mouse
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Zapper {
+    // position in NES pixels, None when the cursor is off screen
+    pub position: Option<(usize, usize)>,
+    pub trigger: bool,
+}
+
+impl Zapper {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Converts a mouse position in window coordinates, scaled by `scale`
+    pub fn aim(&mut self, window_x: i32, window_y: i32, scale: u32, frame: &Image) {
+        let scale = scale.max(1) as i32;
+        self.position = if window_x < 0 || window_y < 0 {
+            None
+        } else {
+            let (x, y) = ((window_x / scale) as usize, (window_y / scale) as usize);
+            (x < frame.width && y < frame.height).then_some((x, y))
+        };
+    }
+
+    // Value of the Zapper bits read from $4017 while the PPU is at `dot` of `scanline`.
+    // The frame is the one currently being rendered, so only pixels the beam has
+    // passed over recently can be seen by the sensor. Column x is drawn on dot x + 1.
+    pub fn sense(&self, frame: &Image, scanline: usize, dot: usize) -> u8 {
+        let mut value = LIGHT_NOT_SENSED;
+        if self.trigger {
+            value |= TRIGGER_PULLED;
+        }
+        if let Some((x, y)) = self.position {
+            let drawn = scanline > y || (scanline == y && dot > x + 1);
+            let recently_drawn = drawn && scanline - y < LIGHT_SENSE_SCANLINES;
+            if recently_drawn && brightness(frame, x, y) >= BRIGHTNESS_THRESHOLD {
+                value &= !LIGHT_NOT_SENSED;
+            }
+        }
+        value
+    }
+}
+
+// The Zapper has no shift register, it reports the trigger and light sensor as they are
+impl InputDevice for Zapper {
+    fn strobe(&mut self, _value: u8) {}
+
+    fn read(&mut self, ppu: &Ppu) -> u8 {
+        self.peek(ppu)
+    }
+
+    // The beam is drawing into the PPU's back buffer
+    fn peek(&self, ppu: &Ppu) -> u8 {
+        self.sense(&ppu.back_buffer, ppu.scanline as usize, ppu.dot as usize)
+    }
+
+    fn device(&self) -> Device {
+        Device::Zapper
+    }
+
+    fn set_pointer(&mut self, position: Option<(usize, usize)>, buttons: MouseButton) {
+        self.position = position;
+        self.trigger = buttons.contains(MouseButton::Left);
+    }
+}
+
+impl SaveState for Zapper {
+    fn save_state(&self, _out: &mut Vec<u8>) {}
+
+    fn load_state(&mut self, _input: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+// Perceived brightness using the Rec. 601 luma weights
+fn brightness(frame: &Image, x: usize, y: usize) -> u32 {
+    let (r, g, b) = frame.get_pixel(x, y);
+    (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame_with_white_pixel(x: usize, y: usize) -> Image {
+        let mut frame = Image::new(256, 240);
+        frame.set_pixel(x, y, (0xFF, 0xFF, 0xFF));
+        frame
+    }
+
+    #[test]
+    fn test_trigger() {
+        let frame = Image::new(256, 240);
+        let mut zapper = Zapper::new();
+        assert_eq!(zapper.sense(&frame, 0, 0), LIGHT_NOT_SENSED);
+        zapper.trigger = true;
+        assert_eq!(zapper.sense(&frame, 0, 0), LIGHT_NOT_SENSED | TRIGGER_PULLED);
+    }
+
+    #[test]
+    fn test_light_sense_timing() {
+        let frame = frame_with_white_pixel(100, 50);
+        let mut zapper = Zapper::new();
+        zapper.aim(300, 150, 3, &frame);
+        assert_eq!(zapper.position, Some((100, 50)));
+
+        // not drawn yet, then seen for a while after the beam passes
+        assert_eq!(zapper.sense(&frame, 49, 300), LIGHT_NOT_SENSED);
+        assert_eq!(zapper.sense(&frame, 50, 101), LIGHT_NOT_SENSED);
+        assert_eq!(zapper.sense(&frame, 50, 102), 0);
+        assert_eq!(zapper.sense(&frame, 69, 0), 0);
+        assert_eq!(zapper.sense(&frame, 70, 0), LIGHT_NOT_SENSED);
+    }
+
+    #[test]
+    fn test_senses_the_rendered_frame() {
+        // a white backdrop drawn over a back buffer still holding a black frame
+        let mut ppu = Ppu::new();
+        ppu.palette[0] = 0x30;
+        let mut zapper = Zapper::new();
+        zapper.position = Some((100, 50));
+        while (ppu.scanline, ppu.dot) != (50, 101) {
+            ppu.tick(None);
+        }
+        assert_eq!(zapper.peek(&ppu), LIGHT_NOT_SENSED);
+        ppu.tick(None);
+        assert_eq!(zapper.peek(&ppu), 0);
+    }
+
+    #[test]
+    fn test_dark_pixel_and_off_screen() {
+        let frame = frame_with_white_pixel(100, 50);
+        let mut zapper = Zapper::new();
+        zapper.aim(101, 50, 1, &frame);
+        assert_eq!(zapper.sense(&frame, 55, 0), LIGHT_NOT_SENSED);
+        zapper.aim(-1, 50, 1, &frame);
+        assert_eq!(zapper.position, None);
+        zapper.aim(256, 50, 1, &frame);
+        assert_eq!(zapper.position, None);
+    }
+}
@@ -0,0 +1,46 @@
+// Runs blargg's test ROMs when they have been placed in roms/blargg/
+use std::path::{Path, PathBuf};
+
+use madnes::blargg::{RomTestError, RomTestRunner};
+use madnes::nes::NesError;
+
+fn rom_path(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join("blargg").join(name)
+}
+
+fn run(name: &str) {
+    let path = rom_path(name);
+    if !path.exists() {
+        eprintln!("skipping {}: roms/blargg/{} not found", name, name);
+        return;
+    }
+    match RomTestRunner::load(&path).and_then(|mut runner| runner.run()) {
+        Ok(_) => {}
+        Err(RomTestError::Nes(NesError::UnsupportedMapper(mapper))) => {
+            eprintln!("skipping {}: mapper {} is not supported", name, mapper);
+        }
+        Err(error) => panic!("{}: {}", name, error),
+    }
+}
+
+#[test]
+fn cpu_instrs() {
+    run("cpu_instrs.nes");
+}
+
+#[test]
+fn instr_timing() {
+    run("instr_timing.nes");
+}
+
+#[test]
+#[ignore = "the PPU is not emulated yet"]
+fn ppu_vbl_nmi() {
+    run("ppu_vbl_nmi.nes");
+}
+
+#[test]
+#[ignore = "the APU is not emulated yet"]
+fn apu_test() {
+    run("apu_test.nes");
+}
@@ -0,0 +1,18 @@
+// Runs Tom Harte's nes6502 processor tests when they have been placed in roms/nes6502/,
+// one file per opcode: cargo test --release --features cpu_vectors
+#![cfg(feature = "cpu_vectors")]
+use std::path::Path;
+
+use madnes::harte::run_dir;
+
+#[test]
+fn nes6502() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("roms").join("nes6502");
+    if !dir.exists() {
+        eprintln!("skipping nes6502: roms/nes6502/ not found");
+        return;
+    }
+    let report = run_dir(&dir).unwrap_or_else(|error| panic!("{}", error));
+    eprintln!("{}", report);
+    assert!(report.passed(), "{}", report);
+}
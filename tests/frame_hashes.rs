@@ -0,0 +1,25 @@
+// Checks the frames listed in tests/frame_hashes.txt against their golden hashes.
+// ROMs are looked for in tests/roms, which holds small ones built for this, then roms/.
+use std::fs;
+use std::path::Path;
+
+use madnes::framehash;
+
+#[test]
+fn frame_hashes() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let text = fs::read_to_string(root.join("tests").join("frame_hashes.txt")).unwrap();
+    let cases = framehash::parse_cases(&text).unwrap();
+    let directories = [root.join("tests").join("roms"), root.join("roms")];
+    let mut failures = Vec::new();
+    for case in &cases {
+        let Some(roms) = directories.iter().find(|roms| roms.join(&case.rom).exists()) else {
+            eprintln!("skipping {}: roms/{} not found", case.rom.display(), case.rom.display());
+            continue;
+        };
+        if let Err(error) = framehash::check(case, roms) {
+            failures.push(format!("{}: {}", case.rom.display(), error));
+        }
+    }
+    assert!(failures.is_empty(), "{}", failures.join("\n"));
+}
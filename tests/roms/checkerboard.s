@@ -0,0 +1,97 @@
+; Fills a band of the screen with a checkerboard of tile 1 over a blue backdrop,
+; for tests/frame_hashes.txt. Assembled at $C000 with madnes::assembler into a
+; 16KB NROM image with 8KB of CHR RAM, which the program fills itself.
+PPUCTRL = $2000
+PPUMASK = $2001
+PPUSTATUS = $2002
+PPUSCROLL = $2005
+PPUADDR = $2006
+PPUDATA = $2007
+
+    .org $C000
+reset:
+    SEI
+    LDX #$FF
+    TXS
+    LDA #0
+    STA PPUCTRL
+    STA PPUMASK
+    ; the PPU ignores writes until it has warmed up
+vblank1:
+    BIT PPUSTATUS
+    BPL vblank1
+vblank2:
+    BIT PPUSTATUS
+    BPL vblank2
+
+    ; tile 1 into CHR RAM
+    LDA #$00
+    STA PPUADDR
+    LDA #$10
+    STA PPUADDR
+    LDX #0
+tile:
+    LDA pattern,X
+    STA PPUDATA
+    INX
+    CPX #16
+    BNE tile
+
+    ; background palettes 0 and 1
+    LDA #$3F
+    STA PPUADDR
+    LDA #$00
+    STA PPUADDR
+    LDX #0
+palette:
+    LDA colors,X
+    STA PPUDATA
+    INX
+    CPX #8
+    BNE palette
+
+    ; every other tile of rows 8-15
+    LDA #$21
+    STA PPUADDR
+    LDA #$00
+    STA PPUADDR
+    LDX #0
+band:
+    TXA
+    AND #1
+    STA PPUDATA
+    INX
+    BNE band
+
+    ; palette 1 for rows 8-11
+    LDA #$23
+    STA PPUADDR
+    LDA #$D0
+    STA PPUADDR
+    LDA #$55
+    LDX #8
+attributes:
+    STA PPUDATA
+    DEX
+    BNE attributes
+
+    LDA #0
+    STA PPUSCROLL
+    STA PPUSCROLL
+    STA PPUCTRL
+    LDA #$0A
+    STA PPUMASK
+forever:
+    JMP forever
+
+interrupt:
+    RTI
+
+pattern:
+    .byte $FF, $FF, $FF, $FF, $FF, $FF, $FF, $FF
+    .byte $AA, $55, $AA, $55, $AA, $55, $AA, $55
+colors:
+    .byte $01, $16, $27, $30, $01, $1A, $2B, $3C
+
+    .org $FFFA
+    .word interrupt, reset, interrupt